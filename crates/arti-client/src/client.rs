@@ -853,10 +853,17 @@ impl<R: Runtime> TorClient<R> {
         let dir_cfg = {
             let mut c: tor_dirmgr::DirMgrConfig = config.dir_mgr_config()?;
             c.extensions = dirmgr_extensions;
+            #[cfg(feature = "dir-proxy-fallback")]
+            if c.extensions.fallback_fetcher.is_none() {
+                c.extensions.fallback_fetcher = config
+                    .directory_proxy_fallback
+                    .fallback_fetcher(runtime.clone());
+            }
             c
         };
-        let statemgr = FsStateMgr::from_path_and_mistrust(&state_dir, mistrust)
-            .map_err(ErrorDetail::StateMgrSetup)?;
+        let statemgr =
+            FsStateMgr::from_path_and_mistrust(config.statemgr_dir(&state_dir), mistrust)
+                .map_err(ErrorDetail::StateMgrSetup)?;
         // Try to take state ownership early, so we'll know if we have it.
         // (At this point we don't yet care if we have it.)
         let _ignore_status = statemgr.try_lock().map_err(ErrorDetail::StateMgrSetup)?;
@@ -1204,7 +1211,14 @@ impl<R: Runtime> TorClient<R> {
         // and we have no way to compare them, but this field is explicitly documented as being
         // non-reconfigurable anyways.
 
-        let dir_cfg = new_config.dir_mgr_config().map_err(wrap_err)?;
+        #[allow(unused_mut)]
+        let mut dir_cfg = new_config.dir_mgr_config().map_err(wrap_err)?;
+        #[cfg(feature = "dir-proxy-fallback")]
+        {
+            dir_cfg.extensions.fallback_fetcher = new_config
+                .directory_proxy_fallback
+                .fallback_fetcher(self.runtime.clone());
+        }
         let state_cfg = new_config
             .storage
             .expand_state_dir(&self.path_resolver)
@@ -1212,8 +1226,16 @@ impl<R: Runtime> TorClient<R> {
         let addr_cfg = &new_config.address_filter;
         let timeout_cfg = &new_config.stream_timeouts;
 
-        if state_cfg != self.statemgr.path() {
+        if !self.statemgr.path().starts_with(&state_cfg) {
             how.cannot_change("storage.state_dir").map_err(wrap_err)?;
+        } else if new_config.statemgr_dir(&state_cfg) != self.statemgr.path() {
+            // Our state manager belongs to the network that we started on,
+            // and we can't replace it while we're running.  Switching to
+            // another network without it would mix up the two networks'
+            // guards, so we refuse even when we're only asked to warn.
+            return Err(wrap_err(tor_config::ReconfigureError::CannotChange {
+                field: "tor_network.network_name".into(),
+            }));
         }
 
         self.memquota
@@ -1226,8 +1248,12 @@ impl<R: Runtime> TorClient<R> {
             .map_err(wrap_err)?;
 
         #[cfg(any(feature = "onion-service-client", feature = "onion-service-service"))]
-        if retire_circuits != RetireCircuits::None {
-            self.hs_circ_pool.retire_all_circuits().map_err(wrap_err)?;
+        match retire_circuits {
+            RetireCircuits::None => {}
+            RetireCircuits::Some(first_hops) => {
+                self.hs_circ_pool.retire_circuits_through(&first_hops);
+            }
+            _ => self.hs_circ_pool.retire_all_circuits().map_err(wrap_err)?,
         }
 
         self.dirmgr.reconfigure(&dir_cfg, how).map_err(wrap_err)?;
@@ -2217,4 +2243,47 @@ mod test {
                 .unwrap();
         });
     }
+
+    #[test]
+    fn reconfigure_network_name() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let state_dir = tempfile::tempdir().unwrap();
+            let cache_dir = tempfile::tempdir().unwrap();
+            let mut bld = TorClientConfigBuilder::from_directories(&state_dir, cache_dir);
+            let mut testnet = crate::config::dir::AlternativeNetwork::builder();
+            testnet.set_authorities(vec![crate::config::dir::Authority::builder()
+                .name("test")
+                .v3ident([b'?'; 20].into())
+                .clone()]);
+            testnet.set_fallback_caches(vec![]);
+            bld.tor_network().networks().insert("test".into(), testnet);
+            let cfg = bld.build().unwrap();
+            bld.tor_network().network_name("test");
+            let cfg_test = bld.build().unwrap();
+
+            // Each network keeps its state in its own place.
+            assert_eq!(
+                cfg_test.statemgr_dir(state_dir.path()),
+                state_dir.path().join("networks").join("test")
+            );
+            let tor_client = TorClient::with_runtime(rt)
+                .config(cfg_test.clone())
+                .bootstrap_behavior(BootstrapBehavior::Manual)
+                .create_unbootstrapped()
+                .unwrap();
+            assert_eq!(
+                tor_client.statemgr.path(),
+                state_dir.path().join("networks").join("test")
+            );
+
+            // We can't switch networks while running, even if we're only
+            // asked to warn.
+            tor_client
+                .reconfigure(&cfg_test, Reconfigure::AllOrNothing)
+                .unwrap();
+            for how in [Reconfigure::AllOrNothing, Reconfigure::WarnOnFailures] {
+                assert!(tor_client.reconfigure(&cfg, how).is_err());
+            }
+        });
+    }
 }
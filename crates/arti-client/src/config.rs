@@ -44,10 +44,13 @@ pub mod circ {
 /// Types for configuring how Tor accesses its directory information.
 pub mod dir {
     pub use tor_dirmgr::{
-        Authority, AuthorityBuilder, DirMgrConfig, DirTolerance, DirToleranceBuilder,
-        DownloadSchedule, DownloadScheduleConfig, DownloadScheduleConfigBuilder, FallbackDir,
-        FallbackDirBuilder, NetworkConfig, NetworkConfigBuilder,
+        AlternativeNetwork, AlternativeNetworkBuilder, Authority, AuthorityBuilder, DirMgrConfig,
+        DirTolerance, DirToleranceBuilder, DownloadSchedule, DownloadScheduleConfig,
+        DownloadScheduleConfigBuilder, FallbackDir, FallbackDirBuilder, NetworkConfig,
+        NetworkConfigBuilder,
     };
+    #[cfg(feature = "dir-proxy-fallback")]
+    pub use tor_dirmgr::{ProxyFallbackConfig, ProxyFallbackConfigBuilder, ProxyProtocol};
 }
 
 /// Types for configuring pluggable transports.
@@ -62,6 +65,17 @@ pub mod onion_service {
     pub use tor_hsservice::config::{OnionServiceConfig, OnionServiceConfigBuilder};
 }
 
+/// Types for configuring how we choose and use guards.
+pub mod guards {
+    pub use tor_guardmgr::{
+        GuardConfig, GuardConfigBuilder, GuardParamOverrides, GuardParamOverridesBuilder,
+        ReachabilityInference, ReachabilityInferenceBuilder, SamplePrunePolicy,
+        SamplePrunePolicyBuilder,
+    };
+    #[cfg(feature = "geoip")]
+    pub use tor_guardmgr::{GuardCountryRestrictions, GuardCountryRestrictionsBuilder};
+}
+
 /// Types for configuring vanguards.
 pub mod vanguards {
     pub use tor_guardmgr::{VanguardConfig, VanguardConfigBuilder};
@@ -569,6 +583,16 @@ pub struct TorClientConfig {
     #[builder_field_attr(serde(default))]
     directory_tolerance: dir::DirTolerance,
 
+    /// How to fetch directory information through a proxy, if we can't
+    /// bootstrap in the usual way.
+    ///
+    /// This is much less private than fetching over Tor; only enable it if
+    /// you can't otherwise bootstrap.
+    #[cfg(feature = "dir-proxy-fallback")]
+    #[builder(sub_builder)]
+    #[builder_field_attr(serde(default))]
+    pub(crate) directory_proxy_fallback: dir::ProxyFallbackConfig,
+
     /// Facility to override network parameters from the values set in the
     /// consensus.
     #[builder(
@@ -633,6 +657,11 @@ pub struct TorClientConfig {
     #[builder_field_attr(serde(default))]
     pub(crate) vanguards: vanguards::VanguardConfig,
 
+    /// Information about how we choose and use guards.
+    #[builder(sub_builder)]
+    #[builder_field_attr(serde(default))]
+    pub(crate) guards: guards::GuardConfig,
+
     /// Resolves paths in this configuration.
     ///
     /// This is not [reconfigurable](crate::TorClient::reconfigure).
@@ -706,6 +735,11 @@ impl AsRef<tor_guardmgr::fallback::FallbackList> for TorClientConfig {
         self.tor_network.fallback_caches()
     }
 }
+impl AsRef<tor_guardmgr::fallback::AuthorityDirList> for TorClientConfig {
+    fn as_ref(&self) -> &tor_guardmgr::fallback::AuthorityDirList {
+        self.tor_network.authority_dirs()
+    }
+}
 impl AsRef<[BridgeConfig]> for TorClientConfig {
     fn as_ref(&self) -> &[BridgeConfig] {
         #[cfg(feature = "bridge-client")]
@@ -728,6 +762,25 @@ impl tor_guardmgr::GuardMgrConfig for TorClientConfig {
     fn bridges_enabled(&self) -> bool {
         self.bridges.bridges_enabled()
     }
+    fn authorities_only_bootstrap(&self) -> bool {
+        self.tor_network.authorities_only_bootstrap()
+    }
+    fn reachability_inference(&self) -> guards::ReachabilityInference {
+        self.guards.reachability_inference().clone()
+    }
+    fn guard_param_overrides(&self) -> guards::GuardParamOverrides {
+        self.guards.param_overrides().clone()
+    }
+    fn guard_sample_prune_policy(&self) -> guards::SamplePrunePolicy {
+        self.guards.sample_pruning().clone()
+    }
+    fn learn_fallbacks(&self) -> bool {
+        self.guards.learn_fallbacks()
+    }
+    #[cfg(feature = "geoip")]
+    fn guard_country_restrictions(&self) -> guards::GuardCountryRestrictions {
+        self.guards.countries().clone()
+    }
 }
 
 impl TorClientConfig {
@@ -781,6 +834,18 @@ impl TorClientConfig {
         Ok((state_dir, mistrust))
     }
 
+    /// Return the directory where our state manager keeps its state, given
+    /// our state directory `state_dir`.
+    ///
+    /// Each network from `tor_network.networks` has its own, so that we
+    /// never use guards that we chose on one network when we're on another.
+    pub(crate) fn statemgr_dir(&self, state_dir: &Path) -> PathBuf {
+        match self.tor_network.network_name() {
+            Some(name) => state_dir.join("networks").join(name),
+            None => state_dir.to_owned(),
+        }
+    }
+
     /// Access the `tor_memquota` configuration
     ///
     /// Ad-hoc accessor for testing purposes.
@@ -425,6 +425,8 @@ mod test {
         feature = "pt-client",
         feature = "onion-service-client",
         feature = "rpc",
+        feature = "dir-proxy-fallback",
+        feature = "geoip",
     ));
 
     /// Return the expected exceptions to the usual expectations about config and examples
@@ -508,6 +510,47 @@ mod test {
                 "path_rules.long_lived_ports",
                 "proxy.socks_listen",
                 "proxy.dns_listen",
+                "tor_network.authorities_only_bootstrap",
+                "download_schedule.prefetch_lead_time",
+                "download_schedule.microdesc_failovers",
+                "guards",
+                "guards.learn_fallbacks",
+                "guards.reachability_inference",
+                "guards.reachability_inference.enabled",
+                "guards.reachability_inference.lifetime",
+                "guards.reachability_inference.min_failed_guards",
+                "guards.param_overrides",
+                "guards.sample_pruning",
+                "guards.sample_pruning.max_unused",
+                "guards.sample_pruning.max_unrecognized",
+            ],
+        );
+
+        declare_exceptions(
+            None,
+            None,
+            Recognized,
+            &[
+                // Guard parameter overrides, which have no default, and so
+                // appear only as examples
+                "guards.param_overrides.max_sample_size",
+                "guards.param_overrides.min_filtered_sample_size",
+                "guards.param_overrides.n_primary",
+                "guards.param_overrides.lifetime_unconfirmed",
+                "guards.param_overrides.lifetime_confirmed",
+                "guards.param_overrides.lifetime_unlisted",
+            ],
+        );
+
+        declare_exceptions(
+            None,
+            Some(InNew),
+            FeatureDependent,
+            &[
+                // Settings only available with geoip support
+                "guards.countries",
+                "guards.countries.required",
+                "guards.countries.excluded",
             ],
         );
 
@@ -518,7 +561,10 @@ mod test {
             &[
                 // Examples exist but are not auto-testable
                 "tor_network.authorities",
+                "tor_network.authority_dirs",
                 "tor_network.fallback_caches",
+                "tor_network.network_name",
+                "tor_network.networks",
             ],
         );
 
@@ -623,6 +669,31 @@ mod test {
             ],
         );
 
+        declare_exceptions(
+            None,
+            Some(InNew),
+            FeatureDependent,
+            &[
+                // Settings only available with dir-proxy-fallback support
+                "directory_proxy_fallback",
+                "directory_proxy_fallback.protocol",
+                "directory_proxy_fallback.mirror_port",
+                "directory_proxy_fallback.after_failed_attempts",
+            ],
+        );
+
+        declare_exceptions(
+            None,
+            None,
+            FeatureDependent,
+            &[
+                // dir-proxy-fallback settings that have no default, and so
+                // appear only as examples
+                "directory_proxy_fallback.proxy",
+                "directory_proxy_fallback.mirror",
+            ],
+        );
+
         // These are commented-out by default
         declare_exceptions(
             None,
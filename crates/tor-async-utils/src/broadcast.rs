@@ -0,0 +1,126 @@
+//! Send a copy of each event to a changing set of listeners.
+
+use futures::channel::mpsc;
+
+/// A list of unbounded channels, each of which gets a copy of every item that
+/// we send.
+///
+/// This is for the common case where an object hands out a new event stream
+/// every time somebody asks for one, and then needs to report each event to
+/// all of those streams.
+///
+/// Each listener can have some associated information of type `A`, which
+/// [`send_with`](UnboundedBroadcaster::send_with) can use to decide what to
+/// send it.
+///
+/// We drop each sender once we notice that its receiver is gone.
+#[derive(Debug)]
+pub struct UnboundedBroadcaster<T, A = ()> {
+    /// The senders for our listeners, along with their associated information.
+    senders: Vec<(mpsc::UnboundedSender<T>, A)>,
+}
+
+impl<T, A> Default for UnboundedBroadcaster<T, A> {
+    fn default() -> Self {
+        Self {
+            senders: Vec::new(),
+        }
+    }
+}
+
+impl<T> UnboundedBroadcaster<T> {
+    /// Return a new receiver that will get a copy of every subsequent item.
+    pub fn subscribe(&mut self) -> mpsc::UnboundedReceiver<T> {
+        self.subscribe_with(())
+    }
+}
+
+impl<T, A> UnboundedBroadcaster<T, A> {
+    /// Return a new `UnboundedBroadcaster` with no listeners.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return a new receiver, associated with `info`, that will get an item
+    /// for every subsequent send.
+    pub fn subscribe_with(&mut self, info: A) -> mpsc::UnboundedReceiver<T> {
+        let (snd, rcv) = mpsc::unbounded();
+        self.senders.push((snd, info));
+        rcv
+    }
+
+    /// Send a copy of `item` to every listener.
+    pub fn send(&mut self, item: &T)
+    where
+        T: Clone,
+    {
+        self.send_with(|_| item.clone());
+    }
+
+    /// Send every listener the item that `make_item` returns for its
+    /// associated information.
+    pub fn send_with<F>(&mut self, mut make_item: F)
+    where
+        F: FnMut(&A) -> T,
+    {
+        self.senders
+            .retain(|(snd, info)| snd.unbounded_send(make_item(info)).is_ok());
+    }
+
+    /// Return true if nobody is listening any more.
+    pub fn is_empty(&self) -> bool {
+        self.senders.iter().all(|(snd, _)| snd.is_closed())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+
+    use super::*;
+
+    #[test]
+    fn broadcast() {
+        let mut bc = UnboundedBroadcaster::new();
+        assert!(bc.is_empty());
+        bc.send(&1_u8);
+
+        let mut r1 = bc.subscribe();
+        let mut r2 = bc.subscribe();
+        assert!(!bc.is_empty());
+        bc.send(&2);
+        assert_eq!(r1.try_next().unwrap(), Some(2));
+        assert_eq!(r2.try_next().unwrap(), Some(2));
+
+        drop(r1);
+        bc.send(&3);
+        assert_eq!(bc.senders.len(), 1);
+        assert_eq!(r2.try_next().unwrap(), Some(3));
+        assert!(r2.try_next().is_err());
+
+        drop(r2);
+        assert!(bc.is_empty());
+    }
+
+    #[test]
+    fn send_with() {
+        let mut bc = UnboundedBroadcaster::new();
+        let mut small = bc.subscribe_with(1_u32);
+        let mut big = bc.subscribe_with(100);
+        bc.send_with(|scale| scale * 7);
+        assert_eq!(small.try_next().unwrap(), Some(7));
+        assert_eq!(big.try_next().unwrap(), Some(700));
+    }
+}
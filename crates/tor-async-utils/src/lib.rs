@@ -42,6 +42,7 @@
 #![allow(clippy::needless_lifetimes)] // See arti#1765
 //! <!-- @@ end lint list maintained by maint/add_warning @@ -->
 
+mod broadcast;
 mod join_read_write;
 mod prepare_send;
 mod sink_close_channel;
@@ -52,6 +53,8 @@ mod watch;
 pub mod peekable_stream;
 pub mod stream_peek;
 
+pub use broadcast::UnboundedBroadcaster;
+
 pub use join_read_write::*;
 
 pub use prepare_send::{SinkPrepareExt, SinkPrepareSendFuture, SinkSendable};
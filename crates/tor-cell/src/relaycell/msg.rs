@@ -142,19 +142,7 @@ impl From<u32> for BeginFlags {
 
 /// A preference for IPv4 vs IPv6 addresses; usable as a nicer frontend for
 /// BeginFlags.
-#[derive(Clone, Default, Copy, Debug, Eq, PartialEq)]
-#[non_exhaustive]
-pub enum IpVersionPreference {
-    /// Only IPv4 is allowed.
-    Ipv4Only,
-    /// IPv4 and IPv6 are both allowed, and IPv4 is preferred.
-    #[default]
-    Ipv4Preferred,
-    /// IPv4 and IPv6 are both allowed, and IPv6 is preferred.
-    Ipv6Preferred,
-    /// Only IPv6 is allowed.
-    Ipv6Only,
-}
+pub use tor_linkspec::IpVersionPreference;
 impl From<IpVersionPreference> for BeginFlags {
     fn from(v: IpVersionPreference) -> Self {
         use IpVersionPreference::*;
@@ -163,6 +151,8 @@ impl From<IpVersionPreference> for BeginFlags {
             Ipv4Preferred => BeginFlags::IPV6_OKAY,
             Ipv6Preferred => BeginFlags::IPV6_OKAY | BeginFlags::IPV6_PREFERRED,
             Ipv6Only => BeginFlags::IPV4_NOT_OKAY,
+            // Treat any preference we don't know about like the default.
+            _ => BeginFlags::IPV6_OKAY,
         }
     }
 }
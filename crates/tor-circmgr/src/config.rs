@@ -384,6 +384,7 @@ pub(crate) mod test_config {
     use super::*;
     use crate::*;
     use tor_guardmgr::bridge::BridgeConfig;
+    use tor_guardmgr::fallback::AuthorityDirList;
     #[cfg(all(feature = "vanguards", feature = "hs-common"))]
     use tor_guardmgr::VanguardConfig;
 
@@ -410,10 +411,18 @@ pub(crate) mod test_config {
             &self.guardmgr.fallbacks
         }
     }
+    impl AsRef<AuthorityDirList> for TestConfig {
+        fn as_ref(&self) -> &AuthorityDirList {
+            &self.guardmgr.authority_dirs
+        }
+    }
     impl GuardMgrConfig for TestConfig {
         fn bridges_enabled(&self) -> bool {
             self.guardmgr.bridges_enabled()
         }
+        fn authorities_only_bootstrap(&self) -> bool {
+            self.guardmgr.authorities_only_bootstrap()
+        }
     }
     impl CircMgrConfig for TestConfig {
         fn path_rules(&self) -> &PathConfig {
@@ -20,7 +20,7 @@ use tor_error::{bad_api_usage, internal};
 use tor_error::{debug_report, Bug};
 use tor_guardmgr::VanguardMode;
 use tor_linkspec::{
-    CircTarget, HasRelayIds as _, IntoOwnedChanTarget, OwnedChanTarget, OwnedCircTarget,
+    CircTarget, HasRelayIds as _, IntoOwnedChanTarget, OwnedChanTarget, OwnedCircTarget, RelayIds,
 };
 use tor_netdir::{NetDir, NetDirProvider, Relay};
 use tor_proto::circuit::{self, CircParameters, ClientCirc};
@@ -272,6 +272,15 @@ impl<R: Runtime> HsCircPool<R> {
     pub fn retire_all_circuits(&self) -> StdResult<(), tor_config::ReconfigureError> {
         self.0.retire_all_circuits()
     }
+
+    /// Retire every circuit in this pool whose first hop is any of the relays
+    /// in `first_hops`.
+    ///
+    /// This is used when bridges are removed from our configuration: only the
+    /// circuits built through those bridges need to go.
+    pub fn retire_circuits_through(&self, first_hops: &[RelayIds]) {
+        self.0.retire_circuits_through(first_hops);
+    }
 }
 
 /// An object to provide circuits for implementing onion services.
@@ -488,6 +497,14 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> HsCircPoolInner<B, R> {
         Ok(())
     }
 
+    /// Internal implementation for [`HsCircPool::retire_circuits_through`].
+    pub(crate) fn retire_circuits_through(&self, first_hops: &[RelayIds]) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        inner
+            .pool
+            .retain(|circ| !circuit_starts_at_any(circ, first_hops));
+    }
+
     /// Take and return a circuit from our pool suitable for being extended to `avoid_target`.
     ///
     /// If vanguards are enabled, this will try to build a circuit stem of the specified
@@ -886,6 +903,18 @@ where
     all_compatible
 }
 
+/// Return true if the first hop of `circ` has any of the identities in
+/// any member of `first_hops`.
+fn circuit_starts_at_any<C: AbstractCirc>(circ: &HsCircStem<C>, first_hops: &[RelayIds]) -> bool {
+    let path = circ.circ.path_ref();
+    let Some(first) = path.hops().first().and_then(|hop| hop.as_chan_target()) else {
+        return false;
+    };
+    first_hops
+        .iter()
+        .any(|ids| first.has_any_relay_id_from(ids))
+}
+
 /// Background task to launch onion circuits as needed.
 async fn launch_hs_circuits_as_needed<B: AbstractCircBuilder<R> + 'static, R: Runtime>(
     pool: Weak<HsCircPoolInner<B, R>>,
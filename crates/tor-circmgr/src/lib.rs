@@ -308,7 +308,8 @@ impl<R: Runtime> CircMgr<R> {
     ///
     /// The actual behavior here will depend on the value of `how`.
     ///
-    /// Returns whether any of the circuit pools should be cleared.
+    /// Returns which of our circuits (if any) we have retired, so that the
+    /// caller can retire the same circuits from its other circuit pools.
     pub fn reconfigure<CFG: CircMgrConfig>(
         &self,
         new_config: &CFG,
@@ -584,7 +585,8 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> CircMgrInner<B, R> {
     ///
     /// The actual behavior here will depend on the value of `how`.
     ///
-    /// Returns whether any of the circuit pools should be cleared.
+    /// Returns which of our circuits (if any) we have retired, so that the
+    /// caller can retire the same circuits from its other circuit pools.
     pub(crate) fn reconfigure<CFG: CircMgrConfig>(
         &self,
         new_config: &CFG,
@@ -623,7 +625,7 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> CircMgrInner<B, R> {
         let discard_all_circuits = !new_config
             .path_rules()
             .at_least_as_permissive_as(&old_path_rules)
-            || retire_because_of_guardmgr != tor_guardmgr::RetireCircuits::None;
+            || retire_because_of_guardmgr == tor_guardmgr::RetireCircuits::All;
 
         #[cfg(all(feature = "vanguards", feature = "hs-common"))]
         let discard_all_circuits = discard_all_circuits
@@ -644,6 +646,14 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> CircMgrInner<B, R> {
             self.retire_all_circuits();
             return Ok(RetireCircuits::All);
         }
+        if let RetireCircuits::Some(first_hops) = &retire_because_of_guardmgr {
+            info!(
+                "{} bridge(s) are no longer configured: retiring circuits through them.",
+                first_hops.len()
+            );
+            self.mgr.retire_circuits_through(first_hops);
+            return Ok(retire_because_of_guardmgr);
+        }
         Ok(RetireCircuits::None)
     }
 
@@ -32,7 +32,7 @@ use tor_config::MutCfg;
 use tor_error::{debug_report, info_report, internal, warn_report, AbsRetryTime, HasRetryTime};
 #[cfg(feature = "vanguards")]
 use tor_guardmgr::vanguards::VanguardMgr;
-use tor_linkspec::CircTarget;
+use tor_linkspec::{CircTarget, HasRelayIds as _, RelayIds};
 use tor_proto::circuit::{CircParameters, Path, UniqId};
 use tor_rtcompat::{Runtime, SleepProviderExt};
 
@@ -666,6 +666,24 @@ impl<B: AbstractCircBuilder<R>, R: Runtime> CircList<B, R> {
         self.pending_circs.clear();
         self.open_circs.clear();
     }
+
+    /// Clear all pending circuits, and every open circuit whose first hop has
+    /// any of the identities in `first_hops`.
+    ///
+    /// We don't know where a pending circuit starts, so we clear them all, as
+    /// in [`clear_all_circuits`](CircList::clear_all_circuits).
+    fn clear_circuits_through(&mut self, first_hops: &[RelayIds]) {
+        self.pending_circs.clear();
+        self.open_circs.retain(|_, ent| {
+            let path = ent.circ.path_ref();
+            let Some(first) = path.hops().first().and_then(|hop| hop.as_chan_target()) else {
+                return true;
+            };
+            !first_hops
+                .iter()
+                .any(|ids| first.has_any_relay_id_from(ids))
+        });
+    }
 }
 
 /// Timing information for circuits that have been built but never used.
@@ -1402,6 +1420,16 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> AbstractCircMgr<B, R> {
         list.clear_all_circuits();
     }
 
+    /// Make sure that no circuit whose first hop has any of the identities in
+    /// `first_hops` will ever be given out again.
+    ///
+    /// As with [`retire_all_circuits`](AbstractCircMgr::retire_all_circuits),
+    /// this also retires all pending circuits.
+    pub(crate) fn retire_circuits_through(&self, first_hops: &[RelayIds]) {
+        let mut list = self.circs.lock().expect("poisoned lock");
+        list.clear_circuits_through(first_hops);
+    }
+
     /// Expire circuits according to the rules in `config` and the
     /// current time `now`.
     ///
@@ -426,6 +426,9 @@ mod test {
                 ));
                 mon.succeeded();
                 assert!(usable.await.unwrap());
+                // Make sure the guard manager has heard about our success, so
+                // that it doesn't count the attempt as still in flight.
+                guards.flush_msg_queue().await;
             }
             assert_eq!(distinct_guards.len(), 1);
             assert_ne!(distinct_mid.len(), 1);
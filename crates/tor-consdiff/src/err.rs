@@ -18,6 +18,11 @@ pub enum Error {
     /// to the given input.
     #[error("Diff didn't apply to input: {0}")]
     CantApply(&'static str),
+
+    /// We were asked to generate a diff to a document that can't be
+    /// represented in the diff format.
+    #[error("Can't generate diff: {0}")]
+    CantGenerate(&'static str),
 }
 
 impl From<ParseIntError> for Error {
@@ -0,0 +1,395 @@
+//! Generate consensus diffs.
+//!
+//! A directory cache that holds several consecutive consensuses can save its
+//! clients a lot of bandwidth by sending them a diff from a consensus that they
+//! already have, instead of a whole new one.
+//!
+//! We find the lines that the two documents have in common with a "patience
+//! diff": we anchor on lines that appear exactly once in each document, and
+//! then repeat the process between each pair of anchors.  Nearly every line
+//! in a consensus (the `r` and `m` lines in particular) is unique, so this
+//! gives small diffs quickly, without the quadratic worst case of a full
+//! longest-common-subsequence search.
+
+use std::collections::HashMap;
+
+use crate::{Error, Result};
+
+/// The largest region, in (lines of old document) × (lines of new document),
+/// for which we'll fall back to an exact longest-common-subsequence search
+/// when we can't find any unique lines to anchor on.
+///
+/// Larger regions than this are simply replaced in their entirety.
+const MAX_LCS_CELLS: usize = 1 << 20;
+
+/// Generate a diff that transforms the consensus `old` into the consensus
+/// `new`, in the format described in dir-spec.txt.
+///
+/// `old_digest_as_signed` must be the SHA3-256 digest of the signed part of
+/// `old`: it is what clients use to check that they are applying the diff
+/// to the right document.  (We can compute the digest of `new` ourselves.)
+///
+/// The result can be applied to `old` with [`apply_diff`](crate::apply_diff).
+///
+/// Gives an error if `new` can't be represented in a diff: this happens if it
+/// has a line consisting of a single `.`, which can't appear in a well-formed
+/// consensus.
+pub fn generate_diff(old: &str, new: &str, old_digest_as_signed: [u8; 32]) -> Result<String> {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+
+    let mut out = String::new();
+    out.push_str("network-status-diff-version 1\n");
+    out.push_str(&format!(
+        "hash {} {}\n",
+        hex::encode_upper(old_digest_as_signed),
+        hex::encode_upper(digest_of_lines(&new_lines)),
+    ));
+
+    // The diff format requires us to list our changes from the end of the
+    // document to the start.
+    for hunk in hunks(&old_lines, &new_lines).iter().rev() {
+        let inserted = &new_lines[hunk.new_lo..hunk.new_hi];
+        if inserted.contains(&".") {
+            return Err(Error::CantGenerate(
+                "document contains a line with a lone '.'",
+            ));
+        }
+        let range = if hunk.old_hi == hunk.old_lo + 1 {
+            format!("{}", hunk.old_hi)
+        } else {
+            format!("{},{}", hunk.old_lo + 1, hunk.old_hi)
+        };
+        let command = match (hunk.old_lo < hunk.old_hi, inserted.is_empty()) {
+            (true, true) => {
+                out.push_str(&format!("{}d\n", range));
+                continue;
+            }
+            (true, false) => format!("{}c\n", range),
+            (false, _) => format!("{}a\n", hunk.old_lo),
+        };
+        out.push_str(&command);
+        for line in inserted {
+            out.push_str(line);
+            out.push('\n');
+        }
+        out.push_str(".\n");
+    }
+
+    Ok(out)
+}
+
+/// Return the SHA3-256 digest of a document made of `lines`, each followed by
+/// a newline.
+///
+/// This matches the digest that [`DiffResult::check_digest`](crate::DiffResult::check_digest)
+/// computes.
+fn digest_of_lines(lines: &[&str]) -> [u8; 32] {
+    use digest::Digest;
+    use tor_llcrypto::d::Sha3_256;
+    let mut d = Sha3_256::new();
+    for line in lines {
+        d.update(line.as_bytes());
+        d.update(b"\n");
+    }
+    d.finalize().into()
+}
+
+/// A contiguous change between two documents: the lines `old_lo..old_hi` of
+/// the old document are replaced by the lines `new_lo..new_hi` of the new one.
+///
+/// Line numbers here are 0-indexed; either range may be empty, but not both.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Hunk {
+    /// The first line of the old document to remove.
+    old_lo: usize,
+    /// The line of the old document after the last one to remove.
+    old_hi: usize,
+    /// The first line of the new document to insert.
+    new_lo: usize,
+    /// The line of the new document after the last one to insert.
+    new_hi: usize,
+}
+
+/// Return the list of hunks that transform `old` into `new`, in order.
+fn hunks(old: &[&str], new: &[&str]) -> Vec<Hunk> {
+    let mut result = Vec::new();
+    let (mut old_lo, mut new_lo) = (0, 0);
+    let end = std::iter::once((old.len(), new.len()));
+    for (i, j) in matching_lines(old, new).into_iter().chain(end) {
+        if i > old_lo || j > new_lo {
+            result.push(Hunk {
+                old_lo,
+                old_hi: i,
+                new_lo,
+                new_hi: j,
+            });
+        }
+        old_lo = i + 1;
+        new_lo = j + 1;
+    }
+    result
+}
+
+/// Return every pair of 0-indexed line numbers `(i, j)` such that `old[i]`
+/// should be kept as `new[j]`, in increasing order.
+fn matching_lines(old: &[&str], new: &[&str]) -> Vec<(usize, usize)> {
+    let mut matches = Vec::new();
+    // Regions that we still have to match, as (old_lo, old_hi, new_lo, new_hi).
+    //
+    // We use an explicit stack rather than recursion, so that a pathological
+    // input can't exhaust our real one.
+    let mut regions = vec![(0, old.len(), 0, new.len())];
+
+    while let Some((mut old_lo, mut old_hi, mut new_lo, mut new_hi)) = regions.pop() {
+        // Any lines that the regions start or end with in common are matches.
+        while old_lo < old_hi && new_lo < new_hi && old[old_lo] == new[new_lo] {
+            matches.push((old_lo, new_lo));
+            old_lo += 1;
+            new_lo += 1;
+        }
+        while old_lo < old_hi && new_lo < new_hi && old[old_hi - 1] == new[new_hi - 1] {
+            old_hi -= 1;
+            new_hi -= 1;
+            matches.push((old_hi, new_hi));
+        }
+        if old_lo == old_hi || new_lo == new_hi {
+            continue;
+        }
+
+        let old_region = &old[old_lo..old_hi];
+        let new_region = &new[new_lo..new_hi];
+        let anchors = unique_common_lines(old_region, new_region);
+        if anchors.is_empty() {
+            let lcs = exact_lcs(old_region, new_region).unwrap_or_default();
+            matches.extend(lcs.into_iter().map(|(i, j)| (i + old_lo, j + new_lo)));
+            continue;
+        }
+
+        let (mut prev_old, mut prev_new) = (old_lo, new_lo);
+        for (i, j) in anchors {
+            let (i, j) = (i + old_lo, j + new_lo);
+            regions.push((prev_old, i, prev_new, j));
+            matches.push((i, j));
+            prev_old = i + 1;
+            prev_new = j + 1;
+        }
+        regions.push((prev_old, old_hi, prev_new, new_hi));
+    }
+
+    matches.sort_unstable();
+    matches
+}
+
+/// Find the lines that appear exactly once in each of `old` and `new`, and
+/// return the longest sequence of them that appears in the same order in
+/// both, as pairs of indices.
+fn unique_common_lines(old: &[&str], new: &[&str]) -> Vec<(usize, usize)> {
+    /// How many times we've seen a line, and where we saw it last, in each
+    /// document.
+    #[derive(Default)]
+    struct Seen {
+        /// The number of times the line appears in `old`.
+        n_old: usize,
+        /// The index of the line in `old`.
+        old_idx: usize,
+        /// The number of times the line appears in `new`.
+        n_new: usize,
+        /// The index of the line in `new`.
+        new_idx: usize,
+    }
+
+    let mut seen: HashMap<&str, Seen> = HashMap::new();
+    for (i, line) in old.iter().enumerate() {
+        let s = seen.entry(line).or_default();
+        s.n_old += 1;
+        s.old_idx = i;
+    }
+    for (j, line) in new.iter().enumerate() {
+        if let Some(s) = seen.get_mut(line) {
+            s.n_new += 1;
+            s.new_idx = j;
+        }
+    }
+    let mut candidates: Vec<(usize, usize)> = seen
+        .values()
+        .filter(|s| s.n_old == 1 && s.n_new == 1)
+        .map(|s| (s.old_idx, s.new_idx))
+        .collect();
+    candidates.sort_unstable();
+
+    longest_increasing_by_new(&candidates)
+}
+
+/// Given a list of `(old, new)` index pairs sorted by `old`, return the
+/// longest subsequence of them in which `new` is also increasing.
+///
+/// (This is the "patience sorting" step of a patience diff.)
+fn longest_increasing_by_new(pairs: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    // tails[k] is the index in `pairs` of the smallest-ending increasing
+    // subsequence of length k+1 that we've found so far.
+    let mut tails: Vec<usize> = Vec::new();
+    // prev[i] is the index in `pairs` of the element before pairs[i] in the
+    // best subsequence ending at pairs[i].
+    let mut prev: Vec<Option<usize>> = Vec::with_capacity(pairs.len());
+
+    for (i, &(_, new_idx)) in pairs.iter().enumerate() {
+        let k = tails.partition_point(|&t| pairs[t].1 < new_idx);
+        prev.push(k.checked_sub(1).map(|k| tails[k]));
+        if k == tails.len() {
+            tails.push(i);
+        } else {
+            tails[k] = i;
+        }
+    }
+
+    let mut result = Vec::with_capacity(tails.len());
+    let mut cur = tails.last().copied();
+    while let Some(i) = cur {
+        result.push(pairs[i]);
+        cur = prev[i];
+    }
+    result.reverse();
+    result
+}
+
+/// Return the longest common subsequence of `old` and `new`, as pairs of
+/// indices, or None if the regions are too large to search exactly.
+fn exact_lcs(old: &[&str], new: &[&str]) -> Option<Vec<(usize, usize)>> {
+    let (n, m) = (old.len(), new.len());
+    if n.checked_mul(m)? > MAX_LCS_CELLS {
+        return None;
+    }
+    // len[i * (m+1) + j] is the length of the LCS of old[i..] and new[j..].
+    let width = m + 1;
+    let mut len = vec![0_u32; (n + 1) * width];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            len[i * width + j] = if old[i] == new[j] {
+                len[(i + 1) * width + j + 1] + 1
+            } else {
+                len[(i + 1) * width + j].max(len[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut result = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if old[i] == new[j] {
+            result.push((i, j));
+            i += 1;
+            j += 1;
+        } else if len[(i + 1) * width + j] >= len[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::{apply_diff, apply_diff_trivial};
+
+    /// Generate a diff from `old` to `new`, make sure it applies correctly,
+    /// and return it.
+    fn roundtrip(old: &str, new: &str) -> String {
+        let d1 = [0x42; 32];
+        let diff = generate_diff(old, new, d1).unwrap();
+
+        let result = apply_diff(old, &diff, Some(d1)).unwrap();
+        result.check_digest().unwrap();
+        assert_eq!(result.to_string(), new);
+
+        let result = apply_diff_trivial(old, &diff).unwrap();
+        result.check_digest().unwrap();
+        assert_eq!(result.to_string(), new);
+
+        diff
+    }
+
+    /// Return the commands in `diff`, without its header.
+    fn body(diff: &str) -> Vec<&str> {
+        diff.lines().skip(2).collect()
+    }
+
+    #[test]
+    fn simple() {
+        let diff = roundtrip("a\nb\nc\n", "a\nb\nc\n");
+        assert!(body(&diff).is_empty());
+
+        let diff = roundtrip("a\nb\nc\n", "a\nc\n");
+        assert_eq!(body(&diff), ["2d"]);
+
+        let diff = roundtrip("a\nb\nc\n", "a\nB\nc\n");
+        assert_eq!(body(&diff), ["2c", "B", "."]);
+
+        let diff = roundtrip("a\nb\nc\n", "z\na\nb\nc\n");
+        assert_eq!(body(&diff), ["0a", "z", "."]);
+
+        let diff = roundtrip("a\nb\nc\nd\ne\n", "a\ne\nf\n");
+        assert_eq!(body(&diff), ["5a", "f", ".", "2,4d"]);
+
+        roundtrip("", "a\nb\n");
+        roundtrip("a\nb\n", "");
+        roundtrip("", "");
+    }
+
+    #[test]
+    fn repeated_lines() {
+        // With no unique lines, we fall back to an exact search.
+        roundtrip("x\nx\ny\nx\ny\n", "y\nx\nx\ny\nx\n");
+        roundtrip("s Fast\ns Fast\ns Fast\n", "s Fast\ns Exit Fast\ns Fast\n");
+    }
+
+    #[test]
+    fn consensus_like() {
+        // Build a pair of documents that look a bit like consensuses: each
+        // relay has a unique "r" line, and some repetitive lines.
+        let relay = |i: usize, bw: usize| {
+            format!("r relay{i} ID{i}\ns Fast Running Valid\nw Bandwidth={bw}\n")
+        };
+        let old: String = (0..200).map(|i| relay(i, i % 7)).collect();
+        let new: String = (0..200)
+            .filter(|i| i % 17 != 3)
+            .map(|i| relay(i, if i % 5 == 0 { 99 } else { i % 7 }))
+            .chain((200..205).map(|i| relay(i, 1)))
+            .collect();
+        let diff = roundtrip(&old, &new);
+        // Only the changed parts should be in the diff.
+        assert!(diff.len() < new.len() / 4);
+
+        roundtrip(&new, &old);
+    }
+
+    #[test]
+    fn lone_period() {
+        assert!(generate_diff("a\n", "a\n.\n", [0; 32]).is_err());
+    }
+
+    #[test]
+    fn lis() {
+        let pairs = [(0, 3), (1, 1), (2, 2), (3, 0), (4, 4)];
+        assert_eq!(
+            longest_increasing_by_new(&pairs),
+            vec![(1, 1), (2, 2), (4, 4)]
+        );
+        assert!(longest_increasing_by_new(&[]).is_empty());
+    }
+}
@@ -47,7 +47,9 @@ use std::num::NonZeroUsize;
 use std::str::FromStr;
 
 mod err;
+mod gen;
 pub use err::Error;
+pub use gen::generate_diff;
 
 /// Result type used by this crate
 type Result<T> = std::result::Result<T, Error>;
@@ -166,6 +166,11 @@ impl ConsensusRequest {
         self.last_consensus_published = Some(when);
     }
 
+    /// Return the flavor of consensus that this request is for.
+    pub fn flavor(&self) -> ConsensusFlavor {
+        self.flavor
+    }
+
     /// Return a slice of the consensus digests that we're saying we
     /// already have.
     pub fn old_consensus_digests(&self) -> impl Iterator<Item = &[u8; 32]> {
@@ -559,6 +564,101 @@ impl sealed::RequestableInner for RoutersOwnDescRequest {
     }
 }
 
+/// A request for one or more votes from a directory authority.
+///
+/// Only directory authorities serve votes, and only for the current voting
+/// period.
+#[derive(Debug, Clone, Default)]
+pub struct VoteRequest {
+    /// The SHA1 digests of the votes we want.
+    ///
+    /// If this is empty, we want the vote of the authority that we're asking.
+    digests: Vec<[u8; 20]>,
+}
+
+impl VoteRequest {
+    /// Construct a request for the vote of the authority that we make the
+    /// request to.
+    pub fn new() -> Self {
+        VoteRequest::default()
+    }
+
+    /// Add `d` to the list of vote digests that we're asking for.
+    ///
+    /// Once we have added any digests, the request no longer asks for the
+    /// authority's own vote, unless its digest is in the list.
+    pub fn push(&mut self, d: [u8; 20]) {
+        self.digests.push(d);
+    }
+
+    /// Return an iterator over the vote digests that we're asking for.
+    pub fn digests(&self) -> impl Iterator<Item = &[u8; 20]> {
+        self.digests.iter()
+    }
+}
+
+impl sealed::RequestableInner for VoteRequest {
+    fn make_request(&self) -> Result<http::Request<String>> {
+        let uri = match digest_list_stringify(&self.digests, hex::encode, "+") {
+            Some(ids) => format!("/tor/status-vote/current/d/{}.z", ids),
+            None => "/tor/status-vote/current/authority.z".to_string(),
+        };
+        let req = http::Request::builder().method("GET").uri(uri);
+        let req = add_common_headers(req, self.anonymized());
+
+        Ok(req.body(String::new())?)
+    }
+
+    fn partial_response_body_ok(&self) -> bool {
+        self.digests.len() > 1
+    }
+
+    fn max_response_len(&self) -> usize {
+        // A vote is a little larger than an ns consensus.
+        self.digests.len().max(1).saturating_mul(16 * 1024 * 1024)
+    }
+
+    fn anonymized(&self) -> AnonymizedRequest {
+        AnonymizedRequest::Direct
+    }
+}
+
+/// A request for the detached signatures that a directory authority has
+/// collected on the current consensus.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct DetachedSignaturesRequest {}
+
+impl DetachedSignaturesRequest {
+    /// Construct a new request.
+    pub fn new() -> Self {
+        DetachedSignaturesRequest::default()
+    }
+}
+
+impl sealed::RequestableInner for DetachedSignaturesRequest {
+    fn make_request(&self) -> Result<http::Request<String>> {
+        let uri = "/tor/status-vote/current/consensus-signatures.z";
+        let req = http::Request::builder().method("GET").uri(uri);
+        let req = add_common_headers(req, self.anonymized());
+
+        Ok(req.body(String::new())?)
+    }
+
+    fn partial_response_body_ok(&self) -> bool {
+        false
+    }
+
+    fn max_response_len(&self) -> usize {
+        // TODO: Pick a more principled number; I just made this one up.
+        1024 * 1024
+    }
+
+    fn anonymized(&self) -> AnonymizedRequest {
+        AnonymizedRequest::Direct
+    }
+}
+
 /// A request to download a hidden service descriptor
 ///
 /// rend-spec-v3 2.2.6
@@ -661,6 +761,53 @@ impl sealed::RequestableInner for HsDescUploadRequest {
     }
 }
 
+/// A request that sends another [`Requestable`] with a `Host` header.
+///
+/// Directory caches don't look at the `Host` header, but an ordinary web
+/// server that mirrors directory documents will usually need one.
+#[derive(Debug, Clone)]
+pub struct WithHostHeader<'a, R: ?Sized> {
+    /// The request to send.
+    inner: &'a R,
+    /// The value of the `Host` header.
+    host: String,
+}
+
+impl<'a, R: Requestable + ?Sized> WithHostHeader<'a, R> {
+    /// Return a request that sends `inner` with `host` in its `Host` header.
+    pub fn new(inner: &'a R, host: impl Into<String>) -> Self {
+        WithHostHeader {
+            inner,
+            host: host.into(),
+        }
+    }
+}
+
+impl<R: Requestable + ?Sized> sealed::RequestableInner for WithHostHeader<'_, R> {
+    fn make_request(&self) -> Result<http::Request<String>> {
+        let mut req = self.inner.make_request()?;
+        let host = http::HeaderValue::from_str(&self.host).map_err(http::Error::from)?;
+        req.headers_mut().insert(http::header::HOST, host);
+        Ok(req)
+    }
+
+    fn partial_response_body_ok(&self) -> bool {
+        self.inner.partial_response_body_ok()
+    }
+
+    fn max_response_len(&self) -> usize {
+        self.inner.max_response_len()
+    }
+
+    fn check_circuit(&self, circ: &ClientCirc) -> Result<()> {
+        self.inner.check_circuit(circ)
+    }
+
+    fn anonymized(&self) -> AnonymizedRequest {
+        self.inner.anonymized()
+    }
+}
+
 /// Encodings that all Tor clients support.
 const UNIVERSAL_ENCODINGS: &str = "deflate, identity";
 
@@ -817,6 +964,61 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_with_host_header() -> Result<()> {
+        let inner = ConsensusRequest::default();
+        let req = WithHostHeader::new(&inner, "mirror.example.com");
+        assert_eq!(
+            req.partial_response_body_ok(),
+            inner.partial_response_body_ok()
+        );
+        assert_eq!(req.max_response_len(), inner.max_response_len());
+        let req = crate::util::encode_request(&req.make_request()?);
+        assert_eq!(req,
+                   format!("GET /tor/status-vote/current/consensus-microdesc.z HTTP/1.0\r\naccept-encoding: {}\r\nhost: mirror.example.com\r\n\r\n", all_encodings()));
+
+        let req = ConsensusRequest::default();
+        let req = WithHostHeader::new(&req, "bad\r\nhost");
+        assert!(req.make_request().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vote_request() -> Result<()> {
+        let req = VoteRequest::new();
+        assert!(!req.partial_response_body_ok());
+        assert_eq!(req.max_response_len(), 16 << 20);
+        let req = crate::util::encode_request(&req.make_request()?);
+        assert_eq!(
+            req,
+            format!(
+                "GET /tor/status-vote/current/authority.z HTTP/1.0\r\naccept-encoding: {}\r\n\r\n",
+                all_encodings()
+            )
+        );
+
+        let mut req = VoteRequest::new();
+        req.push(*b"of writing in hex...");
+        req.push(*b"at some point I got ");
+        assert!(req.partial_response_body_ok());
+        assert_eq!(req.digests().count(), 2);
+        let req = crate::util::encode_request(&req.make_request()?);
+        assert_eq!(req,
+                   format!("GET /tor/status-vote/current/d/617420736f6d6520706f696e74204920676f7420+6f662077726974696e6720696e206865782e2e2e.z HTTP/1.0\r\naccept-encoding: {}\r\n\r\n", all_encodings()));
+
+        let req = DetachedSignaturesRequest::new();
+        let req = crate::util::encode_request(&req.make_request()?);
+        assert_eq!(
+            req,
+            format!(
+                "GET /tor/status-vote/current/consensus-signatures.z HTTP/1.0\r\naccept-encoding: {}\r\n\r\n",
+                all_encodings()
+            )
+        );
+        Ok(())
+    }
+
     #[test]
     #[cfg(feature = "routerdesc")]
     fn test_rd_request_all() -> Result<()> {
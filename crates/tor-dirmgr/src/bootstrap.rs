@@ -4,13 +4,17 @@
 use std::num::NonZeroUsize;
 use std::ops::Deref;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     sync::{Arc, Weak},
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 use crate::err::BootstrapAction;
+use crate::event::RequestRecord;
+use crate::failreport;
+use crate::mdprogress::MdDownloadProgress;
 use crate::state::{DirState, PoisonedState};
+use crate::timing;
 use crate::DirMgrConfig;
 use crate::DocSource;
 use crate::{
@@ -18,11 +22,16 @@ use crate::{
     upgrade_weak_ref, DirMgr, DocId, DocQuery, DocumentText, Error, Readiness, Result,
 };
 
-use futures::FutureExt;
+use async_trait::async_trait;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::stream::FuturesUnordered;
 use futures::StreamExt;
+use futures::{Future, FutureExt};
 use oneshot_fused_workaround as oneshot;
 use tor_dirclient::DirResponse;
 use tor_error::{info_report, warn_report};
+use tor_linkspec::HasRelayIds as _;
+use tor_llcrypto::pk::rsa::RsaIdentity;
 use tor_rtcompat::scheduler::TaskSchedule;
 use tor_rtcompat::Runtime;
 use tracing::{debug, info, trace, warn};
@@ -271,21 +280,128 @@ pub(crate) fn make_requests_for_documents<R: Runtime>(
     Ok(res)
 }
 
+/// Construct a set of `ClientRequest`s in order to fetch the documents in
+/// `docs`, as [`make_requests_for_documents`] does.
+///
+/// But if `md_download_key` is provided, we are downloading the
+/// microdescriptors for the consensus that it names: in that case, we batch
+/// them by resuming from any progress that we saved in `store`, and we return
+/// the progress that we should save next.  Its batches are the first requests
+/// that we return.
+fn make_resumable_requests<R: Runtime>(
+    rt: &R,
+    docs: &[DocId],
+    store: &dyn Store,
+    config: &DirMgrConfig,
+    md_download_key: Option<[u8; 32]>,
+) -> Result<(Vec<ClientRequest>, Option<MdDownloadProgress>)> {
+    let Some(key) = md_download_key else {
+        return Ok((make_requests_for_documents(rt, docs, store, config)?, None));
+    };
+    let mut mds = Vec::new();
+    let mut others = Vec::new();
+    for doc in docs {
+        match doc {
+            DocId::Microdesc(digest) => mds.push(*digest),
+            _ => others.push(*doc),
+        }
+    }
+    let saved = store.md_download_progress(&key).unwrap_or_else(|e| {
+        warn_report!(e, "Unable to load saved microdescriptor download progress");
+        None
+    });
+    let progress = MdDownloadProgress::plan(key, saved, &mds);
+    let mut requests = progress.requests();
+    requests.extend(make_requests_for_documents(rt, &others, store, config)?);
+    Ok((requests, Some(progress)))
+}
+
+/// Save `progress` in the store of `dirmgr`, if we can.
+fn save_md_download_progress<R: Runtime>(dirmgr: &DirMgr<R>, progress: &MdDownloadProgress) {
+    let mut store = dirmgr.store.lock().expect("store lock poisoned");
+    if store.is_readonly() {
+        return;
+    }
+    if let Err(e) = store.store_md_download_progress(progress) {
+        warn_report!(e, "Unable to save microdescriptor download progress");
+    }
+}
+
+/// A stream over which we can send a single directory request.
+///
+/// (This trait exists so that we can make trait objects for any type that is
+/// both `AsyncRead` and `AsyncWrite`.)
+pub(crate) trait DirStream: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> DirStream for S {}
+
+/// An object that can fetch directory documents without going through Tor.
+///
+/// Normally, a [`DirMgr`] fetches its documents from directory caches, over
+/// one-hop circuits that it gets from its circuit manager.  Where that isn't
+/// possible, but some other copy of the directory is reachable, install a
+/// `DocumentFetcher` with
+/// [`DirMgrExtensions::fetcher`](crate::config::DirMgrExtensions::fetcher),
+/// and we'll use it for all of our requests instead.
+///
+/// We check every document that a fetcher gives us exactly as we would check
+/// one from a directory cache, so a fetcher does not need to be trusted: a
+/// fetcher that returns bad documents can stop us from bootstrapping, but it
+/// can't make us accept a directory that our authorities didn't sign.
+///
+/// See [`LocalDirFetcher`](crate::LocalDirFetcher) and
+/// [`HttpsMirrorFetcher`](crate::HttpsMirrorFetcher) for the implementations
+/// that we provide.
+#[async_trait]
+pub trait DocumentFetcher: std::fmt::Debug + Send + Sync {
+    /// Fetch the documents described by `request`, and return the response.
+    ///
+    /// The response should hold the documents as a directory cache would
+    /// return them, already decompressed.  It's okay to return a response
+    /// that only contains some of the requested documents.
+    async fn fetch(&self, request: &ClientRequest) -> Result<DirResponse>;
+}
+
+/// The means by which we send a directory request.
+enum Transport<R: Runtime> {
+    /// Ask the circuit manager for a circuit to a directory cache, and open a
+    /// stream on it.
+    CircMgr(Arc<CircMgr<R>>),
+    /// Use a stream that the caller gave us with
+    /// [`DirMgr::use_stream_for_next_fetch`].
+    Supplied(Box<dyn DirStream>),
+    /// Use a [`DocumentFetcher`] from our configuration.
+    Fetcher(Arc<dyn DocumentFetcher>),
+}
+
 /// Launch a single client request and get an associated response.
 async fn fetch_single<R: Runtime>(
     rt: &R,
     request: ClientRequest,
     current_netdir: Option<&NetDir>,
-    circmgr: Arc<CircMgr<R>>,
+    transport: Transport<R>,
 ) -> Result<(ClientRequest, DirResponse)> {
-    let dirinfo: DirInfo = match current_netdir {
-        Some(netdir) => netdir.into(),
-        None => tor_circmgr::DirInfo::Nothing,
+    let outcome = match transport {
+        Transport::CircMgr(circmgr) => {
+            let dirinfo: DirInfo = match current_netdir {
+                Some(netdir) => netdir.into(),
+                None => tor_circmgr::DirInfo::Nothing,
+            };
+            let outcome =
+                tor_dirclient::get_resource(request.as_requestable(), dirinfo, rt, circmgr.clone())
+                    .await;
+            note_request_outcome(&circmgr, &outcome);
+            outcome
+        }
+        Transport::Supplied(mut stream) => {
+            // We don't know anything about where this stream goes, so we
+            // can't blame (or credit) any cache for the outcome.
+            tor_dirclient::send_request(rt, request.as_requestable(), &mut stream, None).await
+        }
+        Transport::Fetcher(fetcher) => {
+            // As above, there's no cache to blame or credit.
+            return fetcher.fetch(&request).await.map(|resp| (request, resp));
+        }
     };
-    let outcome =
-        tor_dirclient::get_resource(request.as_requestable(), dirinfo, rt, circmgr.clone()).await;
-
-    note_request_outcome(&circmgr, &outcome);
 
     let resource = outcome?;
     Ok((request, resource))
@@ -303,20 +419,55 @@ static CANNED_RESPONSE: Lazy<Mutex<Vec<String>>> = Lazy::new(|| Mutex::new(vec![
 /// `missing`, and return each request along with the response it received.
 ///
 /// Don't launch more than `parallelism` requests at once.
+///
+/// If a request for microdescriptors fails, we send it again (up to
+/// [`microdesc_failovers`](crate::DownloadScheduleConfig) times) while the
+/// other requests are still running, rather than waiting for the next
+/// attempt.  By then we have told the circuit manager about the failure, so
+/// the new request will usually go to a different cache.
 async fn fetch_multiple<R: Runtime>(
     dirmgr: Arc<DirMgr<R>>,
     attempt_id: AttemptId,
     missing: &[DocId],
+    md_download_key: Option<[u8; 32]>,
     parallelism: usize,
 ) -> Result<Vec<(ClientRequest, DirResponse)>> {
-    let requests = {
+    let config = dirmgr.config.get();
+    let (requests, mut progress) = {
         let store = dirmgr.store.lock().expect("store lock poisoned");
-        make_requests_for_documents(&dirmgr.runtime, missing, &**store, &dirmgr.config.get())?
+        make_resumable_requests(&dirmgr.runtime, missing, &**store, &config, md_download_key)?
     };
+    if let Some(progress) = &progress {
+        save_md_download_progress(&dirmgr, progress);
+    }
 
     trace!(attempt=%attempt_id, "Launching {} requests for {} documents",
            requests.len(), missing.len());
 
+    // Only use timely directories for bootstrapping directories; otherwise, we'll try fallbacks.
+    let netdir = dirmgr.netdir(tor_netdir::Timeliness::Timely).ok();
+    let started = (dirmgr.runtime.wallclock(), dirmgr.runtime.now());
+
+    if !requests.is_empty() {
+        if let Some(stream) = dirmgr.take_supplied_stream() {
+            // A supplied stream can only carry a single request: send our first
+            // request over it, and leave the rest for a later attempt.
+            trace!(attempt=%attempt_id, "Using a caller-supplied stream for this download.");
+            let query = requests.into_iter().next().expect("requests was empty");
+            let fetch = fetch_single(
+                &dirmgr.runtime,
+                query,
+                netdir.as_deref(),
+                Transport::Supplied(stream),
+            );
+            let outcomes = vec![timed_fetch(&dirmgr.runtime, Duration::ZERO, fetch).await];
+            return Ok(useful_responses(
+                attempt_id,
+                note_outcomes(&dirmgr, attempt_id, started, outcomes),
+            ));
+        }
+    }
+
     #[cfg(test)]
     {
         let m = CANNED_RESPONSE.lock().expect("Poisoned mutex");
@@ -328,18 +479,189 @@ async fn fetch_multiple<R: Runtime>(
         }
     }
 
-    let circmgr = dirmgr.circmgr()?;
-    // Only use timely directories for bootstrapping directories; otherwise, we'll try fallbacks.
-    let netdir = dirmgr.netdir(tor_netdir::Timeliness::Timely).ok();
+    let transport = transports(&dirmgr, &config)?;
 
     // TODO: instead of waiting for all the queries to finish, we
     // could stream the responses back or something.
-    let responses: Vec<Result<(ClientRequest, DirResponse)>> = futures::stream::iter(requests)
-        .map(|query| fetch_single(&dirmgr.runtime, query, netdir.as_deref(), circmgr.clone()))
-        .buffer_unordered(parallelism)
-        .collect()
-        .await;
+    let timing = timing::policy(&config);
+    let runtime = &dirmgr.runtime;
+    let n_requests = requests.len();
+    let max_failovers = config.schedule.microdesc_failovers;
+
+    // Launch `query`, which is the request at position `idx`, and which has
+    // already failed `n_failed` times.  Keep a copy of it if we might need to
+    // send it again.
+    let launch = |idx: usize, query: ClientRequest, n_failed: u8| {
+        let spare = (n_failed < max_failovers && matches!(query, ClientRequest::Microdescs(_)))
+            .then(|| query.clone());
+        let delay = if n_failed == 0 {
+            timing.request_delay(idx, n_requests)
+        } else {
+            Duration::ZERO
+        };
+        let fetch = fetch_single(runtime, query, netdir.as_deref(), transport());
+        timed_fetch(runtime, delay, fetch).map(move |outcome| (outcome, idx, spare, n_failed))
+    };
 
+    let mut queue: VecDeque<(usize, ClientRequest, u8)> = requests
+        .into_iter()
+        .enumerate()
+        .map(|(idx, query)| (idx, query, 0))
+        .collect();
+    let mut running = FuturesUnordered::new();
+    let mut outcomes = Vec::with_capacity(n_requests);
+    loop {
+        while running.len() < parallelism {
+            let Some((idx, query, n_failed)) = queue.pop_front() else {
+                break;
+            };
+            running.push(launch(idx, query, n_failed));
+        }
+        let Some((outcome, idx, spare, n_failed)) = running.next().await else {
+            break;
+        };
+        let succeeded = matches!(&outcome.0, Ok((_, resp)) if resp.status_code() == 200);
+        if !succeeded {
+            if let Some(progress) = &mut progress {
+                progress.note_failure(idx);
+            }
+        }
+        if let (false, Some(spare)) = (succeeded, spare) {
+            debug!(attempt=%attempt_id, "Microdescriptor request failed; sending it again.");
+            queue.push_back((idx, spare, n_failed + 1));
+        }
+        outcomes.push(outcome);
+    }
+    if let Some(progress) = &progress {
+        save_md_download_progress(&dirmgr, progress);
+    }
+
+    let responses = note_outcomes(&dirmgr, attempt_id, started, outcomes);
+    Ok(useful_responses(attempt_id, responses))
+}
+
+/// Return a function that gives us a [`Transport`] for each request that we
+/// send with `dirmgr` under `config`.
+///
+/// If we have a fetcher, we use it instead of the circuit manager; if we have
+/// a fallback fetcher, and have failed to bootstrap often enough, we use that.
+/// (We never use a supplied stream here: the caller has to check for one.)
+fn transports<R: Runtime>(
+    dirmgr: &DirMgr<R>,
+    config: &DirMgrConfig,
+) -> Result<Box<dyn Fn() -> Transport<R> + Send + Sync>> {
+    #[cfg(feature = "proxy-fallback")]
+    if let Some(fallback) = &config.extensions.fallback_fetcher {
+        if config.extensions.fetcher.is_none() && dirmgr.should_use_fallback_fetcher(fallback) {
+            info!("Unable to bootstrap a directory; using our fallback fetcher instead.");
+            let fetcher = Arc::clone(&fallback.fetcher);
+            return Ok(Box::new(move || Transport::Fetcher(Arc::clone(&fetcher))));
+        }
+    }
+    Ok(match config.extensions.fetcher.clone() {
+        Some(fetcher) => Box::new(move || Transport::Fetcher(Arc::clone(&fetcher))),
+        None => {
+            let circmgr = dirmgr.circmgr()?;
+            Box::new(move || Transport::CircMgr(Arc::clone(&circmgr)))
+        }
+    })
+}
+
+/// Send a single `request` with `dirmgr`, as we would for a download, and
+/// return the response.
+///
+/// Unlike a download, this doesn't use a supplied stream, doesn't affect our
+/// bootstrap status, and doesn't look at the response.
+pub(crate) async fn probe<R: Runtime>(
+    dirmgr: &DirMgr<R>,
+    request: ClientRequest,
+) -> Result<DirResponse> {
+    let config = dirmgr.config.get();
+    let transport = transports(dirmgr, &config)?;
+    let netdir = dirmgr.netdir(tor_netdir::Timeliness::Timely).ok();
+    fetch_single(&dirmgr.runtime, request, netdir.as_deref(), transport())
+        .await
+        .map(|(_, response)| response)
+}
+
+/// Wait for `delay`, then run `fetch`, and return its outcome along with how
+/// long it took (not counting the delay).
+async fn timed_fetch<R: Runtime>(
+    runtime: &R,
+    delay: Duration,
+    fetch: impl Future<Output = Result<(ClientRequest, DirResponse)>>,
+) -> (Result<(ClientRequest, DirResponse)>, Duration) {
+    if !delay.is_zero() {
+        runtime.sleep(delay).await;
+    }
+    let start = runtime.now();
+    let outcome = fetch.await;
+    (outcome, runtime.now().saturating_duration_since(start))
+}
+
+/// Record the outcomes of a set of requests, which we launched at `started`,
+/// in the bootstrap status of `dirmgr`; then return the outcomes without their
+/// timings.
+fn note_outcomes<R: Runtime>(
+    dirmgr: &DirMgr<R>,
+    attempt_id: AttemptId,
+    started: (SystemTime, Instant),
+    outcomes: Vec<(Result<(ClientRequest, DirResponse)>, Duration)>,
+) -> Vec<Result<(ClientRequest, DirResponse)>> {
+    let records: Vec<_> = outcomes
+        .iter()
+        .map(|(outcome, elapsed)| request_record(outcome, *elapsed))
+        .collect();
+    let elapsed = dirmgr.runtime.now().saturating_duration_since(started.1);
+    dirmgr.note_requests(attempt_id, started.0, elapsed, &records);
+    let failures: Vec<_> = outcomes
+        .iter()
+        .zip(&records)
+        .map(|((outcome, _), record)| (record.cache, failreport::classify(outcome)))
+        .collect();
+    dirmgr.note_request_failures(started.0, &failures);
+    outcomes.into_iter().map(|(outcome, _)| outcome).collect()
+}
+
+/// Describe the outcome of a single request, which took `elapsed`, for our
+/// bootstrap metrics.
+fn request_record(
+    outcome: &Result<(ClientRequest, DirResponse)>,
+    elapsed: Duration,
+) -> RequestRecord {
+    use tor_dirclient::{Error::RequestFailed, RequestFailedError};
+    /// Return the RSA identity of the cache described in `source`.
+    fn cache_rsa_id(source: &tor_dirclient::SourceInfo) -> Option<RsaIdentity> {
+        source.cache_id().rsa_identity().copied()
+    }
+    match outcome {
+        Ok((_, response)) => RequestRecord {
+            cache: response.source().and_then(cache_rsa_id),
+            n_bytes: response.output_unchecked().len(),
+            ok: response.status_code() == 200 && !response.is_partial(),
+            elapsed,
+        },
+        Err(e) => RequestRecord {
+            cache: match e {
+                Error::DirClientError(RequestFailed(RequestFailedError {
+                    source: Some(source),
+                    ..
+                })) => cache_rsa_id(source),
+                _ => None,
+            },
+            n_bytes: 0,
+            ok: false,
+            elapsed,
+        },
+    }
+}
+
+/// Discard every failed or declined response in `responses`, and return the
+/// rest.
+fn useful_responses(
+    attempt_id: AttemptId,
+    responses: Vec<Result<(ClientRequest, DirResponse)>>,
+) -> Vec<(ClientRequest, DirResponse)> {
     let mut useful_responses = Vec::new();
     for r in responses {
         // TODO: on some error cases we might want to stop using this source.
@@ -360,7 +682,7 @@ async fn fetch_multiple<R: Runtime>(
 
     trace!(attempt=%attempt_id, "received {} useful responses from our requests.", useful_responses.len());
 
-    Ok(useful_responses)
+    useful_responses
 }
 
 /// Try to update `state` by loading cached information from `dirmgr`.
@@ -461,7 +783,14 @@ async fn download_attempt<R: Runtime>(
     attempt_id: AttemptId,
 ) -> Result<()> {
     let missing = state.missing_docs();
-    let fetched = fetch_multiple(Arc::clone(dirmgr), attempt_id, &missing, parallelism).await?;
+    let fetched = fetch_multiple(
+        Arc::clone(dirmgr),
+        attempt_id,
+        &missing,
+        state.md_download_key(),
+        parallelism,
+    )
+    .await?;
     let mut n_errors = 0;
     for (client_req, dir_response) in fetched {
         let source = dir_response.source().cloned();
@@ -604,7 +933,11 @@ pub(crate) async fn download<R: Runtime>(
             // We wait at the start of this loop, on all attempts but the first.
             // This ensures that we always wait between attempts, but not after
             // the final attempt.
-            let next_delay = retry.next_delay(&mut rand::thread_rng());
+            let next_delay = {
+                let dirmgr = upgrade_weak_ref(&dirmgr)?;
+                let config = dirmgr.config.get();
+                timing::policy(&config).retry_delay(&mut retry)
+            };
             if let Some(delay) = delay.replace(next_delay) {
                 let time_until_reset = {
                     reset_time
@@ -628,6 +961,7 @@ pub(crate) async fn download<R: Runtime>(
 
             now = {
                 let dirmgr = upgrade_weak_ref(&dirmgr)?;
+                dirmgr.note_download_attempt();
                 futures::select_biased! {
                     outcome = download_attempt(&dirmgr, state, parallelism.into(), attempt_id).fuse() => {
                         if let Err(e) = outcome {
@@ -734,6 +1068,7 @@ mod test {
     #![allow(clippy::needless_pass_by_value)]
     //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
     use super::*;
+    use crate::fetcher::test::answer_request;
     use crate::storage::DynStore;
     use crate::test::new_mgr;
     use crate::DownloadSchedule;
@@ -965,4 +1300,227 @@ mod test {
             assert!(state.is_ready(Readiness::Complete));
         });
     }
+
+    #[test]
+    fn supplied_stream() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+            let mgr = Arc::new(mgr);
+            let (client, mut server) = tor_rtmock::io::stream_pair();
+            mgr.use_stream_for_next_fetch(client);
+
+            let missing = [DocId::Microdesc(H1), DocId::Microdesc(H2)];
+            let (fetched, request) = futures::join!(
+                fetch_multiple(Arc::clone(&mgr), AttemptId::next(), &missing, None, 4),
+                answer_request(&mut server, b"HTTP/1.0 200 OK\r\n\r\nhello")
+            );
+
+            assert!(request.starts_with("GET /tor/micro/d/"));
+            let fetched = fetched.unwrap();
+            assert_eq!(fetched.len(), 1);
+            let (req, resp) = fetched.into_iter().next().unwrap();
+            assert!(matches!(req, ClientRequest::Microdescs(_)));
+            assert!(resp.source().is_none());
+            assert_eq!(resp.into_output_unchecked(), b"hello");
+
+            // The stream is only used once.
+            assert!(mgr.take_supplied_stream().is_none());
+        });
+    }
+
+    /// A [`DocumentFetcher`] that remembers its requests, and answers each
+    /// one with a fixed body.
+    #[derive(Debug, Default)]
+    struct RecordingFetcher {
+        requests: Mutex<Vec<ClientRequest>>,
+    }
+
+    #[async_trait]
+    impl DocumentFetcher for RecordingFetcher {
+        async fn fetch(&self, request: &ClientRequest) -> Result<DirResponse> {
+            self.requests.lock().unwrap().push(request.clone());
+            Ok(DirResponse::from_body("fetched"))
+        }
+    }
+
+    #[test]
+    fn configured_fetcher() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+            let fetcher = Arc::new(RecordingFetcher::default());
+            let mut config = (*mgr.config.get()).clone();
+            config.extensions.fetcher = Some(fetcher.clone());
+            mgr.config.replace(config);
+            let mgr = Arc::new(mgr);
+
+            // We don't need a circuit manager to fetch with a fetcher.
+            assert!(mgr.circmgr().is_err());
+            let missing = [DocId::Microdesc(H1), DocId::Microdesc(H2)];
+            let fetched = fetch_multiple(Arc::clone(&mgr), AttemptId::next(), &missing, None, 4)
+                .await
+                .unwrap();
+            assert_eq!(fetched.len(), 1);
+            let (req, resp) = fetched.into_iter().next().unwrap();
+            assert!(matches!(req, ClientRequest::Microdescs(_)));
+            assert!(resp.source().is_none());
+            assert_eq!(resp.into_output_unchecked(), b"fetched");
+
+            let requests = fetcher.requests.lock().unwrap();
+            assert_eq!(requests.len(), 1);
+            let ClientRequest::Microdescs(req) = &requests[0] else {
+                panic!("Wrong request type");
+            };
+            assert_eq!(req.digests().count(), 2);
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "proxy-fallback")]
+    fn fallback_fetcher() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+            let fetcher = Arc::new(RecordingFetcher::default());
+            let mut config = (*mgr.config.get()).clone();
+            config.extensions.fallback_fetcher = Some(crate::FallbackFetcher::new(fetcher.clone()));
+            mgr.config.replace(config);
+            let mgr = Arc::new(mgr);
+            let missing = [DocId::Microdesc(H1)];
+
+            // Until two attempts have failed, we try to use our (missing)
+            // circuit manager.
+            for _ in 0..2 {
+                mgr.note_download_attempt();
+                let outcome =
+                    fetch_multiple(Arc::clone(&mgr), AttemptId::next(), &missing, None, 4).await;
+                assert!(outcome.is_err());
+            }
+            assert!(fetcher.requests.lock().unwrap().is_empty());
+
+            mgr.note_download_attempt();
+            let fetched = fetch_multiple(Arc::clone(&mgr), AttemptId::next(), &missing, None, 4)
+                .await
+                .unwrap();
+            assert_eq!(fetched.len(), 1);
+            assert_eq!(fetcher.requests.lock().unwrap().len(), 1);
+
+            // Giving up on bootstrapping resets our failure log, but we
+            // still fall back until we have a directory.
+            mgr.finish_failure_report();
+            mgr.note_download_attempt();
+            fetch_multiple(Arc::clone(&mgr), AttemptId::next(), &missing, None, 4)
+                .await
+                .unwrap();
+            assert_eq!(fetcher.requests.lock().unwrap().len(), 2);
+
+            // Once we're told that our directory is usable, we start over.
+            mgr.clear_failure_log();
+            mgr.note_download_attempt();
+            assert!(
+                fetch_multiple(Arc::clone(&mgr), AttemptId::next(), &missing, None, 4)
+                    .await
+                    .is_err()
+            );
+            assert_eq!(fetcher.requests.lock().unwrap().len(), 2);
+        });
+    }
+
+    /// A [`DocumentFetcher`] that fails its first `n_failures` requests.
+    #[derive(Debug)]
+    struct FlakyFetcher {
+        n_failures: usize,
+        n_requests: Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl DocumentFetcher for FlakyFetcher {
+        async fn fetch(&self, _request: &ClientRequest) -> Result<DirResponse> {
+            let mut n_requests = self.n_requests.lock().unwrap();
+            *n_requests += 1;
+            if *n_requests <= self.n_failures {
+                Err(Error::DocumentFetch {
+                    from: "a flaky fetcher".into(),
+                    action: crate::FetchAction::Connecting,
+                    cause: Arc::new(std::io::ErrorKind::ConnectionReset.into()),
+                })
+            } else {
+                Ok(DirResponse::from_body("fetched"))
+            }
+        }
+    }
+
+    #[test]
+    fn microdesc_failover() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+            let mgr = Arc::new(mgr);
+            let missing = [DocId::Microdesc(H1), DocId::Microdesc(H2)];
+
+            for (n_failures, failovers, expect_fetched, expect_requests) in
+                [(1, 2, 1, 2), (2, 2, 1, 3), (3, 2, 0, 3), (1, 0, 0, 1)]
+            {
+                let fetcher = Arc::new(FlakyFetcher {
+                    n_failures,
+                    n_requests: Mutex::new(0),
+                });
+                let mut config = (*mgr.config.get()).clone();
+                config.extensions.fetcher = Some(fetcher.clone());
+                config.schedule.microdesc_failovers = failovers;
+                mgr.config.replace(config);
+
+                let fetched =
+                    fetch_multiple(Arc::clone(&mgr), AttemptId::next(), &missing, None, 4)
+                        .await
+                        .unwrap();
+                assert_eq!(fetched.len(), expect_fetched);
+                assert_eq!(*fetcher.n_requests.lock().unwrap(), expect_requests);
+            }
+        });
+    }
+
+    #[test]
+    fn md_download_progress() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+            let mgr = Arc::new(mgr);
+            let key = [9; 32];
+            let missing = [DocId::Microdesc(H2), DocId::Microdesc(H1)];
+
+            let fetcher = Arc::new(FlakyFetcher {
+                n_failures: 1,
+                n_requests: Mutex::new(0),
+            });
+            let mut config = (*mgr.config.get()).clone();
+            config.extensions.fetcher = Some(fetcher);
+            config.schedule.microdesc_failovers = 0;
+            mgr.config.replace(config);
+
+            let fetched =
+                fetch_multiple(Arc::clone(&mgr), AttemptId::next(), &missing, Some(key), 4)
+                    .await
+                    .unwrap();
+            assert!(fetched.is_empty());
+
+            // We remember the batch that we asked for, and that it failed.
+            let store = mgr.store.lock().unwrap();
+            let saved = store.md_download_progress(&key).unwrap().unwrap();
+            assert_eq!(saved.batches().len(), 1);
+            assert_eq!(saved.batches()[0].digests(), &[H1, H2]);
+            assert_eq!(saved.batches()[0].n_failures(), 1);
+
+            // After a restart, we resume from that batch.
+            let config = mgr.config.get();
+            let (requests, progress) = make_resumable_requests(
+                &mgr.runtime,
+                &[DocId::Microdesc(H2)],
+                &**store,
+                &config,
+                Some(key),
+            )
+            .unwrap();
+            assert_eq!(requests.len(), 1);
+            let progress = progress.unwrap();
+            assert_eq!(progress.batches()[0].digests(), &[H2]);
+            assert_eq!(progress.batches()[0].n_failures(), 1);
+        });
+    }
 }
@@ -0,0 +1,70 @@
+//! A record of how our published directory has changed, so that we can answer
+//! [`NetDirProvider::change_summary`](tor_netdir::NetDirProvider::change_summary).
+
+use std::collections::VecDeque;
+
+use tor_netdir::{NetDirChangeSummary, NetDirGeneration};
+
+/// The largest number of publications that we remember.
+const MAX_ENTRIES: usize = 64;
+
+/// A log of the changes between the directories that we have published.
+#[derive(Debug, Default)]
+pub(crate) struct ChangeLog {
+    /// The generation of the oldest directory that we remember.
+    base: Option<NetDirGeneration>,
+    /// For each directory that we published after `base`, its generation, and
+    /// a summary of how it differs from the one before it.
+    entries: VecDeque<(NetDirGeneration, NetDirChangeSummary)>,
+}
+
+impl ChangeLog {
+    /// Return the generation of the latest directory that we remember.
+    fn latest(&self) -> Option<NetDirGeneration> {
+        self.entries.back().map(|(g, _)| *g).or(self.base)
+    }
+
+    /// Record that we replaced the directory with generation `prev` (if any)
+    /// with one whose generation is `new`, and which differs as described in
+    /// `summary`.
+    pub(crate) fn record(
+        &mut self,
+        prev: Option<NetDirGeneration>,
+        new: NetDirGeneration,
+        summary: NetDirChangeSummary,
+    ) {
+        let Some(prev) = prev else {
+            self.entries.clear();
+            self.base = Some(new);
+            return;
+        };
+        if self.latest() != Some(prev) {
+            // We don't know how `prev` relates to what we remember, so we
+            // have to start over.
+            self.entries.clear();
+            self.base = Some(prev);
+        }
+        self.entries.push_back((new, summary));
+        if self.entries.len() > MAX_ENTRIES {
+            self.base = self.entries.pop_front().map(|(g, _)| g);
+        }
+    }
+
+    /// Return a summary of the changes since the directory with generation
+    /// `since`, if we remember it.
+    pub(crate) fn since(&self, since: NetDirGeneration) -> Option<NetDirChangeSummary> {
+        let skip = if self.base == Some(since) {
+            0
+        } else {
+            1 + self.entries.iter().position(|(g, _)| *g == since)?
+        };
+        Some(
+            self.entries
+                .iter()
+                .skip(skip)
+                .fold(NetDirChangeSummary::default(), |acc, (_, s)| {
+                    acc.followed_by(s)
+                }),
+        )
+    }
+}
@@ -15,10 +15,11 @@ use crate::Result;
 use tor_checkable::timed::TimerangeBound;
 use tor_config::{define_list_builder_accessors, impl_standard_builder, ConfigBuildError};
 use tor_guardmgr::fallback::FallbackDirBuilder;
-use tor_netdoc::doc::netstatus::{self, Lifetime};
+use tor_netdoc::doc::netstatus::{self, ConsensusFlavor, Lifetime};
 
 use derive_builder::Builder;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::time::Duration;
 
@@ -60,6 +61,67 @@ pub struct NetworkConfig {
     /// whose identities and public keys are shipped as part of the Arti source code.
     #[builder(sub_builder, setter(custom))]
     pub(crate) authorities: AuthorityList,
+
+    /// List of directory authorities that we can contact directly to download
+    /// directory information, if `authorities_only_bootstrap` is set.
+    ///
+    /// This section can be changed in a running Arti client.
+    ///
+    /// The default is an empty list.
+    #[builder(sub_builder, setter(custom))]
+    pub(crate) authority_dirs: tor_guardmgr::fallback::AuthorityDirList,
+
+    /// If true, we never download directory information from the fallback
+    /// directories: when we have no directory yet, we contact the
+    /// directory authorities in `authority_dirs` instead.
+    ///
+    /// This puts extra load on the directory authorities, and means that we
+    /// cannot bootstrap at all when they are unreachable.  Don't enable it
+    /// unless you have a specific reason to.
+    ///
+    /// This option can be changed in a running Arti client.
+    #[builder(default)]
+    #[builder_field_attr(serde(default))]
+    pub(crate) authorities_only_bootstrap: bool,
+
+    /// Other Tor networks that we know how to use, by name: for example, a
+    /// private test network.
+    ///
+    /// Each has its own authorities and fallback directories, which we use
+    /// instead of those above when `network_name` names it.  Each also has
+    /// its own directory cache, in a `networks/NAME` subdirectory of our
+    /// cache directory.
+    ///
+    /// Names may only contain ASCII letters, digits, `-`, and `_`.
+    ///
+    /// This section can be changed in a running Arti client; but the
+    /// authorities of the network that we're using can't.
+    #[builder(
+        setter(custom),
+        field(
+            type = "BTreeMap<String, AlternativeNetworkBuilder>",
+            build = "self.build_networks()?"
+        )
+    )]
+    #[builder_field_attr(serde(default))]
+    pub(crate) networks: BTreeMap<String, AlternativeNetwork>,
+
+    /// The name of the network from `networks` to use.
+    ///
+    /// If this is not set, we use the network that the rest of this section
+    /// describes: ordinarily, the public Tor network.
+    ///
+    /// This option cannot be changed in a running Arti client, since Arti
+    /// keeps separate guard state for each network.  A `DirMgr` on its own
+    /// can switch, though: when this changes in
+    /// [`DirMgr::reconfigure`](crate::DirMgr::reconfigure), we stop using the
+    /// directory that we have, and bootstrap a new one from the other
+    /// network's cache, or from its fallbacks.  (A download that is in
+    /// progress when the option changes may finish first, but its results
+    /// are not stored in the new network's cache.)
+    #[builder(default, setter(strip_option, into))]
+    #[builder_field_attr(serde(default))]
+    pub(crate) network_name: Option<String>,
 }
 
 impl_standard_builder! { NetworkConfig }
@@ -68,19 +130,103 @@ define_list_builder_accessors! {
     struct NetworkConfigBuilder {
         pub fallback_caches: [FallbackDirBuilder],
         pub authorities: [AuthorityBuilder],
+        pub authority_dirs: [FallbackDirBuilder],
     }
 }
 
 impl NetworkConfig {
-    /// Return the list of fallback directory caches from this configuration.
+    /// Return the list of fallback directory caches from this configuration,
+    /// for the network that we're configured to use.
     pub fn fallback_caches(&self) -> &tor_guardmgr::fallback::FallbackList {
-        &self.fallback_caches
+        match self.selected_network() {
+            Some(net) => &net.fallback_caches,
+            None => &self.fallback_caches,
+        }
+    }
+
+    /// Return the list of directory authorities that we may contact directly
+    /// from this configuration, for the network that we're configured to use.
+    pub fn authority_dirs(&self) -> &tor_guardmgr::fallback::AuthorityDirList {
+        match self.selected_network() {
+            Some(net) => &net.authority_dirs,
+            None => &self.authority_dirs,
+        }
+    }
+
+    /// Return the name of the network from
+    /// [`networks`](NetworkConfigBuilder::networks) that we're configured to
+    /// use, or `None` if we're using the default network.
+    pub fn network_name(&self) -> Option<&str> {
+        self.network_name.as_deref()
+    }
+
+    /// Return the alternative network that we're configured to use, if any.
+    fn selected_network(&self) -> Option<&AlternativeNetwork> {
+        // (Our builder makes sure that the network exists.)
+        self.network_name
+            .as_ref()
+            .and_then(|name| self.networks.get(name))
+    }
+
+    /// Return the list of directory authorities for the network that we're
+    /// configured to use.
+    pub(crate) fn authorities(&self) -> &AuthorityList {
+        match self.selected_network() {
+            Some(net) => &net.authorities,
+            None => &self.authorities,
+        }
+    }
+
+    /// Return a mutable reference to the list of directory authorities for
+    /// the network that we're configured to use.
+    fn authorities_mut(&mut self) -> &mut AuthorityList {
+        match self
+            .network_name
+            .as_ref()
+            .and_then(|name| self.networks.get_mut(name))
+        {
+            Some(net) => &mut net.authorities,
+            None => &mut self.authorities,
+        }
+    }
+
+    /// Return true if we should only bootstrap from the directory authorities.
+    pub fn authorities_only_bootstrap(&self) -> bool {
+        self.authorities_only_bootstrap
     }
 }
 
 impl NetworkConfigBuilder {
+    /// Return a mutable reference to the map of alternative networks that
+    /// this builder will configure, by name.
+    pub fn networks(&mut self) -> &mut BTreeMap<String, AlternativeNetworkBuilder> {
+        &mut self.networks
+    }
+
+    /// Build our map of alternative networks.
+    fn build_networks(
+        &self,
+    ) -> std::result::Result<BTreeMap<String, AlternativeNetwork>, ConfigBuildError> {
+        self.networks
+            .iter()
+            .map(|(name, net)| Ok((name.clone(), net.build()?)))
+            .collect()
+    }
+
     /// Check that this builder will give a reasonable network.
     fn validate(&self) -> std::result::Result<(), ConfigBuildError> {
+        for name in self.networks.keys() {
+            let valid = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid {
+                return Err(ConfigBuildError::Invalid {
+                    field: "networks".to_owned(),
+                    problem: format!("{:?} is not a valid network name", name),
+                });
+            }
+        }
         if self.opt_authorities().is_some() && self.opt_fallback_caches().is_none() {
             return Err(ConfigBuildError::Inconsistent {
                 fields: vec!["authorities".to_owned(), "fallbacks".to_owned()],
@@ -88,7 +234,93 @@ impl NetworkConfigBuilder {
                     .to_owned(),
             });
         }
+        let (authority_dirs_field, authority_dirs) = match &self.network_name {
+            Some(Some(name)) => {
+                let Some(network) = self.networks.get(name) else {
+                    return Err(ConfigBuildError::Inconsistent {
+                        fields: vec!["network_name".to_owned(), "networks".to_owned()],
+                        problem: format!("There is no network called {:?}", name),
+                    });
+                };
+                (
+                    format!("networks.{}.authority_dirs", name),
+                    network.opt_authority_dirs(),
+                )
+            }
+            _ => ("authority_dirs".to_owned(), self.opt_authority_dirs()),
+        };
+        if self.authorities_only_bootstrap == Some(true)
+            && authority_dirs.as_ref().map_or(true, |dirs| dirs.is_empty())
+        {
+            return Err(ConfigBuildError::Inconsistent {
+                fields: vec![
+                    "authorities_only_bootstrap".to_owned(),
+                    authority_dirs_field,
+                ],
+                problem:
+                    "Bootstrapping only from authorities, but no authority_dirs are configured"
+                        .to_owned(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// The directory authorities and fallback directories for a Tor network other
+/// than the one that a [`NetworkConfig`] describes by default: for example, a
+/// private test network.
+///
+/// Unlike for the default network, there are no built-in authorities or
+/// fallbacks: you must list both.
+///
+/// This type is immutable once constructed. To make one, use
+/// [`AlternativeNetworkBuilder`], or deserialize it from a string.
+#[derive(Debug, Clone, Builder, Eq, PartialEq)]
+#[builder(build_fn(validate = "Self::validate", error = "ConfigBuildError"))]
+#[builder(derive(Debug, Serialize, Deserialize))]
+pub struct AlternativeNetwork {
+    /// List of locations to look in when downloading directory information
+    /// for this network, if we don't actually have a directory yet.
+    #[builder(sub_builder, setter(custom))]
+    pub(crate) fallback_caches: tor_guardmgr::fallback::FallbackList,
+
+    /// List of directory authorities which we expect to sign this network's
+    /// consensus documents.
+    #[builder(sub_builder, setter(custom))]
+    pub(crate) authorities: AuthorityList,
+
+    /// List of this network's directory authorities that we can contact
+    /// directly, if `authorities_only_bootstrap` is set.
+    ///
+    /// The default is an empty list.
+    #[builder(sub_builder, setter(custom))]
+    pub(crate) authority_dirs: tor_guardmgr::fallback::AuthorityDirList,
+}
+
+impl_standard_builder! { AlternativeNetwork: !Default }
+
+define_list_builder_accessors! {
+    struct AlternativeNetworkBuilder {
+        pub fallback_caches: [FallbackDirBuilder],
+        pub authorities: [AuthorityBuilder],
+        pub authority_dirs: [FallbackDirBuilder],
+    }
+}
 
+impl AlternativeNetworkBuilder {
+    /// Check that this builder lists this network's authorities and fallbacks.
+    fn validate(&self) -> std::result::Result<(), ConfigBuildError> {
+        for (field, missing) in [
+            ("authorities", self.opt_authorities().is_none()),
+            ("fallback_caches", self.opt_fallback_caches().is_none()),
+        ] {
+            if missing {
+                return Err(ConfigBuildError::MissingField {
+                    field: field.to_owned(),
+                });
+            }
+        }
         Ok(())
     }
 }
@@ -127,6 +359,35 @@ pub struct DownloadScheduleConfig {
     )]
     #[builder_field_attr(serde(default))]
     pub(crate) retry_microdescs: DownloadSchedule,
+
+    /// How long before our consensus expires should we try to fetch the next
+    /// one?
+    ///
+    /// If this is nonzero, we start trying to replace each consensus this long
+    /// before it stops being valid, instead of at the time chosen by the
+    /// dir-spec schedule.  We add a random jitter of up to a quarter of this
+    /// period, and we never start before the next consensus could have been
+    /// published.
+    ///
+    /// This is useful for fetching directory information while we have a
+    /// good network connection, rather than later on a worse one.
+    ///
+    /// Defaults to zero, which means to use the dir-spec schedule.
+    #[builder(default)]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    pub(crate) prefetch_lead_time: Duration,
+
+    /// How many times should we send a failed request for microdescriptors
+    /// again, within a single download attempt?
+    ///
+    /// We fetch microdescriptors in batches, sending up to
+    /// `retry_microdescs.parallelism` batches at once.  When a batch fails, we
+    /// reassign it (usually to a different directory cache) as soon as
+    /// possible, instead of waiting until the next attempt for that whole set
+    /// of downloads.
+    #[builder(default = "2")]
+    #[builder_field_attr(serde(default))]
+    pub(crate) microdesc_failovers: u8,
 }
 
 impl_standard_builder! { DownloadScheduleConfig }
@@ -265,23 +526,60 @@ impl DirMgrConfig {
     /// Note that each time this is called, a new store object will be
     /// created: you probably only want to call this once.
     pub(crate) fn open_store(&self, readonly: bool) -> Result<DynStore> {
-        Ok(Box::new(
-            crate::storage::SqliteStore::from_path_and_mistrust(
-                &self.cache_dir,
+        let cache_dir = self.network_cache_dir();
+        let store: DynStore = if self.extensions.immutable_cache {
+            Box::new(
+                crate::storage::SqliteStore::from_immutable_path_and_mistrust(
+                    &cache_dir,
+                    &self.cache_trust,
+                )?,
+            )
+        } else {
+            Box::new(crate::storage::SqliteStore::from_path_and_mistrust(
+                &cache_dir,
                 &self.cache_trust,
                 readonly,
-            )?,
-        ))
+            )?)
+        };
+        Ok(match &self.extensions.store_timing {
+            Some(timing) => Box::new(crate::storage::TimedStore::new(store, timing.clone())),
+            None => store,
+        })
+    }
+
+    /// Return the directory in which we keep the cache for the network that
+    /// we're configured to use.
+    ///
+    /// This is `cache_dir` for the default network, and a subdirectory of it
+    /// for each alternative network.
+    pub(crate) fn network_cache_dir(&self) -> PathBuf {
+        match self.network.network_name() {
+            Some(name) => self.cache_dir.join("networks").join(name),
+            None => self.cache_dir.clone(),
+        }
     }
 
-    /// Return a slice of the configured authorities
+    /// Return a slice of the configured authorities, for the network that
+    /// we're configured to use.
     pub fn authorities(&self) -> &[Authority] {
-        &self.network.authorities
+        self.network.authorities()
     }
 
-    /// Return the configured set of fallback directories
+    /// Return the configured set of fallback directories, for the network
+    /// that we're configured to use.
     pub fn fallbacks(&self) -> &tor_guardmgr::fallback::FallbackList {
-        &self.network.fallback_caches
+        self.network.fallback_caches()
+    }
+
+    /// Return true if `new_config` would switch us to a different network,
+    /// and we can do that without restarting.
+    ///
+    /// We can't switch networks if our directory comes from a static bundle
+    /// or an immutable cache.
+    pub(crate) fn can_switch_network_to(&self, new_config: &DirMgrConfig) -> bool {
+        new_config.network.network_name != self.network.network_name
+            && self.extensions.static_bundle.is_none()
+            && !self.extensions.immutable_cache
     }
 
     /// Construct a new configuration object where all replaceable fields in
@@ -290,17 +588,34 @@ impl DirMgrConfig {
     /// Any fields which aren't allowed to change at runtime are copied from self.
     pub(crate) fn update_from_config(&self, new_config: &DirMgrConfig) -> DirMgrConfig {
         // NOTE: keep this in sync with the behaviour of `DirMgr::reconfigure`
+        let mut network = new_config.network.clone();
+        if !self.can_switch_network_to(new_config) {
+            // We keep using the same network, with the same authorities.
+            network.network_name.clone_from(&self.network.network_name);
+            if let (Some(name), Some(old)) =
+                (&self.network.network_name, self.network.selected_network())
+            {
+                // (The new configuration might not have this network at all.)
+                network
+                    .networks
+                    .entry(name.clone())
+                    .or_insert_with(|| old.clone());
+            }
+            *network.authorities_mut() = self.network.authorities().clone();
+        }
         DirMgrConfig {
             cache_dir: self.cache_dir.clone(),
             cache_trust: self.cache_trust.clone(),
-            network: NetworkConfig {
-                fallback_caches: new_config.network.fallback_caches.clone(),
-                authorities: self.network.authorities.clone(),
-            },
+            network,
             schedule: new_config.schedule.clone(),
             tolerance: new_config.tolerance.clone(),
             override_net_params: new_config.override_net_params.clone(),
-            extensions: new_config.extensions.clone(),
+            extensions: DirMgrExtensions {
+                static_bundle: self.extensions.static_bundle.clone(),
+                store_timing: self.extensions.store_timing.clone(),
+                immutable_cache: self.extensions.immutable_cache,
+                ..new_config.extensions.clone()
+            },
         }
     }
 
@@ -321,6 +636,190 @@ pub struct DirMgrExtensions {
     /// A filter to be used when installing new directory objects.
     #[cfg(feature = "dirfilter")]
     pub filter: crate::filter::FilterConfig,
+
+    /// Built-in filters to apply to directory objects, before `filter`.
+    ///
+    /// Like `filter`, this cannot be changed on a running `DirMgr`.
+    #[cfg(feature = "dirfilter")]
+    pub builtin_filters: crate::filter::BuiltinFilterConfig,
+
+    /// A policy to use for deciding when to download directory objects.
+    #[cfg(feature = "dirtiming")]
+    pub timing: crate::timing::TimingConfig,
+
+    /// Which kinds of directory documents to download and store.
+    pub maintained_docs: MaintainedDocs,
+
+    /// Which flavor of consensus to download and maintain.
+    ///
+    /// Clients want [`ConsensusFlavor::Microdesc`], the default.
+    /// With [`ConsensusFlavor::Ns`] (which needs the `ns_consensus`
+    /// feature), we download, validate, and store the ns-flavored consensus
+    /// and its certificates, and then fetch router descriptors instead of
+    /// microdescriptors.  The [`NetDir`](tor_netdir::NetDir) that we provide
+    /// is built from those the same way that `tor_netdir::NsNetDir` builds
+    /// one.  Callers can find the
+    /// latest ns consensus itself with [`DirMgr::text`](crate::DirMgr::text).
+    ///
+    /// Each flavor is stored separately in the cache, so switching flavors
+    /// does not discard the consensus of the other flavor.  Changing this on
+    /// a running `DirMgr` takes effect the next time it fetches a consensus.
+    pub consensus_flavor: ConsensusFlavor,
+
+    /// When and how to report that our directory is getting stale.
+    pub freshness: crate::freshness::FreshnessWatchdogConfig,
+
+    /// How old a cached consensus may be for us to try loading it when we
+    /// bootstrap.
+    ///
+    /// This is only consulted when we bootstrap; use
+    /// [`DirMgr::startup_cache_decision`](crate::DirMgr::startup_cache_decision)
+    /// to find out what we decided.
+    pub startup_cache: crate::startup::StartupCacheConfig,
+
+    /// If present, a fixed set of directory documents to use instead of
+    /// downloading anything.
+    ///
+    /// When this is set, the `DirMgr` builds its directory from these
+    /// documents when it bootstraps, never contacts the network, and never
+    /// considers its directory to have expired.
+    ///
+    /// Cannot be changed on a running `DirMgr`.
+    pub static_bundle: Option<crate::staticdir::StaticDirBundle>,
+
+    /// If present, we measure how long each operation on our directory cache
+    /// takes, and log the slow ones.
+    ///
+    /// Use [`DirMgr::store_latency`](crate::DirMgr::store_latency) to find
+    /// out how long operations have been taking.
+    ///
+    /// Cannot be changed on a running `DirMgr`.
+    pub store_timing: Option<crate::StoreTimingConfig>,
+
+    /// If true, act as a directory cache: each time we download a new
+    /// consensus, compute and store a diff to it from our previous one.
+    ///
+    /// Whether or not this is set, [`DirMgr::consensus_diff`](crate::DirMgr::consensus_diff)
+    /// can compute a diff between any two consensuses in our cache; this
+    /// option just makes sure that the diffs that clients are most likely to
+    /// ask for are ready ahead of time.
+    pub dir_cache: bool,
+
+    /// If nonzero, keep this many of our latest usable consensuses of each
+    /// flavor in our cache, however old they are, along with the
+    /// microdescriptors that they list.
+    ///
+    /// Ordinarily we discard a consensus a couple of days after it expires.
+    /// With an archive, [`DirMgr::netdir_at`](crate::DirMgr::netdir_at) can
+    /// rebuild the directory as it was at any time that an archived
+    /// consensus covers.  Since a new consensus comes out every hour, each
+    /// day of history costs 24 consensuses, and the microdescriptors that
+    /// change during that day.
+    ///
+    /// Changing this on a running `DirMgr` takes effect the next time we
+    /// expire old documents from our cache.  Lowering it discards history
+    /// that we can't get back.
+    pub archived_consensuses: usize,
+
+    /// If present, try to keep our cache no larger than this many bytes.
+    ///
+    /// Each time we expire old documents from our cache, if it is still
+    /// larger than this, we evict the microdescriptors and router
+    /// descriptors that the latest consensus can't list, least recently
+    /// listed (or published) first, and then compact the database (at most
+    /// once every few hours).  We never evict anything that the latest
+    /// consensus needs, or any consensus, so the cache can still grow past
+    /// this limit if the limit is too small.
+    ///
+    /// Use [`DirMgr::cache_usage`](crate::DirMgr::cache_usage) to see how
+    /// much space the cache is using.
+    ///
+    /// Changing this on a running `DirMgr` takes effect the next time we
+    /// expire old documents from our cache.
+    pub max_cache_size: Option<u64>,
+
+    /// If true, our cache directory holds an immutable, pre-populated image
+    /// of a directory cache (for example, one shipped inside an application
+    /// bundle), and we must never write to it.
+    ///
+    /// In this mode we open the cache strictly read-only, without creating a
+    /// lockfile or anything else, so it works on a read-only filesystem.  We
+    /// never download anything: instead, we reload the directory from the
+    /// image periodically, as we do when another process owns the cache.  To
+    /// keep the directory fresh, the embedder should replace the image from
+    /// time to time (atomically, so that we never see a partial image), and
+    /// may call [`DirMgr::reload_immutable_cache`](crate::DirMgr::reload_immutable_cache)
+    /// to have us pick up the new one right away.
+    ///
+    /// Cannot be changed on a running `DirMgr`.
+    pub immutable_cache: bool,
+
+    /// If present, an object to fetch directory documents with, instead of
+    /// fetching them from directory caches over Tor.
+    ///
+    /// This is for environments where we can't build circuits to directory
+    /// caches, but where some other copy of the directory can be reached.  We
+    /// still check every document that it gives us.  A stream given to
+    /// [`DirMgr::use_stream_for_next_fetch`](crate::DirMgr::use_stream_for_next_fetch)
+    /// takes precedence over this.
+    ///
+    /// Changing this on a running `DirMgr` takes effect with the next
+    /// download attempt.
+    pub fetcher: Option<std::sync::Arc<dyn crate::DocumentFetcher>>,
+
+    /// If present, an object to fetch directory documents with when we have
+    /// no directory, and our usual downloads have failed several times in a
+    /// row.
+    ///
+    /// This is meant for users who can only reach the internet through a
+    /// proxy: see [`ProxiedMirrorFetcher`](crate::ProxiedMirrorFetcher), and
+    /// the privacy warning there.  If [`fetcher`](DirMgrExtensions::fetcher)
+    /// is also present, we always use that instead.
+    #[cfg(feature = "proxy-fallback")]
+    pub fallback_fetcher: Option<crate::FallbackFetcher>,
+
+    /// If present, the source of the GeoIP database that we use to find the
+    /// countries of relays.
+    ///
+    /// Each time we build a new directory, we use whatever database this
+    /// manager holds at that moment, so replacing its database takes effect
+    /// with the next consensus.  If absent, we use the embedded database.
+    #[cfg(feature = "geoip")]
+    pub geoip: Option<std::sync::Arc<tor_geoip::GeoipDbManager>>,
+}
+
+/// Which kinds of directory documents a [`DirMgr`](crate::DirMgr) should
+/// download and store.
+///
+/// Most users need [`MaintainedDocs::Full`]: without it, the directory manager
+/// never produces a [`NetDir`](tor_netdir::NetDir), and so cannot be used to
+/// build circuits.
+///
+/// The other options are for embedders that only need information from the
+/// consensus itself (for example, to monitor the number of relays), and want
+/// to save the bandwidth of downloading everything else.  They can find the
+/// latest consensus with [`DirMgr::text`](crate::DirMgr::text).
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum MaintainedDocs {
+    /// Download only the consensus.
+    ///
+    /// Without authority certificates, we cannot check the consensus's
+    /// signatures: we only check that it purports to be signed by the right
+    /// authorities.  Do not rely on the contents of a consensus fetched in
+    /// this mode for anything security-sensitive.
+    ///
+    /// Since the consensus is never checked, we never mark it usable in our
+    /// cache: we download a fresh one every time we start.
+    ConsensusOnly,
+    /// Download the consensus, and the authority certificates needed to
+    /// validate it.
+    ConsensusAndCerts,
+    /// Download everything needed to build circuits: the consensus, the
+    /// authority certificates, and the microdescriptors (or, for an
+    /// ns-flavored consensus, the router descriptors).
+    #[default]
+    Full,
 }
 
 #[cfg(test)]
@@ -396,6 +895,124 @@ mod test {
         let cfg = bld.build().unwrap();
         assert_eq!(cfg.authorities.len(), 2);
         assert_eq!(cfg.fallback_caches.len(), 1);
+        assert!(cfg.authority_dirs().is_empty());
+        assert!(!cfg.authorities_only_bootstrap());
+
+        // If we bootstrap only from authorities, we need to know where they are.
+        bld.authorities_only_bootstrap(true);
+        assert!(bld.build().is_err());
+
+        bld.set_authority_dirs(vec![{
+            let mut bld = FallbackDir::builder();
+            bld.rsa_identity([b'a'; 20].into())
+                .ed_identity([b'b'; 32].into());
+            bld.orports().push("127.0.0.2:99".parse().unwrap());
+            bld
+        }]);
+        let cfg = bld.build().unwrap();
+        assert_eq!(cfg.authority_dirs().len(), 1);
+        assert!(cfg.authorities_only_bootstrap());
+
+        Ok(())
+    }
+
+    #[test]
+    fn alternative_networks() -> Result<()> {
+        use tor_guardmgr::fallback::FallbackDir;
+
+        let dflt = NetworkConfig::default();
+        let mut testnet = AlternativeNetwork::builder();
+        testnet.set_authorities(vec![Authority::builder()
+            .name("test")
+            .v3ident([b'?'; 20].into())
+            .clone()]);
+        // The fallbacks are required.
+        assert!(testnet.build().is_err());
+        testnet.set_fallback_caches(vec![{
+            let mut bld = FallbackDir::builder();
+            bld.rsa_identity([b'x'; 20].into())
+                .ed_identity([b'y'; 32].into());
+            bld.orports().push("127.0.0.1:99".parse().unwrap());
+            bld
+        }]);
+
+        let mut bld = NetworkConfig::builder();
+        bld.networks().insert("test-net".into(), testnet.clone());
+        let cfg = bld.build().unwrap();
+        // Until we select it, we use the default network.
+        assert_eq!(cfg.network_name(), None);
+        assert_eq!(cfg.authorities(), dflt.authorities());
+        assert_eq!(cfg.fallback_caches().len(), dflt.fallback_caches().len());
+
+        // We can only select a network that exists.
+        bld.network_name("other");
+        assert!(bld.build().is_err());
+        bld.network_name("test-net");
+        let cfg = bld.build().unwrap();
+        assert_eq!(cfg.network_name(), Some("test-net"));
+        assert_eq!(cfg.authorities().len(), 1);
+        assert_eq!(cfg.fallback_caches().len(), 1);
+        assert!(cfg.authority_dirs().is_empty());
+
+        // We check the network names.
+        bld.networks().insert("../escape".into(), testnet);
+        assert!(bld.build().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn switch_network() -> Result<()> {
+        let tmp = tempdir().unwrap();
+        let testnet = {
+            let mut bld = AlternativeNetwork::builder();
+            bld.set_authorities(vec![Authority::builder()
+                .name("test")
+                .v3ident([b'?'; 20].into())
+                .clone()]);
+            bld.set_fallback_caches(vec![]);
+            bld
+        };
+        let mut bld = NetworkConfig::builder();
+        bld.networks().insert("test".into(), testnet);
+        let cfg = DirMgrConfig {
+            cache_dir: tmp.path().into(),
+            network: bld.build().unwrap(),
+            ..Default::default()
+        };
+        bld.network_name("test");
+        let cfg_test = DirMgrConfig {
+            network: bld.build().unwrap(),
+            ..cfg.clone()
+        };
+
+        // Each network has its own cache.
+        assert_eq!(cfg.network_cache_dir(), tmp.path());
+        assert_eq!(
+            cfg_test.network_cache_dir(),
+            tmp.path().join("networks").join("test")
+        );
+
+        // We can switch networks, authorities and all.
+        assert!(cfg.can_switch_network_to(&cfg_test));
+        assert!(!cfg.can_switch_network_to(&cfg));
+        let updated = cfg.update_from_config(&cfg_test);
+        assert_eq!(updated.network.network_name(), Some("test"));
+        assert_eq!(updated.authorities().len(), 1);
+
+        // But not if our cache is immutable: then we keep the old network.
+        let mut cfg_immutable = cfg.clone();
+        cfg_immutable.extensions.immutable_cache = true;
+        assert!(!cfg_immutable.can_switch_network_to(&cfg_test));
+        let updated = cfg_immutable.update_from_config(&cfg_test);
+        assert_eq!(updated.network.network_name(), None);
+        assert_eq!(updated.authorities(), cfg.authorities());
+
+        // Without switching networks, we can't change the authorities.
+        let mut cfg_test2 = cfg_test.clone();
+        cfg_test2.network.authorities_mut().clear();
+        let updated = cfg_test.update_from_config(&cfg_test2);
+        assert_eq!(updated.authorities(), cfg_test.authorities());
 
         Ok(())
     }
@@ -409,6 +1026,8 @@ mod test {
         assert_eq!(cfg.retry_microdescs.parallelism(), 4);
         assert_eq!(cfg.retry_microdescs.n_attempts(), 3);
         assert_eq!(cfg.retry_bootstrap.n_attempts(), 128);
+        assert_eq!(cfg.prefetch_lead_time, Duration::ZERO);
+        assert_eq!(cfg.microdesc_failovers, 2);
 
         bld.retry_consensus().attempts(7);
         bld.retry_consensus().initial_delay(Duration::new(86400, 0));
@@ -423,6 +1042,8 @@ mod test {
         bld.retry_microdescs().attempts(6);
         bld.retry_microdescs().initial_delay(Duration::new(3600, 0));
         bld.retry_microdescs().parallelism(1);
+        bld.prefetch_lead_time(Duration::new(1800, 0));
+        bld.microdesc_failovers(0);
 
         let cfg = bld.build().unwrap();
         assert_eq!(cfg.retry_microdescs.parallelism(), 1);
@@ -430,6 +1051,8 @@ mod test {
         assert_eq!(cfg.retry_bootstrap.n_attempts(), 4);
         assert_eq!(cfg.retry_consensus.n_attempts(), 7);
         assert_eq!(cfg.retry_certs.n_attempts(), 5);
+        assert_eq!(cfg.prefetch_lead_time, Duration::new(1800, 0));
+        assert_eq!(cfg.microdesc_failovers, 0);
 
         Ok(())
     }
@@ -0,0 +1,86 @@
+//! Producing consensus diffs, for directory caches.
+//!
+//! A directory cache that has several consecutive consensuses can answer a
+//! client's request for a new consensus with a diff from one that the client
+//! already has.  Clients name the consensuses they have by the SHA3-256 digest
+//! of their signed part, so we do the same.
+//!
+//! When we are configured to act as a cache (see
+//! [`DirMgrExtensions::dir_cache`](crate::config::DirMgrExtensions::dir_cache)),
+//! we compute and store a diff from our previous consensus every time we
+//! download a new one.  We can also compute a diff between any two consensuses
+//! in our cache on request.
+
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use tor_error::warn_report;
+
+use crate::docmeta::ConsensusMeta;
+use crate::storage::{DynStore, EXPIRATION_DEFAULTS};
+use crate::Result;
+
+/// Return the diff from the consensus whose signed part has SHA3-256 digest
+/// `from` to the one whose signed part has digest `to`.
+///
+/// If we have not stored that diff yet, but we have both consensuses, we
+/// compute the diff, and store it for next time if we can.  Computing a diff
+/// can take a while, so we don't hold the lock on `store` while we do it.
+///
+/// Return `Ok(None)` if we don't have one of the consensuses.
+pub(crate) fn consensus_diff(
+    store: &Mutex<DynStore>,
+    from: &[u8; 32],
+    to: &[u8; 32],
+) -> Result<Option<String>> {
+    let (old, old_meta, new) = {
+        let store = store.lock().expect("store lock poisoned");
+        if let Some(diff) = store.consensus_diff(from, to)? {
+            return Ok(Some(diff));
+        }
+        let Some((old, old_meta)) = store.consensus_by_sha3_digest_of_signed_part(from)? else {
+            return Ok(None);
+        };
+        let Some((new, _)) = store.consensus_by_sha3_digest_of_signed_part(to)? else {
+            return Ok(None);
+        };
+        (old, old_meta, new)
+    };
+    let diff = tor_consdiff::generate_diff(old.as_str()?, new.as_str()?, *from)?;
+    let mut store = store.lock().expect("store lock poisoned");
+    if !store.is_readonly() {
+        store.store_consensus_diff(from, to, diff_expiry(&old_meta), &diff)?;
+    }
+    Ok(Some(diff))
+}
+
+/// Having just stored the consensus described by `new`, compute and store a
+/// diff to it from `prev`, our previous consensus.
+///
+/// Failing to produce a diff doesn't stop us from using the new consensus,
+/// so we log any error rather than returning it.
+pub(crate) fn note_new_consensus(
+    store: &Mutex<DynStore>,
+    prev: Option<&ConsensusMeta>,
+    new: &ConsensusMeta,
+) {
+    let Some(prev) = prev else {
+        return;
+    };
+    if prev.sha3_256_of_signed() == new.sha3_256_of_signed()
+        || store.lock().expect("store lock poisoned").is_readonly()
+    {
+        return;
+    }
+    if let Err(e) = consensus_diff(store, prev.sha3_256_of_signed(), new.sha3_256_of_signed()) {
+        warn_report!(e, "Unable to compute diff from our previous consensus");
+    }
+}
+
+/// Return the time after which we no longer need a diff from the consensus
+/// described by `from`.
+///
+/// We keep the diff as long as we would keep the consensus itself.
+fn diff_expiry(from: &ConsensusMeta) -> SystemTime {
+    from.lifetime().valid_until() + EXPIRATION_DEFAULTS.consensuses.unsigned_abs()
+}
@@ -69,8 +69,11 @@ impl DocId {
 
 /// A request for a specific kind of directory resource that a DirMgr can
 /// request.
+///
+/// These are given to a [`DocumentFetcher`](crate::DocumentFetcher) to fetch.
 #[derive(Clone, Debug)]
-pub(crate) enum ClientRequest {
+#[non_exhaustive]
+pub enum ClientRequest {
     /// Request for a consensus
     Consensus(request::ConsensusRequest),
     /// Request for one or more authority certificates
@@ -84,7 +87,7 @@ pub(crate) enum ClientRequest {
 
 impl ClientRequest {
     /// Turn a ClientRequest into a Requestable.
-    pub(crate) fn as_requestable(&self) -> &(dyn request::Requestable + Send + Sync) {
+    pub fn as_requestable(&self) -> &(dyn request::Requestable + Send + Sync) {
         use ClientRequest::*;
         match self {
             Consensus(a) => a,
@@ -187,9 +190,18 @@ impl DocQuery {
                 v.sort_unstable();
                 v[..].chunks(N).map(|s| AuthCert(s.to_vec())).collect()
             }
-            Microdesc(mut v) => {
-                v.sort_unstable();
-                v[..].chunks(N).map(|s| Microdesc(s.to_vec())).collect()
+            Microdesc(v) => {
+                // We keep the caller's order when chunking, since the
+                // microdescriptors we need most urgently come first; we only
+                // sort within each request.
+                v[..]
+                    .chunks(N)
+                    .map(|s| {
+                        let mut s = s.to_vec();
+                        s.sort_unstable();
+                        Microdesc(s)
+                    })
+                    .collect()
             }
             #[cfg(feature = "routerdesc")]
             RouterDesc(mut v) => {
@@ -386,6 +398,19 @@ mod test {
         assert_eq!(found_ids.len(), 3400);
         assert_eq!(found_ids, ids);
 
+        // Microdesc chunks keep the order in which they were requested.
+        let ordered: Vec<MdDigest> = ids.iter().copied().collect();
+        let split = DocQuery::Microdesc(ordered.clone()).split_for_download();
+        for (q, expected) in split.into_iter().zip(ordered.chunks(500)) {
+            let expected: HashSet<_> = expected.iter().copied().collect();
+            match q {
+                DocQuery::Microdesc(got) => {
+                    assert_eq!(got.into_iter().collect::<HashSet<_>>(), expected);
+                }
+                _ => panic!("Wrong type."),
+            }
+        }
+
         // Test routerdescs.
         #[cfg(feature = "routerdesc")]
         {
@@ -9,7 +9,7 @@ use digest::Digest;
 use tor_llcrypto as ll;
 use tor_netdoc::doc::{
     authcert::{AuthCert, AuthCertKeyIds},
-    netstatus::{Lifetime, MdConsensus, UnvalidatedMdConsensus},
+    netstatus::{Lifetime, MdConsensus, UnvalidatedConsensus},
 };
 
 use std::time::SystemTime;
@@ -43,12 +43,12 @@ impl ConsensusMeta {
             sha3_256_of_whole,
         }
     }
-    /// Derive a new ConsensusMeta from an UnvalidatedMdConsensus and the
-    /// text of its signed portion.
-    pub(crate) fn from_unvalidated(
+    /// Derive a new ConsensusMeta from an UnvalidatedConsensus (of any
+    /// flavor) and the text of its signed portion.
+    pub(crate) fn from_unvalidated<RS>(
         signed_part: &str,
         remainder: &str,
-        con: &UnvalidatedMdConsensus,
+        con: &UnvalidatedConsensus<RS>,
     ) -> Self {
         let lifetime = con.peek_lifetime().clone();
         let (sd, wd) = sha3_dual(signed_part, remainder);
@@ -8,6 +8,7 @@ use fs_mistrust::anon_home::PathExt as _;
 use futures::task::SpawnError;
 use thiserror::Error;
 use tor_error::{ErrorKind, HasKind};
+use tor_netdoc::doc::netstatus::ConsensusFlavor;
 use tor_persist::FsMistrustErrorExt as _;
 
 /// An error originated by the directory manager code
@@ -112,6 +113,17 @@ pub enum Error {
     /// An error given by the checkable crate.
     #[error("Invalid signatures")]
     SignatureError(#[source] Arc<signature::Error>),
+    /// We couldn't build a usable directory from a static directory bundle.
+    #[error("Unusable static directory bundle: {0}")]
+    StaticBundle(&'static str),
+    /// We have an archived consensus for the time we were asked about, but
+    /// not enough of its microdescriptors to build a usable directory.
+    #[error("Not enough archived microdescriptors to rebuild the directory")]
+    ArchiveIncomplete,
+    /// We were configured to use a consensus flavor that this build of
+    /// the directory manager doesn't support.
+    #[error("Consensus flavor {} is not supported in this build", .0.name())]
+    UnsupportedFlavor(ConsensusFlavor),
     /// An attempt was made to bootstrap a `DirMgr` created in offline mode.
     #[error("Tried to bootstrap a DirMgr that was configured as offline-only")]
     OfflineMode,
@@ -135,6 +147,24 @@ pub enum Error {
         cause: Arc<SpawnError>,
     },
 
+    /// A [`DocumentFetcher`](crate::DocumentFetcher) was unable to fetch
+    /// the documents we asked for.
+    #[error("Unable to fetch directory documents from {from} while {action}")]
+    DocumentFetch {
+        /// A description of where we were fetching from.
+        from: String,
+        /// What we were doing.
+        action: FetchAction,
+        /// What went wrong.
+        #[source]
+        cause: Arc<std::io::Error>,
+    },
+
+    /// A problem storing or loading our guard manager's state, while
+    /// coordinating it with our directory.
+    #[error("Problem with guard state")]
+    GuardState(#[source] tor_guardmgr::GuardMgrError),
+
     /// Other error from an external directory provider
     #[error("Error from external directory provider")]
     ExternalDirProvider {
@@ -173,6 +203,27 @@ impl AsRef<dyn StdError + 'static> for Error {
     }
 }
 
+/// What a [`DocumentFetcher`](crate::DocumentFetcher) was doing when it
+/// failed.
+///
+/// See [`Error::DocumentFetch`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, derive_more::Display)]
+#[non_exhaustive]
+pub enum FetchAction {
+    /// Reading documents from a file.
+    #[display("reading")]
+    Reading,
+    /// Opening a TCP connection.
+    #[display("connecting")]
+    Connecting,
+    /// Asking a proxy to connect us to where we're fetching from.
+    #[display("negotiating with proxy")]
+    NegotiatingWithProxy,
+    /// Negotiating TLS.
+    #[display("negotiating TLS")]
+    NegotiatingTls,
+}
+
 /// The effect that a given error has on our bootstrapping process
 #[derive(Copy, Clone, Debug)]
 pub(crate) enum BootstrapAction {
@@ -245,7 +296,12 @@ impl Error {
             | Error::BadUtf8InCache(_)
             | Error::BadHexInCache(_)
             | Error::OfflineMode
+            | Error::StaticBundle(_)
+            | Error::ArchiveIncomplete
+            | Error::UnsupportedFlavor(_)
             | Error::Spawn { .. }
+            | Error::GuardState(_)
+            | Error::DocumentFetch { .. }
             | Error::NetDirOlder
             | Error::Bug(_) => false,
 
@@ -298,12 +354,16 @@ impl Error {
             | Error::UntimelyObject(_)
             | Error::DirClientError(_)
             | Error::SignatureError(_)
+            | Error::DocumentFetch { .. }
             | Error::NetDocError { .. } => BootstrapAction::Nonfatal,
 
             Error::ConsensusInvalid { .. } | Error::CantAdvanceState => BootstrapAction::Reset,
 
             Error::NoDownloadSupport
             | Error::OfflineMode
+            | Error::StaticBundle(_)
+            | Error::ArchiveIncomplete
+            | Error::UnsupportedFlavor(_)
             | Error::CacheCorruption(_)
             | Error::SqliteError(_)
             | Error::ReadOnlyStorage(_)
@@ -316,6 +376,7 @@ impl Error {
             | Error::CachePermissions(_)
             | Error::CacheAccess(_)
             | Error::Spawn { .. }
+            | Error::GuardState(_)
             | Error::ExternalDirProvider { .. } => BootstrapAction::Fatal,
 
             // These should actually be impossible during the bootstrap process.
@@ -350,6 +411,7 @@ impl HasKind for Error {
             E::CachePermissions(e) => e.cache_error_kind(),
             E::CacheAccess(e) => e.cache_error_kind(),
             E::SqliteError(e) => sqlite_error_kind(e),
+            E::ReadOnlyStorage(ReadOnlyStorageError::Immutable) => EK::BadApiUsage,
             E::ReadOnlyStorage(_) => EK::LocalResourceAlreadyInUse,
             E::UnrecognizedSchema { .. } => EK::CacheCorrupted,
             E::DirectoryNotPresent => EK::DirectoryExpired,
@@ -365,17 +427,26 @@ impl HasKind for Error {
             E::ConsensusDiffError(_) => EK::TorProtocolViolation,
             E::NetDocError { source, .. } => match source {
                 DocSource::LocalCache => EK::CacheCorrupted,
+                DocSource::StaticBundle => EK::InvalidConfig,
+                DocSource::Caller => EK::BadApiUsage,
                 DocSource::DirServer { .. } => EK::TorProtocolViolation,
             },
             E::ConsensusInvalid { source, .. } => match source {
                 DocSource::LocalCache => EK::CacheCorrupted,
+                DocSource::StaticBundle => EK::InvalidConfig,
+                DocSource::Caller => EK::BadApiUsage,
                 DocSource::DirServer { .. } => EK::TorProtocolViolation,
             },
             E::UntimelyObject(_) => EK::TorProtocolViolation,
             E::DirClientError(e) => e.kind(),
             E::SignatureError(_) => EK::TorProtocolViolation,
             E::OfflineMode => EK::BadApiUsage,
+            E::StaticBundle(_) => EK::InvalidConfig,
+            E::ArchiveIncomplete => EK::TorDirectoryUnusable,
+            E::UnsupportedFlavor(_) => EK::FeatureDisabled,
             E::Spawn { cause, .. } => cause.kind(),
+            E::GuardState(e) => e.kind(),
+            E::DocumentFetch { .. } => EK::TorDirectoryError,
             E::ExternalDirProvider { kind, .. } => *kind,
             E::Bug(e) => e.kind(),
         }
@@ -439,4 +510,10 @@ pub enum ReadOnlyStorageError {
         /// The schema that we actually support.
         supported: u32,
     },
+
+    /// We tried to modify a cache that was opened as an immutable image.
+    ///
+    /// (See [`DirMgrExtensions::immutable_cache`](crate::config::DirMgrExtensions::immutable_cache).)
+    #[error("The directory cache is an immutable image, and cannot be modified.")]
+    Immutable,
 }
@@ -5,6 +5,7 @@
 // into another crate.
 
 use std::{
+    collections::HashSet,
     fmt,
     marker::PhantomData,
     pin::Pin,
@@ -13,7 +14,7 @@ use std::{
         Arc,
     },
     task::Poll,
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 
 use educe::Educe;
@@ -22,6 +23,7 @@ use itertools::chain;
 use paste::paste;
 use time::OffsetDateTime;
 use tor_basic_utils::skip_fmt;
+use tor_llcrypto::pk::rsa::RsaIdentity;
 use tor_netdir::DirEvent;
 use tor_netdoc::doc::netstatus;
 
@@ -347,6 +349,117 @@ pub(crate) struct DirStatus {
     /// How many times has an `update_progress` call not actually moved us
     /// forward since we last advanced the 'progress' on this directory?
     n_stalls: usize,
+    /// What downloads have we made for this directory?
+    ///
+    /// (This is boxed to keep `StatusEnum` small.)
+    downloads: Box<DownloadCounters>,
+}
+
+/// Counters describing the downloads we have made for a single directory.
+#[derive(Clone, Debug, Default)]
+struct DownloadCounters {
+    /// How many requests have we made?
+    n_requests: usize,
+    /// How many of those requests failed, or were declined?
+    n_failed_requests: usize,
+    /// How many bytes of response bodies have we received?
+    n_bytes: u64,
+    /// The identities of the directory caches that we have contacted.
+    caches: HashSet<RsaIdentity>,
+    /// When did we launch our first set of requests?
+    first_request: Option<SystemTime>,
+    /// When did we launch our most recent set of requests?
+    last_request: Option<SystemTime>,
+    /// How long have we spent waiting for sets of requests to finish, in
+    /// total?
+    time_downloading: Duration,
+    /// How long did our slowest single request take?
+    slowest_request: Duration,
+}
+
+/// The outcome of a single directory request, as recorded in our
+/// [`DirBootstrapStatus`].
+#[derive(Clone, Debug)]
+pub(crate) struct RequestRecord {
+    /// The identity of the cache that we asked, if we know it.
+    pub(crate) cache: Option<RsaIdentity>,
+    /// The number of bytes in the response body.
+    pub(crate) n_bytes: usize,
+    /// True if the request succeeded.
+    pub(crate) ok: bool,
+    /// How long the request took.
+    pub(crate) elapsed: Duration,
+}
+
+/// A phase of fetching a single directory.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum DirPhase {
+    /// We are fetching a consensus.
+    FetchingConsensus,
+    /// We have a consensus, and are fetching the authority certificates we
+    /// need to validate it.
+    FetchingCerts,
+    /// We have validated a consensus, and are fetching its microdescriptors.
+    FetchingMicrodescs,
+    /// We have enough information to use this directory.
+    ///
+    /// We may still be fetching the microdescriptors that we lack.
+    Usable,
+}
+
+/// Detailed metrics about our progress in fetching a single directory.
+///
+/// Unlike [`DirBootstrapStatus::frac_at`], these numbers have a fixed
+/// meaning, and are suitable for showing to a user.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct DirAttemptMetrics {
+    /// Which phase of fetching this directory are we in?
+    pub phase: DirPhase,
+    /// The number of authority certificates that we have, and the number that
+    /// we want, if we are fetching certificates.
+    pub certs: Option<(u16, u16)>,
+    /// The number of microdescriptors that we have, and the number that we
+    /// want, if we have validated the consensus.
+    pub microdescs: Option<(u32, u32)>,
+    /// The total size of the response bodies that we have downloaded.
+    pub n_bytes_downloaded: u64,
+    /// The number of distinct directory caches that we have contacted.
+    ///
+    /// Requests sent over a stream that we did not open ourselves do not count
+    /// towards this number, since we don't know where they went.
+    pub n_caches_contacted: usize,
+    /// The number of download requests that we have made.
+    pub n_requests: usize,
+    /// The number of those requests that failed, or were declined.
+    pub n_failed_requests: usize,
+    /// When we launched our first set of download requests, if we have.
+    pub first_request: Option<SystemTime>,
+    /// When we launched our most recent set of download requests, if we have.
+    pub last_request: Option<SystemTime>,
+    /// The total time that we have spent waiting for sets of requests to
+    /// finish.
+    pub time_downloading: Duration,
+    /// The time taken by our slowest single request.
+    pub slowest_request: Duration,
+    /// The number of times we have had to restart fetching this directory.
+    pub n_resets: usize,
+}
+
+/// A snapshot of detailed metrics about our attempts to fetch a directory.
+///
+/// Returned by [`DirBootstrapStatus::metrics`] and
+/// [`DirMgr::bootstrap_metrics`](crate::DirMgr::bootstrap_metrics).
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct DirBootstrapMetrics {
+    /// Metrics for the directory that we are using, or that we will use as
+    /// soon as it is complete enough.
+    pub current: Option<DirAttemptMetrics>,
+    /// Metrics for the directory that we are fetching to replace `current`, if
+    /// there is one.
+    pub next: Option<DirAttemptMetrics>,
 }
 
 /// How much progress have we made in downloading a given directory?
@@ -538,6 +651,14 @@ impl DirBootstrapStatus {
         chain!(current, next,)
     }
 
+    /// Return detailed metrics about the directories that we are fetching.
+    pub fn metrics(&self) -> DirBootstrapMetrics {
+        DirBootstrapMetrics {
+            current: self.current().map(DirStatus::metrics),
+            next: self.next().map(DirStatus::metrics),
+        }
+    }
+
     /// Return the fraction of completion for directory download, in a form
     /// suitable for a progress bar at some particular time.
     ///
@@ -661,6 +782,21 @@ impl DirBootstrapStatus {
             status.n_resets += 1;
         }
     }
+
+    /// Update this status by noting that, at `when`, we launched a set of
+    /// requests for a given download attempt, which took `elapsed` to finish
+    /// and had the outcomes in `requests`.
+    pub(crate) fn note_requests(
+        &mut self,
+        attempt_id: AttemptId,
+        when: SystemTime,
+        elapsed: Duration,
+        requests: &[RequestRecord],
+    ) {
+        if let Some(status) = self.mut_status_for(attempt_id) {
+            status.downloads.note_requests(when, elapsed, requests);
+        }
+    }
 }
 
 impl StatusEntry {
@@ -674,7 +810,59 @@ impl StatusEntry {
     }
 }
 
+impl DownloadCounters {
+    /// Record a set of requests launched at `when`, which took `elapsed` to
+    /// finish and had the outcomes in `requests`.
+    fn note_requests(&mut self, when: SystemTime, elapsed: Duration, requests: &[RequestRecord]) {
+        self.first_request.get_or_insert(when);
+        self.last_request = Some(when);
+        self.time_downloading += elapsed;
+        for r in requests {
+            self.n_requests += 1;
+            if !r.ok {
+                self.n_failed_requests += 1;
+            }
+            self.n_bytes += r.n_bytes as u64;
+            self.caches.extend(r.cache);
+            self.slowest_request = self.slowest_request.max(r.elapsed);
+        }
+    }
+}
+
 impl DirStatus {
+    /// Return detailed metrics about this directory.
+    fn metrics(&self) -> DirAttemptMetrics {
+        let (phase, certs, microdescs) = match &self.progress {
+            DirProgress::NoConsensus { .. } => (DirPhase::FetchingConsensus, None, None),
+            DirProgress::FetchingCerts { n_certs, .. } => {
+                (DirPhase::FetchingCerts, Some(*n_certs), None)
+            }
+            DirProgress::Validated { n_mds, usable, .. } => {
+                let phase = if *usable {
+                    DirPhase::Usable
+                } else {
+                    DirPhase::FetchingMicrodescs
+                };
+                (phase, None, Some(*n_mds))
+            }
+        };
+        let d = &self.downloads;
+        DirAttemptMetrics {
+            phase,
+            certs,
+            microdescs,
+            n_bytes_downloaded: d.n_bytes,
+            n_caches_contacted: d.caches.len(),
+            n_requests: d.n_requests,
+            n_failed_requests: d.n_failed_requests,
+            first_request: d.first_request,
+            last_request: d.last_request,
+            time_downloading: d.time_downloading,
+            slowest_request: d.slowest_request,
+            n_resets: self.n_resets,
+        }
+    }
+
     /// Return the declared consensus lifetime for this directory, if we have one.
     fn declared_lifetime(&self) -> Option<&netstatus::Lifetime> {
         match &self.progress {
@@ -1065,6 +1253,96 @@ mod test {
         );
     }
 
+    #[test]
+    fn bootstrap_metrics() {
+        let now = SystemTime::now();
+        let hour = Duration::new(3600, 0);
+        let ms = Duration::from_millis;
+        let lifetime = netstatus::Lifetime::new(now, now + hour, now + hour * 2).unwrap();
+        let attempt = AttemptId::next();
+        let mut bs = DirBootstrapStatus::default();
+
+        let m = bs.metrics();
+        assert!(m.current.is_none() && m.next.is_none());
+
+        let cache = |n: u8| Some(RsaIdentity::from([n; 20]));
+        bs.note_requests(
+            attempt,
+            now,
+            ms(300),
+            &[RequestRecord {
+                cache: cache(1),
+                n_bytes: 1000,
+                ok: true,
+                elapsed: ms(250),
+            }],
+        );
+        bs.update_progress(
+            attempt,
+            DirProgress::FetchingCerts {
+                lifetime: lifetime.clone(),
+                usable_lifetime: lifetime.clone(),
+                n_certs: (2, 9),
+            },
+        );
+        let m = bs.metrics().current.unwrap();
+        assert_eq!(m.phase, DirPhase::FetchingCerts);
+        assert_eq!(m.certs, Some((2, 9)));
+        assert_eq!(m.microdescs, None);
+
+        bs.note_requests(
+            attempt,
+            now + ms(500),
+            ms(400),
+            &[
+                RequestRecord {
+                    cache: cache(1),
+                    n_bytes: 500,
+                    ok: true,
+                    elapsed: ms(100),
+                },
+                RequestRecord {
+                    cache: cache(2),
+                    n_bytes: 0,
+                    ok: false,
+                    elapsed: ms(400),
+                },
+                RequestRecord {
+                    cache: None,
+                    n_bytes: 20,
+                    ok: true,
+                    elapsed: ms(10),
+                },
+            ],
+        );
+        bs.update_progress(
+            attempt,
+            DirProgress::Validated {
+                lifetime: lifetime.clone(),
+                usable_lifetime: lifetime,
+                n_mds: (30, 40),
+                usable: false,
+            },
+        );
+        bs.note_reset(attempt);
+
+        let m = bs.metrics();
+        assert!(m.next.is_none());
+        let m = m.current.unwrap();
+        assert_eq!(m.phase, DirPhase::FetchingMicrodescs);
+        assert_eq!(m.certs, None);
+        assert_eq!(m.microdescs, Some((30, 40)));
+        assert_eq!(m.n_bytes_downloaded, 1520);
+        assert_eq!(m.n_caches_contacted, 2);
+        assert_eq!(m.n_requests, 4);
+        assert_eq!(m.n_failed_requests, 1);
+        assert_eq!(m.first_request, Some(now));
+        assert_eq!(m.last_request, Some(now + ms(500)));
+        assert_eq!(m.time_downloading, ms(700));
+        assert_eq!(m.slowest_request, ms(400));
+        assert_eq!(m.n_resets, 1);
+    }
+
     #[test]
     fn bootstrap_status() {
         use time::macros::datetime;
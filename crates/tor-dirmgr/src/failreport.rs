@@ -0,0 +1,407 @@
+//! Reports on why we gave up on bootstrapping.
+//!
+//! When we run out of attempts to bootstrap a directory, the error that we
+//! return ([`Error::CantAdvanceState`]) only says that we couldn't make
+//! progress.  To help diagnose why, we keep a log of the directory requests
+//! that we've made since our directory was last usable, and when we give up,
+//! we turn it into a [`BootstrapFailureReport`] that the caller can get from
+//! [`DirMgr::last_bootstrap_report`](crate::DirMgr::last_bootstrap_report).
+
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use tor_dirclient::DirResponse;
+use tor_guardmgr::fallback::FallbackList;
+use tor_linkspec::HasRelayIds as _;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+
+use crate::docid::ClientRequest;
+use crate::selftest::{self, SelfTestVerdict};
+use crate::Error;
+
+/// The way in which a single directory request failed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+#[non_exhaustive]
+pub enum RequestFailureClass {
+    /// We couldn't open a TCP connection to the cache.
+    ///
+    /// (We always connect to caches by address, so there is no separate
+    /// class for DNS failures.)
+    Tcp,
+    /// We opened a TCP connection, but our TLS (or Tor link) handshake
+    /// failed.
+    Tls,
+    /// We couldn't open a channel to the cache, and can't tell whether the
+    /// TCP connection or the handshake failed.
+    Channel,
+    /// The request, or the connection that it needed, took too long.
+    Timeout,
+    /// The cache answered, but declined our request, or sent something that
+    /// wasn't a well-formed answer.
+    BadResponse,
+    /// The request failed for some other reason, or we couldn't tell why.
+    Other,
+}
+
+/// The kind of directory cache that we asked for documents.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum CacheKind {
+    /// One of our configured fallback directories.
+    Fallback,
+    /// Any other cache: usually, one of our guards.
+    Guard,
+}
+
+/// A summary of the requests that we sent to a single directory cache.
+#[derive(Clone, Debug)]
+pub struct CacheAttempts {
+    /// The identity of the cache.
+    id: RsaIdentity,
+    /// What kind of cache this is.
+    kind: CacheKind,
+    /// How many requests we sent it.
+    n_requests: usize,
+    /// How many of those requests failed.
+    n_failures: usize,
+}
+
+impl CacheAttempts {
+    /// Return the RSA identity of this cache.
+    pub fn id(&self) -> &RsaIdentity {
+        &self.id
+    }
+
+    /// Return what kind of cache this is.
+    pub fn kind(&self) -> CacheKind {
+        self.kind
+    }
+
+    /// Return the number of requests that we sent to this cache.
+    pub fn n_requests(&self) -> usize {
+        self.n_requests
+    }
+
+    /// Return the number of our requests to this cache that failed.
+    pub fn n_failures(&self) -> usize {
+        self.n_failures
+    }
+}
+
+/// A report on a series of bootstrap attempts that ended with our giving up.
+///
+/// Returned by [`DirMgr::last_bootstrap_report`](crate::DirMgr::last_bootstrap_report).
+#[derive(Clone, Debug)]
+pub struct BootstrapFailureReport {
+    /// When we made the first request covered by this report.
+    started: Option<SystemTime>,
+    /// When we gave up.
+    ended: SystemTime,
+    /// How many download attempts we made.
+    n_attempts: usize,
+    /// How many requests we sent.
+    n_requests: usize,
+    /// Every cache that we asked, in order of identity.
+    caches: Vec<CacheAttempts>,
+    /// The number of failed requests of each class.
+    failures: BTreeMap<RequestFailureClass, usize>,
+    /// A description of the state that we couldn't get past, the last time
+    /// we got stuck.
+    state: Option<String>,
+    /// The number of microdescriptors that we were still missing, if we got
+    /// as far as fetching them.
+    n_missing_microdescs: Option<usize>,
+    /// The error that ended the last series of attempts.
+    last_error: Option<Error>,
+}
+
+impl BootstrapFailureReport {
+    /// Return the time of the first request covered by this report, if we
+    /// made any.
+    pub fn started(&self) -> Option<SystemTime> {
+        self.started
+    }
+
+    /// Return the time at which we gave up.
+    pub fn ended(&self) -> SystemTime {
+        self.ended
+    }
+
+    /// Return the number of download attempts that we made.
+    pub fn n_attempts(&self) -> usize {
+        self.n_attempts
+    }
+
+    /// Return the number of directory requests that we sent.
+    pub fn n_requests(&self) -> usize {
+        self.n_requests
+    }
+
+    /// Return every directory cache that we sent requests to.
+    ///
+    /// Requests that we sent through a
+    /// [`DocumentFetcher`](crate::DocumentFetcher), or over a stream from
+    /// [`DirMgr::use_stream_for_next_fetch`](crate::DirMgr::use_stream_for_next_fetch),
+    /// don't go to a cache that we know, so they only count towards
+    /// [`n_requests`](BootstrapFailureReport::n_requests) and the failure
+    /// classes.
+    pub fn caches(&self) -> &[CacheAttempts] {
+        &self.caches[..]
+    }
+
+    /// Return the number of requests that failed in the way described by
+    /// `class`.
+    pub fn n_failures(&self, class: RequestFailureClass) -> usize {
+        self.failures.get(&class).copied().unwrap_or(0)
+    }
+
+    /// Return every class of failure that we saw, with the number of
+    /// requests that failed in that way.
+    pub fn failures(&self) -> impl Iterator<Item = (RequestFailureClass, usize)> + '_ {
+        self.failures.iter().map(|(class, n)| (*class, *n))
+    }
+
+    /// Return a description of the state that we couldn't get past, the
+    /// last time we got stuck.
+    pub fn state(&self) -> Option<&str> {
+        self.state.as_deref()
+    }
+
+    /// Return the number of microdescriptors that we were still missing when
+    /// we got stuck, or None if we didn't get as far as fetching them.
+    pub fn n_missing_microdescs(&self) -> Option<usize> {
+        self.n_missing_microdescs
+    }
+
+    /// Return the error that ended our last series of download attempts,
+    /// if there was one.
+    pub fn last_error(&self) -> Option<&Error> {
+        self.last_error.as_ref()
+    }
+}
+
+/// A log of the directory requests that we've made since our directory was
+/// last usable.
+#[derive(Debug, Default)]
+pub(crate) struct FailureLog {
+    /// When we made the first request in this log.
+    started: Option<SystemTime>,
+    /// How many download attempts we've made.
+    n_attempts: usize,
+    /// How many requests we've sent.
+    n_requests: usize,
+    /// The caches that we've asked, by identity.
+    caches: BTreeMap<RsaIdentity, CacheAttempts>,
+    /// The number of failed requests of each class.
+    failures: BTreeMap<RequestFailureClass, usize>,
+    /// A description of the state that we last got stuck in.
+    state: Option<String>,
+    /// The number of microdescriptors that we were missing when we last got
+    /// stuck.
+    n_missing_microdescs: Option<usize>,
+    /// The error that ended our last series of download attempts.
+    last_error: Option<Error>,
+}
+
+impl FailureLog {
+    /// Note that we're starting a new download attempt.
+    pub(crate) fn note_attempt(&mut self) {
+        self.n_attempts += 1;
+    }
+
+    /// Note that, at `now`, we got the outcomes of a set of requests.
+    ///
+    /// Each entry in `requests` gives the identity of the cache that we
+    /// asked, if we know it, and how the request failed, if it did.  We use
+    /// `fallbacks` to tell which caches are fallbacks.
+    pub(crate) fn note_requests(
+        &mut self,
+        now: SystemTime,
+        requests: &[(Option<RsaIdentity>, Option<RequestFailureClass>)],
+        fallbacks: &FallbackList,
+    ) {
+        if requests.is_empty() {
+            return;
+        }
+        self.started.get_or_insert(now);
+        self.n_requests += requests.len();
+        for (cache, failure) in requests {
+            if let Some(class) = failure {
+                *self.failures.entry(*class).or_default() += 1;
+            }
+            if let Some(id) = cache {
+                let entry = self.caches.entry(*id).or_insert_with(|| {
+                    let is_fallback = fallbacks.iter().any(|fb| fb.rsa_identity() == Some(id));
+                    CacheAttempts {
+                        id: *id,
+                        kind: if is_fallback {
+                            CacheKind::Fallback
+                        } else {
+                            CacheKind::Guard
+                        },
+                        n_requests: 0,
+                        n_failures: 0,
+                    }
+                });
+                entry.n_requests += 1;
+                if failure.is_some() {
+                    entry.n_failures += 1;
+                }
+            }
+        }
+    }
+
+    /// Note that a series of download attempts ended with `error`, while we
+    /// were in a state described by `state`, missing `n_missing_microdescs`
+    /// microdescriptors.
+    pub(crate) fn note_stuck(&mut self, state: String, n_missing_microdescs: usize, error: Error) {
+        self.state = Some(state);
+        self.n_missing_microdescs = (n_missing_microdescs > 0).then_some(n_missing_microdescs);
+        self.last_error = Some(error);
+    }
+
+    /// Turn this log into a report, as of `now`, and start a new log.
+    pub(crate) fn take_report(&mut self, now: SystemTime) -> BootstrapFailureReport {
+        let log = std::mem::take(self);
+        BootstrapFailureReport {
+            started: log.started,
+            ended: now,
+            n_attempts: log.n_attempts,
+            n_requests: log.n_requests,
+            caches: log.caches.into_values().collect(),
+            failures: log.failures,
+            state: log.state,
+            n_missing_microdescs: log.n_missing_microdescs,
+            last_error: log.last_error,
+        }
+    }
+
+    /// Forget everything in this log.
+    pub(crate) fn clear(&mut self) {
+        *self = FailureLog::default();
+    }
+}
+
+/// Return the way in which `outcome`, the outcome of a directory request,
+/// failed; or None if it succeeded.
+pub(crate) fn classify(
+    outcome: &crate::Result<(ClientRequest, DirResponse)>,
+) -> Option<RequestFailureClass> {
+    match outcome {
+        Ok((_, response)) if response.status_code() == 200 && !response.is_partial() => None,
+        Ok(_) => Some(RequestFailureClass::BadResponse),
+        Err(e) => Some(match selftest::verdict_for_error(e) {
+            SelfTestVerdict::BlockedAtTcp => RequestFailureClass::Tcp,
+            SelfTestVerdict::BlockedAtTls => RequestFailureClass::Tls,
+            SelfTestVerdict::BlockedAtFirstHop => RequestFailureClass::Channel,
+            SelfTestVerdict::TimedOut => RequestFailureClass::Timeout,
+            SelfTestVerdict::BadResponse => RequestFailureClass::BadResponse,
+            SelfTestVerdict::Inconclusive | SelfTestVerdict::Ok => RequestFailureClass::Other,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tor_guardmgr::fallback::FallbackDir;
+    use tor_netdoc::doc::netstatus::ConsensusFlavor;
+
+    #[test]
+    fn failure_log() {
+        let fb_id = RsaIdentity::from([1; 20]);
+        let guard_id = RsaIdentity::from([2; 20]);
+        let fallback = {
+            let mut bld = FallbackDir::builder();
+            bld.ed_identity([1; 32].into())
+                .rsa_identity(fb_id)
+                .orports()
+                .push("127.0.0.1:9001".parse().unwrap());
+            bld.build().unwrap()
+        };
+        let fallbacks: FallbackList = vec![fallback].into();
+        let t1 = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let t2 = t1 + Duration::from_secs(60);
+
+        let mut log = FailureLog::default();
+        log.note_attempt();
+        log.note_requests(
+            t1,
+            &[
+                (Some(fb_id), Some(RequestFailureClass::Tcp)),
+                (Some(fb_id), None),
+                (None, Some(RequestFailureClass::Timeout)),
+            ],
+            &fallbacks,
+        );
+        log.note_attempt();
+        log.note_requests(
+            t2,
+            &[(Some(guard_id), Some(RequestFailureClass::Tcp))],
+            &fallbacks,
+        );
+        log.note_stuck("fetching".into(), 12, Error::CantAdvanceState);
+
+        let report = log.take_report(t2);
+        assert_eq!(report.started(), Some(t1));
+        assert_eq!(report.ended(), t2);
+        assert_eq!(report.n_attempts(), 2);
+        assert_eq!(report.n_requests(), 4);
+        assert_eq!(report.n_failures(RequestFailureClass::Tcp), 2);
+        assert_eq!(report.n_failures(RequestFailureClass::Timeout), 1);
+        assert_eq!(report.n_failures(RequestFailureClass::Tls), 0);
+        assert_eq!(report.failures().count(), 2);
+        assert_eq!(report.state(), Some("fetching"));
+        assert_eq!(report.n_missing_microdescs(), Some(12));
+        assert!(matches!(report.last_error(), Some(Error::CantAdvanceState)));
+
+        let caches = report.caches();
+        assert_eq!(caches.len(), 2);
+        assert_eq!(caches[0].id(), &fb_id);
+        assert_eq!(caches[0].kind(), CacheKind::Fallback);
+        assert_eq!(caches[0].n_requests(), 2);
+        assert_eq!(caches[0].n_failures(), 1);
+        assert_eq!(caches[1].kind(), CacheKind::Guard);
+
+        // Taking the report starts a new log.
+        let report = log.take_report(t2);
+        assert_eq!(report.n_requests(), 0);
+        assert!(report.state().is_none());
+    }
+
+    #[test]
+    fn classes() {
+        let request = ClientRequest::Consensus(tor_dirclient::request::ConsensusRequest::new(
+            ConsensusFlavor::Microdesc,
+        ));
+        let ok = Ok((request, DirResponse::from_body("hello")));
+        assert_eq!(classify(&ok), None);
+
+        let io_err = Arc::new(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+        let tcp = Err(Error::DocumentFetch {
+            from: "somewhere".into(),
+            action: crate::FetchAction::Connecting,
+            cause: io_err,
+        });
+        assert_eq!(classify(&tcp), Some(RequestFailureClass::Tcp));
+        assert_eq!(
+            classify(&Err(Error::NoDownloadSupport)),
+            Some(RequestFailureClass::Other)
+        );
+    }
+}
@@ -0,0 +1,371 @@
+//! [`DocumentFetcher`] implementations for fetching directory documents
+//! without building circuits.
+//!
+//! These are for environments where we can't reach any directory cache over
+//! Tor, but where a copy of the directory is available some other way: from a
+//! mirror on the open web, or from files that somebody has carried in.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use once_cell::sync::OnceCell;
+use tor_basic_utils::PathExt as _;
+use tor_checkable::{SelfSigned as _, Timebound as _};
+use tor_dirclient::request::WithHostHeader;
+use tor_dirclient::DirResponse;
+use tor_netdoc::doc::authcert::AuthCert;
+use tor_netdoc::doc::microdesc::MicrodescReader;
+use tor_netdoc::doc::netstatus::ConsensusFlavor;
+use tor_netdoc::AllowAnnotations;
+use tor_rtcompat::{tls::TlsConnector as _, Runtime};
+
+use crate::docid::ClientRequest;
+use crate::staticdir::{CERTS_FILE, CONSENSUS_FILE, MICRODESC_FILES};
+use crate::{DocumentFetcher, Error, FetchAction, Result};
+
+/// The name of the file from which [`LocalDirFetcher`] reads an ns-flavored
+/// consensus.
+const NS_CONSENSUS_FILE: &str = "cached-consensus";
+
+/// The names of the files from which [`LocalDirFetcher`] reads router
+/// descriptors.
+#[cfg(feature = "routerdesc")]
+const ROUTERDESC_FILES: &[&str] = &["cached-descriptors", "cached-descriptors.new"];
+
+/// The result of reading a single file for a [`LocalDirFetcher`]: its
+/// contents, if it exists.
+type FileContents = std::result::Result<Option<String>, Arc<std::io::Error>>;
+
+/// A [`DocumentFetcher`] that reads documents from a directory of files.
+///
+/// The files use the same names as in a C Tor data directory:
+/// `cached-microdesc-consensus` (or `cached-consensus`, for an ns-flavored
+/// consensus) holds the consensus; `cached-microdescs` and
+/// `cached-microdescs.new` hold microdescriptors; and `cached-certs` holds
+/// authority certificates.
+///
+/// We read all of the files once, the first time that we're asked for a
+/// document, and answer every request from what we read then: our requests
+/// come from an async context, where we shouldn't block on the filesystem any
+/// more than we have to.  To pick up replacement files, construct a new
+/// `LocalDirFetcher`.  From the certificate and microdescriptor files, we only
+/// return the documents that were requested.
+#[derive(Clone, Debug)]
+pub struct LocalDirFetcher {
+    /// The directory holding the files.
+    dir: PathBuf,
+    /// The contents of the files in `dir`, indexed by name, once we've read
+    /// them.
+    files: Arc<OnceCell<HashMap<&'static str, FileContents>>>,
+}
+
+impl LocalDirFetcher {
+    /// Construct a new `LocalDirFetcher` to read documents from the files in
+    /// `dir`.
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        LocalDirFetcher {
+            dir: dir.as_ref().to_owned(),
+            files: Default::default(),
+        }
+    }
+
+    /// Read every file that we might be asked for.
+    fn read_all(&self) -> HashMap<&'static str, FileContents> {
+        let names = [CONSENSUS_FILE, NS_CONSENSUS_FILE, CERTS_FILE]
+            .iter()
+            .chain(MICRODESC_FILES);
+        #[cfg(feature = "routerdesc")]
+        let names = names.chain(ROUTERDESC_FILES);
+        names
+            .map(|&name| {
+                let contents = match std::fs::read_to_string(self.dir.join(name)) {
+                    Ok(text) => Ok(Some(text)),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                    Err(e) => Err(Arc::new(e)),
+                };
+                (name, contents)
+            })
+            .collect()
+    }
+
+    /// Return the contents of every file in `names` that exists.
+    ///
+    /// It's an error if none of them do.
+    fn read_any(&self, names: &[&str]) -> Result<Vec<&str>> {
+        let files = self.files.get_or_init(|| self.read_all());
+        let mut texts = Vec::new();
+        for name in names {
+            match files.get(*name) {
+                Some(Ok(Some(text))) => texts.push(text.as_str()),
+                Some(Ok(None)) | None => {}
+                Some(Err(e)) => return Err(self.error(Arc::clone(e))),
+            }
+        }
+        if texts.is_empty() {
+            return Err(self.error(Arc::new(std::io::ErrorKind::NotFound.into())));
+        }
+        Ok(texts)
+    }
+
+    /// Return an error for a failure to read from our directory.
+    fn error(&self, error: Arc<std::io::Error>) -> Error {
+        Error::DocumentFetch {
+            from: format!("local directory {}", self.dir.display_lossy()),
+            action: FetchAction::Reading,
+            cause: error,
+        }
+    }
+}
+
+#[async_trait]
+impl DocumentFetcher for LocalDirFetcher {
+    async fn fetch(&self, request: &ClientRequest) -> Result<DirResponse> {
+        let body = match request {
+            ClientRequest::Consensus(req) => {
+                let name = match req.flavor() {
+                    ConsensusFlavor::Microdesc => CONSENSUS_FILE,
+                    ConsensusFlavor::Ns => NS_CONSENSUS_FILE,
+                    other => return Err(Error::UnsupportedFlavor(other)),
+                };
+                self.read_any(&[name])?.concat()
+            }
+            ClientRequest::AuthCert(req) => {
+                let wanted: Vec<_> = req.keys().collect();
+                let mut body = String::new();
+                for text in self.read_any(&[CERTS_FILE])? {
+                    // Skip anything we can't parse: the state machine would
+                    // reject it anyway.
+                    for cert in AuthCert::parse_multiple(text).flatten() {
+                        let within = cert.within(text);
+                        let Ok(cert) = cert.check_signature() else {
+                            continue;
+                        };
+                        if wanted.contains(&cert.dangerously_assume_timely().key_ids()) {
+                            body.extend(within);
+                        }
+                    }
+                }
+                body
+            }
+            ClientRequest::Microdescs(req) => {
+                let wanted: Vec<_> = req.digests().collect();
+                let mut body = String::new();
+                for text in self.read_any(MICRODESC_FILES)? {
+                    let reader = MicrodescReader::new(text, &AllowAnnotations::AnnotationsAllowed);
+                    for annotated in reader.flatten() {
+                        if wanted.contains(&annotated.md().digest()) {
+                            body.extend(annotated.within(text));
+                        }
+                    }
+                }
+                body
+            }
+            #[cfg(feature = "routerdesc")]
+            ClientRequest::RouterDescs(_) => self.read_any(ROUTERDESC_FILES)?.concat(),
+        };
+        Ok(DirResponse::from_body(body))
+    }
+}
+
+/// A [`DocumentFetcher`] that sends requests to a mirror over HTTPS.
+///
+/// The mirror must answer requests at the same paths as a directory cache
+/// (`/tor/status-vote/current/consensus-microdesc`, `/tor/micro/d/...`, and so
+/// on).  We make a new connection for each request.
+///
+/// We check that the mirror's TLS certificate is valid for its hostname, and
+/// send that hostname in our `Host` header.  We still check the documents
+/// that the mirror gives us, so we don't need to trust it: the TLS connection
+/// keeps anybody else from seeing which documents we fetch, or from
+/// answering in the mirror's place.
+#[derive(Clone, Debug)]
+pub struct HttpsMirrorFetcher<R: Runtime> {
+    /// The runtime to use for making connections.
+    runtime: R,
+    /// The address of the mirror.
+    addr: SocketAddr,
+    /// The hostname to send when negotiating TLS with the mirror.
+    hostname: String,
+}
+
+impl<R: Runtime> HttpsMirrorFetcher<R> {
+    /// Construct a new `HttpsMirrorFetcher` to fetch documents from the
+    /// mirror at `addr`, whose certificate must be valid for `hostname`.
+    pub fn new(runtime: R, addr: SocketAddr, hostname: impl Into<String>) -> Self {
+        HttpsMirrorFetcher {
+            runtime,
+            addr,
+            hostname: hostname.into(),
+        }
+    }
+
+    /// Return an error for a failure to talk to our mirror while doing
+    /// `action`.
+    fn error(&self, action: FetchAction, error: std::io::Error) -> Error {
+        Error::DocumentFetch {
+            from: format!("mirror {} at {}", self.hostname, self.addr),
+            action,
+            cause: Arc::new(error),
+        }
+    }
+}
+
+#[async_trait]
+impl<R: Runtime> DocumentFetcher for HttpsMirrorFetcher<R> {
+    async fn fetch(&self, request: &ClientRequest) -> Result<DirResponse> {
+        let stream = self
+            .runtime
+            .connect(&self.addr)
+            .await
+            .map_err(|e| self.error(FetchAction::Connecting, e))?;
+        let mut stream = self
+            .runtime
+            .tls_connector()
+            .negotiate_validated(stream, &self.hostname)
+            .await
+            .map_err(|e| self.error(FetchAction::NegotiatingTls, e))?;
+        let request = WithHostHeader::new(request.as_requestable(), &self.hostname);
+        Ok(tor_dirclient::send_request(&self.runtime, &request, &mut stream, None).await?)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::staticdir::test::{CONSENSUS2, MICRODESCS};
+    use futures::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _, StreamExt as _};
+    use tor_dirclient::request::{ConsensusRequest, MicrodescRequest};
+    use tor_rtcompat::NetStreamListener as _;
+    use tor_rtmock::net::MockNetwork;
+
+    /// Act as a directory cache on `conn`: read a request, answer it with
+    /// `response`, and hang up.  Return the request.
+    pub(crate) async fn answer_request<S>(conn: &mut S, response: &[u8]) -> String
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut request = Vec::new();
+        let mut buf = [0_u8; 256];
+        while !request.ends_with(b"\r\n\r\n") {
+            let n = conn.read(&mut buf).await.unwrap();
+            assert_ne!(n, 0);
+            request.extend_from_slice(&buf[..n]);
+        }
+        conn.write_all(response).await.unwrap();
+        conn.close().await.unwrap();
+        String::from_utf8(request).unwrap()
+    }
+
+    #[test]
+    fn local_dir() {
+        tor_rtcompat::test_with_one_runtime!(|_rt| async {
+            let dir = tempfile::TempDir::new().unwrap();
+            std::fs::write(dir.path().join(CONSENSUS_FILE), CONSENSUS2).unwrap();
+            std::fs::write(dir.path().join(MICRODESC_FILES[0]), MICRODESCS).unwrap();
+            let fetcher = LocalDirFetcher::new(dir.path());
+
+            let req = ClientRequest::Consensus(ConsensusRequest::new(ConsensusFlavor::Microdesc));
+            let resp = fetcher.fetch(&req).await.unwrap();
+            assert_eq!(resp.output_string().unwrap(), CONSENSUS2);
+
+            // We don't have an ns consensus.
+            let req = ClientRequest::Consensus(ConsensusRequest::new(ConsensusFlavor::Ns));
+            let err = fetcher.fetch(&req).await.unwrap_err();
+            assert!(matches!(err, Error::DocumentFetch { .. }));
+
+            // We only get the microdescriptors we asked for.
+            let all: Vec<_> =
+                MicrodescReader::new(MICRODESCS, &AllowAnnotations::AnnotationsAllowed)
+                    .map(|md| md.unwrap().into_microdesc())
+                    .collect();
+            assert!(all.len() > 2);
+            let mut mdreq = MicrodescRequest::new();
+            mdreq.push(*all[0].digest());
+            mdreq.push(*all[2].digest());
+            let resp = fetcher
+                .fetch(&ClientRequest::Microdescs(mdreq))
+                .await
+                .unwrap();
+            let got: Vec<_> = MicrodescReader::new(
+                resp.output_string().unwrap(),
+                &AllowAnnotations::AnnotationsNotAllowed,
+            )
+            .map(|md| *md.unwrap().md().digest())
+            .collect();
+            assert_eq!(got, vec![*all[0].digest(), *all[2].digest()]);
+        });
+    }
+
+    #[test]
+    fn https_mirror() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            let network = MockNetwork::new();
+            let mirror_addr: SocketAddr = "192.0.2.80:443".parse().unwrap();
+            let client_rt = network
+                .builder()
+                .add_address("192.0.2.17".parse().unwrap())
+                .runtime(rt.clone());
+            let mirror_rt = network
+                .builder()
+                .add_address(mirror_addr.ip())
+                .runtime(rt.clone());
+            let listener = mirror_rt
+                .mock_net()
+                .listen_tls(&mirror_addr, b"mirror.example.com".to_vec())
+                .unwrap();
+
+            let fetcher = HttpsMirrorFetcher::new(client_rt, mirror_addr, "mirror.example.com");
+            let req = ClientRequest::Consensus(ConsensusRequest::new(ConsensusFlavor::Microdesc));
+            let mut incoming = listener.incoming();
+            let (resp, request) = futures::join!(fetcher.fetch(&req), async {
+                let (mut conn, _) = incoming.next().await.unwrap().unwrap();
+                answer_request(&mut conn, b"HTTP/1.0 200 OK\r\n\r\nconsensus goes here").await
+            });
+
+            assert!(request.starts_with("GET /tor/status-vote/current/consensus-microdesc"));
+            assert!(request.contains("\r\nhost: mirror.example.com\r\n"));
+            let resp = resp.unwrap();
+            assert!(resp.source().is_none());
+            assert_eq!(resp.output_string().unwrap(), "consensus goes here");
+
+            // The mirror's certificate isn't valid for this name.
+            let impostor =
+                HttpsMirrorFetcher::new(fetcher.runtime.clone(), mirror_addr, "other.example.com");
+            let (err, ()) = futures::join!(impostor.fetch(&req), async {
+                let _conn = incoming.next().await.unwrap().unwrap();
+            });
+            assert!(matches!(
+                err.unwrap_err(),
+                Error::DocumentFetch {
+                    action: FetchAction::NegotiatingTls,
+                    ..
+                }
+            ));
+
+            // Nobody is listening here.
+            let fetcher = HttpsMirrorFetcher::new(
+                fetcher.runtime.clone(),
+                "192.0.2.81:443".parse().unwrap(),
+                "mirror.example.com",
+            );
+            let err = fetcher.fetch(&req).await.unwrap_err();
+            assert!(matches!(err, Error::DocumentFetch { .. }));
+        });
+    }
+}
@@ -7,15 +7,47 @@
 //! future versions, or its API might change completely. There are no semver
 //! guarantees.
 
+use std::collections::HashSet;
 use std::fmt::Debug;
 use std::sync::Arc;
 
 use crate::Result;
-use tor_netdoc::doc::{microdesc::Microdesc, netstatus::UncheckedMdConsensus};
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdoc::doc::{
+    microdesc::Microdesc,
+    netstatus::{
+        MdConsensusRouterStatus, RelayFlags, RelayWeight, RouterStatus, UncheckedMdConsensus,
+    },
+};
 
 /// Filtering configuration, as provided to the directory code
 pub type FilterConfig = Option<Arc<dyn DirFilter>>;
 
+/// Configuration for the built-in filters.
+///
+/// Each filter that is configured here is applied in the order of the fields
+/// below, before any custom [`FilterConfig`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct BuiltinFilterConfig {
+    /// Relays to remove from every consensus, by RSA identity.
+    pub drop_relays: Vec<RsaIdentity>,
+    /// Flags to remove from every relay in every consensus.
+    pub clear_flags: RelayFlags,
+    /// If present, the largest weight that any relay may have in a consensus.
+    pub max_weight: Option<u32>,
+}
+
+impl Default for BuiltinFilterConfig {
+    fn default() -> Self {
+        Self {
+            drop_relays: Vec::new(),
+            clear_flags: RelayFlags::empty(),
+            max_weight: None,
+        }
+    }
+}
+
 /// An object that can filter directory documents before they're handled.
 ///
 /// Instances of DirFilter can be used for testing, to modify directory data
@@ -37,3 +69,282 @@ pub trait DirFilter: Debug + Send + Sync {
 pub struct NilFilter;
 
 impl DirFilter for NilFilter {}
+
+/// A [`DirFilter`] that applies a list of other filters, in order.
+#[derive(Debug, Clone, Default)]
+pub struct FilterChain {
+    /// The filters to apply.
+    filters: Vec<Arc<dyn DirFilter>>,
+}
+
+impl FilterChain {
+    /// Return a new empty `FilterChain`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return a new `FilterChain` with the built-in filters from `config`.
+    pub fn from_config(config: &BuiltinFilterConfig) -> Self {
+        let mut chain = Self::new();
+        if !config.drop_relays.is_empty() {
+            chain.push(Arc::new(DropRelaysFilter::new(
+                config.drop_relays.iter().copied(),
+            )));
+        }
+        if !config.clear_flags.is_empty() {
+            chain.push(Arc::new(ClearFlagsFilter::new(config.clear_flags)));
+        }
+        if let Some(max_weight) = config.max_weight {
+            chain.push(Arc::new(CapWeightFilter::new(max_weight)));
+        }
+        chain
+    }
+
+    /// Add `filter` to the end of this chain.
+    pub fn push(&mut self, filter: Arc<dyn DirFilter>) -> &mut Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Return true if this chain has no filters.
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+}
+
+impl DirFilter for FilterChain {
+    fn filter_consensus(&self, consensus: UncheckedMdConsensus) -> Result<UncheckedMdConsensus> {
+        self.filters
+            .iter()
+            .try_fold(consensus, |consensus, f| f.filter_consensus(consensus))
+    }
+    fn filter_md(&self, md: Microdesc) -> Result<Microdesc> {
+        self.filters.iter().try_fold(md, |md, f| f.filter_md(md))
+    }
+}
+
+/// Apply `func` to the list of relays in `consensus`.
+fn modify_relays<F>(consensus: UncheckedMdConsensus, func: F) -> UncheckedMdConsensus
+where
+    F: FnOnce(&mut Vec<MdConsensusRouterStatus>),
+{
+    let (mut consensus, (start_time, end_time)) = consensus.dangerously_into_parts();
+    consensus.modify_relays(func);
+    UncheckedMdConsensus::new_from_start_end(consensus, start_time, end_time)
+}
+
+/// A [`DirFilter`] that removes a set of relays from every consensus.
+#[derive(Debug, Clone)]
+pub struct DropRelaysFilter {
+    /// The RSA identities of the relays to remove.
+    ids: HashSet<RsaIdentity>,
+}
+
+impl DropRelaysFilter {
+    /// Return a new filter to remove the relays with the RSA identities in
+    /// `ids`.
+    pub fn new(ids: impl IntoIterator<Item = RsaIdentity>) -> Self {
+        Self {
+            ids: ids.into_iter().collect(),
+        }
+    }
+}
+
+impl DirFilter for DropRelaysFilter {
+    fn filter_consensus(&self, consensus: UncheckedMdConsensus) -> Result<UncheckedMdConsensus> {
+        Ok(modify_relays(consensus, |relays| {
+            relays.retain(|rs| !self.ids.contains(rs.rsa_identity()));
+        }))
+    }
+}
+
+/// A [`DirFilter`] that removes some flags from every relay in every consensus.
+#[derive(Debug, Clone)]
+pub struct ClearFlagsFilter {
+    /// The flags to remove.
+    flags: RelayFlags,
+}
+
+impl ClearFlagsFilter {
+    /// Return a new filter to remove `flags` from every relay.
+    pub fn new(flags: RelayFlags) -> Self {
+        Self { flags }
+    }
+}
+
+impl DirFilter for ClearFlagsFilter {
+    fn filter_consensus(&self, consensus: UncheckedMdConsensus) -> Result<UncheckedMdConsensus> {
+        Ok(modify_relays(consensus, |relays| {
+            for rs in relays {
+                rs.clear_flags(self.flags);
+            }
+        }))
+    }
+}
+
+/// A [`DirFilter`] that limits the weight of every relay in every consensus.
+///
+/// Weights above the limit are lowered to it; whether a weight is measured
+/// does not change.
+#[derive(Debug, Clone)]
+pub struct CapWeightFilter {
+    /// The largest weight to allow.
+    max_weight: u32,
+}
+
+impl CapWeightFilter {
+    /// Return a new filter to lower every weight above `max_weight` to
+    /// `max_weight`.
+    pub fn new(max_weight: u32) -> Self {
+        Self { max_weight }
+    }
+}
+
+impl DirFilter for CapWeightFilter {
+    fn filter_consensus(&self, consensus: UncheckedMdConsensus) -> Result<UncheckedMdConsensus> {
+        Ok(modify_relays(consensus, |relays| {
+            for rs in relays {
+                let weight = match *rs.weight() {
+                    RelayWeight::Unmeasured(w) => RelayWeight::Unmeasured(w.min(self.max_weight)),
+                    RelayWeight::Measured(w) => RelayWeight::Measured(w.min(self.max_weight)),
+                    other => other,
+                };
+                rs.set_weight(weight);
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use tor_checkable::{ExternallySigned, Timebound};
+    use tor_netdoc::doc::netstatus::MdConsensus;
+
+    const CONSENSUS: &str = include_str!("../testdata/mdconsensus1.txt");
+
+    /// Parse our test consensus, run it through `filter`, and return its relays.
+    fn filtered_relays(filter: &dyn DirFilter) -> Vec<MdConsensusRouterStatus> {
+        let (_, _, consensus) = MdConsensus::parse(CONSENSUS).unwrap();
+        let consensus = filter.filter_consensus(consensus).unwrap();
+        consensus
+            .dangerously_assume_timely()
+            .dangerously_assume_wellsigned()
+            .relays()
+            .to_vec()
+    }
+
+    #[test]
+    fn empty_chain() {
+        let chain = FilterChain::from_config(&BuiltinFilterConfig::default());
+        assert!(chain.is_empty());
+        let unfiltered = filtered_relays(&NilFilter);
+        let relays = filtered_relays(&chain);
+        assert_eq!(relays.len(), unfiltered.len());
+    }
+
+    #[test]
+    fn builtin_filters() {
+        let unfiltered = filtered_relays(&NilFilter);
+        let dropped = *unfiltered[0].rsa_identity();
+        assert!(unfiltered.iter().any(|rs| rs.is_flagged_guard()));
+
+        let mut config = BuiltinFilterConfig::default();
+        config.drop_relays.push(dropped);
+        config.clear_flags = RelayFlags::GUARD | RelayFlags::HSDIR;
+        config.max_weight = Some(100);
+        let chain = FilterChain::from_config(&config);
+        assert!(!chain.is_empty());
+
+        let relays = filtered_relays(&chain);
+        assert_eq!(relays.len(), unfiltered.len() - 1);
+        assert!(relays.iter().all(|rs| rs.rsa_identity() != &dropped));
+        assert!(relays.iter().all(|rs| !rs.is_flagged_guard()));
+        assert!(relays.iter().all(|rs| !rs.is_flagged_hsdir()));
+        for (rs, orig) in relays.iter().zip(&unfiltered[1..]) {
+            assert_eq!(rs.rsa_identity(), orig.rsa_identity());
+            assert_eq!(rs.is_flagged_exit(), orig.is_flagged_exit());
+        }
+    }
+
+    #[test]
+    fn cap_weight() {
+        /// A filter that gives every relay a different weight.
+        #[derive(Debug)]
+        struct SetWeights;
+        impl DirFilter for SetWeights {
+            fn filter_consensus(
+                &self,
+                consensus: UncheckedMdConsensus,
+            ) -> Result<UncheckedMdConsensus> {
+                Ok(modify_relays(consensus, |relays| {
+                    for (i, rs) in relays.iter_mut().enumerate() {
+                        let w = i as u32 * 50;
+                        rs.set_weight(if i % 2 == 0 {
+                            RelayWeight::Measured(w)
+                        } else {
+                            RelayWeight::Unmeasured(w)
+                        });
+                    }
+                }))
+            }
+        }
+
+        let mut chain = FilterChain::new();
+        chain.push(Arc::new(SetWeights));
+        chain.push(Arc::new(CapWeightFilter::new(120)));
+        let relays = filtered_relays(&chain);
+        let weights = relays.iter().map(|rs| *rs.weight()).collect::<Vec<_>>();
+        assert_eq!(
+            weights,
+            vec![
+                RelayWeight::Measured(0),
+                RelayWeight::Unmeasured(50),
+                RelayWeight::Measured(100),
+                RelayWeight::Unmeasured(120),
+                RelayWeight::Measured(120),
+                RelayWeight::Unmeasured(120),
+            ]
+        );
+    }
+
+    #[test]
+    fn chain_order() {
+        /// A filter that checks that a given relay is already gone.
+        #[derive(Debug)]
+        struct CheckDropped(RsaIdentity);
+        impl DirFilter for CheckDropped {
+            fn filter_consensus(
+                &self,
+                consensus: UncheckedMdConsensus,
+            ) -> Result<UncheckedMdConsensus> {
+                Ok(modify_relays(consensus, |relays| {
+                    assert!(relays.iter().all(|rs| rs.rsa_identity() != &self.0));
+                }))
+            }
+        }
+
+        // The built-in filters run first, so a custom filter that comes later
+        // never sees the dropped relay.
+        let unfiltered = filtered_relays(&NilFilter);
+        let dropped = *unfiltered[0].rsa_identity();
+        let mut config = BuiltinFilterConfig::default();
+        config.drop_relays.push(dropped);
+        let mut chain = FilterChain::from_config(&config);
+        chain.push(Arc::new(CheckDropped(dropped)));
+        assert_eq!(filtered_relays(&chain).len(), unfiltered.len() - 1);
+    }
+}
@@ -0,0 +1,284 @@
+//! A watchdog that notices when our directory information is getting old.
+//!
+//! Once a consensus stops being fresh, we keep using it while we try to
+//! download a new one, as long as it is still within our
+//! [`DirTolerance`](crate::DirTolerance).  Usually a new consensus arrives
+//! quickly.  But if we are stuck on an old one for a long time, something is
+//! probably wrong (a broken network, a censor, a bad clock), and eventually
+//! the consensus will become too old to use at all.
+//!
+//! The watchdog in this module lets applications find out about this
+//! situation before it starts to break things, so that they can tell their
+//! users.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
+use tor_config::{impl_standard_builder, ConfigBuildError};
+use tor_netdoc::doc::netstatus::Lifetime;
+
+/// An object that wants to be told when our directory information is
+/// getting stale.
+///
+/// Install one with [`FreshnessWatchdogConfigBuilder::hook`].
+pub trait StalenessAlertHook: Debug + Send + Sync {
+    /// Called when the directory we are using has been stale for longer
+    /// than the configured threshold.
+    ///
+    /// This is called once each time the directory becomes "too stale";
+    /// it won't be called again until we have had a fresh directory in the
+    /// meantime.
+    ///
+    /// This function should not block.
+    fn directory_getting_stale(&self, alert: &StalenessAlert);
+}
+
+/// Configuration for the directory freshness watchdog.
+///
+/// This type is immutable once constructed.  To build one, use
+/// [`FreshnessWatchdogConfigBuilder`].
+#[derive(Debug, Clone, Builder)]
+#[builder(build_fn(validate = "Self::validate", error = "ConfigBuildError"))]
+#[builder(derive(Debug, Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct FreshnessWatchdogConfig {
+    /// How long after the end of a consensus's fresh period we should wait
+    /// before we report that it is getting stale.
+    ///
+    /// A consensus is normally fresh for an hour, and we expect to have
+    /// replaced it well before it has been stale for three more: so this
+    /// defaults to three hours.
+    #[builder(default = "Duration::from_secs(3 * 60 * 60)")]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    pub(crate) alert_after: Duration,
+
+    /// How often we should check the age of our directory.
+    ///
+    /// Must be nonzero.  Defaults to five minutes.
+    #[builder(default = "Duration::from_secs(5 * 60)")]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    pub(crate) check_interval: Duration,
+
+    /// A hook to call when our directory has been stale for too long.
+    ///
+    /// We report the same information via
+    /// [`DirMgr::freshness_events`](crate::DirMgr::freshness_events)
+    /// whether or not this is set.
+    #[builder(default, setter(strip_option))]
+    #[builder_field_attr(serde(skip))]
+    pub(crate) hook: Option<Arc<dyn StalenessAlertHook>>,
+}
+
+impl_standard_builder! { FreshnessWatchdogConfig }
+
+impl PartialEq for FreshnessWatchdogConfig {
+    fn eq(&self, other: &Self) -> bool {
+        // We can't compare hooks, so two configurations are only equal if
+        // they have the very same hook.
+        let same_hook = match (&self.hook, &other.hook) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            (_, _) => false,
+        };
+        self.alert_after == other.alert_after
+            && self.check_interval == other.check_interval
+            && same_hook
+    }
+}
+
+impl FreshnessWatchdogConfigBuilder {
+    /// Check that this builder will give a reasonable configuration.
+    fn validate(&self) -> std::result::Result<(), ConfigBuildError> {
+        if self.check_interval == Some(Duration::ZERO) {
+            return Err(ConfigBuildError::Invalid {
+                field: "check_interval".to_owned(),
+                problem: "Cannot be zero".to_owned(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl FreshnessWatchdogConfig {
+    /// Return how long a consensus must have been stale before we report it.
+    pub fn alert_after(&self) -> Duration {
+        self.alert_after
+    }
+
+    /// Return how often we check the age of our directory.
+    pub fn check_interval(&self) -> Duration {
+        self.check_interval
+    }
+}
+
+/// A report that the directory we are using has been stale for too long.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StalenessAlert {
+    /// The time at which the directory stopped being fresh.
+    fresh_until: SystemTime,
+    /// The time at which the directory stops being valid.
+    valid_until: SystemTime,
+    /// How long the directory has been stale, as of when we checked.
+    stale_for: Duration,
+}
+
+impl StalenessAlert {
+    /// Return the time at which the directory stopped being fresh.
+    pub fn fresh_until(&self) -> SystemTime {
+        self.fresh_until
+    }
+
+    /// Return the time at which the directory stops being valid, according
+    /// to the consensus itself.
+    ///
+    /// (We may continue to tolerate it for a while after this time; see
+    /// [`DirTolerance`](crate::DirTolerance).)
+    pub fn valid_until(&self) -> SystemTime {
+        self.valid_until
+    }
+
+    /// Return how long the directory had been stale when we noticed.
+    pub fn stale_for(&self) -> Duration {
+        self.stale_for
+    }
+}
+
+/// An event from the directory freshness watchdog.
+///
+/// Returned by [`DirMgr::freshness_events`](crate::DirMgr::freshness_events).
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum FreshnessEvent {
+    /// Our directory has been stale for longer than the configured
+    /// threshold.
+    GettingStale(StalenessAlert),
+    /// After reporting [`FreshnessEvent::GettingStale`], we have found a
+    /// fresh directory again.
+    Fresh,
+}
+
+/// The state of the directory freshness watchdog.
+///
+/// This remembers whether we have alerted about the current stale period, so
+/// that we only alert once per period.
+#[derive(Debug, Default)]
+pub(crate) struct FreshnessWatchdog {
+    /// True if we have reported that our directory is getting stale, and
+    /// haven't seen a fresh one since.
+    alerted: bool,
+}
+
+impl FreshnessWatchdog {
+    /// Check whether a directory with `lifetime` is getting stale as of
+    /// `now`, and return an event to report if our opinion has changed.
+    pub(crate) fn check(
+        &mut self,
+        lifetime: &Lifetime,
+        now: SystemTime,
+        alert_after: Duration,
+    ) -> Option<FreshnessEvent> {
+        let fresh_until = lifetime.fresh_until();
+        match now.duration_since(fresh_until) {
+            Ok(stale_for) if stale_for >= alert_after => {
+                if self.alerted {
+                    return None;
+                }
+                self.alerted = true;
+                Some(FreshnessEvent::GettingStale(StalenessAlert {
+                    fresh_until,
+                    valid_until: lifetime.valid_until(),
+                    stale_for,
+                }))
+            }
+            Ok(_) => None,
+            Err(_) => {
+                // The directory is fresh.
+                if self.alerted {
+                    self.alerted = false;
+                    Some(FreshnessEvent::Fresh)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn config() {
+        let cfg = FreshnessWatchdogConfig::default();
+        assert_eq!(cfg.check_interval(), Duration::from_secs(5 * 60));
+        assert!(cfg.hook.is_none());
+
+        let cfg = FreshnessWatchdogConfig::builder()
+            .check_interval(Duration::from_secs(60))
+            .alert_after(Duration::from_secs(2 * 60 * 60))
+            .build()
+            .unwrap();
+        assert_eq!(cfg.check_interval(), Duration::from_secs(60));
+        assert_eq!(cfg.alert_after(), Duration::from_secs(2 * 60 * 60));
+
+        // A zero interval would have us check in a busy loop.
+        let err = FreshnessWatchdogConfig::builder()
+            .check_interval(Duration::ZERO)
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, ConfigBuildError::Invalid { .. }));
+    }
+
+    #[test]
+    fn watchdog() {
+        let hour = Duration::from_secs(3600);
+        let start = SystemTime::UNIX_EPOCH + 1000 * hour;
+        let lifetime = Lifetime::new(start, start + hour, start + 3 * hour).unwrap();
+        let alert_after = hour;
+        let mut wd = FreshnessWatchdog::default();
+
+        // Fresh, or only a little stale: nothing to say.
+        assert_eq!(wd.check(&lifetime, start, alert_after), None);
+        assert_eq!(wd.check(&lifetime, start + hour * 3 / 2, alert_after), None);
+
+        // Stale for too long: we alert, but only once.
+        let ev = wd.check(&lifetime, start + hour * 5 / 2, alert_after);
+        let Some(FreshnessEvent::GettingStale(alert)) = ev else {
+            panic!("unexpected event {:?}", ev);
+        };
+        assert_eq!(alert.fresh_until(), start + hour);
+        assert_eq!(alert.valid_until(), start + 3 * hour);
+        assert_eq!(alert.stale_for(), hour * 3 / 2);
+        assert_eq!(wd.check(&lifetime, start + 4 * hour, alert_after), None);
+
+        // Once we have a fresh directory, we say so, and can alert again later.
+        let lifetime2 =
+            Lifetime::new(start + 4 * hour, start + 5 * hour, start + 7 * hour).unwrap();
+        assert_eq!(
+            wd.check(&lifetime2, start + 4 * hour, alert_after),
+            Some(FreshnessEvent::Fresh)
+        );
+        assert_eq!(wd.check(&lifetime2, start + 4 * hour, alert_after), None);
+        assert!(matches!(
+            wd.check(&lifetime2, start + 6 * hour, alert_after),
+            Some(FreshnessEvent::GettingStale(_))
+        ));
+    }
+}
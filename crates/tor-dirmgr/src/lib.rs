@@ -49,47 +49,78 @@
 
 pub mod authority;
 mod bootstrap;
+mod changelog;
 pub mod config;
+mod dircache;
 mod docid;
 mod docmeta;
 mod err;
 mod event;
+mod failreport;
+mod fetcher;
+mod freshness;
+mod mdprogress;
+mod provenance;
+#[cfg(feature = "proxy-fallback")]
+mod proxyfetch;
 mod retry;
+mod selftest;
 mod shared_ref;
+mod startup;
 mod state;
+mod staticdir;
 mod storage;
 
 #[cfg(feature = "bridge-client")]
 pub mod bridgedesc;
 #[cfg(feature = "dirfilter")]
 pub mod filter;
-
-use crate::docid::{CacheUsage, ClientRequest, DocQuery};
+#[cfg(feature = "dirtiming")]
+pub mod timing;
+#[cfg(not(feature = "dirtiming"))]
+mod timing;
+#[cfg(feature = "verify")]
+mod verify;
+#[cfg(feature = "votes")]
+mod votes;
+
+use crate::docid::{CacheUsage, DocQuery};
 use crate::err::BootstrapAction;
 #[cfg(not(feature = "experimental-api"))]
 use crate::shared_ref::SharedMutArc;
 #[cfg(feature = "experimental-api")]
 pub use crate::shared_ref::SharedMutArc;
-use crate::storage::{DynStore, Store};
+use crate::storage::{DynStore, ExpirationConfig, Store};
 use bootstrap::AttemptId;
 use event::DirProgress;
 use postage::watch;
 pub use retry::{DownloadSchedule, DownloadScheduleBuilder};
 use scopeguard::ScopeGuard;
+use tor_checkable::{ExternallySigned, Timebound};
 use tor_circmgr::CircMgr;
 use tor_dirclient::SourceInfo;
-use tor_error::{info_report, into_internal, warn_report};
+use tor_error::{bad_api_usage, info_report, into_internal, warn_report, ErrorReport as _};
+use tor_guardmgr::GuardMgr;
 use tor_netdir::params::NetParameters;
-use tor_netdir::{DirEvent, MdReceiver, NetDir, NetDirProvider};
+use tor_netdir::{
+    DetailedDirEvent, DirEvent, MdReceiver, NetDir, NetDirChangeSummary, NetDirGeneration,
+    NetDirProvider, PartialNetDir, RelayListChange,
+};
+use tor_netdoc::doc::bwfile::BandwidthFile;
+use tor_netdoc::doc::microdesc::{MdDigest, Microdesc};
+use tor_netdoc::doc::netstatus::{ConsensusFlavor, MdConsensus};
 
 use async_trait::async_trait;
 use futures::{stream::BoxStream, task::SpawnExt};
 use oneshot_fused_workaround as oneshot;
+use tor_async_utils::UnboundedBroadcaster;
 use tor_rtcompat::scheduler::{TaskHandle, TaskSchedule};
 use tor_rtcompat::Runtime;
 use tracing::{debug, info, trace, warn};
 
 use std::marker::PhantomData;
+#[cfg(feature = "proxy-fallback")]
+use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
@@ -98,16 +129,47 @@ use std::{fmt::Debug, time::SystemTime};
 
 use crate::state::{DirState, NetDirChange};
 pub use authority::{Authority, AuthorityBuilder};
+pub use bootstrap::DocumentFetcher;
 pub use config::{
-    DirMgrConfig, DirTolerance, DirToleranceBuilder, DownloadScheduleConfig,
-    DownloadScheduleConfigBuilder, NetworkConfig, NetworkConfigBuilder,
+    AlternativeNetwork, AlternativeNetworkBuilder, DirMgrConfig, DirTolerance, DirToleranceBuilder,
+    DownloadScheduleConfig, DownloadScheduleConfigBuilder, MaintainedDocs, NetworkConfig,
+    NetworkConfigBuilder,
+};
+pub use docid::{ClientRequest, DocId};
+pub use err::{Error, FetchAction};
+pub use event::{
+    DirAttemptMetrics, DirBlockage, DirBootstrapEvents, DirBootstrapMetrics, DirBootstrapStatus,
+    DirPhase,
+};
+pub use failreport::{BootstrapFailureReport, CacheAttempts, CacheKind, RequestFailureClass};
+pub use fetcher::{HttpsMirrorFetcher, LocalDirFetcher};
+pub use freshness::{
+    FreshnessEvent, FreshnessWatchdogConfig, FreshnessWatchdogConfigBuilder, StalenessAlert,
+    StalenessAlertHook,
+};
+pub use provenance::NetDirProvenance;
+#[cfg(feature = "proxy-fallback")]
+#[cfg_attr(docsrs, doc(cfg(feature = "proxy-fallback")))]
+pub use proxyfetch::{
+    FallbackFetcher, ProxiedMirrorFetcher, ProxyFallbackConfig, ProxyFallbackConfigBuilder,
+    ProxyProtocol,
+};
+pub use selftest::{SelfTestReport, SelfTestVerdict};
+pub use startup::{StartupCacheConfig, StartupCacheDecision};
+pub use staticdir::StaticDirBundle;
+pub use storage::{
+    CacheRepairReport, CacheSizeReport, DocumentText, OpLatency, StoreLatencyReport,
+    StoreTimingConfig,
 };
-pub use docid::DocId;
-pub use err::Error;
-pub use event::{DirBlockage, DirBootstrapEvents, DirBootstrapStatus};
-pub use storage::DocumentText;
 pub use tor_guardmgr::fallback::{FallbackDir, FallbackDirBuilder};
+pub use tor_guardmgr::{DirectoryAnchor, DirectoryPairing};
 pub use tor_netdir::Timeliness;
+#[cfg(feature = "verify")]
+#[cfg_attr(docsrs, doc(cfg(feature = "verify")))]
+pub use tor_netdoc::doc::netstatus::SignatureStatus;
+#[cfg(feature = "verify")]
+#[cfg_attr(docsrs, doc(cfg(feature = "verify")))]
+pub use verify::{verify_consensus_document, ConsensusVerification, SignatureCheck};
 
 /// Re-export of `strum` crate for use by an internal macro
 use strum;
@@ -174,6 +236,10 @@ impl<R: Runtime> NetDirProvider for DirMgr<R> {
     fn netdir(&self, timeliness: Timeliness) -> tor_netdir::Result<Arc<NetDir>> {
         use tor_netdir::Error as NetDirError;
         let netdir = self.netdir.get().ok_or(NetDirError::NoInfo)?;
+        if self.config.get().extensions.static_bundle.is_some() {
+            // A static directory never expires.
+            return Ok(netdir);
+        }
         let lifetime = match timeliness {
             Timeliness::Strict => netdir.lifetime().clone(),
             Timeliness::Timely => self
@@ -197,6 +263,22 @@ impl<R: Runtime> NetDirProvider for DirMgr<R> {
         Box::pin(self.events.subscribe())
     }
 
+    fn detailed_events(&self) -> BoxStream<'static, DetailedDirEvent> {
+        Box::pin(
+            self.detailed_events
+                .lock()
+                .expect("poisoned lock")
+                .subscribe(),
+        )
+    }
+
+    fn change_summary(&self, generation: NetDirGeneration) -> Option<NetDirChangeSummary> {
+        self.change_log
+            .lock()
+            .expect("poisoned lock")
+            .since(generation)
+    }
+
     fn params(&self) -> Arc<dyn AsRef<tor_netdir::params::NetParameters>> {
         if let Some(netdir) = self.netdir.get() {
             // We have a directory, so we'd like to give it out for its
@@ -267,6 +349,12 @@ pub struct DirMgr<R: Runtime> {
     // rusqlite::Connection isn't Sync.
     // TODO is needed?
     store: Arc<Mutex<DynStore>>,
+    /// A switch to another network's directory cache that we've been
+    /// reconfigured to make, but haven't made yet.
+    ///
+    /// We make the switch the next time that it's safe for us to replace
+    /// `store`: see [`DirMgr::apply_network_switch`].
+    pending_network_switch: Mutex<Option<PendingNetworkSwitch>>,
     /// Our latest sufficiently bootstrapped directory, if we have one.
     ///
     /// We use the RwLock so that we can give this out to a bunch of other
@@ -275,16 +363,51 @@ pub struct DirMgr<R: Runtime> {
     //            gets wrapped in an Arc)
     netdir: Arc<SharedMutArc<NetDir>>,
 
+    /// A record of where the documents in `netdir` came from, if we have a
+    /// netdir.
+    provenance: Mutex<Option<NetDirProvenance>>,
+
+    /// A log of how `netdir` has changed each time we replaced or modified it.
+    change_log: Mutex<changelog::ChangeLog>,
+
+    /// What we decided about loading a consensus from our cache, when we
+    /// bootstrapped.
+    startup_cache_decision: Mutex<Option<StartupCacheDecision>>,
+
     /// A set of network parameters to hand out when we have no directory.
     default_parameters: Mutex<Arc<NetParameters>>,
 
     /// A publisher handle that we notify whenever the consensus changes.
     events: event::FlagPublisher<DirEvent>,
 
+    /// Senders for every stream returned by
+    /// [`NetDirProvider::detailed_events`].
+    detailed_events: Mutex<UnboundedBroadcaster<DetailedDirEvent>>,
+
+    /// Senders for every stream returned by [`DirMgr::freshness_events`].
+    freshness_events: Mutex<UnboundedBroadcaster<FreshnessEvent>>,
+
     /// A publisher handle that we notify whenever our bootstrapping status
     /// changes.
     send_status: Mutex<watch::Sender<event::DirBootstrapStatus>>,
 
+    /// A log of the directory requests that we've made since our directory
+    /// was last usable.
+    failure_log: Mutex<failreport::FailureLog>,
+
+    /// The number of download attempts that we've started since our
+    /// directory was last usable.
+    ///
+    /// Unlike the count in `failure_log`, this doesn't go back to zero when
+    /// we give up and write a failure report, so we can use it to decide
+    /// when to use our fallback fetcher.
+    #[cfg(feature = "proxy-fallback")]
+    n_attempts_since_usable: AtomicUsize,
+
+    /// A report on the last time that we gave up on bootstrapping, if we
+    /// have ever done so.
+    last_failure_report: Mutex<Option<BootstrapFailureReport>>,
+
     /// A receiver handle that gets notified whenever our bootstrapping status
     /// changes.
     ///
@@ -295,6 +418,10 @@ pub struct DirMgr<R: Runtime> {
     /// A circuit manager, if this DirMgr supports downloading.
     circmgr: Option<Arc<CircMgr<R>>>,
 
+    /// A stream that the caller has given us to use for our next directory
+    /// request, instead of a circuit from `circmgr`.
+    supplied_stream: Mutex<Option<Box<dyn bootstrap::DirStream>>>,
+
     /// Our asynchronous runtime.
     runtime: R,
 
@@ -313,6 +440,15 @@ pub struct DirMgr<R: Runtime> {
     #[cfg(feature = "dirfilter")]
     filter: crate::filter::FilterConfig,
 
+    /// The GeoIP database manager that we made in
+    /// [`DirMgr::set_geoip_db`], if our configuration didn't have one.
+    ///
+    /// We keep this here, and put it back in our configuration whenever a
+    /// new configuration has no manager of its own, so that reconfiguring
+    /// doesn't discard the database.
+    #[cfg(feature = "geoip")]
+    own_geoip: Mutex<Option<Arc<tor_geoip::GeoipDbManager>>>,
+
     /// A task schedule that can be used if we're bootstrapping.  If this is
     /// None, then there's currently a scheduled task in progress.
     task_schedule: Mutex<Option<TaskSchedule<R>>>,
@@ -321,6 +457,14 @@ pub struct DirMgr<R: Runtime> {
     task_handle: TaskHandle,
 }
 
+/// A switch to another network that we haven't made yet.
+struct PendingNetworkSwitch {
+    /// The name of the network whose cache we're still using.
+    from: Option<String>,
+    /// The store for the network that we're switching to.
+    store: DynStore,
+}
+
 /// The possible origins of a document.
 ///
 /// Used (for example) to report where we got a document from if it fails to
@@ -330,6 +474,11 @@ pub struct DirMgr<R: Runtime> {
 pub enum DocSource {
     /// We loaded the document from our cache.
     LocalCache,
+    /// The document came from a caller-provided [`StaticDirBundle`].
+    StaticBundle,
+    /// The document was given to us directly by our caller, as with
+    /// [`DirMgr::set_bandwidth_file`].
+    Caller,
     /// We fetched the document from a server.
     DirServer {
         /// Information about the server we fetched the document from.
@@ -341,6 +490,8 @@ impl std::fmt::Display for DocSource {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             DocSource::LocalCache => write!(f, "local cache"),
+            DocSource::StaticBundle => write!(f, "static directory bundle"),
+            DocSource::Caller => write!(f, "caller"),
             DocSource::DirServer { source: None } => write!(f, "directory server"),
             DocSource::DirServer { source: Some(info) } => write!(f, "directory server {}", info),
         }
@@ -358,6 +509,12 @@ impl<R: Runtime> DirMgr<R> {
     /// program; it's only suitable for command-line or batch tools.
     // TODO: I wish this function didn't have to be async or take a runtime.
     pub async fn load_once(runtime: R, config: DirMgrConfig) -> Result<Arc<NetDir>> {
+        if let Some(bundle) = &config.extensions.static_bundle {
+            // We don't need the cache at all.
+            let (netdir, _provenance) = bundle.build_netdir(&config)?;
+            return Ok(Arc::new(netdir));
+        }
+
         let store = DirMgrStore::new(&config, runtime.clone(), true)?;
         let dirmgr = Arc::new(Self::from_config(config, runtime, store, None, true)?);
 
@@ -437,6 +594,10 @@ impl<R: Runtime> DirMgr<R> {
             return Err(Error::OfflineMode);
         }
 
+        if let Some(bundle) = &self.config.get().extensions.static_bundle {
+            return self.bootstrap_from_static_bundle(bundle);
+        }
+
         // The semantics of this are "attempt to replace a 'false' value with 'true'.
         // If the value in bootstrap_started was not 'false' when the attempt was made, returns
         // `Err`; this means another bootstrap attempt is in progress or has completed, so we
@@ -472,7 +633,23 @@ impl<R: Runtime> DirMgr<R> {
         // Try to load from the cache.
         let attempt_id = AttemptId::next();
         trace!(attempt=%attempt_id, "Starting to bootstrap directory");
-        let have_directory = self.load_directory(attempt_id).await?;
+        let decision = self.decide_startup_cache_usage();
+        let have_directory = if decision.should_load() {
+            debug!("Startup cache decision: {}", decision);
+            self.load_directory(attempt_id).await?
+        } else {
+            info!("Not loading a directory from cache: {}", decision);
+            false
+        };
+        // If we decided that our cached consensus was too old to load, then
+        // don't let our first download attempt load it either.
+        let initial_cache_usage = match decision {
+            StartupCacheDecision::SkipTooOld { .. } | StartupCacheDecision::SkipExpired { .. } => {
+                CacheUsage::MustDownload
+            }
+            _ => CacheUsage::CacheOkay,
+        };
+        *self.startup_cache_decision.lock().expect("poisoned lock") = Some(decision);
 
         let (mut sender, receiver) = if have_directory {
             info!("Loaded a good directory from cache.");
@@ -509,9 +686,14 @@ impl<R: Runtime> DirMgr<R> {
                         Error::ManagerDropped => {}
                         _ => warn_report!(e, "Unrecovered error while waiting for bootstrap",),
                     }
-                } else if let Err(e) =
-                    Self::download_forever(dirmgr_weak.clone(), &mut schedule, attempt_id, sender)
-                        .await
+                } else if let Err(e) = Self::download_forever(
+                    dirmgr_weak.clone(),
+                    &mut schedule,
+                    attempt_id,
+                    initial_cache_usage,
+                    sender,
+                )
+                .await
                 {
                     match e {
                         Error::ManagerDropped => {}
@@ -521,6 +703,11 @@ impl<R: Runtime> DirMgr<R> {
             })
             .map_err(|e| Error::from_spawn("directory updater task", e))?;
 
+        let dirmgr_weak = Arc::downgrade(self);
+        self.runtime
+            .spawn(Self::watch_freshness(dirmgr_weak))
+            .map_err(|e| Error::from_spawn("directory freshness watchdog", e))?;
+
         if let Some(receiver) = receiver {
             match receiver.await {
                 Ok(()) => {
@@ -537,6 +724,66 @@ impl<R: Runtime> DirMgr<R> {
         Ok(())
     }
 
+    /// Bootstrap this `DirMgr` from `bundle`, without launching any background
+    /// tasks.
+    ///
+    /// If bootstrapping has already successfully taken place, do nothing.
+    fn bootstrap_from_static_bundle(&self, bundle: &StaticDirBundle) -> Result<()> {
+        if self
+            .bootstrap_started
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            debug!("Attempted to bootstrap twice; ignoring.");
+            return Ok(());
+        }
+        // As in `bootstrap`, reset `bootstrap_started` if we can't build the
+        // directory.
+        let reset_bootstrap_started = scopeguard::guard(&self.bootstrap_started, |v| {
+            v.store(false, Ordering::SeqCst);
+        });
+
+        let attempt_id = AttemptId::next();
+        let config = self.config.get();
+        let (netdir, provenance) = bundle.build_netdir(&config)?;
+        let lifetime = netdir.lifetime().clone();
+        let count = |n: usize| u32::try_from(n).unwrap_or(u32::MAX);
+        let n_mds = (
+            count(netdir.relays().count()),
+            count(netdir.all_relays().count()),
+        );
+        info!(
+            "Loaded a static directory with {} usable relays, valid after {}.",
+            n_mds.0,
+            humantime::format_rfc3339(lifetime.valid_after()),
+        );
+
+        // We're willing to use this directory forever, even after it expires.
+        let forever = SystemTime::UNIX_EPOCH + Duration::from_secs(u32::MAX.into());
+        let usable_lifetime = tor_netdoc::doc::netstatus::Lifetime::new(
+            lifetime.valid_after(),
+            lifetime.fresh_until(),
+            forever.max(lifetime.valid_until()),
+        )
+        .map_err(into_internal!("Couldn't extend directory lifetime"))?;
+
+        self.replace_netdir(netdir);
+        self.note_provenance(&provenance);
+        self.update_progress(
+            attempt_id,
+            DirProgress::Validated {
+                usable_lifetime,
+                lifetime,
+                n_mds,
+                usable: true,
+            },
+        );
+        let _ = ScopeGuard::into_inner(reset_bootstrap_started);
+        self.events.publish(DirEvent::NewConsensus);
+        self.events.publish(DirEvent::NewDescriptors);
+        Ok(())
+    }
+
     /// Returns `true` if a bootstrap attempt is in progress, or successfully completed.
     pub fn bootstrap_started(&self) -> bool {
         self.bootstrap_started.load(Ordering::SeqCst)
@@ -580,6 +827,9 @@ impl<R: Runtime> DirMgr<R> {
         loop {
             {
                 let dirmgr = upgrade_weak_ref(weak)?;
+                if dirmgr.apply_network_switch() {
+                    bootstrapped = false;
+                }
                 trace!("Trying to take ownership of the directory cache lock");
                 if dirmgr.try_upgrade_to_readwrite()? {
                     // We now own the lock!  (Maybe we owned it before; the
@@ -594,7 +844,16 @@ impl<R: Runtime> DirMgr<R> {
 
             if !logged {
                 logged = true;
-                if bootstrapped {
+                let immutable = upgrade_weak_ref(weak)?
+                    .config
+                    .get()
+                    .extensions
+                    .immutable_cache;
+                if immutable {
+                    info!(
+                        "Our directory cache is an immutable image. We'll reload it periodically."
+                    );
+                } else if bootstrapped {
                     info!("Another process is managing the directory. We'll use its cache.");
                 } else {
                     info!("Another process is bootstrapping the directory. Waiting till it finishes or exits.");
@@ -614,6 +873,9 @@ impl<R: Runtime> DirMgr<R> {
             // our state functions.
             {
                 let dirmgr = upgrade_weak_ref(weak)?;
+                if let Err(e) = dirmgr.reopen_immutable_image() {
+                    warn_report!(e, "Unable to reopen immutable directory cache");
+                }
                 trace!("Trying to load from the directory cache");
                 if dirmgr.load_directory(attempt_id).await? {
                     // Successfully loaded a bootstrapped directory.
@@ -631,27 +893,26 @@ impl<R: Runtime> DirMgr<R> {
 
     /// Try to fetch our directory info and keep it updated, indefinitely.
     ///
+    /// Our first attempt uses the cache according to `initial_cache_usage`.
+    ///
     /// If we have begin to have a bootstrapped directory, send a
     /// message using `on_complete`.
     async fn download_forever(
         weak: Weak<Self>,
         schedule: &mut TaskSchedule<R>,
         mut attempt_id: AttemptId,
+        initial_cache_usage: CacheUsage,
         mut on_complete: Option<oneshot::Sender<()>>,
     ) -> Result<()> {
+        // The flavor of consensus that `state` is fetching.  (Every state
+        // that we reach by resetting or advancing `state` fetches the same
+        // flavor.)
+        let mut flavor;
         let mut state: Box<dyn DirState> = {
             let dirmgr = upgrade_weak_ref(&weak)?;
-            Box::new(state::GetConsensusState::new(
-                dirmgr.runtime.clone(),
-                dirmgr.config.get(),
-                CacheUsage::CacheOkay,
-                Some(dirmgr.netdir.clone()),
-                #[cfg(feature = "dirfilter")]
-                dirmgr
-                    .filter
-                    .clone()
-                    .unwrap_or_else(|| Arc::new(crate::filter::NilFilter)),
-            ))
+            let config = dirmgr.config.get();
+            flavor = config.extensions.consensus_flavor;
+            Box::new(dirmgr.new_consensus_state(config, initial_cache_usage))
         };
 
         trace!("Entering download loop.");
@@ -659,16 +920,26 @@ impl<R: Runtime> DirMgr<R> {
         loop {
             let mut usable = false;
 
-            let retry_config = {
+            let config = {
                 let dirmgr = upgrade_weak_ref(&weak)?;
                 // TODO(nickm): instead of getting this every time we loop, it
                 // might be a good idea to refresh it with each attempt, at
                 // least at the point of checking the number of attempts.
-                dirmgr.config.get().schedule.retry_bootstrap
+                dirmgr.config.get()
             };
+            let retry_config = config.schedule.retry_bootstrap;
             let mut retry_delay = retry_config.schedule();
 
             'retry_attempt: for try_num in retry_config.attempts() {
+                {
+                    let dirmgr = upgrade_weak_ref(&weak)?;
+                    if dirmgr.apply_network_switch() {
+                        // Start over with the new network.
+                        let config = dirmgr.config.get();
+                        flavor = config.extensions.consensus_flavor;
+                        state = Box::new(dirmgr.new_consensus_state(config, CacheUsage::CacheOkay));
+                    }
+                }
                 trace!(attempt=%attempt_id, ?try_num, "Trying to download a directory.");
                 let outcome = bootstrap::download(
                     Weak::clone(&weak),
@@ -683,6 +954,7 @@ impl<R: Runtime> DirMgr<R> {
                 if let Err(err) = outcome {
                     if state.is_ready(Readiness::Usable) {
                         usable = true;
+                        upgrade_weak_ref(&weak)?.clear_failure_log();
                         info_report!(err, "Unable to completely download a directory. (Nevertheless, the directory is usable, so we'll pause for now)");
                         break 'retry_attempt;
                     }
@@ -697,8 +969,12 @@ impl<R: Runtime> DirMgr<R> {
                         BootstrapAction::Reset => {}
                         BootstrapAction::Fatal => return Err(err),
                     }
+                    {
+                        let dirmgr = upgrade_weak_ref(&weak)?;
+                        dirmgr.note_stuck(state.as_ref(), &err);
+                    }
 
-                    let delay = retry_delay.next_delay(&mut rand::thread_rng());
+                    let delay = timing::policy(&config).retry_delay(&mut retry_delay);
                     warn_report!(
                         err,
                         "Unable to download a usable directory. (We will restart in {})",
@@ -713,6 +989,7 @@ impl<R: Runtime> DirMgr<R> {
                 } else {
                     info!(attempt=%attempt_id, "Directory is complete.");
                     usable = true;
+                    upgrade_weak_ref(&weak)?.clear_failure_log();
                     break 'retry_attempt;
                 }
             }
@@ -723,6 +1000,7 @@ impl<R: Runtime> DirMgr<R> {
                     "We failed {} times to bootstrap a directory. We're going to give up.",
                     retry_config.n_attempts()
                 );
+                upgrade_weak_ref(&weak)?.finish_failure_report();
                 return Err(Error::CantAdvanceState);
             } else {
                 // Report success, if appropriate.
@@ -735,21 +1013,110 @@ impl<R: Runtime> DirMgr<R> {
             match reset_at {
                 Some(t) => {
                     trace!("Sleeping until {}", time::OffsetDateTime::from(t));
-                    schedule.sleep_until_wallclock(t).await?;
+                    Self::sleep_until_wallclock_or_network_switch(&weak, schedule, t).await?;
                 }
                 None => return Ok(()),
             }
             attempt_id = bootstrap::AttemptId::next();
             trace!(attempt=%attempt_id, "Beginning new attempt to bootstrap directory");
             state = state.reset();
+
+            // If we've been reconfigured to use a different network or
+            // consensus flavor, start over with that instead.  (We may
+            // already have a consensus of that flavor in the cache.)
+            let dirmgr = upgrade_weak_ref(&weak)?;
+            let switched_network = dirmgr.apply_network_switch();
+            let config = dirmgr.config.get();
+            if switched_network || config.extensions.consensus_flavor != flavor {
+                if config.extensions.consensus_flavor != flavor {
+                    flavor = config.extensions.consensus_flavor;
+                    info!("Switching to {} consensus flavor.", flavor.name());
+                }
+                state = Box::new(dirmgr.new_consensus_state(config, CacheUsage::CacheOkay));
+            }
+        }
+    }
+
+    /// Sleep until the wall-clock time `when`, or until we've been
+    /// reconfigured to use another network, whichever comes first.
+    async fn sleep_until_wallclock_or_network_switch(
+        weak: &Weak<Self>,
+        schedule: &mut TaskSchedule<R>,
+        when: SystemTime,
+    ) -> Result<()> {
+        /// The longest that we sleep at once, so that we notice if the
+        /// wall clock jumps.
+        const MAX_SLEEP: Duration = Duration::from_secs(600);
+        loop {
+            let now = {
+                let dirmgr = upgrade_weak_ref(weak)?;
+                if dirmgr.network_switch_pending() {
+                    return Ok(());
+                }
+                dirmgr.runtime.wallclock()
+            };
+            match when.duration_since(now) {
+                Ok(remaining) if !remaining.is_zero() => {
+                    schedule.sleep(remaining.min(MAX_SLEEP)).await?;
+                }
+                _ => return Ok(()),
+            }
         }
     }
 
+    /// Return a new state machine to start fetching a consensus of our
+    /// configured flavor, replacing our current `NetDir` if we have one.
+    fn new_consensus_state(
+        &self,
+        config: Arc<DirMgrConfig>,
+        cache_usage: CacheUsage,
+    ) -> state::GetConsensusState<R> {
+        state::GetConsensusState::new(
+            self.runtime.clone(),
+            config,
+            cache_usage,
+            Some(self.netdir.clone()),
+            #[cfg(feature = "dirfilter")]
+            self.filter
+                .clone()
+                .unwrap_or_else(|| Arc::new(crate::filter::NilFilter)),
+        )
+    }
+
     /// Get a reference to the circuit manager, if we have one.
     fn circmgr(&self) -> Result<Arc<CircMgr<R>>> {
         self.circmgr.clone().ok_or(Error::NoDownloadSupport)
     }
 
+    /// Use `stream` to send our next directory request, instead of asking the
+    /// circuit manager for a circuit.
+    ///
+    /// The stream must already be connected to a directory cache: for example,
+    /// it might be a stream that the caller built over a bridge by some
+    /// non-standard means.  We use it for exactly one request, and then drop
+    /// it.  When we use a supplied stream, we send only that single request
+    /// during that download attempt, so this works even if this `DirMgr` has
+    /// no circuit manager.
+    ///
+    /// This doesn't make us download anything: the stream is used the next
+    /// time that we would download something anyway.  If this is called again
+    /// before the stream has been used, the old stream is dropped.
+    ///
+    /// Since we don't know where the stream goes, we don't report the outcome
+    /// of the request to the circuit manager or guard manager.
+    pub fn use_stream_for_next_fetch<S>(&self, stream: S)
+    where
+        S: futures::io::AsyncRead + futures::io::AsyncWrite + Send + Unpin + 'static,
+    {
+        *self.supplied_stream.lock().expect("poisoned lock") = Some(Box::new(stream));
+    }
+
+    /// Remove and return the stream that the caller gave us with
+    /// [`DirMgr::use_stream_for_next_fetch`], if there is one.
+    fn take_supplied_stream(&self) -> Option<Box<dyn bootstrap::DirStream>> {
+        self.supplied_stream.lock().expect("poisoned lock").take()
+    }
+
     /// Try to change our configuration to `new_config`.
     ///
     /// Actual behavior will depend on the value of `how`.
@@ -769,24 +1136,88 @@ impl<R: Runtime> DirMgr<R> {
         if new_config.cache_trust != config.cache_trust {
             how.cannot_change("storage.permissions")?;
         }
-        if new_config.authorities() != config.authorities() {
+        // Switching to another network is the one way to change our
+        // authorities: we start over with that network's own cache.
+        let switch_network = config.can_switch_network_to(new_config);
+        if switch_network {
+            // (We check this again below, when we open its cache.)
+        } else if new_config.network.network_name() != config.network.network_name() {
+            how.cannot_change("network.network_name")?;
+        } else if new_config.authorities() != config.authorities() {
             how.cannot_change("network.authorities")?;
         }
+        if new_config.extensions.static_bundle.is_some()
+            != config.extensions.static_bundle.is_some()
+        {
+            how.cannot_change("static directory bundle")?;
+        }
+        if new_config.extensions.store_timing.is_some() != config.extensions.store_timing.is_some()
+        {
+            how.cannot_change("directory cache timing")?;
+        }
+        if new_config.extensions.immutable_cache != config.extensions.immutable_cache {
+            how.cannot_change("immutable directory cache")?;
+        }
+        let flavor = new_config.extensions.consensus_flavor;
+        if !state::flavor_is_supported(flavor) {
+            return Err(tor_config::ReconfigureError::UnsupportedSituation(format!(
+                "consensus flavor {} is not supported in this build",
+                flavor.name()
+            )));
+        }
 
         if how == tor_config::Reconfigure::CheckAllOrNothing {
+            if switch_network {
+                // Make sure that we'll be able to use the new network's
+                // cache, so that we don't fail halfway through reconfiguring.
+                let new_effective_config = config.update_from_config(new_config);
+                if new_effective_config.network.network_name()
+                    != self.network_switch_origin().as_deref()
+                {
+                    drop(self.open_network_store(&new_effective_config)?);
+                }
+            }
             return Ok(());
         }
 
         let params_changed = new_config.override_net_params != config.override_net_params;
 
-        self.config
-            .map_and_replace(|cfg| cfg.update_from_config(new_config));
+        #[allow(unused_mut)]
+        let mut new_effective_config = config.update_from_config(new_config);
+        #[cfg(feature = "geoip")]
+        if new_effective_config.extensions.geoip.is_none() {
+            new_effective_config.extensions.geoip =
+                self.own_geoip.lock().expect("lock poisoned").clone();
+        }
+        let pending_store = if switch_network {
+            self.prepare_network_switch(&config, &new_effective_config)?
+        } else {
+            None
+        };
+        self.config.replace(new_effective_config);
+        if switch_network {
+            *self.pending_network_switch.lock().expect("lock poisoned") = pending_store;
+            if self.offline || !self.bootstrap_started() {
+                // Nothing is using the store yet, so we can switch right away.
+                self.apply_network_switch();
+            } else {
+                // Wake up the download task, so that it switches as soon as
+                // it can.
+                self.task_handle.fire();
+            }
+        }
 
         if params_changed {
-            let _ignore_err = self.netdir.mutate(|netdir| {
+            let prev = self.netdir_generation();
+            let replaced = self.netdir.mutate(|netdir| {
                 netdir.replace_overridden_parameters(&new_config.override_net_params);
                 Ok(())
             });
+            if replaced.is_ok() {
+                let mut summary = NetDirChangeSummary::default();
+                summary.params_changed = true;
+                self.note_netdir_change(prev, summary);
+            }
             {
                 let mut params = self.default_parameters.lock().expect("lock failed");
                 *params = Arc::new(NetParameters::from_map(&new_config.override_net_params));
@@ -795,11 +1226,169 @@ impl<R: Runtime> DirMgr<R> {
             // (It's okay to ignore the error, since it just means that there
             // was no current netdir.)
             self.events.publish(DirEvent::NewConsensus);
+            self.publish_detailed(&DetailedDirEvent::NewParameters);
         }
 
         Ok(())
     }
 
+    /// Get ready to switch from the network in `config` to the one in
+    /// `new_config`, by opening the new network's directory cache.
+    ///
+    /// Return the switch to make, or `None` if we're returning to the network
+    /// whose cache we're still using, before we ever left it.
+    fn prepare_network_switch(
+        &self,
+        config: &DirMgrConfig,
+        new_config: &DirMgrConfig,
+    ) -> std::result::Result<Option<PendingNetworkSwitch>, tor_config::ReconfigureError> {
+        // If we were already waiting to switch, forget about that, and
+        // release the cache that we were going to switch to.
+        let from = match self
+            .pending_network_switch
+            .lock()
+            .expect("lock poisoned")
+            .take()
+        {
+            Some(pending) => pending.from,
+            None => config.network.network_name().map(str::to_owned),
+        };
+        if new_config.network.network_name() == from.as_deref() {
+            return Ok(None);
+        }
+
+        let store = self.open_network_store(new_config)?;
+        Ok(Some(PendingNetworkSwitch { from, store }))
+    }
+
+    /// Return the name of the network whose directory cache is in `store`,
+    /// or `None` for the default network.
+    ///
+    /// This differs from the network in our configuration when we're still
+    /// waiting to make a switch.
+    fn network_switch_origin(&self) -> Option<String> {
+        match &*self.pending_network_switch.lock().expect("lock poisoned") {
+            Some(pending) => pending.from.clone(),
+            None => self.config.get().network.network_name().map(str::to_owned),
+        }
+    }
+
+    /// Open the directory cache for the network in `new_config`, and make
+    /// sure that we can use it the way we use our current one.
+    fn open_network_store(
+        &self,
+        new_config: &DirMgrConfig,
+    ) -> std::result::Result<DynStore, tor_config::ReconfigureError> {
+        let was_readonly = self.store.lock().expect("lock poisoned").is_readonly();
+        let store = new_config.open_store(self.offline).map_err(|e| {
+            tor_config::ReconfigureError::UnsupportedSituation(format!(
+                "Unable to open the directory cache for the new network: {}",
+                e.report()
+            ))
+        })?;
+        if store.is_readonly() && !was_readonly {
+            // We're responsible for keeping the directory up to date, and we
+            // can't do that for the new network.
+            return Err(tor_config::ReconfigureError::UnsupportedSituation(
+                "Another process is using the directory cache for the new network".into(),
+            ));
+        }
+        Ok(store)
+    }
+
+    /// If we've been reconfigured to use another network, switch to its
+    /// directory cache now, and forget the directory that we had.
+    ///
+    /// We only call this when nothing is in the middle of using our store:
+    /// either before we've started bootstrapping, or between download
+    /// attempts.
+    ///
+    /// Return true if we switched.
+    fn apply_network_switch(&self) -> bool {
+        let pending = self
+            .pending_network_switch
+            .lock()
+            .expect("lock poisoned")
+            .take();
+        let Some(PendingNetworkSwitch { store, .. }) = pending else {
+            return false;
+        };
+        *self.store.lock().expect("Directory storage lock poisoned") = store;
+        self.netdir.clear();
+        *self.provenance.lock().expect("lock poisoned") = None;
+        let config = self.config.get();
+        info!(
+            "Switched to the {} network.",
+            config.network.network_name().unwrap_or("default")
+        );
+        // Tell our users that the directory they had is gone, so that they
+        // stop using relays from the old network.
+        self.events.publish(DirEvent::NewConsensus);
+        true
+    }
+
+    /// Return true if we've been reconfigured to use another network, and
+    /// haven't switched to it yet.
+    fn network_switch_pending(&self) -> bool {
+        self.pending_network_switch
+            .lock()
+            .expect("lock poisoned")
+            .is_some()
+    }
+
+    /// Install `db` as the GeoIP database for this `DirMgr`.
+    ///
+    /// We look up every relay in the current directory again in `db`, and
+    /// announce the result with a [`DirEvent::NewConsensus`] event; we also use
+    /// `db` for every directory that we build from now on.
+    ///
+    /// If our configuration has a [`GeoipDbManager`](tor_geoip::GeoipDbManager),
+    /// we install `db` in it, so that anybody else who shares the manager sees
+    /// it too.  Otherwise, we keep `db` in a manager of our own, which we use
+    /// whenever our configuration doesn't have one, even after
+    /// [`reconfigure`](DirMgr::reconfigure).
+    ///
+    /// If we have no directory yet, there is nothing to announce.
+    #[cfg(feature = "geoip")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "geoip")))]
+    pub fn set_geoip_db(&self, db: Arc<tor_geoip::GeoipDb>) {
+        // Install the database before we look up the current directory's
+        // relays in it, so that any directory that we build in the meantime
+        // uses it too.
+        let mgr = self.config.get().extensions.geoip.clone();
+        let db = match mgr {
+            Some(mgr) => {
+                mgr.replace(db);
+                mgr.current()
+            }
+            None => {
+                let mgr = Arc::new(tor_geoip::GeoipDbManager::new(db));
+                *self.own_geoip.lock().expect("lock poisoned") = Some(Arc::clone(&mgr));
+                self.config.map_and_replace(|cfg| {
+                    let mut cfg = DirMgrConfig::clone(cfg);
+                    cfg.extensions.geoip = Some(Arc::clone(&mgr));
+                    cfg
+                });
+                mgr.current()
+            }
+        };
+
+        let prev = self.netdir_generation();
+        let Ok(n_changed) = self
+            .netdir
+            .mutate(|netdir| Ok(netdir.replace_geoip_db(&db)))
+        else {
+            // We have no directory, so nobody needs to hear about this.
+            return;
+        };
+        let mut summary = NetDirChangeSummary::default();
+        summary.n_changed = n_changed;
+        self.note_netdir_change(prev, summary);
+
+        self.events.publish(DirEvent::NewConsensus);
+        self.publish_detailed(&DetailedDirEvent::Unspecified(DirEvent::NewConsensus));
+    }
+
     /// Return a stream of [`DirBootstrapStatus`] events to tell us about changes
     /// in the latest directory's bootstrap status.
     ///
@@ -809,6 +1398,17 @@ impl<R: Runtime> DirMgr<R> {
         self.receive_status.clone()
     }
 
+    /// Return a snapshot of detailed metrics about our attempts to fetch a
+    /// directory.
+    ///
+    /// These metrics are more detailed than the events from
+    /// [`bootstrap_events`](DirMgr::bootstrap_events): they are meant for
+    /// callers that want to show the progress of each download in their own
+    /// interface.
+    pub fn bootstrap_metrics(&self) -> event::DirBootstrapMetrics {
+        self.receive_status.inner.borrow().metrics()
+    }
+
     /// Replace the latest status with `progress` and broadcast to anybody
     /// watching via a [`DirBootstrapEvents`] stream.
     fn update_progress(&self, attempt_id: AttemptId, progress: DirProgress) {
@@ -831,6 +1431,114 @@ impl<R: Runtime> DirMgr<R> {
         status.note_errors(attempt_id, n_errors);
     }
 
+    /// Update our status tracker to note that, at `when`, we launched a set of
+    /// requests that took `elapsed` to finish and had the outcomes in
+    /// `requests`.
+    fn note_requests(
+        &self,
+        attempt_id: AttemptId,
+        when: SystemTime,
+        elapsed: Duration,
+        requests: &[event::RequestRecord],
+    ) {
+        let mut sender = self.send_status.lock().expect("poisoned lock");
+        let mut status = sender.borrow_mut();
+
+        status.note_requests(attempt_id, when, elapsed, requests);
+    }
+
+    /// Note that, at `when`, we got the outcomes of a set of requests, as
+    /// described in `requests`, for our bootstrap failure report.
+    fn note_request_failures(
+        &self,
+        when: SystemTime,
+        requests: &[(
+            Option<tor_llcrypto::pk::rsa::RsaIdentity>,
+            Option<RequestFailureClass>,
+        )],
+    ) {
+        let config = self.config.get();
+        self.failure_log
+            .lock()
+            .expect("poisoned lock")
+            .note_requests(when, requests, config.fallbacks());
+    }
+
+    /// Note that we're starting a new attempt to download documents, for our
+    /// bootstrap failure report.
+    fn note_download_attempt(&self) {
+        self.failure_log
+            .lock()
+            .expect("poisoned lock")
+            .note_attempt();
+        #[cfg(feature = "proxy-fallback")]
+        self.n_attempts_since_usable.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Note that our attempts to advance `state` have ended with `err`, for
+    /// our bootstrap failure report.
+    fn note_stuck(&self, state: &dyn DirState, err: &Error) {
+        let n_missing_microdescs = state
+            .missing_docs()
+            .iter()
+            .filter(|d| matches!(d, DocId::Microdesc(_)))
+            .count();
+        self.failure_log.lock().expect("poisoned lock").note_stuck(
+            state.describe(),
+            n_missing_microdescs,
+            err.clone(),
+        );
+    }
+
+    /// Return true if we should send our requests through `fallback` instead
+    /// of fetching them in the usual way.
+    ///
+    /// That's the case when we have no directory, and at least
+    /// `fallback.after_failed_attempts` download attempts have failed since
+    /// we last had a usable one.
+    #[cfg(feature = "proxy-fallback")]
+    fn should_use_fallback_fetcher(&self, fallback: &FallbackFetcher) -> bool {
+        if self.netdir.get().is_some() {
+            return false;
+        }
+        // (This counts the attempt in progress, too.)
+        let n_attempts = self.n_attempts_since_usable.load(Ordering::SeqCst);
+        n_attempts.saturating_sub(1) >= fallback.after_failed_attempts
+    }
+
+    /// Forget the requests that we've logged for our bootstrap failure
+    /// report, since our directory is usable.
+    fn clear_failure_log(&self) {
+        self.failure_log.lock().expect("poisoned lock").clear();
+        #[cfg(feature = "proxy-fallback")]
+        self.n_attempts_since_usable.store(0, Ordering::SeqCst);
+    }
+
+    /// Turn our log of requests into a report on why we gave up on
+    /// bootstrapping.
+    fn finish_failure_report(&self) {
+        let report = self
+            .failure_log
+            .lock()
+            .expect("poisoned lock")
+            .take_report(self.runtime.wallclock());
+        *self.last_failure_report.lock().expect("poisoned lock") = Some(report);
+    }
+
+    /// Return a report on the last time that we gave up on bootstrapping a
+    /// directory, or None if we never have.
+    ///
+    /// When bootstrapping fails with [`Error::CantAdvanceState`], this report
+    /// says which directory caches we asked, how our requests to them failed,
+    /// and how far we got.  It covers every request since our directory was
+    /// last usable.
+    pub fn last_bootstrap_report(&self) -> Option<BootstrapFailureReport> {
+        self.last_failure_report
+            .lock()
+            .expect("poisoned lock")
+            .clone()
+    }
+
     /// Update our status tracker to note that we've needed to reset our download attempt.
     fn note_reset(&self, attempt_id: AttemptId) {
         let mut sender = self.send_status.lock().expect("poisoned lock");
@@ -852,6 +1560,15 @@ impl<R: Runtime> DirMgr<R> {
             .upgrade_to_readwrite()
     }
 
+    /// If our store is an immutable image, reopen it, to pick up any new
+    /// image that has replaced it.
+    fn reopen_immutable_image(&self) -> Result<()> {
+        self.store
+            .lock()
+            .expect("Directory storage lock poisoned")
+            .reopen_immutable_image()
+    }
+
     /// Return a reference to the store, if it is currently read-write.
     #[cfg(test)]
     fn store_if_rw(&self) -> Option<&Mutex<DynStore>> {
@@ -880,6 +1597,11 @@ impl<R: Runtime> DirMgr<R> {
         circmgr: Option<Arc<CircMgr<R>>>,
         offline: bool,
     ) -> Result<Self> {
+        let flavor = config.extensions.consensus_flavor;
+        if !state::flavor_is_supported(flavor) {
+            return Err(Error::UnsupportedFlavor(flavor));
+        }
+
         let netdir = Arc::new(SharedMutArc::new());
         let events = event::FlagPublisher::new();
         let default_parameters = NetParameters::from_map(&config.override_net_params);
@@ -891,7 +1613,14 @@ impl<R: Runtime> DirMgr<R> {
             inner: receive_status,
         };
         #[cfg(feature = "dirfilter")]
-        let filter = config.extensions.filter.clone();
+        let filter = {
+            let mut chain =
+                crate::filter::FilterChain::from_config(&config.extensions.builtin_filters);
+            if let Some(filter) = &config.extensions.filter {
+                chain.push(Arc::clone(filter));
+            }
+            (!chain.is_empty()).then(|| Arc::new(chain) as Arc<dyn crate::filter::DirFilter>)
+        };
 
         // We create these early so the client code can access task_handle before bootstrap() returns.
         let (task_schedule, task_handle) = TaskSchedule::new(runtime.clone());
@@ -900,15 +1629,28 @@ impl<R: Runtime> DirMgr<R> {
         Ok(DirMgr {
             config: config.into(),
             store: store.store,
+            pending_network_switch: Mutex::new(None),
             netdir,
+            provenance: Mutex::new(None),
+            change_log: Mutex::new(Default::default()),
+            startup_cache_decision: Mutex::new(None),
             default_parameters,
             events,
+            detailed_events: Mutex::new(UnboundedBroadcaster::new()),
+            freshness_events: Mutex::new(UnboundedBroadcaster::new()),
             send_status,
             receive_status,
             circmgr,
+            supplied_stream: Mutex::new(None),
+            failure_log: Mutex::new(failreport::FailureLog::default()),
+            #[cfg(feature = "proxy-fallback")]
+            n_attempts_since_usable: AtomicUsize::new(0),
+            last_failure_report: Mutex::new(None),
             runtime,
             offline,
             bootstrap_started: AtomicBool::new(false),
+            #[cfg(feature = "geoip")]
+            own_geoip: Mutex::new(None),
             #[cfg(feature = "dirfilter")]
             filter,
             task_schedule,
@@ -916,6 +1658,43 @@ impl<R: Runtime> DirMgr<R> {
         })
     }
 
+    /// Decide whether to load a consensus from our cache as we bootstrap,
+    /// based on the metadata of the latest one that we have.
+    fn decide_startup_cache_usage(&self) -> StartupCacheDecision {
+        let config = self.config.get();
+        let flavor = config.extensions.consensus_flavor;
+        let meta = self
+            .store
+            .lock()
+            .expect("poisoned lock")
+            .latest_consensus_meta(flavor);
+        match meta {
+            Ok(meta) => startup::decide(
+                meta.as_ref().map(|m| m.lifetime()),
+                self.runtime.wallclock(),
+                &config.tolerance,
+                &config.extensions.startup_cache,
+            ),
+            Err(e) => {
+                warn_report!(e, "Error loading directory metadata");
+                StartupCacheDecision::MetadataUnavailable
+            }
+        }
+    }
+
+    /// Return what we decided about loading a consensus from our cache when
+    /// we bootstrapped, and why.
+    ///
+    /// Returns `None` if we haven't bootstrapped yet, or if we bootstrapped
+    /// without consulting our cache (for example, from a
+    /// [`StaticDirBundle`]).
+    pub fn startup_cache_decision(&self) -> Option<StartupCacheDecision> {
+        self.startup_cache_decision
+            .lock()
+            .expect("poisoned lock")
+            .clone()
+    }
+
     /// Load the latest non-pending non-expired directory from the
     /// cache, if it is newer than the one we have.
     ///
@@ -936,6 +1715,26 @@ impl<R: Runtime> DirMgr<R> {
         Ok(self.netdir.get().is_some())
     }
 
+    /// Reload our directory from our immutable cache image, right away.
+    ///
+    /// Embedders that use
+    /// [`DirMgrExtensions::immutable_cache`](crate::config::DirMgrExtensions::immutable_cache)
+    /// should call this after replacing the image, so that we don't have to
+    /// wait for our next periodic reload to notice the new one.
+    ///
+    /// Return true if we now have a usable directory.
+    ///
+    /// Gives an error if we are not using an immutable cache, or if we
+    /// couldn't read the new image.  In the latter case, we keep using the
+    /// directory we had before.
+    pub async fn reload_immutable_cache(self: &Arc<Self>) -> Result<bool> {
+        if !self.config.get().extensions.immutable_cache {
+            return Err(bad_api_usage!("Not using an immutable directory cache").into());
+        }
+        self.reopen_immutable_image()?;
+        self.load_directory(AttemptId::next()).await
+    }
+
     /// Return a new asynchronous stream that will receive notification
     /// whenever the consensus has changed.
     ///
@@ -946,31 +1745,181 @@ impl<R: Runtime> DirMgr<R> {
         self.events.subscribe()
     }
 
-    /// Try to load the text of a single document described by `doc` from
-    /// storage.
-    pub fn text(&self, doc: &DocId) -> Result<Option<DocumentText>> {
-        use itertools::Itertools;
-        let mut result = HashMap::new();
-        let query: DocQuery = (*doc).into();
-        let store = self.store.lock().expect("store lock poisoned");
-        query.load_from_store_into(&mut result, &**store)?;
-        let item = result.into_iter().at_most_one().map_err(|_| {
-            Error::CacheCorruption("Found more than one entry in storage for given docid")
-        })?;
-        if let Some((docid, doctext)) = item {
-            if &docid != doc {
-                return Err(Error::CacheCorruption(
-                    "Item from storage had incorrect docid.",
-                ));
-            }
-            Ok(Some(doctext))
-        } else {
-            Ok(None)
-        }
+    /// Return a record of where the documents in our current network
+    /// directory came from, if we have one.
+    ///
+    /// This lists the source of the consensus, and every source that has
+    /// contributed microdescriptors.
+    pub fn netdir_provenance(&self) -> Option<NetDirProvenance> {
+        self.provenance.lock().expect("poisoned lock").clone()
     }
 
-    /// Load the text for a collection of documents.
-    ///
+    /// Remember that the documents in our current network directory came from
+    /// the sources in `provenance`.
+    fn note_provenance(&self, provenance: &NetDirProvenance) {
+        *self.provenance.lock().expect("poisoned lock") = Some(provenance.clone());
+    }
+
+    /// Return the generation of our current network directory, if we have one.
+    fn netdir_generation(&self) -> Option<NetDirGeneration> {
+        self.netdir.get().map(|netdir| netdir.generation())
+    }
+
+    /// Replace our network directory with `netdir`, and record how it differs
+    /// from the old one.
+    fn replace_netdir(&self, netdir: NetDir) {
+        let old = self.netdir.get();
+        let summary = NetDirChangeSummary::between(old.as_deref(), &netdir);
+        let prev = old.map(|old| old.generation());
+        self.netdir.replace(netdir);
+        self.note_netdir_change(prev, summary);
+    }
+
+    /// Record that we changed our network directory from the one with
+    /// generation `prev`, as described in `summary`.
+    fn note_netdir_change(&self, prev: Option<NetDirGeneration>, summary: NetDirChangeSummary) {
+        if let Some(new) = self.netdir_generation() {
+            self.change_log
+                .lock()
+                .expect("poisoned lock")
+                .record(prev, new, summary);
+        }
+    }
+
+    /// Return true if anybody may be listening for detailed events.
+    fn has_detailed_listeners(&self) -> bool {
+        !self
+            .detailed_events
+            .lock()
+            .expect("poisoned lock")
+            .is_empty()
+    }
+
+    /// Return a summary of how long operations on our directory cache have
+    /// taken.
+    ///
+    /// Returns `None` unless we were configured to measure that, with
+    /// [`DirMgrExtensions::store_timing`](crate::config::DirMgrExtensions::store_timing).
+    pub fn store_latency(&self) -> Option<StoreLatencyReport> {
+        self.store.lock().expect("poisoned lock").latency_report()
+    }
+
+    /// Return a summary of the partially-written entries that we found and
+    /// removed from our directory cache when we opened it for writing.
+    ///
+    /// Returns `None` if we have not yet opened the cache for writing (for
+    /// example, because another process holds the lock).
+    pub fn cache_repairs(&self) -> Option<CacheRepairReport> {
+        self.store.lock().expect("poisoned lock").repair_report()
+    }
+
+    /// Return a summary of how much space our directory cache is using, and
+    /// of what we have evicted to keep it under
+    /// [`DirMgrExtensions::max_cache_size`](crate::config::DirMgrExtensions::max_cache_size).
+    pub fn cache_usage(&self) -> Result<CacheSizeReport> {
+        let mut usage = self.store.lock().expect("poisoned lock").cache_usage()?;
+        usage.max_size = self.config.get().extensions.max_cache_size;
+        Ok(usage)
+    }
+
+    /// Return a stream of events from the directory freshness watchdog.
+    ///
+    /// Once we have bootstrapped, we periodically check how long our directory
+    /// has been stale, and report when it has been stale for longer than
+    /// [`FreshnessWatchdogConfig::alert_after`].  If a
+    /// [`StalenessAlertHook`] is configured, we call it at the same time.
+    pub fn freshness_events(&self) -> BoxStream<'static, FreshnessEvent> {
+        Box::pin(
+            self.freshness_events
+                .lock()
+                .expect("poisoned lock")
+                .subscribe(),
+        )
+    }
+
+    /// Check whether our current directory is getting stale as of `now`, and
+    /// report the result if it has changed since `watchdog` last checked.
+    fn check_freshness(&self, watchdog: &mut freshness::FreshnessWatchdog, now: SystemTime) {
+        let Some(netdir) = self.netdir.get() else {
+            return;
+        };
+        let config = self.config.get();
+        let wd_config = &config.extensions.freshness;
+        let Some(event) = watchdog.check(netdir.lifetime(), now, wd_config.alert_after) else {
+            return;
+        };
+        match &event {
+            FreshnessEvent::GettingStale(alert) => {
+                warn!(
+                    "Our directory information has been stale for {}; we may soon be unable to use the Tor network.",
+                    humantime::format_duration(alert.stale_for()),
+                );
+                if let Some(hook) = &wd_config.hook {
+                    hook.directory_getting_stale(alert);
+                }
+            }
+            FreshnessEvent::Fresh => {
+                info!("Our directory information is fresh again.");
+            }
+        }
+        self.freshness_events
+            .lock()
+            .expect("poisoned lock")
+            .send(&event);
+    }
+
+    /// Run forever, periodically checking whether our directory is getting
+    /// stale.
+    ///
+    /// Exits once the `DirMgr` is dropped.
+    async fn watch_freshness(weak: Weak<Self>) {
+        let mut watchdog = freshness::FreshnessWatchdog::default();
+        loop {
+            let (runtime, interval) = {
+                let Some(dirmgr) = Weak::upgrade(&weak) else {
+                    return;
+                };
+                dirmgr.check_freshness(&mut watchdog, dirmgr.runtime.wallclock());
+                let interval = dirmgr.config.get().extensions.freshness.check_interval;
+                (dirmgr.runtime.clone(), interval)
+            };
+            runtime.sleep(interval).await;
+        }
+    }
+
+    /// Send `event` to every listener for detailed events.
+    fn publish_detailed(&self, event: &DetailedDirEvent) {
+        self.detailed_events
+            .lock()
+            .expect("poisoned lock")
+            .send(event);
+    }
+
+    /// Try to load the text of a single document described by `doc` from
+    /// storage.
+    pub fn text(&self, doc: &DocId) -> Result<Option<DocumentText>> {
+        use itertools::Itertools;
+        let mut result = HashMap::new();
+        let query: DocQuery = (*doc).into();
+        let store = self.store.lock().expect("store lock poisoned");
+        query.load_from_store_into(&mut result, &**store)?;
+        let item = result.into_iter().at_most_one().map_err(|_| {
+            Error::CacheCorruption("Found more than one entry in storage for given docid")
+        })?;
+        if let Some((docid, doctext)) = item {
+            if &docid != doc {
+                return Err(Error::CacheCorruption(
+                    "Item from storage had incorrect docid.",
+                ));
+            }
+            Ok(Some(doctext))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Load the text for a collection of documents.
+    ///
     /// If many of the documents have the same type, this can be more
     /// efficient than calling [`text`](Self::text).
     pub fn texts<T>(&self, docs: T) -> Result<HashMap<DocId, DocumentText>>
@@ -986,6 +1935,224 @@ impl<R: Runtime> DirMgr<R> {
         Ok(result)
     }
 
+    /// Return a diff from the consensus whose signed part has SHA3-256
+    /// digest `from_digest` to the one whose signed part has digest
+    /// `to_digest`, in the format described in dir-spec.txt.
+    ///
+    /// This is for directory caches: clients name the consensuses they already
+    /// have by these digests, and a cache can answer with a diff to its latest
+    /// consensus (see [`latest_consensus_digest`](Self::latest_consensus_digest))
+    /// instead of the whole document.
+    ///
+    /// If we haven't already stored this diff, we compute it (and store it,
+    /// if we can).  Returns `Ok(None)` if either consensus is not in our cache.
+    pub fn consensus_diff(
+        &self,
+        from_digest: &[u8; 32],
+        to_digest: &[u8; 32],
+    ) -> Result<Option<String>> {
+        dircache::consensus_diff(&self.store, from_digest, to_digest)
+    }
+
+    /// Return the SHA3-256 digest of the signed part of the latest usable
+    /// consensus in our cache, of the flavor we are configured to maintain.
+    ///
+    /// Returns `Ok(None)` if we have no such consensus.
+    pub fn latest_consensus_digest(&self) -> Result<Option<[u8; 32]>> {
+        let flavor = self.config.get().extensions.consensus_flavor;
+        let store = self.store.lock().expect("store lock poisoned");
+        Ok(store
+            .latest_consensus_meta(flavor)?
+            .map(|meta| *meta.sha3_256_of_signed()))
+    }
+
+    /// Rebuild our network directory as it was at `when`, from the
+    /// consensuses and microdescriptors in our cache.
+    ///
+    /// We use the latest usable microdescriptor consensus that was valid at
+    /// `when`.  Ordinarily we only keep the last few days' worth of
+    /// consensuses; set
+    /// [`archived_consensuses`](crate::config::DirMgrExtensions::archived_consensuses)
+    /// to keep more.
+    ///
+    /// The directory uses the network parameter overrides from our current
+    /// configuration, and has no measured bandwidths.  We don't check the
+    /// consensus's signatures again: we only mark a consensus usable after
+    /// checking them, and the certificates we used may be long gone.
+    ///
+    /// Return `Ok(None)` if we have no consensus that was valid at `when`,
+    /// and [`Error::ArchiveIncomplete`] if we don't have enough of its
+    /// microdescriptors to build a usable directory.
+    pub fn netdir_at(&self, when: SystemTime) -> Result<Option<NetDir>> {
+        let config = self.config.get();
+        // We only hold the store lock while we read from the store, and not
+        // while we parse.
+        let text = self
+            .store
+            .lock()
+            .expect("store lock poisoned")
+            .consensus_valid_at(ConsensusFlavor::Microdesc, when)?;
+        let Some((text, _)) = text else {
+            return Ok(None);
+        };
+        let (_, _, unchecked) = MdConsensus::parse(text.as_str()?)
+            .map_err(|e| Error::from_netdoc(DocSource::LocalCache, e))?;
+        let consensus = unchecked
+            .check_valid_at(&when)?
+            .dangerously_assume_wellsigned();
+
+        let params = &config.override_net_params;
+        #[cfg(not(feature = "geoip"))]
+        let mut partial = PartialNetDir::new(consensus, Some(params));
+        #[cfg(feature = "geoip")]
+        let mut partial = match &config.extensions.geoip {
+            Some(mgr) => PartialNetDir::new_with_geoip_manager(consensus, Some(params), mgr),
+            None => PartialNetDir::new_with_geoip(
+                consensus,
+                Some(params),
+                &tor_geoip::GeoipDb::new_embedded(),
+            ),
+        };
+        if let Some(pool) = self.netdir.get().and_then(|d| d.md_pool().cloned()) {
+            partial = partial.with_md_pool(pool);
+        }
+
+        let digests: Vec<MdDigest> = partial.missing_microdescs().copied().collect();
+        let mds = self
+            .store
+            .lock()
+            .expect("store lock poisoned")
+            .microdescs(&digests)?;
+        for (digest, text) in mds {
+            match Microdesc::parse(&text) {
+                Ok(md) if md.digest() == &digest => {
+                    partial.add_microdesc(md);
+                }
+                _ => warn!("Found a mismatched microdescriptor in cache; ignoring"),
+            }
+        }
+        partial
+            .unwrap_if_sufficient()
+            .map(Some)
+            .map_err(|_| Error::ArchiveIncomplete)
+    }
+
+    /// Use `text`, a bandwidth file from a bandwidth scanner such as `sbws`,
+    /// as our source of measured bandwidths.
+    ///
+    /// We store the file in our cache, replacing any earlier one, and record
+    /// its measurements in our current network directory and in the ones that
+    /// replace it, where they are available from
+    /// [`Relay::measured_bandwidth`](tor_netdir::Relay::measured_bandwidth).
+    /// They have no effect on path selection.
+    ///
+    /// Return the number of relays in our current directory for which the file
+    /// has a measurement.  (This is zero if we have no directory yet.)
+    pub fn set_bandwidth_file(&self, text: &str) -> Result<usize> {
+        let file =
+            BandwidthFile::parse(text).map_err(|e| Error::from_netdoc(DocSource::Caller, e))?;
+        {
+            let mut store = self.store.lock().expect("store lock poisoned");
+            if !store.is_readonly() {
+                store.store_bandwidth_file(file.timestamp(), text)?;
+            }
+        }
+
+        let prev = self.netdir_generation();
+        let mut n_measured = 0;
+        // (It's okay to ignore the error, since it just means that there
+        // was no current netdir.)
+        let replaced = self.netdir.mutate(|netdir| {
+            n_measured = netdir.set_measured_bandwidths(&file);
+            Ok(())
+        });
+        if replaced.is_ok() {
+            self.note_netdir_change(prev, NetDirChangeSummary::default());
+        }
+        Ok(n_measured)
+    }
+
+    /// Return our current directory, and the SHA3-256 digest of the signed
+    /// part of its consensus.
+    ///
+    /// Returns `Ok(None)` if we have no directory, or if it wasn't built from
+    /// the latest usable consensus in our cache (as when it came from a static
+    /// bundle).
+    fn netdir_with_consensus_digest(&self) -> Result<Option<(Arc<NetDir>, [u8; 32])>> {
+        let Some(netdir) = self.netdir.get() else {
+            return Ok(None);
+        };
+        let flavor = self.config.get().extensions.consensus_flavor;
+        let store = self.store.lock().expect("store lock poisoned");
+        Ok(store
+            .latest_consensus_meta(flavor)?
+            .filter(|meta| meta.lifetime().valid_after() == netdir.lifetime().valid_after())
+            .map(|meta| (netdir, *meta.sha3_256_of_signed())))
+    }
+
+    /// Save the persistent state of `guardmgr`, recording which of our
+    /// directories it goes with.
+    ///
+    /// Call this at shutdown, so that at the next startup,
+    /// [`check_guard_pairing`](DirMgr::check_guard_pairing) can tell whether
+    /// the restored guards match the restored directory.
+    ///
+    /// We only record our current directory if it came from the latest
+    /// consensus in our cache, and it has the information we need about every
+    /// one of `guardmgr`'s primary guards.  Otherwise, we record that we don't
+    /// know which directory the guards go with.
+    ///
+    /// Returns the [`DirectoryAnchor`] that we recorded, if any.
+    pub fn store_guard_state(&self, guardmgr: &GuardMgr<R>) -> Result<Option<DirectoryAnchor>> {
+        let anchor = match self.netdir_with_consensus_digest()? {
+            Some((netdir, digest))
+                if guardmgr
+                    .check_directory_pairing(&netdir, &digest)
+                    .covers_primary_guards() =>
+            {
+                Some(DirectoryAnchor::new(
+                    digest,
+                    netdir.lifetime().valid_after(),
+                ))
+            }
+            _ => None,
+        };
+        guardmgr.set_directory_anchor(anchor.clone());
+        guardmgr
+            .store_persistent_state()
+            .map_err(Error::GuardState)?;
+        Ok(anchor)
+    }
+
+    /// Check whether the restored state of `guardmgr` is consistent with our
+    /// current directory.
+    ///
+    /// Call this at startup, once we have loaded a directory from our cache.
+    /// If the result doesn't [cover our primary
+    /// guards](DirectoryPairing::covers_primary_guards), our cached directory
+    /// doesn't know about some of the guards that we would use first, and we
+    /// should not rely on it to tell us about them until we have a fresh one.
+    ///
+    /// Returns `Ok(None)` if we have no directory, or if it wasn't built from
+    /// the latest usable consensus in our cache.
+    pub fn check_guard_pairing(&self, guardmgr: &GuardMgr<R>) -> Result<Option<DirectoryPairing>> {
+        let Some((netdir, digest)) = self.netdir_with_consensus_digest()? else {
+            return Ok(None);
+        };
+        let pairing = guardmgr.check_directory_pairing(&netdir, &digest);
+        match &pairing {
+            DirectoryPairing::MissingPrimaryGuards { n_missing } => warn!(
+                "Our cached directory is missing information about {} of our primary guards.",
+                n_missing
+            ),
+            DirectoryPairing::Changed { .. } => {
+                debug!("Our guard state was saved with a different directory.");
+            }
+            _ => {}
+        }
+        Ok(Some(pairing))
+    }
+
     /// Given a request we sent and the response we got from a
     /// directory server, see whether we should expand that response
     /// into "something larger".
@@ -1032,6 +2199,8 @@ impl<R: Runtime> DirMgr<R> {
                 NetDirChange::AttemptReplace {
                     netdir,
                     consensus_meta,
+                    provenance,
+                    newly_listed,
                 } => {
                     // Check the new netdir is sufficient, if we have a circmgr.
                     // (Unwraps are fine because the `Option` is `Some` until we take it.)
@@ -1064,27 +2233,62 @@ impl<R: Runtime> DirMgr<R> {
                     let cfg = self.config.get();
                     let mut netdir = netdir.take().expect("AttemptReplace had None");
                     netdir.replace_overridden_parameters(&cfg.override_net_params);
-                    self.netdir.replace(netdir);
+                    if self.netdir.get().is_none() {
+                        // Later directories take their measured bandwidths
+                        // from this one; this one has to get them from the
+                        // cache.
+                        load_bandwidth_file(store, &mut netdir);
+                    }
+                    let change = self
+                        .has_detailed_listeners()
+                        .then(|| RelayListChange::between(self.netdir.get().as_deref(), &netdir));
+                    self.replace_netdir(netdir);
+                    self.note_provenance(provenance);
                     self.events.publish(DirEvent::NewConsensus);
                     self.events.publish(DirEvent::NewDescriptors);
+                    if let Some(change) = change {
+                        self.publish_detailed(&DetailedDirEvent::NewConsensus(Arc::new(change)));
+                    }
 
                     info!("Marked consensus usable.");
                     if !store.is_readonly() {
-                        store.mark_consensus_usable(consensus_meta)?;
                         // Now that a consensus is usable, older consensuses may
                         // need to expire.
-                        store.expire_all(&crate::storage::EXPIRATION_DEFAULTS)?;
+                        mark_usable_and_expire(
+                            store,
+                            consensus_meta,
+                            newly_listed,
+                            &ExpirationConfig::from_config(&self.config.get()),
+                        )?;
+                        newly_listed.clear();
                     }
                     Ok(())
                 }
-                NetDirChange::AddMicrodescs(mds) => {
+                NetDirChange::AddMicrodescs(mds, provenance) => {
+                    let mut added = Vec::with_capacity(mds.len());
+                    let prev = self.netdir_generation();
                     self.netdir.mutate(|netdir| {
                         for md in mds.drain(..) {
-                            netdir.add_microdesc(md);
+                            let digest = *md.digest();
+                            if netdir.add_microdesc(md) {
+                                added.push(digest);
+                            }
                         }
                         Ok(())
                     })?;
+                    let mut summary = NetDirChangeSummary::default();
+                    summary.n_changed = added.len();
+                    self.note_netdir_change(prev, summary);
+                    self.note_provenance(provenance);
                     self.events.publish(DirEvent::NewDescriptors);
+                    self.publish_detailed(&DetailedDirEvent::NewDescriptors(added.into()));
+                    Ok(())
+                }
+                NetDirChange::MarkConsensusUsable { consensus_meta } => {
+                    info!("Marked consensus usable.");
+                    if !store.is_readonly() {
+                        store.mark_consensus_usable(consensus_meta)?;
+                    }
                     Ok(())
                 }
             }
@@ -1094,6 +2298,26 @@ impl<R: Runtime> DirMgr<R> {
     }
 }
 
+/// Mark the consensus described by `consensus_meta` usable in `store`, and
+/// then expire old documents from `store`.
+///
+/// Before we expire anything, we record that the consensus lists the
+/// microdescriptors in `newly_listed`.  Otherwise, the ones that we loaded
+/// from the cache would still look unlisted, and we might evict them to keep
+/// the cache under its size limit.
+fn mark_usable_and_expire(
+    store: &mut dyn Store,
+    consensus_meta: &docmeta::ConsensusMeta,
+    newly_listed: &[MdDigest],
+    expiration: &ExpirationConfig,
+) -> Result<()> {
+    store.mark_consensus_usable(consensus_meta)?;
+    if !newly_listed.is_empty() {
+        store.update_microdescs_listed(newly_listed, consensus_meta.lifetime().valid_after())?;
+    }
+    store.expire_all(expiration)
+}
+
 /// A degree of readiness for a given directory state object.
 #[derive(Debug, Copy, Clone)]
 enum Readiness {
@@ -1109,6 +2333,31 @@ fn upgrade_weak_ref<T>(weak: &Weak<T>) -> Result<Arc<T>> {
     Weak::upgrade(weak).ok_or(Error::ManagerDropped)
 }
 
+/// Record the measured bandwidths from the bandwidth file in `store`, if there
+/// is one, in `netdir`.
+///
+/// A bandwidth file that we can't read only costs us those measurements, so we
+/// just warn about it.
+fn load_bandwidth_file(store: &dyn Store, netdir: &mut NetDir) {
+    let text = match store.latest_bandwidth_file() {
+        Ok(Some(text)) => text,
+        Ok(None) => return,
+        Err(e) => {
+            warn_report!(e, "Unable to read bandwidth file from cache");
+            return;
+        }
+    };
+    match BandwidthFile::parse(&text) {
+        Ok(file) => {
+            netdir.set_measured_bandwidths(&file);
+        }
+        Err(e) => {
+            let e = Error::from_netdoc(DocSource::LocalCache, e);
+            warn_report!(e, "Unable to parse bandwidth file from cache");
+        }
+    }
+}
+
 /// Given a time `now`, and an amount of tolerated clock skew `tolerance`,
 /// return the age of the oldest consensus that we should request at that time.
 pub(crate) fn default_consensus_cutoff(
@@ -1172,6 +2421,303 @@ mod test {
         (dir, dirmgr)
     }
 
+    #[test]
+    fn mark_listed_before_evicting() {
+        use crate::storage::sqlite::test::new_empty;
+
+        let (_tmp_dir, mut store) = new_empty().unwrap();
+        let now = SystemTime::now();
+        let one_hour = Duration::from_secs(3600);
+        let cmeta = ConsensusMeta::new(
+            Lifetime::new(now - one_hour, now, now + one_hour * 2).unwrap(),
+            [1; 32],
+            [2; 32],
+        );
+        store
+            .store_consensus(
+                &cmeta,
+                ConsensusFlavor::Microdesc,
+                true,
+                "Pretend consensus",
+            )
+            .unwrap();
+
+        // Two microdescriptors that were last listed three days ago.  The new
+        // consensus lists one of them, but we loaded it from the cache, so
+        // the store doesn't know that yet.
+        let text = "x".repeat(1000);
+        let (listed, unlisted) = ([10; 32], [11; 32]);
+        store
+            .store_microdescs(&[(&text, &listed), (&text, &unlisted)], now - one_hour * 72)
+            .unwrap();
+
+        // With an impossible size limit, we evict only the one that the
+        // consensus doesn't list.
+        let expiration = ExpirationConfig {
+            max_cache_size: Some(0),
+            ..storage::EXPIRATION_DEFAULTS
+        };
+        mark_usable_and_expire(&mut store, &cmeta, &[listed], &expiration).unwrap();
+        assert_eq!(store.microdescs(&[listed]).unwrap().len(), 1);
+        assert!(store.microdescs(&[unlisted]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn static_bundle() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use crate::staticdir::test::{config2, CONSENSUS2, MICRODESCS};
+            let dir = TempDir::new().unwrap();
+            let mut config = config2();
+            config.cache_dir = dir.path().into();
+            config.extensions.static_bundle =
+                Some(StaticDirBundle::from_texts(CONSENSUS2, [MICRODESCS]));
+
+            // load_once doesn't need a cache.
+            let netdir = DirMgr::load_once(rt.clone(), config.clone()).await.unwrap();
+            assert_eq!(netdir.relays().count(), 4);
+
+            let store = DirMgrStore::new(&config, rt.clone(), false).unwrap();
+            let mgr =
+                Arc::new(DirMgr::from_config(config.clone(), rt, store, None, false).unwrap());
+            mgr.bootstrap().await.unwrap();
+            assert!(mgr.bootstrap_started());
+            // This consensus expired long ago, but we use it anyway.
+            let netdir = mgr.netdir(Timeliness::Strict).unwrap();
+            assert_eq!(netdir.relays().count(), 4);
+            assert!(matches!(
+                mgr.netdir_provenance().unwrap().consensus_source(),
+                DocSource::StaticBundle
+            ));
+            let status = DirMgr::bootstrap_events(&mgr).inner.borrow().clone();
+            assert!(status.usable_at(SystemTime::now()));
+
+            // Bootstrapping again does nothing.
+            mgr.bootstrap().await.unwrap();
+
+            // We can't turn static mode off.
+            let mut new_config = config.clone();
+            new_config.extensions.static_bundle = None;
+            assert!(mgr
+                .reconfigure(&new_config, tor_config::Reconfigure::CheckAllOrNothing)
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn netdir_at() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use crate::docmeta::ConsensusMeta;
+            use crate::staticdir::test::{CONSENSUS2, MICRODESCS};
+            use tor_netdoc::doc::microdesc::MicrodescReader;
+            use tor_netdoc::AllowAnnotations;
+
+            let (_tempdir, mgr) = new_mgr(rt);
+            let (signed, rest, consensus) = MdConsensus::parse(CONSENSUS2).unwrap();
+            let consensus = consensus
+                .dangerously_assume_timely()
+                .dangerously_assume_wellsigned();
+            let meta = ConsensusMeta::from_consensus(signed, rest, &consensus);
+            let valid_after = consensus.lifetime().valid_after();
+            let during = valid_after + Duration::from_secs(10);
+
+            assert!(mgr.netdir_at(during).unwrap().is_none());
+
+            mgr.store_if_rw()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .store_consensus(&meta, ConsensusFlavor::Microdesc, false, CONSENSUS2)
+                .unwrap();
+            let r = mgr.netdir_at(during);
+            assert!(matches!(r, Err(Error::ArchiveIncomplete)), "{:?}", r);
+
+            let mds: Vec<_> =
+                MicrodescReader::new(MICRODESCS, &AllowAnnotations::AnnotationsAllowed)
+                    .map(|md| {
+                        let md = md.unwrap();
+                        let text = md.within(MICRODESCS).unwrap().to_owned();
+                        (text, *md.into_microdesc().digest())
+                    })
+                    .collect();
+            let mds: Vec<_> = mds.iter().map(|(text, d)| (text.as_str(), d)).collect();
+            mgr.store_if_rw()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .store_microdescs(&mds, valid_after)
+                .unwrap();
+
+            let netdir = mgr.netdir_at(during).unwrap().unwrap();
+            assert_eq!(netdir.relays().count(), 4);
+            assert_eq!(netdir.lifetime().valid_after(), valid_after);
+            // We have nothing from before this consensus.
+            assert!(mgr
+                .netdir_at(valid_after - Duration::from_secs(10))
+                .unwrap()
+                .is_none());
+        });
+    }
+
+    #[test]
+    fn guard_pairing() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use tor_guardmgr::{GuardMgr, TestConfig};
+            use tor_persist::{StateMgr as _, TestingStateMgr};
+
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            let statemgr = TestingStateMgr::new();
+            assert!(statemgr.try_lock().unwrap().held());
+            let guardmgr = GuardMgr::new(rt, statemgr, &TestConfig::default()).unwrap();
+
+            // No directory: nothing to record or check.
+            assert!(mgr.store_guard_state(&guardmgr).unwrap().is_none());
+            assert!(mgr.check_guard_pairing(&guardmgr).unwrap().is_none());
+
+            let netdir = tor_netdir::testnet::construct_netdir()
+                .unwrap_if_sufficient()
+                .unwrap();
+            guardmgr.install_test_netdir(&netdir);
+            mgr.netdir.replace(netdir.clone());
+
+            // A directory that isn't from our cache: nothing to record.
+            assert!(mgr.store_guard_state(&guardmgr).unwrap().is_none());
+
+            let cmeta = ConsensusMeta::new(netdir.lifetime().clone(), [102; 32], [103; 32]);
+            mgr.store
+                .lock()
+                .unwrap()
+                .store_consensus(&cmeta, ConsensusFlavor::Microdesc, false, "Fake consensus!")
+                .unwrap();
+
+            let anchor = mgr.store_guard_state(&guardmgr).unwrap().unwrap();
+            assert_eq!(anchor.consensus_digest(), &[102; 32]);
+            assert_eq!(guardmgr.directory_anchor(), Some(anchor));
+            assert_eq!(
+                mgr.check_guard_pairing(&guardmgr).unwrap(),
+                Some(DirectoryPairing::Consistent)
+            );
+        });
+    }
+
+    #[test]
+    fn change_summary() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+            let netdir = || {
+                tor_netdir::testnet::construct_netdir()
+                    .unwrap_if_sufficient()
+                    .unwrap()
+            };
+            let (first, second) = (netdir(), netdir());
+            let (g1, g2) = (first.generation(), second.generation());
+            let expected = NetDirChangeSummary::between(Some(&first), &second);
+            assert!(mgr.change_summary(g1).is_none());
+
+            mgr.replace_netdir(first);
+            assert_eq!(mgr.change_summary(g1), Some(Default::default()));
+            mgr.replace_netdir(second);
+            assert_eq!(mgr.change_summary(g1), Some(expected.clone()));
+            assert_eq!(mgr.change_summary(g2), Some(Default::default()));
+
+            // Changing the parameters is a change too.
+            let mut new_config = mgr.config.get().as_ref().clone();
+            new_config.override_net_params.set("circwindow".into(), 500);
+            mgr.reconfigure(&new_config, tor_config::Reconfigure::AllOrNothing)
+                .unwrap();
+            let since_first = mgr.change_summary(g1).unwrap();
+            assert!(since_first.params_changed);
+            assert_eq!(since_first.n_changed, expected.n_changed);
+            let since_second = mgr.change_summary(g2).unwrap();
+            assert!(since_second.params_changed);
+            assert_eq!(since_second.n_changed, 0);
+
+            // A directory that we never published is unknown.
+            assert!(mgr.change_summary(netdir().generation()).is_none());
+
+            // We get the same answers through an Arc.
+            let mgr = Arc::new(mgr);
+            assert_eq!(NetDirProvider::change_summary(&mgr, g2), Some(since_second));
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "geoip")]
+    fn set_geoip_db() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use futures::{FutureExt as _, StreamExt as _};
+            use tor_geoip::HasCountryCode as _;
+            use tor_llcrypto::pk::ed25519::Ed25519Identity;
+            let (_tempdir, mgr) = new_mgr(rt);
+            let mut events = mgr.events();
+            assert!(mgr.config.get().extensions.geoip.is_none());
+
+            // With no directory, we install the database, but there's
+            // nothing to announce.
+            let db =
+                tor_geoip::GeoipDb::new_from_legacy_format("16777216,33554431,FR", "").unwrap();
+            mgr.set_geoip_db(Arc::new(db));
+            assert!(events.next().now_or_never().is_none());
+            let own_db = mgr.config.get().extensions.geoip.clone().unwrap();
+            assert_eq!(
+                own_db
+                    .current()
+                    .lookup_country_code("1.0.0.3".parse().unwrap())
+                    .map(|cc| cc.to_string()),
+                Some("FR".into())
+            );
+
+            // Reconfiguring without a manager doesn't lose our database.
+            let mut new_config = (*mgr.config.get()).clone();
+            new_config.extensions.geoip = None;
+            mgr.reconfigure(&new_config, tor_config::Reconfigure::AllOrNothing)
+                .unwrap();
+            assert!(Arc::ptr_eq(
+                &mgr.config.get().extensions.geoip.clone().unwrap(),
+                &own_db
+            ));
+
+            let netdir = tor_netdir::testnet::construct_netdir()
+                .unwrap_if_sufficient()
+                .unwrap();
+            let g1 = netdir.generation();
+            mgr.replace_netdir(netdir);
+            let mut events = mgr.events();
+
+            // Every fifth relay in the test network is at 1.0.0.3.
+            let db =
+                tor_geoip::GeoipDb::new_from_legacy_format("16777216,33554431,US", "").unwrap();
+            mgr.set_geoip_db(Arc::new(db));
+            let ev = events.next().now_or_never().unwrap().unwrap();
+            assert_eq!(ev, DirEvent::NewConsensus);
+            assert_eq!(mgr.change_summary(g1).unwrap().n_changed, 8);
+
+            let netdir = mgr.netdir(Timeliness::Unchecked).unwrap();
+            let cc = |idx: u8| {
+                netdir
+                    .by_id(&Ed25519Identity::from([idx; 32]))
+                    .unwrap()
+                    .country_code()
+                    .map(|cc| cc.to_string())
+            };
+            assert_eq!(cc(1), Some("US".into()));
+            assert_eq!(cc(2), None);
+
+            // The database is in our configuration, for later directories.
+            let mgr_db = mgr.config.get().extensions.geoip.clone().unwrap();
+            assert!(Arc::ptr_eq(&mgr_db, &own_db));
+            let db =
+                tor_geoip::GeoipDb::new_from_legacy_format("16777216,33554431,DE", "").unwrap();
+            mgr.set_geoip_db(Arc::new(db));
+            assert_eq!(
+                mgr_db
+                    .current()
+                    .lookup_country_code("1.0.0.3".parse().unwrap())
+                    .map(|cc| cc.to_string()),
+                Some("DE".into())
+            );
+        });
+    }
+
     #[test]
     fn failing_accessors() {
         tor_rtcompat::test_with_one_runtime!(|rt| async {
@@ -1182,6 +2728,269 @@ mod test {
         });
     }
 
+    #[test]
+    fn detailed_events() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use futures::{FutureExt as _, StreamExt as _};
+            let (_tempdir, mgr) = new_mgr(rt);
+            let mut events = mgr.detailed_events();
+            assert!(events.next().now_or_never().is_none());
+
+            let mut config = (*mgr.config.get()).clone();
+            config.override_net_params.set("circwindow".into(), 999);
+            mgr.reconfigure(&config, tor_config::Reconfigure::AllOrNothing)
+                .unwrap();
+            let ev = events.next().now_or_never().unwrap().unwrap();
+            assert!(matches!(ev, DetailedDirEvent::NewParameters));
+
+            // Once the stream is dropped, we stop sending to it.
+            drop(events);
+            mgr.publish_detailed(&DetailedDirEvent::NewParameters);
+            assert!(!mgr.has_detailed_listeners());
+        });
+    }
+
+    #[test]
+    fn store_latency() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            // Without timing configured, we have no report.
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            assert!(mgr.store_latency().is_none());
+
+            let dir = TempDir::new().unwrap();
+            let mut config = DirMgrConfig {
+                cache_dir: dir.path().into(),
+                ..Default::default()
+            };
+            config.extensions.store_timing = Some(StoreTimingConfig::default());
+            let store = DirMgrStore::new(&config, rt.clone(), false).unwrap();
+            let mgr = DirMgr::from_config(config.clone(), rt, store, None, false).unwrap();
+
+            let d1 = [5_u8; 32];
+            mgr.store
+                .lock()
+                .unwrap()
+                .store_microdescs(&[("Fake micro 1", &d1)], SystemTime::now())
+                .unwrap();
+            let _ = mgr.text(&DocId::Microdesc(d1)).unwrap();
+
+            let report = mgr.store_latency().unwrap();
+            assert_eq!(report.by_operation["store_microdescs"].count, 1);
+            assert_eq!(report.by_operation["microdescs"].count, 1);
+            assert!(report.overall.count >= 2);
+
+            // We can't turn timing off on a running DirMgr.
+            config.extensions.store_timing = None;
+            assert!(mgr
+                .reconfigure(&config, tor_config::Reconfigure::AllOrNothing)
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn immutable_cache() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            // Only a DirMgr with an immutable cache can reload it.
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            let mgr = Arc::new(mgr);
+            assert!(mgr.reload_immutable_cache().await.is_err());
+
+            // Make an empty image, and open it as immutable.
+            let dir = TempDir::new().unwrap();
+            let mut config = DirMgrConfig {
+                cache_dir: dir.path().into(),
+                ..Default::default()
+            };
+            drop(DirMgrStore::new(&config, rt.clone(), false).unwrap());
+            std::fs::remove_file(dir.path().join("dir.lock")).unwrap();
+            config.extensions.immutable_cache = true;
+            let store = DirMgrStore::new(&config, rt.clone(), false).unwrap();
+            let mgr =
+                Arc::new(DirMgr::from_config(config.clone(), rt, store, None, false).unwrap());
+            assert!(!dir.path().join("dir.lock").try_exists().unwrap());
+            assert!(mgr.store_if_rw().is_none());
+            assert!(!mgr.try_upgrade_to_readwrite().unwrap());
+
+            // There's nothing to load, but reloading works.
+            assert!(!mgr.reload_immutable_cache().await.unwrap());
+            assert!(mgr.netdir.get().is_none());
+
+            // Writes give a structured error.
+            let d1 = [5_u8; 32];
+            let err = mgr
+                .store
+                .lock()
+                .unwrap()
+                .store_microdescs(&[("Fake micro 1", &d1)], SystemTime::now())
+                .unwrap_err();
+            assert!(matches!(
+                err,
+                Error::ReadOnlyStorage(crate::err::ReadOnlyStorageError::Immutable)
+            ));
+
+            // We can't change this on a running DirMgr.
+            config.extensions.immutable_cache = false;
+            assert!(mgr
+                .reconfigure(&config, tor_config::Reconfigure::AllOrNothing)
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn freshness_without_netdir() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use futures::{FutureExt as _, StreamExt as _};
+            let (_tempdir, mgr) = new_mgr(rt);
+            let mut events = mgr.freshness_events();
+
+            // With no directory, there is nothing to be stale.
+            let mut watchdog = freshness::FreshnessWatchdog::default();
+            let far_future = SystemTime::now() + Duration::from_secs(86400 * 365);
+            mgr.check_freshness(&mut watchdog, far_future);
+            assert!(events.next().now_or_never().is_none());
+        });
+    }
+
+    #[test]
+    fn freshness_alert() {
+        /// A hook that remembers every alert it gets.
+        #[derive(Debug, Default)]
+        struct RecordingHook(Mutex<Vec<StalenessAlert>>);
+        impl StalenessAlertHook for RecordingHook {
+            fn directory_getting_stale(&self, alert: &StalenessAlert) {
+                self.0.lock().unwrap().push(alert.clone());
+            }
+        }
+
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use futures::{FutureExt as _, StreamExt as _};
+            let hook = Arc::new(RecordingHook::default());
+            let dir = TempDir::new().unwrap();
+            let mut config = DirMgrConfig {
+                cache_dir: dir.path().into(),
+                ..Default::default()
+            };
+            config.extensions.freshness = FreshnessWatchdogConfig::builder()
+                .hook(hook.clone() as Arc<dyn StalenessAlertHook>)
+                .build()
+                .unwrap();
+            let alert_after = config.extensions.freshness.alert_after();
+            let store = DirMgrStore::new(&config, rt.clone(), false).unwrap();
+            let mgr = DirMgr::from_config(config, rt, store, None, false).unwrap();
+            let mut events = mgr.freshness_events();
+
+            let netdir = tor_netdir::testnet::construct_netdir()
+                .unwrap_if_sufficient()
+                .unwrap();
+            let lifetime = netdir.lifetime().clone();
+            mgr.netdir.replace(netdir);
+            let mut watchdog = freshness::FreshnessWatchdog::default();
+
+            // Stale, but not for long enough to worry about.
+            mgr.check_freshness(&mut watchdog, lifetime.fresh_until() + alert_after / 2);
+            assert!(events.next().now_or_never().is_none());
+            assert!(hook.0.lock().unwrap().is_empty());
+
+            // Stale for too long: we tell the hook and the event stream.
+            let stale_for = alert_after + Duration::from_secs(60);
+            mgr.check_freshness(&mut watchdog, lifetime.fresh_until() + stale_for);
+            let Some(Some(FreshnessEvent::GettingStale(alert))) = events.next().now_or_never()
+            else {
+                panic!("no staleness alert");
+            };
+            assert_eq!(alert.fresh_until(), lifetime.fresh_until());
+            assert_eq!(alert.stale_for(), stale_for);
+            assert_eq!(hook.0.lock().unwrap().as_slice(), &[alert]);
+
+            // Once the directory is fresh again, we say so, but don't call the hook.
+            mgr.check_freshness(&mut watchdog, lifetime.valid_after());
+            assert_eq!(
+                events.next().now_or_never(),
+                Some(Some(FreshnessEvent::Fresh))
+            );
+            assert_eq!(hook.0.lock().unwrap().len(), 1);
+        });
+    }
+
+    #[test]
+    fn switch_network() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use futures::{FutureExt as _, StreamExt as _};
+            let (_tempdir, mgr) = new_mgr(rt);
+            let mut events = mgr.events();
+            let now = mgr.runtime.wallclock();
+            let digest = [5_u8; 32];
+            let has_md = |mgr: &DirMgr<_>| {
+                let store = mgr.store.lock().unwrap();
+                !store.microdescs(&[digest]).unwrap().is_empty()
+            };
+            mgr.store
+                .lock()
+                .unwrap()
+                .store_microdescs(&[("Fake micro", &digest)], now)
+                .unwrap();
+            let netdir = tor_netdir::testnet::construct_netdir()
+                .unwrap_if_sufficient()
+                .unwrap();
+            mgr.netdir.replace(netdir);
+
+            // Configure a test network, and switch to it.
+            let config = mgr.config.get();
+            let mut testnet = AlternativeNetwork::builder();
+            testnet.set_authorities(vec![Authority::builder()
+                .name("test")
+                .v3ident([b'?'; 20].into())
+                .clone()]);
+            testnet.set_fallback_caches(vec![]);
+            let mut bld = NetworkConfig::builder();
+            bld.networks().insert("test".into(), testnet);
+            bld.network_name("test");
+            let new_config = DirMgrConfig {
+                network: bld.build().unwrap(),
+                ..(*config).clone()
+            };
+
+            // If we can't open the new network's cache, we find out before
+            // we change anything.
+            let test_cache_dir = new_config.network_cache_dir();
+            std::fs::create_dir_all(test_cache_dir.parent().unwrap()).unwrap();
+            std::fs::write(&test_cache_dir, "not a directory").unwrap();
+            assert!(mgr
+                .reconfigure(&new_config, tor_config::Reconfigure::CheckAllOrNothing)
+                .is_err());
+            std::fs::remove_file(&test_cache_dir).unwrap();
+            mgr.reconfigure(&new_config, tor_config::Reconfigure::CheckAllOrNothing)
+                .unwrap();
+            assert_eq!(mgr.config.get().network.network_name(), None);
+            assert!(mgr.netdir.get().is_some());
+            assert!(events.next().now_or_never().is_none());
+
+            mgr.reconfigure(&new_config, tor_config::Reconfigure::AllOrNothing)
+                .unwrap();
+
+            // We haven't started bootstrapping, so we switched right away,
+            // and told our users that our directory is gone.
+            let config = mgr.config.get();
+            assert_eq!(config.network.network_name(), Some("test"));
+            assert_eq!(config.authorities().len(), 1);
+            assert!(mgr.netdir.get().is_none());
+            assert!(!mgr.store.lock().unwrap().is_readonly());
+            assert!(!has_md(&mgr));
+            let ev = events.next().now_or_never().unwrap().unwrap();
+            assert_eq!(ev, DirEvent::NewConsensus);
+
+            // When we switch back, we find our old cache again.
+            let old_config = DirMgrConfig {
+                network: NetworkConfig::default(),
+                ..(*config).clone()
+            };
+            mgr.reconfigure(&old_config, tor_config::Reconfigure::AllOrNothing)
+                .unwrap();
+            assert_eq!(mgr.config.get().network.network_name(), None);
+            assert!(has_md(&mgr));
+        });
+    }
+
     #[test]
     fn load_and_store_internals() {
         tor_rtcompat::test_with_one_runtime!(|rt| async {
@@ -1515,4 +3324,101 @@ replacement line
             assert!(expanded.is_err());
         });
     }
+    #[test]
+    fn consensus_diff() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let now = rt.wallclock();
+            let day = Duration::from_secs(86400);
+            let (_tempdir, mgr) = new_mgr(rt);
+
+            let old_text = "line 1\nline 2\nline 3\n";
+            let new_text = "line 1\nreplacement line\nline 3\nline 4\n";
+            let old_meta = ConsensusMeta::new(
+                Lifetime::new(now, now + day, now + 2 * day).unwrap(),
+                [0x11; 32],
+                [0x11; 32],
+            );
+            let new_meta = ConsensusMeta::new(
+                Lifetime::new(now + day, now + 2 * day, now + 3 * day).unwrap(),
+                [0x22; 32],
+                [0x22; 32],
+            );
+            assert!(mgr.latest_consensus_digest().unwrap().is_none());
+            {
+                let mut store = mgr.store.lock().unwrap();
+                store
+                    .store_consensus(&old_meta, ConsensusFlavor::Microdesc, false, old_text)
+                    .unwrap();
+                store
+                    .store_consensus(&new_meta, ConsensusFlavor::Microdesc, false, new_text)
+                    .unwrap();
+            }
+            assert_eq!(mgr.latest_consensus_digest().unwrap(), Some([0x22; 32]));
+
+            // We can't make a diff from a consensus we don't have.
+            assert!(mgr
+                .consensus_diff(&[0x33; 32], &[0x22; 32])
+                .unwrap()
+                .is_none());
+
+            let diff = mgr
+                .consensus_diff(&[0x11; 32], &[0x22; 32])
+                .unwrap()
+                .unwrap();
+            let applied = tor_consdiff::apply_diff(old_text, &diff, Some([0x11; 32])).unwrap();
+            assert_eq!(applied.to_string(), new_text);
+
+            // The diff should have been stored for next time.
+            let stored = mgr
+                .store
+                .lock()
+                .unwrap()
+                .consensus_diff(&[0x11; 32], &[0x22; 32])
+                .unwrap();
+            assert_eq!(stored, Some(diff));
+        });
+    }
+
+    #[test]
+    fn bandwidth_file() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use tor_error::{ErrorKind, HasKind as _};
+            use tor_llcrypto::pk::rsa::RsaIdentity;
+
+            let (_tempdir, mgr) = new_mgr(rt);
+            let text = "1523911758\n\
+                        node_id=$0101010101010101010101010101010101010101 bw=760\n";
+            let measured = |netdir: &NetDir| {
+                netdir
+                    .by_id(&RsaIdentity::from([1; 20]))
+                    .unwrap()
+                    .measured_bandwidth()
+            };
+            let new_netdir = || {
+                tor_netdir::testnet::construct_netdir()
+                    .unwrap_if_sufficient()
+                    .unwrap()
+            };
+
+            let err = mgr.set_bandwidth_file("not a bandwidth file").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::BadApiUsage);
+
+            // With no directory, we just store the file...
+            assert_eq!(mgr.set_bandwidth_file(text).unwrap(), 0);
+            assert_eq!(
+                mgr.store.lock().unwrap().latest_bandwidth_file().unwrap(),
+                Some(text.to_string())
+            );
+            // ... so that we can use it for the first directory we build.
+            let mut netdir = new_netdir();
+            load_bandwidth_file(&**mgr.store.lock().unwrap(), &mut netdir);
+            assert_eq!(measured(&netdir), Some(760));
+
+            // With a directory, we use the file right away.
+            mgr.netdir.replace(new_netdir());
+            assert_eq!(measured(&mgr.netdir.get().unwrap()), None);
+            assert_eq!(mgr.set_bandwidth_file(text).unwrap(), 1);
+            assert_eq!(measured(&mgr.netdir.get().unwrap()), Some(760));
+        });
+    }
 }
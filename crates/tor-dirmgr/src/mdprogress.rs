@@ -0,0 +1,216 @@
+//! Remembering how far we got with downloading microdescriptors.
+//!
+//! We split the microdescriptors that a consensus needs into batches, and ask
+//! a directory cache for each batch.  If we're restarted partway through, the
+//! microdescriptors that we already downloaded are still in the cache, but we
+//! used to forget which batches we had asked for and which of them had
+//! failed: we would split the rest up differently, and send requests that
+//! overlapped with the ones we had already made.
+//!
+//! Now we save an [`MdDownloadProgress`] in the store whenever we send a round
+//! of requests, and when we learn how they went.  When we find one for the
+//! same consensus, we resume from its batches.
+
+use std::collections::HashSet;
+
+use tor_netdoc::doc::microdesc::MdDigest;
+
+use crate::docid::{ClientRequest, DocQuery};
+
+/// A set of microdescriptors that we request together.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct MdBatch {
+    /// The digests of the microdescriptors in this batch, in ascending order.
+    digests: Vec<MdDigest>,
+    /// The number of times that a request for this batch has failed.
+    n_failures: u32,
+}
+
+impl MdBatch {
+    /// Construct a new `MdBatch` for `digests`, which has failed `n_failures`
+    /// times.
+    pub(crate) fn new(mut digests: Vec<MdDigest>, n_failures: u32) -> Self {
+        digests.sort_unstable();
+        MdBatch {
+            digests,
+            n_failures,
+        }
+    }
+
+    /// Return the digests of the microdescriptors in this batch.
+    pub(crate) fn digests(&self) -> &[MdDigest] {
+        &self.digests[..]
+    }
+
+    /// Return the number of times that a request for this batch has failed.
+    pub(crate) fn n_failures(&self) -> u32 {
+        self.n_failures
+    }
+}
+
+/// The batches in which we are downloading the microdescriptors for a single
+/// consensus.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct MdDownloadProgress {
+    /// The SHA3-256 digest of the signed part of the consensus.
+    consensus: [u8; 32],
+    /// The batches that we're requesting, in the order that we request them.
+    batches: Vec<MdBatch>,
+}
+
+impl MdDownloadProgress {
+    /// Construct a new `MdDownloadProgress` for the consensus whose signed
+    /// part has the SHA3-256 digest `consensus`.
+    pub(crate) fn new(consensus: [u8; 32], batches: Vec<MdBatch>) -> Self {
+        MdDownloadProgress { consensus, batches }
+    }
+
+    /// Decide how to batch the `missing` microdescriptors for the consensus
+    /// `consensus`, resuming from `saved` if it describes the same consensus.
+    ///
+    /// We keep every saved batch that still has missing microdescriptors,
+    /// with the ones that we already have removed; batches that have failed
+    /// more often go after the others, so that they don't hold them up.  We
+    /// put any microdescriptors that no saved batch covers into new batches
+    /// at the end, in the order that `missing` lists them.
+    pub(crate) fn plan(
+        consensus: [u8; 32],
+        saved: Option<MdDownloadProgress>,
+        missing: &[MdDigest],
+    ) -> Self {
+        let wanted: HashSet<&MdDigest> = missing.iter().collect();
+        let mut covered = HashSet::new();
+        let mut batches = Vec::new();
+        if let Some(saved) = saved.filter(|saved| saved.consensus == consensus) {
+            for mut batch in saved.batches {
+                batch
+                    .digests
+                    .retain(|d| wanted.contains(d) && covered.insert(*d));
+                if !batch.digests.is_empty() {
+                    batches.push(batch);
+                }
+            }
+            // (This sort is stable, so we keep the original order otherwise.)
+            batches.sort_by_key(MdBatch::n_failures);
+        }
+
+        let uncovered = missing
+            .iter()
+            .filter(|d| !covered.contains(*d))
+            .copied()
+            .collect();
+        batches.extend(
+            DocQuery::Microdesc(uncovered)
+                .split_for_download()
+                .into_iter()
+                .filter_map(|query| match query {
+                    DocQuery::Microdesc(digests) => Some(MdBatch::new(digests, 0)),
+                    _ => None,
+                }),
+        );
+
+        MdDownloadProgress { consensus, batches }
+    }
+
+    /// Return the SHA3-256 digest of the signed part of the consensus whose
+    /// microdescriptors we're downloading.
+    pub(crate) fn consensus(&self) -> &[u8; 32] {
+        &self.consensus
+    }
+
+    /// Return our batches, in the order that we request them.
+    pub(crate) fn batches(&self) -> &[MdBatch] {
+        &self.batches[..]
+    }
+
+    /// Return a request for each of our batches, in order.
+    pub(crate) fn requests(&self) -> Vec<ClientRequest> {
+        self.batches
+            .iter()
+            .map(|batch| ClientRequest::Microdescs(batch.digests.iter().copied().collect()))
+            .collect()
+    }
+
+    /// Note that a request for the batch at position `idx` has failed.
+    ///
+    /// Does nothing if there is no such batch.
+    pub(crate) fn note_failure(&mut self, idx: usize) {
+        if let Some(batch) = self.batches.get_mut(idx) {
+            batch.n_failures = batch.n_failures.saturating_add(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    /// Return `n` distinct digests, starting at `start`.
+    fn digests(start: u16, n: u16) -> Vec<MdDigest> {
+        (start..start + n)
+            .map(|i| {
+                let mut d = [0; 32];
+                d[..2].copy_from_slice(&i.to_be_bytes());
+                d
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fresh_plan() {
+        let missing = digests(0, 1200);
+        let progress = MdDownloadProgress::plan([1; 32], None, &missing);
+        assert_eq!(progress.consensus(), &[1; 32]);
+        let sizes: Vec<_> = progress
+            .batches()
+            .iter()
+            .map(|b| b.digests().len())
+            .collect();
+        assert_eq!(sizes, vec![500, 500, 200]);
+        assert_eq!(progress.requests().len(), 3);
+    }
+
+    #[test]
+    fn resume() {
+        let all = digests(0, 1200);
+        let mut saved = MdDownloadProgress::plan([1; 32], None, &all);
+        saved.note_failure(0);
+        saved.note_failure(7); // no such batch; ignored.
+
+        // We got the whole second batch, and half of the third.
+        let mut missing = all[..500].to_vec();
+        missing.extend_from_slice(&all[1100..]);
+        // ... and we've somehow lost one from the second batch.
+        missing.push(all[600]);
+        // This one is new.
+        let extra = digests(5000, 1);
+        missing.extend_from_slice(&extra);
+
+        let progress = MdDownloadProgress::plan([1; 32], Some(saved.clone()), &missing);
+        let batches = progress.batches();
+        assert_eq!(batches.len(), 4);
+        // The first batch goes after the others, since it failed.
+        assert_eq!(batches[0], MdBatch::new(vec![all[600]], 0));
+        assert_eq!(batches[1], MdBatch::new(all[1100..].to_vec(), 0));
+        assert_eq!(batches[2], MdBatch::new(all[..500].to_vec(), 1));
+        assert_eq!(batches[3], MdBatch::new(extra, 0));
+
+        // If the consensus is different, we start over.
+        let progress = MdDownloadProgress::plan([2; 32], Some(saved), &missing);
+        assert_eq!(progress.batches().len(), 2);
+        assert!(progress.batches().iter().all(|b| b.n_failures() == 0));
+    }
+}
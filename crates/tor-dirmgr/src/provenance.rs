@@ -0,0 +1,136 @@
+//! Records of where the documents in a network directory came from.
+
+use crate::DocSource;
+
+/// A record of which sources contributed the documents in a
+/// [`NetDir`](tor_netdir::NetDir).
+///
+/// This is meant for debugging, and for analyzing whether a client's view of
+/// the network was (for example) provided entirely by a single directory cache.
+///
+/// Returned by [`DirMgr::netdir_provenance`](crate::DirMgr::netdir_provenance).
+#[derive(Clone, Debug)]
+pub struct NetDirProvenance {
+    /// Where we got the consensus.
+    consensus_source: DocSource,
+    /// The number of microdescriptors that we took from the previous directory
+    /// when we first built this one.
+    n_from_previous: usize,
+    /// The sources that contributed microdescriptors, with the number of
+    /// microdescriptors that each one contributed.
+    ///
+    /// Each source appears here at most once.
+    microdesc_sources: Vec<(DocSource, usize)>,
+}
+
+impl NetDirProvenance {
+    /// Construct a new `NetDirProvenance` for a directory whose consensus came
+    /// from `consensus_source`, and which has no microdescriptors yet.
+    pub(crate) fn new(consensus_source: DocSource) -> Self {
+        NetDirProvenance {
+            consensus_source,
+            n_from_previous: 0,
+            microdesc_sources: Vec::new(),
+        }
+    }
+
+    /// Record that we took `n` microdescriptors from a previous directory.
+    pub(crate) fn note_from_previous(&mut self, n: usize) {
+        self.n_from_previous += n;
+    }
+
+    /// Record that `source` contributed `n` microdescriptors.
+    pub(crate) fn note_microdescs(&mut self, source: &DocSource, n: usize) {
+        if n == 0 {
+            return;
+        }
+        match self
+            .microdesc_sources
+            .iter_mut()
+            .find(|(s, _)| same_source(s, source))
+        {
+            Some((_, count)) => *count += n,
+            None => self.microdesc_sources.push((source.clone(), n)),
+        }
+    }
+
+    /// Return the source from which we got this directory's consensus.
+    pub fn consensus_source(&self) -> &DocSource {
+        &self.consensus_source
+    }
+
+    /// Return the number of microdescriptors that we reused from the directory
+    /// that this one replaced.
+    ///
+    /// (We do not track where those microdescriptors originally came from.)
+    pub fn n_microdescs_from_previous(&self) -> usize {
+        self.n_from_previous
+    }
+
+    /// Return an iterator over every source that has contributed
+    /// microdescriptors to this directory, along with the number of
+    /// microdescriptors it contributed.
+    ///
+    /// Sources are listed in the order in which they first contributed.
+    /// Downloads on different circuits count as different sources, even if
+    /// they were made to the same directory cache.
+    pub fn microdesc_sources(&self) -> impl Iterator<Item = (&DocSource, usize)> + '_ {
+        self.microdesc_sources.iter().map(|(s, n)| (s, *n))
+    }
+}
+
+/// Return true if `a` and `b` describe the same source.
+///
+/// Two downloads are from the same source if they used the same circuit.
+fn same_source(a: &DocSource, b: &DocSource) -> bool {
+    match (a, b) {
+        (DocSource::LocalCache, DocSource::LocalCache) => true,
+        (DocSource::StaticBundle, DocSource::StaticBundle) => true,
+        (DocSource::Caller, DocSource::Caller) => true,
+        (DocSource::DirServer { source: a }, DocSource::DirServer { source: b }) => match (a, b) {
+            (None, None) => true,
+            (Some(a), Some(b)) => a.unique_circ_id() == b.unique_circ_id(),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn merge_sources() {
+        let server = DocSource::DirServer { source: None };
+        let mut p = NetDirProvenance::new(server.clone());
+        p.note_from_previous(7);
+        p.note_microdescs(&DocSource::LocalCache, 10);
+        p.note_microdescs(&server, 3);
+        p.note_microdescs(&DocSource::LocalCache, 0);
+        p.note_microdescs(&server, 4);
+
+        assert!(matches!(
+            p.consensus_source(),
+            DocSource::DirServer { source: None }
+        ));
+        assert_eq!(p.n_microdescs_from_previous(), 7);
+        let sources: Vec<_> = p.microdesc_sources().collect();
+        assert_eq!(sources.len(), 2);
+        assert!(matches!(sources[0], (DocSource::LocalCache, 10)));
+        assert!(matches!(sources[1], (DocSource::DirServer { .. }, 7)));
+    }
+}
@@ -0,0 +1,579 @@
+//! Fetching directory documents through an ordinary proxy, as a last resort.
+//!
+//! Some users are on networks (captive portals, corporate firewalls) where
+//! the only way out is through a configured HTTP or SOCKS proxy, and where we
+//! can't reach any directory cache to bootstrap.  For them, a
+//! [`ProxiedMirrorFetcher`] fetches documents from an HTTPS mirror through
+//! such a proxy, and a [`FallbackFetcher`] makes us switch to it once our
+//! usual downloads have failed a few times.
+//!
+//! # Privacy
+//!
+//! This is much less private than fetching over Tor.  The proxy learns that
+//! we are fetching a Tor directory, and from which mirror; the mirror learns
+//! our proxy's address.  Nobody can make us accept a directory that our
+//! authorities didn't sign, since we check every document as usual, but
+//! anybody who can watch the proxy can tell that we are starting Tor.  Only
+//! enable this for users who cannot otherwise bootstrap.
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use derive_builder::Builder;
+use futures::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use serde::{Deserialize, Serialize};
+use tor_config::{impl_standard_builder, ConfigBuildError};
+use tor_dirclient::request::WithHostHeader;
+use tor_dirclient::DirResponse;
+use tor_rtcompat::{tls::TlsConnector as _, Runtime};
+use tor_socksproto::{
+    Handshake as _, SocksAddr, SocksAuth, SocksClientHandshake, SocksCmd, SocksRequest,
+    SocksStatus, SocksVersion,
+};
+
+use crate::docid::ClientRequest;
+use crate::{DocumentFetcher, Error, FetchAction, Result};
+
+/// The most bytes that we'll accept in an HTTP proxy's reply headers.
+const MAX_PROXY_REPLY_LEN: usize = 8192;
+
+/// The protocol that a proxy speaks.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ProxyProtocol {
+    /// An HTTP proxy that supports the `CONNECT` method.
+    HttpConnect,
+    /// A SOCKS5 proxy that needs no authentication.
+    Socks5,
+}
+
+/// A [`DocumentFetcher`] that fetches documents from an HTTPS mirror through
+/// an HTTP or SOCKS proxy.
+///
+/// As with [`HttpsMirrorFetcher`](crate::HttpsMirrorFetcher), the mirror must
+/// answer requests at the same paths as a directory cache, and we check its
+/// TLS certificate against its hostname, so that the proxy can't answer in
+/// its place.  We ask the proxy to connect to the mirror by hostname, so we
+/// never need to resolve it ourselves.  We make a new connection for each
+/// request.
+///
+/// See the [module documentation](self) for why this is less private than
+/// fetching over Tor.
+#[derive(Clone, Debug)]
+pub struct ProxiedMirrorFetcher<R: Runtime> {
+    /// The runtime to use for making connections.
+    runtime: R,
+    /// The address of the proxy.
+    proxy: SocketAddr,
+    /// The protocol that the proxy speaks.
+    protocol: ProxyProtocol,
+    /// The hostname of the mirror.
+    hostname: String,
+    /// The port on which the mirror answers HTTPS.
+    port: u16,
+}
+
+impl<R: Runtime> ProxiedMirrorFetcher<R> {
+    /// Construct a new `ProxiedMirrorFetcher` to fetch documents from the
+    /// mirror at `hostname` and `port`, through the proxy at `proxy`, which
+    /// speaks `protocol`.
+    pub fn new(
+        runtime: R,
+        proxy: SocketAddr,
+        protocol: ProxyProtocol,
+        hostname: impl Into<String>,
+        port: u16,
+    ) -> Self {
+        ProxiedMirrorFetcher {
+            runtime,
+            proxy,
+            protocol,
+            hostname: hostname.into(),
+            port,
+        }
+    }
+
+    /// Return an error for a failure to reach our mirror while doing
+    /// `action`.
+    fn error(&self, action: FetchAction, error: std::io::Error) -> Error {
+        Error::DocumentFetch {
+            from: format!(
+                "mirror {}:{} via proxy at {}",
+                self.hostname, self.port, self.proxy
+            ),
+            action,
+            cause: Arc::new(error),
+        }
+    }
+}
+
+#[async_trait]
+impl<R: Runtime> DocumentFetcher for ProxiedMirrorFetcher<R> {
+    async fn fetch(&self, request: &ClientRequest) -> Result<DirResponse> {
+        let mut stream = self
+            .runtime
+            .connect(&self.proxy)
+            .await
+            .map_err(|e| self.error(FetchAction::Connecting, e))?;
+        let handshake = match self.protocol {
+            ProxyProtocol::HttpConnect => {
+                http_connect(&mut stream, &self.hostname, self.port).await
+            }
+            ProxyProtocol::Socks5 => socks5_connect(&mut stream, &self.hostname, self.port).await,
+        };
+        handshake.map_err(|e| self.error(FetchAction::NegotiatingWithProxy, e))?;
+        let mut stream = self
+            .runtime
+            .tls_connector()
+            .negotiate_validated(stream, &self.hostname)
+            .await
+            .map_err(|e| self.error(FetchAction::NegotiatingTls, e))?;
+        let request = WithHostHeader::new(request.as_requestable(), &self.hostname);
+        Ok(tor_dirclient::send_request(&self.runtime, &request, &mut stream, None).await?)
+    }
+}
+
+/// Ask the HTTP proxy on `stream` to connect us to `hostname` and `port`.
+///
+/// We read the proxy's reply one byte at a time, so that we never consume
+/// anything that the target sends after it.
+async fn http_connect<S>(stream: &mut S, hostname: &str, port: u16) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    check_hostname(hostname)?;
+    let target = if hostname.parse::<Ipv6Addr>().is_ok() {
+        format!("[{}]:{}", hostname, port)
+    } else {
+        format!("{}:{}", hostname, port)
+    };
+    let request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    let mut reply = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !reply.ends_with(b"\r\n\r\n") {
+        if reply.len() >= MAX_PROXY_REPLY_LEN {
+            return Err(std::io::Error::other("proxy reply was too long"));
+        }
+        if stream.read(&mut byte).await? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        reply.push(byte[0]);
+    }
+
+    // We only need the status code, from a line like "HTTP/1.1 200 OK".
+    let status_line = String::from_utf8_lossy(&reply);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    let mut words = status_line.split_whitespace();
+    let status = match (words.next(), words.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/") => status,
+        _ => {
+            return Err(std::io::Error::other(format!(
+                "malformed reply from proxy: {:?}",
+                status_line
+            )))
+        }
+    };
+    if !status.starts_with('2') {
+        return Err(std::io::Error::other(format!(
+            "proxy refused to connect: {:?}",
+            status_line
+        )));
+    }
+    Ok(())
+}
+
+/// Ask the SOCKS5 proxy on `stream` to connect us to `hostname` and `port`.
+async fn socks5_connect<S>(stream: &mut S, hostname: &str, port: u16) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    check_hostname(hostname)?;
+    let addr = match hostname.parse::<IpAddr>() {
+        Ok(ip) => SocksAddr::Ip(ip),
+        Err(_) => SocksAddr::Hostname(
+            hostname
+                .to_owned()
+                .try_into()
+                .map_err(std::io::Error::other)?,
+        ),
+    };
+    let request = SocksRequest::new(
+        SocksVersion::V5,
+        SocksCmd::CONNECT,
+        addr,
+        port,
+        SocksAuth::NoAuth,
+    )
+    .map_err(std::io::Error::other)?;
+    let mut handshake = SocksClientHandshake::new(request);
+    let mut buf = tor_socksproto::Buffer::new();
+    let reply = loop {
+        use tor_socksproto::NextStep as NS;
+        match handshake.step(&mut buf).map_err(std::io::Error::other)? {
+            NS::Send(send) => {
+                stream.write_all(&send).await?;
+                stream.flush().await?;
+            }
+            NS::Finished(fin) => {
+                break fin
+                    .into_output_forbid_pipelining()
+                    .map_err(std::io::Error::other)?
+            }
+            NS::Recv(mut recv) => {
+                let n = stream.read(recv.buf()).await?;
+                recv.note_received(n).map_err(std::io::Error::other)?;
+            }
+        }
+    };
+    if reply.status() != SocksStatus::SUCCEEDED {
+        return Err(std::io::Error::other(format!(
+            "proxy refused to connect: {}",
+            reply.status()
+        )));
+    }
+    Ok(())
+}
+
+/// Return an error if `hostname` can't safely be sent to a proxy.
+fn check_hostname(hostname: &str) -> std::io::Result<()> {
+    if !is_valid_hostname(hostname) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid mirror hostname: {:?}", hostname),
+        ));
+    }
+    Ok(())
+}
+
+/// Return true if `hostname` is one that we're willing to send to a proxy.
+///
+/// An empty hostname is never right, and one with whitespace or control
+/// characters (such as CR or LF) could let it inject headers into our
+/// `CONNECT` request.
+fn is_valid_hostname(hostname: &str) -> bool {
+    !hostname.is_empty()
+        && !hostname
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// A [`DocumentFetcher`] to use when we keep failing to bootstrap in the
+/// usual way.
+///
+/// Install one with
+/// [`DirMgrExtensions::fallback_fetcher`](crate::config::DirMgrExtensions::fallback_fetcher).
+/// Until we have a directory, once `after_failed_attempts` download attempts
+/// in a row have failed, we send all of our requests through `fetcher`
+/// instead of over Tor.  Once we have a directory, we go back to fetching
+/// over Tor.
+///
+/// Usually `fetcher` is a [`ProxiedMirrorFetcher`]; see the [module
+/// documentation](self) for why that is less private.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct FallbackFetcher {
+    /// The fetcher to fall back to.
+    pub fetcher: Arc<dyn DocumentFetcher>,
+    /// The number of download attempts that must fail before we fall back.
+    pub after_failed_attempts: usize,
+}
+
+impl FallbackFetcher {
+    /// Return a new `FallbackFetcher` that falls back to `fetcher` after two
+    /// failed download attempts.
+    pub fn new(fetcher: Arc<dyn DocumentFetcher>) -> Self {
+        FallbackFetcher {
+            fetcher,
+            after_failed_attempts: 2,
+        }
+    }
+}
+
+/// Configuration for fetching directory documents through a proxy when
+/// we can't bootstrap in the usual way.
+///
+/// This is off unless both `proxy` and `mirror` are set.  See the [module
+/// documentation](self) for why it is less private than fetching over Tor.
+#[derive(Debug, Clone, Builder, Eq, PartialEq)]
+#[builder(build_fn(validate = "Self::validate", error = "ConfigBuildError"))]
+#[builder(derive(Debug, Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct ProxyFallbackConfig {
+    /// The address of the proxy to fetch documents through.
+    #[builder(default, setter(strip_option))]
+    #[builder_field_attr(serde(default))]
+    pub(crate) proxy: Option<SocketAddr>,
+
+    /// The protocol that `proxy` speaks.
+    ///
+    /// Defaults to `http_connect`.
+    #[builder(default = "ProxyProtocol::HttpConnect")]
+    #[builder_field_attr(serde(default))]
+    pub(crate) protocol: ProxyProtocol,
+
+    /// The hostname of the HTTPS mirror to fetch documents from.
+    #[builder(default, setter(strip_option, into))]
+    #[builder_field_attr(serde(default))]
+    pub(crate) mirror: Option<String>,
+
+    /// The port on which `mirror` answers HTTPS.
+    ///
+    /// Defaults to 443.
+    #[builder(default = "443")]
+    #[builder_field_attr(serde(default))]
+    pub(crate) mirror_port: u16,
+
+    /// The number of download attempts that must fail before we fetch
+    /// through the proxy.
+    ///
+    /// Defaults to 2.
+    #[builder(default = "2")]
+    #[builder_field_attr(serde(default))]
+    pub(crate) after_failed_attempts: usize,
+}
+
+impl_standard_builder! { ProxyFallbackConfig }
+
+impl ProxyFallbackConfigBuilder {
+    /// Check that this builder will give a reasonable configuration.
+    fn validate(&self) -> std::result::Result<(), ConfigBuildError> {
+        let proxy = self.proxy.flatten();
+        let mirror = self.mirror.as_ref().and_then(Option::as_deref);
+        if proxy.is_some() != mirror.is_some() {
+            return Err(ConfigBuildError::Inconsistent {
+                fields: vec!["proxy".to_owned(), "mirror".to_owned()],
+                problem: "Both or neither of these must be set".to_owned(),
+            });
+        }
+        if let Some(mirror) = mirror {
+            if !is_valid_hostname(mirror) {
+                return Err(ConfigBuildError::Invalid {
+                    field: "mirror".to_owned(),
+                    problem: format!("{:?} is not a valid hostname", mirror),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ProxyFallbackConfig {
+    /// Return a [`FallbackFetcher`] that fetches through our proxy using
+    /// `runtime`, or None if we aren't configured to use one.
+    pub fn fallback_fetcher<R: Runtime>(&self, runtime: R) -> Option<FallbackFetcher> {
+        let (Some(proxy), Some(mirror)) = (self.proxy, &self.mirror) else {
+            return None;
+        };
+        let fetcher =
+            ProxiedMirrorFetcher::new(runtime, proxy, self.protocol, mirror, self.mirror_port);
+        let mut fallback = FallbackFetcher::new(Arc::new(fetcher));
+        fallback.after_failed_attempts = self.after_failed_attempts;
+        Some(fallback)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use tor_rtmock::io::stream_pair;
+
+    /// Read from `conn`, one byte at a time, until what we've read ends with
+    /// `end`.
+    async fn read_until<S: AsyncRead + Unpin>(conn: &mut S, end: &[u8]) -> Vec<u8> {
+        let mut got = Vec::new();
+        let mut byte = [0_u8; 1];
+        while !got.ends_with(end) {
+            assert_eq!(conn.read(&mut byte).await.unwrap(), 1);
+            got.push(byte[0]);
+        }
+        got
+    }
+
+    #[test]
+    fn http_proxy() {
+        tor_rtcompat::test_with_one_runtime!(|_rt| async move {
+            let (mut client, mut proxy) = stream_pair();
+            let (outcome, connect) = futures::join!(
+                http_connect(&mut client, "mirror.example.com", 443),
+                async {
+                    let connect = read_until(&mut proxy, b"\r\n\r\n").await;
+                    proxy
+                        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhello")
+                        .await
+                        .unwrap();
+                    String::from_utf8(connect).unwrap()
+                }
+            );
+            outcome.unwrap();
+            assert!(connect.starts_with("CONNECT mirror.example.com:443 HTTP/1.1\r\n"));
+            // We didn't consume anything that came after the proxy's reply.
+            let mut hello = [0_u8; 5];
+            client.read_exact(&mut hello).await.unwrap();
+            assert_eq!(&hello, b"hello");
+
+            // A proxy that refuses gives an error.
+            let (mut client, mut proxy) = stream_pair();
+            let (outcome, ()) = futures::join!(
+                http_connect(&mut client, "mirror.example.com", 443),
+                async {
+                    let _ = read_until(&mut proxy, b"\r\n\r\n").await;
+                    proxy
+                        .write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")
+                        .await
+                        .unwrap();
+                }
+            );
+            assert!(outcome
+                .unwrap_err()
+                .to_string()
+                .contains("proxy refused to connect"));
+
+            // So does one that doesn't speak HTTP.
+            let (mut client, mut proxy) = stream_pair();
+            let (outcome, ()) = futures::join!(
+                http_connect(&mut client, "mirror.example.com", 443),
+                async {
+                    let _ = read_until(&mut proxy, b"\r\n\r\n").await;
+                    proxy.write_all(b"SSH-2.0-OpenSSH\r\n\r\n").await.unwrap();
+                }
+            );
+            assert!(outcome.unwrap_err().to_string().contains("malformed reply"));
+
+            // IPv6 literals get brackets.
+            let (mut client, mut proxy) = stream_pair();
+            let (outcome, connect) =
+                futures::join!(http_connect(&mut client, "2001:db8::1", 443), async {
+                    let connect = read_until(&mut proxy, b"\r\n\r\n").await;
+                    proxy
+                        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                        .await
+                        .unwrap();
+                    String::from_utf8(connect).unwrap()
+                });
+            outcome.unwrap();
+            assert!(connect.starts_with("CONNECT [2001:db8::1]:443 HTTP/1.1\r\n"));
+            assert!(connect.contains("\r\nHost: [2001:db8::1]:443\r\n"));
+        });
+    }
+
+    #[test]
+    fn bad_hostnames() {
+        tor_rtcompat::test_with_one_runtime!(|_rt| async move {
+            for hostname in ["", "example.com\r\nX-Evil: 1", "example.com\n", "a b"] {
+                // We refuse before sending anything, so the proxy never
+                // needs to answer.
+                let (mut client, _proxy) = stream_pair();
+                let err = http_connect(&mut client, hostname, 443).await.unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+                let err = socks5_connect(&mut client, hostname, 443)
+                    .await
+                    .unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            }
+        });
+    }
+
+    #[test]
+    fn config() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            // By default, we don't fall back.
+            let cfg = ProxyFallbackConfig::default();
+            assert!(cfg.fallback_fetcher(rt.clone()).is_none());
+
+            let cfg = ProxyFallbackConfig::builder()
+                .proxy("127.0.0.1:3128".parse().unwrap())
+                .protocol(ProxyProtocol::Socks5)
+                .mirror("mirror.example.com")
+                .after_failed_attempts(5)
+                .build()
+                .unwrap();
+            assert_eq!(cfg.protocol, ProxyProtocol::Socks5);
+            assert_eq!(cfg.mirror_port, 443);
+            let fallback = cfg.fallback_fetcher(rt.clone()).unwrap();
+            assert_eq!(fallback.after_failed_attempts, 5);
+
+            // We need both a proxy and a mirror.
+            let mut bld = ProxyFallbackConfig::builder();
+            bld.proxy("127.0.0.1:3128".parse().unwrap());
+            assert!(matches!(
+                bld.build(),
+                Err(ConfigBuildError::Inconsistent { .. })
+            ));
+
+            // And the mirror needs a hostname that we can send to the proxy.
+            bld.mirror("mirror.example.com\r\nX-Evil: 1");
+            assert!(matches!(bld.build(), Err(ConfigBuildError::Invalid { .. })));
+            bld.mirror("mirror.example.com");
+            assert!(bld.build().is_ok());
+        });
+    }
+
+    #[test]
+    fn socks_proxy() {
+        tor_rtcompat::test_with_one_runtime!(|_rt| async move {
+            let (mut client, mut proxy) = stream_pair();
+            let (outcome, connect) = futures::join!(
+                socks5_connect(&mut client, "mirror.example.com", 443),
+                async {
+                    // Version 5, one method: no authentication.
+                    let mut hello = [0_u8; 3];
+                    proxy.read_exact(&mut hello).await.unwrap();
+                    assert_eq!(hello, [5, 1, 0]);
+                    proxy.write_all(&[5, 0]).await.unwrap();
+                    // CONNECT to a hostname.
+                    let mut connect = vec![0_u8; 5 + "mirror.example.com".len() + 2];
+                    proxy.read_exact(&mut connect).await.unwrap();
+                    proxy
+                        .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                        .await
+                        .unwrap();
+                    connect
+                }
+            );
+            outcome.unwrap();
+            assert_eq!(&connect[..5], &[5, 1, 0, 3, 18]);
+            assert_eq!(&connect[5..23], b"mirror.example.com");
+            assert_eq!(&connect[23..], &443_u16.to_be_bytes());
+
+            // A proxy that refuses gives an error.
+            let (mut client, mut proxy) = stream_pair();
+            let (outcome, ()) = futures::join!(
+                socks5_connect(&mut client, "mirror.example.com", 443),
+                async {
+                    let mut hello = [0_u8; 3];
+                    proxy.read_exact(&mut hello).await.unwrap();
+                    proxy.write_all(&[5, 0]).await.unwrap();
+                    let mut connect = vec![0_u8; 5 + "mirror.example.com".len() + 2];
+                    proxy.read_exact(&mut connect).await.unwrap();
+                    // "Connection not allowed by ruleset"
+                    proxy
+                        .write_all(&[5, 2, 0, 1, 0, 0, 0, 0, 0, 0])
+                        .await
+                        .unwrap();
+                }
+            );
+            assert!(outcome
+                .unwrap_err()
+                .to_string()
+                .contains("proxy refused to connect"));
+        });
+    }
+}
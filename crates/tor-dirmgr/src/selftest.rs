@@ -0,0 +1,299 @@
+//! A self-test to check whether we can reach the directory at all.
+//!
+//! See [`DirMgr::connectivity_self_test`](crate::DirMgr::connectivity_self_test).
+
+use std::time::Duration;
+
+use tor_dirclient::request::ConsensusRequest;
+use tor_dirclient::{RequestError, RequestFailedError};
+use tor_rtcompat::{Runtime, SleepProviderExt as _};
+
+use crate::docid::ClientRequest;
+use crate::{bootstrap, DirMgr, Error, FetchAction};
+
+/// How long we wait for a self-test request before giving up.
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The outcome of a [`DirMgr::connectivity_self_test`].
+///
+/// The variants are in order of how far we got: when we made several
+/// attempts (for example, to several guards), we report the one that got
+/// furthest.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[non_exhaustive]
+pub enum SelfTestVerdict {
+    /// We couldn't tell where the request failed: for example, because we
+    /// have no way to download anything, or because the failure was beyond
+    /// our first hop.
+    Inconclusive,
+    /// The request didn't finish in time.
+    TimedOut,
+    /// We couldn't open a channel to the first hop of our circuit.  We can't
+    /// tell whether that was because the TCP connection or the handshake
+    /// failed.
+    BlockedAtFirstHop,
+    /// We couldn't open a TCP connection to any of the places we tried.
+    BlockedAtTcp,
+    /// We opened a TCP connection, but our TLS (or Tor link) handshake
+    /// failed.
+    BlockedAtTls,
+    /// We sent our request, but the answer was not a well-formed answer to
+    /// it.
+    BadResponse,
+    /// We got a well-formed answer to our request.
+    Ok,
+}
+
+/// A report from [`DirMgr::connectivity_self_test`].
+#[derive(Clone, Debug)]
+pub struct SelfTestReport {
+    /// What we concluded.
+    verdict: SelfTestVerdict,
+    /// The HTTP status code we got, if we got one.
+    status: Option<u16>,
+    /// How long the test took.
+    elapsed: Duration,
+    /// The error that made the test fail, if there was one.
+    error: Option<Error>,
+}
+
+impl SelfTestReport {
+    /// Return what we concluded.
+    pub fn verdict(&self) -> SelfTestVerdict {
+        self.verdict
+    }
+
+    /// Return the HTTP status code that we got in response to our request,
+    /// if we got one.
+    pub fn status(&self) -> Option<u16> {
+        self.status
+    }
+
+    /// Return how long the test took.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Return the error that made the test fail, if there was one.
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+}
+
+impl<R: Runtime> DirMgr<R> {
+    /// Check whether we can fetch anything from the directory, and report
+    /// where we fail if we can't.
+    ///
+    /// We send a single small request, in the same way that we send our
+    /// downloads: through a [`DocumentFetcher`](crate::DocumentFetcher) if
+    /// one is configured, or over a circuit from our circuit manager
+    /// otherwise.  We ask for a consensus only if it is newer than the current
+    /// time, so a working directory cache answers "304 Not Modified" without
+    /// sending anything.
+    ///
+    /// This has no effect on our directory, and doesn't count towards our
+    /// bootstrap status, so it is safe to call at any time: it's meant for
+    /// diagnostic tools.  (It may still cause the circuit manager to build a
+    /// circuit, or to stop using a circuit that fails.)
+    pub async fn connectivity_self_test(&self) -> SelfTestReport {
+        let config = self.config.get();
+        let mut request = ConsensusRequest::new(config.extensions.consensus_flavor);
+        for auth in config.authorities() {
+            request.push_authority_id(auth.v3ident);
+        }
+        request.set_last_consensus_date(self.runtime.wallclock());
+
+        let started = self.runtime.now();
+        let outcome = self
+            .runtime
+            .timeout(
+                SELF_TEST_TIMEOUT,
+                bootstrap::probe(self, ClientRequest::Consensus(request)),
+            )
+            .await;
+        let elapsed = self.runtime.now().saturating_duration_since(started);
+
+        let (verdict, status, error) = match outcome {
+            Err(_) => (SelfTestVerdict::TimedOut, None, None),
+            Ok(Err(e)) => (verdict_for_error(&e), None, Some(e)),
+            Ok(Ok(response)) => {
+                let status = response.status_code();
+                let verdict = match (status, response.error()) {
+                    (200 | 304, None) => SelfTestVerdict::Ok,
+                    _ => SelfTestVerdict::BadResponse,
+                };
+                (verdict, Some(status), None)
+            }
+        };
+        SelfTestReport {
+            verdict,
+            status,
+            elapsed,
+            error,
+        }
+    }
+}
+
+/// Return the verdict for a self-test that failed with `err`.
+pub(crate) fn verdict_for_error(err: &Error) -> SelfTestVerdict {
+    match err {
+        Error::DirClientError(e) => verdict_for_dirclient_error(e),
+        Error::DocumentFetch { action, .. } => match action {
+            FetchAction::Connecting => SelfTestVerdict::BlockedAtTcp,
+            FetchAction::NegotiatingTls => SelfTestVerdict::BlockedAtTls,
+            _ => SelfTestVerdict::Inconclusive,
+        },
+        _ => SelfTestVerdict::Inconclusive,
+    }
+}
+
+/// Return the verdict for a self-test that failed with `err`.
+fn verdict_for_dirclient_error(err: &tor_dirclient::Error) -> SelfTestVerdict {
+    use RequestError as RE;
+    match err {
+        tor_dirclient::Error::CircMgr(e) => verdict_for_circmgr_error(e),
+        tor_dirclient::Error::RequestFailed(RequestFailedError { error, .. }) => match error {
+            RE::TruncatedHeaders
+            | RE::HttparseError(_)
+            | RE::HttpError(_)
+            | RE::HttpStatus(..)
+            | RE::ContentEncoding(_)
+            | RE::ResponseTooLong(_)
+            | RE::Utf8Encoding(_) => SelfTestVerdict::BadResponse,
+            RE::DirTimeout => SelfTestVerdict::TimedOut,
+            _ => SelfTestVerdict::Inconclusive,
+        },
+        _ => SelfTestVerdict::Inconclusive,
+    }
+}
+
+/// Return the verdict for a self-test that failed with `err`.
+fn verdict_for_circmgr_error(err: &tor_circmgr::Error) -> SelfTestVerdict {
+    match err {
+        tor_circmgr::Error::Channel { .. } => SelfTestVerdict::BlockedAtFirstHop,
+        // Report the attempt that got furthest.
+        tor_circmgr::Error::RequestFailed(errs) => errs
+            .sources()
+            .map(|e| verdict_for_circmgr_error(e))
+            .max()
+            .unwrap_or(SelfTestVerdict::Inconclusive),
+        _ => SelfTestVerdict::Inconclusive,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::fetcher::test::answer_request;
+    use crate::test::new_mgr;
+    use crate::HttpsMirrorFetcher;
+    use futures::StreamExt as _;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tor_rtcompat::NetStreamListener as _;
+    use tor_rtmock::net::MockNetwork;
+
+    #[test]
+    fn verdicts() {
+        let fetch_err = |action| Error::DocumentFetch {
+            from: "somewhere".into(),
+            action,
+            cause: Arc::new(std::io::ErrorKind::ConnectionReset.into()),
+        };
+        assert_eq!(
+            verdict_for_error(&fetch_err(FetchAction::NegotiatingTls)),
+            SelfTestVerdict::BlockedAtTls
+        );
+        assert_eq!(
+            verdict_for_error(&fetch_err(FetchAction::Connecting)),
+            SelfTestVerdict::BlockedAtTcp
+        );
+        assert_eq!(
+            verdict_for_error(&fetch_err(FetchAction::NegotiatingWithProxy)),
+            SelfTestVerdict::Inconclusive
+        );
+        assert_eq!(
+            verdict_for_error(&Error::NoDownloadSupport),
+            SelfTestVerdict::Inconclusive
+        );
+    }
+
+    #[test]
+    fn self_test() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            let network = MockNetwork::new();
+            let mirror_addr: SocketAddr = "192.0.2.80:443".parse().unwrap();
+            let client_rt = network
+                .builder()
+                .add_address("192.0.2.17".parse().unwrap())
+                .runtime(rt.clone());
+            let mirror_rt = network
+                .builder()
+                .add_address(mirror_addr.ip())
+                .runtime(rt.clone());
+
+            let (_tempdir, mgr) = new_mgr(client_rt.clone());
+
+            // With no circuit manager or fetcher, we can't tell anything.
+            let report = mgr.connectivity_self_test().await;
+            assert_eq!(report.verdict(), SelfTestVerdict::Inconclusive);
+            assert!(matches!(report.error(), Some(Error::NoDownloadSupport)));
+
+            let mut config = (*mgr.config.get()).clone();
+            config.extensions.fetcher = Some(Arc::new(HttpsMirrorFetcher::new(
+                client_rt,
+                mirror_addr,
+                "mirror.example.com",
+            )));
+            mgr.config.replace(config);
+
+            // Nobody is listening yet.
+            let report = mgr.connectivity_self_test().await;
+            assert_eq!(report.verdict(), SelfTestVerdict::BlockedAtTcp);
+            assert_eq!(report.status(), None);
+
+            let listener = mirror_rt
+                .mock_net()
+                .listen_tls(&mirror_addr, b"mirror.example.com".to_vec())
+                .unwrap();
+            let mut incoming = listener.incoming();
+
+            let (report, request) = futures::join!(mgr.connectivity_self_test(), async {
+                let (mut conn, _) = incoming.next().await.unwrap().unwrap();
+                answer_request(&mut conn, b"HTTP/1.0 304 Not modified\r\n\r\n").await
+            });
+            assert!(request.starts_with("GET /tor/status-vote/current/consensus-microdesc/"));
+            assert!(request.contains("if-modified-since: "));
+            assert_eq!(report.verdict(), SelfTestVerdict::Ok);
+            assert_eq!(report.status(), Some(304));
+            assert!(report.error().is_none());
+
+            let (report, _) = futures::join!(mgr.connectivity_self_test(), async {
+                let (mut conn, _) = incoming.next().await.unwrap().unwrap();
+                answer_request(&mut conn, b"HTTP/1.0 503 Busy\r\n\r\n").await
+            });
+            assert_eq!(report.verdict(), SelfTestVerdict::BadResponse);
+            assert_eq!(report.status(), Some(503));
+
+            let (report, _) = futures::join!(mgr.connectivity_self_test(), async {
+                let (mut conn, _) = incoming.next().await.unwrap().unwrap();
+                answer_request(&mut conn, b"this is not http\r\n\r\n").await
+            });
+            assert_eq!(report.verdict(), SelfTestVerdict::BadResponse);
+            assert!(report.error().is_some());
+        });
+    }
+}
@@ -43,7 +43,6 @@ impl<T> SharedMutArc<T> {
     }
 
     /// Remove the current value of this SharedMutArc.
-    #[allow(unused)]
     pub(crate) fn clear(&self) {
         let mut w = self
             .dir
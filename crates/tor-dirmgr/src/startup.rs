@@ -0,0 +1,253 @@
+//! Deciding whether to load a cached consensus when we start up.
+//!
+//! Ordinarily, when we bootstrap, we first try to build a directory from
+//! whatever we have in our cache, and only then start downloading.  That's
+//! what we want after a short absence.  But after a long one (say, a laptop
+//! that has been suspended for a week) the cached consensus is far too old
+//! to use: we would spend time reading, parsing, and validating it, only to
+//! throw it away and go to the network anyway.
+//!
+//! The policy in this module lets us look at the cached consensus's metadata
+//! first, and skip straight to downloading when the consensus is too old to
+//! be worth loading.  This is separate from [`DirTolerance`], which controls
+//! how long we keep _using_ a consensus that we already have.
+
+use std::fmt::{self, Display};
+use std::time::{Duration, SystemTime};
+
+use tor_netdoc::doc::netstatus::Lifetime;
+
+use crate::DirTolerance;
+
+/// Configuration for how we use our cache when we first bootstrap.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct StartupCacheConfig {
+    /// The oldest cached consensus that we will try to load at startup,
+    /// measured from the start of its validity period.
+    ///
+    /// If our latest cached consensus is older than this, we don't load it,
+    /// and download a new one instead.  (We still use any certificates and
+    /// microdescriptors from our cache.)
+    ///
+    /// If this is `None` (the default), we only skip cached consensuses that
+    /// are too old for our [`DirTolerance`] to accept at all.
+    pub max_consensus_age: Option<Duration>,
+}
+
+/// The decision that we made, when we bootstrapped, about whether to load a
+/// consensus from our cache.
+///
+/// Returned by
+/// [`DirMgr::startup_cache_decision`](crate::DirMgr::startup_cache_decision).
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum StartupCacheDecision {
+    /// There was no consensus of the flavor we wanted in our cache.
+    NoCachedConsensus,
+    /// We couldn't read the metadata for our cached consensus, so we tried
+    /// to load it anyway.
+    MetadataUnavailable,
+    /// Our cached consensus was recent enough, so we tried to load it.
+    Load {
+        /// The start of the cached consensus's validity period.
+        valid_after: SystemTime,
+        /// How old the cached consensus was.
+        age: Duration,
+    },
+    /// Our cached consensus was older than
+    /// [`StartupCacheConfig::max_consensus_age`], so we didn't load it.
+    SkipTooOld {
+        /// The start of the cached consensus's validity period.
+        valid_after: SystemTime,
+        /// How old the cached consensus was.
+        age: Duration,
+        /// The oldest consensus that we were willing to load.
+        max_age: Duration,
+    },
+    /// Our cached consensus had expired, even allowing for our
+    /// [`DirTolerance`], so we didn't load it.
+    SkipExpired {
+        /// The end of the cached consensus's validity period.
+        valid_until: SystemTime,
+        /// The last time at which our `DirTolerance` would have accepted it.
+        usable_until: SystemTime,
+    },
+}
+
+impl StartupCacheDecision {
+    /// Return true if this decision was to try loading a consensus from the
+    /// cache.
+    pub fn should_load(&self) -> bool {
+        match self {
+            StartupCacheDecision::NoCachedConsensus
+            | StartupCacheDecision::SkipTooOld { .. }
+            | StartupCacheDecision::SkipExpired { .. } => false,
+            StartupCacheDecision::MetadataUnavailable | StartupCacheDecision::Load { .. } => true,
+        }
+    }
+}
+
+impl Display for StartupCacheDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use humantime::{format_duration, format_rfc3339_seconds};
+        match self {
+            StartupCacheDecision::NoCachedConsensus => write!(f, "no cached consensus"),
+            StartupCacheDecision::MetadataUnavailable => write!(
+                f,
+                "couldn't read cached consensus metadata; trying to load it anyway"
+            ),
+            StartupCacheDecision::Load { valid_after, age } => write!(
+                f,
+                "loading cached consensus from {} ({} old)",
+                format_rfc3339_seconds(*valid_after),
+                format_duration(*age)
+            ),
+            StartupCacheDecision::SkipTooOld {
+                valid_after,
+                age,
+                max_age,
+            } => write!(
+                f,
+                "skipping cached consensus from {}: it is {} old, and we only load ones up to {} old",
+                format_rfc3339_seconds(*valid_after),
+                format_duration(*age),
+                format_duration(*max_age)
+            ),
+            StartupCacheDecision::SkipExpired {
+                valid_until,
+                usable_until,
+            } => write!(
+                f,
+                "skipping cached consensus: it expired at {}, and was only usable until {}",
+                format_rfc3339_seconds(*valid_until),
+                format_rfc3339_seconds(*usable_until)
+            ),
+        }
+    }
+}
+
+/// Decide, as of `now`, whether to load a cached consensus with `lifetime`
+/// when we start up.
+///
+/// `lifetime` is `None` if we have no cached consensus.
+pub(crate) fn decide(
+    lifetime: Option<&Lifetime>,
+    now: SystemTime,
+    tolerance: &DirTolerance,
+    config: &StartupCacheConfig,
+) -> StartupCacheDecision {
+    let Some(lifetime) = lifetime else {
+        return StartupCacheDecision::NoCachedConsensus;
+    };
+
+    let valid_until = lifetime.valid_until();
+    let usable_until = valid_until + tolerance.post_valid_tolerance;
+    if now > usable_until {
+        return StartupCacheDecision::SkipExpired {
+            valid_until,
+            usable_until,
+        };
+    }
+
+    let valid_after = lifetime.valid_after();
+    // If the consensus seems to be from the future, our clock may be a bit
+    // behind; our DirTolerance will decide whether that's acceptable.
+    let age = now.duration_since(valid_after).unwrap_or(Duration::ZERO);
+    match config.max_consensus_age {
+        Some(max_age) if age > max_age => StartupCacheDecision::SkipTooOld {
+            valid_after,
+            age,
+            max_age,
+        },
+        _ => StartupCacheDecision::Load { valid_after, age },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    #[test]
+    fn decisions() {
+        let valid_after = SystemTime::UNIX_EPOCH + 1000 * HOUR;
+        let lifetime =
+            Lifetime::new(valid_after, valid_after + HOUR, valid_after + 3 * HOUR).unwrap();
+        let tolerance = DirTolerance::default();
+        let mut config = StartupCacheConfig::default();
+
+        let d = decide(None, valid_after, &tolerance, &config);
+        assert_eq!(d, StartupCacheDecision::NoCachedConsensus);
+        assert!(!d.should_load());
+
+        // With no maximum age, we load anything that our tolerance allows.
+        let now = valid_after + 2 * 24 * HOUR;
+        let d = decide(Some(&lifetime), now, &tolerance, &config);
+        assert_eq!(
+            d,
+            StartupCacheDecision::Load {
+                valid_after,
+                age: 2 * 24 * HOUR
+            }
+        );
+        assert!(d.should_load());
+
+        // A consensus from the future has an age of zero.
+        let d = decide(Some(&lifetime), valid_after - HOUR, &tolerance, &config);
+        assert_eq!(
+            d,
+            StartupCacheDecision::Load {
+                valid_after,
+                age: Duration::ZERO
+            }
+        );
+
+        // But we never load one that has expired.
+        let now = valid_after + 7 * 24 * HOUR;
+        let d = decide(Some(&lifetime), now, &tolerance, &config);
+        assert_eq!(
+            d,
+            StartupCacheDecision::SkipExpired {
+                valid_until: valid_after + 3 * HOUR,
+                usable_until: valid_after + 3 * HOUR + 3 * 24 * HOUR,
+            }
+        );
+        assert!(!d.should_load());
+
+        // With a maximum age, we skip anything older.
+        config.max_consensus_age = Some(12 * HOUR);
+        let d = decide(Some(&lifetime), valid_after + 6 * HOUR, &tolerance, &config);
+        assert!(d.should_load());
+        let d = decide(
+            Some(&lifetime),
+            valid_after + 13 * HOUR,
+            &tolerance,
+            &config,
+        );
+        assert_eq!(
+            d,
+            StartupCacheDecision::SkipTooOld {
+                valid_after,
+                age: 13 * HOUR,
+                max_age: 12 * HOUR,
+            }
+        );
+        assert!(!d.should_load());
+        assert!(d.to_string().contains("13h old"));
+    }
+}
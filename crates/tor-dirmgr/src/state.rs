@@ -5,6 +5,9 @@
 //! to validate that consensus ([`GetCertsState`]), and looking for
 //! microdescriptors ([`GetMicrodescsState`]).
 //!
+//! If we have been configured not to maintain every kind of document (see
+//! [`MaintainedDocs`]), we stop early, in [`MaintainedState`].
+//!
 //! These states have no contact with the network, and are purely
 //! reactive to other code that drives them.  See the
 //! [`bootstrap`](crate::bootstrap) module for functions that actually
@@ -26,17 +29,23 @@ use tracing::{debug, warn};
 use crate::event::DirProgress;
 
 use crate::storage::DynStore;
+use crate::timing;
 use crate::{
     docmeta::{AuthCertMeta, ConsensusMeta},
     event,
     retry::DownloadSchedule,
-    CacheUsage, ClientRequest, DirMgrConfig, DocId, DocumentText, Error, Readiness, Result,
+    CacheUsage, ClientRequest, DirMgrConfig, DirTolerance, DocId, DocumentText, Error,
+    MaintainedDocs, Readiness, Result,
 };
-use crate::{DocSource, SharedMutArc};
+use crate::{DocSource, NetDirProvenance, SharedMutArc};
 use tor_checkable::{ExternallySigned, SelfSigned, Timebound};
 #[cfg(feature = "geoip")]
 use tor_geoip::GeoipDb;
 use tor_llcrypto::pk::rsa::RsaIdentity;
+#[cfg(feature = "ns_consensus")]
+use tor_netdoc::doc::netstatus::{NsConsensus, UnvalidatedNsConsensus};
+#[cfg(feature = "ns_consensus")]
+use tor_netdoc::doc::routerdesc::{RdDigest, RouterDesc};
 use tor_netdoc::doc::{
     microdesc::{MdDigest, Microdesc},
     netstatus::MdConsensus,
@@ -45,7 +54,9 @@ use tor_netdoc::{
     doc::{
         authcert::{AuthCert, AuthCertKeyIds},
         microdesc::MicrodescReader,
-        netstatus::{ConsensusFlavor, UnvalidatedMdConsensus},
+        netstatus::{
+            ConsensusFlavor, UncheckedConsensus, UnvalidatedConsensus, UnvalidatedMdConsensus,
+        },
     },
     AllowAnnotations,
 };
@@ -66,9 +77,31 @@ pub(crate) enum NetDirChange<'a> {
         netdir: &'a mut Option<NetDir>,
         /// The consensus metadata for this netdir.
         consensus_meta: &'a ConsensusMeta,
+        /// Where the documents in this netdir came from.
+        provenance: &'a NetDirProvenance,
+        /// Microdescriptors that this netdir's consensus lists, but that we
+        /// have not yet marked as listed in the cache.
+        ///
+        /// The caller must mark them before it expires anything from the
+        /// cache, and then clear this list.
+        newly_listed: &'a mut Vec<MdDigest>,
     },
     /// Add the provided microdescriptors to the current `NetDir`.
-    AddMicrodescs(&'a mut Vec<Microdesc>),
+    ///
+    /// The `NetDirProvenance` describes the current `NetDir` once they are added.
+    AddMicrodescs(&'a mut Vec<Microdesc>, &'a NetDirProvenance),
+    /// Mark the provided consensus as usable in the cache, without changing
+    /// the current `NetDir`.
+    ///
+    /// Used when we have been configured not to maintain a `NetDir` at all,
+    /// once we have checked the signatures on the consensus.  Unlike
+    /// `AttemptReplace`, this doesn't expire anything from the cache: we
+    /// haven't built a directory, so we don't know which documents are still
+    /// needed.
+    MarkConsensusUsable {
+        /// The consensus metadata for the consensus to mark.
+        consensus_meta: &'a ConsensusMeta,
+    },
 }
 
 /// A "state" object used to represent our progress in downloading a
@@ -97,6 +130,14 @@ pub(crate) trait DirState: Send {
     /// or resetting the state should add new DocIds that weren't
     /// there before.
     fn missing_docs(&self) -> Vec<DocId>;
+    /// If this state is downloading the microdescriptors for a consensus,
+    /// return the SHA3-256 digest of that consensus's signed part.
+    ///
+    /// We use this to save our download progress, so that we can resume it
+    /// after a restart.
+    fn md_download_key(&self) -> Option<[u8; 32]> {
+        None
+    }
     /// Describe whether this state has reached `ready` status.
     fn is_ready(&self, ready: Readiness) -> bool;
     /// If the state object wants to make changes to the currently running `NetDir`,
@@ -165,12 +206,22 @@ impl PreviousNetDir for SharedMutArc<NetDir> {
     }
 }
 
+/// Return true if we know how to download and validate a consensus of the
+/// given `flavor`.
+pub(crate) fn flavor_is_supported(flavor: ConsensusFlavor) -> bool {
+    flavor == ConsensusFlavor::Microdesc
+        || (flavor == ConsensusFlavor::Ns && cfg!(feature = "ns_consensus"))
+}
+
 /// Initial state: fetching or loading a consensus directory.
 #[derive(Clone, Debug)]
 pub(crate) struct GetConsensusState<R: Runtime> {
     /// How should we get the consensus from the cache, if at all?
     cache_usage: CacheUsage,
 
+    /// Which flavor of consensus are we looking for?
+    flavor: ConsensusFlavor,
+
     /// If present, a time after which we want our consensus to have
     /// been published.
     //
@@ -226,6 +277,7 @@ impl<R: Runtime> GetConsensusState<R> {
 
         GetConsensusState {
             cache_usage,
+            flavor: config.extensions.consensus_flavor,
             after,
             next: None,
             authority_ids,
@@ -255,9 +307,8 @@ impl<R: Runtime> DirState for GetConsensusState<R> {
         if self.can_advance() {
             return Vec::new();
         }
-        let flavor = ConsensusFlavor::Microdesc;
         vec![DocId::LatestConsensus {
-            flavor,
+            flavor: self.flavor,
             cache_usage: self.cache_usage,
         }]
     }
@@ -284,14 +335,12 @@ impl<R: Runtime> DirState for GetConsensusState<R> {
     ) -> Result<()> {
         let text = match docs.into_iter().next() {
             None => return Ok(()),
-            Some((
-                DocId::LatestConsensus {
-                    flavor: ConsensusFlavor::Microdesc,
-                    ..
-                },
-                text,
-            )) => text,
-            _ => return Err(Error::CacheCorruption("Not an md consensus")),
+            Some((DocId::LatestConsensus { flavor, .. }, text)) if flavor == self.flavor => text,
+            _ => {
+                return Err(Error::CacheCorruption(
+                    "Not a consensus of the flavor we wanted",
+                ))
+            }
         };
 
         let source = DocSource::LocalCache;
@@ -316,16 +365,34 @@ impl<R: Runtime> DirState for GetConsensusState<R> {
             ClientRequest::Consensus(r) => r.last_consensus_date(),
             _ => None,
         };
+        let flavor = self.flavor;
+        let dir_cache = self.config.extensions.dir_cache;
         let meta = self.add_consensus_text(source, text, requested_newer_than, changed)?;
 
         if let Some(store) = storage {
-            let mut w = store.lock().expect("Directory storage lock poisoned");
-            w.store_consensus(meta, ConsensusFlavor::Microdesc, true, text)?;
+            let prev = {
+                let mut w = store.lock().expect("Directory storage lock poisoned");
+                let prev = if dir_cache {
+                    w.latest_consensus_meta(flavor)?
+                } else {
+                    None
+                };
+                w.store_consensus(meta, flavor, true, text)?;
+                prev
+            };
+            if dir_cache {
+                crate::dircache::note_new_consensus(store, prev.as_ref(), meta);
+            }
         }
         Ok(())
     }
     fn advance(self: Box<Self>) -> Box<dyn DirState> {
         match self.next {
+            Some(next)
+                if self.config.extensions.maintained_docs == MaintainedDocs::ConsensusOnly =>
+            {
+                Box::new(next.into_maintained())
+            }
             Some(next) => Box::new(next),
             None => self,
         }
@@ -355,47 +422,42 @@ impl<R: Runtime> GetConsensusState<R> {
         changed: &mut bool,
     ) -> Result<&ConsensusMeta> {
         // Try to parse it and get its metadata.
-        let (consensus_meta, unvalidated) = {
-            let (signedval, remainder, parsed) =
-                MdConsensus::parse(text).map_err(|e| Error::from_netdoc(source.clone(), e))?;
-            #[cfg(feature = "dirfilter")]
-            let parsed = self.filter.filter_consensus(parsed)?;
-            let parsed = self.config.tolerance.extend_tolerance(parsed);
-            let now = self.rt.wallclock();
-            let timely = parsed.check_valid_at(&now)?;
-            if let Some(cutoff) = cutoff {
-                if timely.peek_lifetime().valid_after() < cutoff {
-                    return Err(Error::Unwanted("consensus was older than requested"));
-                }
+        let (consensus_meta, consensus, desired_certs) = match self.flavor {
+            ConsensusFlavor::Microdesc => {
+                let (signedval, remainder, parsed) =
+                    MdConsensus::parse(text).map_err(|e| Error::from_netdoc(source.clone(), e))?;
+                #[cfg(feature = "dirfilter")]
+                let parsed = self.filter.filter_consensus(parsed)?;
+                let (meta, unvalidated, desired_certs) =
+                    self.check_unvalidated(signedval, remainder, parsed, cutoff)?;
+                (
+                    meta,
+                    GetCertsConsensus::Unvalidated(unvalidated),
+                    desired_certs,
+                )
             }
-            let meta = ConsensusMeta::from_unvalidated(signedval, remainder, &timely);
-            (meta, timely)
+            #[cfg(feature = "ns_consensus")]
+            ConsensusFlavor::Ns => {
+                let (signedval, remainder, parsed) =
+                    NsConsensus::parse(text).map_err(|e| Error::from_netdoc(source.clone(), e))?;
+                let (meta, unvalidated, desired_certs) =
+                    self.check_unvalidated(signedval, remainder, parsed, cutoff)?;
+                (
+                    meta,
+                    GetCertsConsensus::UnvalidatedNs(unvalidated),
+                    desired_certs,
+                )
+            }
+            flavor => return Err(Error::UnsupportedFlavor(flavor)),
         };
 
-        // Check out what authorities we believe in, and see if enough
-        // of them are purported to have signed this consensus.
-        let n_authorities = self.authority_ids.len() as u16;
-        let unvalidated = unvalidated.set_n_authorities(n_authorities);
-
-        let id_refs: Vec<_> = self.authority_ids.iter().collect();
-        if !unvalidated.authorities_are_correct(&id_refs[..]) {
-            return Err(Error::UnrecognizedAuthorities);
-        }
         // Yes, we've added the consensus.  That's a change.
         *changed = true;
 
-        // Make a set of all the certificates we want -- the subset of
-        // those listed on the consensus that we would indeed accept as
-        // authoritative.
-        let desired_certs = unvalidated
-            .signing_cert_ids()
-            .filter(|m| self.recognizes_authority(&m.id_fingerprint))
-            .collect();
-
         self.next = Some(GetCertsState {
             cache_usage: self.cache_usage,
             consensus_source: source,
-            consensus: GetCertsConsensus::Unvalidated(unvalidated),
+            consensus,
             consensus_meta,
             missing_certs: desired_certs,
             certs: Vec::new(),
@@ -411,12 +473,85 @@ impl<R: Runtime> GetConsensusState<R> {
         Ok(&self.next.as_ref().unwrap().consensus_meta)
     }
 
-    /// Return true if `id` is an authority identity we recognize
-    fn recognizes_authority(&self, id: &RsaIdentity) -> bool {
-        self.authority_ids.iter().any(|auth| auth == id)
+    /// Helper: check a freshly parsed consensus of any flavor for timeliness,
+    /// and make sure that it purports to be signed by the right authorities.
+    ///
+    /// On success, return its metadata, the consensus itself, and the set of
+    /// certificates that we would want in order to validate it.
+    fn check_unvalidated<RS>(
+        &self,
+        signedval: &str,
+        remainder: &str,
+        parsed: UncheckedConsensus<RS>,
+        cutoff: Option<SystemTime>,
+    ) -> Result<(
+        ConsensusMeta,
+        UnvalidatedConsensus<RS>,
+        HashSet<AuthCertKeyIds>,
+    )> {
+        let parsed = self.config.tolerance.extend_tolerance(parsed);
+        let now = self.rt.wallclock();
+        let timely = parsed.check_valid_at(&now)?;
+        if let Some(cutoff) = cutoff {
+            if timely.peek_lifetime().valid_after() < cutoff {
+                return Err(Error::Unwanted("consensus was older than requested"));
+            }
+        }
+        let meta = ConsensusMeta::from_unvalidated(signedval, remainder, &timely);
+        let (unvalidated, desired_certs) =
+            check_consensus_authorities(timely, &self.authority_ids)?;
+
+        Ok((meta, unvalidated, desired_certs))
     }
 }
 
+/// Helper: make sure that `consensus` (which we have already checked for
+/// timeliness) purports to be signed by enough of the authorities in
+/// `authority_ids`.
+///
+/// On success, return the consensus, and the set of certificates that we
+/// would want in order to validate it.
+///
+/// This is shared between bootstrapping and `verify_consensus_document`, so
+/// that they apply exactly the same rules.
+pub(crate) fn check_consensus_authorities<RS>(
+    consensus: UnvalidatedConsensus<RS>,
+    authority_ids: &[RsaIdentity],
+) -> Result<(UnvalidatedConsensus<RS>, HashSet<AuthCertKeyIds>)> {
+    // Check out what authorities we believe in, and see if enough
+    // of them are purported to have signed this consensus.
+    let n_authorities = authority_ids.len() as u16;
+    let unvalidated = consensus.set_n_authorities(n_authorities);
+
+    let id_refs: Vec<_> = authority_ids.iter().collect();
+    if !unvalidated.authorities_are_correct(&id_refs[..]) {
+        return Err(Error::UnrecognizedAuthorities);
+    }
+
+    // Make a set of all the certificates we want -- the subset of
+    // those listed on the consensus that we would indeed accept as
+    // authoritative.
+    let desired_certs = unvalidated
+        .signing_cert_ids()
+        .filter(|m| authority_ids.contains(&m.id_fingerprint))
+        .collect();
+
+    Ok((unvalidated, desired_certs))
+}
+
+/// Helper: check the signature on `cert`, and make sure that it is valid at
+/// `now`, allowing for `tolerance`.
+pub(crate) fn check_authcert(
+    cert: UncheckedAuthCert,
+    tolerance: &DirTolerance,
+    now: SystemTime,
+) -> Result<AuthCert> {
+    let wellsigned = cert.check_signature()?;
+    Ok(tolerance
+        .extend_tolerance(wellsigned)
+        .check_valid_at(&now)?)
+}
+
 /// One of two possible internal states for the consensus in a GetCertsState.
 ///
 /// This inner object is advanced by `try_checking_sigs`.
@@ -426,6 +561,13 @@ enum GetCertsConsensus {
     Unvalidated(UnvalidatedMdConsensus),
     /// A validated consensus: the signatures are fine and we can advance.
     Validated(MdConsensus),
+    /// We have an unvalidated ns-flavored consensus; we haven't checked its
+    /// signatures.
+    #[cfg(feature = "ns_consensus")]
+    UnvalidatedNs(UnvalidatedNsConsensus),
+    /// We have validated an ns-flavored consensus, and can advance.
+    #[cfg(feature = "ns_consensus")]
+    ValidatedNs(NsConsensus),
     /// We failed to validate the consensus, even after getting enough certificates.
     Failed,
 }
@@ -484,13 +626,7 @@ impl<R: Runtime> GetCertsState<R> {
         let cert_text = parsed
             .within(within)
             .expect("Certificate was not in input as expected");
-        let wellsigned = parsed.check_signature()?;
-        let now = self.rt.wallclock();
-        let timely_cert = self
-            .config
-            .tolerance
-            .extend_tolerance(wellsigned)
-            .check_valid_at(&now)?;
+        let timely_cert = check_authcert(parsed, &self.config.tolerance, self.rt.wallclock())?;
         Ok((timely_cert, cert_text))
     }
 
@@ -508,8 +644,14 @@ impl<R: Runtime> GetCertsState<R> {
         let mut consensus = C::Failed;
         std::mem::swap(&mut consensus, &mut self.consensus);
 
-        let unvalidated = match consensus {
-            C::Unvalidated(uv) if uv.key_is_correct(&self.certs[..]).is_ok() => uv,
+        let checked = match consensus {
+            C::Unvalidated(uv) if uv.key_is_correct(&self.certs[..]).is_ok() => {
+                uv.check_signature(&self.certs[..]).map(C::Validated)
+            }
+            #[cfg(feature = "ns_consensus")]
+            C::UnvalidatedNs(uv) if uv.key_is_correct(&self.certs[..]).is_ok() => {
+                uv.check_signature(&self.certs[..]).map(C::ValidatedNs)
+            }
             _ => {
                 // nothing to check at this point.  Either we already checked the consensus, or we don't yet have enough certificates.
                 self.consensus = consensus;
@@ -517,8 +659,8 @@ impl<R: Runtime> GetCertsState<R> {
             }
         };
 
-        let (new_consensus, outcome) = match unvalidated.check_signature(&self.certs[..]) {
-            Ok(validated) => (C::Validated(validated), Ok(())),
+        let (new_consensus, outcome) = match checked {
+            Ok(validated) => (validated, Ok(())),
             Err(cause) => (
                 C::Failed,
                 Err(Error::ConsensusInvalid {
@@ -533,10 +675,61 @@ impl<R: Runtime> GetCertsState<R> {
     }
 }
 
+impl<R: Runtime> GetCertsState<R> {
+    /// Return true if we have checked the signatures on our consensus, and
+    /// found them to be valid.
+    fn is_validated(&self) -> bool {
+        // (We can't use `matches!` here, since one of the patterns is
+        // conditionally compiled.)
+        #[allow(clippy::match_like_matches_macro)]
+        match self.consensus {
+            GetCertsConsensus::Validated(_) => true,
+            #[cfg(feature = "ns_consensus")]
+            GetCertsConsensus::ValidatedNs(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Stop fetching documents, and keep the consensus that we have.
+    ///
+    /// Used when we have been configured not to maintain the documents that
+    /// we would fetch next.
+    fn into_maintained(self) -> MaintainedState<R> {
+        let validated = self.is_validated();
+        let replace_time =
+            timing::consensus_download_time(&self.config, self.consensus_meta.lifetime());
+        MaintainedState {
+            cache_usage: self.cache_usage,
+            meta: self.consensus_meta,
+            validated,
+            // We only mark a consensus usable once we've checked its
+            // signatures; otherwise we'd treat an unchecked document as one
+            // we could build a directory from.
+            need_mark_usable: validated,
+            replace_time,
+            rt: self.rt,
+            config: self.config,
+            prev_netdir: self.prev_netdir,
+            #[cfg(feature = "dirfilter")]
+            filter: self.filter,
+        }
+    }
+}
+
 impl<R: Runtime> DirState for GetCertsState<R> {
     fn describe(&self) -> String {
         use GetCertsConsensus as C;
         match &self.consensus {
+            #[cfg(feature = "ns_consensus")]
+            C::UnvalidatedNs(_) => format!(
+                "Downloading certificates for ns consensus (we are missing {}/{}).",
+                self.missing_certs.len(),
+                self.certs.len() + self.missing_certs.len()
+            ),
+            #[cfg(feature = "ns_consensus")]
+            C::ValidatedNs(_) => {
+                "Validated ns consensus; about to get router descriptors".to_string()
+            }
             C::Unvalidated(_) => {
                 let total = self.certs.len() + self.missing_certs.len();
                 format!(
@@ -559,7 +752,7 @@ impl<R: Runtime> DirState for GetCertsState<R> {
         false
     }
     fn can_advance(&self) -> bool {
-        matches!(self.consensus, GetCertsConsensus::Validated(_))
+        self.is_validated()
     }
     fn bootstrap_progress(&self) -> DirProgress {
         let n_certs = self.certs.len();
@@ -681,9 +874,33 @@ impl<R: Runtime> DirState for GetCertsState<R> {
     fn advance(self: Box<Self>) -> Box<dyn DirState> {
         use GetCertsConsensus::*;
         match self.consensus {
+            Validated(_)
+                if self.config.extensions.maintained_docs == MaintainedDocs::ConsensusAndCerts =>
+            {
+                Box::new(self.into_maintained())
+            }
+            #[cfg(feature = "ns_consensus")]
+            ValidatedNs(_)
+                if self.config.extensions.maintained_docs == MaintainedDocs::ConsensusAndCerts =>
+            {
+                Box::new(self.into_maintained())
+            }
+            #[cfg(feature = "ns_consensus")]
+            ValidatedNs(validated) => Box::new(GetRouterDescsState::new(
+                self.cache_usage,
+                &validated,
+                self.consensus_source,
+                self.consensus_meta,
+                self.rt,
+                self.config,
+                self.prev_netdir,
+                #[cfg(feature = "dirfilter")]
+                self.filter,
+            )),
             Validated(validated) => Box::new(GetMicrodescsState::new(
                 self.cache_usage,
                 validated,
+                self.consensus_source,
                 self.consensus_meta,
                 self.rt,
                 self.config,
@@ -724,6 +941,113 @@ impl<R: Runtime> DirState for GetCertsState<R> {
     }
 }
 
+/// Final state, if we're not maintaining microdescriptors: we have a
+/// consensus, and we're not fetching anything else.
+#[derive(Clone, Debug)]
+struct MaintainedState<R: Runtime> {
+    /// The cache usage we had in mind when we began.  Used to reset.
+    cache_usage: CacheUsage,
+    /// Metadata for the consensus that we have.
+    meta: ConsensusMeta,
+    /// True if we checked the signatures on the consensus.
+    validated: bool,
+    /// True if we have not yet asked our caller to mark the consensus usable.
+    ///
+    /// Always false if the consensus is not `validated`.
+    need_mark_usable: bool,
+    /// The time at which we should try to replace this consensus.
+    replace_time: SystemTime,
+
+    /// A `Runtime` implementation.
+    rt: R,
+    /// The configuration of the directory manager.
+    config: Arc<DirMgrConfig>,
+    /// If one exists, the netdir we're trying to update.
+    prev_netdir: Option<Arc<dyn PreviousNetDir>>,
+
+    /// A filter that gets applied to directory objects before we use them.
+    #[cfg(feature = "dirfilter")]
+    filter: Arc<dyn crate::filter::DirFilter>,
+}
+
+impl<R: Runtime> DirState for MaintainedState<R> {
+    fn describe(&self) -> String {
+        if self.validated {
+            "Have a validated consensus; not fetching anything else.".to_string()
+        } else {
+            "Have a consensus; not fetching anything else.".to_string()
+        }
+    }
+    fn missing_docs(&self) -> Vec<DocId> {
+        Vec::new()
+    }
+    fn is_ready(&self, _ready: Readiness) -> bool {
+        // We have everything we've been asked to maintain.
+        true
+    }
+    fn get_netdir_change(&mut self) -> Option<NetDirChange<'_>> {
+        mem::take(&mut self.need_mark_usable).then_some(NetDirChange::MarkConsensusUsable {
+            consensus_meta: &self.meta,
+        })
+    }
+    fn can_advance(&self) -> bool {
+        false
+    }
+    fn add_from_cache(
+        &mut self,
+        _docs: HashMap<DocId, DocumentText>,
+        _changed: &mut bool,
+    ) -> Result<()> {
+        Ok(())
+    }
+    fn add_from_download(
+        &mut self,
+        _text: &str,
+        _request: &ClientRequest,
+        _source: DocSource,
+        _storage: Option<&Mutex<DynStore>>,
+        _changed: &mut bool,
+    ) -> Result<()> {
+        Ok(())
+    }
+    fn bootstrap_progress(&self) -> DirProgress {
+        // We report ourselves as usable, since we have everything that we
+        // need to have.
+        DirProgress::Validated {
+            lifetime: self.meta.lifetime().clone(),
+            usable_lifetime: self.config.tolerance.extend_lifetime(self.meta.lifetime()),
+            n_mds: (0, 0),
+            usable: true,
+        }
+    }
+    fn dl_config(&self) -> DownloadSchedule {
+        self.config.schedule.retry_consensus
+    }
+    fn advance(self: Box<Self>) -> Box<dyn DirState> {
+        self
+    }
+    fn reset_time(&self) -> Option<SystemTime> {
+        Some(self.replace_time)
+    }
+    fn reset(self: Box<Self>) -> Box<dyn DirState> {
+        let cache_usage = if self.cache_usage == CacheUsage::CacheOnly {
+            // Cache only means we can't ever download.
+            CacheUsage::CacheOnly
+        } else {
+            // We're replacing the consensus we have, so we want a newer one.
+            CacheUsage::MustDownload
+        };
+        Box::new(GetConsensusState::new(
+            self.rt,
+            self.config,
+            cache_usage,
+            self.prev_netdir,
+            #[cfg(feature = "dirfilter")]
+            self.filter,
+        ))
+    }
+}
+
 /// Final state: we're fetching or loading microdescriptors
 #[derive(Debug, Clone)]
 struct GetMicrodescsState<R: Runtime> {
@@ -735,6 +1059,8 @@ struct GetMicrodescsState<R: Runtime> {
     partial: PendingNetDir,
     /// Metadata for the current consensus.
     meta: ConsensusMeta,
+    /// Where the documents in our netdir came from.
+    provenance: NetDirProvenance,
     /// A pending list of microdescriptor digests whose
     /// "last-listed-at" times we should update.
     newly_listed: Vec<MdDigest>,
@@ -788,6 +1114,31 @@ enum PendingNetDir {
     Dummy,
 }
 
+/// How many "critical" microdescriptors should we try to put at the front of
+/// our download queue?
+///
+/// This matches the number of microdescriptors we put in a single request.
+const N_CRITICAL_MDS: usize = 500;
+
+impl PendingNetDir {
+    /// Return every missing microdescriptor digest, with the ones that would
+    /// do the most to make this directory usable listed first.
+    fn missing_microdescs_by_priority(&self) -> Vec<MdDigest> {
+        match self {
+            PendingNetDir::Partial(partial) => {
+                let critical = partial.critical_missing_mds(N_CRITICAL_MDS);
+                let critical_set: HashSet<_> = critical.iter().copied().collect();
+                let rest = partial
+                    .missing_microdescs()
+                    .filter(|d| !critical_set.contains(*d))
+                    .copied();
+                critical.into_iter().chain(rest).collect()
+            }
+            _ => self.missing_microdescs().copied().collect(),
+        }
+    }
+}
+
 impl MdReceiver for PendingNetDir {
     fn missing_microdescs(&self) -> Box<dyn Iterator<Item = &MdDigest> + '_> {
         match self {
@@ -854,13 +1205,16 @@ impl MdReceiver for PendingNetDir {
 
 impl PendingNetDir {
     /// If this PendingNetDir is Partial and could not be partial, upgrade it.
-    fn upgrade_if_necessary(&mut self) {
+    ///
+    /// Use `config` to decide when we should replace the upgraded netdir.
+    fn upgrade_if_necessary(&mut self, config: &DirMgrConfig) {
         if matches!(self, PendingNetDir::Partial(..)) {
             match mem::replace(self, PendingNetDir::Dummy) {
                 PendingNetDir::Partial(p) => match p.unwrap_if_sufficient() {
                     Ok(nd) => {
                         let missing: HashSet<_> = nd.missing_microdescs().copied().collect();
-                        let replace_dir_time = pick_download_time(nd.lifetime());
+                        let replace_dir_time =
+                            timing::consensus_download_time(config, nd.lifetime());
                         debug!(
                             "Consensus now usable, with {} microdescriptors missing. \
                                 The current consensus is fresh until {}, and valid until {}. \
@@ -891,9 +1245,11 @@ impl PendingNetDir {
 impl<R: Runtime> GetMicrodescsState<R> {
     /// Create a new [`GetMicrodescsState`] from a provided
     /// microdescriptor consensus.
+    #[allow(clippy::too_many_arguments)]
     fn new(
         cache_usage: CacheUsage,
         consensus: MdConsensus,
+        consensus_source: DocSource,
         meta: ConsensusMeta,
         rt: R,
         config: Arc<DirMgrConfig>,
@@ -905,26 +1261,42 @@ impl<R: Runtime> GetMicrodescsState<R> {
 
         let params = &config.override_net_params;
         #[cfg(not(feature = "geoip"))]
-        let mut partial_dir = PartialNetDir::new(consensus, Some(params));
-        // TODO(eta): Make this embedded database configurable using the `DirMgrConfig`.
+        let partial_dir = PartialNetDir::new(consensus, Some(params));
         #[cfg(feature = "geoip")]
-        let mut partial_dir =
-            PartialNetDir::new_with_geoip(consensus, Some(params), &GeoipDb::new_embedded());
+        let partial_dir = match &config.extensions.geoip {
+            Some(mgr) => PartialNetDir::new_with_geoip_manager(consensus, Some(params), mgr),
+            None => {
+                PartialNetDir::new_with_geoip(consensus, Some(params), &GeoipDb::new_embedded())
+            }
+        };
+
+        // Share microdescriptors with the previous directory (and the ones
+        // that come after this one), so that we hold only one copy of each.
+        let old_dir = prev_netdir.as_ref().and_then(|x| x.get_netdir());
+        let md_pool = old_dir
+            .as_ref()
+            .and_then(|d| d.md_pool().cloned())
+            .unwrap_or_default();
+        let mut partial_dir = partial_dir.with_md_pool(md_pool);
 
-        if let Some(old_dir) = prev_netdir.as_ref().and_then(|x| x.get_netdir()) {
+        let mut provenance = NetDirProvenance::new(consensus_source);
+        if let Some(old_dir) = old_dir {
+            let n_missing = partial_dir.n_missing();
             partial_dir.fill_from_previous_netdir(old_dir);
+            provenance.note_from_previous(n_missing - partial_dir.n_missing());
         }
 
         // Always upgrade at least once: otherwise, we won't notice we're ready unless we
         // add a microdescriptor.
         let mut partial = PendingNetDir::Partial(partial_dir);
-        partial.upgrade_if_necessary();
+        partial.upgrade_if_necessary(&config);
 
         GetMicrodescsState {
             cache_usage,
             n_microdescs,
             partial,
             meta,
+            provenance,
             newly_listed: Vec::new(),
             reset_time,
             rt,
@@ -937,7 +1309,7 @@ impl<R: Runtime> GetMicrodescsState<R> {
     }
 
     /// Add a bunch of microdescriptors to the in-progress netdir.
-    fn register_microdescs<I>(&mut self, mds: I, _source: &DocSource, changed: &mut bool)
+    fn register_microdescs<I>(&mut self, mds: I, source: &DocSource, changed: &mut bool)
     where
         I: IntoIterator<Item = Microdesc>,
     {
@@ -947,14 +1319,18 @@ impl<R: Runtime> GetMicrodescsState<R> {
             .filter_map(|m| self.filter.filter_md(m).ok())
             .collect();
         let is_partial = matches!(self.partial, PendingNetDir::Partial(..));
+        let mut n_added = 0;
         for md in mds {
             if is_partial {
                 self.newly_listed.push(*md.digest());
             }
-            self.partial.add_microdesc(md);
+            if self.partial.add_microdesc(md) {
+                n_added += 1;
+            }
             *changed = true;
         }
-        self.partial.upgrade_if_necessary();
+        self.provenance.note_microdescs(source, n_added);
+        self.partial.upgrade_if_necessary(&self.config);
     }
 }
 
@@ -967,10 +1343,14 @@ impl<R: Runtime> DirState for GetMicrodescsState<R> {
     }
     fn missing_docs(&self) -> Vec<DocId> {
         self.partial
-            .missing_microdescs()
-            .map(|d| DocId::Microdesc(*d))
+            .missing_microdescs_by_priority()
+            .into_iter()
+            .map(DocId::Microdesc)
             .collect()
     }
+    fn md_download_key(&self) -> Option<[u8; 32]> {
+        Some(*self.meta.sha3_256_of_signed())
+    }
     fn get_netdir_change(&mut self) -> Option<NetDirChange<'_>> {
         match self.partial {
             PendingNetDir::Yielding {
@@ -982,11 +1362,16 @@ impl<R: Runtime> DirState for GetMicrodescsState<R> {
                     Some(NetDirChange::AttemptReplace {
                         netdir,
                         consensus_meta: &self.meta,
+                        provenance: &self.provenance,
+                        newly_listed: &mut self.newly_listed,
                     })
                 } else {
                     collected_microdescs
                         .is_empty()
-                        .then_some(NetDirChange::AddMicrodescs(collected_microdescs))
+                        .then_some(NetDirChange::AddMicrodescs(
+                            collected_microdescs,
+                            &self.provenance,
+                        ))
                 }
             }
             _ => None,
@@ -1155,13 +1540,259 @@ impl<R: Runtime> DirState for GetMicrodescsState<R> {
     }
 }
 
+/// Final state, for an ns-flavored consensus: we're fetching or loading the
+/// router descriptors that it lists.
+///
+/// We build our directory the same way that `tor_netdir::NsNetDir` does: we
+/// translate the consensus into a microdesc-flavored one, and each router
+/// descriptor into the microdescriptor that the translated consensus lists for
+/// it.  That lets us leave nearly all of the work to a [`GetMicrodescsState`].
+#[cfg(feature = "ns_consensus")]
+#[derive(Debug, Clone)]
+struct GetRouterDescsState<R: Runtime> {
+    /// The state that builds our directory from the translated documents.
+    inner: GetMicrodescsState<R>,
+    /// The digest of the router descriptor for each microdescriptor that the
+    /// translated consensus lists.
+    rd_digests: HashMap<MdDigest, RdDigest>,
+}
+
+#[cfg(feature = "ns_consensus")]
+impl<R: Runtime> GetRouterDescsState<R> {
+    /// Create a new [`GetRouterDescsState`] from a provided ns-flavored
+    /// consensus.
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        cache_usage: CacheUsage,
+        consensus: &NsConsensus,
+        consensus_source: DocSource,
+        meta: ConsensusMeta,
+        rt: R,
+        config: Arc<DirMgrConfig>,
+        prev_netdir: Option<Arc<dyn PreviousNetDir>>,
+        #[cfg(feature = "dirfilter")] filter: Arc<dyn crate::filter::DirFilter>,
+    ) -> Self {
+        let md_consensus = consensus.to_md_consensus();
+        // The translated consensus lists the same relays in the same order.
+        let rd_digests = md_consensus
+            .relays()
+            .iter()
+            .zip(consensus.relays())
+            .map(|(md_rs, ns_rs)| (*md_rs.md_digest(), *ns_rs.rd_digest()))
+            .collect();
+        let inner = GetMicrodescsState::new(
+            cache_usage,
+            md_consensus,
+            consensus_source,
+            meta,
+            rt,
+            config,
+            prev_netdir,
+            #[cfg(feature = "dirfilter")]
+            filter,
+        );
+        GetRouterDescsState { inner, rd_digests }
+    }
+
+    /// Check a router descriptor that we have loaded or downloaded, and
+    /// translate it into a microdescriptor.
+    ///
+    /// Return `None` if the descriptor isn't one that our consensus lists.
+    fn translate(&self, rd: &RouterDesc) -> Option<Microdesc> {
+        let md = Microdesc::from_routerdesc(rd);
+        (self.rd_digests.get(md.digest()) == Some(rd.digest())).then_some(md)
+    }
+}
+
+/// Parse and check a router descriptor from `text`.
+///
+/// We don't check whether the descriptor is timely: a consensus that lists it
+/// vouches for it, in the same way that it vouches for a microdescriptor.
+#[cfg(feature = "ns_consensus")]
+fn parse_routerdesc(text: &str) -> tor_netdoc::Result<RouterDesc> {
+    Ok(RouterDesc::parse(text)?
+        .check_signature()?
+        .dangerously_assume_timely())
+}
+
+/// Split `text`, a response to a request for router descriptors, into the
+/// texts of the individual descriptors.
+///
+/// Each descriptor starts with a `router` line.
+#[cfg(feature = "ns_consensus")]
+fn split_routerdescs(text: &str) -> impl Iterator<Item = &str> {
+    let mut starts: Vec<usize> = text
+        .match_indices("\nrouter ")
+        .map(|(pos, _)| pos + 1)
+        .collect();
+    starts.insert(0, 0);
+    let ends: Vec<usize> = starts.iter().skip(1).copied().chain([text.len()]).collect();
+    starts
+        .into_iter()
+        .zip(ends)
+        .map(move |(start, end)| &text[start..end])
+        .filter(|rd_text| !rd_text.trim().is_empty())
+}
+
+#[cfg(feature = "ns_consensus")]
+impl<R: Runtime> DirState for GetRouterDescsState<R> {
+    fn describe(&self) -> String {
+        format!(
+            "Downloading router descriptors (we are missing {}).",
+            self.inner.partial.n_missing()
+        )
+    }
+    fn missing_docs(&self) -> Vec<DocId> {
+        self.inner
+            .partial
+            .missing_microdescs_by_priority()
+            .into_iter()
+            .filter_map(|md_digest| self.rd_digests.get(&md_digest))
+            .map(|rd_digest| DocId::RouterDesc(*rd_digest))
+            .collect()
+    }
+    fn get_netdir_change(&mut self) -> Option<NetDirChange<'_>> {
+        self.inner.get_netdir_change()
+    }
+    fn is_ready(&self, ready: Readiness) -> bool {
+        self.inner.is_ready(ready)
+    }
+    fn can_advance(&self) -> bool {
+        false
+    }
+    fn bootstrap_progress(&self) -> DirProgress {
+        self.inner.bootstrap_progress()
+    }
+    fn dl_config(&self) -> DownloadSchedule {
+        self.inner.dl_config()
+    }
+    fn add_from_cache(
+        &mut self,
+        docs: HashMap<DocId, DocumentText>,
+        changed: &mut bool,
+    ) -> Result<()> {
+        let mut microdescs = Vec::new();
+        for (id, text) in docs {
+            if let DocId::RouterDesc(digest) = id {
+                if let Ok(rd) = parse_routerdesc(text.as_str().map_err(Error::BadUtf8InCache)?) {
+                    if rd.digest() == &digest {
+                        if let Some(md) = self.translate(&rd) {
+                            microdescs.push(md);
+                        }
+                        continue;
+                    }
+                }
+                warn!("Found a mismatched router descriptor in cache; ignoring");
+            }
+        }
+
+        self.inner
+            .register_microdescs(microdescs, &DocSource::LocalCache, changed);
+        Ok(())
+    }
+
+    fn add_from_download(
+        &mut self,
+        text: &str,
+        request: &ClientRequest,
+        source: DocSource,
+        storage: Option<&Mutex<DynStore>>,
+        changed: &mut bool,
+    ) -> Result<()> {
+        if !matches!(request, ClientRequest::RouterDescs(_)) {
+            return Err(internal!("expected a router descriptor request").into());
+        }
+        let mut new_rds = Vec::new();
+        let mut nonfatal_err = None;
+
+        for rd_text in split_routerdescs(text) {
+            let rd = match parse_routerdesc(rd_text) {
+                Err(e) => {
+                    nonfatal_err.get_or_insert_with(|| Error::from_netdoc(source.clone(), e));
+                    continue;
+                }
+                Ok(rd) => rd,
+            };
+            let published = rd.published();
+            let digest = *rd.digest();
+            let Some(md) = self.translate(&rd) else {
+                warn!(
+                    "Received router descriptor from {} we did not ask for: {:?}",
+                    source, digest
+                );
+                nonfatal_err.get_or_insert(Error::Unwanted("un-requested router descriptor"));
+                continue;
+            };
+            new_rds.push((rd_text, published, digest, md));
+        }
+
+        if let Some(store) = storage {
+            if !new_rds.is_empty() {
+                let mut s = store.lock().expect("Directory storage lock poisoned");
+                s.store_routerdescs(
+                    &new_rds
+                        .iter()
+                        .map(|(text, published, digest, _)| (*text, *published, digest))
+                        .collect::<Vec<_>>(),
+                )?;
+            }
+        }
+
+        self.inner.register_microdescs(
+            new_rds.into_iter().map(|(_, _, _, md)| md),
+            &source,
+            changed,
+        );
+
+        opt_err_to_result(nonfatal_err)
+    }
+    fn advance(self: Box<Self>) -> Box<dyn DirState> {
+        self
+    }
+    fn reset_time(&self) -> Option<SystemTime> {
+        self.inner.reset_time()
+    }
+    fn reset(self: Box<Self>) -> Box<dyn DirState> {
+        Box::new(self.inner).reset()
+    }
+}
+
 /// Choose a random download time to replace a consensus whose lifetime
 /// is `lifetime`.
-fn pick_download_time(lifetime: &Lifetime) -> SystemTime {
+pub(crate) fn pick_download_time(lifetime: &Lifetime) -> SystemTime {
     let (lowbound, uncertainty) = client_download_range(lifetime);
     lowbound + rand::thread_rng().gen_range_infallible(..=uncertainty)
 }
 
+/// Choose a random download time to replace a consensus whose lifetime is
+/// `lifetime`, about `lead_time` before it becomes invalid.
+pub(crate) fn pick_prefetch_time(lifetime: &Lifetime, lead_time: Duration) -> SystemTime {
+    let (lowbound, uncertainty) = client_prefetch_range(lifetime, lead_time);
+    lowbound + rand::thread_rng().gen_range_infallible(..=uncertainty)
+}
+
+/// Based on the lifetime for a consensus, return the time range during which
+/// clients that want to fetch the next one `lead_time` early should do so.
+///
+/// We jitter the start time by up to a quarter of `lead_time`; but we never
+/// start before the consensus stops being fresh, since no newer one will
+/// have been published.
+fn client_prefetch_range(lt: &Lifetime, lead_time: Duration) -> (SystemTime, Duration) {
+    let fresh_until = lt.fresh_until();
+    let latest = lt
+        .valid_until()
+        .checked_sub(lead_time)
+        .map_or(fresh_until, |t| t.max(fresh_until));
+    let earliest = latest
+        .checked_sub(lead_time / 4)
+        .map_or(fresh_until, |t| t.max(fresh_until));
+    let uncertainty = latest
+        .duration_since(earliest)
+        .expect("clamped start must precede end");
+
+    (earliest, uncertainty)
+}
+
 /// Based on the lifetime for a consensus, return the time range during which
 /// clients should fetch the next one.
 fn client_download_range(lt: &Lifetime) -> (SystemTime, Duration) {
@@ -1295,6 +1926,47 @@ mod test {
         }
     }
 
+    #[test]
+    fn prefetch_schedule() {
+        let va = datetime!(2008-08-02 20:00 UTC).into();
+        let fu = datetime!(2008-08-02 21:00 UTC).into();
+        let vu = datetime!(2008-08-02 23:00 UTC).into();
+        let lifetime = Lifetime::new(va, fu, vu).unwrap();
+
+        // Forty minutes before valid-until, jittered by up to ten minutes.
+        let expected_start: SystemTime = datetime!(2008-08-02 22:10 UTC).into();
+        let expected_range = Duration::from_secs(10 * 60);
+        let (start, range) = client_prefetch_range(&lifetime, Duration::from_secs(40 * 60));
+        assert_eq!(start, expected_start);
+        assert_eq!(range, expected_range);
+
+        // We never start before fresh-until.
+        let (start, range) = client_prefetch_range(&lifetime, Duration::from_secs(100 * 60));
+        assert_eq!(start, fu);
+        assert_eq!(range, Duration::from_secs(20 * 60));
+        let (start, range) = client_prefetch_range(&lifetime, Duration::from_secs(130 * 60));
+        assert_eq!(start, fu);
+        assert_eq!(range, Duration::ZERO);
+
+        for _ in 0..100 {
+            let when = pick_prefetch_time(&lifetime, Duration::from_secs(40 * 60));
+            assert!(when >= expected_start);
+            assert!(when <= expected_start + expected_range);
+        }
+
+        // The configuration decides which schedule we use.
+        let mut cfg = (*make_dirmgr_config(None)).clone();
+        cfg.schedule = DownloadScheduleConfig::builder()
+            .prefetch_lead_time(Duration::from_secs(40 * 60))
+            .build()
+            .unwrap();
+        for _ in 0..100 {
+            let when = timing::consensus_download_time(&cfg, &lifetime);
+            assert!(when >= expected_start);
+            assert!(when <= expected_start + expected_range);
+        }
+    }
+
     /// Makes a memory-backed storage.
     fn temp_store() -> (TempDir, Mutex<DynStore>) {
         let tempdir = TempDir::new().unwrap();
@@ -1669,6 +2341,328 @@ mod test {
         });
     }
 
+    #[test]
+    fn maintained_docs() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            /// Construct a state with a consensus, maintaining only `docs`.
+            fn new_state(rt: impl Runtime, docs: MaintainedDocs) -> Box<dyn DirState> {
+                let rt = make_time_shifted_runtime(test_time(), rt);
+                let mut cfg = (*make_dirmgr_config(Some(test_authorities()))).clone();
+                cfg.extensions.maintained_docs = docs;
+                let mut state = GetConsensusState::new(
+                    rt,
+                    Arc::new(cfg),
+                    CacheUsage::CacheOkay,
+                    None,
+                    #[cfg(feature = "dirfilter")]
+                    Arc::new(crate::filter::NilFilter),
+                );
+                let source = DocSource::DirServer { source: None };
+                let req = tor_dirclient::request::ConsensusRequest::new(ConsensusFlavor::Microdesc);
+                let req = crate::docid::ClientRequest::Consensus(req);
+                let mut changed = false;
+                let outcome = state.add_from_download(CONSENSUS, &req, source, None, &mut changed);
+                assert!(outcome.is_ok());
+                Box::new(state).advance()
+            }
+            /// Check that `state` has everything it wants, and nothing more to do.
+            fn check_maintained(mut state: Box<dyn DirState>, validated: bool) {
+                assert!(state.missing_docs().is_empty());
+                assert!(!state.can_advance());
+                assert!(state.is_ready(Readiness::Complete));
+                assert!(state.is_ready(Readiness::Usable));
+                assert!(state.bootstrap_progress().to_string().starts_with("usable"));
+                // We ask once (and only once) to have the consensus marked
+                // usable, and only if we checked its signatures.
+                if validated {
+                    assert!(matches!(
+                        state.get_netdir_change(),
+                        Some(NetDirChange::MarkConsensusUsable { .. })
+                    ));
+                }
+                assert!(state.get_netdir_change().is_none());
+                // We'll want to replace the consensus after it stops being
+                // fresh, but before it expires.
+                let fresh_until: SystemTime = datetime!(2020-08-07 12:43:00 UTC).into();
+                let valid_until: SystemTime = datetime!(2020-08-07 12:43:20 UTC).into();
+                let reset_time = state.reset_time().unwrap();
+                assert!(reset_time >= fresh_until);
+                assert!(reset_time <= valid_until);
+                let state = state.reset();
+                assert_eq!(&state.describe(), "Downloading a consensus.");
+            }
+
+            // Consensus-only: we never ask for certificates.
+            let state = new_state(rt.clone(), MaintainedDocs::ConsensusOnly);
+            assert_eq!(
+                &state.describe(),
+                "Have a consensus; not fetching anything else."
+            );
+            check_maintained(state, false);
+
+            // Consensus and certificates: we stop after validating.
+            let mut state = new_state(rt.clone(), MaintainedDocs::ConsensusAndCerts);
+            assert_eq!(state.missing_docs().len(), 2);
+            let text1: crate::storage::InputString = AUTHCERT_5696.to_owned().into();
+            let text2: crate::storage::InputString = AUTHCERT_5A23.to_owned().into();
+            let docs = vec![
+                (DocId::AuthCert(authcert_id_5696()), text1.into()),
+                (DocId::AuthCert(authcert_id_5a23()), text2.into()),
+            ]
+            .into_iter()
+            .collect();
+            let mut changed = false;
+            state.add_from_cache(docs, &mut changed).unwrap();
+            assert!(state.can_advance());
+            let state = state.advance();
+            assert_eq!(
+                &state.describe(),
+                "Have a validated consensus; not fetching anything else."
+            );
+            check_maintained(state, true);
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "ns_consensus")]
+    fn ns_flavor() {
+        const NS_CONSENSUS: &str = include_str!("../testdata/nsconsensus1.txt");
+        const NS_CERTS: &str = include_str!("../testdata/nscerts.txt");
+
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            for maintained_docs in [MaintainedDocs::Full, MaintainedDocs::ConsensusAndCerts] {
+                let now = datetime!(2021-03-26 23:26:30 UTC).into();
+                let rt = make_time_shifted_runtime(now, rt.clone());
+                let authorities = [
+                    "54BD7B9AE5FBD492633D05E645857C5DD531BC8B",
+                    "67DBEFD4EAB5F3298BB1453E2958CBFD65494218",
+                    "E21A5E3F8346DFA38970A5C9E99A5B7B15BDC800",
+                ]
+                .iter()
+                .map(|id| Authority::builder().name("ignore").v3ident(rsa(id)).clone())
+                .collect();
+                let mut cfg = (*make_dirmgr_config(Some(authorities))).clone();
+                cfg.extensions.consensus_flavor = ConsensusFlavor::Ns;
+                cfg.extensions.maintained_docs = maintained_docs;
+                let cfg = Arc::new(cfg);
+
+                let mut state = GetConsensusState::new(
+                    rt.clone(),
+                    Arc::clone(&cfg),
+                    CacheUsage::CacheOkay,
+                    None,
+                    #[cfg(feature = "dirfilter")]
+                    Arc::new(crate::filter::NilFilter),
+                );
+                assert_eq!(
+                    state.missing_docs(),
+                    vec![DocId::LatestConsensus {
+                        flavor: ConsensusFlavor::Ns,
+                        cache_usage: CacheUsage::CacheOkay,
+                    }]
+                );
+
+                // An md consensus is no good to us.
+                let source = DocSource::DirServer { source: None };
+                let req = tor_dirclient::request::ConsensusRequest::new(ConsensusFlavor::Ns);
+                let req = crate::docid::ClientRequest::Consensus(req);
+                let mut changed = false;
+                let outcome =
+                    state.add_from_download(CONSENSUS, &req, source.clone(), None, &mut changed);
+                assert!(outcome.is_err());
+                assert!(!changed);
+
+                // An ns consensus gets stored under the ns flavor.
+                let (_tempdir, store) = temp_store();
+                let outcome =
+                    state.add_from_download(NS_CONSENSUS, &req, source, Some(&store), &mut changed);
+                assert!(outcome.is_ok());
+                assert!(changed);
+                {
+                    let store = store.lock().unwrap();
+                    assert!(store
+                        .latest_consensus(ConsensusFlavor::Ns, None)
+                        .unwrap()
+                        .is_some());
+                    assert!(store
+                        .latest_consensus(ConsensusFlavor::Microdesc, None)
+                        .unwrap()
+                        .is_none());
+                }
+
+                // Once we have the certificates, we validate the consensus.
+                let mut state = Box::new(state).advance();
+                assert_eq!(state.missing_docs().len(), 3);
+                let docs = AuthCert::parse_multiple(NS_CERTS)
+                    .map(|cert| {
+                        let cert = cert.unwrap();
+                        let text = cert.within(NS_CERTS).unwrap();
+                        let cert = cert.check_signature().unwrap().dangerously_assume_timely();
+                        let text: crate::storage::InputString = text.to_owned().into();
+                        (DocId::AuthCert(*cert.key_ids()), text.into())
+                    })
+                    .collect();
+                let mut changed = false;
+                state.add_from_cache(docs, &mut changed).unwrap();
+                assert!(changed);
+                assert!(state.can_advance());
+                let mut state = state.advance();
+                if maintained_docs == MaintainedDocs::Full {
+                    // We go on to fetch the router descriptors for every relay.
+                    assert_eq!(
+                        &state.describe(),
+                        "Downloading router descriptors (we are missing 8)."
+                    );
+                    let missing = state.missing_docs();
+                    assert_eq!(missing.len(), 8);
+                    assert!(missing.iter().all(|id| matches!(id, DocId::RouterDesc(_))));
+                    assert!(!state.is_ready(Readiness::Usable));
+                    assert!(state.get_netdir_change().is_none());
+                } else {
+                    // We were told to stop once we have validated the consensus.
+                    assert_eq!(
+                        &state.describe(),
+                        "Have a validated consensus; not fetching anything else."
+                    );
+                    assert!(state.missing_docs().is_empty());
+                    assert!(state.is_ready(Readiness::Complete));
+                    assert!(matches!(
+                        state.get_netdir_change(),
+                        Some(NetDirChange::MarkConsensusUsable { .. })
+                    ));
+                }
+
+                // When we start over, we look for an ns consensus again.
+                let state = state.reset();
+                assert!(matches!(
+                    state.missing_docs()[0],
+                    DocId::LatestConsensus {
+                        flavor: ConsensusFlavor::Ns,
+                        ..
+                    }
+                ));
+            }
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "ns_consensus")]
+    fn get_routerdescs_state() {
+        use tor_linkspec::HasRelayIds as _;
+        use tor_netdoc::doc::netstatus::{RelayFlags, RelayWeight};
+
+        const ROUTERDESC: &str = include_str!("../testdata/routerdesc1.txt");
+
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            let rd = parse_routerdesc(ROUTERDESC).unwrap();
+            let now = rd.published();
+            let rt = make_time_shifted_runtime(now, rt);
+
+            // Build an ns consensus that lists our relay with a high weight,
+            // and another relay (whose descriptor we never get) with a very
+            // low one.
+            let one_day = Duration::new(86400, 0);
+            let flags = RelayFlags::RUNNING
+                | RelayFlags::VALID
+                | RelayFlags::FAST
+                | RelayFlags::STABLE
+                | RelayFlags::GUARD;
+            let mut bld = NsConsensus::builder();
+            bld.consensus_method(34)
+                .lifetime(Lifetime::new(now, now + one_day / 2, now + one_day).unwrap())
+                .param("bwweightscale", 1)
+                .weights("".parse().unwrap());
+            for (id, port, digest, weight) in [
+                (
+                    *rd.rsa_identity(),
+                    rd.or_ports().next().unwrap(),
+                    *rd.digest(),
+                    10000,
+                ),
+                (
+                    [9; 20].into(),
+                    "192.0.2.9:9001".parse().unwrap(),
+                    [9; 20],
+                    1,
+                ),
+            ] {
+                bld.rs()
+                    .identity(id)
+                    .add_or_port(port)
+                    .doc_digest(digest)
+                    .protos("".parse().unwrap())
+                    .set_flags(flags)
+                    .weight(RelayWeight::Measured(weight))
+                    .build_into(&mut bld)
+                    .unwrap();
+            }
+            let consensus = bld.testing_consensus().unwrap();
+            let meta = ConsensusMeta::new(consensus.lifetime().clone(), [0; 32], [0; 32]);
+
+            let mut cfg = (*make_dirmgr_config(None)).clone();
+            cfg.extensions.consensus_flavor = ConsensusFlavor::Ns;
+            let mut state = GetRouterDescsState::new(
+                CacheUsage::CacheOkay,
+                &consensus,
+                DocSource::LocalCache,
+                meta,
+                rt.clone(),
+                Arc::new(cfg),
+                None,
+                #[cfg(feature = "dirfilter")]
+                Arc::new(crate::filter::NilFilter),
+            );
+            let missing: HashSet<_> = state.missing_docs().into_iter().collect();
+            assert_eq!(
+                missing,
+                [DocId::RouterDesc([9; 20]), DocId::RouterDesc(*rd.digest())].into()
+            );
+            assert!(!state.is_ready(Readiness::Usable));
+
+            // Download our relay's descriptor, along with one that we didn't
+            // ask for.
+            let unwanted = include_str!("../testdata/routerdesc2.txt");
+            let text = format!("{}{}", ROUTERDESC, unwanted);
+            let req = ClientRequest::RouterDescs([*rd.digest()].into_iter().collect());
+            let source = DocSource::DirServer { source: None };
+            let (_tempdir, store) = temp_store();
+            let mut changed = false;
+            let outcome = state.add_from_download(&text, &req, source, Some(&store), &mut changed);
+            assert!(matches!(outcome, Err(Error::Unwanted(_))));
+            assert!(changed);
+            assert_eq!(state.missing_docs(), vec![DocId::RouterDesc([9; 20])]);
+            {
+                // We stored the descriptor that we wanted, and only that one.
+                let store = store.lock().unwrap();
+                let found = store.routerdescs(&[*rd.digest(), [9; 20]]).unwrap();
+                assert_eq!(found.len(), 1);
+                assert_eq!(found[rd.digest()].trim_end(), ROUTERDESC.trim_end());
+            }
+
+            // That's enough to build a directory, which gets the relay's
+            // details from its router descriptor.
+            let Some(NetDirChange::AttemptReplace { netdir, .. }) = state.get_netdir_change()
+            else {
+                panic!("no netdir");
+            };
+            let netdir = netdir.take().unwrap();
+            let relay = netdir.by_id(rd.rsa_identity()).unwrap();
+            assert_eq!(relay.ed_identity(), Some(rd.ed_identity()));
+            assert!(state.is_ready(Readiness::Usable));
+            assert!(!state.is_ready(Readiness::Complete));
+
+            // When we start over, we look for an ns consensus again.
+            let state = Box::new(state).reset();
+            assert!(matches!(
+                state.missing_docs()[0],
+                DocId::LatestConsensus {
+                    flavor: ConsensusFlavor::Ns,
+                    ..
+                }
+            ));
+        });
+    }
+
     #[test]
     fn get_microdescs_state() {
         tor_rtcompat::test_with_one_runtime!(|rt| async move {
@@ -1684,6 +2678,7 @@ mod test {
                 GetMicrodescsState::new(
                     CacheUsage::CacheOkay,
                     consensus,
+                    DocSource::LocalCache,
                     meta,
                     rt,
                     cfg,
@@ -1782,8 +2777,19 @@ mod test {
             assert!(outcome.is_ok()); // successfully loaded MDs
             assert!(changed);
             match state.get_netdir_change().unwrap() {
-                NetDirChange::AttemptReplace { netdir, .. } => {
+                NetDirChange::AttemptReplace {
+                    netdir, provenance, ..
+                } => {
                     assert!(netdir.take().is_some());
+                    // We remember where the consensus and each microdesc came from.
+                    assert!(matches!(
+                        provenance.consensus_source(),
+                        DocSource::LocalCache
+                    ));
+                    let md_sources: Vec<_> = provenance.microdesc_sources().collect();
+                    assert_eq!(md_sources.len(), 2);
+                    assert!(matches!(md_sources[0], (DocSource::LocalCache, 1)));
+                    assert!(matches!(md_sources[1], (DocSource::DirServer { .. }, 3)));
                 }
                 x => panic!("wrong netdir change: {:?}", x),
             }
@@ -1803,4 +2809,63 @@ mod test {
             assert!(missing.is_empty());
         });
     }
+
+    #[cfg(feature = "dirtiming")]
+    #[test]
+    fn get_microdescs_state_custom_timing() {
+        /// A timing policy that always replaces the consensus at the same time.
+        #[derive(Debug)]
+        struct FixedTiming(SystemTime);
+        impl crate::timing::DirTiming for FixedTiming {
+            fn consensus_download_time(&self, _lifetime: &Lifetime) -> SystemTime {
+                self.0
+            }
+        }
+
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            let replace_at: SystemTime = datetime!(2021-10-27 21:27:05 UTC).into();
+            let rt = make_time_shifted_runtime(test_time(), rt);
+            let mut cfg = (*make_dirmgr_config(Some(test_authorities()))).clone();
+            cfg.extensions.timing = Some(Arc::new(FixedTiming(replace_at)));
+            let (signed, rest, consensus) = MdConsensus::parse(CONSENSUS2).unwrap();
+            let consensus = consensus
+                .dangerously_assume_timely()
+                .dangerously_assume_wellsigned();
+            let meta = ConsensusMeta::from_consensus(signed, rest, &consensus);
+            let mut state = GetMicrodescsState::new(
+                CacheUsage::CacheOkay,
+                consensus,
+                DocSource::LocalCache,
+                meta,
+                rt,
+                Arc::new(cfg),
+                None,
+                #[cfg(feature = "dirfilter")]
+                Arc::new(crate::filter::NilFilter),
+            );
+
+            // Add all the microdescs, and take the resulting netdir.
+            let mut req = tor_dirclient::request::MicrodescRequest::new();
+            let mut response = "".to_owned();
+            for (md_digest, text) in microdescs() {
+                response.push_str(&text);
+                req.push(md_digest);
+            }
+            let req = ClientRequest::Microdescs(req);
+            let source = DocSource::DirServer { source: None };
+            let mut changed = false;
+            let outcome =
+                state.add_from_download(response.as_str(), &req, source, None, &mut changed);
+            assert!(outcome.is_ok());
+            match state.get_netdir_change().unwrap() {
+                NetDirChange::AttemptReplace { netdir, .. } => {
+                    assert!(netdir.take().is_some());
+                }
+                x => panic!("wrong netdir change: {:?}", x),
+            }
+
+            // Our policy decides when we replace it.
+            assert_eq!(state.reset_time(), Some(replace_at));
+        });
+    }
 }
@@ -0,0 +1,345 @@
+//! Support for using a fixed, caller-provided set of directory documents.
+//!
+//! In "static directory" mode, a [`DirMgr`](crate::DirMgr) never downloads
+//! anything.  Instead, it builds its [`NetDir`] from a consensus and a set of
+//! microdescriptors that the caller gave it in a [`StaticDirBundle`], and it
+//! keeps using that `NetDir` forever, no matter how old it gets.
+//!
+//! This is useful for reproducible integration tests, and for tools that want
+//! to analyze an archived consensus without touching the network.  It is not
+//! useful for anything that needs to build circuits on the live Tor network.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use tor_checkable::{ExternallySigned, SelfSigned, Timebound};
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdir::{MdReceiver, NetDir, PartialNetDir};
+use tor_netdoc::doc::authcert::AuthCert;
+use tor_netdoc::doc::microdesc::MicrodescReader;
+use tor_netdoc::doc::netstatus::MdConsensus;
+use tor_netdoc::AllowAnnotations;
+use tracing::warn;
+
+use crate::{DirMgrConfig, DocSource, Error, NetDirProvenance, Result};
+
+/// The name of the file from which [`StaticDirBundle::from_dir`] reads a
+/// consensus.
+pub(crate) const CONSENSUS_FILE: &str = "cached-microdesc-consensus";
+
+/// The names of the files from which [`StaticDirBundle::from_dir`] reads
+/// microdescriptors.
+pub(crate) const MICRODESC_FILES: &[&str] = &["cached-microdescs", "cached-microdescs.new"];
+
+/// The name of the file from which [`StaticDirBundle::from_dir`] reads
+/// authority certificates.
+pub(crate) const CERTS_FILE: &str = "cached-certs";
+
+/// A fixed set of directory documents from which to build a [`NetDir`].
+///
+/// Install one with
+/// [`DirMgrExtensions::static_bundle`](crate::config::DirMgrExtensions::static_bundle).
+#[derive(Clone, Debug)]
+pub struct StaticDirBundle {
+    /// The documents themselves.
+    ///
+    /// These are behind an `Arc` since they can be large, and since we clone
+    /// our configuration fairly often.
+    inner: Arc<BundleInner>,
+}
+
+/// The contents of a [`StaticDirBundle`].
+#[derive(Clone, Debug)]
+struct BundleInner {
+    /// The text of a microdescriptor consensus.
+    consensus: String,
+    /// Texts containing microdescriptors, possibly with annotations.
+    microdescs: Vec<String>,
+    /// Text containing authority certificates, if we have any.
+    authcerts: Option<String>,
+}
+
+impl StaticDirBundle {
+    /// Construct a new `StaticDirBundle` from the text of a microdescriptor
+    /// consensus, and any number of texts containing microdescriptors.
+    ///
+    /// Each microdescriptor text may contain any number of microdescriptors,
+    /// with or without annotations.
+    ///
+    /// Without authority certificates, we don't check the consensus's
+    /// signatures; see [`StaticDirBundle::with_authority_certs`].
+    pub fn from_texts<I, S>(consensus: impl Into<String>, microdescs: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        StaticDirBundle {
+            inner: Arc::new(BundleInner {
+                consensus: consensus.into(),
+                microdescs: microdescs.into_iter().map(Into::into).collect(),
+                authcerts: None,
+            }),
+        }
+    }
+
+    /// Return a copy of this bundle that also contains the authority
+    /// certificates in `certs`.
+    ///
+    /// When a bundle has certificates, we use them to check the consensus's
+    /// signatures, and refuse to use a consensus that is not properly signed
+    /// by our configured authorities.  We don't check whether the
+    /// certificates (or the consensus) have expired.
+    pub fn with_authority_certs(self, certs: impl Into<String>) -> Self {
+        let BundleInner {
+            consensus,
+            microdescs,
+            ..
+        } = Arc::unwrap_or_clone(self.inner);
+        StaticDirBundle {
+            inner: Arc::new(BundleInner {
+                consensus,
+                microdescs,
+                authcerts: Some(certs.into()),
+            }),
+        }
+    }
+
+    /// Load a `StaticDirBundle` from the files in the directory `dir`.
+    ///
+    /// The files use the same names as in a C Tor data directory:
+    /// `cached-microdesc-consensus` holds the consensus, and must be present;
+    /// `cached-microdescs` and `cached-microdescs.new` hold microdescriptors;
+    /// and `cached-certs`, if present, holds authority certificates.
+    pub fn from_dir(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let read_if_present = |name: &str| {
+            let fname = dir.join(name);
+            match std::fs::read_to_string(&fname) {
+                Ok(text) => Ok(Some(text)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(error) => Err(Error::CacheFile {
+                    action: "reading",
+                    fname,
+                    error: Arc::new(error),
+                }),
+            }
+        };
+        let read = |name: &str| {
+            read_if_present(name)?.ok_or_else(|| Error::CacheFile {
+                action: "reading",
+                fname: dir.join(name),
+                error: Arc::new(std::io::ErrorKind::NotFound.into()),
+            })
+        };
+
+        let consensus = read(CONSENSUS_FILE)?;
+        let mut microdescs = Vec::new();
+        for name in MICRODESC_FILES {
+            microdescs.extend(read_if_present(name)?);
+        }
+        let bundle = StaticDirBundle::from_texts(consensus, microdescs);
+        Ok(match read_if_present(CERTS_FILE)? {
+            Some(certs) => bundle.with_authority_certs(certs),
+            None => bundle,
+        })
+    }
+
+    /// Build a [`NetDir`] from this bundle, using the authorities and
+    /// parameter overrides in `config`.
+    ///
+    /// Return an error if the documents can't be parsed, if the consensus
+    /// isn't from our authorities, or if we don't have enough
+    /// microdescriptors to build a usable directory.
+    pub(crate) fn build_netdir(&self, config: &DirMgrConfig) -> Result<(NetDir, NetDirProvenance)> {
+        let source = DocSource::StaticBundle;
+        let (_, _, unchecked) = MdConsensus::parse(&self.inner.consensus)
+            .map_err(|e| Error::from_netdoc(source.clone(), e))?;
+
+        // We never expire anything in static mode.
+        let unvalidated = unchecked.dangerously_assume_timely();
+        let authority_ids: Vec<&RsaIdentity> = config
+            .authorities()
+            .iter()
+            .map(|auth| &auth.v3ident)
+            .collect();
+        let n_authorities = u16::try_from(authority_ids.len())
+            .map_err(|_| Error::StaticBundle("too many authorities"))?;
+        let unvalidated = unvalidated.set_n_authorities(n_authorities);
+        if !unvalidated.authorities_are_correct(&authority_ids) {
+            return Err(Error::UnrecognizedAuthorities);
+        }
+
+        let consensus = match &self.inner.authcerts {
+            Some(text) => {
+                let mut certs = Vec::new();
+                for cert in AuthCert::parse_multiple(text) {
+                    let cert = cert.map_err(|e| Error::from_netdoc(source.clone(), e))?;
+                    certs.push(cert.check_signature()?.dangerously_assume_timely());
+                }
+                unvalidated.check_signature(&certs[..]).map_err(|cause| {
+                    Error::ConsensusInvalid {
+                        source: source.clone(),
+                        cause,
+                    }
+                })?
+            }
+            None => {
+                warn!("Using a static directory bundle without checking its signatures.");
+                unvalidated.dangerously_assume_wellsigned()
+            }
+        };
+
+        let mut partial = PartialNetDir::new(consensus, Some(&config.override_net_params));
+        let mut n_microdescs = 0;
+        for text in &self.inner.microdescs {
+            for annotated in MicrodescReader::new(text, &AllowAnnotations::AnnotationsAllowed) {
+                let md = annotated
+                    .map_err(|e| Error::from_netdoc(source.clone(), e))?
+                    .into_microdesc();
+                if partial.add_microdesc(md) {
+                    n_microdescs += 1;
+                }
+            }
+        }
+        let netdir = partial.unwrap_if_sufficient().map_err(|_| {
+            Error::StaticBundle("not enough microdescriptors to build a usable directory")
+        })?;
+
+        let mut provenance = NetDirProvenance::new(source.clone());
+        provenance.note_microdescs(&source, n_microdescs);
+        Ok((netdir, provenance))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::{Authority, NetworkConfig};
+
+    /// A consensus for which we have authority certificates.
+    const CONSENSUS: &str = include_str!("../testdata/mdconsensus1.txt");
+    /// A consensus for which we have microdescriptors.
+    pub(crate) const CONSENSUS2: &str = include_str!("../testdata/mdconsensus2.txt");
+    pub(crate) const MICRODESCS: &str = include_str!("../testdata/microdescs.txt");
+    const AUTHCERT_5696: &str = include_str!("../testdata/cert-5696.txt");
+    const AUTHCERT_5A23: &str = include_str!("../testdata/cert-5A23.txt");
+
+    /// Return a configuration that recognizes the authorities with the
+    /// identities in `ids`.
+    fn config_with_authorities(ids: &[&str]) -> DirMgrConfig {
+        let mut netcfg = NetworkConfig::builder();
+        netcfg.set_fallback_caches(vec![]);
+        netcfg.set_authorities(
+            ids.iter()
+                .map(|id| {
+                    Authority::builder()
+                        .name("ignore")
+                        .v3ident(RsaIdentity::from_hex(id).unwrap())
+                        .clone()
+                })
+                .collect(),
+        );
+        DirMgrConfig {
+            cache_dir: "/we_will_never_use_this/".into(),
+            network: netcfg.build().unwrap(),
+            ..Default::default()
+        }
+    }
+
+    /// Return a configuration that recognizes the authorities that signed
+    /// `CONSENSUS2`.
+    pub(crate) fn config2() -> DirMgrConfig {
+        config_with_authorities(&[
+            "17447C92250D02BF17EA127F4BFED73EDC351025",
+            "80091EF12DCDF803E87AE7114E77E05C3FC3A61D",
+            "FB73296E2241B835A3ACBD4D2FC58A1864362779",
+        ])
+    }
+
+    #[test]
+    fn unsigned() {
+        let bundle = StaticDirBundle::from_texts(CONSENSUS2, [MICRODESCS]);
+        let (netdir, provenance) = bundle.build_netdir(&config2()).unwrap();
+        assert_eq!(netdir.relays().count(), 4);
+        assert!(matches!(
+            provenance.consensus_source(),
+            DocSource::StaticBundle
+        ));
+        let sources: Vec<_> = provenance.microdesc_sources().collect();
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].1, 4);
+
+        // Without the microdescriptors, the directory isn't usable.
+        let bundle = StaticDirBundle::from_texts(CONSENSUS2, Vec::<String>::new());
+        assert!(matches!(
+            bundle.build_netdir(&config2()),
+            Err(Error::StaticBundle(_))
+        ));
+
+        // With the default authorities, the consensus isn't acceptable.
+        let bundle = StaticDirBundle::from_texts(CONSENSUS2, [MICRODESCS]);
+        assert!(matches!(
+            bundle.build_netdir(&DirMgrConfig::default()),
+            Err(Error::UnrecognizedAuthorities)
+        ));
+    }
+
+    #[test]
+    fn signed() {
+        let config = config_with_authorities(&[
+            "5696AB38CB3852AFA476A5C07B2D4788963D5567",
+            "5A23BA701776C9C1AB1C06E734E92AB3D5350D64",
+        ]);
+
+        // We have no microdescriptors for this consensus, so we can't build a
+        // directory from it; but we get far enough to check its signatures.
+        let certs = format!("{}{}", AUTHCERT_5696, AUTHCERT_5A23);
+        let bundle =
+            StaticDirBundle::from_texts(CONSENSUS, [MICRODESCS]).with_authority_certs(certs);
+        assert!(matches!(
+            bundle.build_netdir(&config),
+            Err(Error::StaticBundle(_))
+        ));
+
+        // With only one of the two certificates, we can't validate it.
+        let bundle = StaticDirBundle::from_texts(CONSENSUS, [MICRODESCS])
+            .with_authority_certs(AUTHCERT_5696);
+        assert!(matches!(
+            bundle.build_netdir(&config),
+            Err(Error::ConsensusInvalid { .. })
+        ));
+    }
+
+    #[test]
+    fn from_dir() {
+        let dir = tempfile::TempDir::new().unwrap();
+        assert!(matches!(
+            StaticDirBundle::from_dir(dir.path()),
+            Err(Error::CacheFile { .. })
+        ));
+
+        std::fs::write(dir.path().join(CONSENSUS_FILE), CONSENSUS2).unwrap();
+        std::fs::write(dir.path().join("cached-microdescs.new"), MICRODESCS).unwrap();
+        let bundle = StaticDirBundle::from_dir(dir.path()).unwrap();
+        assert_eq!(bundle.inner.microdescs.len(), 1);
+        assert!(bundle.inner.authcerts.is_none());
+        bundle.build_netdir(&config2()).unwrap();
+
+        std::fs::write(dir.path().join(CERTS_FILE), AUTHCERT_5696).unwrap();
+        let bundle = StaticDirBundle::from_dir(dir.path()).unwrap();
+        assert!(bundle.inner.authcerts.is_some());
+    }
+}
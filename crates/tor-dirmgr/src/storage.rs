@@ -6,6 +6,7 @@
 // storage: Search the git history for tor-dirmgr/src/storage/legacy.rs
 // if you ever need to reinstate it.)
 
+use tor_llcrypto::pk::rsa::RsaIdentity;
 use tor_netdoc::doc::authcert::AuthCertKeyIds;
 use tor_netdoc::doc::microdesc::MdDigest;
 use tor_netdoc::doc::netstatus::ConsensusFlavor;
@@ -17,6 +18,7 @@ use tor_netdoc::doc::routerdesc::RdDigest;
 pub(crate) use tor_guardmgr::bridge::BridgeConfig;
 
 use crate::docmeta::{AuthCertMeta, ConsensusMeta};
+use crate::mdprogress::MdDownloadProgress;
 use crate::{Error, Result};
 use std::cell::RefCell;
 use std::collections::HashMap;
@@ -27,8 +29,11 @@ use std::time::SystemTime;
 use time::Duration;
 
 pub(crate) mod sqlite;
+mod timed;
 
 pub(crate) use sqlite::SqliteStore;
+pub(crate) use timed::TimedStore;
+pub use timed::{OpLatency, StoreLatencyReport, StoreTimingConfig};
 
 /// Convenient Sized & dynamic [`Store`]
 pub(crate) type DynStore = Box<dyn Store>;
@@ -205,6 +210,29 @@ pub(crate) struct ExpirationConfig {
     pub(super) authcerts: Duration,
     /// How long to keep expired consensus.
     pub(super) consensuses: Duration,
+    /// How many of our latest usable consensuses of each flavor to keep,
+    /// however old they are.
+    ///
+    /// We also keep every microdescriptor that any of these consensuses
+    /// might list.  See
+    /// [`DirMgrExtensions::archived_consensuses`](crate::config::DirMgrExtensions::archived_consensuses).
+    pub(super) archived_consensuses: usize,
+    /// If present, the largest size in bytes that we try to keep our cache
+    /// under.
+    ///
+    /// See [`DirMgrExtensions::max_cache_size`](crate::config::DirMgrExtensions::max_cache_size).
+    pub(super) max_cache_size: Option<u64>,
+}
+
+impl ExpirationConfig {
+    /// Return the expiration configuration to use with `config`.
+    pub(crate) fn from_config(config: &crate::DirMgrConfig) -> Self {
+        ExpirationConfig {
+            archived_consensuses: config.extensions.archived_consensuses,
+            max_cache_size: config.extensions.max_cache_size,
+            ..EXPIRATION_DEFAULTS
+        }
+    }
 }
 
 /// Configuration of expiration shared between [`Store`] implementations.
@@ -227,6 +255,8 @@ pub(crate) const EXPIRATION_DEFAULTS: ExpirationConfig = {
         microdescs: Duration::days(7),
         authcerts: Duration::ZERO,
         consensuses: Duration::days(2),
+        archived_consensuses: 0,
+        max_cache_size: None,
     }
 };
 
@@ -241,6 +271,13 @@ pub(crate) trait Store: Send + 'static {
     ///
     /// Return true on success; false if another process had the lock.
     fn upgrade_to_readwrite(&mut self) -> Result<bool>;
+    /// If this [`Store`] reads from an immutable image, reopen that image, so
+    /// that we see any new image that has replaced it.
+    ///
+    /// Does nothing for other stores.
+    fn reopen_immutable_image(&mut self) -> Result<()> {
+        Ok(())
+    }
 
     /// Delete all completely-expired objects from the database.
     ///
@@ -265,6 +302,16 @@ pub(crate) trait Store: Send + 'static {
     /// Try to read the consensus corresponding to the provided metadata object.
     #[cfg(test)]
     fn consensus_by_meta(&self, cmeta: &ConsensusMeta) -> Result<InputString>;
+    /// Try to read the latest usable consensus of `flavor` that was valid
+    /// at `when`, and its metadata.
+    ///
+    /// Unless we are keeping an archive of old consensuses, we only have the
+    /// last few days' worth.
+    fn consensus_valid_at(
+        &self,
+        flavor: ConsensusFlavor,
+        when: SystemTime,
+    ) -> Result<Option<(InputString, ConsensusMeta)>>;
     /// Try to read the consensus whose SHA3-256 digests is the provided
     /// value, and its metadata.
     fn consensus_by_sha3_digest_of_signed_part(
@@ -287,6 +334,49 @@ pub(crate) trait Store: Send + 'static {
     #[allow(dead_code)] // see also allow on REMOVE_CONSENSUS
     fn delete_consensus(&mut self, cmeta: &ConsensusMeta) -> Result<()>;
 
+    /// Read the diff from the consensus whose signed part has SHA3-256 digest
+    /// `from` to the one whose signed part has digest `to`, if we have stored
+    /// it.
+    fn consensus_diff(&self, from: &[u8; 32], to: &[u8; 32]) -> Result<Option<String>>;
+    /// Store a diff between two consensuses, named as in
+    /// [`consensus_diff`](Store::consensus_diff).
+    ///
+    /// The diff will be deleted some time after `expires`.
+    fn store_consensus_diff(
+        &mut self,
+        from: &[u8; 32],
+        to: &[u8; 32],
+        expires: SystemTime,
+        diff: &str,
+    ) -> Result<()>;
+
+    /// Read the latest bandwidth file that we have stored, if any.
+    fn latest_bandwidth_file(&self) -> Result<Option<String>>;
+    /// Store `text` as our latest bandwidth file, replacing any earlier one.
+    ///
+    /// `timestamp` is the time at which the file says it was made.
+    fn store_bandwidth_file(&mut self, timestamp: SystemTime, text: &str) -> Result<()>;
+
+    /// Read the votes that we have stored from the latest voting round.
+    fn latest_votes(&self) -> Result<Vec<String>>;
+    /// Store `text` as the vote that the authority with identity `authority`
+    /// cast for the consensus that becomes valid at `valid_after`.
+    ///
+    /// Votes from earlier voting rounds are discarded.
+    fn store_vote(
+        &mut self,
+        authority: &RsaIdentity,
+        valid_after: SystemTime,
+        text: &str,
+    ) -> Result<()>;
+    /// Read the latest detached signatures that we have stored, if any.
+    fn latest_detached_signatures(&self) -> Result<Option<String>>;
+    /// Store `text` as our latest detached signatures, replacing any earlier
+    /// ones.
+    ///
+    /// `valid_after` is the start of the lifetime of the signed consensus.
+    fn store_detached_signatures(&mut self, valid_after: SystemTime, text: &str) -> Result<()>;
+
     /// Read all of the specified authority certs from the cache.
     fn authcerts(&self, certs: &[AuthCertKeyIds]) -> Result<HashMap<AuthCertKeyIds, String>>;
     /// Save a list of authority certificates to the cache.
@@ -300,6 +390,13 @@ pub(crate) trait Store: Send + 'static {
     /// Update the `last-listed` time of every microdescriptor in
     /// `input` to `when` or later.
     fn update_microdescs_listed(&mut self, digests: &[MdDigest], when: SystemTime) -> Result<()>;
+    /// Return the microdescriptor download progress that we saved for the
+    /// consensus whose signed part has the SHA3-256 digest `consensus`, if
+    /// any.
+    fn md_download_progress(&self, consensus: &[u8; 32]) -> Result<Option<MdDownloadProgress>>;
+    /// Save `progress`, replacing any microdescriptor download progress that
+    /// we saved before.
+    fn store_md_download_progress(&mut self, progress: &MdDownloadProgress) -> Result<()>;
 
     /// Read all the microdescriptors listed in `input` from the cache.
     ///
@@ -335,6 +432,102 @@ pub(crate) trait Store: Send + 'static {
     // Nothing uses this yet; removal is handled from `expire_all`.
     #[allow(dead_code)] // see also allow on DELETE_BRIDGEDESC
     fn delete_bridgedesc(&mut self, bridge: &BridgeConfig) -> Result<()>;
+
+    /// Return a summary of how long our operations have taken, if we are
+    /// measuring that.
+    fn latency_report(&self) -> Option<StoreLatencyReport> {
+        None
+    }
+
+    /// Return a summary of what we repaired in this store when we opened it
+    /// read-write, if we have done so.
+    fn repair_report(&self) -> Option<CacheRepairReport> {
+        None
+    }
+
+    /// Return a summary of how much space this store is using.
+    fn cache_usage(&self) -> Result<CacheSizeReport>;
+}
+
+/// A summary of how much space our directory cache is using.
+///
+/// Returned by [`DirMgr::cache_usage`](crate::DirMgr::cache_usage).
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct CacheSizeReport {
+    /// The size of the database file, in bytes.
+    pub database_bytes: u64,
+    /// The number of bytes in the database file that hold no data, and that
+    /// we will give back the next time we compact the database.
+    pub reclaimable_bytes: u64,
+    /// The total size of the files that we store outside the database (such
+    /// as consensuses), in bytes.
+    pub blob_bytes: u64,
+    /// The number of microdescriptors in the cache.
+    pub microdescs: usize,
+    /// The number of router descriptors in the cache.
+    pub routerdescs: usize,
+    /// The number of consensuses in the cache, pending or not.
+    pub consensuses: usize,
+    /// The number of microdescriptors that we have evicted to stay under
+    /// our size limit since we opened the cache.
+    pub evicted_microdescs: usize,
+    /// The number of router descriptors that we have evicted to stay under
+    /// our size limit since we opened the cache.
+    pub evicted_routerdescs: usize,
+    /// The size limit that we are trying to stay under, if any.
+    pub max_size: Option<u64>,
+}
+
+impl CacheSizeReport {
+    /// Return the number of bytes that hold data in the cache: that is,
+    /// everything except the reclaimable part of the database.
+    ///
+    /// This is the value that we compare against our size limit.
+    pub fn used_bytes(&self) -> u64 {
+        // (A stale reading can count more free pages than the database has.)
+        self.database_bytes
+            .saturating_sub(self.reclaimable_bytes)
+            .saturating_add(self.blob_bytes)
+    }
+
+    /// Return true if the cache is larger than our size limit.
+    ///
+    /// This can happen if everything in the cache is still needed, or if we
+    /// haven't yet had a chance to evict anything.
+    pub fn is_over_budget(&self) -> bool {
+        self.max_size.is_some_and(|max| self.used_bytes() > max)
+    }
+}
+
+/// A summary of the damage that we found and repaired in our directory cache
+/// when we opened it.
+///
+/// If a process stops while it is writing to the cache (for example, because
+/// the computer lost power), the cache can be left with entries that refer to
+/// missing files, or files that nothing refers to.  We remove these when we
+/// next open the cache for writing.
+///
+/// Returned by [`DirMgr::cache_repairs`](crate::DirMgr::cache_repairs).
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct CacheRepairReport {
+    /// The number of consensuses we discarded because their documents were
+    /// missing.
+    pub consensuses_without_blobs: usize,
+    /// The number of stored-document entries we discarded because their files
+    /// were missing.
+    pub missing_blobs: usize,
+    /// The number of document files we removed because nothing referred to
+    /// them.
+    pub orphaned_blobs: usize,
+}
+
+impl CacheRepairReport {
+    /// Return true if we didn't need to repair anything.
+    pub fn is_empty(&self) -> bool {
+        self == &CacheRepairReport::default()
+    }
 }
 
 /// Value in the bridge descriptor cache
@@ -366,6 +559,26 @@ mod test {
     use super::*;
     use tempfile::tempdir;
 
+    #[test]
+    fn cache_size_report() {
+        let report = CacheSizeReport {
+            database_bytes: 1000,
+            reclaimable_bytes: 200,
+            blob_bytes: 50,
+            max_size: Some(800),
+            ..Default::default()
+        };
+        assert_eq!(report.used_bytes(), 850);
+        assert!(report.is_over_budget());
+
+        let stale = CacheSizeReport {
+            reclaimable_bytes: 2000,
+            ..report
+        };
+        assert_eq!(stale.used_bytes(), 50);
+        assert!(!stale.is_over_budget());
+    }
+
     #[test]
     fn strings() {
         let s: InputString = "Hello world".to_string().into();
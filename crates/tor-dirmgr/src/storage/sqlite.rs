@@ -6,12 +6,14 @@
 use super::ExpirationConfig;
 use crate::docmeta::{AuthCertMeta, ConsensusMeta};
 use crate::err::ReadOnlyStorageError;
-use crate::storage::{InputString, Store};
+use crate::mdprogress::{MdBatch, MdDownloadProgress};
+use crate::storage::{CacheRepairReport, CacheSizeReport, InputString, Store};
 use crate::{Error, Result};
 
 use fs_mistrust::CheckedDir;
 use tor_basic_utils::PathExt as _;
-use tor_error::warn_report;
+use tor_error::{bad_api_usage, internal, warn_report};
+use tor_llcrypto::pk::rsa::RsaIdentity;
 use tor_netdoc::doc::authcert::AuthCertKeyIds;
 use tor_netdoc::doc::microdesc::MdDigest;
 use tor_netdoc::doc::netstatus::{ConsensusFlavor, Lifetime};
@@ -25,11 +27,11 @@ use std::collections::HashMap;
 use std::fs::OpenOptions;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
 use rusqlite::{params, OpenFlags, OptionalExtension, Transaction};
 use time::OffsetDateTime;
-use tracing::{trace, warn};
+use tracing::{info, trace, warn};
 
 /// Local directory cache using a Sqlite3 connection.
 pub(crate) struct SqliteStore {
@@ -47,8 +49,44 @@ pub(crate) struct SqliteStore {
     /// (sqlite supports that with connection locking, but we want to
     /// be a little more coarse-grained here)
     lockfile: Option<fslock::LockFile>,
+    /// What we repaired in this cache when we opened it read-write, if we
+    /// have done so.
+    repair_report: Option<CacheRepairReport>,
+    /// True if this store is an immutable image that we must never modify.
+    ///
+    /// (See [`SqliteStore::from_immutable_path_and_mistrust`].)
+    immutable: bool,
+    /// The number of microdescriptors that we have evicted to stay under our
+    /// size limit.
+    n_evicted_mds: usize,
+    /// The number of router descriptors that we have evicted to stay under
+    /// our size limit.
+    n_evicted_rds: usize,
+    /// When we last compacted the database, if we have done so.
+    last_vacuum: Option<Instant>,
 }
 
+/// How long before the latest consensus a router descriptor must have been
+/// published for us to be sure that the consensus doesn't list it.
+///
+/// Relays publish a new descriptor at least every 18 hours, and the
+/// authorities won't list a relay whose latest descriptor is more than a
+/// day old.
+const LISTED_ROUTERDESC_MAX_AGE: time::Duration = time::Duration::days(1);
+
+/// How long we wait after compacting the database before we compact it again.
+///
+/// Compacting rewrites the whole database, which is slow for a large cache:
+/// we don't want to do it every time that we expire documents.
+const MIN_VACUUM_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// How old an unreferenced blob must be before we treat it as left over from
+/// a partial write.
+///
+/// We write a blob before we record it in the database, so a recent blob that
+/// nothing refers to yet may belong to a write that is still in progress.
+const ORPHANED_BLOB_MIN_AGE: time::Duration = time::Duration::hours(1);
+
 impl SqliteStore {
     /// Construct or open a new SqliteStore at some location on disk.
     /// The provided location must be a directory, or a possible
@@ -112,9 +150,68 @@ impl SqliteStore {
         let mut store = SqliteStore::from_conn_internal(conn, blob_dir, readonly)?;
         store.sql_path = Some(sqlpath);
         store.lockfile = Some(lockfile);
+        if !readonly {
+            store.note_repairs();
+        }
+        Ok(store)
+    }
+
+    /// Open an existing SqliteStore at some location on disk, as an
+    /// immutable image.
+    ///
+    /// Unlike a read-only store from
+    /// [`from_path_and_mistrust`](SqliteStore::from_path_and_mistrust), this
+    /// never creates or modifies anything under `path`, not even the
+    /// lockfile, and so it works on a read-only filesystem.  We tell SQLite
+    /// that the database will not change while we have it open, so it does no
+    /// locking either.  The resulting store can never be upgraded to
+    /// read-write, and every attempt to modify it fails with
+    /// [`ReadOnlyStorageError::Immutable`].
+    ///
+    /// To pick up a new image, replace the old one (for example, with an atomic
+    /// rename of its parent directory) and call
+    /// [`reopen_immutable_image`](Store::reopen_immutable_image).
+    pub(crate) fn from_immutable_path_and_mistrust<P: AsRef<Path>>(
+        path: P,
+        mistrust: &fs_mistrust::Mistrust,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let sqlpath = path.join("dir.sqlite3");
+        let blobpath = path.join("dir_blobs/");
+
+        let verifier = mistrust.verifier().permit_readable().check_content();
+        let blob_dir = verifier.secure_dir(blobpath)?;
+        match mistrust
+            .verifier()
+            .permit_readable()
+            .require_file()
+            .check(&sqlpath)
+        {
+            Ok(()) => {}
+            Err(fs_mistrust::Error::NotFound(_)) => {
+                return Err(Error::ReadOnlyStorage(ReadOnlyStorageError::NoDatabase))
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let conn = open_immutable_db(&sqlpath)?;
+        let mut store = SqliteStore::from_conn_internal(conn, blob_dir, true)?;
+        store.sql_path = Some(sqlpath);
+        store.immutable = true;
         Ok(store)
     }
 
+    /// Return an error if this store is an immutable image.
+    ///
+    /// Every method that modifies the store calls this first, so that we
+    /// report a clear error rather than whatever SQLite tells us.
+    fn check_mutable(&self) -> Result<()> {
+        if self.immutable {
+            return Err(Error::ReadOnlyStorage(ReadOnlyStorageError::Immutable));
+        }
+        Ok(())
+    }
+
     /// Construct a new SqliteStore from a database connection and a location
     /// for blob files.
     ///
@@ -145,6 +242,11 @@ impl SqliteStore {
             blob_dir,
             lockfile: None,
             sql_path: None,
+            repair_report: None,
+            immutable: false,
+            n_evicted_mds: 0,
+            n_evicted_rds: 0,
+            last_vacuum: None,
         };
 
         result.check_schema(readonly)?;
@@ -340,22 +442,37 @@ impl SqliteStore {
         expiration: &ExpirationConfig,
     ) -> Result<()> {
         // Now, look for any unreferenced blobs that are a bit old.
+        let _n_removed =
+            self.remove_unreferenced_blobs_older_than(Some(now - expiration.consensuses))?;
+        Ok(())
+    }
+
+    /// Delete any blob files that are not mentioned in the ExtDocs table, and
+    /// that were last modified before `cutoff` (if it is provided).
+    ///
+    /// Return the number of files that we removed.
+    fn remove_unreferenced_blobs_older_than(
+        &self,
+        cutoff: Option<OffsetDateTime>,
+    ) -> Result<usize> {
+        let mut n_removed = 0;
         for ent in self.blob_dir.read_directory(".")?.flatten() {
-            let md_error = |io_error| Error::CacheFile {
-                action: "getting metadata",
-                fname: ent.file_name().into(),
-                error: Arc::new(io_error),
-            };
-            if ent
-                .metadata()
-                .map_err(md_error)?
-                .modified()
-                .map_err(md_error)?
-                + expiration.consensuses
-                >= now
-            {
-                // this file is sufficiently recent that we should not remove it, just to be cautious.
-                continue;
+            if let Some(cutoff) = cutoff {
+                let md_error = |io_error| Error::CacheFile {
+                    action: "getting metadata",
+                    fname: ent.file_name().into(),
+                    error: Arc::new(io_error),
+                };
+                if OffsetDateTime::from(
+                    ent.metadata()
+                        .map_err(md_error)?
+                        .modified()
+                        .map_err(md_error)?,
+                ) >= cutoff
+                {
+                    // this file is sufficiently recent that we should not remove it, just to be cautious.
+                    continue;
+                }
             }
             let filename = match ent.file_name().into_string() {
                 Ok(s) => s,
@@ -366,6 +483,7 @@ impl SqliteStore {
                         os_str.to_string_lossy()
                     );
                     self.remove_blob_or_warn(ent.file_name());
+                    n_removed += 1;
                     continue;
                 }
             };
@@ -377,21 +495,167 @@ impl SqliteStore {
             if found == (0,) {
                 warn!("Removing unreferenced file '{}' from blob store", &filename);
                 self.remove_blob_or_warn(ent.file_name());
+                n_removed += 1;
+            }
+        }
+
+        Ok(n_removed)
+    }
+
+    /// Look for anything left half-stored by a process that crashed (or lost
+    /// power) while writing to this cache, and remove it.
+    ///
+    /// We look for:
+    ///  * ExtDocs entries whose blob files are missing,
+    ///    along with any consensus (pending or not) that refers to them;
+    ///  * consensus entries whose ExtDocs entries are missing;
+    ///  * blob files that no ExtDocs entry refers to, and which are older than
+    ///    [`ORPHANED_BLOB_MIN_AGE`].
+    ///
+    /// (Microdescriptors, router descriptors, and authority certificates are
+    /// stored inline in their tables, so they can't be left half-written.)
+    ///
+    /// Only call this while we hold the lock: otherwise, we might delete blobs
+    /// that another process is in the middle of storing.
+    fn repair_partial_writes(&mut self) -> Result<CacheRepairReport> {
+        let mut report = CacheRepairReport::default();
+
+        let tx = self.conn.transaction()?;
+        let mut stmt = tx.prepare(FIND_ALL_EXTDOC_FILENAMES)?;
+        let filenames: Vec<String> = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<std::result::Result<_, _>>()?;
+        drop(stmt);
+        for fname in filenames {
+            // If we can't tell whether the blob is there, leave it alone.
+            let present = self.blob_dir.join(&fname)?.try_exists().unwrap_or(true);
+            if !present {
+                report.consensuses_without_blobs +=
+                    tx.execute(DELETE_CONSENSUSES_BY_EXTDOC_FILENAME, params![fname])?;
+                report.missing_blobs += tx.execute(DELETE_EXTDOC_BY_FILENAME, params![fname])?;
+            }
+        }
+        report.consensuses_without_blobs += tx.execute(DELETE_CONSENSUSES_WITHOUT_EXTDOCS, [])?;
+        tx.commit()?;
+
+        let cutoff = OffsetDateTime::now_utc() - ORPHANED_BLOB_MIN_AGE;
+        report.orphaned_blobs = self.remove_unreferenced_blobs_older_than(Some(cutoff))?;
+
+        Ok(report)
+    }
+
+    /// Run [`repair_partial_writes`](Self::repair_partial_writes), and
+    /// remember what it did.
+    ///
+    /// A failure here only means that we might find more trouble later on, so
+    /// we log it rather than returning it.
+    fn note_repairs(&mut self) {
+        match self.repair_partial_writes() {
+            Ok(report) => {
+                if !report.is_empty() {
+                    info!("Repaired partially written directory cache: {:?}", report);
+                }
+                self.repair_report = Some(report);
+            }
+            Err(e) => warn_report!(e, "Unable to check directory cache for partial writes"),
+        }
+    }
+
+    /// Return the total size, in bytes, of the files in our blob directory.
+    fn blob_bytes(&self) -> Result<u64> {
+        let mut total = 0;
+        for ent in self.blob_dir.read_directory(".")?.flatten() {
+            // A file that vanished or that we can't examine takes no space
+            // that we can do anything about.
+            if let Ok(meta) = ent.metadata() {
+                total += meta.len();
+            }
+        }
+        Ok(total)
+    }
+
+    /// Evict microdescriptors and router descriptors that the latest
+    /// consensus can't list, oldest first, until this store uses no more
+    /// than `max_size` bytes (or we run out of things to evict).
+    ///
+    /// Afterwards, compact the database if we evicted anything, or if much
+    /// of it is free space, unless we compacted it recently.
+    fn enforce_size_limit(&mut self, max_size: u64) -> Result<()> {
+        let usage = self.cache_usage()?;
+        let excess = usage.used_bytes().saturating_sub(max_size);
+
+        let (mut n_mds, mut n_rds) = (0, 0);
+        if excess > 0 {
+            let tx = self.conn.transaction()?;
+            let latest: Option<OffsetDateTime> =
+                tx.query_row(FIND_LATEST_VALID_AFTER, [], |row| row.get(0))?;
+            // With no consensus at all, nothing is listed.
+            let latest = latest.unwrap_or_else(OffsetDateTime::now_utc);
+            let candidates: Vec<(String, String, u64)> = {
+                let mut stmt = tx.prepare(FIND_EVICTION_CANDIDATES)?;
+                let rows = stmt
+                    .query_map(params![latest, latest - LISTED_ROUTERDESC_MAX_AGE], |row| {
+                        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                    })?
+                    .collect::<std::result::Result<_, _>>()?;
+                rows
+            };
+            let mut freed = 0;
+            for (kind, digest, size) in candidates {
+                if freed >= excess {
+                    break;
+                }
+                if kind == "md" {
+                    tx.execute(DELETE_MD, params![digest])?;
+                    n_mds += 1;
+                } else {
+                    tx.execute(DELETE_RD, params![digest])?;
+                    n_rds += 1;
+                }
+                freed += size;
+            }
+            tx.commit()?;
+            self.n_evicted_mds += n_mds;
+            self.n_evicted_rds += n_rds;
+            info!(
+                "Directory cache is over its size limit: evicted {} microdescriptors and {} router descriptors.",
+                n_mds, n_rds
+            );
+            if freed < excess {
+                warn!(
+                    "Directory cache is still over its size limit, but everything in it is in use."
+                );
             }
         }
 
+        let want_vacuum = n_mds + n_rds > 0 || usage.reclaimable_bytes > usage.database_bytes / 4;
+        let vacuumed_recently = match self.last_vacuum {
+            Some(when) => when.elapsed() < MIN_VACUUM_INTERVAL,
+            None => false,
+        };
+        if want_vacuum && !vacuumed_recently {
+            self.conn.execute_batch("VACUUM;")?;
+            self.last_vacuum = Some(Instant::now());
+        }
         Ok(())
     }
 }
 
 impl Store for SqliteStore {
     fn is_readonly(&self) -> bool {
+        if self.immutable {
+            return true;
+        }
         match &self.lockfile {
             Some(f) => !f.owns_lock(),
             None => false,
         }
     }
     fn upgrade_to_readwrite(&mut self) -> Result<bool> {
+        if self.immutable {
+            // Nobody will ever write to this cache.
+            return Ok(false);
+        }
         if self.is_readonly() && self.sql_path.is_some() {
             let lf = self
                 .lockfile
@@ -412,10 +676,32 @@ impl Store for SqliteStore {
                     return Err(e.into());
                 }
             }
+            self.note_repairs();
         }
         Ok(true)
     }
+    fn reopen_immutable_image(&mut self) -> Result<()> {
+        if !self.immutable {
+            return Ok(());
+        }
+        let sql_path = self
+            .sql_path
+            .as_ref()
+            .ok_or_else(|| internal!("Immutable store with no path"))?;
+        let conn = open_immutable_db(sql_path)?;
+        conn.pragma_update(None, "foreign_keys", "ON")?;
+        let old_conn = std::mem::replace(&mut self.conn, conn);
+        if let Err(e) = self.check_schema(true) {
+            // Keep using the image we had.
+            self.conn = old_conn;
+            return Err(e);
+        }
+        Ok(())
+    }
     fn expire_all(&mut self, expiration: &ExpirationConfig) -> Result<()> {
+        self.check_mutable()?;
+        // (Any value this big means "keep everything".)
+        let n_archived = i64::try_from(expiration.archived_consensuses).unwrap_or(i64::MAX);
         let tx = self.conn.transaction()?;
         // This works around a false positive; see
         //   https://github.com/rust-lang/rust-clippy/issues/8114
@@ -423,22 +709,38 @@ impl Store for SqliteStore {
         let expired_blobs: Vec<String> = {
             let mut stmt = tx.prepare(FIND_EXPIRED_EXTDOCS)?;
             let names = stmt
-                .query_map([], |row| row.get::<_, String>(0))?
+                .query_map(params![n_archived], |row| row.get::<_, String>(0))?
                 .filter_map(std::result::Result::ok)
                 .collect();
             names
         };
 
         let now = OffsetDateTime::now_utc();
-        tx.execute(DROP_OLD_EXTDOCS, [])?;
+        tx.execute(DROP_OLD_EXTDOCS, params![n_archived])?;
+
+        // Every microdescriptor listed in an archived consensus was last
+        // listed no earlier than that consensus became valid, so we keep
+        // everything listed since the oldest one.
+        let oldest_archived: Option<OffsetDateTime> =
+            tx.query_row(FIND_OLDEST_ARCHIVED_CONSENSUS, params![n_archived], |row| {
+                row.get(0)
+            })?;
+        let md_cutoff = match oldest_archived {
+            Some(oldest) => std::cmp::min(oldest, now - expiration.microdescs),
+            None => now - expiration.microdescs,
+        };
 
         // In theory bad system clocks might generate table rows with times far in the future.
         // However, for data which is cached here which comes from the network consensus,
         // we rely on the fact that no consensus from the future exists, so this can't happen.
-        tx.execute(DROP_OLD_MICRODESCS, [now - expiration.microdescs])?;
+        tx.execute(DROP_OLD_MICRODESCS, [md_cutoff])?;
         tx.execute(DROP_OLD_AUTHCERTS, [now - expiration.authcerts])?;
-        tx.execute(DROP_OLD_CONSENSUSES, [now - expiration.consensuses])?;
+        tx.execute(
+            DROP_OLD_CONSENSUSES,
+            params![n_archived, now - expiration.consensuses],
+        )?;
         tx.execute(DROP_OLD_ROUTERDESCS, [now - expiration.router_descs])?;
+        tx.execute(DROP_OLD_CONSENSUS_DIFFS, [now])?;
 
         // Bridge descriptors come from bridges and bridges might send crazy times,
         // so we need to discard any that look like they are from the future,
@@ -456,6 +758,10 @@ impl Store for SqliteStore {
 
         self.remove_unreferenced_blobs(now, expiration)?;
 
+        if let Some(max_size) = expiration.max_cache_size {
+            self.enforce_size_limit(max_size)?;
+        }
+
         Ok(())
     }
 
@@ -509,6 +815,23 @@ impl Store for SqliteStore {
             ))
         }
     }
+    fn consensus_valid_at(
+        &self,
+        flavor: ConsensusFlavor,
+        when: SystemTime,
+    ) -> Result<Option<(InputString, ConsensusMeta)>> {
+        let when: OffsetDateTime = when.into();
+        let mut stmt = self.conn.prepare(FIND_CONSENSUS_AND_META_VALID_AT)?;
+        let mut rows = stmt.query(params![flavor.name(), when])?;
+        if let Some(row) = rows.next()? {
+            let meta = cmeta_from_row(row)?;
+            let fname: String = row.get(5)?;
+            if let Some(text) = self.read_blob(&fname)? {
+                return Ok(Some((text, meta)));
+            }
+        }
+        Ok(None)
+    }
     fn consensus_by_sha3_digest_of_signed_part(
         &self,
         d: &[u8; 32],
@@ -534,6 +857,7 @@ impl Store for SqliteStore {
         pending: bool,
         contents: &str,
     ) -> Result<()> {
+        self.check_mutable()?;
         let lifetime = cmeta.lifetime();
         let sha3_of_signed = cmeta.sha3_256_of_signed();
         let sha3_of_whole = cmeta.sha3_256_of_whole();
@@ -574,6 +898,7 @@ impl Store for SqliteStore {
         Ok(())
     }
     fn mark_consensus_usable(&mut self, cmeta: &ConsensusMeta) -> Result<()> {
+        self.check_mutable()?;
         let d = hex::encode(cmeta.sha3_256_of_whole());
         let digest = format!("sha3-256-{}", d);
 
@@ -585,6 +910,7 @@ impl Store for SqliteStore {
         Ok(())
     }
     fn delete_consensus(&mut self, cmeta: &ConsensusMeta) -> Result<()> {
+        self.check_mutable()?;
         let d = hex::encode(cmeta.sha3_256_of_whole());
         let digest = format!("sha3-256-{}", d);
 
@@ -597,6 +923,96 @@ impl Store for SqliteStore {
         Ok(())
     }
 
+    fn consensus_diff(&self, from: &[u8; 32], to: &[u8; 32]) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                FIND_CONSENSUS_DIFF,
+                params![hex::encode(from), hex::encode(to)],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?)
+    }
+
+    fn store_consensus_diff(
+        &mut self,
+        from: &[u8; 32],
+        to: &[u8; 32],
+        expires: SystemTime,
+        diff: &str,
+    ) -> Result<()> {
+        self.check_mutable()?;
+        let expires: OffsetDateTime = expires.into();
+        self.conn.execute(
+            INSERT_CONSENSUS_DIFF,
+            params![hex::encode(from), hex::encode(to), expires, diff],
+        )?;
+        Ok(())
+    }
+
+    fn latest_bandwidth_file(&self) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(FIND_BANDWIDTH_FILE, [], |row| row.get::<_, String>(0))
+            .optional()?)
+    }
+
+    fn store_bandwidth_file(&mut self, timestamp: SystemTime, text: &str) -> Result<()> {
+        self.check_mutable()?;
+        // The timestamp comes from the file itself, so don't trust it to be
+        // representable.
+        let timestamp = checked_offset_datetime(timestamp)
+            .ok_or_else(|| bad_api_usage!("Bandwidth file timestamp out of range"))?;
+        let tx = self.conn.transaction()?;
+        tx.execute(DELETE_BANDWIDTH_FILES, [])?;
+        tx.execute(INSERT_BANDWIDTH_FILE, params![timestamp, text])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn latest_votes(&self) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(FIND_LATEST_VOTES)?;
+        let votes = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(votes)
+    }
+
+    fn store_vote(
+        &mut self,
+        authority: &RsaIdentity,
+        valid_after: SystemTime,
+        text: &str,
+    ) -> Result<()> {
+        self.check_mutable()?;
+        let valid_after: OffsetDateTime = valid_after.into();
+        let tx = self.conn.transaction()?;
+        tx.execute(DELETE_OLD_VOTES, params![valid_after])?;
+        tx.execute(
+            INSERT_VOTE,
+            params![hex::encode(authority.as_bytes()), valid_after, text],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn latest_detached_signatures(&self) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(FIND_DETACHED_SIGNATURES, [], |row| row.get::<_, String>(0))
+            .optional()?)
+    }
+
+    fn store_detached_signatures(&mut self, valid_after: SystemTime, text: &str) -> Result<()> {
+        self.check_mutable()?;
+        let valid_after: OffsetDateTime = valid_after.into();
+        let tx = self.conn.transaction()?;
+        tx.execute(DELETE_DETACHED_SIGNATURES, [])?;
+        tx.execute(INSERT_DETACHED_SIGNATURES, params![valid_after, text])?;
+        tx.commit()?;
+        Ok(())
+    }
+
     fn authcerts(&self, certs: &[AuthCertKeyIds]) -> Result<HashMap<AuthCertKeyIds, String>> {
         let mut result = HashMap::new();
         // TODO(nickm): Do I need to get a transaction here for performance?
@@ -616,6 +1032,7 @@ impl Store for SqliteStore {
         Ok(result)
     }
     fn store_authcerts(&mut self, certs: &[(AuthCertMeta, &str)]) -> Result<()> {
+        self.check_mutable()?;
         let tx = self.conn.transaction()?;
         let mut stmt = tx.prepare(INSERT_AUTHCERT)?;
         for (meta, content) in certs {
@@ -650,6 +1067,7 @@ impl Store for SqliteStore {
         Ok(result)
     }
     fn store_microdescs(&mut self, digests: &[(&str, &MdDigest)], when: SystemTime) -> Result<()> {
+        self.check_mutable()?;
         let when: OffsetDateTime = when.into();
 
         let tx = self.conn.transaction()?;
@@ -664,6 +1082,7 @@ impl Store for SqliteStore {
         Ok(())
     }
     fn update_microdescs_listed(&mut self, digests: &[MdDigest], when: SystemTime) -> Result<()> {
+        self.check_mutable()?;
         let tx = self.conn.transaction()?;
         let mut stmt = tx.prepare(UPDATE_MD_LISTED)?;
         let when: OffsetDateTime = when.into();
@@ -678,6 +1097,46 @@ impl Store for SqliteStore {
         Ok(())
     }
 
+    fn md_download_progress(&self, consensus: &[u8; 32]) -> Result<Option<MdDownloadProgress>> {
+        let mut stmt = self.conn.prepare(FIND_MD_DOWNLOAD_BATCHES)?;
+        let rows = stmt
+            .query_map(params![hex::encode(consensus)], |row| {
+                Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        let batches = rows
+            .into_iter()
+            .map(|(n_failures, digests)| {
+                let digests = digests
+                    .split_ascii_whitespace()
+                    .map(digest_from_hex)
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(MdBatch::new(digests, n_failures))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(MdDownloadProgress::new(*consensus, batches)))
+    }
+
+    fn store_md_download_progress(&mut self, progress: &MdDownloadProgress) -> Result<()> {
+        self.check_mutable()?;
+        let consensus = hex::encode(progress.consensus());
+        let tx = self.conn.transaction()?;
+        tx.execute(DELETE_MD_DOWNLOAD_BATCHES, [])?;
+        {
+            let mut stmt = tx.prepare(INSERT_MD_DOWNLOAD_BATCH)?;
+            for (idx, batch) in progress.batches().iter().enumerate() {
+                let digests: Vec<_> = batch.digests().iter().map(hex::encode).collect();
+                let digests = digests.join(" ");
+                stmt.execute(params![consensus, idx, batch.n_failures(), digests])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
     #[cfg(feature = "routerdesc")]
     fn routerdescs(&self, digests: &[RdDigest]) -> Result<HashMap<RdDigest, String>> {
         let mut result = HashMap::new();
@@ -699,6 +1158,7 @@ impl Store for SqliteStore {
     }
     #[cfg(feature = "routerdesc")]
     fn store_routerdescs(&mut self, digests: &[(&str, SystemTime, &RdDigest)]) -> Result<()> {
+        self.check_mutable()?;
         let tx = self.conn.transaction()?;
         let mut stmt = tx.prepare(INSERT_RD)?;
 
@@ -761,6 +1221,44 @@ impl Store for SqliteStore {
         self.conn.execute(DELETE_BRIDGEDESC, params![bridge_line])?;
         Ok(())
     }
+
+    fn repair_report(&self) -> Option<CacheRepairReport> {
+        self.repair_report
+    }
+    fn cache_usage(&self) -> Result<CacheSizeReport> {
+        let pragma = |name: &str| -> Result<u64> {
+            let n: i64 = self.conn.pragma_query_value(None, name, |row| row.get(0))?;
+            Ok(u64::try_from(n).unwrap_or(0))
+        };
+        let count = |query: &str| -> Result<usize> {
+            let n: i64 = self.conn.query_row(query, [], |row| row.get(0))?;
+            Ok(usize::try_from(n).unwrap_or(0))
+        };
+        let page_size = pragma("page_size")?;
+        Ok(CacheSizeReport {
+            database_bytes: pragma("page_count")? * page_size,
+            reclaimable_bytes: pragma("freelist_count")? * page_size,
+            blob_bytes: self.blob_bytes()?,
+            microdescs: count(COUNT_MDS)?,
+            routerdescs: count(COUNT_RDS)?,
+            consensuses: count(COUNT_CONSENSUSES)?,
+            evicted_microdescs: self.n_evicted_mds,
+            evicted_routerdescs: self.n_evicted_rds,
+            max_size: None,
+        })
+    }
+}
+
+/// Convert `when` to an `OffsetDateTime`, to the nearest second, or return
+/// `None` if it is too far from the present to represent.
+///
+/// (Unlike `OffsetDateTime::from`, this never panics.)
+fn checked_offset_datetime(when: SystemTime) -> Option<OffsetDateTime> {
+    let secs = match when.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(after) => i64::try_from(after.as_secs()).ok()?,
+        Err(before) => -i64::try_from(before.duration().as_secs()).ok()?,
+    };
+    OffsetDateTime::from_unix_timestamp(secs).ok()
 }
 
 /// Handle to a blob that we have saved to disk but not yet committed to
@@ -808,6 +1306,45 @@ impl Drop for Unlinker {
     }
 }
 
+/// Open the sqlite database at `path` read-only, telling SQLite that nobody
+/// will modify it while we have it open.
+///
+/// With the `immutable` flag, SQLite takes no locks and never looks for a
+/// journal, so this works even on a read-only filesystem.
+fn open_immutable_db(path: &Path) -> Result<rusqlite::Connection> {
+    let Some(path_str) = path.to_str() else {
+        return Err(Error::CacheFile {
+            action: "opening",
+            fname: path.to_path_buf(),
+            error: Arc::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "can't open a cache with a non-UTF-8 path as immutable",
+            )),
+        });
+    };
+    let uri = format!("file:{}?immutable=1", encode_uri_path(path_str));
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI;
+    Ok(rusqlite::Connection::open_with_flags(uri, flags)?)
+}
+
+/// Escape `path` for use as the path part of a SQLite `file:` URI.
+fn encode_uri_path(path: &str) -> String {
+    use std::fmt::Write as _;
+    let mut out = String::with_capacity(path.len());
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' => {
+                out.push(char::from(b));
+            }
+            b'\\' if cfg!(windows) => out.push('/'),
+            _ => {
+                let _ = write!(out, "%{:02X}", b);
+            }
+        }
+    }
+    out
+}
+
 /// Convert a hexadecimal sha3-256 digest from the database into an array.
 fn digest_from_hex(s: &str) -> Result<[u8; 32]> {
     let mut bytes = [0_u8; 32];
@@ -918,6 +1455,53 @@ const UPDATE_SCHEMA: &[&str] = &["
     until DATE NOT NULL,
     contents BLOB NOT NULL
   );
+","
+  -- Update the database schema from version 2 to version 3.
+  -- Diffs between consensuses, for serving as a directory cache.  Each
+  -- consensus is named by the hex-encoded SHA3-256 digest of its signed part.
+  CREATE TABLE ConsensusDiffs (
+    from_digest TEXT NOT NULL,
+    to_digest TEXT NOT NULL,
+    expires DATE NOT NULL,
+    contents BLOB NOT NULL,
+    PRIMARY KEY (from_digest, to_digest)
+  );
+","
+  -- Update the database schema from version 3 to version 4.
+  -- The latest bandwidth file we've been given, if any.  We only keep one.
+  CREATE TABLE BandwidthFiles (
+    timestamp DATE NOT NULL,
+    contents BLOB NOT NULL
+  );
+","
+  -- Update the database schema from version 4 to version 5.
+  -- Authority votes from the latest voting round, by the hex-encoded
+  -- identity of the authority that cast them.  We only keep one round.
+  CREATE TABLE Votes (
+    authority TEXT NOT NULL,
+    valid_after DATE NOT NULL,
+    contents BLOB NOT NULL,
+    PRIMARY KEY (authority, valid_after)
+  );
+  -- The latest detached signatures we've been given, if any.  We only keep one.
+  CREATE TABLE DetachedSignatures (
+    valid_after DATE NOT NULL,
+    contents BLOB NOT NULL
+  );
+","
+  -- Update the database schema from version 5 to version 6.
+  -- The batches in which we are downloading the microdescriptors for a
+  -- consensus, so that we can resume after a restart.  The consensus is
+  -- named by the hex-encoded SHA3-256 digest of its signed part, and each
+  -- batch lists its hex-encoded digests, separated by spaces.  We only keep
+  -- the batches for one consensus.
+  CREATE TABLE MdDownloadBatches (
+    consensus TEXT NOT NULL,
+    batch INTEGER NOT NULL,
+    n_failures INTEGER NOT NULL,
+    digests TEXT NOT NULL,
+    PRIMARY KEY (consensus, batch)
+  );
 "];
 
 /// Update the database schema version tracking, from each version to the next
@@ -969,6 +1553,17 @@ const FIND_CONSENSUS_AND_META_BY_DIGEST_OF_SIGNED: &str = "
   LIMIT 1;
 ";
 
+/// Look up the latest usable consensus of a given flavor that is valid at a
+/// given time.  (Both ?2 = the time.)
+const FIND_CONSENSUS_AND_META_VALID_AT: &str = "
+  SELECT valid_after, fresh_until, valid_until, sha3_of_signed_part, Consensuses.digest, filename
+  FROM Consensuses
+  INNER JOIN ExtDocs on ExtDocs.digest = Consensuses.digest
+  WHERE pending = 0 AND flavor = ?1 AND valid_after <= ?2 AND valid_until >= ?2
+  ORDER BY valid_after DESC
+  LIMIT 1;
+";
+
 /// Query: Update the consensus whose digest field is 'digest' to call it
 /// no longer pending.
 const MARK_CONSENSUS_NON_PENDING: &str = "
@@ -1004,10 +1599,41 @@ const FIND_RD: &str = "
   WHERE sha1_digest = ?
 ";
 
-/// Query: find every ExtDocs member that has expired.
-const FIND_EXPIRED_EXTDOCS: &str = "
-  SELECT filename FROM ExtDocs where expires < datetime('now');
-";
+/// Expands to a subquery that finds the digest of every archived consensus:
+/// that is, of each of the latest ?1 usable consensuses of each flavor.
+///
+/// (A consensus's `RANK()` is one more than the number of newer usable
+/// consensuses of the same flavor.)
+macro_rules! archived_consensus_digests {
+    () => {
+        "SELECT digest FROM (
+      SELECT digest, RANK() OVER (
+        PARTITION BY flavor ORDER BY valid_after DESC
+      ) AS newness
+      FROM Consensuses WHERE pending = 0
+    ) WHERE newness <= ?1"
+    };
+}
+
+/// Query: find every ExtDocs member that has expired, other than archived
+/// consensuses.  (?1 = the number of consensuses to archive.)
+const FIND_EXPIRED_EXTDOCS: &str = concat!(
+    "
+  SELECT filename FROM ExtDocs where expires < datetime('now')
+  AND digest NOT IN (",
+    archived_consensus_digests!(),
+    ");"
+);
+
+/// Query: find the valid-after time of the oldest archived consensus.
+/// (?1 = the number of consensuses to archive.)
+const FIND_OLDEST_ARCHIVED_CONSENSUS: &str = concat!(
+    "
+  SELECT min(valid_after) FROM Consensuses
+  WHERE digest IN (",
+    archived_consensus_digests!(),
+    ");"
+);
 
 /// Query: find whether an ExtDoc is listed.
 const COUNT_EXTDOC_BY_PATH: &str = "
@@ -1055,6 +1681,72 @@ const UPDATE_MD_LISTED: &str = "
   WHERE sha256_digest = ?;
 ";
 
+/// Query: Find the diff between two consensuses.
+const FIND_CONSENSUS_DIFF: &str = "
+  SELECT contents FROM ConsensusDiffs WHERE from_digest = ? AND to_digest = ?;
+";
+
+/// Query: Add a new diff between two consensuses.
+const INSERT_CONSENSUS_DIFF: &str = "
+  INSERT OR REPLACE INTO ConsensusDiffs ( from_digest, to_digest, expires, contents )
+  VALUES ( ?, ?, ?, ? );
+";
+
+/// Query: Find the bandwidth file that we have stored.
+const FIND_BANDWIDTH_FILE: &str = "
+  SELECT contents FROM BandwidthFiles ORDER BY timestamp DESC LIMIT 1;
+";
+
+/// Query: Discard every bandwidth file that we have stored.
+const DELETE_BANDWIDTH_FILES: &str = "DELETE FROM BandwidthFiles;";
+
+/// Query: Add a new bandwidth file.
+const INSERT_BANDWIDTH_FILE: &str = "
+  INSERT INTO BandwidthFiles ( timestamp, contents ) VALUES ( ?, ? );
+";
+
+/// Query: Find the microdescriptor download batches for a given consensus.
+const FIND_MD_DOWNLOAD_BATCHES: &str = "
+  SELECT n_failures, digests FROM MdDownloadBatches WHERE consensus = ? ORDER BY batch;
+";
+
+/// Query: Discard every microdescriptor download batch that we have stored.
+const DELETE_MD_DOWNLOAD_BATCHES: &str = "DELETE FROM MdDownloadBatches;";
+
+/// Query: Add a new microdescriptor download batch.
+const INSERT_MD_DOWNLOAD_BATCH: &str = "
+  INSERT INTO MdDownloadBatches ( consensus, batch, n_failures, digests )
+  VALUES ( ?, ?, ?, ? );
+";
+
+/// Query: Find the votes from the latest voting round that we have stored.
+const FIND_LATEST_VOTES: &str = "
+  SELECT contents FROM Votes
+  WHERE valid_after = (SELECT max(valid_after) FROM Votes)
+  ORDER BY authority;
+";
+
+/// Query: Discard every vote from a voting round before a given time.
+const DELETE_OLD_VOTES: &str = "DELETE FROM Votes WHERE valid_after < ?;";
+
+/// Query: Add a new vote.
+const INSERT_VOTE: &str = "
+  INSERT OR REPLACE INTO Votes ( authority, valid_after, contents ) VALUES ( ?, ?, ? );
+";
+
+/// Query: Find the detached signatures that we have stored.
+const FIND_DETACHED_SIGNATURES: &str = "
+  SELECT contents FROM DetachedSignatures ORDER BY valid_after DESC LIMIT 1;
+";
+
+/// Query: Discard all the detached signatures that we have stored.
+const DELETE_DETACHED_SIGNATURES: &str = "DELETE FROM DetachedSignatures;";
+
+/// Query: Add new detached signatures.
+const INSERT_DETACHED_SIGNATURES: &str = "
+  INSERT INTO DetachedSignatures ( valid_after, contents ) VALUES ( ?, ? );
+";
+
 /// Query: Find a cached bridge descriptor
 #[cfg(feature = "bridge-client")]
 const FIND_BRIDGEDESC: &str = "SELECT fetched, contents FROM BridgeDescs WHERE bridge_line = ?;";
@@ -1071,12 +1763,33 @@ const DELETE_BRIDGEDESC: &str = "DELETE FROM BridgeDescs WHERE bridge_line = ?;"
 
 /// Query: Discard every expired extdoc.
 ///
-/// External documents aren't exposed through [`Store`].
-const DROP_OLD_EXTDOCS: &str = "DELETE FROM ExtDocs WHERE expires < datetime('now');";
+/// External documents aren't exposed through [`Store`].  We keep the ones
+/// that hold archived consensuses.  (?1 = the number of consensuses to
+/// archive.)
+const DROP_OLD_EXTDOCS: &str = concat!(
+    "DELETE FROM ExtDocs WHERE expires < datetime('now') AND digest NOT IN (",
+    archived_consensus_digests!(),
+    ");"
+);
 
 /// Query: Discard an extdoc with a given path.
 const DELETE_EXTDOC_BY_FILENAME: &str = "DELETE FROM ExtDocs WHERE filename = ?;";
 
+/// Query: List the filename of every extdoc.
+const FIND_ALL_EXTDOC_FILENAMES: &str = "SELECT filename FROM ExtDocs;";
+
+/// Query: Discard every consensus stored in the extdoc with a given path.
+const DELETE_CONSENSUSES_BY_EXTDOC_FILENAME: &str = "
+  DELETE FROM Consensuses
+  WHERE digest IN (SELECT digest FROM ExtDocs WHERE filename = ?);
+";
+
+/// Query: Discard every consensus whose extdoc is missing.
+const DELETE_CONSENSUSES_WITHOUT_EXTDOCS: &str = "
+  DELETE FROM Consensuses
+  WHERE digest NOT IN (SELECT digest FROM ExtDocs);
+";
+
 /// Query: Discard every router descriptor that hasn't been listed for 3
 /// months.
 // TODO: Choose a more realistic time.
@@ -1087,8 +1800,40 @@ const DROP_OLD_MICRODESCS: &str = "DELETE FROM Microdescs WHERE last_listed < ?;
 /// Query: Discard every expired authority certificate.
 const DROP_OLD_AUTHCERTS: &str = "DELETE FROM Authcerts WHERE expires < ?;";
 /// Query: Discard every consensus that's been expired for at least
-/// two days.
-const DROP_OLD_CONSENSUSES: &str = "DELETE FROM Consensuses WHERE valid_until < ?;";
+/// two days, other than archived consensuses.  (?1 = the number of
+/// consensuses to archive; ?2 = the cutoff.)
+const DROP_OLD_CONSENSUSES: &str = concat!(
+    "DELETE FROM Consensuses WHERE valid_until < ?2 AND digest NOT IN (",
+    archived_consensus_digests!(),
+    ");"
+);
+/// Query: find the valid-after time of the latest consensus, pending or not.
+const FIND_LATEST_VALID_AFTER: &str = "SELECT max(valid_after) FROM Consensuses;";
+/// Query: find every microdescriptor last listed before ?1, and every router
+/// descriptor published before ?2, least recently listed or published first.
+///
+/// Each row has the kind of the document (`md` or `rd`), its digest, its
+/// size in bytes, and the time by which we order it.
+const FIND_EVICTION_CANDIDATES: &str = "
+  SELECT 'md', sha256_digest, length(CAST(contents AS BLOB)), last_listed AS t
+    FROM Microdescs WHERE last_listed < ?1
+  UNION ALL
+  SELECT 'rd', sha1_digest, length(CAST(contents AS BLOB)), published AS t
+    FROM RouterDescs WHERE published < ?2
+  ORDER BY t;
+";
+/// Query: Discard the microdescriptor with a given hex-encoded digest.
+const DELETE_MD: &str = "DELETE FROM Microdescs WHERE sha256_digest = ?;";
+/// Query: Discard the router descriptor with a given hex-encoded digest.
+const DELETE_RD: &str = "DELETE FROM RouterDescs WHERE sha1_digest = ?;";
+/// Query: Count the microdescriptors.
+const COUNT_MDS: &str = "SELECT COUNT(*) FROM Microdescs;";
+/// Query: Count the router descriptors.
+const COUNT_RDS: &str = "SELECT COUNT(*) FROM RouterDescs;";
+/// Query: Count the consensuses.
+const COUNT_CONSENSUSES: &str = "SELECT COUNT(*) FROM Consensuses;";
+/// Query: Discard every consensus diff that has expired.
+const DROP_OLD_CONSENSUS_DIFFS: &str = "DELETE FROM ConsensusDiffs WHERE expires < ?;";
 /// Query: Discard every bridge descriptor that is too old, or from the future.  (Both ?=now.)
 #[cfg(feature = "bridge-client")]
 const DROP_OLD_BRIDGEDESCS: &str = "DELETE FROM BridgeDescs WHERE ? > until OR fetched > ?;";
@@ -1331,6 +2076,141 @@ pub(crate) mod test {
         Ok(())
     }
 
+    #[test]
+    fn consensus_diffs() -> Result<()> {
+        let (_tmp_dir, mut store) = new_empty()?;
+        let now = OffsetDateTime::now_utc();
+        let one_hour = 1.hours();
+
+        assert!(store.consensus_diff(&[1; 32], &[2; 32])?.is_none());
+
+        store.store_consensus_diff(
+            &[1; 32],
+            &[2; 32],
+            (now + one_hour).into(),
+            "Pretend this is a diff",
+        )?;
+        store.store_consensus_diff(
+            &[2; 32],
+            &[3; 32],
+            (now - one_hour).into(),
+            "Pretend this is an old diff",
+        )?;
+        assert_eq!(
+            store.consensus_diff(&[1; 32], &[2; 32])?.unwrap(),
+            "Pretend this is a diff"
+        );
+        assert!(store.consensus_diff(&[2; 32], &[1; 32])?.is_none());
+        assert!(store.consensus_diff(&[2; 32], &[3; 32])?.is_some());
+
+        // Storing the same diff again replaces it.
+        store.store_consensus_diff(
+            &[1; 32],
+            &[2; 32],
+            (now + one_hour).into(),
+            "Pretend this is a better diff",
+        )?;
+        assert_eq!(
+            store.consensus_diff(&[1; 32], &[2; 32])?.unwrap(),
+            "Pretend this is a better diff"
+        );
+
+        store.expire_all(&EXPIRATION_DEFAULTS)?;
+        assert!(store.consensus_diff(&[1; 32], &[2; 32])?.is_some());
+        assert!(store.consensus_diff(&[2; 32], &[3; 32])?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn md_download_progress() -> Result<()> {
+        let (_tmp_dir, mut store) = new_empty()?;
+        assert!(store.md_download_progress(&[1; 32])?.is_none());
+
+        let progress = MdDownloadProgress::new(
+            [1; 32],
+            vec![
+                MdBatch::new(vec![[7; 32], [5; 32]], 0),
+                MdBatch::new(vec![[9; 32]], 3),
+            ],
+        );
+        store.store_md_download_progress(&progress)?;
+        assert_eq!(store.md_download_progress(&[1; 32])?, Some(progress));
+        assert!(store.md_download_progress(&[2; 32])?.is_none());
+
+        // We only keep one consensus's worth of progress.
+        let progress = MdDownloadProgress::new([2; 32], vec![MdBatch::new(vec![[5; 32]], 1)]);
+        store.store_md_download_progress(&progress)?;
+        assert!(store.md_download_progress(&[1; 32])?.is_none());
+        assert_eq!(store.md_download_progress(&[2; 32])?, Some(progress));
+
+        Ok(())
+    }
+
+    #[test]
+    fn bandwidth_files() -> Result<()> {
+        let (_tmp_dir, mut store) = new_empty()?;
+        let now = OffsetDateTime::now_utc();
+
+        assert!(store.latest_bandwidth_file()?.is_none());
+
+        store.store_bandwidth_file(now.into(), "Pretend this is a bandwidth file")?;
+        assert_eq!(
+            store.latest_bandwidth_file()?.unwrap(),
+            "Pretend this is a bandwidth file"
+        );
+
+        // Storing another file replaces the first one, even if it is older.
+        store.store_bandwidth_file((now - 1.hours()).into(), "Pretend this is another one")?;
+        assert_eq!(
+            store.latest_bandwidth_file()?.unwrap(),
+            "Pretend this is another one"
+        );
+
+        // A timestamp that we can't represent is an error, not a panic.
+        let far_future = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1 << 40);
+        assert!(store
+            .store_bandwidth_file(far_future, "Pretend this is from the future")
+            .is_err());
+        assert_eq!(
+            store.latest_bandwidth_file()?.unwrap(),
+            "Pretend this is another one"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn votes() -> Result<()> {
+        let (_tmp_dir, mut store) = new_empty()?;
+        let now = OffsetDateTime::now_utc();
+        let (a, b) = ([1; 20].into(), [2; 20].into());
+
+        assert!(store.latest_votes()?.is_empty());
+        assert!(store.latest_detached_signatures()?.is_none());
+
+        store.store_vote(&b, now.into(), "Vote from b")?;
+        store.store_vote(&a, now.into(), "Vote from a")?;
+        // An older vote doesn't replace the latest round.
+        store.store_vote(&a, (now - 1.hours()).into(), "Old vote from a")?;
+        assert_eq!(store.latest_votes()?, vec!["Vote from a", "Vote from b"]);
+
+        // A newer vote starts a new round, and discards the older ones.
+        store.store_vote(&b, (now + 1.hours()).into(), "New vote from b")?;
+        assert_eq!(store.latest_votes()?, vec!["New vote from b"]);
+        store.store_vote(&a, now.into(), "Vote from a")?;
+        assert_eq!(store.latest_votes()?, vec!["New vote from b"]);
+
+        store.store_detached_signatures(now.into(), "Some signatures")?;
+        store.store_detached_signatures((now - 1.hours()).into(), "Other signatures")?;
+        assert_eq!(
+            store.latest_detached_signatures()?.unwrap(),
+            "Other signatures"
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn authcerts() -> Result<()> {
         let (_tmp_dir, mut store) = new_empty()?;
@@ -1397,6 +2277,199 @@ pub(crate) mod test {
         Ok(())
     }
 
+    #[test]
+    fn archived_consensuses() -> Result<()> {
+        use tor_netdoc::doc::netstatus;
+
+        let (_tmp_dir, mut store) = new_empty()?;
+        let now = OffsetDateTime::now_utc();
+        let one_hour = 1.hours();
+        let one_day = 1.days();
+
+        // Four usable consensuses, from 30, 20, and 10 days ago, and from
+        // an hour ago, and one pending consensus from 5 days ago.
+        let valid_afters = [
+            now - one_day * 30,
+            now - one_day * 20,
+            now - one_day * 10,
+            now - one_hour,
+            now - one_day * 5,
+        ];
+        for (idx, va) in valid_afters.into_iter().enumerate() {
+            let idx = idx as u8;
+            let cmeta = ConsensusMeta::new(
+                netstatus::Lifetime::new(
+                    va.into(),
+                    (va + one_hour).into(),
+                    SystemTime::from(va + one_hour * 3),
+                )
+                .unwrap(),
+                [idx; 32],
+                [idx; 32],
+            );
+            let text = format!("Pretend this is consensus {}", idx);
+            store.store_consensus(&cmeta, ConsensusFlavor::Microdesc, true, &text)?;
+            if idx < 4 {
+                store.mark_consensus_usable(&cmeta)?;
+            }
+        }
+        let d1: MdDigest = [1; 32];
+        let d2: MdDigest = [2; 32];
+        let listed_1: OffsetDateTime = now - one_day * 9;
+        let listed_2: OffsetDateTime = now - one_day * 11;
+        store.store_microdescs(&[("Fake micro 1", &d1)], listed_1.into())?;
+        store.store_microdescs(&[("Fake micro 2", &d2)], listed_2.into())?;
+
+        let valid_at = |store: &SqliteStore, va: OffsetDateTime| -> Result<Option<String>> {
+            Ok(store
+                .consensus_valid_at(ConsensusFlavor::Microdesc, (va + one_hour).into())?
+                .map(|(text, _)| text.as_str().unwrap().to_owned()))
+        };
+        assert_eq!(
+            valid_at(&store, valid_afters[0])?.unwrap(),
+            "Pretend this is consensus 0"
+        );
+        assert!(valid_at(&store, now - one_day * 25)?.is_none());
+        // We never look at pending consensuses.
+        assert!(valid_at(&store, valid_afters[4])?.is_none());
+
+        // Keep the two latest usable consensuses, and the microdescriptors
+        // that they might list.
+        let expiration = ExpirationConfig {
+            archived_consensuses: 2,
+            ..EXPIRATION_DEFAULTS
+        };
+        store.expire_all(&expiration)?;
+        assert!(valid_at(&store, valid_afters[0])?.is_none());
+        assert!(valid_at(&store, valid_afters[1])?.is_none());
+        assert_eq!(
+            valid_at(&store, valid_afters[2])?.unwrap(),
+            "Pretend this is consensus 2"
+        );
+        assert_eq!(
+            valid_at(&store, valid_afters[3])?.unwrap(),
+            "Pretend this is consensus 3"
+        );
+        let mds = store.microdescs(&[d1, d2])?;
+        assert_eq!(mds.len(), 1);
+        assert!(mds.contains_key(&d1));
+
+        // Without an archive, the old consensus and microdescriptor go away.
+        store.expire_all(&EXPIRATION_DEFAULTS)?;
+        assert!(valid_at(&store, valid_afters[2])?.is_none());
+        assert!(valid_at(&store, valid_afters[3])?.is_some());
+        assert!(store.microdescs(&[d1, d2])?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn size_limit() -> Result<()> {
+        use tor_netdoc::doc::netstatus;
+
+        let (_tmp_dir, mut store) = new_empty()?;
+        let now = OffsetDateTime::now_utc();
+        let one_hour = 1.hours();
+
+        let valid_after = now - one_hour;
+        let cmeta = ConsensusMeta::new(
+            netstatus::Lifetime::new(
+                valid_after.into(),
+                now.into(),
+                SystemTime::from(now + one_hour * 2),
+            )
+            .unwrap(),
+            [1; 32],
+            [2; 32],
+        );
+        store.store_consensus(
+            &cmeta,
+            ConsensusFlavor::Microdesc,
+            false,
+            "Pretend consensus",
+        )?;
+
+        // Ten microdescriptors that the consensus doesn't list, each 1000
+        // bytes long, listed between one and two days ago; and five that it
+        // does list.
+        let text = "x".repeat(1000);
+        let old: Vec<MdDigest> = (0..10_u8).map(|i| [i; 32]).collect();
+        let current: Vec<MdDigest> = (10..15_u8).map(|i| [i; 32]).collect();
+        for (i, d) in old.iter().enumerate() {
+            let listed = now - 1.days() - one_hour * (i as i32);
+            store.store_microdescs(&[(&text, d)], listed.into())?;
+        }
+        let entries: Vec<_> = current.iter().map(|d| (text.as_str(), d)).collect();
+        store.store_microdescs(&entries, valid_after.into())?;
+
+        let usage = store.cache_usage()?;
+        assert_eq!(usage.microdescs, 15);
+        assert_eq!(usage.consensuses, 1);
+        assert_eq!(usage.evicted_microdescs, 0);
+        assert!(usage.blob_bytes > 0);
+        assert!(usage.used_bytes() > 15_000);
+        assert!(!usage.is_over_budget());
+
+        // Without a limit, expiring keeps everything.
+        store.expire_all(&EXPIRATION_DEFAULTS)?;
+        assert_eq!(store.cache_usage()?.microdescs, 15);
+
+        // Freeing 3500 bytes takes the four least recently listed
+        // microdescriptors.
+        let expiration = ExpirationConfig {
+            max_cache_size: Some(usage.used_bytes() - 3500),
+            ..EXPIRATION_DEFAULTS
+        };
+        store.expire_all(&expiration)?;
+        let usage = store.cache_usage()?;
+        assert_eq!(usage.microdescs, 11);
+        assert_eq!(usage.evicted_microdescs, 4);
+        assert!(store.microdescs(&old[6..])?.is_empty());
+        assert_eq!(store.microdescs(&old[..6])?.len(), 6);
+
+        // A router descriptor published a few hours before the consensus
+        // might be listed in it; one from three days before can't be.
+        #[cfg(feature = "routerdesc")]
+        store.store_routerdescs(&[
+            (
+                "Fake routerdesc 1",
+                SystemTime::from(now - 3.days()),
+                &[1; 20],
+            ),
+            (
+                "Fake routerdesc 2",
+                SystemTime::from(now - one_hour * 5),
+                &[2; 20],
+            ),
+        ])?;
+
+        // With an impossible limit, we evict everything that the consensus
+        // doesn't list, but nothing else.
+        let expiration = ExpirationConfig {
+            max_cache_size: Some(0),
+            ..EXPIRATION_DEFAULTS
+        };
+        // We compacted the database a moment ago, so we won't do it again
+        // until we've waited a while: pretend that we never did.
+        assert!(store.last_vacuum.is_some());
+        store.last_vacuum = None;
+        store.expire_all(&expiration)?;
+        let usage = store.cache_usage()?;
+        assert_eq!(usage.microdescs, 5);
+        assert_eq!(usage.evicted_microdescs, 10);
+        assert_eq!(usage.consensuses, 1);
+        assert_eq!(store.microdescs(&current)?.len(), 5);
+        assert_eq!(usage.reclaimable_bytes, 0);
+        #[cfg(feature = "routerdesc")]
+        {
+            assert_eq!(usage.routerdescs, 1);
+            assert_eq!(usage.evicted_routerdescs, 1);
+            assert_eq!(store.routerdescs(&[[2; 20]])?.len(), 1);
+        }
+
+        Ok(())
+    }
+
     #[test]
     #[cfg(feature = "routerdesc")]
     fn routerdescs() -> Result<()> {
@@ -1465,6 +2538,136 @@ pub(crate) mod test {
         Ok(())
     }
 
+    /// Make a cache image in `dir` holding one consensus with the text
+    /// `text`, whose digests are all `tag`, the way an embedder would before
+    /// shipping it.
+    fn make_image(dir: &Path, text: &str, tag: u8) -> Result<ConsensusMeta> {
+        use tor_netdoc::doc::netstatus;
+
+        let mistrust = fs_mistrust::Mistrust::new_dangerously_trust_everyone();
+        let now = OffsetDateTime::now_utc();
+        let cmeta = ConsensusMeta::new(
+            netstatus::Lifetime::new(
+                now.into(),
+                (now + 1.hours()).into(),
+                SystemTime::from(now + 2.hours()),
+            )
+            .unwrap(),
+            [tag; 32],
+            [tag; 32],
+        );
+        {
+            let mut store = SqliteStore::from_path_and_mistrust(dir, &mistrust, false)?;
+            store.store_consensus(&cmeta, ConsensusFlavor::Microdesc, false, text)?;
+        }
+        std::fs::remove_file(dir.join("dir.lock")).unwrap();
+        Ok(cmeta)
+    }
+
+    #[test]
+    fn immutable_image() -> Result<()> {
+        let tmp = tempdir().unwrap();
+        let mistrust = fs_mistrust::Mistrust::new_dangerously_trust_everyone();
+        let image = tmp.path().join("image");
+
+        // Nothing there: can't open it, and we don't create anything.
+        let r = SqliteStore::from_immutable_path_and_mistrust(&image, &mistrust);
+        assert!(r.is_err());
+        assert!(!image.try_exists().unwrap());
+
+        let cmeta1 = make_image(&image, "Pretend this is a consensus", 0xAB)?;
+        #[cfg(unix)]
+        let set_mode = |mode| {
+            use std::os::unix::fs::PermissionsExt as _;
+            std::fs::set_permissions(&image, std::fs::Permissions::from_mode(mode)).unwrap();
+        };
+        #[cfg(unix)]
+        set_mode(0o555);
+
+        let mut store = SqliteStore::from_immutable_path_and_mistrust(&image, &mistrust)?;
+        assert!(store.is_readonly());
+        assert!(!store.upgrade_to_readwrite()?);
+        assert!(!image.join("dir.lock").try_exists().unwrap());
+
+        // We can read...
+        let consensus = store
+            .latest_consensus(ConsensusFlavor::Microdesc, None)?
+            .unwrap();
+        assert_eq!(consensus.as_str()?, "Pretend this is a consensus");
+
+        // ...but every write gives the same error.
+        let is_immutable_err = |r: Result<()>| {
+            matches!(
+                r,
+                Err(Error::ReadOnlyStorage(ReadOnlyStorageError::Immutable))
+            )
+        };
+        assert!(is_immutable_err(store.store_consensus(
+            &cmeta1,
+            ConsensusFlavor::Microdesc,
+            false,
+            "Another consensus"
+        )));
+        assert!(is_immutable_err(store.mark_consensus_usable(&cmeta1)));
+        assert!(is_immutable_err(store.delete_consensus(&cmeta1)));
+        assert!(is_immutable_err(store.expire_all(&EXPIRATION_DEFAULTS)));
+        assert!(is_immutable_err(store.store_consensus_diff(
+            &[0x11; 32],
+            &[0x22; 32],
+            SystemTime::now(),
+            "diff"
+        )));
+        let d = [5_u8; 32];
+        assert!(is_immutable_err(
+            store.store_microdescs(&[("Fake micro", &d)], SystemTime::now())
+        ));
+        assert!(is_immutable_err(
+            store.update_microdescs_listed(&[d], SystemTime::now())
+        ));
+        assert!(is_immutable_err(store.store_authcerts(&[])));
+
+        // Reopening without a new image changes nothing.
+        store.reopen_immutable_image()?;
+        let consensus = store
+            .latest_consensus(ConsensusFlavor::Microdesc, None)?
+            .unwrap();
+        assert_eq!(consensus.as_str()?, "Pretend this is a consensus");
+
+        // Now replace the image, and reopen it.
+        let new_image = tmp.path().join("new_image");
+        let _ = make_image(&new_image, "Pretend this is a newer consensus", 0xCD)?;
+        #[cfg(unix)]
+        set_mode(0o755);
+        std::fs::remove_dir_all(&image).unwrap();
+        std::fs::rename(&new_image, &image).unwrap();
+        store.reopen_immutable_image()?;
+        let consensus = store
+            .latest_consensus(ConsensusFlavor::Microdesc, None)?
+            .unwrap();
+        assert_eq!(consensus.as_str()?, "Pretend this is a newer consensus");
+
+        // If the image goes away, we keep what we had.
+        std::fs::remove_dir_all(&image).unwrap();
+        assert!(store.reopen_immutable_image().is_err());
+        assert!(store
+            .latest_consensus_meta(ConsensusFlavor::Microdesc)?
+            .is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn immutable_uri() {
+        assert_eq!(
+            encode_uri_path("/var/cache/dir.sqlite3"),
+            "/var/cache/dir.sqlite3"
+        );
+        assert_eq!(
+            encode_uri_path("/odd dir/?#%/dir.sqlite3"),
+            "/odd%20dir/%3F%23%25/dir.sqlite3"
+        );
+    }
+
     #[test]
     fn orphaned_blobs() -> Result<()> {
         let (_tmp_dir, mut store) = new_empty()?;
@@ -1507,4 +2710,84 @@ pub(crate) mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn repair_partial_writes() -> Result<()> {
+        use tor_netdoc::doc::netstatus;
+
+        let (_tmp_dir, mut store) = new_empty()?;
+        let now = OffsetDateTime::now_utc();
+        let one_hour = 1.hours();
+        let cmeta = |d| {
+            ConsensusMeta::new(
+                netstatus::Lifetime::new(
+                    now.into(),
+                    (now + one_hour).into(),
+                    SystemTime::from(now + one_hour * 2),
+                )
+                .unwrap(),
+                [d; 32],
+                [d; 32],
+            )
+        };
+
+        // Nothing to repair in an empty store.
+        assert!(store.repair_partial_writes()?.is_empty());
+
+        // A healthy consensus, and a pending one whose blob has gone missing.
+        store.store_consensus(&cmeta(0xAB), ConsensusFlavor::Microdesc, false, "Healthy")?;
+        store.store_consensus(&cmeta(0xCD), ConsensusFlavor::Microdesc, true, "Doomed")?;
+        let doomed = format!("con_microdesc_sha3-256-{}", hex::encode([0xCD; 32]));
+        store.blob_dir.remove_file(&doomed)?;
+
+        // A consensus whose ExtDocs entry is missing altogether.  (Older versions
+        // of Arti didn't enforce foreign keys, so this could happen.)
+        store.conn.pragma_update(None, "foreign_keys", "OFF")?;
+        store.conn.execute(
+            INSERT_CONSENSUS,
+            params![now, now, now, "microdesc", true, "ef", "sha3-256-ef"],
+        )?;
+        store.conn.pragma_update(None, "foreign_keys", "ON")?;
+
+        // A blob that was written, but never recorded.
+        store
+            .blob_dir
+            .write_and_replace("half_written", b"never committed")?;
+        filetime::set_file_mtime(
+            store.blob_dir.join("half_written")?,
+            SystemTime::from(now - one_hour * 2).into(),
+        )
+        .expect("Can't adjust mtime");
+        // A blob that might still be in the middle of being recorded.
+        store
+            .blob_dir
+            .write_and_replace("in_progress", b"about to be committed")?;
+
+        let report = store.repair_partial_writes()?;
+        assert_eq!(
+            report,
+            CacheRepairReport {
+                consensuses_without_blobs: 2,
+                missing_blobs: 1,
+                orphaned_blobs: 1,
+            }
+        );
+
+        let n_consensuses: u32 =
+            store
+                .conn
+                .query_row("SELECT COUNT(*) FROM Consensuses", [], |row| row.get(0))?;
+        assert_eq!(n_consensuses, 1);
+        assert_eq!(store.blob_dir.read_directory(".")?.count(), 2);
+        assert!(store.blob_dir.join("in_progress")?.try_exists().unwrap());
+        let consensus = store
+            .latest_consensus(ConsensusFlavor::Microdesc, None)?
+            .unwrap();
+        assert_eq!(consensus.as_str()?, "Healthy");
+
+        // Running it again finds nothing more to do.
+        assert!(store.repair_partial_writes()?.is_empty());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,480 @@
+//! A [`Store`] wrapper that measures how long each operation takes.
+//!
+//! When a user tells us that bootstrapping is slow, it's useful to know
+//! whether the time is going into the network or into the disk: some network
+//! filesystems and cheap flash media can make SQLite very slow indeed.  A
+//! [`TimedStore`] wraps another store, logs any operation that takes longer
+//! than a configured threshold, and keeps enough recent measurements to report
+//! latency percentiles.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime};
+
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdoc::doc::authcert::AuthCertKeyIds;
+use tor_netdoc::doc::microdesc::MdDigest;
+use tor_netdoc::doc::netstatus::ConsensusFlavor;
+#[cfg(feature = "routerdesc")]
+use tor_netdoc::doc::routerdesc::RdDigest;
+use tracing::info;
+
+#[cfg(feature = "bridge-client")]
+use super::{BridgeConfig, CachedBridgeDescriptor};
+use super::{CacheRepairReport, CacheSizeReport, DynStore, ExpirationConfig, InputString, Store};
+use crate::docmeta::{AuthCertMeta, ConsensusMeta};
+use crate::mdprogress::MdDownloadProgress;
+use crate::Result;
+
+/// Configuration for measuring the time taken by operations on our
+/// directory cache.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct StoreTimingConfig {
+    /// Log a message for every cache operation that takes longer than this.
+    pub slow_threshold: Duration,
+
+    /// How many recent measurements of each kind of operation we keep, for
+    /// computing percentiles.
+    pub max_samples: usize,
+}
+
+impl Default for StoreTimingConfig {
+    fn default() -> Self {
+        StoreTimingConfig {
+            slow_threshold: Duration::from_millis(500),
+            max_samples: 1000,
+        }
+    }
+}
+
+/// Latency statistics for one kind of cache operation, or for all of them.
+///
+/// The percentiles are computed over the most recent measurements only:
+/// see [`StoreTimingConfig::max_samples`].
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct OpLatency {
+    /// The number of operations we have measured.
+    pub count: u64,
+    /// The number of those operations that were slower than our threshold.
+    pub n_slow: u64,
+    /// The median latency of recent operations.
+    pub p50: Duration,
+    /// The 90th percentile latency of recent operations.
+    pub p90: Duration,
+    /// The 99th percentile latency of recent operations.
+    pub p99: Duration,
+    /// The latency of the slowest operation we have measured.
+    pub max: Duration,
+}
+
+/// A summary of how long operations on our directory cache have taken.
+///
+/// Returned by [`DirMgr::store_latency`](crate::DirMgr::store_latency).
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct StoreLatencyReport {
+    /// Statistics for every operation taken together.
+    pub overall: OpLatency,
+    /// Statistics for each kind of operation, by name.
+    pub by_operation: BTreeMap<&'static str, OpLatency>,
+}
+
+/// Measurements for one kind of cache operation.
+#[derive(Debug, Default)]
+struct OpSamples {
+    /// The number of operations we have measured.
+    count: u64,
+    /// The number of those operations that were slower than our threshold.
+    n_slow: u64,
+    /// The slowest operation we have measured.
+    max: Duration,
+    /// The most recent measurements, oldest first.
+    recent: VecDeque<Duration>,
+}
+
+impl OpSamples {
+    /// Summarize these measurements.
+    fn latency(&self) -> OpLatency {
+        let mut sorted: Vec<Duration> = self.recent.iter().copied().collect();
+        sorted.sort_unstable();
+        OpLatency {
+            count: self.count,
+            n_slow: self.n_slow,
+            p50: percentile(&sorted, 50),
+            p90: percentile(&sorted, 90),
+            p99: percentile(&sorted, 99),
+            max: self.max,
+        }
+    }
+}
+
+/// Return the `pct`th percentile of `sorted`, using the nearest-rank method.
+///
+/// Return zero if `sorted` is empty.
+fn percentile(sorted: &[Duration], pct: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (sorted.len() * pct).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+/// The measurements we have taken for a [`TimedStore`].
+#[derive(Debug)]
+struct Timings {
+    /// Our configuration.
+    config: StoreTimingConfig,
+    /// Measurements for each kind of operation.
+    ///
+    /// We use a `RefCell` because many [`Store`] operations only take `&self`.
+    /// That's safe enough: every `Store` lives behind a `Mutex`.
+    samples: RefCell<HashMap<&'static str, OpSamples>>,
+}
+
+impl Timings {
+    /// Run `f`, which performs the cache operation called `op`, and record how
+    /// long it took.
+    fn time<T>(&self, op: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.record(op, start.elapsed());
+        result
+    }
+
+    /// Record that the cache operation called `op` took `elapsed`.
+    fn record(&self, op: &'static str, elapsed: Duration) {
+        let slow = elapsed > self.config.slow_threshold;
+        if slow {
+            info!(
+                "Directory cache operation {} took {}; our cache may be on slow storage.",
+                op,
+                humantime::format_duration(elapsed),
+            );
+        }
+
+        let mut samples = self.samples.borrow_mut();
+        let s = samples.entry(op).or_default();
+        s.count += 1;
+        if slow {
+            s.n_slow += 1;
+        }
+        s.max = s.max.max(elapsed);
+        if s.recent.len() >= self.config.max_samples {
+            s.recent.pop_front();
+        }
+        if self.config.max_samples > 0 {
+            s.recent.push_back(elapsed);
+        }
+    }
+
+    /// Summarize every measurement we have taken.
+    fn report(&self) -> StoreLatencyReport {
+        let samples = self.samples.borrow();
+        let mut overall = OpSamples::default();
+        for s in samples.values() {
+            overall.count += s.count;
+            overall.n_slow += s.n_slow;
+            overall.max = overall.max.max(s.max);
+            overall.recent.extend(s.recent.iter().copied());
+        }
+        StoreLatencyReport {
+            overall: overall.latency(),
+            by_operation: samples.iter().map(|(op, s)| (*op, s.latency())).collect(),
+        }
+    }
+}
+
+/// A [`Store`] that measures how long each operation on another `Store` takes.
+pub(crate) struct TimedStore {
+    /// The store that actually does the work.
+    inner: DynStore,
+    /// Our measurements.
+    timings: Timings,
+}
+
+impl TimedStore {
+    /// Wrap `inner` so that we measure its operations according to `config`.
+    pub(crate) fn new(inner: DynStore, config: StoreTimingConfig) -> Self {
+        TimedStore {
+            inner,
+            timings: Timings {
+                config,
+                samples: RefCell::new(HashMap::new()),
+            },
+        }
+    }
+}
+
+impl Store for TimedStore {
+    fn is_readonly(&self) -> bool {
+        self.inner.is_readonly()
+    }
+    fn upgrade_to_readwrite(&mut self) -> Result<bool> {
+        self.timings
+            .time("upgrade_to_readwrite", || self.inner.upgrade_to_readwrite())
+    }
+    fn reopen_immutable_image(&mut self) -> Result<()> {
+        self.timings.time("reopen_immutable_image", || {
+            self.inner.reopen_immutable_image()
+        })
+    }
+    fn expire_all(&mut self, expiration: &ExpirationConfig) -> Result<()> {
+        self.timings
+            .time("expire_all", || self.inner.expire_all(expiration))
+    }
+    fn latest_consensus(
+        &self,
+        flavor: ConsensusFlavor,
+        pending: Option<bool>,
+    ) -> Result<Option<InputString>> {
+        self.timings.time("latest_consensus", || {
+            self.inner.latest_consensus(flavor, pending)
+        })
+    }
+    fn latest_consensus_meta(&self, flavor: ConsensusFlavor) -> Result<Option<ConsensusMeta>> {
+        self.timings.time("latest_consensus_meta", || {
+            self.inner.latest_consensus_meta(flavor)
+        })
+    }
+    #[cfg(test)]
+    fn consensus_by_meta(&self, cmeta: &ConsensusMeta) -> Result<InputString> {
+        self.timings
+            .time("consensus_by_meta", || self.inner.consensus_by_meta(cmeta))
+    }
+    fn consensus_valid_at(
+        &self,
+        flavor: ConsensusFlavor,
+        when: SystemTime,
+    ) -> Result<Option<(InputString, ConsensusMeta)>> {
+        self.timings.time("consensus_valid_at", || {
+            self.inner.consensus_valid_at(flavor, when)
+        })
+    }
+    fn consensus_by_sha3_digest_of_signed_part(
+        &self,
+        d: &[u8; 32],
+    ) -> Result<Option<(InputString, ConsensusMeta)>> {
+        self.timings
+            .time("consensus_by_sha3_digest_of_signed_part", || {
+                self.inner.consensus_by_sha3_digest_of_signed_part(d)
+            })
+    }
+    fn store_consensus(
+        &mut self,
+        cmeta: &ConsensusMeta,
+        flavor: ConsensusFlavor,
+        pending: bool,
+        contents: &str,
+    ) -> Result<()> {
+        self.timings.time("store_consensus", || {
+            self.inner.store_consensus(cmeta, flavor, pending, contents)
+        })
+    }
+    fn mark_consensus_usable(&mut self, cmeta: &ConsensusMeta) -> Result<()> {
+        self.timings.time("mark_consensus_usable", || {
+            self.inner.mark_consensus_usable(cmeta)
+        })
+    }
+    fn delete_consensus(&mut self, cmeta: &ConsensusMeta) -> Result<()> {
+        self.timings
+            .time("delete_consensus", || self.inner.delete_consensus(cmeta))
+    }
+    fn consensus_diff(&self, from: &[u8; 32], to: &[u8; 32]) -> Result<Option<String>> {
+        self.timings
+            .time("consensus_diff", || self.inner.consensus_diff(from, to))
+    }
+    fn store_consensus_diff(
+        &mut self,
+        from: &[u8; 32],
+        to: &[u8; 32],
+        expires: SystemTime,
+        diff: &str,
+    ) -> Result<()> {
+        self.timings.time("store_consensus_diff", || {
+            self.inner.store_consensus_diff(from, to, expires, diff)
+        })
+    }
+    fn latest_bandwidth_file(&self) -> Result<Option<String>> {
+        self.timings.time("latest_bandwidth_file", || {
+            self.inner.latest_bandwidth_file()
+        })
+    }
+    fn store_bandwidth_file(&mut self, timestamp: SystemTime, text: &str) -> Result<()> {
+        self.timings.time("store_bandwidth_file", || {
+            self.inner.store_bandwidth_file(timestamp, text)
+        })
+    }
+    fn latest_votes(&self) -> Result<Vec<String>> {
+        self.timings
+            .time("latest_votes", || self.inner.latest_votes())
+    }
+    fn store_vote(
+        &mut self,
+        authority: &RsaIdentity,
+        valid_after: SystemTime,
+        text: &str,
+    ) -> Result<()> {
+        self.timings.time("store_vote", || {
+            self.inner.store_vote(authority, valid_after, text)
+        })
+    }
+    fn latest_detached_signatures(&self) -> Result<Option<String>> {
+        self.timings.time("latest_detached_signatures", || {
+            self.inner.latest_detached_signatures()
+        })
+    }
+    fn store_detached_signatures(&mut self, valid_after: SystemTime, text: &str) -> Result<()> {
+        self.timings.time("store_detached_signatures", || {
+            self.inner.store_detached_signatures(valid_after, text)
+        })
+    }
+    fn authcerts(&self, certs: &[AuthCertKeyIds]) -> Result<HashMap<AuthCertKeyIds, String>> {
+        self.timings
+            .time("authcerts", || self.inner.authcerts(certs))
+    }
+    fn store_authcerts(&mut self, certs: &[(AuthCertMeta, &str)]) -> Result<()> {
+        self.timings
+            .time("store_authcerts", || self.inner.store_authcerts(certs))
+    }
+    fn microdescs(&self, digests: &[MdDigest]) -> Result<HashMap<MdDigest, String>> {
+        self.timings
+            .time("microdescs", || self.inner.microdescs(digests))
+    }
+    fn store_microdescs(&mut self, digests: &[(&str, &MdDigest)], when: SystemTime) -> Result<()> {
+        self.timings.time("store_microdescs", || {
+            self.inner.store_microdescs(digests, when)
+        })
+    }
+    fn update_microdescs_listed(&mut self, digests: &[MdDigest], when: SystemTime) -> Result<()> {
+        self.timings.time("update_microdescs_listed", || {
+            self.inner.update_microdescs_listed(digests, when)
+        })
+    }
+    fn md_download_progress(&self, consensus: &[u8; 32]) -> Result<Option<MdDownloadProgress>> {
+        self.timings.time("md_download_progress", || {
+            self.inner.md_download_progress(consensus)
+        })
+    }
+    fn store_md_download_progress(&mut self, progress: &MdDownloadProgress) -> Result<()> {
+        self.timings.time("store_md_download_progress", || {
+            self.inner.store_md_download_progress(progress)
+        })
+    }
+    #[cfg(feature = "routerdesc")]
+    fn routerdescs(&self, digests: &[RdDigest]) -> Result<HashMap<RdDigest, String>> {
+        self.timings
+            .time("routerdescs", || self.inner.routerdescs(digests))
+    }
+    #[cfg(feature = "routerdesc")]
+    fn store_routerdescs(&mut self, digests: &[(&str, SystemTime, &RdDigest)]) -> Result<()> {
+        self.timings.time("store_routerdescs", || {
+            self.inner.store_routerdescs(digests)
+        })
+    }
+    #[cfg(feature = "bridge-client")]
+    fn lookup_bridgedesc(&self, bridge: &BridgeConfig) -> Result<Option<CachedBridgeDescriptor>> {
+        self.timings
+            .time("lookup_bridgedesc", || self.inner.lookup_bridgedesc(bridge))
+    }
+    #[cfg(feature = "bridge-client")]
+    fn store_bridgedesc(
+        &mut self,
+        bridge: &BridgeConfig,
+        entry: CachedBridgeDescriptor,
+        until: SystemTime,
+    ) -> Result<()> {
+        self.timings.time("store_bridgedesc", || {
+            self.inner.store_bridgedesc(bridge, entry, until)
+        })
+    }
+    #[cfg(feature = "bridge-client")]
+    fn delete_bridgedesc(&mut self, bridge: &BridgeConfig) -> Result<()> {
+        self.timings
+            .time("delete_bridgedesc", || self.inner.delete_bridgedesc(bridge))
+    }
+    fn latency_report(&self) -> Option<StoreLatencyReport> {
+        Some(self.timings.report())
+    }
+    fn repair_report(&self) -> Option<CacheRepairReport> {
+        self.inner.repair_report()
+    }
+    fn cache_usage(&self) -> Result<CacheSizeReport> {
+        self.timings
+            .time("cache_usage", || self.inner.cache_usage())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn percentiles() {
+        assert_eq!(percentile(&[], 50), Duration::ZERO);
+        let sorted: Vec<_> = (1..=100).map(ms).collect();
+        assert_eq!(percentile(&sorted, 50), ms(50));
+        assert_eq!(percentile(&sorted, 90), ms(90));
+        assert_eq!(percentile(&sorted, 99), ms(99));
+        assert_eq!(percentile(&sorted[..1], 99), ms(1));
+        assert_eq!(percentile(&sorted[..3], 50), ms(2));
+    }
+
+    #[test]
+    fn record_and_report() {
+        let config = StoreTimingConfig {
+            slow_threshold: ms(100),
+            max_samples: 10,
+        };
+        let timings = Timings {
+            config,
+            samples: RefCell::new(HashMap::new()),
+        };
+
+        for i in 1..=20 {
+            timings.record("microdescs", ms(i));
+        }
+        timings.record("store_consensus", ms(250));
+
+        let report = timings.report();
+        let md = report.by_operation["microdescs"];
+        assert_eq!(md.count, 20);
+        assert_eq!(md.n_slow, 0);
+        // Only the last 10 measurements are kept for percentiles.
+        assert_eq!(md.p50, ms(15));
+        assert_eq!(md.p99, ms(20));
+        assert_eq!(md.max, ms(20));
+
+        let cons = report.by_operation["store_consensus"];
+        assert_eq!(cons.count, 1);
+        assert_eq!(cons.n_slow, 1);
+        assert_eq!(cons.p50, ms(250));
+
+        assert_eq!(report.overall.count, 21);
+        assert_eq!(report.overall.n_slow, 1);
+        assert_eq!(report.overall.max, ms(250));
+        assert_eq!(report.overall.p50, ms(16));
+
+        // The closure's value comes back unchanged.
+        assert_eq!(timings.time("expire_all", || 7), 7);
+        assert_eq!(timings.report().by_operation["expire_all"].count, 1);
+    }
+}
@@ -0,0 +1,103 @@
+//! A mechanism for overriding the timing decisions that the directory
+//! manager makes.
+//!
+//! This module and its members are only public when `tor-dirmgr` is built
+//! with the `dirtiming` feature.
+//!
+//! This is unstable code, intended for research into how the timing of
+//! directory downloads can be used to fingerprint clients.  It might go away
+//! in future versions, or its API might change completely. There are no semver
+//! guarantees.
+
+use std::fmt::Debug;
+#[cfg(feature = "dirtiming")]
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tor_basic_utils::retry::RetryDelay;
+use tor_netdoc::doc::netstatus::Lifetime;
+
+use crate::DirMgrConfig;
+
+/// Timing configuration, as provided to the directory code.
+#[cfg(feature = "dirtiming")]
+pub type TimingConfig = Option<Arc<dyn DirTiming>>;
+
+/// An object that decides when the directory manager should do things.
+///
+/// Every method has a default implementation that matches the behavior of
+/// [`DefaultTiming`], so an implementation only needs to override the
+/// decisions it wants to change.
+#[cfg_attr(docsrs, doc(cfg(feature = "dirtiming")))]
+#[cfg_attr(not(feature = "dirtiming"), allow(unreachable_pub))]
+pub trait DirTiming: Debug + Send + Sync {
+    /// Return the time at which we should start trying to replace a
+    /// consensus whose lifetime is `lifetime`.
+    ///
+    /// By default, this is chosen uniformly at random from the range given in
+    /// the dir-spec.
+    fn consensus_download_time(&self, lifetime: &Lifetime) -> SystemTime {
+        crate::state::pick_download_time(lifetime)
+    }
+
+    /// Return how long we should wait before our next download attempt,
+    /// advancing `retry` as appropriate.
+    ///
+    /// By default, this uses `retry`'s own randomized backoff.
+    fn retry_delay(&self, retry: &mut RetryDelay) -> Duration {
+        retry.next_delay(&mut rand::thread_rng())
+    }
+
+    /// Return how long we should wait before launching the `idx`th request
+    /// (counting from zero) in a batch of `n_requests` directory requests.
+    ///
+    /// Note that this delay is in addition to the limit on how many
+    /// requests we launch in parallel.
+    ///
+    /// By default, this is always zero.
+    fn request_delay(&self, idx: usize, n_requests: usize) -> Duration {
+        let _ = (idx, n_requests);
+        Duration::ZERO
+    }
+}
+
+/// A [`DirTiming`] that makes the same decisions as an unmodified directory
+/// manager.
+#[derive(Debug)]
+#[allow(clippy::exhaustive_structs)]
+#[cfg_attr(docsrs, doc(cfg(feature = "dirtiming")))]
+#[cfg_attr(not(feature = "dirtiming"), allow(unreachable_pub))]
+pub struct DefaultTiming;
+
+impl DirTiming for DefaultTiming {}
+
+/// Return the [`DirTiming`] that we should use with `config`.
+pub(crate) fn policy(config: &DirMgrConfig) -> &dyn DirTiming {
+    #[cfg(feature = "dirtiming")]
+    if let Some(timing) = &config.extensions.timing {
+        return timing.as_ref();
+    }
+    #[cfg(not(feature = "dirtiming"))]
+    let _ = config;
+
+    &DefaultTiming
+}
+
+/// Return the time at which we should start trying to replace a consensus
+/// whose lifetime is `lifetime`, according to `config`.
+///
+/// A custom [`DirTiming`] takes precedence over the configured
+/// `prefetch_lead_time`; otherwise, we use that lead time if it is nonzero.
+pub(crate) fn consensus_download_time(config: &DirMgrConfig, lifetime: &Lifetime) -> SystemTime {
+    #[cfg(feature = "dirtiming")]
+    if config.extensions.timing.is_some() {
+        return policy(config).consensus_download_time(lifetime);
+    }
+
+    let lead_time = config.schedule.prefetch_lead_time;
+    if lead_time.is_zero() {
+        policy(config).consensus_download_time(lifetime)
+    } else {
+        crate::state::pick_prefetch_time(lifetime, lead_time)
+    }
+}
@@ -0,0 +1,352 @@
+//! Checking a consensus document offline.
+//!
+//! Tools that archive or audit consensus documents want to know whether a
+//! given document would have been accepted, and by whose signatures, without
+//! running a whole [`DirMgr`](crate::DirMgr).  [`verify_consensus_document`]
+//! runs the same checks that we use when bootstrapping, on documents that
+//! the caller provides.
+
+use std::collections::HashSet;
+use std::time::SystemTime;
+
+use tor_checkable::{ExternallySigned as _, Timebound as _};
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdoc::doc::authcert::{AuthCert, AuthCertKeyIds};
+#[cfg(feature = "ns_consensus")]
+use tor_netdoc::doc::netstatus::NsConsensus;
+use tor_netdoc::doc::netstatus::{
+    ConsensusFlavor, Lifetime, MdConsensus, SignatureStatus, UncheckedConsensus,
+};
+
+use crate::state::{check_authcert, check_consensus_authorities};
+use crate::{DirTolerance, DocSource, Error, Result};
+
+/// The outcome of checking a single signature on a consensus.
+#[derive(Clone, Debug)]
+pub struct SignatureCheck {
+    /// The keys of the certificate that made the signature.
+    key_ids: AuthCertKeyIds,
+    /// True if the signature is from one of the authorities that we trust.
+    trusted: bool,
+    /// What we found out about the signature.
+    status: SignatureStatus,
+}
+
+impl SignatureCheck {
+    /// Return the identity of the authority that claims to have made this
+    /// signature.
+    pub fn authority_id(&self) -> &RsaIdentity {
+        &self.key_ids.id_fingerprint
+    }
+
+    /// Return the identity and signing keys of the certificate that claims
+    /// to have made this signature.
+    pub fn key_ids(&self) -> &AuthCertKeyIds {
+        &self.key_ids
+    }
+
+    /// Return true if this signature claims to be from one of the
+    /// authorities that we were told to trust.
+    ///
+    /// We never use a certificate from any other authority, so the status
+    /// of a signature from an untrusted authority is always
+    /// [`SignatureStatus::MissingCert`].
+    pub fn is_trusted(&self) -> bool {
+        self.trusted
+    }
+
+    /// Return what we found out about this signature.
+    pub fn status(&self) -> SignatureStatus {
+        self.status
+    }
+}
+
+/// A report from [`verify_consensus_document`].
+#[derive(Clone, Debug)]
+pub struct ConsensusVerification {
+    /// The flavor of the consensus.
+    flavor: ConsensusFlavor,
+    /// The lifetime of the consensus.
+    lifetime: Lifetime,
+    /// Each signature on the consensus, in the order in which it appears.
+    signatures: Vec<SignatureCheck>,
+    /// The reasons for which we rejected any of the certificates we were
+    /// given.
+    rejected_certs: Vec<Error>,
+    /// If the consensus is not well-signed, the reason why.
+    error: Option<Error>,
+}
+
+impl ConsensusVerification {
+    /// Return the flavor of the consensus.
+    pub fn flavor(&self) -> ConsensusFlavor {
+        self.flavor
+    }
+
+    /// Return the lifetime that the consensus declares.
+    pub fn lifetime(&self) -> &Lifetime {
+        &self.lifetime
+    }
+
+    /// Return true if enough trusted authorities have validly signed the
+    /// consensus for us to accept it.
+    pub fn is_valid(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// If the consensus is not adequately signed, return the error that we
+    /// would have reported when bootstrapping.
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+
+    /// Return every signature on the consensus, in the order in which they
+    /// appear.
+    pub fn signatures(&self) -> &[SignatureCheck] {
+        &self.signatures[..]
+    }
+
+    /// Return the identities of the trusted authorities that validly signed
+    /// the consensus.
+    pub fn signed_by(&self) -> impl Iterator<Item = &RsaIdentity> + '_ {
+        self.signatures
+            .iter()
+            .filter(|sig| sig.trusted && sig.status == SignatureStatus::Valid)
+            .map(SignatureCheck::authority_id)
+    }
+
+    /// Return every signature that we tried to check and found to be invalid.
+    pub fn failed_signatures(&self) -> impl Iterator<Item = &SignatureCheck> + '_ {
+        self.signatures
+            .iter()
+            .filter(|sig| sig.status == SignatureStatus::Invalid)
+    }
+
+    /// Return the reasons for which we rejected any of the certificates that
+    /// we were given.
+    ///
+    /// A rejected certificate doesn't make the consensus invalid by itself,
+    /// but it may be why some of its signatures are
+    /// [`MissingCert`](SignatureStatus::MissingCert).
+    pub fn rejected_certs(&self) -> &[Error] {
+        &self.rejected_certs[..]
+    }
+}
+
+/// Check whether the consensus in `text` is adequately signed by
+/// `authorities`, using the authority certificates in `certs`, as of `now`.
+///
+/// This applies the same rules as bootstrapping, with the default
+/// [`DirTolerance`]:
+///
+///  * The consensus must be well-formed, and valid at `now` (allowing for
+///    the tolerance); otherwise we return an error.
+///  * It must claim to be signed by enough of `authorities` that it could
+///    be valid; otherwise we return [`Error::UnrecognizedAuthorities`].
+///  * We only use certificates from `authorities` that are well-signed and
+///    valid at `now`.  Others are reported in
+///    [`ConsensusVerification::rejected_certs`], or ignored if they are from
+///    other authorities.
+///
+/// If we get this far, we check every signature, and report whether the
+/// consensus is valid, along with the outcome for each signature.
+///
+/// `certs` may hold any number of concatenated certificates.  We accept
+/// microdescriptor consensuses, and (with the `ns_consensus` feature)
+/// ns-flavored ones.
+pub fn verify_consensus_document(
+    text: &str,
+    certs: &str,
+    authorities: &[RsaIdentity],
+    now: SystemTime,
+) -> Result<ConsensusVerification> {
+    let source = DocSource::Caller;
+    match flavor_of(text)? {
+        ConsensusFlavor::Microdesc => {
+            let (_, _, parsed) =
+                MdConsensus::parse(text).map_err(|e| Error::from_netdoc(source, e))?;
+            verify_parsed(ConsensusFlavor::Microdesc, parsed, certs, authorities, now)
+        }
+        #[cfg(feature = "ns_consensus")]
+        ConsensusFlavor::Ns => {
+            let (_, _, parsed) =
+                NsConsensus::parse(text).map_err(|e| Error::from_netdoc(source, e))?;
+            verify_parsed(ConsensusFlavor::Ns, parsed, certs, authorities, now)
+        }
+        #[allow(unreachable_patterns)]
+        flavor => Err(Error::UnsupportedFlavor(flavor)),
+    }
+}
+
+/// Helper: return the flavor that the first line of `text` declares.
+///
+/// If the first line isn't a consensus header at all, we return
+/// [`ConsensusFlavor::Microdesc`], and let the parser report the problem.
+fn flavor_of(text: &str) -> Result<ConsensusFlavor> {
+    let mut words = text.lines().next().unwrap_or_default().split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("network-status-version"), Some("3")) => ConsensusFlavor::from_opt_name(words.next())
+            .map_err(|e| Error::from_netdoc(DocSource::Caller, e)),
+        _ => Ok(ConsensusFlavor::Microdesc),
+    }
+}
+
+/// Helper: check a freshly parsed consensus of any flavor, as for
+/// [`verify_consensus_document`].
+fn verify_parsed<RS>(
+    flavor: ConsensusFlavor,
+    parsed: UncheckedConsensus<RS>,
+    certs: &str,
+    authorities: &[RsaIdentity],
+    now: SystemTime,
+) -> Result<ConsensusVerification> {
+    let tolerance = DirTolerance::default();
+    let timely = tolerance.extend_tolerance(parsed).check_valid_at(&now)?;
+    let lifetime = timely.peek_lifetime().clone();
+    let (unvalidated, desired_certs) = check_consensus_authorities(timely, authorities)?;
+
+    let (certs, rejected_certs) = usable_certs(certs, &desired_certs, &tolerance, now);
+
+    let signatures = unvalidated
+        .signature_statuses(&certs[..])
+        .into_iter()
+        .map(|(key_ids, status)| SignatureCheck {
+            key_ids,
+            trusted: authorities.contains(&key_ids.id_fingerprint),
+            status,
+        })
+        .collect();
+    let error =
+        unvalidated
+            .check_signature(&certs[..])
+            .err()
+            .map(|cause| Error::ConsensusInvalid {
+                source: DocSource::Caller,
+                cause,
+            });
+
+    Ok(ConsensusVerification {
+        flavor,
+        lifetime,
+        signatures,
+        rejected_certs,
+        error,
+    })
+}
+
+/// Helper: parse and check the certificates in `text`, and return the ones
+/// in `desired` that we can use, along with the errors from any that we
+/// couldn't.
+fn usable_certs(
+    text: &str,
+    desired: &HashSet<AuthCertKeyIds>,
+    tolerance: &DirTolerance,
+    now: SystemTime,
+) -> (Vec<AuthCert>, Vec<Error>) {
+    let mut certs = Vec::new();
+    let mut rejected = Vec::new();
+    for parsed in AuthCert::parse_multiple(text) {
+        let checked = parsed
+            .map_err(|e| Error::from_netdoc(DocSource::Caller, e))
+            .and_then(|cert| check_authcert(cert, tolerance, now));
+        match checked {
+            Ok(cert) if desired.contains(cert.key_ids()) => certs.push(cert),
+            // This certificate can't help us.
+            Ok(_) => {}
+            Err(e) => rejected.push(e),
+        }
+    }
+    (certs, rejected)
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use std::time::Duration;
+    use time::macros::datetime;
+
+    const CONSENSUS: &str = include_str!("../testdata/mdconsensus1.txt");
+    const AUTHCERT_5696: &str = include_str!("../testdata/cert-5696.txt");
+    const AUTHCERT_5A23: &str = include_str!("../testdata/cert-5A23.txt");
+    const AUTHCERT_7C47: &str = include_str!("../testdata/cert-7C47.txt");
+
+    fn test_time() -> SystemTime {
+        datetime!(2020-08-07 12:42:45 UTC).into()
+    }
+    fn rsa(s: &str) -> RsaIdentity {
+        RsaIdentity::from_hex(s).unwrap()
+    }
+    fn authorities() -> Vec<RsaIdentity> {
+        // The consensus is also signed by 7C47..., which we don't trust.
+        vec![
+            rsa("5696AB38CB3852AFA476A5C07B2D4788963D5567"),
+            rsa("5A23BA701776C9C1AB1C06E734E92AB3D5350D64"),
+        ]
+    }
+
+    #[test]
+    fn verify_consensus() {
+        let auths = authorities();
+        let all_certs = format!("{}{}{}", AUTHCERT_5696, AUTHCERT_5A23, AUTHCERT_7C47);
+        let v = verify_consensus_document(CONSENSUS, &all_certs, &auths, test_time()).unwrap();
+        assert_eq!(v.flavor(), ConsensusFlavor::Microdesc);
+        assert!(v.is_valid());
+        assert!(v.error().is_none());
+        assert!(v.rejected_certs().is_empty());
+        assert_eq!(v.signed_by().cloned().collect::<Vec<_>>(), auths);
+        assert_eq!(v.failed_signatures().count(), 0);
+        let untrusted: Vec<_> = v.signatures().iter().filter(|s| !s.is_trusted()).collect();
+        assert_eq!(untrusted.len(), 1);
+        assert_eq!(
+            untrusted[0].authority_id(),
+            &rsa("7C47DCB4A90E2C2B7C7AD27BD641D038CF5D7EBE")
+        );
+        assert_eq!(untrusted[0].status(), SignatureStatus::MissingCert);
+
+        // With only one of the two certificates we need, the consensus isn't
+        // valid, but we can still say who signed it.
+        let v = verify_consensus_document(CONSENSUS, AUTHCERT_5696, &auths, test_time()).unwrap();
+        assert!(!v.is_valid());
+        assert!(v.error().is_some());
+        assert_eq!(v.signed_by().collect::<Vec<_>>(), vec![&auths[0]]);
+        let missing = v
+            .signatures()
+            .iter()
+            .find(|s| s.authority_id() == &auths[1])
+            .unwrap();
+        assert_eq!(missing.status(), SignatureStatus::MissingCert);
+    }
+
+    #[test]
+    fn verify_rejects() {
+        let auths = authorities();
+        let certs = format!("{}{}", AUTHCERT_5696, AUTHCERT_5A23);
+
+        // Not signed by the authorities we trust.
+        let others = vec![rsa("0000000000000000000000000000000000000000")];
+        assert!(matches!(
+            verify_consensus_document(CONSENSUS, &certs, &others, test_time()),
+            Err(Error::UnrecognizedAuthorities)
+        ));
+
+        // Long expired.
+        let later = test_time() + Duration::from_secs(30 * 86400);
+        assert!(verify_consensus_document(CONSENSUS, &certs, &auths, later).is_err());
+
+        // Not a consensus at all.
+        assert!(verify_consensus_document("hello world\n", &certs, &auths, test_time()).is_err());
+    }
+}
@@ -0,0 +1,305 @@
+//! Downloading and storing directory authority votes and detached signatures.
+//!
+//! Clients don't need votes: they only use the consensus that the
+//! authorities compute from them.  But tools that monitor the health of the
+//! consensus process want to compare each authority's vote with the
+//! consensus, so when the `votes` feature is enabled, we can fetch the votes
+//! from the latest voting round, keep them in our cache, and hand them out
+//! as parsed documents.
+//!
+//! Only the authorities serve votes, so we fetch them directly from each
+//! authority that our current directory lists, rather than from a directory
+//! cache.
+
+use tor_checkable::{SelfSigned as _, Timebound as _};
+use tor_dirclient::request::{DetachedSignaturesRequest, Requestable, VoteRequest};
+use tor_dirclient::{RequestError, RequestFailedError};
+use tor_error::warn_report;
+use tor_linkspec::OwnedChanTarget;
+use tor_netdir::RelayFlagQuery;
+use tor_netdoc::doc::netstatus::{ConsensusFlavor, DetachedSignatures, RelayFlags, Vote};
+use tor_rtcompat::Runtime;
+use tracing::{debug, warn};
+
+use crate::{DirMgr, DocSource, Error, Result};
+
+impl<R: Runtime> DirMgr<R> {
+    /// Add `text`, an authority's vote, to our cache.
+    ///
+    /// We check that the vote comes from one of our configured authorities,
+    /// and that it is signed with the certificate that it contains.  We don't
+    /// check whether it is timely: a monitoring tool may have good reason to
+    /// look at an old vote.
+    ///
+    /// Votes from earlier voting rounds are discarded.
+    ///
+    /// Return true if we stored the vote, or false if our cache is read-only
+    /// (for example, because another process is using it).
+    pub fn add_vote(&self, text: &str) -> Result<bool> {
+        let vote = Vote::parse(text)
+            .map_err(|e| Error::from_netdoc(DocSource::Caller, e))?
+            .check_signature()?
+            .dangerously_assume_timely();
+        let config = self.config.get();
+        if !config
+            .authorities()
+            .iter()
+            .any(|auth| &auth.v3ident == vote.authority_id())
+        {
+            return Err(Error::UnrecognizedAuthorities);
+        }
+
+        let mut store = self.store.lock().expect("store lock poisoned");
+        if store.is_readonly() {
+            return Ok(false);
+        }
+        store.store_vote(vote.authority_id(), vote.lifetime().valid_after(), text)?;
+        Ok(true)
+    }
+
+    /// Add `text`, a set of detached signatures on the upcoming consensus, to
+    /// our cache, replacing any earlier ones.
+    ///
+    /// We reject the document unless at least one of its signatures comes
+    /// from one of our configured authorities.  (We store the text as we
+    /// received it, and drop the other signatures again when we parse it in
+    /// [`latest_detached_signatures`](Self::latest_detached_signatures).)
+    ///
+    /// Return true if we stored the signatures, or false if our cache is
+    /// read-only.
+    pub fn add_detached_signatures(&self, text: &str) -> Result<bool> {
+        let sigs = self.parse_detached_signatures(text, DocSource::Caller)?;
+        if sigs.signers(ConsensusFlavor::Ns).next().is_none()
+            && sigs.signers(ConsensusFlavor::Microdesc).next().is_none()
+        {
+            return Err(Error::UnrecognizedAuthorities);
+        }
+
+        let mut store = self.store.lock().expect("store lock poisoned");
+        if store.is_readonly() {
+            return Ok(false);
+        }
+        store.store_detached_signatures(sigs.lifetime().valid_after(), text)?;
+        Ok(true)
+    }
+
+    /// Return every vote that we have stored from the latest voting round,
+    /// ordered by the identity of the authority that cast it.
+    ///
+    /// Returns an empty list if we have no votes.
+    pub fn latest_votes(&self) -> Result<Vec<Vote>> {
+        let texts = self
+            .store
+            .lock()
+            .expect("store lock poisoned")
+            .latest_votes()?;
+        let votes = texts
+            .iter()
+            .filter_map(|text| match Vote::parse(text) {
+                // We checked the signature before we stored the vote.
+                Ok(vote) => Some(
+                    vote.dangerously_assume_wellsigned()
+                        .dangerously_assume_timely(),
+                ),
+                Err(e) => {
+                    let e = Error::from_netdoc(DocSource::LocalCache, e);
+                    warn_report!(e, "Unable to parse a vote from our cache; ignoring");
+                    None
+                }
+            })
+            .collect();
+        Ok(votes)
+    }
+
+    /// Return the latest detached signatures that we have stored, if any.
+    pub fn latest_detached_signatures(&self) -> Result<Option<DetachedSignatures>> {
+        let text = self
+            .store
+            .lock()
+            .expect("store lock poisoned")
+            .latest_detached_signatures()?;
+        text.map(|text| self.parse_detached_signatures(&text, DocSource::LocalCache))
+            .transpose()
+    }
+
+    /// Parse `text` as a set of detached signatures, and discard every
+    /// signature that doesn't come from one of our configured authorities.
+    fn parse_detached_signatures(
+        &self,
+        text: &str,
+        source: DocSource,
+    ) -> Result<DetachedSignatures> {
+        let mut sigs =
+            DetachedSignatures::parse(text).map_err(|e| Error::from_netdoc(source, e))?;
+        let config = self.config.get();
+        let authorities = config.authorities();
+        sigs.retain_signers(|key_ids| {
+            authorities
+                .iter()
+                .any(|auth| auth.v3ident == key_ids.id_fingerprint)
+        });
+        Ok(sigs)
+    }
+
+    /// Download the current votes and detached signatures from every
+    /// authority in our current directory, and add them to our cache.
+    ///
+    /// Failures to reach a single authority, or bad documents from one, are
+    /// logged and otherwise ignored.  Return the number of votes that we
+    /// stored: this is zero if our cache is read-only.
+    ///
+    /// Authorities only serve votes and detached signatures during part of
+    /// each voting period, so a monitoring tool should call this some time
+    /// after the votes are due.
+    pub async fn download_votes(&self) -> Result<usize> {
+        let circmgr = self.circmgr()?;
+        let targets: Vec<OwnedChanTarget> = {
+            let netdir = self.netdir.get().ok_or(Error::DirectoryNotPresent)?;
+            let query = RelayFlagQuery::new().require(RelayFlags::AUTHORITY);
+            netdir
+                .relays_with_flags(&query)
+                .map(|relay| OwnedChanTarget::from_chan_target(&relay))
+                .collect()
+        };
+
+        let mut n_added = 0;
+        let mut have_sigs = false;
+        for target in targets {
+            let vote = self
+                .fetch_from(&circmgr, &target, &VoteRequest::new())
+                .await;
+            match vote.and_then(|text| self.add_vote(&text)) {
+                Ok(true) => n_added += 1,
+                Ok(false) => debug!("Not storing vote from {}: cache is read-only", target),
+                Err(e) => warn_report!(e, "Unable to fetch a vote from {}", target),
+            }
+            if have_sigs {
+                continue;
+            }
+            let sigs = self
+                .fetch_from(&circmgr, &target, &DetachedSignaturesRequest::new())
+                .await;
+            match sigs.and_then(|text| self.add_detached_signatures(&text)) {
+                Ok(stored) => {
+                    if !stored {
+                        debug!("Not storing detached signatures: cache is read-only");
+                    }
+                    have_sigs = true;
+                }
+                Err(e) => debug!("Unable to fetch detached signatures from {}: {}", target, e),
+            }
+        }
+        if !have_sigs {
+            warn!("Unable to fetch detached signatures from any authority");
+        }
+        Ok(n_added)
+    }
+
+    /// Send `request` directly to the relay `target`, and return the body of
+    /// its response.
+    async fn fetch_from<Q: Requestable + Sync>(
+        &self,
+        circmgr: &tor_circmgr::CircMgr<R>,
+        target: &OwnedChanTarget,
+        request: &Q,
+    ) -> Result<String> {
+        let circ = circmgr
+            .get_or_launch_dir_specific(target.clone())
+            .await
+            .map_err(|e| Error::DirClientError(e.into()))?;
+        let mut stream = circ.begin_dir_stream().await.map_err(|e| {
+            Error::DirClientError(
+                RequestFailedError {
+                    source: None,
+                    error: RequestError::Proto(e),
+                }
+                .into(),
+            )
+        })?;
+        let response =
+            tor_dirclient::send_request(&self.runtime, request, &mut stream, None).await?;
+        response
+            .into_output_string()
+            .map_err(|e| Error::DirClientError(e.into()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::test::new_mgr;
+    use crate::{Authority, DirMgrStore};
+
+    /// A vote from the authority in tor-netdoc's test data.
+    const VOTE: &str = include_str!("../../tor-netdoc/testdata/vote1.txt");
+    /// Detached signatures from tor-netdoc's test data.
+    const SIGS: &str = include_str!("../../tor-netdoc/testdata/detached-sigs1.txt");
+
+    #[test]
+    fn add_votes() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            assert!(mgr.latest_votes().unwrap().is_empty());
+            assert!(mgr.latest_detached_signatures().unwrap().is_none());
+
+            // The authority isn't one of ours.
+            assert!(matches!(
+                mgr.add_vote(VOTE),
+                Err(Error::UnrecognizedAuthorities)
+            ));
+            assert!(matches!(
+                mgr.add_detached_signatures(SIGS),
+                Err(Error::UnrecognizedAuthorities)
+            ));
+            assert!(mgr.latest_detached_signatures().unwrap().is_none());
+            assert!(mgr.add_vote("not a vote").is_err());
+
+            let id = Vote::parse(VOTE)
+                .unwrap()
+                .dangerously_assume_wellsigned()
+                .dangerously_assume_timely()
+                .authority_id()
+                .clone();
+            let mut config = (*mgr.config.get()).clone();
+            config.network.authorities.push(
+                Authority::builder()
+                    .name("test-auth")
+                    .v3ident(id)
+                    .build()
+                    .unwrap(),
+            );
+            mgr.config.replace(config);
+
+            assert!(mgr.add_vote(VOTE).unwrap());
+            let votes = mgr.latest_votes().unwrap();
+            assert_eq!(votes.len(), 1);
+            assert_eq!(votes[0].authority_id(), &id);
+
+            assert!(mgr.add_detached_signatures(SIGS).unwrap());
+            let sigs = mgr.latest_detached_signatures().unwrap().unwrap();
+            assert_eq!(sigs.signers(ConsensusFlavor::Ns).count(), 1);
+
+            // A manager with a read-only cache accepts the documents, but
+            // tells us that it didn't store them.
+            let config = (*mgr.config.get()).clone();
+            let store = DirMgrStore::new(&config, rt.clone(), true).unwrap();
+            let readonly = DirMgr::from_config(config, rt.clone(), store, None, true).unwrap();
+            assert!(readonly.store.lock().unwrap().is_readonly());
+            assert!(!readonly.add_vote(VOTE).unwrap());
+            assert!(!readonly.add_detached_signatures(SIGS).unwrap());
+        });
+    }
+}
@@ -0,0 +1,262 @@
+//! Build script for `tor-geoip`.
+//!
+//! If the `embedded-db` feature is enabled, we convert the legacy-format
+//! databases in `data/` into sorted arrays, so that the crate can embed them
+//! without having to parse anything at runtime.
+//!
+//! The arrays have the same layout as a `RangeTable` in `src/table.rs`: each
+//! entry gives the first address of a range, and the index of its country
+//! code and ASN in a shared table of definitions.  Each range runs until the
+//! start of the next one.  Definition 0 means "no information", and is used
+//! to fill the gaps between the ranges in the database.
+//!
+//! We write the (small) table of definitions as Rust source to
+//! `$OUT_DIR/embedded_db.rs`.  We write the (large) arrays of range starts
+//! and definition indices as big-endian binary files in `$OUT_DIR`, which
+//! `embedded_db.rs` includes with `include_bytes!`: the compiler handles
+//! those far faster than it would handle array literals.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt::{Display, Write as _};
+use std::fs;
+use std::net::Ipv6Addr;
+use std::path::Path;
+
+/// A country code and ASN, as they will appear in the generated table.
+type Defn = ([u8; 2], u32);
+
+/// The definition that we use for addresses with no information.
+const EMPTY: Defn = (*b"??", 0);
+
+/// The definitions that we have seen so far, and their indices.
+#[derive(Default)]
+struct Defns {
+    /// The definitions, in the order in which we first saw them.
+    list: Vec<Defn>,
+    /// The index of each definition in `list`.
+    index: HashMap<Defn, u32>,
+}
+
+impl Defns {
+    /// Return the index of `defn`, adding it if we haven't seen it before.
+    fn index_of(&mut self, defn: Defn) -> u32 {
+        *self.index.entry(defn).or_insert_with(|| {
+            self.list.push(defn);
+            u32::try_from(self.list.len() - 1).expect("too many definitions")
+        })
+    }
+}
+
+/// Parse one legacy-format database into a sorted list of
+/// `(first, last, defn)` entries.
+///
+/// `parse_addr` parses an address into a key.
+fn parse_db<K: Ord + Copy + Display>(
+    name: &str,
+    text: &str,
+    parse_addr: impl Fn(&str) -> Option<K>,
+) -> Vec<(K, K, Defn)> {
+    let mut entries = Vec::new();
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fail = |msg: &str| -> ! { panic!("{}:{}: {}", name, lineno + 1, msg) };
+        let mut split = line.split(',');
+        let mut field = || split.next().unwrap_or_else(|| fail("too few fields"));
+        let from = parse_addr(field()).unwrap_or_else(|| fail("bad address"));
+        let to = parse_addr(field()).unwrap_or_else(|| fail("bad address"));
+        let cc = field().to_ascii_uppercase();
+        let asn = match split.next() {
+            Some(asn) => asn.parse().unwrap_or_else(|_| fail("bad ASN")),
+            None => 0,
+        };
+        let cc: [u8; 2] = cc
+            .as_bytes()
+            .try_into()
+            .unwrap_or_else(|_| fail("bad country code"));
+        if !cc.iter().all(|b| b.is_ascii_graphic()) {
+            fail("bad country code");
+        }
+        if from > to {
+            fail("range ends before it starts");
+        }
+        entries.push((from, to, (cc, asn)));
+    }
+    entries.sort_by_key(|(from, _, _)| *from);
+    for pair in entries.windows(2) {
+        if pair[1].0 <= pair[0].1 {
+            panic!("{}: overlapping ranges at {}", name, pair[1].0);
+        }
+    }
+    entries
+}
+
+/// Convert sorted, non-overlapping `entries` into arrays of range starts and
+/// definition indices.
+///
+/// `next` returns the address after its argument, or `None` if there is none.
+fn to_table<K: Ord + Copy>(
+    entries: &[(K, K, Defn)],
+    defns: &mut Defns,
+    next: impl Fn(K) -> Option<K>,
+) -> (Vec<K>, Vec<u32>) {
+    let mut starts = Vec::new();
+    let mut idxs: Vec<u32> = Vec::new();
+    let mut push = |start: K, idx: u32| {
+        if idxs.last() != Some(&idx) {
+            starts.push(start);
+            idxs.push(idx);
+        }
+    };
+    let mut next_start = None;
+    for (from, to, defn) in entries {
+        if let Some(next) = next_start {
+            if next != *from {
+                push(next, 0);
+            }
+        }
+        push(*from, defns.index_of(*defn));
+        next_start = next(*to);
+    }
+    if let Some(next) = next_start {
+        push(next, 0);
+    }
+    (starts, idxs)
+}
+
+/// A type that we can write to a binary file in big-endian form.
+trait ToBeBytes {
+    /// Append the big-endian encoding of `self` to `out`.
+    fn append_be(&self, out: &mut Vec<u8>);
+}
+
+impl ToBeBytes for u32 {
+    fn append_be(&self, out: &mut Vec<u8>) {
+        out.extend(self.to_be_bytes());
+    }
+}
+
+impl ToBeBytes for u128 {
+    fn append_be(&self, out: &mut Vec<u8>) {
+        out.extend(self.to_be_bytes());
+    }
+}
+
+/// Write `items` in big-endian form to a file called `fname` in `out_dir`,
+/// and declare a static byte slice called `name` that includes it to `out`.
+fn write_binary<T: ToBeBytes>(
+    out: &mut String,
+    out_dir: &Path,
+    doc: &str,
+    name: &str,
+    fname: &str,
+    items: &[T],
+) {
+    let mut bytes = Vec::new();
+    for item in items {
+        item.append_be(&mut bytes);
+    }
+    let path = out_dir.join(fname);
+    fs::write(&path, bytes).unwrap_or_else(|e| panic!("can't write {:?}: {}", path, e));
+    writeln!(out, "/// {}", doc).expect("write to string failed");
+    writeln!(
+        out,
+        "pub(super) static {}: &[u8] = include_bytes!(concat!(env!(\"OUT_DIR\"), \"/{}\"));",
+        name, fname
+    )
+    .expect("write to string failed");
+}
+
+/// Write a static array called `name`, with elements of type `ty`, to `out`.
+fn write_array<T: Display>(out: &mut String, doc: &str, name: &str, ty: &str, items: &[T]) {
+    writeln!(out, "/// {}", doc).expect("write to string failed");
+    writeln!(
+        out,
+        "pub(super) static {}: [{}; {}] = [",
+        name,
+        ty,
+        items.len()
+    )
+    .expect("write to string failed");
+    for chunk in items.chunks(16) {
+        let line: Vec<String> = chunk.iter().map(ToString::to_string).collect();
+        writeln!(out, "    {},", line.join(", ")).expect("write to string failed");
+    }
+    writeln!(out, "];").expect("write to string failed");
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    if env::var_os("CARGO_FEATURE_EMBEDDED_DB").is_none() {
+        return;
+    }
+    println!("cargo:rerun-if-changed=data/geoip");
+    println!("cargo:rerun-if-changed=data/geoip6");
+
+    let read = |name: &str| {
+        fs::read_to_string(name).unwrap_or_else(|e| panic!("can't read {}: {}", name, e))
+    };
+    let v4 = parse_db("data/geoip", &read("data/geoip"), |s| s.parse::<u32>().ok());
+    let v6 = parse_db("data/geoip6", &read("data/geoip6"), |s| {
+        s.parse::<Ipv6Addr>().ok().map(u128::from)
+    });
+
+    let mut defns = Defns::default();
+    assert_eq!(defns.index_of(EMPTY), 0);
+    let (v4_starts, v4_idx) = to_table(&v4, &mut defns, |k| k.checked_add(1));
+    let (v6_starts, v6_idx) = to_table(&v6, &mut defns, |k| k.checked_add(1));
+
+    let out_dir = env::var_os("OUT_DIR").expect("OUT_DIR not set");
+    let out_dir = Path::new(&out_dir);
+    let mut out = String::new();
+    let defn_exprs: Vec<String> = defns
+        .list
+        .iter()
+        .map(|(cc, asn)| format!("NetDefn::from_raw([{}, {}], {})", cc[0], cc[1], asn))
+        .collect();
+    write_array(
+        &mut out,
+        "The distinct definitions in the embedded database.",
+        "EMBEDDED_DEFNS",
+        "NetDefn",
+        &defn_exprs,
+    );
+    write_binary(
+        &mut out,
+        out_dir,
+        "The first address of each IPv4 range, as big-endian `u32`s.",
+        "EMBEDDED_V4_STARTS",
+        "embedded_v4_starts.bin",
+        &v4_starts,
+    );
+    write_binary(
+        &mut out,
+        out_dir,
+        "The index in `EMBEDDED_DEFNS` of each IPv4 range's definition, as big-endian `u32`s.",
+        "EMBEDDED_V4_DEFN_IDX",
+        "embedded_v4_defn_idx.bin",
+        &v4_idx,
+    );
+    write_binary(
+        &mut out,
+        out_dir,
+        "The first address of each IPv6 range, as big-endian `u128`s.",
+        "EMBEDDED_V6_STARTS",
+        "embedded_v6_starts.bin",
+        &v6_starts,
+    );
+    write_binary(
+        &mut out,
+        out_dir,
+        "The index in `EMBEDDED_DEFNS` of each IPv6 range's definition, as big-endian `u32`s.",
+        "EMBEDDED_V6_DEFN_IDX",
+        "embedded_v6_defn_idx.bin",
+        &v6_idx,
+    );
+
+    let path = out_dir.join("embedded_db.rs");
+    fs::write(&path, out).unwrap_or_else(|e| panic!("can't write {:?}: {}", path, e));
+}
@@ -2,6 +2,7 @@
 
 use std::net::AddrParseError;
 use std::num::ParseIntError;
+use std::sync::Arc;
 use thiserror::Error;
 
 /// An error type from the tor-geoip crate.
@@ -15,6 +16,10 @@ pub enum Error {
     #[error("Unsupported country code in file: {0}")]
     BadCountryCode(String),
 
+    /// We couldn't read a GeoIP database file.
+    #[error("Unable to read GeoIP data file")]
+    Io(#[source] Arc<std::io::Error>),
+
     /// Tried to use ?? somewhere that expected a country code.
     #[error("The 'nowhere' country code ('??') is not supported in this context.")]
     NowhereNotSupported,
@@ -48,28 +48,42 @@
 pub use crate::err::Error;
 use once_cell::sync::OnceCell;
 use rangemap::RangeInclusiveMap;
+use std::collections::{BTreeMap, HashSet};
 use std::fmt::{Debug, Display, Formatter};
-use std::net::{IpAddr, Ipv6Addr};
+use std::mem::size_of;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::num::{NonZeroU32, NonZeroU8, TryFromIntError};
+use std::ops::RangeInclusive;
 use std::str::FromStr;
 use std::sync::Arc;
 
 mod err;
+mod location;
+mod manager;
+#[cfg(feature = "mmdb")]
+mod mmdb;
+mod table;
 
-/// An embedded copy of the latest geoip v4 database at the time of compilation.
-///
-/// FIXME(eta): This does use a few megabytes of binary size, which is less than ideal.
-///             It would be better to parse it at compile time or something.
-#[cfg(feature = "embedded-db")]
-static EMBEDDED_DB_V4: &str = include_str!("../data/geoip");
+pub use location::Location;
+pub use manager::{GeoipDbManager, GeoipDbSource};
+use table::{RangeTable, TableKey};
 
-/// An embedded copy of the latest geoip v6 database at the time of compilation.
+/// An embedded copy of the latest geoip database at the time of compilation.
+///
+/// Our build script converts `data/geoip` and `data/geoip6` into sorted
+/// arrays (see [`RangeTable`]), which this file defines as
+/// `EMBEDDED_DEFNS`, `EMBEDDED_V4_STARTS`, `EMBEDDED_V4_DEFN_IDX`,
+/// `EMBEDDED_V6_STARTS`, and `EMBEDDED_V6_DEFN_IDX`.  All but the first are
+/// big-endian binary data, included with `include_bytes!`.
 #[cfg(feature = "embedded-db")]
-static EMBEDDED_DB_V6: &str = include_str!("../data/geoip6");
+mod embedded {
+    use crate::NetDefn;
+    include!(concat!(env!("OUT_DIR"), "/embedded_db.rs"));
+}
 
-/// A parsed copy of the embedded database.
+/// A shared copy of the embedded database.
 #[cfg(feature = "embedded-db")]
-static EMBEDDED_DB_PARSED: OnceCell<Arc<GeoipDb>> = OnceCell::new();
+static EMBEDDED_DB: OnceCell<Arc<GeoipDb>> = OnceCell::new();
 
 /// A two-letter country code.
 ///
@@ -85,7 +99,7 @@ static EMBEDDED_DB_PARSED: OnceCell<Arc<GeoipDb>> = OnceCell::new();
 /// countries; we do not include the pseudo-countries `A1` through `An` for
 /// "anonymous proxies", since doing so would mean putting nearly all Tor relays
 /// into one of those countries.
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct CountryCode {
     /// The underlying value (two printable ASCII characters, stored uppercase).
     ///
@@ -212,46 +226,208 @@ impl Display for OptionCc {
     }
 }
 
+/// An autonomous system number (ASN).
+///
+/// Every network that takes part in global Internet routing has one of these,
+/// so relays with the same `AsNumber` are likely to be under the control of
+/// the same operator, or at least to share an upstream.
+///
+/// The value 0 is reserved, and never refers to a real autonomous system; we
+/// don't allow it here.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct AsNumber(NonZeroU32);
+
+impl AsNumber {
+    /// Make a new `AsNumber`, or return `None` if `asn` is 0.
+    pub fn new(asn: u32) -> Option<Self> {
+        NonZeroU32::new(asn).map(Self)
+    }
+
+    /// Get the actual AS number.
+    pub fn get(&self) -> u32 {
+        self.0.get()
+    }
+}
+
+impl Display for AsNumber {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AS{}", self.0)
+    }
+}
+
+impl Debug for AsNumber {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AsNumber({})", self.0)
+    }
+}
+
+impl From<AsNumber> for u32 {
+    fn from(asn: AsNumber) -> u32 {
+        asn.get()
+    }
+}
+
 /// A country code / ASN definition.
 ///
 /// Type lifted from `geoip-db-tool` in the C-tor source.
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 struct NetDefn {
     /// The country code.
     ///
     /// We translate the value "??" into None.
     cc: Option<CountryCode>,
     /// The ASN, if we have one. We translate the value "0" into None.
-    asn: Option<NonZeroU32>,
+    asn: Option<AsNumber>,
 }
 
 impl NetDefn {
+    /// A `NetDefn` with no country code and no ASN.
+    const EMPTY: NetDefn = NetDefn {
+        cc: None,
+        asn: None,
+    };
+
     /// Make a new `NetDefn`.
     fn new(cc: &str, asn: Option<u32>) -> Result<Self, Error> {
-        let asn = NonZeroU32::new(asn.unwrap_or(0));
+        let asn = AsNumber::new(asn.unwrap_or(0));
         let cc = cc.parse::<OptionCc>()?.into();
 
         Ok(Self { cc, asn })
     }
 
+    /// Make a new `NetDefn` from a country code and an ASN, as written by our
+    /// build script.
+    ///
+    /// The country code must be two uppercase printable ASCII characters; we
+    /// translate `??` into None.  We translate an ASN of 0 into None.
+    ///
+    /// # Panics
+    ///
+    /// Panics if either byte of `cc` is zero.  (Since this is only used in
+    /// constants, that means that the crate won't compile.)
+    #[cfg(feature = "embedded-db")]
+    const fn from_raw(cc: [u8; 2], asn: u32) -> Self {
+        /// Convert `b` to a `NonZeroU8`, or panic.
+        const fn nz(b: u8) -> NonZeroU8 {
+            match NonZeroU8::new(b) {
+                Some(b) => b,
+                None => panic!("zero byte in country code"),
+            }
+        }
+        let cc = if cc[0] == b'?' && cc[1] == b'?' {
+            None
+        } else {
+            Some(CountryCode {
+                inner: [nz(cc[0]), nz(cc[1])],
+            })
+        };
+        let asn = match NonZeroU32::new(asn) {
+            Some(asn) => Some(AsNumber(asn)),
+            None => None,
+        };
+        Self { cc, asn }
+    }
+
     /// Return the country code.
     fn country_code(&self) -> Option<&CountryCode> {
         self.cc.as_ref()
     }
 
     /// Return the ASN, if there is one.
-    fn asn(&self) -> Option<u32> {
-        self.asn.as_ref().map(|x| x.get())
+    fn asn(&self) -> Option<AsNumber> {
+        self.asn
+    }
+}
+
+/// An inclusive range of IP addresses, all of the same family.
+///
+/// Returned by [`GeoipDb::ranges_for_country`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct IpRange {
+    /// The first address in the range.
+    start: IpAddr,
+    /// The last address in the range.
+    end: IpAddr,
+}
+
+impl IpRange {
+    /// Return the first address in this range.
+    pub fn start(&self) -> IpAddr {
+        self.start
+    }
+
+    /// Return the last address in this range.
+    pub fn end(&self) -> IpAddr {
+        self.end
+    }
+
+    /// Return true if `ip` is in this range.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.start, self.end, ip) {
+            (IpAddr::V4(start), IpAddr::V4(end), IpAddr::V4(ip)) => (start..=end).contains(&ip),
+            (IpAddr::V6(start), IpAddr::V6(end), IpAddr::V6(ip)) => (start..=end).contains(&ip),
+            _ => false,
+        }
     }
 }
 
+/// A summary of the addresses that a [`GeoipDb`] assigns to one country.
+///
+/// Returned by [`GeoipDb::country_stats`].
+///
+/// Adjacent ranges in the same country count as one range, as in
+/// [`GeoipDb::ranges_for_country`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[non_exhaustive]
+pub struct CountryStats {
+    /// The number of IPv4 ranges in the country.
+    pub n_ipv4_ranges: usize,
+    /// The number of IPv4 addresses in the country.
+    pub n_ipv4_addrs: u64,
+    /// The number of IPv6 ranges in the country.
+    pub n_ipv6_ranges: usize,
+    /// The number of IPv6 addresses in the country.
+    ///
+    /// (This saturates at `u128::MAX`.)
+    pub n_ipv6_addrs: u128,
+}
+
+/// Return the ranges in `map` that belong to the country `cc`, in order,
+/// merging any that are adjacent.
+fn country_ranges<K>(
+    map: &RangeTable<K>,
+    cc: CountryCode,
+) -> impl Iterator<Item = RangeInclusive<K>> + '_
+where
+    K: TableKey,
+{
+    let mut ranges = map
+        .iter()
+        .filter(move |(_, defn)| defn.cc == Some(cc))
+        .map(|(range, _)| range)
+        .peekable();
+    std::iter::from_fn(move || {
+        let first = ranges.next()?;
+        let start = *first.start();
+        let mut end = *first.end();
+        while let Some(next) = ranges.next_if(|next| end.add_one() == *next.start()) {
+            end = *next.end();
+        }
+        Some(start..=end)
+    })
+}
+
 /// A database of IP addresses to country codes.
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct GeoipDb {
     /// The IPv4 subset of the database, with v4 addresses stored as 32-bit integers.
-    map_v4: RangeInclusiveMap<u32, NetDefn>,
+    map_v4: RangeTable<u32>,
     /// The IPv6 subset of the database, with v6 addresses stored as 128-bit integers.
-    map_v6: RangeInclusiveMap<u128, NetDefn>,
+    map_v6: RangeTable<u128>,
+    /// The locations of IPv4 addresses, if we have been given any.
+    loc_v4: RangeInclusiveMap<u32, Arc<Location>>,
+    /// The locations of IPv6 addresses, if we have been given any.
+    loc_v6: RangeInclusiveMap<u128, Arc<Location>>,
 }
 
 impl GeoipDb {
@@ -259,23 +435,34 @@ impl GeoipDb {
     ///
     /// The returned instance of the database is shared with `Arc` across all invocations of this
     /// function in the same program.
+    ///
+    /// The compiled-in database is already in the form that we use for
+    /// lookups, so this doesn't need to parse anything, or to allocate
+    /// anything but the `Arc`.
     #[cfg(feature = "embedded-db")]
     pub fn new_embedded() -> Arc<Self> {
-        Arc::clone(EMBEDDED_DB_PARSED.get_or_init(|| {
-            Arc::new(
-                // It's reasonable to assume the one we embedded is fine -- we'll test it in CI, etc.
-                Self::new_from_legacy_format(EMBEDDED_DB_V4, EMBEDDED_DB_V6)
-                    .expect("failed to parse embedded geoip database"),
-            )
+        Arc::clone(EMBEDDED_DB.get_or_init(|| {
+            Arc::new(GeoipDb {
+                map_v4: RangeTable::from_static(
+                    embedded::EMBEDDED_V4_STARTS,
+                    embedded::EMBEDDED_V4_DEFN_IDX,
+                    &embedded::EMBEDDED_DEFNS,
+                ),
+                map_v6: RangeTable::from_static(
+                    embedded::EMBEDDED_V6_STARTS,
+                    embedded::EMBEDDED_V6_DEFN_IDX,
+                    &embedded::EMBEDDED_DEFNS,
+                ),
+                loc_v4: Default::default(),
+                loc_v6: Default::default(),
+            })
         }))
     }
 
     /// Make a new `GeoipDb` using provided copies of the v4 and v6 database, in Tor legacy format.
     pub fn new_from_legacy_format(db_v4: &str, db_v6: &str) -> Result<Self, Error> {
-        let mut ret = GeoipDb {
-            map_v4: Default::default(),
-            map_v6: Default::default(),
-        };
+        let mut map_v4 = RangeInclusiveMap::new();
+        let mut map_v6 = RangeInclusiveMap::new();
 
         for line in db_v4.lines() {
             if line.starts_with('#') {
@@ -301,7 +488,7 @@ impl GeoipDb {
 
             let defn = NetDefn::new(cc, asn)?;
 
-            ret.map_v4.insert(from..=to, defn);
+            map_v4.insert(from..=to, defn);
         }
 
         // This is slightly copypasta, but probably less readable to merge into one thing.
@@ -329,10 +516,92 @@ impl GeoipDb {
 
             let defn = NetDefn::new(cc, asn)?;
 
-            ret.map_v6.insert(from.into()..=to.into(), defn);
+            map_v6.insert(from.into()..=to.into(), defn);
         }
 
-        Ok(ret)
+        Ok(GeoipDb {
+            map_v4: map_v4.into(),
+            map_v6: map_v6.into(),
+            loc_v4: Default::default(),
+            loc_v6: Default::default(),
+        })
+    }
+
+    /// Make a new `GeoipDb` from a MaxMind DB ("MMDB") file, such as a GeoLite2
+    /// country database.
+    ///
+    /// We take the country of each network from its `country` entry, or from
+    /// its `registered_country` entry if it has no `country`.  If the database
+    /// also has AS numbers (as in a combined country and ASN database), we
+    /// use those too.
+    ///
+    /// The whole database is read into memory: later changes to the file
+    /// have no effect on the returned `GeoipDb`.
+    #[cfg(feature = "mmdb")]
+    pub fn new_from_mmdb<P: AsRef<std::path::Path>>(path: P) -> Result<Self, Error> {
+        let db = std::fs::read(path).map_err(|e| Error::Io(Arc::new(e)))?;
+        let (map_v4, map_v6) = mmdb::parse(&db)?;
+        Ok(GeoipDb {
+            map_v4: map_v4.into(),
+            map_v6: map_v6.into(),
+            loc_v4: Default::default(),
+            loc_v6: Default::default(),
+        })
+    }
+
+    /// Return a copy of this database that also has the location data in
+    /// `db_v4` and `db_v6`, replacing any location data that it had before.
+    ///
+    /// The location data is a second dataset, in a format like Tor's legacy
+    /// CSV format, that gives a latitude and longitude (and optionally a
+    /// region and a city) for each range of addresses:
+    ///
+    /// ```text
+    /// 16909056,16909311,51.507400,-0.127800,England,London
+    /// ```
+    ///
+    /// Lines in `db_v6` are the same, but with IPv6 addresses.  The ranges
+    /// don't need to match the ranges in the country data.
+    ///
+    /// Use [`lookup_location`](Self::lookup_location) to look up locations.
+    pub fn with_location_data(self, db_v4: &str, db_v6: &str) -> Result<Self, Error> {
+        let (loc_v4, loc_v6) = location::parse(db_v4, db_v6)?;
+        Ok(GeoipDb {
+            loc_v4,
+            loc_v6,
+            ..self
+        })
+    }
+
+    /// Return the approximate number of bytes of heap memory that this
+    /// database uses.
+    ///
+    /// Data that is compiled into the binary (like the
+    /// [embedded database](Self::new_embedded)) is not counted, since it
+    /// doesn't use any heap memory.
+    pub fn heap_size(&self) -> usize {
+        /// Return the approximate heap size of a location map, counting each
+        /// shared `Location` once.
+        fn loc_size<K>(
+            map: &RangeInclusiveMap<K, Arc<Location>>,
+            seen: &mut HashSet<*const Location>,
+        ) -> usize {
+            map.iter()
+                .map(|(_, loc)| {
+                    let entry = size_of::<(RangeInclusive<K>, Arc<Location>)>();
+                    if seen.insert(Arc::as_ptr(loc)) {
+                        entry + loc.heap_size()
+                    } else {
+                        entry
+                    }
+                })
+                .sum()
+        }
+        let mut seen = HashSet::new();
+        self.map_v4.heap_size()
+            + self.map_v6.heap_size()
+            + loc_size(&self.loc_v4, &mut seen)
+            + loc_size(&self.loc_v6, &mut seen)
     }
 
     /// Get the `NetDefn` for an IP address.
@@ -374,10 +643,104 @@ impl GeoipDb {
         ret
     }
 
+    /// Return the location of the given IP address, if this data is available.
+    ///
+    /// This is only available if the database has location data: see
+    /// [`with_location_data`](Self::with_location_data).
+    pub fn lookup_location(&self, ip: IpAddr) -> Option<&Location> {
+        let loc = match ip {
+            IpAddr::V4(v4) => self.loc_v4.get(&v4.into()),
+            IpAddr::V6(v6) => self.loc_v6.get(&v6.into()),
+        };
+        loc.map(|loc| &**loc)
+    }
+
     /// Return the ASN the IP address is in, if this data is available.
-    pub fn lookup_asn(&self, ip: IpAddr) -> Option<u32> {
+    pub fn lookup_asn(&self, ip: IpAddr) -> Option<AsNumber> {
         self.lookup_defn(ip)?.asn()
     }
+
+    /// Determine the ASN for a host with multiple IP addresses.
+    ///
+    /// This behaves like [`lookup_country_code_multi`](Self::lookup_country_code_multi):
+    /// if the addresses are in different autonomous systems, `None` is
+    /// returned, and addresses with no known ASN are otherwise ignored.
+    pub fn lookup_asn_multi<I>(&self, ips: I) -> Option<AsNumber>
+    where
+        I: IntoIterator<Item = IpAddr>,
+    {
+        let mut ret = None;
+
+        for ip in ips {
+            if let Some(asn) = self.lookup_asn(ip) {
+                if ret.is_some() && ret != Some(asn) {
+                    return None;
+                }
+
+                ret = Some(asn);
+            }
+        }
+
+        ret
+    }
+
+    /// Return every range of addresses that this database assigns to the
+    /// country `cc`.
+    ///
+    /// We return the IPv4 ranges first, then the IPv6 ranges, each in order of
+    /// address.  Adjacent ranges are merged, even if the database lists them
+    /// separately (for example, because they belong to different autonomous
+    /// systems).
+    pub fn ranges_for_country(&self, cc: &CountryCode) -> impl Iterator<Item = IpRange> + '_ {
+        let v4 = country_ranges(&self.map_v4, *cc).map(|r| IpRange {
+            start: Ipv4Addr::from(*r.start()).into(),
+            end: Ipv4Addr::from(*r.end()).into(),
+        });
+        let v6 = country_ranges(&self.map_v6, *cc).map(|r| IpRange {
+            start: Ipv6Addr::from(*r.start()).into(),
+            end: Ipv6Addr::from(*r.end()).into(),
+        });
+        v4.chain(v6)
+    }
+
+    /// Return a summary of how many addresses this database assigns to each
+    /// country.
+    ///
+    /// Addresses that the database doesn't assign to any country are not
+    /// counted.
+    pub fn country_stats(&self) -> BTreeMap<CountryCode, CountryStats> {
+        let mut stats: BTreeMap<CountryCode, CountryStats> = BTreeMap::new();
+        let mut prev: Option<(CountryCode, u32)> = None;
+        for (range, defn) in self.map_v4.iter() {
+            let Some(cc) = defn.cc else {
+                prev = None;
+                continue;
+            };
+            let entry = stats.entry(cc).or_default();
+            if prev != Some((cc, range.start().wrapping_sub(1))) {
+                entry.n_ipv4_ranges += 1;
+            }
+            entry.n_ipv4_addrs += u64::from(range.end() - range.start()) + 1;
+            prev = Some((cc, *range.end()));
+        }
+        let mut prev: Option<(CountryCode, u128)> = None;
+        for (range, defn) in self.map_v6.iter() {
+            let Some(cc) = defn.cc else {
+                prev = None;
+                continue;
+            };
+            let entry = stats.entry(cc).or_default();
+            if prev != Some((cc, range.start().wrapping_sub(1))) {
+                entry.n_ipv6_ranges += 1;
+            }
+            entry.n_ipv6_addrs = entry
+                .n_ipv6_addrs
+                .saturating_add(range.end() - range.start())
+                .saturating_add(1);
+            prev = Some((cc, *range.end()));
+        }
+        stats
+    }
 }
 
 /// A (representation of a) host on the network which may have a known country code.
@@ -395,6 +758,18 @@ pub trait HasCountryCode {
     fn country_code(&self) -> Option<CountryCode>;
 }
 
+/// A (representation of a) host on the network which may have a known autonomous system.
+pub trait HasAsn {
+    /// Return the autonomous system in which this server is most likely located.
+    ///
+    /// As with [`HasCountryCode::country_code`], this is usually a GeoIP lookup on the
+    /// addresses provided by `HasAddrs`, and so is only an estimate.
+    ///
+    /// Returning `None` signifies that no ASN information is available, or that the server's
+    /// addresses are in different autonomous systems.
+    fn asn(&self) -> Option<AsNumber>;
+}
+
 #[cfg(test)]
 mod test {
     // @@ begin test lint list maintained by maint/add_warning @@
@@ -431,6 +806,18 @@ mod test {
                 .map(|x| x.as_ref()),
             Some("US")
         );
+
+        // The embedded database lives in the binary, not on the heap.
+        assert_eq!(db.heap_size(), 0);
+
+        // It has the same contents as the text files that it came from.
+        let parsed = GeoipDb::new_from_legacy_format(
+            include_str!("../data/geoip"),
+            include_str!("../data/geoip6"),
+        )
+        .unwrap();
+        assert!(parsed.heap_size() > 0);
+        assert_eq!(*db, parsed);
     }
 
     #[test]
@@ -528,4 +915,83 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn asn_lookups() {
+        let src_v4 = r#"
+        16909056,16909311,GB,1234
+        16909312,16909567,GB,0
+        16909568,16909823,FR,5678
+        "#;
+        let db = GeoipDb::new_from_legacy_format(src_v4, "").unwrap();
+
+        let asn = |s: &str| db.lookup_asn(s.parse().unwrap());
+        assert_eq!(asn("1.2.3.4"), AsNumber::new(1234));
+        assert_eq!(asn("1.2.4.4"), None);
+        assert_eq!(asn("1.2.5.4"), AsNumber::new(5678));
+        assert_eq!(asn("1.1.1.1"), None);
+
+        let multi = |v: &[&str]| db.lookup_asn_multi(v.iter().map(|s| s.parse().unwrap()));
+        assert_eq!(multi(&["1.2.3.4", "1.2.3.5"]), AsNumber::new(1234));
+        assert_eq!(multi(&["1.2.3.4", "1.2.4.4"]), AsNumber::new(1234));
+        assert_eq!(multi(&["1.2.3.4", "1.2.5.4"]), None);
+        assert_eq!(multi(&[]), None);
+
+        let a = AsNumber::new(1234).unwrap();
+        assert_eq!(a.get(), 1234);
+        assert_eq!(u32::from(a), 1234);
+        assert_eq!(a.to_string(), "AS1234");
+        assert_eq!(AsNumber::new(0), None);
+    }
+
+    #[test]
+    fn ranges_by_country() {
+        // 1.2.3.0/24 and 1.2.4.0/24 are adjacent, but in different ASes.
+        let src_v4 = r#"
+        16909056,16909311,GB,1234
+        16909312,16909567,GB,5678
+        16909568,16909823,FR
+        16909824,16910079,GB
+        16910080,16910335,??
+        "#;
+        let src_v6 = r#"
+        fe80::,fe81::,GB
+        fe81::1,fe81::ffff,FR
+        "#;
+        let db = GeoipDb::new_from_legacy_format(src_v4, src_v6).unwrap();
+        let gb: CountryCode = "GB".parse().unwrap();
+        let fr: CountryCode = "FR".parse().unwrap();
+
+        let ranges: Vec<_> = db
+            .ranges_for_country(&gb)
+            .map(|r| (r.start().to_string(), r.end().to_string()))
+            .collect();
+        let expected = [
+            ("1.2.3.0", "1.2.4.255"),
+            ("1.2.6.0", "1.2.6.255"),
+            ("fe80::", "fe81::"),
+        ];
+        assert_eq!(
+            ranges,
+            expected.map(|(a, b)| (a.to_string(), b.to_string()))
+        );
+        let first = db.ranges_for_country(&gb).next().unwrap();
+        assert!(first.contains("1.2.4.7".parse().unwrap()));
+        assert!(!first.contains("1.2.5.7".parse().unwrap()));
+        assert!(!first.contains("fe80::1".parse().unwrap()));
+
+        let stats = db.country_stats();
+        assert_eq!(stats.len(), 2);
+        let gb_stats = stats[&gb];
+        assert_eq!(gb_stats.n_ipv4_ranges, 2);
+        assert_eq!(gb_stats.n_ipv4_addrs, 768);
+        assert_eq!(gb_stats.n_ipv6_ranges, 1);
+        assert_eq!(gb_stats.n_ipv6_addrs, (1 << 112) + 1);
+        let fr_stats = stats[&fr];
+        assert_eq!(fr_stats.n_ipv4_ranges, 1);
+        assert_eq!(fr_stats.n_ipv4_addrs, 256);
+        assert_eq!(fr_stats.n_ipv6_ranges, 1);
+        assert_eq!(fr_stats.n_ipv6_addrs, 0xffff);
+        assert_eq!(db.ranges_for_country(&fr).count(), 2);
+    }
 }
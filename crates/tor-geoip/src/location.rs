@@ -0,0 +1,252 @@
+//! Support for finer-grained locations than countries.
+//!
+//! A [`GeoipDb`](crate::GeoipDb) can optionally hold a second dataset, which
+//! gives a latitude, a longitude, and (if known) a region and a city for each
+//! range of addresses.  Its format follows Tor's legacy CSV format: each line
+//! of the IPv4 file looks like
+//!
+//! ```text
+//! 16909056,16909311,51.507400,-0.127800,England,London
+//! ```
+//!
+//! where the first two fields are the first and last addresses in the range,
+//! as 32-bit integers.  Lines in the IPv6 file are the same, except that the
+//! addresses are written as IPv6 addresses.  The region and city may be
+//! empty, and may be left out altogether; the city is the rest of the line,
+//! so it may contain commas.  Lines starting with `#` are comments.
+
+use crate::Error;
+use rangemap::RangeInclusiveMap;
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// The number of units in one degree, in the way that we store coordinates.
+const UNITS_PER_DEGREE: f64 = 1_000_000.0;
+
+/// The range maps that we build from a location dataset.
+pub(crate) type LocationMaps = (
+    RangeInclusiveMap<u32, Arc<Location>>,
+    RangeInclusiveMap<u128, Arc<Location>>,
+);
+
+/// A geographical location for a range of IP addresses.
+///
+/// Returned by [`GeoipDb::lookup_location`](crate::GeoipDb::lookup_location).
+///
+/// Like everything else from a GeoIP database, this is only an estimate: an
+/// address's location is often just the middle of its city, or of its
+/// country.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Location {
+    /// The latitude, in millionths of a degree north.
+    ///
+    /// (We store coordinates as integers so that we can compare locations
+    /// exactly, and merge adjacent ranges with the same location.)
+    latitude: i32,
+    /// The longitude, in millionths of a degree east.
+    longitude: i32,
+    /// The region (state, province, or other subdivision), if known.
+    region: Option<Arc<str>>,
+    /// The city, if known.
+    city: Option<Arc<str>>,
+}
+
+impl Location {
+    /// Return the latitude of this location, in degrees north.
+    pub fn latitude(&self) -> f64 {
+        f64::from(self.latitude) / UNITS_PER_DEGREE
+    }
+
+    /// Return the longitude of this location, in degrees east.
+    pub fn longitude(&self) -> f64 {
+        f64::from(self.longitude) / UNITS_PER_DEGREE
+    }
+
+    /// Return the region (such as a state or province) of this location, if
+    /// known.
+    pub fn region(&self) -> Option<&str> {
+        self.region.as_deref()
+    }
+
+    /// Return the city of this location, if known.
+    pub fn city(&self) -> Option<&str> {
+        self.city.as_deref()
+    }
+
+    /// Return the approximate number of bytes of heap memory that this
+    /// location uses, including the `Arc` that holds it.
+    pub(crate) fn heap_size(&self) -> usize {
+        /// The size of the reference counts at the start of an `Arc`.
+        const ARC_OVERHEAD: usize = 2 * std::mem::size_of::<usize>();
+        let name_size =
+            |name: &Option<Arc<str>>| name.as_ref().map_or(0, |n| ARC_OVERHEAD + n.len());
+        ARC_OVERHEAD
+            + std::mem::size_of::<Location>()
+            + name_size(&self.region)
+            + name_size(&self.city)
+    }
+}
+
+/// Parse a coordinate in degrees, which must be no larger than `max` in
+/// either direction.
+fn coordinate(s: &str, max: f64) -> Result<i32, Error> {
+    let degrees: f64 = s
+        .trim()
+        .parse()
+        .map_err(|_| Error::BadFormat("can't parse coordinate"))?;
+    if !(-max..=max).contains(&degrees) {
+        return Err(Error::BadFormat("coordinate out of range"));
+    }
+    // This can't overflow, since |degrees| <= 180.
+    Ok((degrees * UNITS_PER_DEGREE).round() as i32)
+}
+
+/// Turn `s` into a name, or `None` if it's empty.
+fn name(s: Option<&str>) -> Option<Arc<str>> {
+    s.map(str::trim).filter(|s| !s.is_empty()).map(Arc::from)
+}
+
+/// Parse every line of `db` into a range map.
+///
+/// `interned` holds the locations that we've seen already, so that ranges
+/// in the same place can share one `Location`.
+fn parse_one<A, K>(
+    db: &str,
+    interned: &mut HashMap<Location, Arc<Location>>,
+) -> Result<RangeInclusiveMap<K, Arc<Location>>, Error>
+where
+    A: FromStr + Into<K>,
+    Error: From<A::Err>,
+    K: Ord + Clone + rangemap::StepLite,
+{
+    let mut map = RangeInclusiveMap::new();
+    for line in db.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut split = line.splitn(6, ',');
+        let mut field = || {
+            split
+                .next()
+                .ok_or(Error::BadFormat("line with insufficient commas"))
+        };
+        let from: K = field()?.parse::<A>()?.into();
+        let to: K = field()?.parse::<A>()?.into();
+        let latitude = coordinate(field()?, 90.0)?;
+        let longitude = coordinate(field()?, 180.0)?;
+        let region = name(split.next());
+        let city = name(split.next());
+        if from > to {
+            return Err(Error::BadFormat("range ends before it starts"));
+        }
+
+        let location = Location {
+            latitude,
+            longitude,
+            region,
+            city,
+        };
+        let location = interned
+            .entry(location.clone())
+            .or_insert_with(|| Arc::new(location))
+            .clone();
+        map.insert(from..=to, location);
+    }
+    Ok(map)
+}
+
+/// Parse the IPv4 and IPv6 location datasets in `db_v4` and `db_v6`.
+pub(crate) fn parse(db_v4: &str, db_v6: &str) -> Result<LocationMaps, Error> {
+    let mut interned = HashMap::new();
+    let map_v4 = parse_one::<u32, u32>(db_v4, &mut interned)?;
+    let map_v6 = parse_one::<Ipv6Addr, u128>(db_v6, &mut interned)?;
+    Ok((map_v4, map_v6))
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+
+    use super::*;
+    use crate::GeoipDb;
+
+    #[test]
+    fn lookups() {
+        let countries_v4 = "16909056,16910079,GB";
+        let locations_v4 = r#"
+        # London and Manchester
+        16909056,16909311,51.507400,-0.127800,England,London
+        16909312,16909567,51.507400,-0.127800,England,London
+        16909568,16909823,53.480800,-2.242600,England,Manchester, Greater Manchester
+        16909824,16910079,54.0,-2.0
+        "#;
+        let locations_v6 = r#"
+        fe80::,fe81::,-33.8688,151.2093,New South Wales,
+        "#;
+        let db = GeoipDb::new_from_legacy_format(countries_v4, "").unwrap();
+        assert_eq!(db.lookup_location("1.2.3.4".parse().unwrap()), None);
+
+        let db = db.with_location_data(locations_v4, locations_v6).unwrap();
+        let loc = |s: &str| db.lookup_location(s.parse().unwrap());
+
+        let london = loc("1.2.3.4").unwrap();
+        assert_eq!(london.latitude(), 51.5074);
+        assert_eq!(london.longitude(), -0.1278);
+        assert_eq!(london.region(), Some("England"));
+        assert_eq!(london.city(), Some("London"));
+        // Adjacent ranges in the same place are merged.
+        assert!(std::ptr::eq(london, loc("1.2.4.4").unwrap()));
+
+        let manchester = loc("1.2.5.4").unwrap();
+        assert_eq!(manchester.city(), Some("Manchester, Greater Manchester"));
+
+        let somewhere = loc("1.2.6.4").unwrap();
+        assert_eq!(somewhere.latitude(), 54.0);
+        assert_eq!(somewhere.region(), None);
+        assert_eq!(somewhere.city(), None);
+
+        let sydney = loc("fe80::1").unwrap();
+        assert_eq!(sydney.latitude(), -33.8688);
+        assert_eq!(sydney.region(), Some("New South Wales"));
+        assert_eq!(sydney.city(), None);
+
+        assert_eq!(loc("1.1.1.1"), None);
+        assert_eq!(loc("fe82::1"), None);
+
+        // The country data is unchanged.
+        assert_eq!(
+            db.lookup_country_code("1.2.3.4".parse().unwrap())
+                .map(|cc| cc.get()),
+            Some("GB")
+        );
+    }
+
+    #[test]
+    fn bad_data() {
+        let bad = |v4: &str| parse(v4, "").is_err();
+        assert!(!bad("1,2,0,0"));
+        assert!(bad("1,2,0"));
+        assert!(bad("1,2,north,0"));
+        assert!(bad("1,2,91,0"));
+        assert!(bad("1,2,0,-180.5"));
+        assert!(bad("1,2,NaN,0"));
+        assert!(bad("2,1,0,0"));
+        assert!(bad("1,x,0,0"));
+        assert!(parse("", "fe80::,fe81::,0").is_err());
+    }
+}
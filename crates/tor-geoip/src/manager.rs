@@ -0,0 +1,296 @@
+//! Support for replacing a [`GeoipDb`] while the program is running.
+//!
+//! GeoIP data goes out of date: new address blocks are allocated, and old ones
+//! change hands.  A [`GeoipDbManager`] holds the database that we're currently
+//! using, lets a caller swap in a new one, and tells anybody who is interested
+//! when that happens.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use futures::{Stream, StreamExt as _};
+use postage::watch;
+use tracing::{info, warn};
+
+use crate::{Error, GeoipDb};
+
+/// A place from which a [`GeoipDbManager`] can load its database.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum GeoipDbSource {
+    /// A pair of files in Tor's legacy CSV format.
+    ///
+    /// See [`GeoipDb::new_from_legacy_format`].
+    Legacy {
+        /// The file holding the IPv4 database.
+        ipv4: PathBuf,
+        /// The file holding the IPv6 database.
+        ipv6: PathBuf,
+    },
+    /// A file in MaxMind's MMDB format.
+    ///
+    /// See [`GeoipDb::new_from_mmdb`].
+    #[cfg(feature = "mmdb")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "mmdb")))]
+    Mmdb(PathBuf),
+}
+
+impl GeoipDbSource {
+    /// Return every file that this source reads.
+    fn paths(&self) -> Vec<&Path> {
+        match self {
+            GeoipDbSource::Legacy { ipv4, ipv6 } => vec![ipv4, ipv6],
+            #[cfg(feature = "mmdb")]
+            GeoipDbSource::Mmdb(path) => vec![path],
+        }
+    }
+
+    /// Read and parse the database from this source.
+    fn load(&self) -> Result<GeoipDb, Error> {
+        match self {
+            GeoipDbSource::Legacy { ipv4, ipv6 } => {
+                let read = |p: &Path| fs::read_to_string(p).map_err(|e| Error::Io(Arc::new(e)));
+                GeoipDb::new_from_legacy_format(&read(ipv4)?, &read(ipv6)?)
+            }
+            #[cfg(feature = "mmdb")]
+            GeoipDbSource::Mmdb(path) => GeoipDb::new_from_mmdb(path),
+        }
+    }
+
+    /// Return a value that changes whenever any of our files is modified.
+    ///
+    /// Files that we can't examine are recorded as `None`.
+    fn stamp(&self) -> FileStamp {
+        self.paths()
+            .into_iter()
+            .map(|p| fs::metadata(p).ok().map(|m| (m.modified().ok(), m.len())))
+            .collect()
+    }
+}
+
+/// The modification time and length of each file in a [`GeoipDbSource`].
+type FileStamp = Vec<Option<(Option<SystemTime>, u64)>>;
+
+/// The mutable parts of a [`GeoipDbManager`].
+#[derive(Debug)]
+struct Inner {
+    /// The sender that we use to publish each new database.
+    ///
+    /// Its current value is the database that we're using.
+    sender: watch::Sender<Arc<GeoipDb>>,
+    /// The file stamp of our source when we last loaded it successfully.
+    last_loaded: Option<FileStamp>,
+}
+
+/// A holder for the [`GeoipDb`] that we are currently using, which can be
+/// replaced at runtime.
+///
+/// A new database can be installed explicitly with
+/// [`replace`](GeoipDbManager::replace), or, if the manager was created with a
+/// [`GeoipDbSource`], reloaded from disk with
+/// [`reload_if_changed`](GeoipDbManager::reload_if_changed).  Each replacement
+/// is atomic: [`current`](GeoipDbManager::current) always returns a complete
+/// database, either the old one or the new one.  Anybody who needs to know
+/// about replacements can [`subscribe`](GeoipDbManager::subscribe).
+///
+/// A failed reload leaves the current database in place.
+#[derive(Debug)]
+pub struct GeoipDbManager {
+    /// The source from which we reload our database, if any.
+    source: Option<GeoipDbSource>,
+    /// The mutable parts of this manager.
+    inner: Mutex<Inner>,
+    /// A receiver that we use to read the current database, and clone for
+    /// subscribers.
+    receiver: watch::Receiver<Arc<GeoipDb>>,
+}
+
+impl GeoipDbManager {
+    /// Construct a new manager using `db`.
+    ///
+    /// The database can only be changed with [`replace`](GeoipDbManager::replace).
+    pub fn new(db: Arc<GeoipDb>) -> Self {
+        Self::new_inner(db, None, None)
+    }
+
+    /// Construct a new manager that loads its database from `source`.
+    ///
+    /// Return an error if we can't load the database now.
+    pub fn from_source(source: GeoipDbSource) -> Result<Self, Error> {
+        let stamp = source.stamp();
+        let db = source.load()?;
+        Ok(Self::new_inner(Arc::new(db), Some(source), Some(stamp)))
+    }
+
+    /// Helper: construct a new manager from its parts.
+    fn new_inner(
+        db: Arc<GeoipDb>,
+        source: Option<GeoipDbSource>,
+        last_loaded: Option<FileStamp>,
+    ) -> Self {
+        let (sender, receiver) = watch::channel_with(db);
+        GeoipDbManager {
+            source,
+            inner: Mutex::new(Inner {
+                sender,
+                last_loaded,
+            }),
+            receiver,
+        }
+    }
+
+    /// Return the database that we're currently using.
+    pub fn current(&self) -> Arc<GeoipDb> {
+        Arc::clone(&self.receiver.borrow())
+    }
+
+    /// Replace the current database with `db`, and notify our subscribers.
+    pub fn replace(&self, db: Arc<GeoipDb>) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        *inner.sender.borrow_mut() = db;
+    }
+
+    /// Return a stream that yields the current database, and then every
+    /// database that replaces it.
+    ///
+    /// If several replacements happen in quick succession, a subscriber may
+    /// only see the last of them.
+    pub fn subscribe(&self) -> impl Stream<Item = Arc<GeoipDb>> + Send + Unpin + 'static {
+        self.receiver.clone()
+    }
+
+    /// If the files in our source have changed since we last loaded them,
+    /// load them again and replace the current database.
+    ///
+    /// Return true if we replaced the database.  If this manager has no
+    /// source, do nothing and return false.
+    ///
+    /// On error, we keep the current database, and try again the next time
+    /// this is called.
+    pub fn reload_if_changed(&self) -> Result<bool, Error> {
+        let Some(source) = &self.source else {
+            return Ok(false);
+        };
+        let stamp = source.stamp();
+        {
+            let inner = self.inner.lock().expect("poisoned lock");
+            if inner.last_loaded.as_ref() == Some(&stamp) {
+                return Ok(false);
+            }
+        }
+
+        // We parse the database without holding the lock, since it can take a
+        // while.
+        let db = source.load()?;
+
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        *inner.sender.borrow_mut() = Arc::new(db);
+        inner.last_loaded = Some(stamp);
+        Ok(true)
+    }
+
+    /// Call [`reload_if_changed`](GeoipDbManager::reload_if_changed) every
+    /// time `events` yields an item, until it is exhausted.
+    ///
+    /// The stream might come from a file watcher, or from a timer.  Errors are
+    /// logged, and don't stop us from trying again at the next event.
+    pub async fn reload_on<S>(&self, mut events: S)
+    where
+        S: Stream + Unpin,
+    {
+        while events.next().await.is_some() {
+            match self.reload_if_changed() {
+                Ok(true) => info!("Loaded a new GeoIP database."),
+                Ok(false) => {}
+                Err(e) => warn!("Unable to reload GeoIP database: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::CountryCode;
+    use futures::FutureExt as _;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    fn cc_of(db: &GeoipDb, ip: &str) -> Option<String> {
+        db.lookup_country_code(ip.parse::<IpAddr>().unwrap())
+            .map(|cc| cc.get().to_owned())
+    }
+
+    #[test]
+    fn replace_and_subscribe() {
+        let db1 = GeoipDb::new_from_legacy_format("16777216,33554431,US", "").unwrap();
+        let db2 = GeoipDb::new_from_legacy_format("16777216,33554431,DE", "").unwrap();
+        let mgr = GeoipDbManager::new(Arc::new(db1));
+        let mut events = mgr.subscribe();
+
+        assert_eq!(cc_of(&mgr.current(), "1.0.0.1").unwrap(), "US");
+        let first = events.next().now_or_never().unwrap().unwrap();
+        assert_eq!(cc_of(&first, "1.0.0.1").unwrap(), "US");
+        assert!(events.next().now_or_never().is_none());
+
+        mgr.replace(Arc::new(db2));
+        assert_eq!(cc_of(&mgr.current(), "1.0.0.1").unwrap(), "DE");
+        let second = events.next().now_or_never().unwrap().unwrap();
+        assert_eq!(
+            second.lookup_country_code("1.0.0.1".parse().unwrap()),
+            Some(&CountryCode::from_str("DE").unwrap())
+        );
+
+        // Without a source, there's nothing to reload.
+        assert!(!mgr.reload_if_changed().unwrap());
+    }
+
+    #[test]
+    fn reload_from_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let ipv4 = dir.path().join("geoip");
+        let ipv6 = dir.path().join("geoip6");
+        fs::write(&ipv4, "16777216,33554431,US\n").unwrap();
+        fs::write(&ipv6, "").unwrap();
+
+        let mgr = GeoipDbManager::from_source(GeoipDbSource::Legacy {
+            ipv4: ipv4.clone(),
+            ipv6,
+        })
+        .unwrap();
+        assert_eq!(cc_of(&mgr.current(), "1.0.0.1").unwrap(), "US");
+        assert!(!mgr.reload_if_changed().unwrap());
+
+        // A broken file is reported, and leaves the old database in place.
+        fs::write(&ipv4, "16777216,33554431,USA\n").unwrap();
+        assert!(mgr.reload_if_changed().is_err());
+        assert_eq!(cc_of(&mgr.current(), "1.0.0.1").unwrap(), "US");
+
+        fs::write(&ipv4, "16777216,33554431,FR\n").unwrap();
+        assert!(mgr.reload_if_changed().unwrap());
+        assert_eq!(cc_of(&mgr.current(), "1.0.0.1").unwrap(), "FR");
+        assert!(!mgr.reload_if_changed().unwrap());
+
+        // Driving reloads from a stream of events works too.
+        fs::write(&ipv4, "# Updated\n16777216,33554431,JP\n").unwrap();
+        mgr.reload_on(futures::stream::iter([()]))
+            .now_or_never()
+            .unwrap();
+        assert_eq!(cc_of(&mgr.current(), "1.0.0.1").unwrap(), "JP");
+    }
+}
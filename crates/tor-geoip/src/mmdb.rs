@@ -0,0 +1,755 @@
+//! Support for reading MaxMind DB ("MMDB") files, such as the GeoLite2
+//! country databases.
+//!
+//! We only implement enough of the format to convert a database into the
+//! same range maps that we build from the legacy Tor format.  See
+//! <https://maxmind.github.io/MaxMind-DB/> for the specification.
+
+use crate::{Error, NetDefn};
+use rangemap::RangeInclusiveMap;
+use std::collections::HashMap;
+
+/// The marker that introduces the metadata section at the end of the file.
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+
+/// The largest number of bytes at the end of the file that we search for the
+/// metadata marker.
+const MAX_METADATA_SIZE: usize = 128 * 1024;
+
+/// The number of zero bytes between the search tree and the data section.
+const DATA_SECTION_SEPARATOR: usize = 16;
+
+/// The deepest that we allow values in the data section to nest.
+///
+/// (This protects us against pointer loops in a malicious file.)
+const MAX_DEPTH: usize = 32;
+
+/// The largest number of values that we decode for a single record,
+/// counting a value once for each pointer that leads us to it.
+///
+/// (Real records have a few hundred values at most.  Without this limit, a
+/// malicious file could make us do exponential work, by having each level
+/// of a nested record point more than once to the next level.)
+const MAX_VALUES_PER_RECORD: usize = 8192;
+
+/// The range maps that we build from a database.
+pub(crate) type Maps = (
+    RangeInclusiveMap<u32, NetDefn>,
+    RangeInclusiveMap<u128, NetDefn>,
+);
+
+/// Return an error for an MMDB file that we can't parse.
+fn bad(msg: &'static str) -> Error {
+    Error::BadFormat(msg)
+}
+
+/// A value from the data section (or the metadata) of an MMDB file.
+///
+/// We only keep the types that we need: everything else is `Other`.
+#[derive(Debug, Clone)]
+enum Value<'a> {
+    /// A UTF-8 string.
+    Str(&'a str),
+    /// An unsigned integer of any width.
+    Uint(u128),
+    /// A map from strings to values.
+    Map(Vec<(&'a str, Value<'a>)>),
+    /// Some other kind of value, which we have skipped.
+    Other,
+}
+
+impl<'a> Value<'a> {
+    /// If this is a map, return its entry for `key`.
+    fn get(&self, key: &str) -> Option<&Value<'a>> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| *k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// If this is a string, return it.
+    fn as_str(&self) -> Option<&'a str> {
+        match self {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// If this is an unsigned integer that fits in a `T`, return it.
+    fn as_uint<T: TryFrom<u128>>(&self) -> Option<T> {
+        match self {
+            Value::Uint(n) => (*n).try_into().ok(),
+            _ => None,
+        }
+    }
+}
+
+/// A decoder for the data section (or the metadata) of an MMDB file.
+///
+/// Offsets are relative to the start of `data`.
+struct Decoder<'a> {
+    /// The section that we're decoding.
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    /// Return the `n` bytes at `pos`.
+    fn bytes(&self, pos: usize, n: usize) -> Result<&'a [u8], Error> {
+        pos.checked_add(n)
+            .and_then(|end| self.data.get(pos..end))
+            .ok_or_else(|| bad("truncated MMDB data"))
+    }
+
+    /// Return the `n`-byte big-endian unsigned integer at `pos`.
+    fn uint(&self, pos: usize, n: usize) -> Result<u128, Error> {
+        if n > 16 {
+            return Err(bad("oversized integer in MMDB data"));
+        }
+        Ok(self
+            .bytes(pos, n)?
+            .iter()
+            .fold(0, |acc, b| (acc << 8) | u128::from(*b)))
+    }
+
+    /// Decode the record at `pos`.
+    fn decode_record(&self, pos: usize) -> Result<Value<'a>, Error> {
+        let mut budget = MAX_VALUES_PER_RECORD;
+        let (value, _) = self.decode(pos, 0, &mut budget)?;
+        Ok(value)
+    }
+
+    /// Decode the value at `pos`, which is nested `depth` levels deep.
+    ///
+    /// We give up if we would have to decode more than `budget` values
+    /// (including this one), and subtract the number that we decode from it.
+    ///
+    /// Return the value, and the position of the value after it.
+    fn decode(
+        &self,
+        pos: usize,
+        depth: usize,
+        budget: &mut usize,
+    ) -> Result<(Value<'a>, usize), Error> {
+        if depth > MAX_DEPTH {
+            return Err(bad("MMDB data nested too deeply"));
+        }
+        *budget = budget
+            .checked_sub(1)
+            .ok_or_else(|| bad("MMDB record has too many values"))?;
+        let ctrl = self.bytes(pos, 1)?[0];
+        let mut pos = pos + 1;
+        let mut kind = ctrl >> 5;
+
+        if kind == 1 {
+            // A pointer: the value is somewhere else, but the next value is
+            // right after the pointer.
+            let high = usize::from(ctrl & 0x07);
+            let (len, base) = match (ctrl >> 3) & 0x03 {
+                0 => (1, 0),
+                1 => (2, 2048),
+                2 => (3, 526_336),
+                _ => (4, 0),
+            };
+            let low =
+                usize::try_from(self.uint(pos, len)?).map_err(|_| bad("invalid MMDB pointer"))?;
+            let target = if len == 4 {
+                low
+            } else {
+                ((high << (8 * len)) | low) + base
+            };
+            let (value, _) = self.decode(target, depth + 1, budget)?;
+            return Ok((value, pos + len));
+        }
+
+        if kind == 0 {
+            // An extended type.
+            kind = self.bytes(pos, 1)?[0]
+                .checked_add(7)
+                .ok_or_else(|| bad("unknown MMDB data type"))?;
+            pos += 1;
+        }
+
+        let mut size = usize::from(ctrl & 0x1f);
+        if size >= 29 {
+            let len = size - 28;
+            let extra =
+                usize::try_from(self.uint(pos, len)?).map_err(|_| bad("invalid MMDB size"))?;
+            pos += len;
+            size = match len {
+                1 => 29 + extra,
+                2 => 285 + extra,
+                _ => 65_821 + extra,
+            };
+        }
+
+        match kind {
+            // UTF-8 string
+            2 => {
+                let s = std::str::from_utf8(self.bytes(pos, size)?)
+                    .map_err(|_| bad("invalid UTF-8 in MMDB data"))?;
+                Ok((Value::Str(s), pos + size))
+            }
+            // uint16, uint32, uint64, uint128
+            5 | 6 | 9 | 10 => Ok((Value::Uint(self.uint(pos, size)?), pos + size)),
+            // double, bytes, int32, float
+            3 | 4 | 8 | 15 => {
+                let _ = self.bytes(pos, size)?;
+                Ok((Value::Other, pos + size))
+            }
+            // map
+            7 => {
+                let mut entries = Vec::new();
+                for _ in 0..size {
+                    let (key, next) = self.decode(pos, depth + 1, budget)?;
+                    let key = key.as_str().ok_or_else(|| bad("non-string MMDB map key"))?;
+                    let (value, next) = self.decode(next, depth + 1, budget)?;
+                    entries.push((key, value));
+                    pos = next;
+                }
+                Ok((Value::Map(entries), pos))
+            }
+            // array
+            11 => {
+                for _ in 0..size {
+                    let (_, next) = self.decode(pos, depth + 1, budget)?;
+                    pos = next;
+                }
+                Ok((Value::Other, pos))
+            }
+            // boolean: the value is stored in the size.
+            14 => Ok((Value::Other, pos)),
+            _ => Err(bad("unknown MMDB data type")),
+        }
+    }
+}
+
+/// The search tree of an MMDB file.
+struct Tree<'a> {
+    /// The bytes of the tree.
+    tree: &'a [u8],
+    /// The number of nodes in the tree.
+    node_count: usize,
+    /// The number of bits in each record.
+    record_size: usize,
+}
+
+impl Tree<'_> {
+    /// Return the record for `bit` (0 for left, 1 for right) in `node`.
+    fn record(&self, node: usize, bit: u8) -> Result<usize, Error> {
+        let node_len = self.record_size / 4;
+        let b = node
+            .checked_mul(node_len)
+            .and_then(|start| self.tree.get(start..start + node_len))
+            .ok_or_else(|| bad("MMDB tree node out of range"))?;
+        /// Convert big-endian bytes to an integer.
+        fn be(bytes: &[u8]) -> usize {
+            bytes.iter().fold(0, |acc, b| (acc << 8) | usize::from(*b))
+        }
+        Ok(match (self.record_size, bit) {
+            (24, 0) => be(&b[0..3]),
+            (24, _) => be(&b[3..6]),
+            (28, 0) => (usize::from(b[3] & 0xf0) << 20) | be(&b[0..3]),
+            (28, _) => (usize::from(b[3] & 0x0f) << 24) | be(&b[4..7]),
+            (_, 0) => be(&b[0..4]),
+            (_, _) => be(&b[4..8]),
+        })
+    }
+
+    /// Return the offset within the data section that `record` points to.
+    fn data_offset(&self, record: usize) -> Result<usize, Error> {
+        self.node_count
+            .checked_add(DATA_SECTION_SEPARATOR)
+            .and_then(|start| record.checked_sub(start))
+            .ok_or_else(|| bad("invalid MMDB data pointer"))
+    }
+
+    /// Call `visit` on every network in the subtree rooted at `root`, which
+    /// covers addresses of `bits` bits.
+    ///
+    /// `visit` receives the first and last address in the network (as the
+    /// low `bits` bits of a `u128`), and the offset of its data within the
+    /// data section.
+    ///
+    /// We don't descend into `skip`, if it is provided.
+    fn walk<F>(
+        &self,
+        root: usize,
+        bits: usize,
+        skip: Option<usize>,
+        mut visit: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(u128, u128, usize) -> Result<(), Error>,
+    {
+        let mut stack = vec![(root, 0_usize, 0_u128)];
+        while let Some((node, depth, prefix)) = stack.pop() {
+            if depth >= bits {
+                return Err(bad("MMDB tree is too deep"));
+            }
+            for bit in 0..2 {
+                let record = self.record(node, bit)?;
+                let depth = depth + 1;
+                let prefix = prefix | (u128::from(bit) << (bits - depth));
+                if record < self.node_count {
+                    if Some(record) != skip {
+                        stack.push((record, depth, prefix));
+                    }
+                } else if record > self.node_count {
+                    let offset = self.data_offset(record)?;
+                    let host_bits = bits - depth;
+                    let host_mask = match host_bits {
+                        0 => 0,
+                        n => u128::MAX >> (128 - n),
+                    };
+                    visit(prefix, prefix | host_mask, offset)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Extract the information we want from a record in the data section.
+///
+/// Return `None` if the record has neither a country code nor an ASN.
+fn netdefn(record: &Value<'_>) -> Result<Option<NetDefn>, Error> {
+    // Some networks (for example, those of satellite providers) have no
+    // "country", only a "registered_country".
+    let cc = ["country", "registered_country"]
+        .iter()
+        .find_map(|k| record.get(k)?.get("iso_code")?.as_str());
+    let asn = record
+        .get("autonomous_system_number")
+        .and_then(Value::as_uint::<u32>);
+    if cc.is_none() && asn.is_none() {
+        return Ok(None);
+    }
+    NetDefn::new(cc.unwrap_or("??"), asn).map(Some)
+}
+
+/// Parse the MMDB file in `db` into range maps for IPv4 and IPv6.
+///
+/// In IPv6 databases, the IPv4 space is stored at `::/96`.  MaxMind also
+/// makes some other parts of the IPv6 space (like `::ffff:0:0/96` and
+/// `2002::/16`) refer to the IPv4 space; we don't include those parts in our
+/// IPv6 map.
+pub(crate) fn parse(db: &[u8]) -> Result<Maps, Error> {
+    let search_start = db.len().saturating_sub(MAX_METADATA_SIZE);
+    let marker_pos = db[search_start..]
+        .windows(METADATA_MARKER.len())
+        .rposition(|w| w == METADATA_MARKER)
+        .map(|p| p + search_start)
+        .ok_or_else(|| bad("no MMDB metadata found"))?;
+    let metadata = Decoder {
+        data: &db[marker_pos + METADATA_MARKER.len()..],
+    };
+    let metadata = metadata.decode_record(0)?;
+
+    /// Look up a required unsigned field in the metadata.
+    fn field(metadata: &Value<'_>, key: &str) -> Result<usize, Error> {
+        metadata
+            .get(key)
+            .and_then(Value::as_uint)
+            .ok_or_else(|| bad("missing or invalid field in MMDB metadata"))
+    }
+    if field(&metadata, "binary_format_major_version")? != 2 {
+        return Err(bad("unsupported MMDB format version"));
+    }
+    let node_count = field(&metadata, "node_count")?;
+    let record_size = field(&metadata, "record_size")?;
+    let ip_version = field(&metadata, "ip_version")?;
+    if ![24, 28, 32].contains(&record_size) {
+        return Err(bad("unsupported MMDB record size"));
+    }
+
+    let tree_len = node_count
+        .checked_mul(record_size / 4)
+        .filter(|len| {
+            len.checked_add(DATA_SECTION_SEPARATOR)
+                .is_some_and(|end| end <= marker_pos)
+        })
+        .ok_or_else(|| bad("MMDB search tree is too large for file"))?;
+    let tree = Tree {
+        tree: &db[..tree_len],
+        node_count,
+        record_size,
+    };
+    let data = Decoder {
+        data: &db[tree_len + DATA_SECTION_SEPARATOR..marker_pos],
+    };
+
+    // Many networks share a data record, so we only decode each one once.
+    let mut defns: HashMap<usize, Option<NetDefn>> = HashMap::new();
+    let mut lookup = |offset: usize| -> Result<Option<NetDefn>, Error> {
+        if let Some(defn) = defns.get(&offset) {
+            return Ok(*defn);
+        }
+        let record = data.decode_record(offset)?;
+        let defn = netdefn(&record)?;
+        defns.insert(offset, defn);
+        Ok(defn)
+    };
+
+    let mut map_v4 = RangeInclusiveMap::new();
+    let mut map_v6 = RangeInclusiveMap::new();
+
+    let ipv4_root = match ip_version {
+        4 => Some(0),
+        6 => {
+            // Find the node for ::/96, if there is one.
+            let mut node = Some(0);
+            for _ in 0..96 {
+                let Some(n) = node else { break };
+                let record = tree.record(n, 0)?;
+                node = (record < node_count).then_some(record);
+                if record > node_count {
+                    // All of ::/96 is inside a single network, which we'll
+                    // find when we walk the IPv6 tree.  It covers all of the
+                    // IPv4 space.
+                    if let Some(defn) = lookup(tree.data_offset(record)?)? {
+                        map_v4.insert(0..=u32::MAX, defn);
+                    }
+                }
+            }
+            tree.walk(0, 128, node, |first, last, offset| {
+                if let Some(defn) = lookup(offset)? {
+                    map_v6.insert(first..=last, defn);
+                }
+                Ok(())
+            })?;
+            node
+        }
+        _ => return Err(bad("unsupported MMDB IP version")),
+    };
+
+    if let Some(root) = ipv4_root {
+        tree.walk(root, 32, None, |first, last, offset| {
+            if let Some(defn) = lookup(offset)? {
+                // These conversions can't fail, since we're walking a tree
+                // with 32-bit addresses.
+                let first = u32::try_from(first).map_err(|_| bad("bad IPv4 network"))?;
+                let last = u32::try_from(last).map_err(|_| bad("bad IPv4 network"))?;
+                map_v4.insert(first..=last, defn);
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok((map_v4, map_v6))
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+
+    use super::*;
+    use crate::{AsNumber, GeoipDb};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    /// A record in a search tree that we're building.
+    #[derive(Clone, Copy, Debug)]
+    enum Rec {
+        /// No data.
+        Empty,
+        /// Another node.
+        Node(usize),
+        /// A value in the data section, at this offset.
+        Data(usize),
+    }
+
+    /// A minimal writer for MMDB files.
+    struct Writer {
+        /// The nodes of the search tree.
+        nodes: Vec<[Rec; 2]>,
+        /// The data section.
+        data: Vec<u8>,
+        /// The number of bits in each address.
+        bits: usize,
+    }
+
+    /// Encode the control byte(s) for a value of type `kind` and length `size`.
+    fn header(out: &mut Vec<u8>, kind: u8, size: usize) {
+        assert!(size < 29);
+        if kind > 7 {
+            out.push(size as u8);
+            out.push(kind - 7);
+        } else {
+            out.push((kind << 5) | size as u8);
+        }
+    }
+    fn string(out: &mut Vec<u8>, s: &str) {
+        header(out, 2, s.len());
+        out.extend(s.as_bytes());
+    }
+    fn uint(out: &mut Vec<u8>, kind: u8, n: u32) {
+        let bytes = n.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        header(out, kind, 4 - skip);
+        out.extend(&bytes[skip..]);
+    }
+    fn pointer(out: &mut Vec<u8>, target: usize) {
+        assert!(target < 2048);
+        out.push(0x20 | (target >> 8) as u8);
+        out.push(target as u8);
+    }
+    /// Encode a map from country-ish keys to `{"iso_code": cc}`.
+    fn cc_map(out: &mut Vec<u8>, key: &str, cc: &str) {
+        string(out, key);
+        header(out, 7, 1);
+        string(out, "iso_code");
+        string(out, cc);
+    }
+
+    impl Writer {
+        fn new(bits: usize) -> Self {
+            Writer {
+                nodes: vec![[Rec::Empty; 2]],
+                data: Vec::new(),
+                bits,
+            }
+        }
+
+        /// Set the record for the network `prefix/len` to `rec`, creating
+        /// nodes as needed.
+        fn set(&mut self, prefix: u128, len: usize, rec: Rec) {
+            let mut node = 0;
+            for depth in 0..len {
+                let bit = ((prefix >> (self.bits - depth - 1)) & 1) as usize;
+                if depth == len - 1 {
+                    self.nodes[node][bit] = rec;
+                    return;
+                }
+                node = match self.nodes[node][bit] {
+                    Rec::Node(n) => n,
+                    _ => {
+                        self.nodes.push([Rec::Empty; 2]);
+                        let n = self.nodes.len() - 1;
+                        self.nodes[node][bit] = Rec::Node(n);
+                        n
+                    }
+                };
+            }
+        }
+
+        /// Return the node for `prefix/len`.
+        fn node(&self, prefix: u128, len: usize) -> usize {
+            let mut node = 0;
+            for depth in 0..len {
+                let bit = ((prefix >> (self.bits - depth - 1)) & 1) as usize;
+                match self.nodes[node][bit] {
+                    Rec::Node(n) => node = n,
+                    r => panic!("no node: {:?}", r),
+                }
+            }
+            node
+        }
+
+        /// Add a data value, encoded by `f`, and return its offset.
+        fn add_data(&mut self, f: impl FnOnce(&mut Vec<u8>)) -> usize {
+            let offset = self.data.len();
+            f(&mut self.data);
+            offset
+        }
+
+        /// Encode this database.
+        fn finish(&self, record_size: usize) -> Vec<u8> {
+            let n = self.nodes.len();
+            let val = |r: Rec| -> u32 {
+                (match r {
+                    Rec::Empty => n,
+                    Rec::Node(x) => x,
+                    Rec::Data(off) => n + DATA_SECTION_SEPARATOR + off,
+                }) as u32
+            };
+            let mut out = Vec::new();
+            for [l, r] in &self.nodes {
+                let (l, r) = (val(*l), val(*r));
+                match record_size {
+                    24 => {
+                        out.extend(&l.to_be_bytes()[1..]);
+                        out.extend(&r.to_be_bytes()[1..]);
+                    }
+                    28 => {
+                        out.extend(&l.to_be_bytes()[1..]);
+                        out.push((((l >> 24) as u8) << 4) | (r >> 24) as u8);
+                        out.extend(&r.to_be_bytes()[1..]);
+                    }
+                    32 => {
+                        out.extend(l.to_be_bytes());
+                        out.extend(r.to_be_bytes());
+                    }
+                    _ => panic!(),
+                }
+            }
+            out.extend([0; DATA_SECTION_SEPARATOR]);
+            out.extend(&self.data);
+            out.extend(METADATA_MARKER);
+            header(&mut out, 7, 6);
+            string(&mut out, "binary_format_major_version");
+            uint(&mut out, 5, 2);
+            string(&mut out, "node_count");
+            uint(&mut out, 6, n as u32);
+            string(&mut out, "record_size");
+            uint(&mut out, 5, record_size as u32);
+            string(&mut out, "ip_version");
+            uint(&mut out, 5, if self.bits == 128 { 6 } else { 4 });
+            string(&mut out, "languages");
+            header(&mut out, 11, 1);
+            string(&mut out, "en");
+            string(&mut out, "database_type");
+            string(&mut out, "Test-Country");
+            out
+        }
+    }
+
+    fn v4(s: &str) -> u128 {
+        u32::from(s.parse::<Ipv4Addr>().unwrap()).into()
+    }
+    fn v6(s: &str) -> u128 {
+        s.parse::<Ipv6Addr>().unwrap().into()
+    }
+    fn cc(db: &GeoipDb, ip: &str) -> Option<String> {
+        db.lookup_country_code(ip.parse::<IpAddr>().unwrap())
+            .map(|cc| cc.to_string())
+    }
+    fn load(bytes: &[u8]) -> GeoipDb {
+        let (map_v4, map_v6) = parse(bytes).unwrap();
+        GeoipDb {
+            map_v4: map_v4.into(),
+            map_v6: map_v6.into(),
+            loc_v4: Default::default(),
+            loc_v6: Default::default(),
+        }
+    }
+
+    #[test]
+    fn ipv6_db() {
+        let mut w = Writer::new(128);
+        let gb = w.add_data(|out| {
+            header(out, 7, 2);
+            cc_map(out, "country", "GB");
+            // Ignored: a double.
+            string(out, "accuracy");
+            out.push(3 << 5 | 8);
+            out.extend(1.5_f64.to_be_bytes());
+        });
+        let iso_code = w.data.windows(9).position(|x| x == b"\x48iso_code");
+        let us = w.add_data(|out| {
+            // Use pointers for the key and value.
+            header(out, 7, 2);
+            string(out, "registered_country");
+            header(out, 7, 1);
+            pointer(out, iso_code.unwrap());
+            string(out, "US");
+            string(out, "autonomous_system_number");
+            uint(out, 6, 15169);
+        });
+        let de = w.add_data(|out| {
+            header(out, 7, 2);
+            cc_map(out, "country", "DE");
+            // Ignored: a boolean.
+            string(out, "is_in_european_union");
+            header(out, 14, 1);
+        });
+        let nothing = w.add_data(|out| {
+            header(out, 7, 1);
+            string(out, "city");
+            string(out, "Nowhere");
+        });
+        w.set(v4("1.2.3.0"), 96 + 24, Rec::Data(gb));
+        w.set(v4("8.0.0.0"), 96 + 8, Rec::Data(us));
+        w.set(v4("9.0.0.0"), 96 + 8, Rec::Data(nothing));
+        w.set(v6("fe80::"), 16, Rec::Data(de));
+        // Make ::ffff:0:0/96 refer to the IPv4 space, as MaxMind does.
+        let ipv4_root = w.node(0, 96);
+        w.set(v6("::ffff:0:0"), 96, Rec::Node(ipv4_root));
+
+        for record_size in [24, 28, 32] {
+            let db = load(&w.finish(record_size));
+            assert_eq!(cc(&db, "1.2.3.4").as_deref(), Some("GB"));
+            assert_eq!(cc(&db, "1.2.4.4"), None);
+            assert_eq!(cc(&db, "8.8.8.8").as_deref(), Some("US"));
+            assert_eq!(
+                db.lookup_asn("8.8.8.8".parse().unwrap()),
+                AsNumber::new(15169)
+            );
+            assert_eq!(db.lookup_asn("1.2.3.4".parse().unwrap()), None);
+            assert_eq!(cc(&db, "9.9.9.9"), None);
+            assert_eq!(cc(&db, "fe80::1").as_deref(), Some("DE"));
+            assert_eq!(cc(&db, "fe81::1"), None);
+            // We don't include the aliases for the IPv4 space.
+            assert_eq!(cc(&db, "::ffff:1.2.3.4"), None);
+            assert_eq!(db.map_v6.iter().count(), 1);
+        }
+    }
+
+    #[test]
+    fn ipv4_db() {
+        let mut w = Writer::new(32);
+        let gb = w.add_data(|out| {
+            header(out, 7, 1);
+            cc_map(out, "country", "gb");
+        });
+        w.set(v4("1.2.3.0"), 24, Rec::Data(gb));
+        w.set(v4("255.255.255.255"), 32, Rec::Data(gb));
+        let db = load(&w.finish(24));
+        assert_eq!(cc(&db, "1.2.3.255").as_deref(), Some("GB"));
+        assert_eq!(cc(&db, "255.255.255.255").as_deref(), Some("GB"));
+        assert_eq!(cc(&db, "255.255.255.254"), None);
+        assert_eq!(cc(&db, "fe80::1"), None);
+    }
+
+    #[test]
+    fn bad_files() {
+        assert!(parse(b"").is_err());
+        assert!(parse(b"This is not a database.").is_err());
+
+        let mut w = Writer::new(32);
+        let gb = w.add_data(|out| {
+            header(out, 7, 1);
+            cc_map(out, "country", "GB");
+        });
+        w.set(v4("1.2.3.0"), 24, Rec::Data(gb));
+        let good = w.finish(24);
+        assert!(parse(&good).is_ok());
+        // Truncate the metadata.
+        assert!(parse(&good[..good.len() - 3]).is_err());
+        // Make the data point to itself.
+        let mut looped = good.clone();
+        let data_start = w.nodes.len() * 6 + DATA_SECTION_SEPARATOR;
+        looped[data_start] = 0x20;
+        looped[data_start + 1] = 0;
+        assert!(parse(&looped).is_err());
+
+        // Make each level of a record point four times to the next, so that
+        // the record expands to far too many values.
+        let mut w = Writer::new(32);
+        let mut level = w.add_data(|out| string(out, "bottom"));
+        for _ in 0..10 {
+            level = w.add_data(|out| {
+                header(out, 7, 4);
+                for key in ["a", "b", "c", "d"] {
+                    string(out, key);
+                    pointer(out, level);
+                }
+            });
+        }
+        w.set(v4("1.2.3.0"), 24, Rec::Data(level));
+        assert!(matches!(
+            parse(&w.finish(24)),
+            Err(Error::BadFormat("MMDB record has too many values"))
+        ));
+    }
+}
@@ -0,0 +1,327 @@
+//! A compact, read-only table of address ranges.
+//!
+//! A [`RangeTable`] stores the same information as a
+//! `RangeInclusiveMap<K, NetDefn>`, as a pair of sorted arrays: the first
+//! address of each range, and the index of its [`NetDefn`] in a table of
+//! distinct definitions.  Each range runs until the start of the next one;
+//! addresses that the database doesn't cover belong to ranges whose
+//! definition is [`NetDefn::EMPTY`].
+//!
+//! Because the arrays can be borrowed from static data, the build script can
+//! generate the embedded database in this form, so that we don't need to
+//! parse (or allocate) anything to use it.  The build script writes the two
+//! large arrays as big-endian binary files, which we embed with
+//! `include_bytes!`: that is much faster to compile than the equivalent
+//! array literals.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::ops::RangeInclusive;
+
+use rangemap::{RangeInclusiveMap, StepLite};
+
+use crate::NetDefn;
+
+/// An integer type that we can store in a [`Column`].
+pub(crate) trait Packed: Copy + 'static {
+    /// The number of bytes in the big-endian encoding of this type.
+    const SIZE: usize;
+
+    /// Decode a value from its big-endian encoding.
+    ///
+    /// Panics if `bytes` is not exactly `SIZE` bytes long.
+    fn from_be_slice(bytes: &[u8]) -> Self;
+}
+
+impl Packed for u32 {
+    const SIZE: usize = 4;
+    fn from_be_slice(bytes: &[u8]) -> Self {
+        u32::from_be_bytes(bytes.try_into().expect("wrong length for u32"))
+    }
+}
+
+impl Packed for u128 {
+    const SIZE: usize = 16;
+    fn from_be_slice(bytes: &[u8]) -> Self {
+        u128::from_be_bytes(bytes.try_into().expect("wrong length for u128"))
+    }
+}
+
+/// A type of key (that is, an address) that we can store in a
+/// [`RangeTable`].
+pub(crate) trait TableKey: Packed + Ord + StepLite {
+    /// The largest possible key.
+    const MAX: Self;
+}
+
+impl TableKey for u32 {
+    const MAX: Self = u32::MAX;
+}
+
+impl TableKey for u128 {
+    const MAX: Self = u128::MAX;
+}
+
+/// An array of integers, either on the heap, or borrowed from static data in
+/// big-endian form.
+#[derive(Clone, Debug)]
+enum Column<T: Packed> {
+    /// Values that we built at runtime.
+    Owned(Vec<T>),
+    /// The big-endian encodings of the values, one after another.
+    #[cfg_attr(not(feature = "embedded-db"), allow(dead_code))]
+    Static(&'static [u8]),
+}
+
+impl<T: Packed> Column<T> {
+    /// Return the number of values in this column.
+    fn len(&self) -> usize {
+        match self {
+            Column::Owned(v) => v.len(),
+            Column::Static(b) => b.len() / T::SIZE,
+        }
+    }
+
+    /// Return the value at `idx`, if there is one.
+    fn get(&self, idx: usize) -> Option<T> {
+        match self {
+            Column::Owned(v) => v.get(idx).copied(),
+            Column::Static(b) => {
+                let start = idx.checked_mul(T::SIZE)?;
+                let end = start.checked_add(T::SIZE)?;
+                b.get(start..end).map(T::from_be_slice)
+            }
+        }
+    }
+
+    /// Return an iterator over the values in this column.
+    fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        (0..self.len()).filter_map(|idx| self.get(idx))
+    }
+
+    /// Return the index of the first value for which `pred` is false,
+    /// assuming that `pred` is true for every value before it, and false for
+    /// every value after it.
+    ///
+    /// (This is the same as `slice::partition_point`.)
+    fn partition_point(&self, pred: impl Fn(T) -> bool) -> usize {
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match self.get(mid) {
+                Some(v) if pred(v) => lo = mid + 1,
+                _ => hi = mid,
+            }
+        }
+        lo
+    }
+
+    /// Return the number of bytes of heap memory that this column uses.
+    fn heap_size(&self) -> usize {
+        match self {
+            Column::Owned(v) => v.capacity() * size_of::<T>(),
+            Column::Static(_) => 0,
+        }
+    }
+}
+
+/// A sorted table of non-overlapping address ranges, each with a
+/// [`NetDefn`].
+#[derive(Clone, Debug)]
+pub(crate) struct RangeTable<K: TableKey> {
+    /// The first address of each range, in ascending order.
+    ///
+    /// Addresses before the first entry belong to no range.
+    starts: Column<K>,
+    /// For each entry in `starts`, the index of its definition in `defns`.
+    defn_idx: Column<u32>,
+    /// The distinct definitions in this table.
+    defns: Cow<'static, [NetDefn]>,
+}
+
+impl<K: TableKey> RangeTable<K> {
+    /// Make a new `RangeTable` from static data, as generated by our build
+    /// script.
+    ///
+    /// `starts` and `defn_idx` hold big-endian `K` and `u32` values
+    /// respectively.  The caller must make sure that `starts` is sorted, that
+    /// it has as many values as `defn_idx`, and that every index in
+    /// `defn_idx` is in range for `defns`.
+    #[cfg(feature = "embedded-db")]
+    pub(crate) const fn from_static(
+        starts: &'static [u8],
+        defn_idx: &'static [u8],
+        defns: &'static [NetDefn],
+    ) -> Self {
+        RangeTable {
+            starts: Column::Static(starts),
+            defn_idx: Column::Static(defn_idx),
+            defns: Cow::Borrowed(defns),
+        }
+    }
+
+    /// Return the definition for the range containing `key`, if there is one.
+    pub(crate) fn get(&self, key: &K) -> Option<&NetDefn> {
+        let pos = self.starts.partition_point(|start| start <= *key);
+        let idx = self.defn_idx.get(pos.checked_sub(1)?)?;
+        self.defns.get(usize::try_from(idx).ok()?)
+    }
+
+    /// Return an iterator over the ranges in this table that have a
+    /// definition, in order, with their definitions.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (RangeInclusive<K>, &NetDefn)> + '_ {
+        let ends = self
+            .starts
+            .iter()
+            .skip(1)
+            .map(|next| next.sub_one())
+            .chain(std::iter::once(K::MAX));
+        self.starts
+            .iter()
+            .zip(ends)
+            .zip(self.defn_idx.iter())
+            .filter_map(|((start, end), idx)| {
+                let defn = self.defns.get(usize::try_from(idx).ok()?)?;
+                (*defn != NetDefn::EMPTY).then_some((start..=end, defn))
+            })
+    }
+
+    /// Return the number of bytes of heap memory that this table uses.
+    ///
+    /// Data that is borrowed from the binary doesn't count.
+    pub(crate) fn heap_size(&self) -> usize {
+        let defns_size = match &self.defns {
+            Cow::Borrowed(_) => 0,
+            Cow::Owned(v) => v.capacity() * size_of::<NetDefn>(),
+        };
+        self.starts.heap_size() + self.defn_idx.heap_size() + defns_size
+    }
+}
+
+impl<K: TableKey> Default for RangeTable<K> {
+    fn default() -> Self {
+        RangeTable {
+            starts: Column::Owned(Vec::new()),
+            defn_idx: Column::Owned(Vec::new()),
+            defns: Cow::Owned(Vec::new()),
+        }
+    }
+}
+
+impl<K: TableKey> PartialEq for RangeTable<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl<K: TableKey> Eq for RangeTable<K> {}
+
+impl<K: TableKey> From<RangeInclusiveMap<K, NetDefn>> for RangeTable<K> {
+    fn from(map: RangeInclusiveMap<K, NetDefn>) -> Self {
+        let mut starts = Vec::new();
+        let mut defn_idx: Vec<u32> = Vec::new();
+        let mut defns = vec![NetDefn::EMPTY];
+        let mut index: HashMap<NetDefn, u32> = HashMap::from([(NetDefn::EMPTY, 0)]);
+
+        let mut push = |start: K, idx: u32| {
+            if defn_idx.last() != Some(&idx) {
+                starts.push(start);
+                defn_idx.push(idx);
+            }
+        };
+        let mut next_start = None;
+        for (range, defn) in map.iter() {
+            if let Some(next) = next_start {
+                if next != *range.start() {
+                    push(next, 0);
+                }
+            }
+            let idx = *index.entry(*defn).or_insert_with(|| {
+                defns.push(*defn);
+                // No real database has anywhere near 2^32 distinct
+                // definitions.
+                u32::try_from(defns.len() - 1).expect("Too many distinct definitions")
+            });
+            push(*range.start(), idx);
+            next_start = (*range.end() != K::MAX).then(|| range.end().add_one());
+        }
+        if let Some(next) = next_start {
+            push(next, 0);
+        }
+
+        starts.shrink_to_fit();
+        defn_idx.shrink_to_fit();
+        defns.shrink_to_fit();
+        RangeTable {
+            starts: Column::Owned(starts),
+            defn_idx: Column::Owned(defn_idx),
+            defns: Cow::Owned(defns),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+
+    use super::*;
+
+    #[test]
+    fn from_map() {
+        let us = NetDefn::new("US", None).unwrap();
+        let de = NetDefn::new("DE", Some(3320)).unwrap();
+        let mut map = RangeInclusiveMap::new();
+        map.insert(10..=19, us);
+        map.insert(20..=29, us);
+        map.insert(40..=49, de);
+        map.insert(50..=u32::MAX, us);
+        let table = RangeTable::from(map.clone());
+
+        // Adjacent ranges with the same definition are merged, and gaps get
+        // their own entries.
+        assert_eq!(table.starts.iter().collect::<Vec<_>>(), [10, 30, 40, 50]);
+        assert_eq!(table.defns.len(), 3);
+
+        assert_eq!(table.get(&9), None);
+        assert_eq!(table.get(&10), Some(&us));
+        assert_eq!(table.get(&29), Some(&us));
+        assert_eq!(table.get(&30), Some(&NetDefn::EMPTY));
+        assert_eq!(table.get(&45), Some(&de));
+        assert_eq!(table.get(&u32::MAX), Some(&us));
+        assert!(table.iter().eq(map.iter().map(|(r, d)| (r.clone(), d))));
+        assert_eq!(table, RangeTable::from(map));
+        assert!(table.heap_size() > 0);
+
+        let empty = RangeTable::<u128>::from(RangeInclusiveMap::new());
+        assert_eq!(empty.get(&0), None);
+        assert_eq!(empty.iter().count(), 0);
+        assert_eq!(empty, RangeTable::default());
+    }
+
+    #[test]
+    fn static_column() {
+        let bytes: &'static [u8] = &[0, 0, 0, 10, 0, 0, 0, 30, 0, 0, 1, 0];
+        let col = Column::<u32>::Static(bytes);
+        assert_eq!(col.len(), 3);
+        assert_eq!(col.get(1), Some(30));
+        assert_eq!(col.get(3), None);
+        assert_eq!(col.iter().collect::<Vec<_>>(), [10, 30, 256]);
+        assert_eq!(col.partition_point(|v| v <= 9), 0);
+        assert_eq!(col.partition_point(|v| v <= 30), 2);
+        assert_eq!(col.partition_point(|v| v <= 1000), 3);
+        assert_eq!(col.heap_size(), 0);
+    }
+}
@@ -0,0 +1,139 @@
+//! Records of which directory our guard state was last saved with.
+//!
+//! The directory manager and the guard manager persist their state
+//! separately, so after a restart, the restored directory might not be the one
+//! against which our restored guards were last checked.  (For example, one of
+//! the two files might be older than the other, or the directory cache might
+//! have been deleted.)  To detect that, we store a [`DirectoryAnchor`] along
+//! with our guards, and compare it against the restored directory with
+//! [`GuardMgr::check_directory_pairing`](crate::GuardMgr::check_directory_pairing).
+
+use std::time::SystemTime;
+
+use base64ct::{Base64Unpadded, Encoding as _};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+
+/// A reference to the consensus that our guard state was saved with.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DirectoryAnchor {
+    /// The SHA3-256 digest of the signed part of the consensus.
+    #[serde(
+        serialize_with = "serialize_digest",
+        deserialize_with = "deserialize_digest"
+    )]
+    consensus_digest: [u8; 32],
+    /// The time at which the consensus became valid.
+    #[serde(with = "humantime_serde")]
+    valid_after: SystemTime,
+}
+
+impl DirectoryAnchor {
+    /// Construct a new `DirectoryAnchor` for the consensus whose signed part
+    /// has the SHA3-256 digest `consensus_digest`, and which became valid at
+    /// `valid_after`.
+    pub fn new(consensus_digest: [u8; 32], valid_after: SystemTime) -> Self {
+        DirectoryAnchor {
+            consensus_digest,
+            valid_after,
+        }
+    }
+
+    /// Return the SHA3-256 digest of the signed part of the consensus.
+    pub fn consensus_digest(&self) -> &[u8; 32] {
+        &self.consensus_digest
+    }
+
+    /// Return the time at which the consensus became valid.
+    pub fn valid_after(&self) -> SystemTime {
+        self.valid_after
+    }
+}
+
+/// How well a directory matches the one that our guard state was saved with.
+///
+/// Returned by
+/// [`GuardMgr::check_directory_pairing`](crate::GuardMgr::check_directory_pairing).
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum DirectoryPairing {
+    /// The directory is the one that our guard state was saved with, and it
+    /// lists all of our primary guards.
+    Consistent,
+    /// The directory lists all of our primary guards, but we don't know which
+    /// directory our guard state was saved with.
+    Unanchored,
+    /// The directory lists all of our primary guards, but it isn't the one
+    /// that our guard state was saved with.
+    Changed {
+        /// The directory that our guard state was saved with.
+        saved: DirectoryAnchor,
+    },
+    /// The directory is missing information about some of our primary guards:
+    /// either it doesn't list them, or we don't have their descriptors.
+    ///
+    /// The directory should not be trusted to tell us about our guards until
+    /// we have fetched a fresh one.
+    MissingPrimaryGuards {
+        /// The number of primary guards for which we lack information.
+        n_missing: usize,
+    },
+}
+
+impl DirectoryPairing {
+    /// Return true if the directory has the information we need about our
+    /// primary guards.
+    pub fn covers_primary_guards(&self) -> bool {
+        !matches!(self, DirectoryPairing::MissingPrimaryGuards { .. })
+    }
+}
+
+/// Serialize a digest as unpadded base64.
+fn serialize_digest<S: Serializer>(digest: &[u8; 32], s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&Base64Unpadded::encode_string(digest))
+}
+
+/// Deserialize a digest from unpadded base64.
+fn deserialize_digest<'de, D: Deserializer<'de>>(d: D) -> Result<[u8; 32], D::Error> {
+    let s = String::deserialize(d)?;
+    let bytes = Base64Unpadded::decode_vec(&s).map_err(D::Error::custom)?;
+    bytes
+        .try_into()
+        .map_err(|_| D::Error::custom("consensus digest has the wrong length"))
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let valid_after = humantime::parse_rfc3339("2024-06-01T12:00:00Z").unwrap();
+        let anchor = DirectoryAnchor::new([7; 32], valid_after);
+        let json = serde_json::to_value(&anchor).unwrap();
+        assert_eq!(
+            json["consensus_digest"],
+            "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc"
+        );
+        let back: DirectoryAnchor = serde_json::from_value(json).unwrap();
+        assert_eq!(back, anchor);
+
+        let bad = serde_json::json!({
+            "consensus_digest": "BwcH",
+            "valid_after": "2024-06-01T12:00:00Z",
+        });
+        assert!(serde_json::from_value::<DirectoryAnchor>(bad).is_err());
+    }
+}
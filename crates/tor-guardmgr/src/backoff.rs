@@ -0,0 +1,173 @@
+//! Exponential backoff for retrying guards that have failed.
+//!
+//! When a guard fails, we mark it unreachable and wait a while before we try
+//! it again.  Each further failure makes us wait longer, up to a cap, so
+//! that a guard that is down for a long time doesn't cost us much; and we
+//! randomize each delay, so that many clients that lost their guards at the
+//! same moment don't all retry them at once.
+
+use std::time::Duration;
+
+use rand::Rng;
+use tor_basic_utils::RngExt as _;
+use tor_netdir::params::NetParameters;
+
+/// Parameters for the schedule on which we retry a guard after it fails.
+#[derive(Debug, Clone)]
+#[cfg_attr(test, derive(PartialEq))]
+pub(crate) struct RetryParams {
+    /// The nominal delay after the first failure of a primary guard.
+    pub(crate) primary_initial: Duration,
+    /// The nominal delay after the first failure of a non-primary guard.
+    pub(crate) nonprimary_initial: Duration,
+    /// The longest that we will ever wait before retrying a guard.
+    pub(crate) max: Duration,
+    /// The factor by which each further failure multiplies the nominal
+    /// delay.
+    ///
+    /// Always at least 1.0.
+    pub(crate) multiplier: f64,
+    /// The fraction of each nominal delay that is random: we wait for
+    /// somewhere between `(1 - jitter)` times the nominal delay and the
+    /// nominal delay itself.
+    ///
+    /// Always between 0.0 and 1.0.
+    pub(crate) jitter: f64,
+}
+
+impl Default for RetryParams {
+    fn default() -> Self {
+        RetryParams {
+            primary_initial: Duration::from_secs(30),
+            nonprimary_initial: Duration::from_secs(150),
+            max: Duration::from_secs(3600),
+            multiplier: 2.0,
+            jitter: 0.5,
+        }
+    }
+}
+
+impl TryFrom<&NetParameters> for RetryParams {
+    type Error = tor_units::Error;
+    fn try_from(p: &NetParameters) -> Result<RetryParams, Self::Error> {
+        Ok(RetryParams {
+            primary_initial: p.guard_retry_primary_initial.try_into()?,
+            nonprimary_initial: p.guard_retry_nonprimary_initial.try_into()?,
+            max: p.guard_retry_max.try_into()?,
+            multiplier: p.guard_retry_backoff.as_fraction(),
+            jitter: p.guard_retry_jitter.as_fraction(),
+        })
+    }
+}
+
+/// The retry schedule for a single guard that has been failing.
+///
+/// We start a new schedule when a guard fails while it is reachable, and
+/// throw it away once the guard succeeds.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct GuardBackoff {
+    /// How many delays have we already handed out?
+    n_delays: u32,
+}
+
+impl GuardBackoff {
+    /// Return the delay before we should next retry the guard, after
+    /// another failure.
+    ///
+    /// `is_primary` should be true if the guard is currently primary.
+    pub(crate) fn next_delay<R: Rng>(
+        &mut self,
+        params: &RetryParams,
+        is_primary: bool,
+        rng: &mut R,
+    ) -> Duration {
+        let nominal = self.nominal_delay(params, is_primary);
+        self.n_delays = self.n_delays.saturating_add(1);
+
+        let jitter = params.jitter.clamp(0.0, 1.0);
+        let random_part = nominal.mul_f64(jitter);
+        // (This can't underflow, since jitter is at most 1.)
+        nominal.saturating_sub(random_part) + rng.gen_range_infallible(..=random_part)
+    }
+
+    /// Return the longest delay that we would give for the next failure.
+    fn nominal_delay(&self, params: &RetryParams, is_primary: bool) -> Duration {
+        let initial = if is_primary {
+            params.primary_initial
+        } else {
+            params.nonprimary_initial
+        };
+        let exponent = i32::try_from(self.n_delays).unwrap_or(i32::MAX);
+        let factor = params.multiplier.max(1.0).powi(exponent);
+        // (Doing this in f64 means that we never overflow: at worst, `secs`
+        // is infinite, and the cap takes care of it.)
+        let secs = (initial.as_secs_f64() * factor).min(params.max.as_secs_f64());
+        Duration::from_secs_f64(secs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use tor_basic_utils::test_rng::testing_rng;
+
+    #[test]
+    fn backoff() {
+        let params = RetryParams::default();
+        let mut rng = testing_rng();
+        let sec = Duration::from_secs(1);
+
+        let mut b = GuardBackoff::default();
+        for nominal in [30, 60, 120, 240, 480, 960, 1920, 3600, 3600] {
+            let d = b.next_delay(&params, true, &mut rng);
+            assert!(d >= sec * nominal / 2, "{:?} < {}/2", d, nominal);
+            assert!(d <= sec * nominal, "{:?} > {}", d, nominal);
+        }
+
+        // Non-primary guards start later.
+        let mut b = GuardBackoff::default();
+        let d = b.next_delay(&params, false, &mut rng);
+        assert!(d >= sec * 75 && d <= sec * 150);
+
+        // Without jitter, the delays are exact; a huge number of failures
+        // doesn't overflow.
+        let params = RetryParams {
+            jitter: 0.0,
+            multiplier: 3.0,
+            ..RetryParams::default()
+        };
+        let mut b = GuardBackoff::default();
+        assert_eq!(b.next_delay(&params, true, &mut rng), sec * 30);
+        assert_eq!(b.next_delay(&params, true, &mut rng), sec * 90);
+        let mut b = GuardBackoff { n_delays: u32::MAX };
+        assert_eq!(b.next_delay(&params, true, &mut rng), sec * 3600);
+    }
+
+    #[test]
+    fn from_netparams() {
+        let p = NetParameters::from_map(
+            &"guard-retry-primary-initial=10 guard-retry-max=600 guard-retry-backoff-percent=150 guard-retry-jitter-percent=0"
+                .parse()
+                .unwrap(),
+        );
+        let params = RetryParams::try_from(&p).unwrap();
+        assert_eq!(params.primary_initial, Duration::from_secs(10));
+        assert_eq!(params.nonprimary_initial, Duration::from_secs(150));
+        assert_eq!(params.max, Duration::from_secs(600));
+        assert_eq!(params.multiplier, 1.5);
+        assert_eq!(params.jitter, 0.0);
+    }
+}
@@ -0,0 +1,170 @@
+//! Deciding whether a guard is to blame for a failed circuit.
+//!
+//! When a circuit fails, the circuit manager can tell a
+//! [`GuardMonitor`](crate::GuardMonitor) why, with a [`CircuitFailureCause`].
+//! We use that cause to tell a guard that is down (which we should stop using
+//! for a while) from a guard that is working, but whose circuit failed
+//! somewhere further along the path (which says nothing about whether the
+//! guard is up, but which might be suspicious if it happens too often).
+
+use tor_cell::chancell::msg::DestroyReason;
+
+use crate::GuardStatus;
+
+/// The reason that a circuit through a guard failed.
+///
+/// Given to [`GuardMonitor::failed_with`](crate::GuardMonitor::failed_with) or
+/// [`GuardMonitor::cause`](crate::GuardMonitor::cause).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum CircuitFailureCause {
+    /// We couldn't complete a TLS handshake with the guard.
+    TlsHandshakeFailed,
+    /// The channel to the guard closed while we were waiting for it to answer
+    /// our CREATE cell.
+    ChannelClosedDuringCreate,
+    /// The guard didn't answer our CREATE cell in time.
+    CreateTimeout,
+    /// We received a DESTROY cell for the circuit.
+    Destroyed {
+        /// The number of hops that we had finished building when the circuit
+        /// was destroyed.
+        ///
+        /// If this is zero, the guard destroyed the circuit in response to our
+        /// CREATE cell.  Otherwise, the DESTROY may have come from any hop on
+        /// the circuit.
+        hops_completed: usize,
+        /// The reason given in the DESTROY cell.
+        reason: DestroyReason,
+    },
+}
+
+/// Who we blame for a circuit failure.
+///
+/// Returned by [`CircuitFailureCause::blame`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum FailureBlame {
+    /// The guard is down, or can't be used.
+    ///
+    /// We treat this as a [`GuardStatus::Failure`].
+    Guard,
+    /// The guard is working, but the circuit failed beyond it.
+    ///
+    /// We treat this as a [`GuardStatus::Indeterminate`]: it doesn't mean that
+    /// the guard is down, but a guard whose circuits fail like this too often
+    /// is suspicious.
+    BeyondGuard,
+    /// We can't tell who to blame.
+    ///
+    /// We use whatever status was reported.
+    Unclear,
+}
+
+impl CircuitFailureCause {
+    /// Return who we blame for a circuit that failed for this reason.
+    pub fn blame(&self) -> FailureBlame {
+        match self {
+            CircuitFailureCause::TlsHandshakeFailed
+            | CircuitFailureCause::ChannelClosedDuringCreate
+            | CircuitFailureCause::CreateTimeout => FailureBlame::Guard,
+            CircuitFailureCause::Destroyed {
+                hops_completed: 0,
+                reason,
+            } => match *reason {
+                // The guard says that it can't or won't build circuits.
+                DestroyReason::PROTOCOL
+                | DestroyReason::INTERNAL
+                | DestroyReason::HIBERNATING
+                | DestroyReason::RESOURCELIMIT => FailureBlame::Guard,
+                _ => FailureBlame::Unclear,
+            },
+            // The guard handled our CREATE, so it is up; the DESTROY might not
+            // even be from the guard.
+            CircuitFailureCause::Destroyed { .. } => FailureBlame::BeyondGuard,
+        }
+    }
+}
+
+impl FailureBlame {
+    /// Return the status that we should record, given that a circuit was
+    /// reported with `status` and a failure for which we assign this blame.
+    ///
+    /// We only adjust failures: a success, or an abandoned attempt, is
+    /// unchanged.
+    pub(crate) fn adjust(self, status: GuardStatus) -> GuardStatus {
+        match (self, status) {
+            (FailureBlame::Guard, GuardStatus::Failure | GuardStatus::Indeterminate) => {
+                GuardStatus::Failure
+            }
+            (FailureBlame::BeyondGuard, GuardStatus::Failure) => GuardStatus::Indeterminate,
+            (_, status) => status,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    fn destroyed(hops_completed: usize, reason: DestroyReason) -> CircuitFailureCause {
+        CircuitFailureCause::Destroyed {
+            hops_completed,
+            reason,
+        }
+    }
+
+    #[test]
+    fn blame() {
+        use CircuitFailureCause as C;
+        use FailureBlame as B;
+        assert_eq!(C::TlsHandshakeFailed.blame(), B::Guard);
+        assert_eq!(C::ChannelClosedDuringCreate.blame(), B::Guard);
+        assert_eq!(C::CreateTimeout.blame(), B::Guard);
+
+        assert_eq!(destroyed(0, DestroyReason::RESOURCELIMIT).blame(), B::Guard);
+        assert_eq!(destroyed(0, DestroyReason::HIBERNATING).blame(), B::Guard);
+        assert_eq!(destroyed(0, DestroyReason::REQUESTED).blame(), B::Unclear);
+        assert_eq!(destroyed(0, DestroyReason::NONE).blame(), B::Unclear);
+
+        assert_eq!(
+            destroyed(1, DestroyReason::CONNECTFAILED).blame(),
+            B::BeyondGuard
+        );
+        assert_eq!(
+            destroyed(2, DestroyReason::RESOURCELIMIT).blame(),
+            B::BeyondGuard
+        );
+    }
+
+    #[test]
+    fn adjust() {
+        use FailureBlame as B;
+        use GuardStatus as S;
+        let adjusted = |blame: B, status: S| format!("{:?}", blame.adjust(status));
+
+        assert_eq!(adjusted(B::Guard, S::Failure), "Failure");
+        assert_eq!(adjusted(B::Guard, S::Indeterminate), "Failure");
+        assert_eq!(adjusted(B::Guard, S::Success), "Success");
+        assert_eq!(adjusted(B::BeyondGuard, S::Failure), "Indeterminate");
+        assert_eq!(
+            adjusted(B::BeyondGuard, S::AttemptAbandoned),
+            "AttemptAbandoned"
+        );
+        assert_eq!(adjusted(B::Unclear, S::Failure), "Failure");
+        assert_eq!(adjusted(B::Unclear, S::Indeterminate), "Indeterminate");
+    }
+}
@@ -10,9 +10,11 @@
 mod config;
 mod descs;
 mod relay;
+mod status;
 
 pub use config::{BridgeConfig, BridgeConfigBuilder, BridgeParseError};
 pub use descs::{BridgeDesc, BridgeDescError, BridgeDescEvent, BridgeDescList, BridgeDescProvider};
 pub use relay::BridgeRelay;
+pub use status::{BridgeDescStatus, BridgeStatus};
 
 pub(crate) use descs::BridgeSet;
@@ -73,6 +73,16 @@ pub use err::BridgeParseError;
 ///    zero or more `key=value` parameters to pass to the transport
 ///    (smuggled in the SOCKS handshake, as described in the Tor PT specification).
 ///
+/// Within a word, a section in double quotes `"` may contain whitespace,
+/// and a backslash `\` makes the next character literal.
+/// So `key="two words"` and `key=two\ words` both give the
+/// parameter `key` the value `two words`.
+/// When displaying a bridge line, we quote any parameter that needs it,
+/// so that the result always parses back to the same `BridgeConfig`.
+///
+/// To check a bridge line that a user has supplied, with more precise errors,
+/// use [`BridgeConfig::parse_and_validate`].
+///
 /// This type is cheap to clone: it is a newtype around an `Arc`.
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct BridgeConfig(Arc<Inner>);
@@ -434,13 +444,114 @@ impl FromStr for BridgeConfig {
     }
 }
 
+impl BridgeConfig {
+    /// Parse `line` as a bridge line, and check that we could use it.
+    ///
+    /// As well as the checks made by [`FromStr`], this requires that any
+    /// pluggable transport the line uses is one of `known_transports`.
+    /// (Typically, these are the transports for which we have a configured
+    /// binary.)
+    ///
+    /// This is meant for front-ends that let users paste in bridge lines,
+    /// and want to tell them precisely what is wrong with one.
+    pub fn parse_and_validate<'a, I>(
+        line: &str,
+        known_transports: I,
+    ) -> Result<BridgeConfig, BridgeParseError>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let bridge: BridgeConfig = line.parse()?;
+
+        #[cfg(feature = "pt-client")]
+        if let ChannelMethod::Pluggable(target) = &bridge.0.addrs {
+            let transport = target.transport().to_string();
+            if !known_transports.into_iter().any(|t| t == transport) {
+                return Err(BridgeParseError::UnknownTransport { transport });
+            }
+        }
+        #[cfg(not(feature = "pt-client"))]
+        let _ = known_transports;
+
+        Ok(bridge)
+    }
+
+    /// Return a bridge line describing this bridge.
+    ///
+    /// The result is the same as this bridge's [`Display`] output: it doesn't
+    /// include the leading `Bridge` word used in C Tor's `torrc`.  Parsing it
+    /// gives a `BridgeConfig` equal to this one.
+    pub fn to_bridge_line(&self) -> String {
+        self.to_string()
+    }
+}
+
+/// Split a bridge line into words, handling quoting and escaping.
+///
+/// Words are separated by ASCII whitespace.  Within a word, whitespace inside
+/// double quotes does not end the word, and a backslash makes the next
+/// character literal.  Neither the quotes nor the backslashes are part of the
+/// resulting word.
+fn split_bridge_line(line: &str) -> Result<Vec<String>, BridgeParseError> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // True if we have started a word (which might still be empty, as in `""`).
+    let mut in_word = false;
+    let mut in_quotes = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) => word.push(escaped),
+                None => return Err(BridgeParseError::InvalidQuoting { word }),
+            },
+            '"' => in_quotes = !in_quotes,
+            c if c.is_ascii_whitespace() && !in_quotes => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+                continue;
+            }
+            c => word.push(c),
+        }
+        in_word = true;
+    }
+    if in_quotes {
+        return Err(BridgeParseError::InvalidQuoting { word });
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Write `s` as part of a bridge line word, quoting it if necessary.
+///
+/// This is the inverse of the unquoting done by [`split_bridge_line`].
+#[cfg(feature = "pt-client")]
+fn write_quoted(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    if !s.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        return write!(f, "{}", s);
+    }
+    write!(f, "\"")?;
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            write!(f, "\\")?;
+        }
+        write!(f, "{}", c)?;
+    }
+    write!(f, "\"")
+}
+
 impl FromStr for Inner {
     type Err = BridgeParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         use BridgeParseError as BPE;
 
-        let mut s = s.trim().split_ascii_whitespace().peekable();
+        let words = split_bridge_line(s)?;
+        let mut s = words.iter().map(String::as_str).peekable();
 
         // This implements the parsing of bridge lines.
         // Refer to the specification in the rustdoc comment for `Bridge`.
@@ -556,7 +667,11 @@ impl FromStr for Inner {
             }
         }
 
-        let rsa_id = rsa_id.ok_or(BPE::NoRsaIdentity)?;
+        let rsa_id = match (rsa_id, &ed_id) {
+            (Some(rsa_id), _) => rsa_id,
+            (None, Some(_)) => return Err(BPE::NoRsaIdentity),
+            (None, None) => return Err(BPE::MissingFingerprint),
+        };
         Ok(Inner {
             addrs: method,
             rsa_id,
@@ -614,7 +729,10 @@ impl Display for BridgeConfig {
 
         #[cfg(feature = "pt-client")]
         for (k, v) in settings.into_iter().flatten() {
-            write!(f, " {}={}", k, v)?;
+            write!(f, " ")?;
+            write_quoted(f, k)?;
+            write!(f, "=")?;
+            write_quoted(f, v)?;
         }
 
         Ok(())
@@ -727,6 +845,29 @@ mod test {
             ed_id: Some(mk_ed("dGhpcyBpcyBpbmNyZWRpYmx5IHNpbGx5ISEhISEhISE")),
         });
 
+        #[cfg(feature = "pt-client")]
+        chk(
+            &[
+                r#"obfs4 some-host:80 $0bac39417268b96b9f514e7f63fa6fba1a788955 key="two words" q="say \"hi\"" bs="a\\b" empty="#,
+                r#"obfs4 some-host:80 $0bac39417268b96b9f514e7f63fa6fba1a788955 key=two\ words q=say\ \"hi\" bs=a\\b empty="""#,
+                r#"obfs4   some-host:80 $0bac39417268b96b9f514e7f63fa6fba1a788955 "key"="two "words q="say "\"hi\" "bs=a\\b" empty="#,
+            ],
+            Inner {
+                addrs: mk_pt_target(
+                    "obfs4",
+                    PtTargetAddr::HostPort("some-host".into(), 80),
+                    &[
+                        ("key", "two words"),
+                        ("q", "say \"hi\""),
+                        ("bs", "a\\b"),
+                        ("empty", ""),
+                    ],
+                ),
+                rsa_id: mk_rsa("0BAC39417268B96B9F514E7F63FA6FBA1A788955"),
+                ed_id: None,
+            },
+        );
+
         chk(
             &[
                 "38.229.33.83:80 $0bac39417268b96b9f514e7f63fa6fba1a788955",
@@ -770,6 +911,25 @@ mod test {
 
         chk_e(&["", "bridge"], "Bridge line was empty");
 
+        chk_e(
+            &["38.229.33.83:80", "Bridge 38.229.33.83:80"],
+            "Bridge line is missing a fingerprint",
+        );
+
+        #[cfg(feature = "pt-client")]
+        chk_e(
+            &["obfs4 some-host:80 cert=abcd iat-mode=1"],
+            "Bridge line is missing a fingerprint",
+        );
+
+        chk_e(
+            &[
+                r#"38.229.33.83:80 "0BAC39417268B96B9F514E7F63FA6FBA1A788955"#,
+                r"38.229.33.83:80 0BAC39417268B96B9F514E7F63FA6FBA1A788955 \",
+            ],
+            "Unterminated quote or escape in bridge line",
+        );
+
         chk_e(
             &["999.329.33.83:80 0BAC39417268B96B9F514E7F63FA6FBA1A788955"],
             // Some Rust versions say "invalid socket address syntax",
@@ -823,6 +983,40 @@ mod test {
         );
     }
 
+    #[test]
+    fn validate() {
+        let known = ["obfs4", "snowflake"];
+
+        let line = "38.229.33.83:80 $0bac39417268b96b9f514e7f63fa6fba1a788955";
+        let bridge = BridgeConfig::parse_and_validate(line, known).unwrap();
+        assert_eq!(bridge.to_bridge_line(), line);
+        let bridge = BridgeConfig::parse_and_validate(line, []).unwrap();
+        assert_eq!(bridge.to_bridge_line(), line);
+
+        let err = BridgeConfig::parse_and_validate("38.229.33.83:80", known).unwrap_err();
+        assert!(matches!(err, BridgeParseError::MissingFingerprint));
+
+        #[cfg(feature = "pt-client")]
+        {
+            let line =
+                r#"obfs4 38.229.33.83:80 $0bac39417268b96b9f514e7f63fa6fba1a788955 cert="x y""#;
+            let bridge = BridgeConfig::parse_and_validate(line, known).unwrap();
+            assert_eq!(bridge.to_bridge_line(), line);
+            let reparsed: BridgeConfig = bridge.to_bridge_line().parse().unwrap();
+            assert_eq!(reparsed, bridge);
+
+            let err = BridgeConfig::parse_and_validate(
+                "webtunnel 38.229.33.83:80 $0bac39417268b96b9f514e7f63fa6fba1a788955",
+                known,
+            )
+            .unwrap_err();
+            assert!(matches!(
+                &err,
+                BridgeParseError::UnknownTransport { transport } if transport == "webtunnel"
+            ));
+        }
+    }
+
     #[test]
     fn config_api() {
         let chk_bridgeline = |line: &str, jsons: &[&str], f: &dyn Fn(&mut BridgeConfigBuilder)| {
@@ -16,6 +16,14 @@ pub enum BridgeParseError {
     #[error("Bridge line was empty")]
     Empty,
 
+    /// A quoted section wasn't closed, or the line ended with a backslash
+    #[cfg(feature = "bridge-client")]
+    #[error("Unterminated quote or escape in bridge line, at {word:?}")]
+    InvalidQuoting {
+        /// The offending word, as far as we got with it
+        word: String,
+    },
+
     /// Expected PT name or host:port, looked a bit like a PT name, but didn't parse
     #[cfg(feature = "bridge-client")]
     #[error(
@@ -111,11 +119,24 @@ pub enum BridgeParseError {
     #[error("Parameters supplied but not valid without a pluggable transport")]
     DirectParametersNotAllowed,
 
+    /// The bridge line has no identity fingerprint at all
+    #[cfg(feature = "bridge-client")]
+    #[error("Bridge line is missing a fingerprint (RSA identity key)")]
+    MissingFingerprint,
+
     /// Every bridge must have an RSA identity
     #[cfg(feature = "bridge-client")]
     #[error("Bridge line lacks specification of RSA identity key")]
     NoRsaIdentity,
 
+    /// The bridge line uses a pluggable transport that we have not been told about
+    #[cfg(feature = "pt-client")]
+    #[error("Bridge line uses unknown pluggable transport {transport:?}")]
+    UnknownTransport {
+        /// The name of the transport
+        transport: String,
+    },
+
     /// Pluggable transport support disabled in cargo features
     // We deliberately make this one *not* configured out if PT support is enabled
     #[cfg(feature = "bridge-client")]
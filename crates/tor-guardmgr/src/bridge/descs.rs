@@ -16,7 +16,7 @@ use futures::stream::BoxStream;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use strum::{EnumCount, EnumIter};
 use tor_error::{HasKind, HasRetryTime};
-use tor_linkspec::{ChanTarget, HasChanMethod, HasRelayIds, OwnedChanTarget};
+use tor_linkspec::{ChanTarget, HasChanMethod, HasRelayIds, OwnedChanTarget, RelayIdRef};
 use tor_llcrypto::pk::{ed25519::Ed25519Identity, rsa::RsaIdentity};
 use tor_netdir::RelayWeight;
 use tor_netdoc::doc::routerdesc::RouterDesc;
@@ -148,12 +148,22 @@ pub(crate) struct BridgeSet {
     /// A map from those bridges to their descriptors.  It may contain elements
     /// that are not in `config`.
     descs: Option<Arc<BridgeDescList>>,
+    /// The time at which we built this `BridgeSet`.
+    created: SystemTime,
 }
 
 impl BridgeSet {
-    /// Create a new `BridgeSet` from its configuration.
-    pub(crate) fn new(config: Arc<[BridgeConfig]>, descs: Option<Arc<BridgeDescList>>) -> Self {
-        Self { config, descs }
+    /// Create a new `BridgeSet` from its configuration, as of `now`.
+    pub(crate) fn new(
+        config: Arc<[BridgeConfig]>,
+        descs: Option<Arc<BridgeDescList>>,
+        now: SystemTime,
+    ) -> Self {
+        Self {
+            config,
+            descs,
+            created: now,
+        }
     }
 
     /// Returns the bridge that best matches a given guard.
@@ -233,19 +243,36 @@ impl Universe for BridgeSet {
                 full_dir_info: bridge_relay.has_descriptor(),
                 owned_target: OwnedChanTarget::from_chan_target(&bridge_relay),
                 sensitivity: crate::guard::DisplayRule::Redacted,
+                #[cfg(feature = "geoip")]
+                country: None,
             }),
             CandidateStatus::Absent => CandidateStatus::Absent,
             CandidateStatus::Uncertain => CandidateStatus::Uncertain,
         }
     }
 
+    fn candidate_by_id(&self, id: RelayIdRef<'_>) -> Option<Candidate> {
+        let bridge_config = self.config.iter().find(|b| b.has_identity(id))?;
+        let relay = self.relay_by_bridge(bridge_config);
+        Some(Candidate {
+            listed_as_guard: true,
+            is_dir_cache: true,
+            full_dir_info: relay.has_descriptor(),
+            owned_target: OwnedChanTarget::from_chan_target(&relay),
+            sensitivity: crate::guard::DisplayRule::Redacted,
+            #[cfg(feature = "geoip")]
+            country: None,
+        })
+    }
+
     fn timestamp(&self) -> std::time::SystemTime {
-        // We just use the current time as the timestamp of this BridgeSet.
-        // This makes the guard code treat a BridgeSet as _continuously updated_:
-        // anything listed in the guard set is treated as listed right up to this
-        // moment, and anything unlisted is treated as unlisted right up to this
-        // moment.
-        SystemTime::now()
+        // We just use the time at which we built this BridgeSet (which is
+        // always "now", since we build a new one whenever we need one) as its
+        // timestamp.  This makes the guard code treat a BridgeSet as
+        // _continuously updated_: anything listed in the guard set is treated
+        // as listed right up to this moment, and anything unlisted is treated
+        // as unlisted right up to this moment.
+        self.created
     }
 
     /// Note that for a BridgeSet, we always treat the current weight as 0 and
@@ -263,9 +290,15 @@ impl Universe for BridgeSet {
         WeightThreshold {
             current_weight: RelayWeight::from(0),
             maximum_weight: RelayWeight::from(u64::MAX),
+            total_weight: RelayWeight::from(0),
         }
     }
 
+    /// As with `weight_threshold`, bridges have no weight.
+    fn guard_weight<T: HasRelayIds>(&self, _guard: &T) -> Option<RelayWeight> {
+        None
+    }
+
     fn sample<T>(
         &self,
         pre_existing: &tor_linkspec::ByRelayIds<T>,
@@ -282,7 +315,7 @@ impl Universe for BridgeSet {
                 filter.permits(*bridge_conf)
                     && pre_existing.all_overlapping(*bridge_conf).is_empty()
             })
-            .choose_multiple(&mut rand::thread_rng(), n)
+            .choose_multiple(&mut crate::util::rng(), n)
             .into_iter()
             .map(|bridge_config| {
                 let relay = self.relay_by_bridge(bridge_config);
@@ -293,6 +326,8 @@ impl Universe for BridgeSet {
                         full_dir_info: relay.has_descriptor(),
                         owned_target: OwnedChanTarget::from_chan_target(&relay),
                         sensitivity: crate::guard::DisplayRule::Redacted,
+                        #[cfg(feature = "geoip")]
+                        country: None,
                     },
                     RelayWeight::from(0),
                 )
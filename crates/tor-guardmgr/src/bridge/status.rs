@@ -0,0 +1,113 @@
+//! Reporting on the health of our configured bridges.
+
+use std::time::Instant;
+
+use tor_linkspec::{HasChanMethod, TransportId};
+
+use super::{BridgeConfig, BridgeDesc, BridgeDescError};
+use crate::guard::{Guard, GuardReachability};
+use crate::GuardUsage;
+
+/// What we know about the descriptor for one of our bridges.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum BridgeDescStatus {
+    /// We have a descriptor for this bridge, and can build circuits through
+    /// it.
+    Present(BridgeDesc),
+    /// Our latest attempt to get a descriptor for this bridge failed.
+    Failed(Box<dyn BridgeDescError>),
+    /// We don't know anything about this bridge's descriptor yet.
+    ///
+    /// This is the case until our first attempt to fetch it finishes, or if
+    /// we have no way to fetch bridge descriptors at all.
+    Unknown,
+}
+
+/// A snapshot of what we know about one of our configured bridges.
+///
+/// Returned by [`GuardMgr::bridge_status`](crate::GuardMgr::bridge_status).
+///
+/// This is a copy: it doesn't change when the bridge's status does.
+#[derive(Clone, Debug)]
+pub struct BridgeStatus {
+    /// The bridge, as configured.
+    bridge: BridgeConfig,
+    /// What we know about the bridge's descriptor.
+    descriptor: BridgeDescStatus,
+    /// Whether we believe that we can reach the bridge, or `None` if it isn't
+    /// in our sample of bridges.
+    reachability: Option<GuardReachability>,
+    /// When we last gave out this bridge to be used as a first hop.
+    last_attempt: Option<Instant>,
+    /// When we will next be willing to retry this bridge, if we have marked
+    /// it as unreachable.
+    next_retry: Option<Instant>,
+}
+
+impl BridgeStatus {
+    /// Construct a new `BridgeStatus` for `bridge`, from its entry in our
+    /// list of bridge descriptors (if any) and the guard that represents it
+    /// in our sample (if any).
+    pub(crate) fn new(
+        bridge: BridgeConfig,
+        descriptor: BridgeDescStatus,
+        guard: Option<&Guard>,
+    ) -> Self {
+        BridgeStatus {
+            bridge,
+            descriptor,
+            reachability: guard.map(|g| g.reachable().into()),
+            last_attempt: guard.and_then(Guard::last_tried_to_connect_at),
+            next_retry: guard.and_then(|g| g.next_retry(&GuardUsage::default())),
+        }
+    }
+
+    /// Return the configuration for this bridge.
+    pub fn bridge(&self) -> &BridgeConfig {
+        &self.bridge
+    }
+
+    /// Return the transport that we use to reach this bridge.
+    ///
+    /// This is the built-in transport unless the bridge is configured with a
+    /// pluggable transport.
+    pub fn transport(&self) -> TransportId {
+        self.bridge.chan_method().transport_id()
+    }
+
+    /// Return what we know about this bridge's descriptor.
+    pub fn descriptor(&self) -> &BridgeDescStatus {
+        &self.descriptor
+    }
+
+    /// Return true if we have a descriptor for this bridge.
+    ///
+    /// We can connect to a bridge without its descriptor, but we need one
+    /// before we can build multi-hop circuits through it.
+    pub fn has_descriptor(&self) -> bool {
+        matches!(self.descriptor, BridgeDescStatus::Present(_))
+    }
+
+    /// Return whether we believe that this bridge is currently reachable,
+    /// based on our latest attempts to connect to it.
+    ///
+    /// Returns `None` if we have not added this bridge to our sample yet, and
+    /// so have never tried to use it.
+    pub fn reachability(&self) -> Option<GuardReachability> {
+        self.reachability
+    }
+
+    /// Return the most recent time at which we tried to use this bridge as
+    /// the first hop of a circuit, if we have done so since this guard
+    /// manager started.
+    pub fn last_attempt(&self) -> Option<Instant> {
+        self.last_attempt
+    }
+
+    /// Return the time at which we'll be willing to try this bridge again,
+    /// if our recent attempts to use it have failed.
+    pub fn next_retry(&self) -> Option<Instant> {
+        self.next_retry
+    }
+}
@@ -1,9 +1,20 @@
 //! Configuration elements for the guard manager
 
+use std::time::Duration;
+
+use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
 use tor_basic_utils::define_accessor_trait;
+use tor_config::{impl_standard_builder, ConfigBuildError};
+use tracing::warn;
 
 use crate::bridge::BridgeConfig;
-use crate::fallback::FallbackList;
+use crate::fallback::{AuthorityDirList, FallbackList};
+use crate::reachability::{ReachabilityInference, ReachabilityInferenceBuilder};
+#[cfg(feature = "geoip")]
+use crate::GuardFilter;
+#[cfg(feature = "geoip")]
+use tor_geoip::CountryCode;
 
 define_accessor_trait! {
     /// Configuration for a guard manager
@@ -14,6 +25,7 @@ define_accessor_trait! {
     /// Prefer to use `TorClientConfig`, which will always implement this trait.
     pub trait GuardMgrConfig {
         fallbacks: FallbackList,
+        authority_dirs: AuthorityDirList,
         bridges: [BridgeConfig],
         +
         /// Should the bridges be used?
@@ -27,6 +39,465 @@ define_accessor_trait! {
         // Therefore, it is safe (from a "reject unsupported config" point of view)
         // to ctest this only in code which is #[cfg(feature = "bridge-client")].
         fn bridges_enabled(&self) -> bool;
+
+        /// Should we make our initial directory requests only to the directory
+        /// authorities in `authority_dirs()`, and never to fallback directories?
+        ///
+        /// This is only allowed to return true if `authority_dirs()` is nonempty.
+        fn authorities_only_bootstrap(&self) -> bool;
+
+        /// Return the restrictions on which countries our guards may be
+        /// located in.
+        ///
+        /// These restrictions are applied in addition to any
+        /// [`GuardFilter`](crate::GuardFilter) set with
+        /// [`GuardMgr::set_filter`](crate::GuardMgr::set_filter).
+        /// They don't apply to bridges or fallback directories.
+        #[cfg(feature = "geoip")]
+        #[cfg_attr(docsrs, doc(cfg(feature = "geoip")))]
+        fn guard_country_restrictions(&self) -> GuardCountryRestrictions {
+            GuardCountryRestrictions::default()
+        }
+
+        /// Return the policy for discarding stored guard samples that this
+        /// version of Arti does not recognize.
+        fn guard_sample_prune_policy(&self) -> SamplePrunePolicy {
+            SamplePrunePolicy::default()
+        }
+
+        /// Return the guard parameters that we should use in place of the
+        /// ones from the consensus.
+        ///
+        /// This is meant for research and for private Tor networks: using
+        /// non-default guard parameters on the public network makes a client
+        /// stand out, and can make it easier to attack.
+        fn guard_param_overrides(&self) -> GuardParamOverrides {
+            GuardParamOverrides::default()
+        }
+
+        /// Return true if we should learn fallback directories from the
+        /// consensus, and use them alongside the configured ones.
+        fn learn_fallbacks(&self) -> bool {
+            true
+        }
+
+        /// Return whether (and how eagerly) we should stop choosing guards
+        /// on ports and address families that we seem unable to reach.
+        ///
+        /// Any addresses that we infer to be unreachable are excluded in
+        /// addition to any [`GuardFilter`](crate::GuardFilter) set with
+        /// [`GuardMgr::set_filter`](crate::GuardMgr::set_filter).
+        fn reachability_inference(&self) -> ReachabilityInference {
+            ReachabilityInference::default()
+        }
+    }
+}
+
+/// Configuration for how we choose and use guards.
+///
+/// This is the `[guards]` section of Arti's configuration.  Most of what we do
+/// with guards follows the guard specification, and the parameters in the
+/// consensus: the options here are for users whose situation it doesn't fit.
+///
+/// This type is immutable once constructed. To make one, use
+/// [`GuardConfigBuilder`], or deserialize it from a string.
+#[derive(Debug, Clone, Builder, Eq, PartialEq)]
+#[builder(build_fn(error = "ConfigBuildError"))]
+#[builder(derive(Debug, Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct GuardConfig {
+    /// Whether to learn fallback directories from the consensus, and use
+    /// them (once they have been listed for long enough) alongside the
+    /// configured ones.
+    #[builder(default = "true")]
+    #[builder_field_attr(serde(default))]
+    pub(crate) learn_fallbacks: bool,
+
+    /// Whether (and how eagerly) to stop choosing guards on ports and address
+    /// families that we seem unable to reach.
+    #[builder(sub_builder)]
+    #[builder_field_attr(serde(default))]
+    pub(crate) reachability_inference: ReachabilityInference,
+
+    /// Guard parameters to use in place of the ones from the consensus.
+    #[builder(sub_builder)]
+    #[builder_field_attr(serde(default))]
+    pub(crate) param_overrides: GuardParamOverrides,
+
+    /// When to discard stored guard samples that we don't recognize.
+    #[builder(sub_builder)]
+    #[builder_field_attr(serde(default))]
+    pub(crate) sample_pruning: SamplePrunePolicy,
+
+    /// Restrictions on the countries in which our guards may be located.
+    #[cfg(feature = "geoip")]
+    #[builder(sub_builder)]
+    #[builder_field_attr(serde(default))]
+    pub(crate) countries: GuardCountryRestrictions,
+}
+
+impl_standard_builder! { GuardConfig }
+
+impl GuardConfig {
+    /// Return true if we learn fallback directories from the consensus.
+    pub fn learn_fallbacks(&self) -> bool {
+        self.learn_fallbacks
+    }
+
+    /// Return our configuration for inferring which guard addresses we can
+    /// reach.
+    pub fn reachability_inference(&self) -> &ReachabilityInference {
+        &self.reachability_inference
+    }
+
+    /// Return the guard parameters that we use in place of the ones from the
+    /// consensus.
+    pub fn param_overrides(&self) -> &GuardParamOverrides {
+        &self.param_overrides
+    }
+
+    /// Return our policy for discarding stored guard samples that we don't
+    /// recognize.
+    pub fn sample_pruning(&self) -> &SamplePrunePolicy {
+        &self.sample_pruning
+    }
+
+    /// Return our restrictions on the countries in which our guards may be
+    /// located.
+    #[cfg(feature = "geoip")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "geoip")))]
+    pub fn countries(&self) -> &GuardCountryRestrictions {
+        &self.countries
+    }
+}
+
+/// A policy for discarding guard samples that we don't recognize from our
+/// persistent state.
+///
+/// Other versions and configurations of Arti can write guard samples that this
+/// one doesn't know how to use.  We keep those samples in our state file, so
+/// that switching back to such a version or configuration finds its guards
+/// where it left them; but we don't keep them forever.
+///
+/// Samples that we do recognize are never discarded.
+///
+/// This is the `[guards.sample_pruning]` section of Arti's configuration.  In
+/// the configuration (and in [`SamplePrunePolicyBuilder`]), a zero value for
+/// either option means that there is no limit.
+#[derive(Debug, Clone, Builder, Eq, PartialEq)]
+#[builder(build_fn(error = "ConfigBuildError"))]
+#[builder(derive(Debug, Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct SamplePrunePolicy {
+    /// Discard any unrecognized sample that has not been used for this long.
+    ///
+    /// For samples that don't say when they were last used, we count from the
+    /// time at which we first noticed them.
+    ///
+    /// If this is `None`, we never discard samples because of their age.
+    /// The default is 365 days.
+    #[builder(field(
+        type = "Option<Duration>",
+        build = "resolve_limit(self.max_unused, DEFAULT_MAX_UNUSED)"
+    ))]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    pub max_unused: Option<Duration>,
+    /// Keep at most this many unrecognized samples, discarding the least
+    /// recently used ones first.
+    ///
+    /// If this is `None`, there is no limit.  The default is 8.
+    #[builder(field(
+        type = "Option<usize>",
+        build = "resolve_limit(self.max_unrecognized, DEFAULT_MAX_UNRECOGNIZED)"
+    ))]
+    #[builder_field_attr(serde(default))]
+    pub max_unrecognized: Option<usize>,
+}
+
+impl_standard_builder! { SamplePrunePolicy }
+
+/// The default for [`SamplePrunePolicy::max_unused`].
+const DEFAULT_MAX_UNUSED: Duration = Duration::from_secs(365 * 86400);
+
+/// The default for [`SamplePrunePolicy::max_unrecognized`].
+const DEFAULT_MAX_UNRECOGNIZED: usize = 8;
+
+/// Return the limit for a [`SamplePrunePolicyBuilder`] field that was set to
+/// `val`: `default` if it was unset, and no limit if it was zero.
+fn resolve_limit<T: Default + PartialEq>(val: Option<T>, default: T) -> Option<T> {
+    match val {
+        None => Some(default),
+        Some(v) if v == T::default() => None,
+        Some(v) => Some(v),
+    }
+}
+
+impl SamplePrunePolicy {
+    /// Return a policy that never discards any samples.
+    pub fn keep_all() -> Self {
+        Self {
+            max_unused: None,
+            max_unrecognized: None,
+        }
+    }
+}
+
+/// Guard parameters to use in place of the ones from the consensus.
+///
+/// This is the `[guards.param_overrides]` section of Arti's configuration.
+///
+/// Each field that is `Some` replaces the corresponding consensus parameter.
+/// Values outside a sane range are clamped to that range.
+#[derive(Debug, Clone, Builder, Eq, PartialEq)]
+#[builder(build_fn(validate = "Self::validate", error = "ConfigBuildError"))]
+#[builder(derive(Debug, Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct GuardParamOverrides {
+    /// The largest number of guards to have in a guard sample.
+    ///
+    /// (Replaces `guard-max-sample-size`.)
+    #[builder(field(type = "Option<usize>", build = "self.max_sample_size"))]
+    #[builder_field_attr(serde(default))]
+    pub max_sample_size: Option<usize>,
+    /// The smallest number of guards to have in a guard sample, after
+    /// applying any filter.
+    ///
+    /// (Replaces `guard-min-filtered-sample-size`.)
+    #[builder(field(type = "Option<usize>", build = "self.min_filtered_sample_size"))]
+    #[builder_field_attr(serde(default))]
+    pub min_filtered_sample_size: Option<usize>,
+    /// The number of primary guards.
+    ///
+    /// (Replaces `guard-n-primary-guards`.)
+    #[builder(field(type = "Option<usize>", build = "self.n_primary"))]
+    #[builder_field_attr(serde(default))]
+    pub n_primary: Option<usize>,
+    /// How long to keep a guard that we have never connected to in the
+    /// sample.
+    ///
+    /// (Replaces `guard-lifetime-days`.)
+    #[builder(field(type = "Option<Duration>", build = "self.lifetime_unconfirmed"))]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    pub lifetime_unconfirmed: Option<Duration>,
+    /// How long to keep a guard that we have connected to in the sample.
+    ///
+    /// (Replaces `guard-confirmed-min-lifetime-days`.)
+    #[builder(field(type = "Option<Duration>", build = "self.lifetime_confirmed"))]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    pub lifetime_confirmed: Option<Duration>,
+    /// How long a guard may be missing from the consensus before we remove
+    /// it from the sample.
+    ///
+    /// (Replaces `guard-remove-unlisted-guards-after-days`.)
+    #[builder(field(type = "Option<Duration>", build = "self.lifetime_unlisted"))]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    pub lifetime_unlisted: Option<Duration>,
+}
+
+impl_standard_builder! { GuardParamOverrides }
+
+impl GuardParamOverridesBuilder {
+    /// Check that this builder's values make sense.
+    fn validate(&self) -> Result<(), ConfigBuildError> {
+        let counts = [
+            ("max_sample_size", self.max_sample_size),
+            ("min_filtered_sample_size", self.min_filtered_sample_size),
+            ("n_primary", self.n_primary),
+        ];
+        for (field, val) in counts {
+            if val == Some(0) {
+                return Err(ConfigBuildError::Invalid {
+                    field: field.to_owned(),
+                    problem: "must be at least 1".to_owned(),
+                });
+            }
+        }
+        let lifetimes = [
+            ("lifetime_unconfirmed", self.lifetime_unconfirmed),
+            ("lifetime_confirmed", self.lifetime_confirmed),
+            ("lifetime_unlisted", self.lifetime_unlisted),
+        ];
+        for (field, val) in lifetimes {
+            if val == Some(Duration::ZERO) {
+                return Err(ConfigBuildError::Invalid {
+                    field: field.to_owned(),
+                    problem: "must not be zero".to_owned(),
+                });
+            }
+        }
+        if let Some(max) = self.max_sample_size {
+            for (field, val) in [
+                ("min_filtered_sample_size", self.min_filtered_sample_size),
+                ("n_primary", self.n_primary),
+            ] {
+                if val.is_some_and(|val| val > max) {
+                    return Err(ConfigBuildError::Inconsistent {
+                        fields: vec![field.to_owned(), "max_sample_size".to_owned()],
+                        problem: format!("{} is larger than max_sample_size", field),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The range to which we clamp overridden sample sizes and guard counts.
+const OVERRIDE_COUNT_RANGE: (usize, usize) = (1, 1000);
+
+/// The range to which we clamp overridden guard lifetimes.
+const OVERRIDE_LIFETIME_RANGE: (Duration, Duration) =
+    (Duration::from_secs(3600), Duration::from_secs(3650 * 86400));
+
+impl GuardParamOverrides {
+    /// Return true if these overrides don't replace any parameters.
+    pub fn is_empty(&self) -> bool {
+        self == &GuardParamOverrides::default()
+    }
+
+    /// Return a copy of these overrides with every value clamped to a sane
+    /// range.
+    ///
+    /// Warn if any value had to be clamped, and if we are overriding any
+    /// parameters at all.
+    pub(crate) fn sanitized(&self) -> Self {
+        /// Clamp `val`, if present, to `range`, warning if it changes.
+        fn clamp<T>(name: &str, val: Option<T>, (min, max): (T, T)) -> Option<T>
+        where
+            T: Copy + Ord + std::fmt::Debug,
+        {
+            let val = val?;
+            let clamped = val.clamp(min, max);
+            if clamped != val {
+                warn!(
+                    "Configured guard parameter {} was {:?}; using {:?} instead.",
+                    name, val, clamped
+                );
+            }
+            Some(clamped)
+        }
+
+        let sanitized = GuardParamOverrides {
+            max_sample_size: clamp(
+                "max_sample_size",
+                self.max_sample_size,
+                OVERRIDE_COUNT_RANGE,
+            ),
+            min_filtered_sample_size: clamp(
+                "min_filtered_sample_size",
+                self.min_filtered_sample_size,
+                OVERRIDE_COUNT_RANGE,
+            ),
+            n_primary: clamp("n_primary", self.n_primary, OVERRIDE_COUNT_RANGE),
+            lifetime_unconfirmed: clamp(
+                "lifetime_unconfirmed",
+                self.lifetime_unconfirmed,
+                OVERRIDE_LIFETIME_RANGE,
+            ),
+            lifetime_confirmed: clamp(
+                "lifetime_confirmed",
+                self.lifetime_confirmed,
+                OVERRIDE_LIFETIME_RANGE,
+            ),
+            lifetime_unlisted: clamp(
+                "lifetime_unlisted",
+                self.lifetime_unlisted,
+                OVERRIDE_LIFETIME_RANGE,
+            ),
+        };
+        if !sanitized.is_empty() {
+            warn!(
+                "Overriding guard parameters from the consensus with {:?}. This is only a good idea for research or private networks.",
+                sanitized
+            );
+        }
+        sanitized
+    }
+}
+
+/// Restrictions on the countries in which our guards may be located.
+///
+/// This is the `[guards.countries]` section of Arti's configuration, where
+/// each country is given as a two-letter country code.
+///
+/// We learn each relay's country from the GeoIP information in the network
+/// directory.
+#[cfg(feature = "geoip")]
+#[cfg_attr(docsrs, doc(cfg(feature = "geoip")))]
+#[derive(Debug, Clone, Builder, Eq, PartialEq)]
+#[builder(build_fn(validate = "Self::validate", error = "ConfigBuildError"))]
+#[builder(derive(Debug, Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct GuardCountryRestrictions {
+    /// If this is nonempty, we only use guards located in one of these
+    /// countries.
+    ///
+    /// Guards whose country we don't know are not permitted.
+    #[builder(field(
+        type = "Vec<String>",
+        build = r#"parse_countries("required", &self.required)?"#
+    ))]
+    #[builder_field_attr(serde(default))]
+    pub required: Vec<CountryCode>,
+    /// We never use guards located in any of these countries.
+    ///
+    /// Guards whose country we don't know are permitted.
+    #[builder(field(
+        type = "Vec<String>",
+        build = r#"parse_countries("excluded", &self.excluded)?"#
+    ))]
+    #[builder_field_attr(serde(default))]
+    pub excluded: Vec<CountryCode>,
+}
+
+#[cfg(feature = "geoip")]
+impl_standard_builder! { GuardCountryRestrictions }
+
+#[cfg(feature = "geoip")]
+impl GuardCountryRestrictionsBuilder {
+    /// Check that this builder's values make sense.
+    fn validate(&self) -> Result<(), ConfigBuildError> {
+        let required = parse_countries("required", &self.required)?;
+        let excluded = parse_countries("excluded", &self.excluded)?;
+        if let Some(cc) = required.iter().find(|cc| excluded.contains(cc)) {
+            return Err(ConfigBuildError::Inconsistent {
+                fields: vec!["required".to_owned(), "excluded".to_owned()],
+                problem: format!("{} is both required and excluded", cc),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Parse the country codes in `codes`, which were given for `field`.
+#[cfg(feature = "geoip")]
+fn parse_countries(field: &str, codes: &[String]) -> Result<Vec<CountryCode>, ConfigBuildError> {
+    codes
+        .iter()
+        .map(|code| {
+            code.parse().map_err(|_| ConfigBuildError::Invalid {
+                field: field.to_owned(),
+                problem: format!("{:?} is not a country code", code),
+            })
+        })
+        .collect()
+}
+
+#[cfg(feature = "geoip")]
+impl GuardCountryRestrictions {
+    /// Return true if these restrictions permit every guard.
+    pub fn is_empty(&self) -> bool {
+        self.required.is_empty() && self.excluded.is_empty()
+    }
+
+    /// Add these restrictions to `filter`.
+    pub(crate) fn add_to_filter(&self, filter: &mut GuardFilter) {
+        if !self.required.is_empty() {
+            filter.push_required_countries(self.required.iter().copied());
+        }
+        if !self.excluded.is_empty() {
+            filter.push_excluded_countries(self.excluded.iter().copied());
+        }
     }
 }
 
@@ -57,7 +528,16 @@ pub(crate) mod testing {
     pub struct TestConfig {
         #[as_ref]
         pub fallbacks: FallbackList,
+        #[as_ref]
+        pub authority_dirs: AuthorityDirList,
+        pub authorities_only: bool,
         pub bridges: Vec<BridgeConfig>,
+        #[cfg(feature = "geoip")]
+        pub countries: GuardCountryRestrictions,
+        pub sample_prune_policy: SamplePrunePolicy,
+        pub param_overrides: GuardParamOverrides,
+        pub reachability_inference: ReachabilityInference,
+        pub disable_learned_fallbacks: bool,
     }
     impl AsRef<[BridgeConfig]> for TestConfig {
         fn as_ref(&self) -> &[BridgeConfig] {
@@ -68,5 +548,122 @@ pub(crate) mod testing {
         fn bridges_enabled(&self) -> bool {
             !self.bridges.is_empty()
         }
+        fn authorities_only_bootstrap(&self) -> bool {
+            self.authorities_only
+        }
+        #[cfg(feature = "geoip")]
+        fn guard_country_restrictions(&self) -> GuardCountryRestrictions {
+            self.countries.clone()
+        }
+        fn guard_sample_prune_policy(&self) -> SamplePrunePolicy {
+            self.sample_prune_policy.clone()
+        }
+        fn guard_param_overrides(&self) -> GuardParamOverrides {
+            self.param_overrides.clone()
+        }
+        fn reachability_inference(&self) -> ReachabilityInference {
+            self.reachability_inference.clone()
+        }
+        fn learn_fallbacks(&self) -> bool {
+            !self.disable_learned_fallbacks
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn guard_config() {
+        let cfg: GuardConfigBuilder = toml::from_str(
+            r#"
+            [param_overrides]
+            max_sample_size = 30
+            n_primary = 2
+            lifetime_unlisted = "5 days"
+            [sample_pruning]
+            max_unused = "0 s"
+            "#,
+        )
+        .unwrap();
+        let cfg = cfg.build().unwrap();
+        let overrides = cfg.param_overrides();
+        assert_eq!(overrides.max_sample_size, Some(30));
+        assert_eq!(overrides.n_primary, Some(2));
+        assert_eq!(overrides.min_filtered_sample_size, None);
+        assert_eq!(
+            overrides.lifetime_unlisted,
+            Some(Duration::from_secs(5 * 86400))
+        );
+        assert_eq!(cfg.sample_pruning().max_unused, None);
+        assert_eq!(cfg.sample_pruning().max_unrecognized, Some(8));
+
+        let dflt = GuardConfig::default();
+        assert!(dflt.param_overrides().is_empty());
+        assert_eq!(
+            dflt.sample_pruning().max_unused,
+            Some(Duration::from_secs(365 * 86400))
+        );
+    }
+
+    #[test]
+    fn validate_overrides() {
+        assert!(GuardParamOverridesBuilder::default()
+            .n_primary(Some(0))
+            .build()
+            .is_err());
+        assert!(GuardParamOverridesBuilder::default()
+            .lifetime_confirmed(Some(Duration::ZERO))
+            .build()
+            .is_err());
+        let err = GuardParamOverridesBuilder::default()
+            .max_sample_size(Some(10))
+            .min_filtered_sample_size(Some(20))
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, ConfigBuildError::Inconsistent { .. }));
+        assert!(GuardParamOverridesBuilder::default()
+            .max_sample_size(Some(10))
+            .n_primary(Some(10))
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "geoip")]
+    fn validate_countries() {
+        let cfg = GuardCountryRestrictionsBuilder::default()
+            .required(vec!["de".into(), "NL".into()])
+            .excluded(vec!["US".into()])
+            .build()
+            .unwrap();
+        assert_eq!(
+            cfg.required,
+            vec!["DE".parse().unwrap(), "NL".parse().unwrap()]
+        );
+        assert!(GuardCountryRestrictionsBuilder::default()
+            .required(vec!["Germany".into()])
+            .build()
+            .is_err());
+        let err = GuardCountryRestrictionsBuilder::default()
+            .required(vec!["DE".into()])
+            .excluded(vec!["de".into()])
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, ConfigBuildError::Inconsistent { .. }));
     }
 }
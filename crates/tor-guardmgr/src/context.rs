@@ -0,0 +1,115 @@
+//! Named guard contexts, each with its own isolated guard samples.
+//!
+//! An application that acts on behalf of several unlinkable identities (for
+//! example, several browser profiles, or several VPN tunnels) should not let
+//! those identities share guards: a guard that sees the same client for two
+//! identities can link them.  Instead of running a separate guard manager for
+//! each identity, such an application can give each identity its own
+//! [`GuardContextId`], and pass it to
+//! [`GuardUsageBuilder::context`](crate::GuardUsageBuilder::context).
+//!
+//! Each context has its own guard samples, stored under its own key in the
+//! state manager.  Everything else (our configuration, our filter, our
+//! fallback directories, and the parameters that we take from the consensus)
+//! is shared between contexts.
+
+use std::fmt;
+
+use tor_persist::DynStorageHandle;
+
+use crate::ids::GuardId;
+use crate::{GuardMgrError, GuardSets};
+
+/// The longest name that we allow for a guard context.
+const MAX_NAME_LEN: usize = 64;
+
+/// The name of a guard context within a [`GuardMgr`](crate::GuardMgr).
+///
+/// Context names are non-empty, no longer than 64 characters, and contain
+/// only ASCII letters, digits, `-`, and `_`, so that we can use them in the
+/// names of state files.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct GuardContextId(String);
+
+impl GuardContextId {
+    /// Return a new `GuardContextId` with the name `name`.
+    ///
+    /// Returns an error if `name` isn't a valid context name.
+    pub fn new(name: impl Into<String>) -> Result<Self, GuardMgrError> {
+        let name = name.into();
+        let valid = !name.is_empty()
+            && name.len() <= MAX_NAME_LEN
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if valid {
+            Ok(GuardContextId(name))
+        } else {
+            Err(GuardMgrError::InvalidContextName(name))
+        }
+    }
+
+    /// Return the name of this context.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Return the key under which we store the guards for this context.
+    pub(crate) fn storage_key(&self) -> String {
+        format!("{}@{}", crate::STORAGE_KEY, self.0)
+    }
+}
+
+impl fmt::Display for GuardContextId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The state for one guard context, other than the default context.
+///
+/// While we are acting within a context, its guards and its latest primary
+/// guard are swapped into the guard manager's main state, and
+/// the default context's are held here instead.
+pub(crate) struct GuardContext {
+    /// The guard samples for this context.
+    pub(crate) guards: GuardSets,
+    /// The most-preferred primary guard of this context's active set, as of
+    /// the last time that we checked.
+    pub(crate) last_primary_guard: Option<GuardId>,
+    /// Location in which to store this context's guards.
+    pub(crate) storage: DynStorageHandle<GuardSets>,
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn names() {
+        let id = GuardContextId::new("profile-2_a").unwrap();
+        assert_eq!(id.as_str(), "profile-2_a");
+        assert_eq!(id.to_string(), "profile-2_a");
+        assert_eq!(id.storage_key(), "guards@profile-2_a");
+
+        assert!(GuardContextId::new("").is_err());
+        assert!(GuardContextId::new("a/b").is_err());
+        assert!(GuardContextId::new("..").is_err());
+        assert!(GuardContextId::new("naïve").is_err());
+        assert!(GuardContextId::new("x".repeat(64)).is_ok());
+        assert!(GuardContextId::new("x".repeat(65)).is_err());
+    }
+}
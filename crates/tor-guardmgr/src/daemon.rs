@@ -3,15 +3,30 @@
 //! These background tasks keep a weak reference to the [`GuardMgrInner`]
 //! and use that to notice when they should shut down.
 
+use crate::ids::GuardId;
 use crate::pending::{GuardStatus, RequestId};
-use crate::GuardMgrInner;
+use crate::probe::{GuardProbe, PROBE_INTERVAL};
+use crate::{GuardMgrInner, GuardReachability};
 
 use futures::{channel::mpsc, stream::StreamExt};
-#[cfg(test)]
+#[cfg(any(test, feature = "testing"))]
 use oneshot_fused_workaround as oneshot;
 use tor_proto::ClockSkew;
+use tor_rtcompat::scheduler::TaskSchedule;
+use tracing::debug;
 
-use std::sync::{Mutex, Weak};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+/// The largest number of status reports from a single batch that we handle
+/// while holding the lock on the guard manager.
+///
+/// After handling this many, we release the lock and yield, so that other
+/// users of the guard manager get a chance to run.
+const MAX_REPORTS_PER_LOCK: usize = 16;
+
+/// A report about the outcome of a single attempt to use a guard.
+pub(crate) type StatusReport = (RequestId, GuardStatus, Option<ClockSkew>);
 
 /// A message sent by to the [`report_status_events()`] task.
 #[derive(Debug)]
@@ -19,13 +34,123 @@ pub(crate) enum Msg {
     /// A message sent by a [`GuardMonitor`](crate::GuardMonitor) to
     /// report the status of an attempt to use a guard.
     Status(RequestId, GuardStatus, Option<ClockSkew>),
+    /// A message sent by [`GuardMonitor::report_batch`](crate::GuardMonitor::report_batch)
+    /// to report the status of many attempts at once.
+    StatusBatch(Vec<StatusReport>),
     /// Tells the task to reply on the provided oneshot::Sender once
     /// it has seen this message.  Used to indicate that the message
     /// queue is flushed.
-    #[cfg(test)]
+    #[cfg(any(test, feature = "testing"))]
     Ping(oneshot::Sender<()>),
 }
 
+impl Msg {
+    /// Return the number of status reports in this message.
+    fn n_reports(&self) -> usize {
+        match self {
+            Msg::Status(..) => 1,
+            Msg::StatusBatch(reports) => reports.len(),
+            #[cfg(any(test, feature = "testing"))]
+            Msg::Ping(_) => 0,
+        }
+    }
+}
+
+/// A handle used to send messages to the [`report_status_events()`] task.
+///
+/// This wraps an `UnboundedSender`, and keeps track of how many status
+/// reports are waiting in the channel.
+#[derive(Clone, Debug)]
+pub(crate) struct MsgSender {
+    /// The underlying channel.
+    snd: mpsc::UnboundedSender<Msg>,
+    /// Counters shared with the receiving task.
+    counters: Arc<QueueCounters>,
+}
+
+/// Counters describing the traffic on a [`MsgSender`]'s channel.
+#[derive(Debug, Default)]
+pub(crate) struct QueueCounters {
+    /// The number of status reports sent but not yet handled.
+    pending: AtomicUsize,
+    /// The largest value that `pending` has ever had.
+    max_pending: AtomicUsize,
+    /// The total number of status reports we have handled.
+    n_handled: AtomicU64,
+    /// The total number of batches we have received.
+    n_batches: AtomicU64,
+}
+
+impl QueueCounters {
+    /// Record that we have handled `n` status reports.
+    fn note_handled(&self, n: usize) {
+        self.pending.fetch_sub(n, Ordering::Relaxed);
+        self.n_handled.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Return a snapshot of these counters.
+    pub(crate) fn snapshot(&self) -> StatusQueueStats {
+        StatusQueueStats {
+            pending: self.pending.load(Ordering::Relaxed),
+            max_pending: self.max_pending.load(Ordering::Relaxed),
+            n_handled: self.n_handled.load(Ordering::Relaxed),
+            n_batches: self.n_batches.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Diagnostic information about the queue of guard status reports that a
+/// [`GuardMgr`](crate::GuardMgr) has not yet processed.
+///
+/// Returned by [`GuardMgr::status_queue_stats`](crate::GuardMgr::status_queue_stats).
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct StatusQueueStats {
+    /// The number of status reports that have been sent, but not yet handled.
+    pub pending: usize,
+    /// The largest number of status reports that have ever been waiting at
+    /// once.
+    pub max_pending: usize,
+    /// The total number of status reports that have been handled.
+    pub n_handled: u64,
+    /// The total number of batches of status reports that have been received.
+    pub n_batches: u64,
+}
+
+impl MsgSender {
+    /// Construct a new `MsgSender`, and the receiver to pass to
+    /// [`report_status_events()`].
+    pub(crate) fn new() -> (Self, mpsc::UnboundedReceiver<Msg>) {
+        let (snd, rcv) = mpsc::unbounded();
+        let counters = Default::default();
+        (MsgSender { snd, counters }, rcv)
+    }
+
+    /// Send `msg` to the receiving task.
+    pub(crate) fn send(&self, msg: Msg) -> Result<(), mpsc::TrySendError<Msg>> {
+        let n = msg.n_reports();
+        // We add to `pending` before sending, so that it can't underflow if
+        // the receiving task handles the message right away.
+        let pending = self.counters.pending.fetch_add(n, Ordering::Relaxed) + n;
+        self.counters
+            .max_pending
+            .fetch_max(pending, Ordering::Relaxed);
+        self.snd.unbounded_send(msg).inspect_err(|_| {
+            self.counters.pending.fetch_sub(n, Ordering::Relaxed);
+        })
+    }
+
+    /// Return true if `self` and `other` send to the same task.
+    pub(crate) fn same_receiver(&self, other: &MsgSender) -> bool {
+        self.snd.same_receiver(&other.snd)
+    }
+
+    /// Return the counters for this channel.
+    pub(crate) fn counters(&self) -> &Arc<QueueCounters> {
+        &self.counters
+    }
+}
+
 /// Background task: wait for messages about guard statuses, and
 /// tell a guard manager about them.  Runs indefinitely.
 ///
@@ -35,8 +160,8 @@ pub(crate) enum Msg {
 /// Requires a `mpsc::Receiver` that is used to tell the task about
 /// new status events to wait for.
 pub(crate) async fn report_status_events(
-    runtime: impl tor_rtcompat::SleepProvider,
     inner: Weak<Mutex<GuardMgrInner>>,
+    counters: Arc<QueueCounters>,
     mut events: mpsc::UnboundedReceiver<Msg>,
 ) {
     loop {
@@ -45,13 +170,31 @@ pub(crate) async fn report_status_events(
                 // We've got a report about a guard status.
                 if let Some(inner) = inner.upgrade() {
                     let mut inner = inner.lock().expect("Poisoned lock");
-                    inner.handle_msg(id, status, skew, &runtime);
+                    inner.handle_msg(id, status, skew);
                 } else {
                     // The guard manager has gone away.
                     return;
                 }
+                counters.note_handled(1);
+            }
+            Some(Msg::StatusBatch(reports)) => {
+                counters.n_batches.fetch_add(1, Ordering::Relaxed);
+                // Handle the reports a few at a time, so that we don't hold
+                // the lock for too long when there are a lot of them.
+                for chunk in reports.chunks(MAX_REPORTS_PER_LOCK) {
+                    if let Some(inner) = inner.upgrade() {
+                        let mut inner = inner.lock().expect("Poisoned lock");
+                        for &(id, status, skew) in chunk {
+                            inner.handle_msg(id, status, skew);
+                        }
+                    } else {
+                        return;
+                    }
+                    counters.note_handled(chunk.len());
+                    tor_rtcompat::task::yield_now().await;
+                }
             }
-            #[cfg(test)]
+            #[cfg(any(test, feature = "testing"))]
             Some(Msg::Ping(sender)) => {
                 let _ignore = sender.send(());
             }
@@ -77,8 +220,7 @@ pub(crate) async fn run_periodic<R: tor_rtcompat::SleepProvider>(
     loop {
         let delay = if let Some(inner) = inner.upgrade() {
             let mut inner = inner.lock().expect("Poisoned lock");
-            let wallclock = runtime.wallclock();
-            let now = runtime.now();
+            let (wallclock, now) = inner.current_time();
             inner.run_periodic_events(wallclock, now)
         } else {
             // The guard manager has gone away.
@@ -88,10 +230,58 @@ pub(crate) async fn run_periodic<R: tor_rtcompat::SleepProvider>(
     }
 }
 
+/// Background task to probe our unreachable primary guards from time to time,
+/// and make them retriable if the probe can reach them.
+///
+/// Takes the [`GuardMgrInner`] and the [`GuardProbe`] by weak reference; if
+/// either goes away, or if every handle to `sched` is dropped, then this task
+/// exits.  While `sched` is suspended, we don't probe.
+pub(crate) async fn probe_unreachable_guards<R: tor_rtcompat::SleepProvider>(
+    mut sched: TaskSchedule<R>,
+    inner: Weak<Mutex<GuardMgrInner>>,
+    prober: Weak<dyn GuardProbe>,
+) {
+    while sched.sleep(PROBE_INTERVAL).await.is_ok() {
+        let unreachable: Vec<_> = if let Some(inner) = inner.upgrade() {
+            let inner = inner.lock().expect("Poisoned lock");
+            inner
+                .guards
+                .active_guards()
+                .primary_guard_infos()
+                .into_iter()
+                .filter(|g| g.reachability() == GuardReachability::Unreachable)
+                .collect()
+        } else {
+            // The guard manager has gone away.
+            return;
+        };
+
+        for guard in unreachable {
+            // We don't hold the lock while we probe, since a probe can take
+            // a while.
+            let Some(prober) = prober.upgrade() else {
+                return;
+            };
+            if !prober.probe(&guard).await {
+                continue;
+            }
+            if let Some(inner) = inner.upgrade() {
+                let mut inner = inner.lock().expect("Poisoned lock");
+                debug!("Probe reached an unreachable guard; marking it retriable.");
+                inner
+                    .guards
+                    .active_guards_mut()
+                    .mark_guard_retriable(&GuardId::from_relay_ids(guard.ids()));
+            } else {
+                return;
+            }
+        }
+    }
+}
+
 /// Background task to keep a guard manager up-to-date with a given network
 /// directory provider.
-pub(crate) async fn keep_netdir_updated<RT: tor_rtcompat::Runtime>(
-    runtime: RT,
+pub(crate) async fn keep_netdir_updated(
     inner: Weak<Mutex<GuardMgrInner>>,
     netdir_provider: Weak<dyn tor_netdir::NetDirProvider>,
 ) {
@@ -107,7 +297,11 @@ pub(crate) async fn keep_netdir_updated<RT: tor_rtcompat::Runtime>(
             DirEvent::NewConsensus | DirEvent::NewDescriptors => {
                 if let Some(inner) = inner.upgrade() {
                     let mut inner = inner.lock().expect("Poisoned lock");
-                    inner.update(runtime.wallclock(), runtime.now());
+                    let (wallclock, now) = inner.current_time();
+                    inner.for_each_context(|inner| inner.update(wallclock, now));
+                    if event == DirEvent::NewConsensus {
+                        inner.update_learned_fallbacks(wallclock);
+                    }
                 } else {
                     return;
                 }
@@ -120,8 +314,7 @@ pub(crate) async fn keep_netdir_updated<RT: tor_rtcompat::Runtime>(
 /// Background task to keep a guard manager up-to-date with a given bridge
 /// descriptor provider.
 #[cfg(feature = "bridge-client")]
-pub(crate) async fn keep_bridge_descs_updated<RT: tor_rtcompat::Runtime>(
-    runtime: RT,
+pub(crate) async fn keep_bridge_descs_updated(
     inner: Weak<Mutex<GuardMgrInner>>,
     bridge_desc_provider: Weak<dyn crate::bridge::BridgeDescProvider>,
 ) {
@@ -136,7 +329,8 @@ pub(crate) async fn keep_bridge_descs_updated<RT: tor_rtcompat::Runtime>(
             E::SomethingChanged => {
                 if let Some(inner) = inner.upgrade() {
                     let mut inner = inner.lock().expect("Poisoned lock");
-                    inner.update(runtime.wallclock(), runtime.now());
+                    let (wallclock, now) = inner.current_time();
+                    inner.for_each_context(|inner| inner.update(wallclock, now));
                 } else {
                     return;
                 }
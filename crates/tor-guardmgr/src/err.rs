@@ -52,6 +52,10 @@ pub enum PickGuardError {
     #[error("Tried to pick from an empty list")]
     NoCandidatesAvailable,
 
+    /// Tried to select a guard from a guard context that doesn't exist.
+    #[error("No such guard context as {0}")]
+    UnknownContext(crate::GuardContextId),
+
     /// An internal programming error occurred.
     #[error("Internal error")]
     Internal(#[from] Bug),
@@ -64,6 +68,7 @@ impl tor_error::HasKind for PickGuardError {
         match self {
             E::AllFallbacksDown { .. } | E::AllGuardsDown { .. } => EK::TorAccessFailed,
             E::NoCandidatesAvailable => EK::NoPath,
+            E::UnknownContext(_) => EK::BadApiUsage,
             E::Internal(_) => EK::Internal,
         }
     }
@@ -93,6 +98,9 @@ impl tor_error::HasRetryTime for PickGuardError {
             // line.
             E::NoCandidatesAvailable => RT::Never,
 
+            // Nobody is going to create the context for us.
+            E::UnknownContext(_) => RT::Never,
+
             // Don't try to recover from internal errors.
             E::Internal(_) => RT::Never,
         }
@@ -119,6 +127,18 @@ pub enum GuardMgrError {
         #[source]
         cause: Arc<SpawnError>,
     },
+
+    /// Tried to import a guard state blob with a format that we don't
+    /// support.
+    #[error("Guard state has unsupported format version {version}")]
+    UnsupportedStateVersion {
+        /// The format version of the blob.
+        version: u32,
+    },
+
+    /// Tried to name a guard context with a name that we don't allow.
+    #[error("Invalid guard context name {0:?}")]
+    InvalidContextName(String),
 }
 
 impl HasKind for GuardMgrError {
@@ -129,6 +149,8 @@ impl HasKind for GuardMgrError {
             G::State(e)               => e.kind(),
             G::InvalidConfig(e)       => e.kind(),
             G::Spawn{ cause, .. }     => cause.kind(),
+            G::UnsupportedStateVersion{..} => ErrorKind::NotImplemented,
+            G::InvalidContextName(_)  => ErrorKind::BadApiUsage,
         }
     }
 }
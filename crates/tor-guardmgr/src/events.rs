@@ -1,12 +1,15 @@
 //! Code to remotely notify other crates about changes in the status of the
 //! `GuardMgr`.
 
+use std::net::SocketAddr;
 use std::{pin::Pin, task::Poll};
 
 use crate::skew::SkewEstimate;
 use educe::Educe;
+use futures::channel::mpsc;
 use futures::{Stream, StreamExt};
 use tor_basic_utils::skip_fmt;
+use tor_linkspec::RelayIds;
 
 /// A stream of [`SkewEstimate`] events.
 ///
@@ -42,3 +45,216 @@ impl ClockSkewEvents {
         self.inner.borrow().clone()
     }
 }
+
+/// A notification that one of the guards in our sample has changed its
+/// addresses.
+///
+/// Relays move from time to time, but a relay that keeps its identity keys
+/// while changing its address unusually often may be a sign that its keys
+/// have been stolen.
+#[derive(Clone, Debug)]
+pub struct GuardAddrChange {
+    /// The identities of the guard that moved.
+    pub(crate) ids: RelayIds,
+    /// The addresses we knew for the guard before this change.
+    pub(crate) old_addrs: Vec<SocketAddr>,
+    /// The addresses that the guard has now.
+    pub(crate) new_addrs: Vec<SocketAddr>,
+    /// The number of times (including this one) that we have seen this
+    /// guard's addresses change recently.
+    pub(crate) n_recent_changes: usize,
+    /// True if the guard's addresses have changed often enough that we
+    /// consider it suspicious.
+    pub(crate) suspicious: bool,
+}
+
+impl GuardAddrChange {
+    /// Return the identities of the guard whose addresses changed.
+    pub fn relay_ids(&self) -> &RelayIds {
+        &self.ids
+    }
+
+    /// Return the addresses that we knew for this guard before the change.
+    pub fn old_addrs(&self) -> &[SocketAddr] {
+        &self.old_addrs[..]
+    }
+
+    /// Return the addresses that this guard has now.
+    pub fn new_addrs(&self) -> &[SocketAddr] {
+        &self.new_addrs[..]
+    }
+
+    /// Return the number of times that we have seen this guard's addresses
+    /// change recently, including this change.
+    pub fn n_recent_changes(&self) -> usize {
+        self.n_recent_changes
+    }
+
+    /// Return true if this guard has moved often enough that it may have
+    /// been hijacked.
+    pub fn is_suspicious(&self) -> bool {
+        self.suspicious
+    }
+}
+
+/// A stream of [`GuardAddrChange`] events.
+///
+/// Unlike [`ClockSkewEvents`], this stream is not lossy: every address
+/// change that we notice after the stream is created will be delivered.
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct GuardAddrChangeEvents {
+    /// The receiver that we're wrapping.
+    #[educe(Debug(method = "skip_fmt"))]
+    pub(crate) inner: mpsc::UnboundedReceiver<GuardAddrChange>,
+}
+
+impl Stream for GuardAddrChangeEvents {
+    type Item = GuardAddrChange;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+/// A notification that we have decided that we can't reach guards on some
+/// port and address family, and have stopped choosing guards there.
+///
+/// We start choosing guards there again once the inference expires (after
+/// [`ReachabilityInference::lifetime`](crate::ReachabilityInference::lifetime)),
+/// or when we reach a guard there anyway.  We don't send a notification when
+/// that happens.
+///
+/// We only make these inferences when
+/// [`ReachabilityInference`](crate::ReachabilityInference) is enabled.
+#[derive(Clone, Debug)]
+pub struct UnreachableAddrInference {
+    /// True if the unreachable addresses are IPv6 addresses.
+    pub(crate) ipv6: bool,
+    /// The unreachable port.
+    pub(crate) port: u16,
+    /// The number of different guards that we failed to reach on this port
+    /// and family.
+    pub(crate) n_failed_guards: usize,
+}
+
+impl UnreachableAddrInference {
+    /// Return true if we can't reach IPv6 addresses on [`port`](Self::port);
+    /// false if we can't reach IPv4 addresses on it.
+    pub fn is_ipv6(&self) -> bool {
+        self.ipv6
+    }
+
+    /// Return the port on which we can't reach guards.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Return the number of different guards that we had recently failed to
+    /// reach on this port and address family when we made this inference.
+    pub fn n_failed_guards(&self) -> usize {
+        self.n_failed_guards
+    }
+}
+
+/// A stream of [`UnreachableAddrInference`] events.
+///
+/// Like [`GuardAddrChangeEvents`], this stream is not lossy.
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct UnreachableAddrEvents {
+    /// The receiver that we're wrapping.
+    #[educe(Debug(method = "skip_fmt"))]
+    pub(crate) inner: mpsc::UnboundedReceiver<UnreachableAddrInference>,
+}
+
+impl Stream for UnreachableAddrEvents {
+    type Item = UnreachableAddrInference;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+/// How much a subscriber to [`PrimaryGuardEvents`] may learn about our
+/// primary guard.
+///
+/// Most subsystems only need to know _that_ the primary guard has changed,
+/// and should not be told which relay it is.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum GuardIdDisclosure {
+    /// Tell the subscriber that the primary guard changed, but not what it is.
+    #[default]
+    Withhold,
+    /// Also tell the subscriber the identities of the new primary guard.
+    Reveal,
+}
+
+/// A notification that our most-preferred primary guard has changed.
+///
+/// Subsystems that keep state tied to the first hop of our circuits (such as
+/// circuit padding) can use these notifications to know when to discard that
+/// state.
+#[derive(Clone, Debug)]
+pub struct PrimaryGuardChange {
+    /// A counter that increases every time our primary guard changes.
+    pub(crate) epoch: u64,
+    /// True if we have a primary guard at all.
+    pub(crate) has_primary: bool,
+    /// The identities of the new primary guard, if we have one and the
+    /// subscriber is allowed to know it.
+    pub(crate) ids: Option<RelayIds>,
+}
+
+impl PrimaryGuardChange {
+    /// Return a number that increases every time our primary guard changes.
+    ///
+    /// Two events with the same epoch refer to the same primary guard.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Return true if we have a primary guard after this change.
+    ///
+    /// This can be false if, for example, our filter excludes every guard.
+    pub fn has_primary_guard(&self) -> bool {
+        self.has_primary
+    }
+
+    /// Return the identities of our new primary guard.
+    ///
+    /// Return `None` if we have no primary guard, or if this event's
+    /// subscriber asked for [`GuardIdDisclosure::Withhold`].
+    pub fn relay_ids(&self) -> Option<&RelayIds> {
+        self.ids.as_ref()
+    }
+}
+
+/// A stream of [`PrimaryGuardChange`] events.
+///
+/// Like [`GuardAddrChangeEvents`], this stream is not lossy.
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct PrimaryGuardEvents {
+    /// The receiver that we're wrapping.
+    #[educe(Debug(method = "skip_fmt"))]
+    pub(crate) inner: mpsc::UnboundedReceiver<PrimaryGuardChange>,
+}
+
+impl Stream for PrimaryGuardEvents {
+    type Item = PrimaryGuardChange;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
@@ -0,0 +1,72 @@
+//! A portable copy of our guard state, for moving guards between installations.
+//!
+//! Our persistent guard state is an internal detail of how a
+//! [`GuardMgr`](crate::GuardMgr) uses its `StateMgr`: it lives under a
+//! particular key.  A [`GuardStateBlob`] wraps the same information in an
+//! envelope that can be stored anywhere, so that users who move to a new
+//! device (or a new state directory) can carry their guards with them.
+//!
+//! The blob is not a separately specified interchange format: its contents
+//! are our persistent guard state, and are only as stable as that is.
+
+use serde::{Deserialize, Serialize};
+
+use crate::GuardSets;
+
+/// The version of the [`GuardStateBlob`] format that we write.
+///
+/// We can import any blob with this version or an earlier one.
+const BLOB_FORMAT_VERSION: u32 = 1;
+
+/// A complete, portable copy of a guard manager's guard samples.
+///
+/// Returned by [`GuardMgr::export_state`](crate::GuardMgr::export_state), and
+/// accepted by [`GuardMgr::import_state`](crate::GuardMgr::import_state).
+///
+/// This includes every guard sample that we store (including our bridge
+/// sample, and any samples that this version of Arti does not recognize),
+/// each with its guards in sample order, which of them are confirmed, and
+/// their persistent statistics.  Primary guards are not listed separately:
+/// we always choose them from the confirmed guards, in confirmed order, so
+/// they come back the same after an import.
+///
+/// Use any `serde` format to store or transmit a blob.  Its JSON form is an
+/// object with a `version` field, and a `samples` field that holds our
+/// persistent guard state as-is.  That state can change whenever our
+/// internal representation of guards does, so don't rely on its layout:
+/// only pass a blob back to a [`GuardMgr`](crate::GuardMgr), and expect the
+/// same compatibility between Arti versions as for our state files.  The
+/// `version` field only lets us reject blobs from a newer, incompatible
+/// envelope.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuardStateBlob {
+    /// The format version of this blob.
+    version: u32,
+    /// The guard samples in this blob.
+    samples: GuardSets,
+}
+
+impl GuardStateBlob {
+    /// Wrap `samples` in a new blob, using our current format version.
+    pub(crate) fn new(samples: GuardSets) -> Self {
+        GuardStateBlob {
+            version: BLOB_FORMAT_VERSION,
+            samples,
+        }
+    }
+
+    /// Return the format version of this blob.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Return true if we know how to import this blob.
+    pub fn is_supported(&self) -> bool {
+        self.version <= BLOB_FORMAT_VERSION
+    }
+
+    /// Consume this blob, and return the guard samples inside it.
+    pub(crate) fn into_samples(self) -> GuardSets {
+        self.samples
+    }
+}
@@ -10,6 +10,7 @@
 //! The types in this module are re-exported from `arti-client` and
 //! `tor-dirmgr`: any changes here must be reflected there.
 
+mod learned;
 mod set;
 
 use base64ct::{Base64Unpadded, Encoding as _};
@@ -24,8 +25,11 @@ use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 
 use crate::dirstatus::DirStatus;
+pub(crate) use learned::LearnedFallbacks;
+#[cfg(test)]
+pub(crate) use learned::LEARNED_FALLBACK_MIN_AGE;
 pub(crate) use set::FallbackState;
-pub use set::{FallbackList, FallbackListBuilder};
+pub use set::{AuthorityDirList, AuthorityDirListBuilder, FallbackList, FallbackListBuilder};
 
 /// A directory whose location ships with Tor (or arti), and which we
 /// can use for bootstrapping when we don't know anything else about
@@ -61,6 +65,7 @@ impl FallbackDir {
         crate::FirstHop {
             sample: None,
             inner: crate::FirstHopInner::Chan(OwnedChanTarget::from_chan_target(self)),
+            provisional: false,
         }
     }
 }
@@ -0,0 +1,292 @@
+//! Declare the [`LearnedFallbacks`] type, which remembers fallback directories
+//! that we found in the consensus.
+//!
+//! Our compiled-in fallback list goes stale as relays come and go, and a
+//! client that has been offline for a long time may find that few of its
+//! fallbacks still work.  To help with that, whenever we get a new consensus,
+//! we keep track of a few relays that would make good fallbacks: relays that
+//! are listed as `Guard`, `Stable`, and `Fast`, and that are directory caches.
+//! We save them with the rest of our state, and once a relay has stayed
+//! suitable for [`LEARNED_FALLBACK_MIN_AGE`], we use it as a fallback
+//! alongside the configured ones.
+//!
+//! We can't tell which network a relay belongs to before we have a consensus,
+//! so we record which fallback directories we were configured with when we
+//! learned our relays, and forget them all if that changes: a private network,
+//! or another Tor network, has fallbacks of its own.
+
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use base64ct::{Base64Unpadded, Encoding as _};
+use digest::Digest;
+use serde::{Deserialize, Serialize};
+use tor_linkspec::HasAddrs;
+use tor_linkspec::HasRelayIdsLegacy as _;
+use tor_llcrypto::d::Sha256;
+use tor_llcrypto::pk::ed25519::Ed25519Identity;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdir::{NetDir, Relay, WeightRole};
+
+use super::{FallbackDir, FallbackList};
+
+/// The largest number of fallbacks that we learn from the consensus.
+const MAX_LEARNED_FALLBACKS: usize = 64;
+
+/// How long a relay must stay suitable before we use it as a fallback.
+pub(crate) const LEARNED_FALLBACK_MIN_AGE: Duration = Duration::from_secs(7 * 86400);
+
+/// A relay that we found in the consensus, and might use as a fallback.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct LearnedFallback {
+    /// RSA identity for the relay.
+    rsa_identity: RsaIdentity,
+    /// Ed25519 identity for the relay.
+    ed_identity: Ed25519Identity,
+    /// The relay's ORPorts, as of the last consensus in which we saw it.
+    orports: Vec<SocketAddr>,
+    /// When we first saw this relay as suitable.
+    ///
+    /// If it stops being suitable, we forget about it, so this is the start
+    /// of an unbroken run.
+    #[serde(with = "humantime_serde")]
+    suitable_since: SystemTime,
+}
+
+impl LearnedFallback {
+    /// Return this relay as a [`FallbackDir`], or `None` if it has no ORPorts.
+    fn to_fallback_dir(&self) -> Option<FallbackDir> {
+        let mut bld = FallbackDir::builder();
+        bld.rsa_identity(self.rsa_identity)
+            .ed_identity(self.ed_identity);
+        for addr in &self.orports {
+            bld.orports().push(*addr);
+        }
+        bld.build().ok()
+    }
+}
+
+/// The set of fallback directories that we have learned from the consensus,
+/// in the form that we store it.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub(crate) struct LearnedFallbacks {
+    /// The relays that we're tracking, whether or not they are old enough to
+    /// use yet.
+    #[serde(default)]
+    fallbacks: Vec<LearnedFallback>,
+    /// A digest of the fallback directories that we were configured with when
+    /// we learned these relays.
+    ///
+    /// (See [`network_key`].)
+    #[serde(default)]
+    network: Option<String>,
+}
+
+/// Return a key that identifies the network whose configured fallback
+/// directories are `configured`.
+///
+/// This also changes when a new version of Arti ships a new list of fallbacks;
+/// that's fine, since that list is fresh.
+fn network_key(configured: &FallbackList) -> String {
+    let mut ids: Vec<RsaIdentity> = configured.iter().map(|fb| *fb.rsa_identity()).collect();
+    ids.sort();
+    ids.dedup();
+    let mut d = Sha256::new();
+    for id in &ids {
+        d.update(id.as_bytes());
+    }
+    Base64Unpadded::encode_string(&d.finalize())
+}
+
+/// Return true if `relay` would make a good fallback directory.
+fn is_suitable(relay: &Relay<'_>) -> bool {
+    let details = relay.low_level_details();
+    details.is_suitable_as_guard() && details.is_dir_cache() && !relay.addrs().is_empty()
+}
+
+impl LearnedFallbacks {
+    /// Update this set from a new consensus in `netdir`, received at `now`.
+    ///
+    /// We forget every relay that is no longer listed as suitable, and pick
+    /// new suitable relays (weighted like guards) until we are tracking
+    /// [`MAX_LEARNED_FALLBACKS`] of them.
+    ///
+    /// Return true if anything changed.
+    pub(crate) fn update_from_netdir<R: rand::Rng>(
+        &mut self,
+        rng: &mut R,
+        netdir: &NetDir,
+        now: SystemTime,
+    ) -> bool {
+        let mut changed = false;
+        self.fallbacks.retain_mut(|fb| {
+            let relay = netdir
+                .by_id(&fb.ed_identity)
+                .filter(|relay| relay.rsa_id() == &fb.rsa_identity);
+            match relay {
+                Some(relay) if is_suitable(&relay) => {
+                    if relay.addrs() != &fb.orports[..] {
+                        fb.orports = relay.addrs().to_vec();
+                        changed = true;
+                    }
+                    true
+                }
+                _ => {
+                    changed = true;
+                    false
+                }
+            }
+        });
+
+        let n_wanted = MAX_LEARNED_FALLBACKS.saturating_sub(self.fallbacks.len());
+        if n_wanted > 0 {
+            let known: Vec<RsaIdentity> = self.fallbacks.iter().map(|fb| fb.rsa_identity).collect();
+            let new = netdir.pick_n_relays(rng, n_wanted, WeightRole::Guard, |relay| {
+                is_suitable(relay) && !known.contains(relay.rsa_id())
+            });
+            for relay in new {
+                self.fallbacks.push(LearnedFallback {
+                    rsa_identity: *relay.rsa_id(),
+                    ed_identity: *relay.id(),
+                    orports: relay.addrs().to_vec(),
+                    suitable_since: now,
+                });
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    /// Forget every relay in this set, unless we learned them while we were
+    /// configured with the fallback directories in `configured`.
+    ///
+    /// Return true if anything changed.
+    pub(crate) fn retain_only_network(&mut self, configured: &FallbackList) -> bool {
+        let key = network_key(configured);
+        match &self.network {
+            Some(network) if network == &key => return false,
+            // We don't know where relays from an older state file came from,
+            // so we keep them: they'll be gone after the next consensus if
+            // they don't belong to our network.
+            None => {}
+            Some(_) => self.fallbacks.clear(),
+        }
+        self.network = Some(key);
+        true
+    }
+
+    /// Return the number of relays that we're tracking.
+    pub(crate) fn len(&self) -> usize {
+        self.fallbacks.len()
+    }
+
+    /// Return every relay that has been suitable for at least
+    /// [`LEARNED_FALLBACK_MIN_AGE`] as of `now`.
+    pub(crate) fn usable(&self, now: SystemTime) -> Vec<FallbackDir> {
+        self.fallbacks
+            .iter()
+            .filter(|fb| {
+                now.duration_since(fb.suitable_since)
+                    .is_ok_and(|age| age >= LEARNED_FALLBACK_MIN_AGE)
+            })
+            .filter_map(LearnedFallback::to_fallback_dir)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use tor_basic_utils::test_rng::testing_rng;
+    use tor_netdir::testnet;
+    use tor_netdoc::doc::netstatus::RelayFlags;
+
+    #[test]
+    fn learn_and_age() {
+        let mut rng = testing_rng();
+        let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+        let n_suitable = netdir.relays().filter(is_suitable).count();
+        assert!(n_suitable > 0);
+        assert!(n_suitable < MAX_LEARNED_FALLBACKS);
+
+        let t0 = SystemTime::now();
+        let mut learned = LearnedFallbacks::default();
+        assert!(learned.update_from_netdir(&mut rng, &netdir, t0));
+        assert_eq!(learned.len(), n_suitable);
+        // Nothing changes if we see the same directory again.
+        assert!(!learned.update_from_netdir(&mut rng, &netdir, t0 + Duration::from_secs(3600)));
+
+        // We don't use them until they are old enough.
+        assert!(learned.usable(t0).is_empty());
+        let later = t0 + LEARNED_FALLBACK_MIN_AGE;
+        let usable = learned.usable(later);
+        assert_eq!(usable.len(), n_suitable);
+        for fb in &usable {
+            let relay = netdir.by_ids(fb).unwrap();
+            assert!(is_suitable(&relay));
+            assert_eq!(fb.addrs(), relay.addrs());
+        }
+
+        // We forget them all if our configured fallbacks change, but not
+        // otherwise.
+        let configured = FallbackList::default();
+        assert!(learned.retain_only_network(&configured));
+        assert_eq!(learned.len(), n_suitable);
+        assert!(!learned.retain_only_network(&configured));
+        let mut other = learned.clone();
+        let builtin = crate::fallback::FallbackListBuilder::default()
+            .build()
+            .unwrap();
+        assert!(other.retain_only_network(&builtin));
+        assert_eq!(other.len(), 0);
+
+        // We survive a round trip through our storage format.
+        let json = serde_json::to_string(&learned).unwrap();
+        let learned2: LearnedFallbacks = serde_json::from_str(&json).unwrap();
+        assert_eq!(learned, learned2);
+    }
+
+    #[test]
+    fn forget_unsuitable() {
+        let mut rng = testing_rng();
+        let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+        let t0 = SystemTime::now();
+        let mut learned = LearnedFallbacks::default();
+        learned.update_from_netdir(&mut rng, &netdir, t0);
+        let n_before = learned.len();
+
+        // Now the first relay that we learned loses its Guard flag.
+        // (In the test network, relay `idx` has the RSA identity `[idx; 20]`.)
+        let lost = learned.fallbacks[0].rsa_identity;
+        let lost_idx = usize::from(lost.as_bytes()[0]);
+        let netdir2 = testnet::construct_custom_netdir(|idx, node, _| {
+            if idx == lost_idx {
+                node.rs
+                    .set_flags(RelayFlags::RUNNING | RelayFlags::VALID | RelayFlags::V2DIR);
+            }
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+        let t1 = t0 + LEARNED_FALLBACK_MIN_AGE;
+        assert!(learned.update_from_netdir(&mut rng, &netdir2, t1));
+        assert_eq!(learned.len(), n_before - 1);
+        let usable = learned.usable(t1);
+        assert_eq!(usable.len(), n_before - 1);
+        assert!(usable.iter().all(|fb| fb.rsa_identity != lost));
+    }
+}
@@ -1,7 +1,7 @@
 //! Declare the [`FallbackState`] type, which is used to store a set of FallbackDir.
 
 use crate::skew::SkewObservation;
-use rand::seq::IteratorRandom;
+use rand::seq::{IteratorRandom, SliceRandom};
 use std::time::{Duration, Instant};
 use tor_linkspec::HasRelayIds;
 
@@ -48,6 +48,10 @@ impl FallbackList {
     pub fn is_empty(&self) -> bool {
         self.fallbacks.is_empty()
     }
+    /// Return an iterator over the fallbacks in this list.
+    pub fn iter(&self) -> impl Iterator<Item = &FallbackDir> + '_ {
+        self.fallbacks.iter()
+    }
     /// Return a random member of this list.
     pub fn choose<R: rand::Rng>(&self, rng: &mut R) -> Result<&FallbackDir, PickGuardError> {
         self.fallbacks
@@ -57,6 +61,49 @@ impl FallbackList {
     }
 }
 
+/// A list of directory authorities that we can contact directly, for use
+/// instead of fallback directories when we bootstrap.
+///
+/// Unlike a [`FallbackList`], this list is empty by default: we do not ship
+/// the addresses and identities of the directory authorities' directory ports.
+///
+/// (This list is only used if our configuration says that we should only
+/// bootstrap from directory authorities; see
+/// [`GuardMgrConfig::authorities_only_bootstrap`](crate::GuardMgrConfig::authorities_only_bootstrap).)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuthorityDirList {
+    /// The underlying authorities in this list.
+    authorities: Vec<FallbackDir>,
+}
+
+impl<T: IntoIterator<Item = FallbackDir>> From<T> for AuthorityDirList {
+    fn from(authorities: T) -> Self {
+        AuthorityDirList {
+            authorities: authorities.into_iter().collect(),
+        }
+    }
+}
+
+define_list_builder_helper! {
+    // pub because tor-dirmgr needs it for NetworkConfig.authority_dirs
+    pub struct AuthorityDirListBuilder {
+        pub(crate) authorities: [FallbackDirBuilder],
+    }
+    built: AuthorityDirList = AuthorityDirList { authorities };
+    default = vec![];
+}
+
+impl AuthorityDirList {
+    /// Return the number of authorities in this list.
+    pub fn len(&self) -> usize {
+        self.authorities.len()
+    }
+    /// Return true if there are no authorities in this list.
+    pub fn is_empty(&self) -> bool {
+        self.authorities.is_empty()
+    }
+}
+
 /// A set of fallback directories, in usable form.
 #[derive(Debug, Clone)]
 pub(crate) struct FallbackState {
@@ -111,14 +158,38 @@ impl HasRelayIds for Entry {
 
 impl From<&FallbackList> for FallbackState {
     fn from(list: &FallbackList) -> Self {
-        let mut fallbacks: Vec<Entry> = list.fallbacks.iter().map(|fb| fb.clone().into()).collect();
+        FallbackState::from_dirs(&list.fallbacks)
+    }
+}
+
+impl From<&AuthorityDirList> for FallbackState {
+    fn from(list: &AuthorityDirList) -> Self {
+        FallbackState::from_dirs(&list.authorities)
+    }
+}
+
+impl FallbackState {
+    /// Construct a new `FallbackState` holding every directory in `dirs`.
+    fn from_dirs(dirs: &[FallbackDir]) -> Self {
+        let mut fallbacks: Vec<Entry> = dirs.iter().map(|fb| fb.clone().into()).collect();
         fallbacks.sort_by(|x, y| x.cmp_by_relay_ids(y));
         fallbacks.dedup_by(|x, y| x.same_relay_ids(y));
         FallbackState { fallbacks }
     }
-}
 
-impl FallbackState {
+    /// Construct a new `FallbackState` holding every directory in `list`, and
+    /// every directory in `learned`.
+    pub(crate) fn with_learned(list: &FallbackList, learned: Vec<FallbackDir>) -> Self {
+        let mut dirs = list.fallbacks.clone();
+        dirs.extend(learned);
+        FallbackState::from_dirs(&dirs)
+    }
+
+    /// Return the number of entries in this set.
+    pub(crate) fn len(&self) -> usize {
+        self.fallbacks.len()
+    }
+
     /// Return a random member of this FallbackSet that's usable at `now`.
     pub(crate) fn choose<R: rand::Rng>(
         &self,
@@ -146,6 +217,26 @@ impl FallbackState {
             })
     }
 
+    /// Return up to `n` distinct members of this FallbackSet that are usable at
+    /// `now` and permitted by `filter`, in random order.
+    pub(crate) fn choose_multiple<R: rand::Rng>(
+        &self,
+        rng: &mut R,
+        now: Instant,
+        filter: &crate::GuardFilter,
+        n: usize,
+    ) -> Vec<&FallbackDir> {
+        let mut chosen: Vec<_> = self
+            .fallbacks
+            .iter()
+            .filter(|ent| ent.status.usable_at(now) && filter.permits(&ent.fallback))
+            .map(|ent| &ent.fallback)
+            .choose_multiple(rng, n);
+        // `choose_multiple` doesn't randomize the order of its output.
+        chosen.shuffle(rng);
+        chosen
+    }
+
     /// Return the next time at which any member of this set will become ready.
     ///
     /// Returns None if no elements are failing.
@@ -1,5 +1,7 @@
 //! Implement GuardFilter and related types.
 
+#[cfg(feature = "geoip")]
+use tor_geoip::{CountryCode, HasCountryCode as _};
 use tor_linkspec::ChanTarget;
 // TODO(nickm): Conceivably, this type should be exposed from a lower-level crate than
 // tor-netdoc.
@@ -32,6 +34,39 @@ enum SingleFilter {
     /// This list of patterns has "or" semantics: a guard is permitted by this filter
     /// if ANY pattern in this list permits one of the guard's addresses.
     ReachableAddrs(Vec<AddrPortPattern>),
+    /// A set of countries in which our guards must be located.
+    ///
+    /// A relay whose country we don't know is not permitted by this filter.
+    #[cfg(feature = "geoip")]
+    RequireCountries(Vec<CountryCode>),
+    /// A set of countries in which our guards must not be located.
+    ///
+    /// A relay whose country we don't know is permitted by this filter.
+    #[cfg(feature = "geoip")]
+    ExcludeCountries(Vec<CountryCode>),
+}
+
+/// A first hop that a [`GuardFilter`] can check.
+pub(crate) trait FilterTarget: ChanTarget {
+    /// Return the country where this target is located, for the purpose of
+    /// checking country restrictions.
+    #[cfg(feature = "geoip")]
+    fn target_country(&self) -> TargetCountry;
+}
+
+/// The location of a first hop, as far as a [`GuardFilter`] is concerned.
+#[cfg(feature = "geoip")]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum TargetCountry {
+    /// A relay from the network directory, located in the given country (if
+    /// we know it).
+    Listed(Option<CountryCode>),
+    /// A first hop that we didn't take from the network directory, such as a
+    /// fallback directory.
+    ///
+    /// Country restrictions don't apply to these: the user configured them
+    /// explicitly.
+    Unlisted,
 }
 
 impl GuardFilter {
@@ -48,8 +83,44 @@ impl GuardFilter {
             .push(SingleFilter::ReachableAddrs(addrs.into_iter().collect()));
     }
 
+    /// Return an iterator over the lists of address patterns in this filter.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn reachable_addr_patterns(&self) -> impl Iterator<Item = &[AddrPortPattern]> {
+        self.filters.iter().filter_map(|f| match f {
+            SingleFilter::ReachableAddrs(patterns) => Some(&patterns[..]),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        })
+    }
+
+    /// Restrict this filter to only permit relays located in one of the
+    /// countries in `countries`.
+    ///
+    /// Relays whose country we don't know are not permitted.  This restriction
+    /// does not apply to fallback directories or bridges.
+    #[cfg(feature = "geoip")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "geoip")))]
+    pub fn push_required_countries(&mut self, countries: impl IntoIterator<Item = CountryCode>) {
+        self.filters.push(SingleFilter::RequireCountries(
+            countries.into_iter().collect(),
+        ));
+    }
+
+    /// Restrict this filter to only permit relays located outside all of the
+    /// countries in `countries`.
+    ///
+    /// Relays whose country we don't know are permitted.  This restriction does
+    /// not apply to fallback directories or bridges.
+    #[cfg(feature = "geoip")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "geoip")))]
+    pub fn push_excluded_countries(&mut self, countries: impl IntoIterator<Item = CountryCode>) {
+        self.filters.push(SingleFilter::ExcludeCountries(
+            countries.into_iter().collect(),
+        ));
+    }
+
     /// Return true if this filter permits the provided `target`.
-    pub(crate) fn permits<C: ChanTarget>(&self, target: &C) -> bool {
+    pub(crate) fn permits<C: FilterTarget>(&self, target: &C) -> bool {
         self.filters.iter().all(|filt| filt.permits(target))
     }
 
@@ -118,14 +189,44 @@ impl GuardFilter {
                 SingleFilter::ReachableAddrs(addrs) => {
                     RelayRestriction::require_address(addrs.clone())
                 }
+                #[cfg(feature = "geoip")]
+                SingleFilter::RequireCountries(ccs) => {
+                    RelayRestriction::require_country_code_in(ccs.clone())
+                }
+                #[cfg(feature = "geoip")]
+                SingleFilter::ExcludeCountries(ccs) => {
+                    RelayRestriction::exclude_country_codes(ccs.clone())
+                }
             });
         }
     }
 }
 
+impl<'a> FilterTarget for tor_netdir::Relay<'a> {
+    #[cfg(feature = "geoip")]
+    fn target_country(&self) -> TargetCountry {
+        TargetCountry::Listed(self.country_code())
+    }
+}
+
+impl FilterTarget for crate::fallback::FallbackDir {
+    #[cfg(feature = "geoip")]
+    fn target_country(&self) -> TargetCountry {
+        TargetCountry::Unlisted
+    }
+}
+
+#[cfg(feature = "bridge-client")]
+impl FilterTarget for crate::bridge::BridgeConfig {
+    #[cfg(feature = "geoip")]
+    fn target_country(&self) -> TargetCountry {
+        TargetCountry::Unlisted
+    }
+}
+
 impl SingleFilter {
     /// Return true if this filter permits the provided target.
-    fn permits<C: ChanTarget>(&self, target: &C) -> bool {
+    fn permits<C: FilterTarget>(&self, target: &C) -> bool {
         match self {
             // TODO: This is partially duplicated with tor-relay-selection,
             // but (for now) that only covers Relays, not general ChanTargets.
@@ -140,6 +241,16 @@ impl SingleFilter {
                     }
                 })
             }
+            #[cfg(feature = "geoip")]
+            SingleFilter::RequireCountries(ccs) => match target.target_country() {
+                TargetCountry::Listed(cc) => cc.is_some_and(|cc| ccs.contains(&cc)),
+                TargetCountry::Unlisted => true,
+            },
+            #[cfg(feature = "geoip")]
+            SingleFilter::ExcludeCountries(ccs) => match target.target_country() {
+                TargetCountry::Listed(cc) => !cc.is_some_and(|cc| ccs.contains(&cc)),
+                TargetCountry::Unlisted => true,
+            },
         }
     }
 
@@ -178,6 +289,9 @@ impl SingleFilter {
                     .into());
                 }
             }
+            // Country restrictions don't change how we contact a guard.
+            #[cfg(feature = "geoip")]
+            SingleFilter::RequireCountries(_) | SingleFilter::ExcludeCountries(_) => {}
         }
         Ok(first_hop)
     }
@@ -223,4 +337,71 @@ mod test {
         };
         assert_float_eq!(net_1_only.frac_bw_permitted(&nd), 0.28, abs <= TOL);
     }
+
+    #[cfg(feature = "geoip")]
+    #[test]
+    fn countries() {
+        use tor_geoip::GeoipDb;
+        use tor_linkspec::HasAddrs;
+
+        // All the addresses in the test network are {0,1,2,3,4}.0.0.3:9001.
+        // Put 1.0.0.0/8 in the US and 2.0.0.0/8 in Germany.
+        let src_v4 = r#"
+        16777216,33554431,US
+        33554432,50331647,DE
+        "#;
+        let db = GeoipDb::new_from_legacy_format(src_v4, "").unwrap();
+        let nd = testnet::construct_custom_netdir_with_geoip(|_, _, _| {}, &db)
+            .unwrap()
+            .unwrap_if_sufficient()
+            .unwrap();
+        let us: CountryCode = "US".parse().unwrap();
+        let de: CountryCode = "DE".parse().unwrap();
+        const TOL: f64 = 0.01;
+
+        let us_only = {
+            let mut f = GuardFilter::default();
+            f.push_required_countries([us]);
+            f
+        };
+        assert_float_eq!(us_only.frac_bw_permitted(&nd), 0.28, abs <= TOL);
+
+        // Relays whose country we don't know are not excluded.
+        let not_us = {
+            let mut f = GuardFilter::default();
+            f.push_excluded_countries([us]);
+            f
+        };
+        assert_float_eq!(not_us.frac_bw_permitted(&nd), 0.72, abs <= TOL);
+
+        let us_or_de = {
+            let mut f = GuardFilter::default();
+            f.push_required_countries([us, de]);
+            f
+        };
+        let not_us_or_de = {
+            let mut f = GuardFilter::default();
+            f.push_excluded_countries([us, de]);
+            f
+        };
+        let both = us_or_de.frac_bw_permitted(&nd);
+        assert!(both > 0.28 && both < 1.0);
+        assert_float_eq!(not_us_or_de.frac_bw_permitted(&nd), 1.0 - both, abs <= TOL);
+
+        for relay in nd.relays() {
+            let in_us = relay.addrs()[0].ip() == std::net::IpAddr::from([1, 0, 0, 3]);
+            assert_eq!(us_only.permits(&relay), in_us);
+            assert_eq!(not_us.permits(&relay), !in_us);
+        }
+
+        // Country restrictions don't apply to fallbacks.
+        let mut bld = crate::fallback::FallbackDir::builder();
+        bld.rsa_identity([1; 20].into())
+            .ed_identity([1; 32].into())
+            .orports()
+            .push("1.0.0.3:9001".parse().unwrap());
+        let fb = bld.build().unwrap();
+        assert!(us_only.permits(&fb));
+        assert!(not_us.permits(&fb));
+    }
 }
@@ -1,16 +1,17 @@
 //! Code to represent its single guard node and track its status.
 
-use tor_basic_utils::retry::RetryDelay;
-
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::time::{Duration, Instant, SystemTime};
 use tracing::{info, trace, warn};
 
+use crate::backoff::{GuardBackoff, RetryParams};
 use crate::dirstatus::DirStatus;
+use crate::events::GuardAddrChange;
 use crate::sample::Candidate;
 use crate::skew::SkewObservation;
+use crate::stats::GuardStats;
 use crate::util::randomize_time;
 use crate::{ids::GuardId, GuardParams, GuardRestriction, GuardUsage};
 use crate::{sample, ExternalActivity, GuardSetSelector, GuardUsageKind};
@@ -42,6 +43,99 @@ pub(crate) enum Reachable {
     Retriable,
 }
 
+/// Whether we believe that a guard is reachable.
+///
+/// Returned by [`GuardInfo::reachability`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum GuardReachability {
+    /// We have used this guard successfully more recently than we have failed
+    /// to use it.
+    Reachable,
+    /// Our recent attempts to use this guard have failed, and we are waiting
+    /// before we try it again.
+    Unreachable,
+    /// We haven't tried to use this guard since this guard manager started.
+    Untried,
+    /// Our last attempt to use this guard failed, but we are willing to try it
+    /// again.
+    Retriable,
+}
+
+impl From<Reachable> for GuardReachability {
+    fn from(r: Reachable) -> Self {
+        match r {
+            Reachable::Reachable => GuardReachability::Reachable,
+            Reachable::Unreachable => GuardReachability::Unreachable,
+            Reachable::Untried => GuardReachability::Untried,
+            Reachable::Retriable => GuardReachability::Retriable,
+        }
+    }
+}
+
+/// A snapshot of what we know about one guard in our sample.
+///
+/// Returned by [`GuardMgr::primary_guards`](crate::GuardMgr::primary_guards)
+/// and [`GuardMgr::sampled_guards`](crate::GuardMgr::sampled_guards).
+///
+/// This is a copy: it doesn't change when the guard's status does.
+#[derive(Clone, Debug)]
+pub struct GuardInfo {
+    /// The identities of the guard.
+    ids: RelayIds,
+    /// The most recently seen addresses for the guard.
+    addrs: Vec<SocketAddr>,
+    /// True if this is one of our primary guards.
+    is_primary: bool,
+    /// True if we have ever used this guard successfully.
+    is_confirmed: bool,
+    /// Whether we believe that this guard is reachable.
+    reachability: GuardReachability,
+    /// True if this guard is listed in our directory, but we don't have a
+    /// microdescriptor for it.
+    dir_info_missing: bool,
+}
+
+impl GuardInfo {
+    /// Return the identities of this guard.
+    pub fn ids(&self) -> &RelayIds {
+        &self.ids
+    }
+
+    /// Return the most recently seen addresses for this guard.
+    ///
+    /// For a bridge that we reach over a pluggable transport, these are the
+    /// addresses at which the bridge is located, which may not be the ones we
+    /// connect to.
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Return true if this is currently one of our primary guards.
+    pub fn is_primary(&self) -> bool {
+        self.is_primary
+    }
+
+    /// Return true if this guard is "confirmed": that is, if we have ever
+    /// used it successfully.
+    ///
+    /// We prefer confirmed guards over the rest of our sample.
+    pub fn is_confirmed(&self) -> bool {
+        self.is_confirmed
+    }
+
+    /// Return whether we believe that this guard is currently reachable.
+    pub fn reachability(&self) -> GuardReachability {
+        self.reachability
+    }
+
+    /// Return false if this guard is listed in our directory, but we have not
+    /// yet downloaded the directory information we need to use it.
+    pub fn has_dir_info(&self) -> bool {
+        !self.dir_info_missing
+    }
+}
+
 /// The name and version of the crate that first picked a potential
 /// guard.
 ///
@@ -55,6 +149,10 @@ struct CrateId {
     crate_name: String,
     /// The version of the crate that added this guard.
     version: String,
+    /// Fields from the state file that this version of Arti doesn't
+    /// understand.
+    #[serde(flatten)]
+    unknown_fields: HashMap<String, JsonValue>,
 }
 
 impl CrateId {
@@ -65,6 +163,7 @@ impl CrateId {
         Some(CrateId {
             crate_name,
             version,
+            unknown_fields: Default::default(),
         })
     }
 }
@@ -165,6 +264,18 @@ pub(crate) struct Guard {
     #[serde(with = "humantime_serde")]
     unlisted_since: Option<SystemTime>,
 
+    /// The `valid_after` dates of the consensuses in which we saw this guard's
+    /// addresses change, within the last [`ADDR_CHANGE_WINDOW`].
+    ///
+    /// We keep this so that we can notice a guard whose identity stays the
+    /// same, but which moves around unusually often.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    addrs_changed_at: Vec<AddrChangeTime>,
+
+    /// Long-term statistics about our attempts to use this guard.
+    #[serde(default, skip_serializing_if = "GuardStats::is_empty")]
+    stats: GuardStats,
+
     /// True if this guard is listed in the latest consensus, but we don't
     /// have a microdescriptor for it.
     #[serde(skip)]
@@ -185,7 +296,7 @@ pub(crate) struct Guard {
     /// Schedule use to determine when we can next attempt to connect to this
     /// guard.
     #[serde(skip)]
-    retry_schedule: Option<RetryDelay>,
+    retry_schedule: Option<GuardBackoff>,
 
     /// Current reachability status for this guard.
     #[serde(skip)]
@@ -214,6 +325,11 @@ pub(crate) struct Guard {
     #[serde(skip)]
     exploratory_circ_pending: bool,
 
+    /// The number of attempts to use this guard that we have launched, and
+    /// not yet heard the outcome of.
+    #[serde(skip)]
+    attempts_in_flight: usize,
+
     /// A count of all the circuit statuses we've seen on this guard.
     ///
     /// Used to implement a lightweight version of path-bias detection.
@@ -228,6 +344,12 @@ pub(crate) struct Guard {
     #[serde(skip)]
     clock_skew: Option<SkewObservation>,
 
+    /// The country where this guard is located, according to the latest
+    /// directory (if we know it).
+    #[cfg(feature = "geoip")]
+    #[serde(skip)]
+    country: Option<tor_geoip::CountryCode>,
+
     /// How should we display information about this guard?
     #[serde(skip)]
     sensitivity: DisplayRule,
@@ -238,6 +360,21 @@ pub(crate) struct Guard {
     unknown_fields: HashMap<String, JsonValue>,
 }
 
+/// A time at which we noticed that a guard's addresses had changed.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(transparent)]
+struct AddrChangeTime(#[serde(with = "humantime_serde")] SystemTime);
+
+/// How long do we remember that a guard's addresses have changed?
+const ADDR_CHANGE_WINDOW: Duration = Duration::from_secs(30 * 86400);
+
+/// If a guard's addresses change this many times within
+/// [`ADDR_CHANGE_WINDOW`], we consider its behavior suspicious.
+///
+/// (Relays do move from time to time, but a relay that keeps its identity
+/// keys while moving this often may have had its keys stolen.)
+const SUSPICIOUS_ADDR_CHANGES: usize = 3;
+
 /// Lower bound for delay after get a failure using a guard as a directory
 /// cache.
 const GUARD_DIR_RETRY_FLOOR: Duration = Duration::from_secs(60);
@@ -268,12 +405,16 @@ impl Guard {
             is_dir_cache,
             full_dir_info,
             owned_target,
+            #[cfg(feature = "geoip")]
+            country,
             ..
         } = candidate;
 
         Guard {
             is_dir_cache,
             dir_info_missing: !full_dir_info,
+            #[cfg(feature = "geoip")]
+            country,
             ..Self::from_chan_target(&owned_target, now, params)
         }
     }
@@ -287,7 +428,7 @@ impl Guard {
         T: ChanTarget,
     {
         let added_at = randomize_time(
-            &mut rand::thread_rng(),
+            &mut crate::util::rng(),
             now,
             params.lifetime_unconfirmed / 10,
         );
@@ -306,6 +447,27 @@ impl Guard {
         )
     }
 
+    /// Return a [`Guard`] that we imported from another implementation's
+    /// state, which first sampled it at `added_at`.
+    ///
+    /// We don't yet know whether it is listed, so we treat it as missing
+    /// directory information until we next see a directory.
+    pub(crate) fn imported(
+        id: GuardId,
+        orports: Vec<SocketAddr>,
+        added_at: SystemTime,
+        confirmed_at: Option<SystemTime>,
+        unlisted_since: Option<SystemTime>,
+    ) -> Self {
+        Guard {
+            added_by: None,
+            confirmed_at,
+            unlisted_since,
+            dir_info_missing: true,
+            ..Self::new(id, orports, None, added_at)
+        }
+    }
+
     /// Return a new, manually constructed [`Guard`].
     fn new(
         id: GuardId,
@@ -322,6 +484,8 @@ impl Guard {
             disabled: None,
             confirmed_at: None,
             unlisted_since: None,
+            addrs_changed_at: Vec::new(),
+            stats: GuardStats::default(),
             dir_info_missing: false,
             last_tried_to_connect_at: None,
             reachable: Reachable::Untried,
@@ -330,9 +494,12 @@ impl Guard {
             retry_schedule: None,
             is_dir_cache: true,
             exploratory_circ_pending: false,
+            attempts_in_flight: 0,
             circ_history: CircHistory::default(),
             suspicious_behavior_warned: false,
             clock_skew: None,
+            #[cfg(feature = "geoip")]
+            country: None,
             unknown_fields: Default::default(),
             sensitivity: DisplayRule::Sensitive,
         }
@@ -343,11 +510,38 @@ impl Guard {
         &self.id
     }
 
+    /// Return the long-term statistics for this guard.
+    pub(crate) fn stats(&self) -> &GuardStats {
+        &self.stats
+    }
+
+    /// Return a mutable reference to the long-term statistics for this guard.
+    pub(crate) fn stats_mut(&mut self) -> &mut GuardStats {
+        &mut self.stats
+    }
+
     /// Return the reachability status for this guard.
     pub(crate) fn reachable(&self) -> Reachable {
         self.reachable
     }
 
+    /// Return the reachability status that this guard would have at `now`,
+    /// if we checked whether it was time to retry it.
+    ///
+    /// (See [`Guard::consider_retry`].)
+    pub(crate) fn reachable_at(&self, now: Instant) -> Reachable {
+        match self.retry_at {
+            Some(retry_at) if retry_at <= now => Reachable::Retriable,
+            _ => self.reachable,
+        }
+    }
+
+    /// Return the last time at which we gave out this guard in response to a
+    /// request, if we have done so.
+    pub(crate) fn last_tried_to_connect_at(&self) -> Option<Instant> {
+        self.last_tried_to_connect_at
+    }
+
     /// Return the next time at which this guard will be retriable for a given
     /// usage.
     ///
@@ -416,6 +610,8 @@ impl Guard {
             disabled: self.disabled,
             confirmed_at: self.confirmed_at,
             unlisted_since: self.unlisted_since,
+            addrs_changed_at: self.addrs_changed_at,
+            stats: self.stats,
             unknown_fields: self.unknown_fields,
 
             // All non-persistent fields get taken from `other`.
@@ -425,12 +621,15 @@ impl Guard {
             reachable: other.reachable,
             is_dir_cache: other.is_dir_cache,
             exploratory_circ_pending: other.exploratory_circ_pending,
+            attempts_in_flight: other.attempts_in_flight,
             dir_info_missing: other.dir_info_missing,
             circ_history: other.circ_history,
             suspicious_behavior_warned: other.suspicious_behavior_warned,
             dir_status: other.dir_status,
             clock_skew: other.clock_skew,
             sensitivity: other.sensitivity,
+            #[cfg(feature = "geoip")]
+            country: other.country,
             // Note that we _could_ remove either of the above blocks and add
             // `..self` or `..other`, but that would be risky: it would increase
             // the odds that we would forget to add some persistent or
@@ -514,6 +713,7 @@ impl Guard {
             GuardRestriction::AvoidAllIds(avoid_ids) => {
                 self.id.0.identities().all(|id| !avoid_ids.contains(id))
             }
+            GuardRestriction::RequireId(require_id) => self.id.0.has_identity(require_id.as_ref()),
         }
     }
 
@@ -556,8 +756,13 @@ impl Guard {
     /// guard, either directly or via an authenticated directory document.
     ///
     /// Additionally, a guard's `orports` or `pt_targets` may change, if the
-    /// `universe` lists a new address for the relay.
-    pub(crate) fn update_from_universe<U: sample::Universe>(&mut self, universe: &U) {
+    /// `universe` lists a new address for the relay.  If the `orports` change,
+    /// we return a [`GuardAddrChange`] describing the change.
+    pub(crate) fn update_from_universe<U: sample::Universe>(
+        &mut self,
+        universe: &U,
+    ) -> Option<GuardAddrChange> {
+        let mut addr_change = None;
         // This is a tricky check, since if we're missing directory information
         // for the guard, we won't know its full set of identities.
         use sample::CandidateStatus::*;
@@ -568,9 +773,17 @@ impl Guard {
                 full_dir_info,
                 owned_target,
                 sensitivity,
+                #[cfg(feature = "geoip")]
+                country,
             }) => {
                 // Update address information.
-                self.orports = owned_target.addrs().into();
+                let new_orports: Vec<SocketAddr> = owned_target.addrs().into();
+                if addrs_differ(&self.orports, &new_orports) {
+                    let old_orports = std::mem::replace(&mut self.orports, new_orports);
+                    addr_change = Some(self.note_addrs_changed(old_orports, universe.timestamp()));
+                } else {
+                    self.orports = new_orports;
+                }
                 // Update Pt information.
                 self.pt_targets = match owned_target.chan_method() {
                     #[cfg(feature = "pt-client")]
@@ -584,6 +797,10 @@ impl Guard {
                 self.id = GuardId(RelayIds::from_relay_ids(&owned_target));
                 self.dir_info_missing = !full_dir_info;
                 self.sensitivity = sensitivity;
+                #[cfg(feature = "geoip")]
+                {
+                    self.country = country;
+                }
 
                 listed_as_guard
             }
@@ -591,7 +808,7 @@ impl Guard {
             Uncertain => {
                 // We can't tell if this is listed without more directory information.
                 self.dir_info_missing = true;
-                return;
+                return None;
             }
         };
 
@@ -602,6 +819,48 @@ impl Guard {
             // Unlisted or not a guard; mark it unlisted.
             self.mark_unlisted(universe.timestamp());
         }
+
+        addr_change
+    }
+
+    /// Record that this guard's addresses have changed from `old_orports` to
+    /// its current `orports`, as of the directory published at `when`.
+    ///
+    /// If we had given up on this guard, we make it retriable, since our
+    /// earlier failures may have been caused by trying the old addresses.
+    fn note_addrs_changed(
+        &mut self,
+        old_orports: Vec<SocketAddr>,
+        when: SystemTime,
+    ) -> GuardAddrChange {
+        let cutoff = when.checked_sub(ADDR_CHANGE_WINDOW);
+        self.addrs_changed_at
+            .retain(|AddrChangeTime(t)| cutoff.map_or(true, |cutoff| *t > cutoff));
+        self.addrs_changed_at.push(AddrChangeTime(when));
+
+        let n_recent_changes = self.addrs_changed_at.len();
+        let suspicious = n_recent_changes >= SUSPICIOUS_ADDR_CHANGES;
+        if suspicious {
+            warn!(
+                guard_id = ?self.id,
+                "Guard {} has changed its address {} times in the last {} days. Its identity keys may have been stolen.",
+                self,
+                n_recent_changes,
+                ADDR_CHANGE_WINDOW.as_secs() / 86400,
+            );
+        } else {
+            info!(guard_id = ?self.id, "Guard {} has changed its address.", self);
+        }
+
+        self.mark_retriable();
+
+        GuardAddrChange {
+            ids: self.id.0.clone(),
+            old_addrs: old_orports,
+            new_addrs: self.orports.clone(),
+            n_recent_changes,
+            suspicious,
+        }
     }
 
     /// Mark this guard as currently listed in the directory.
@@ -661,16 +920,17 @@ impl Guard {
 
     /// Record that a failure has happened for this guard.
     ///
-    /// If `is_primary` is true, this is a primary guard (q.v.).
-    pub(crate) fn record_failure(&mut self, now: Instant, is_primary: bool) {
+    /// If `is_primary` is true, this is a primary guard (q.v.).  We use
+    /// `params` to decide when to retry it.
+    pub(crate) fn record_failure(&mut self, now: Instant, is_primary: bool, params: &RetryParams) {
         self.set_reachable(Reachable::Unreachable);
         self.exploratory_circ_pending = false;
 
-        let mut rng = rand::thread_rng();
+        let mut rng = crate::util::rng();
         let retry_interval = self
             .retry_schedule
-            .get_or_insert_with(|| retry_schedule(is_primary))
-            .next_delay(&mut rng);
+            .get_or_insert_with(GuardBackoff::default)
+            .next_delay(params, is_primary, &mut rng);
 
         // TODO-SPEC: Document this behavior in guard-spec.
         self.retry_at = Some(now + retry_interval);
@@ -683,12 +943,26 @@ impl Guard {
     /// We use this time to decide when to retry failing guards, and
     /// to see if the guard has been "pending" for a long time.
     pub(crate) fn record_attempt(&mut self, connect_attempt: Instant) {
+        self.stats.note_attempt();
+        self.attempts_in_flight += 1;
         self.last_tried_to_connect_at = self
             .last_tried_to_connect_at
             .map(|last| last.max(connect_attempt))
             .or(Some(connect_attempt));
     }
 
+    /// Note that we have learned the outcome of an attempt to use this guard
+    /// that we recorded with [`Self::record_attempt`].
+    pub(crate) fn record_attempt_finished(&mut self) {
+        self.attempts_in_flight = self.attempts_in_flight.saturating_sub(1);
+    }
+
+    /// Return the number of attempts to use this guard whose outcome we
+    /// haven't heard yet.
+    pub(crate) fn attempts_in_flight(&self) -> usize {
+        self.attempts_in_flight
+    }
+
     /// Return true if this guard has an exploratory circuit pending and
     /// if the most recent attempt to connect to it is after `when`.
     ///
@@ -720,7 +994,7 @@ impl Guard {
         if self.confirmed_at.is_none() {
             self.confirmed_at = Some(
                 randomize_time(
-                    &mut rand::thread_rng(),
+                    &mut crate::util::rng(),
                     now,
                     params.lifetime_unconfirmed / 10,
                 )
@@ -790,6 +1064,7 @@ impl Guard {
             inner: crate::FirstHopInner::Chan(tor_linkspec::OwnedChanTarget::from_chan_target(
                 self,
             )),
+            provisional: false,
         }
     }
 
@@ -804,6 +1079,30 @@ impl Guard {
         self.clock_skew.as_ref()
     }
 
+    /// Return a snapshot of what we know about this guard.
+    ///
+    /// `is_primary` says whether this is one of our primary guards, which
+    /// only the [`GuardSet`](crate::sample::GuardSet) knows.
+    pub(crate) fn info(&self, is_primary: bool) -> GuardInfo {
+        GuardInfo {
+            ids: self.id.0.clone(),
+            addrs: self.orports.clone(),
+            is_primary,
+            is_confirmed: self.confirmed_at.is_some(),
+            reachability: self.reachable.into(),
+            dir_info_missing: self.dir_info_missing,
+        }
+    }
+
+    /// As [`Guard::info`], but report our reachability as of `now`, as
+    /// [`Guard::reachable_at`] does.
+    pub(crate) fn info_at(&self, is_primary: bool, now: Instant) -> GuardInfo {
+        GuardInfo {
+            reachability: self.reachable_at(now).into(),
+            ..self.info(is_primary)
+        }
+    }
+
     /// Testing only: Return true if this guard was ever contacted successfully.
     #[cfg(test)]
     pub(crate) fn confirmed(&self) -> bool {
@@ -840,6 +1139,13 @@ impl tor_linkspec::HasChanMethod for Guard {
 
 impl tor_linkspec::ChanTarget for Guard {}
 
+impl crate::filter::FilterTarget for Guard {
+    #[cfg(feature = "geoip")]
+    fn target_country(&self) -> crate::filter::TargetCountry {
+        crate::filter::TargetCountry::Listed(self.country)
+    }
+}
+
 impl std::fmt::Display for Guard {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.sensitivity {
@@ -850,6 +1156,21 @@ impl std::fmt::Display for Guard {
     }
 }
 
+/// Return true if `a` and `b` do not contain the same set of addresses.
+///
+/// We only report a change if we knew some addresses before: a guard that
+/// we created without any addresses hasn't "moved" when we first learn them.
+fn addrs_differ(a: &[SocketAddr], b: &[SocketAddr]) -> bool {
+    if a.is_empty() {
+        return false;
+    }
+    let mut a = a.to_vec();
+    let mut b = b.to_vec();
+    a.sort_unstable();
+    b.sort_unstable();
+    a != b
+}
+
 /// A reason for permanently disabling a guard.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
@@ -865,19 +1186,6 @@ enum GuardDisabled {
     },
 }
 
-/// Return a new RetryDelay tracker for a guard.
-///
-/// `is_primary should be true if the guard is primary.
-fn retry_schedule(is_primary: bool) -> RetryDelay {
-    let minimum = if is_primary {
-        Duration::from_secs(30)
-    } else {
-        Duration::from_secs(150)
-    };
-
-    RetryDelay::from_duration(minimum)
-}
-
 /// The recent history of circuit activity on this guard.
 ///
 /// We keep this information so that we can tell if too many circuits are
@@ -1013,6 +1321,16 @@ mod test {
             vec![ed([99; 32]), ed([100; 32])].into_iter().collect(),
         ));
         let usage6 = usage6.build().unwrap();
+        let mut usage7 = GuardUsageBuilder::new();
+        usage7
+            .restrictions()
+            .push(GuardRestriction::RequireId(ed([13; 32])));
+        let usage7 = usage7.build().unwrap();
+        let mut usage8 = GuardUsageBuilder::new();
+        usage8
+            .restrictions()
+            .push(GuardRestriction::RequireId(ed([22; 32])));
+        let usage8 = usage8.build().unwrap();
 
         assert!(g.conforms_to_usage(&usage1));
         assert!(!g.conforms_to_usage(&usage2));
@@ -1020,6 +1338,8 @@ mod test {
         assert!(!g.conforms_to_usage(&usage4));
         assert!(!g.conforms_to_usage(&usage5));
         assert!(g.conforms_to_usage(&usage6));
+        assert!(g.conforms_to_usage(&usage7));
+        assert!(!g.conforms_to_usage(&usage8));
     }
 
     #[allow(clippy::redundant_clone)]
@@ -1064,6 +1384,12 @@ mod test {
         assert_eq!(g.last_tried_to_connect_at, Some(t3));
         g.record_attempt(t2);
         assert_eq!(g.last_tried_to_connect_at, Some(t3));
+
+        assert_eq!(g.attempts_in_flight(), 3);
+        for _ in 0..4 {
+            g.record_attempt_finished();
+        }
+        assert_eq!(g.attempts_in_flight(), 0);
     }
 
     #[test]
@@ -1072,16 +1398,28 @@ mod test {
         let t2 = Instant::now();
 
         let mut g = basic_guard();
-        g.record_failure(t1, true);
+        g.record_failure(t1, true, &RetryParams::default());
         assert!(g.retry_schedule.is_some());
         assert_eq!(g.reachable(), Reachable::Unreachable);
         let retry1 = g.retry_at.unwrap();
-        assert_eq!(retry1, t1 + Duration::from_secs(30));
+        assert!(retry1 >= t1 + Duration::from_secs(15));
+        assert!(retry1 <= t1 + Duration::from_secs(30));
 
-        g.record_failure(t2, true);
+        // The second delay is about twice as long.
+        g.record_failure(t2, true, &RetryParams::default());
         let retry2 = g.retry_at.unwrap();
         assert!(retry2 >= t2 + Duration::from_secs(30));
-        assert!(retry2 <= t2 + Duration::from_secs(200));
+        assert!(retry2 <= t2 + Duration::from_secs(60));
+
+        // Consensus parameters can change the schedule.
+        let params = RetryParams {
+            primary_initial: Duration::from_secs(5),
+            jitter: 0.0,
+            ..RetryParams::default()
+        };
+        let mut g = basic_guard();
+        g.record_failure(t1, true, &params);
+        assert_eq!(g.retry_at.unwrap(), t1 + Duration::from_secs(5));
     }
 
     #[test]
@@ -1094,7 +1432,7 @@ mod test {
         let t4 = now + Duration::from_secs(320 * 86400);
 
         let mut g = basic_guard();
-        g.record_failure(t1, true);
+        g.record_failure(t1, true, &RetryParams::default());
         assert_eq!(g.reachable(), Reachable::Unreachable);
 
         let conf = g.record_success(t2, &GuardParams::default());
@@ -1105,7 +1443,7 @@ mod test {
         assert!(g.confirmed_at.unwrap() >= t2 - Duration::from_secs(12 * 86400));
         let confirmed_at_orig = g.confirmed_at;
 
-        g.record_failure(t3, true);
+        g.record_failure(t3, true, &RetryParams::default());
         assert_eq!(g.reachable(), Reachable::Unreachable);
 
         let conf = g.record_success(t4, &GuardParams::default());
@@ -1120,7 +1458,7 @@ mod test {
         let t1 = Instant::now();
         let mut g = basic_guard();
 
-        g.record_failure(t1, true);
+        g.record_failure(t1, true, &RetryParams::default());
         assert!(g.retry_at.is_some());
         assert_eq!(g.reachable(), Reachable::Unreachable);
 
@@ -1276,6 +1614,66 @@ mod test {
         assert!(guard23.is_dir_cache);
     }
 
+    #[test]
+    fn addr_changes() {
+        use tor_netdir::testnet;
+        let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+        // Same as above, but [22] has an additional address.
+        let new_addr: SocketAddr = "[2001:db8::22]:9001".parse().unwrap();
+        let netdir2 = testnet::construct_custom_netdir(|idx, node, _| {
+            if idx == 22 {
+                node.rs.add_or_port(new_addr);
+            }
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+
+        let now = SystemTime::now();
+        let mut guard22 = Guard::new(
+            GuardId::new([22; 32].into(), [22; 20].into()),
+            vec![],
+            None,
+            now,
+        );
+
+        // Learning addresses for the first time isn't a change.
+        assert!(guard22.update_from_universe(&netdir).is_none());
+        let old_addrs = guard22.orports.clone();
+        assert!(guard22.update_from_universe(&netdir).is_none());
+        assert!(guard22.addrs_changed_at.is_empty());
+
+        // Make the guard unreachable, then move it.
+        guard22.record_failure(Instant::now(), true, &RetryParams::default());
+        assert_eq!(guard22.reachable(), Reachable::Unreachable);
+        let change = guard22.update_from_universe(&netdir2).unwrap();
+        assert_eq!(change.old_addrs(), &old_addrs[..]);
+        assert!(change.new_addrs().contains(&new_addr));
+        assert_eq!(change.n_recent_changes(), 1);
+        assert!(!change.is_suspicious());
+        assert!(change.relay_ids().same_relay_ids(&guard22));
+        // We'll try it again at its new address.
+        assert_eq!(guard22.reachable(), Reachable::Retriable);
+
+        // The change history is persistent.
+        let json = serde_json::to_string(&guard22).unwrap();
+        let guard22: Guard = serde_json::from_str(&json).unwrap();
+        assert_eq!(guard22.addrs_changed_at.len(), 1);
+
+        // Moving back and forth too often is suspicious.
+        let mut guard22 = guard22;
+        let change = guard22.update_from_universe(&netdir).unwrap();
+        assert!(!change.is_suspicious());
+        let change = guard22.update_from_universe(&netdir2).unwrap();
+        assert_eq!(change.n_recent_changes(), 3);
+        assert!(change.is_suspicious());
+
+        // Old changes are forgotten.
+        let later = netdir.lifetime().valid_after() + ADDR_CHANGE_WINDOW * 2;
+        let change = guard22.note_addrs_changed(old_addrs, later);
+        assert_eq!(change.n_recent_changes(), 1);
+    }
+
     #[test]
     fn pending() {
         let mut g = basic_guard();
@@ -1403,7 +1801,7 @@ mod test {
         assert!(!g.ready_for_usage(&dir_usage, inst));
 
         // Record a circuit failure.
-        g.record_failure(inst + sec * 10, true);
+        g.record_failure(inst + sec * 10, true, &RetryParams::default());
         let next_circ_retry = g.next_retry(&data_usage).unwrap();
         assert!(!g.ready_for_usage(&data_usage, inst + sec * 10));
         assert!(!g.ready_for_usage(&dir_usage, inst + sec * 10));
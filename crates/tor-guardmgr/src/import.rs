@@ -0,0 +1,331 @@
+//! Importing guards from C Tor's state file.
+//!
+//! When a user switches from C Tor to Arti, starting over with a whole new
+//! guard sample would expose them to a new set of guards, which is just what
+//! guards are supposed to prevent.  The functions here read the `Guard` lines
+//! from C Tor's `state` file, and turn them into a [`GuardStateBlob`] that can
+//! be given to [`GuardMgr::import_state`](crate::GuardMgr::import_state).
+//!
+//! C Tor's `Guard` lines look like this:
+//!
+//! ```text
+//! Guard in=default rsa_id=<HEX> nickname=<NAME> sampled_on=<TIME>
+//!   sampled_idx=<N> sampled_by=<VERSION> listed=1 confirmed_on=<TIME>
+//!   confirmed_idx=<N> ...
+//! ```
+//!
+//! (all on one line).  We keep each guard's sample, its confirmation status,
+//! and the times at which it was sampled, confirmed, and last seen unlisted.
+//! We ignore C Tor's path bias statistics, and any guard samples other than
+//! `default`, `restricted`, and `bridges`.
+//!
+//! C Tor doesn't record the addresses of its (non-bridge) guards, or their
+//! Ed25519 identities: we learn those from the next directory that we see.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use fs_mistrust::anon_home::PathExt as _;
+use tor_error::{ErrorKind, HasKind};
+use tor_linkspec::RelayIds;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tracing::debug;
+
+use crate::guard::Guard;
+use crate::ids::GuardId;
+use crate::sample::GuardSet;
+use crate::{GuardSetSelector, GuardSets, GuardStateBlob};
+
+/// An error that occurred while importing guards from C Tor's state file.
+#[derive(Clone, Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum CtorStateError {
+    /// We couldn't read the state file.
+    #[error("Unable to read C Tor state file {}", path.anonymize_home())]
+    Read {
+        /// The file that we tried to read.
+        path: PathBuf,
+        /// The error that we got.
+        #[source]
+        source: Arc<std::io::Error>,
+    },
+
+    /// A `Guard` line in the state file was malformed.
+    #[error("Invalid Guard entry on line {line} of C Tor state file: {problem}")]
+    InvalidGuard {
+        /// The line number of the malformed entry, starting at 1.
+        line: usize,
+        /// What was wrong with it.
+        problem: String,
+    },
+}
+
+impl HasKind for CtorStateError {
+    fn kind(&self) -> ErrorKind {
+        use CtorStateError as E;
+        match self {
+            E::Read { .. } => ErrorKind::PersistentStateAccessFailed,
+            E::InvalidGuard { .. } => ErrorKind::PersistentStateCorrupted,
+        }
+    }
+}
+
+/// Read the C Tor state file at `path`, and return the guards in it.
+pub fn from_ctor_state<P: AsRef<Path>>(path: P) -> Result<GuardStateBlob, CtorStateError> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path).map_err(|e| CtorStateError::Read {
+        path: path.to_owned(),
+        source: Arc::new(e),
+    })?;
+    from_ctor_state_str(&text)
+}
+
+/// Return the guards in `text`, which should have the format of a C Tor
+/// state file.
+pub fn from_ctor_state_str(text: &str) -> Result<GuardStateBlob, CtorStateError> {
+    let mut by_selection: BTreeMap<GuardSetSelector, Vec<CtorGuard<'_>>> = BTreeMap::new();
+    for (idx, line) in text.lines().enumerate() {
+        let Some(args) = line.trim().strip_prefix("Guard ") else {
+            continue;
+        };
+        let invalid = |problem: String| CtorStateError::InvalidGuard {
+            line: idx + 1,
+            problem,
+        };
+        let guard = CtorGuard::parse(args).map_err(invalid)?;
+        let selector = match guard.selection {
+            "default" => GuardSetSelector::Default,
+            "restricted" => GuardSetSelector::Restricted,
+            #[cfg(feature = "bridge-client")]
+            "bridges" => GuardSetSelector::Bridges,
+            other => {
+                debug!("Ignoring guard from unsupported sample {:?}", other);
+                continue;
+            }
+        };
+        by_selection.entry(selector).or_default().push(guard);
+    }
+
+    let mut sets = GuardSets::default();
+    for (selector, guards) in by_selection {
+        *sets.guards_mut(&selector) = guard_set_from(guards);
+    }
+    Ok(GuardStateBlob::new(sets))
+}
+
+/// Build a [`GuardSet`] from the C Tor guards in `guards`, which are in the
+/// order in which they appeared in the state file.
+fn guard_set_from(mut guards: Vec<CtorGuard<'_>>) -> GuardSet {
+    // C Tor writes guards in sample order, but tells us the order explicitly
+    // too; we trust the explicit order, when it's present.  (The sorts are
+    // stable, so guards without an index stay in file order, after the
+    // others.)
+    guards.sort_by_key(|g| g.sampled_idx.unwrap_or(u32::MAX));
+    let mut confirmed: Vec<_> = guards.iter().filter(|g| g.confirmed_on.is_some()).collect();
+    confirmed.sort_by_key(|g| g.confirmed_idx.unwrap_or(u32::MAX));
+    let confirmed = confirmed.into_iter().map(|g| g.id.clone()).collect();
+
+    let guards = guards
+        .into_iter()
+        .map(|g| {
+            Guard::imported(
+                g.id,
+                g.bridge_addr.into_iter().collect(),
+                g.sampled_on,
+                g.confirmed_on,
+                g.unlisted_since,
+            )
+        })
+        .collect();
+    GuardSet::from_guards(guards, confirmed)
+}
+
+/// The information we use from a single `Guard` line in a C Tor state file.
+struct CtorGuard<'a> {
+    /// The name of the sample that this guard is in.
+    selection: &'a str,
+    /// The identity of this guard.
+    id: GuardId,
+    /// The address of this guard, if it is a bridge.
+    bridge_addr: Option<SocketAddr>,
+    /// When this guard was added to the sample.
+    sampled_on: SystemTime,
+    /// The position of this guard in the sample.
+    sampled_idx: Option<u32>,
+    /// When this guard was confirmed, if it was.
+    confirmed_on: Option<SystemTime>,
+    /// The position of this guard in the confirmed list.
+    confirmed_idx: Option<u32>,
+    /// When this guard was first seen to be unlisted, if it is unlisted.
+    unlisted_since: Option<SystemTime>,
+}
+
+impl<'a> CtorGuard<'a> {
+    /// Parse the arguments of a `Guard` line.
+    fn parse(args: &'a str) -> Result<Self, String> {
+        let mut fields = HashMap::new();
+        for item in args.split_ascii_whitespace() {
+            // C Tor ignores (and preserves) any items it doesn't understand,
+            // including ones without an "=".
+            if let Some((k, v)) = item.split_once('=') {
+                fields.insert(k, v);
+            }
+        }
+        let required = |k: &str| {
+            fields
+                .get(k)
+                .copied()
+                .ok_or_else(|| format!("missing {}", k))
+        };
+        let time = |k: &str| -> Result<Option<SystemTime>, String> {
+            fields
+                .get(k)
+                .map(|v| humantime::parse_rfc3339_weak(v).map_err(|e| format!("bad {}: {}", k, e)))
+                .transpose()
+        };
+        let index = |k: &str| -> Result<Option<u32>, String> {
+            fields
+                .get(k)
+                .map(|v| v.parse().map_err(|e| format!("bad {}: {}", k, e)))
+                .transpose()
+        };
+
+        let rsa_id = required("rsa_id")?;
+        let rsa_id =
+            RsaIdentity::from_hex(rsa_id).ok_or_else(|| format!("bad rsa_id: {}", rsa_id))?;
+        let id = GuardId(
+            RelayIds::builder()
+                .rsa_identity(rsa_id)
+                .build()
+                .map_err(|e| format!("bad rsa_id: {}", e))?,
+        );
+        let bridge_addr = fields
+            .get("bridge_addr")
+            .map(|v| v.parse().map_err(|e| format!("bad bridge_addr: {}", e)))
+            .transpose()?;
+
+        Ok(CtorGuard {
+            selection: required("in")?,
+            id,
+            bridge_addr,
+            sampled_on: time("sampled_on")?.ok_or("missing sampled_on")?,
+            sampled_idx: index("sampled_idx")?,
+            confirmed_on: time("confirmed_on")?,
+            confirmed_idx: index("confirmed_idx")?,
+            unlisted_since: time("unlisted_since")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    const STATE: &str = "\
+# Tor state file last generated on 2024-06-01 12:00:00 local time
+# Other times below are in UTC
+# You *do not* need to edit this file.
+
+AccountingBytesReadInInterval 0
+Guard in=default rsa_id=2222222222222222222222222222222222222222 nickname=Two sampled_on=2024-05-02T00:00:00 sampled_idx=1 sampled_by=0.4.8.10 listed=1 confirmed_on=2024-05-04T00:00:00 confirmed_idx=0 pb_use_attempts=3.000000
+Guard in=default rsa_id=1111111111111111111111111111111111111111 nickname=One sampled_on=2024-05-01T00:00:00 sampled_idx=0 sampled_by=0.4.8.10 listed=1 confirmed_on=2024-05-05T00:00:00 confirmed_idx=1
+Guard in=default rsa_id=3333333333333333333333333333333333333333 nickname=Three sampled_on=2024-05-03T00:00:00 sampled_idx=2 sampled_by=0.4.8.10 unlisted_since=2024-05-20T00:00:00 listed=0
+Guard in=restricted rsa_id=4444444444444444444444444444444444444444 nickname=Four sampled_on=2024-05-03T00:00:00 sampled_idx=0 sampled_by=0.4.8.10 listed=1
+Guard in=bridges rsa_id=5555555555555555555555555555555555555555 bridge_addr=192.0.2.5:443 sampled_on=2024-05-03T00:00:00 sampled_idx=0 sampled_by=0.4.8.10 listed=1 confirmed_on=2024-05-03T00:00:00 confirmed_idx=0
+Guard in=custom rsa_id=6666666666666666666666666666666666666666 sampled_on=2024-05-03T00:00:00
+EntryGuard Seven 7777777777777777777777777777777777777777 DirCache
+LastWritten 2024-06-01 12:00:00
+";
+
+    #[test]
+    fn import_samples() {
+        let blob = from_ctor_state_str(STATE).unwrap();
+        let json = serde_json::to_value(&blob).unwrap();
+        let rsa_ids = |list: &serde_json::Value| -> Vec<String> {
+            list.as_array()
+                .unwrap()
+                .iter()
+                .map(|v| v.get("id").unwrap_or(v)["rsa"].as_str().unwrap().to_owned())
+                .collect()
+        };
+        let hex = |c: char| c.to_string().repeat(40);
+
+        // Guards are in sample order, and confirmed guards in confirmed order.
+        let default = &json["samples"]["default"];
+        assert_eq!(
+            rsa_ids(&default["guards"]),
+            vec![hex('1'), hex('2'), hex('3')]
+        );
+        assert_eq!(rsa_ids(&default["confirmed"]), vec![hex('2'), hex('1')]);
+
+        // Timestamps are preserved.
+        let guards = &default["guards"];
+        assert_eq!(guards[0]["added_at"], "2024-05-01T00:00:00Z");
+        assert_eq!(guards[0]["confirmed_at"], "2024-05-05T00:00:00Z");
+        assert_eq!(guards[2]["confirmed_at"], serde_json::Value::Null);
+        assert_eq!(guards[2]["unlisted_since"], "2024-05-20T00:00:00Z");
+
+        // The other samples we know are imported too; other samples, and
+        // old-style entries, are not.
+        let restricted = &json["samples"]["restricted"];
+        assert_eq!(rsa_ids(&restricted["guards"]), vec![hex('4')]);
+        assert!(rsa_ids(&restricted["confirmed"]).is_empty());
+        #[cfg(feature = "bridge-client")]
+        {
+            let bridges = &json["samples"]["bridges"];
+            assert_eq!(rsa_ids(&bridges["guards"]), vec![hex('5')]);
+            assert_eq!(bridges["guards"][0]["orports"][0], "192.0.2.5:443");
+        }
+        assert!(!json.to_string().contains(&hex('6')));
+        assert!(!json.to_string().contains(&hex('7')));
+    }
+
+    #[test]
+    fn import_errors() {
+        let check_err = |text: &str, expect: &str| {
+            let err = from_ctor_state_str(text).unwrap_err();
+            assert!(matches!(err, CtorStateError::InvalidGuard { line: 2, .. }));
+            assert!(err.to_string().contains(expect), "{} vs {}", err, expect);
+        };
+        check_err(
+            "LastWritten 2024-06-01 12:00:00\nGuard in=default sampled_on=2024-05-01T00:00:00",
+            "missing rsa_id",
+        );
+        check_err(
+            "\nGuard in=default rsa_id=12 sampled_on=2024-05-01T00:00:00",
+            "bad rsa_id",
+        );
+        check_err(
+            "\nGuard in=default rsa_id=1111111111111111111111111111111111111111",
+            "missing sampled_on",
+        );
+        check_err(
+            "\nGuard in=default rsa_id=1111111111111111111111111111111111111111 sampled_on=yesterday",
+            "bad sampled_on",
+        );
+
+        let err = from_ctor_state("/this/file/does/not/exist").unwrap_err();
+        assert!(matches!(err, CtorStateError::Read { .. }));
+
+        // A state file with no guards gives us empty samples.
+        let blob = from_ctor_state_str("LastWritten 2024-06-01 12:00:00\n").unwrap();
+        let samples = blob.into_samples();
+        assert_eq!(samples.default.n_stored_guards(), 0);
+    }
+}
@@ -51,16 +51,16 @@
 //     confirmed
 //     filtered
 
-use futures::channel::mpsc;
 use futures::task::SpawnExt;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex, Weak};
 use std::time::{Duration, Instant, SystemTime};
+use tor_async_utils::UnboundedBroadcaster;
 #[cfg(feature = "bridge-client")]
 use tor_error::internal;
-use tor_linkspec::{OwnedChanTarget, OwnedCircTarget, RelayId, RelayIdSet};
+use tor_linkspec::{OwnedChanTarget, OwnedCircTarget, RelayId, RelayIdSet, RelayIds};
 use tor_netdir::NetDirProvider;
 use tor_proto::ClockSkew;
 use tor_units::BoundedInt32;
@@ -71,22 +71,36 @@ use tor_config::{impl_not_auto_value, ReconfigureError};
 use tor_config::{impl_standard_builder, ExplicitOrAuto};
 use tor_netdir::{params::NetParameters, NetDir, Relay};
 use tor_persist::{DynStorageHandle, StateMgr};
-use tor_rtcompat::Runtime;
+use tor_rtcompat::scheduler::{TaskHandle, TaskSchedule};
+use tor_rtcompat::{DynTimeProvider, Runtime, SleepProvider};
 
+mod anchor;
+mod backoff;
+mod blame;
 #[cfg(feature = "bridge-client")]
 pub mod bridge;
 mod config;
+mod context;
 mod daemon;
 mod dirstatus;
 mod err;
 mod events;
+mod export;
 pub mod fallback;
 mod filter;
 mod guard;
 mod ids;
+pub mod import;
+mod observer;
 mod pending;
+mod preview;
+mod probe;
+mod reachability;
 mod sample;
 mod skew;
+mod stats;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 mod util;
 #[cfg(feature = "vanguards")]
 pub mod vanguards;
@@ -98,23 +112,44 @@ pub mod bridge;
 #[cfg(any(test, feature = "testing"))]
 pub use config::testing::TestConfig;
 
-#[cfg(test)]
+#[cfg(any(test, feature = "testing"))]
 use oneshot_fused_workaround as oneshot;
 
-pub use config::GuardMgrConfig;
+pub use anchor::{DirectoryAnchor, DirectoryPairing};
+pub use blame::{CircuitFailureCause, FailureBlame};
+pub use config::{
+    GuardConfig, GuardConfigBuilder, GuardMgrConfig, GuardParamOverrides,
+    GuardParamOverridesBuilder, SamplePrunePolicy, SamplePrunePolicyBuilder,
+};
+#[cfg(feature = "geoip")]
+pub use config::{GuardCountryRestrictions, GuardCountryRestrictionsBuilder};
+pub use context::GuardContextId;
+pub use daemon::StatusQueueStats;
 pub use err::{GuardMgrConfigError, GuardMgrError, PickGuardError};
-pub use events::ClockSkewEvents;
+pub use events::{
+    ClockSkewEvents, GuardAddrChange, GuardAddrChangeEvents, GuardIdDisclosure, PrimaryGuardChange,
+    PrimaryGuardEvents, UnreachableAddrEvents, UnreachableAddrInference,
+};
+pub use export::GuardStateBlob;
 pub use filter::GuardFilter;
+pub use guard::{GuardInfo, GuardReachability};
 pub use ids::FirstHopId;
 pub use pending::{GuardMonitor, GuardStatus, GuardUsable};
-pub use skew::SkewEstimate;
-
+pub use preview::{CandidateGuard, GuardExclusion};
+pub use probe::GuardProbe;
+pub use reachability::{ReachabilityInference, ReachabilityInferenceBuilder};
+pub use skew::{SkewEstimate, SkewObservation};
+pub use stats::{GuardStatsEntry, SampleWeightFraction};
+
+#[cfg(feature = "metrics")]
+#[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+pub use observer::{GuardCounts, GuardEvent, GuardEventKind, GuardObserver};
 #[cfg(feature = "vanguards")]
 #[cfg_attr(docsrs, doc(cfg(feature = "vanguards")))]
 pub use vanguards::VanguardMgrError;
 
 use pending::{PendingRequest, RequestId};
-use sample::{GuardSet, Universe, UniverseRef};
+use sample::{GuardSet, SampleMeta, Universe, UniverseRef};
 
 use crate::ids::{FirstHopIdInner, GuardId};
 
@@ -141,6 +176,13 @@ pub struct GuardMgr<R: Runtime> {
 /// This would just be a [`GuardMgr`], except that it needs to sit inside
 /// a `Mutex` and get accessed by daemon tasks.
 struct GuardMgrInner {
+    /// The source of the current time for every decision that we make.
+    ///
+    /// This is our runtime, unless somebody has replaced it with
+    /// [`GuardMgr::install_time_provider`].  (We still use the runtime to
+    /// decide when our background tasks wake up.)
+    time: DynTimeProvider,
+
     /// Last time when marked all of our primary guards as retriable.
     ///
     /// We keep track of this time so that we can rate-limit
@@ -160,7 +202,41 @@ struct GuardMgrInner {
     // should fix that.
     filter: GuardFilter,
 
-    /// Configuration values derived from the consensus parameters.
+    /// Restrictions from our configuration on the countries where our guards
+    /// may be located.
+    ///
+    /// We apply these in addition to `filter`, except when we are using
+    /// bridges.
+    #[cfg(feature = "geoip")]
+    country_restrictions: GuardCountryRestrictions,
+
+    /// Our policy for discarding stored guard samples that we don't recognize.
+    sample_prune_policy: SamplePrunePolicy,
+
+    /// Whether we should infer which guard addresses we can reach.
+    reachability_inference: ReachabilityInference,
+
+    /// The outcomes of our guard connection attempts, by port and address
+    /// family, and the ones we have decided we can't reach.
+    ///
+    /// We exclude those in addition to `filter`.
+    reachability: reachability::ReachabilityTracker,
+
+    /// Guard parameters from our configuration, to use in place of the
+    /// consensus parameters, as they were configured.
+    ///
+    /// We keep these so that we can tell when they change, without warning
+    /// about them again.
+    configured_param_overrides: GuardParamOverrides,
+
+    /// Guard parameters from our configuration, to use in place of the
+    /// consensus parameters.
+    ///
+    /// These have already been sanitized.
+    param_overrides: GuardParamOverrides,
+
+    /// Configuration values derived from the consensus parameters, and from
+    /// `param_overrides`.
     ///
     /// This is updated whenever the consensus parameters change.
     params: GuardParams,
@@ -173,8 +249,9 @@ struct GuardMgrInner {
     /// API to be simpler.  The risk, however, is that there's no
     /// backpressure in the event that the task running
     /// [`daemon::report_status_events`] fails to read from this
-    /// channel.
-    ctrl: mpsc::UnboundedSender<daemon::Msg>,
+    /// channel.  (We do keep track of how many messages are waiting: see
+    /// [`GuardMgr::status_queue_stats`].)
+    ctrl: daemon::MsgSender,
 
     /// Information about guards that we've given out, but where we have
     /// not yet heard whether the guard was successful.
@@ -199,9 +276,54 @@ struct GuardMgrInner {
     /// when no other directory information is yet known.
     fallbacks: fallback::FallbackState,
 
+    /// The fallback directories from our configuration.
+    ///
+    /// Our `fallbacks` are these, plus the usable ones from
+    /// `learned_fallbacks`.
+    configured_fallbacks: fallback::FallbackList,
+
+    /// The fallback directories that we have learned from the consensus.
+    ///
+    /// We only keep the ones that we learned with our current
+    /// `configured_fallbacks`.
+    learned_fallbacks: fallback::LearnedFallbacks,
+
+    /// If true, we learn fallback directories from the consensus, and use
+    /// them.
+    learn_fallbacks: bool,
+
+    /// Location in which to store our learned fallback directories.
+    learned_fallbacks_storage: DynStorageHandle<fallback::LearnedFallbacks>,
+
+    /// A list of directory authorities that we contact directly, instead of
+    /// the fallback directories, when `authorities_only` is set.
+    ///
+    /// We track the status of these authorities separately from that of our
+    /// fallbacks, even if some relay appears in both lists.
+    authorities: fallback::FallbackState,
+
+    /// If true, we never use our fallback directories: when we have no guard
+    /// information to use, we contact the directory authorities instead.
+    authorities_only: bool,
+
     /// Location in which to store persistent state.
     storage: DynStorageHandle<GuardSets>,
 
+    /// Our named guard contexts, other than the default one.
+    ///
+    /// See [`GuardMgr::add_context`].
+    contexts: HashMap<GuardContextId, context::GuardContext>,
+
+    /// The context whose guards are currently in `guards`, or `None` for the
+    /// default context.
+    ///
+    /// This is only ever `Some` inside [`GuardMgrInner::with_context`].
+    active_context: Option<GuardContextId>,
+
+    /// A function to make a storage handle for a given key, using our state
+    /// manager.
+    create_storage: Box<dyn Fn(String) -> DynStorageHandle<GuardSets> + Send>,
+
     /// A sender object to publish changes in our estimated clock skew.
     send_skew: postage::watch::Sender<Option<SkewEstimate>>,
 
@@ -209,6 +331,30 @@ struct GuardMgrInner {
     /// changes in our estimated clock skew.
     recv_skew: events::ClockSkewEvents,
 
+    /// The clock skew observations we've made most recently, oldest first.
+    ///
+    /// We keep no more than [`SKEW_HISTORY_LEN`] of these.
+    skew_history: VecDeque<skew::SkewObservation>,
+
+    /// Senders for everybody who wants to know when one of our guards changes
+    /// its addresses.
+    send_addr_changes: UnboundedBroadcaster<GuardAddrChange>,
+
+    /// Senders for everybody who wants to know when we decide that we can't
+    /// reach guards on some port and address family.
+    send_unreachable_addrs: UnboundedBroadcaster<UnreachableAddrInference>,
+
+    /// Senders for everybody who wants to know when our most-preferred primary
+    /// guard changes, along with how much each of them may learn about it.
+    send_primary_changes: UnboundedBroadcaster<PrimaryGuardChange, GuardIdDisclosure>,
+
+    /// The most-preferred primary guard of our active set, as of the last time
+    /// we checked.
+    last_primary_guard: Option<GuardId>,
+
+    /// The number of times that our most-preferred primary guard has changed.
+    primary_guard_epoch: u64,
+
     /// A netdir provider that we can use for adding new guards when
     /// insufficient guards are available.
     ///
@@ -216,6 +362,13 @@ struct GuardMgrInner {
     /// time a GuardMgr is created, there is no NetDirProvider for it to use.
     netdir_provider: Option<Weak<dyn NetDirProvider>>,
 
+    /// If we are recording our inputs for later replay, the recorder that we
+    /// are using.
+    ///
+    /// See [`GuardMgr::start_recording`].
+    #[cfg(any(test, feature = "testing"))]
+    recorder: Option<testing::replay::Recorder>,
+
     /// A netdir provider that we can use for discovering bridge descriptors.
     ///
     /// This has to be an Option so it can be initialized from None: at the time
@@ -258,6 +411,16 @@ enum UniverseType {
 }
 
 impl GuardSetSelector {
+    /// Return the name under which we store the guard set for this selector.
+    fn storage_name(&self) -> &'static str {
+        match self {
+            GuardSetSelector::Default => "default",
+            GuardSetSelector::Restricted => "restricted",
+            #[cfg(feature = "bridge-client")]
+            GuardSetSelector::Bridges => "bridges",
+        }
+    }
+
     /// Return a description of which [`Universe`] this guard sample should take
     /// its guards from.
     fn universe_type(&self) -> UniverseType {
@@ -291,11 +454,63 @@ struct GuardSets {
     #[cfg(feature = "bridge-client")]
     bridges: GuardSet,
 
+    /// The directory that this state was last saved with, if we know it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dir_anchor: Option<DirectoryAnchor>,
+
     /// Unrecognized fields, including (possibly) other guard sets.
     #[serde(flatten)]
     remaining: HashMap<String, tor_persist::JsonValue>,
 }
 
+/// Information about one guard sample in our persistent state.
+///
+/// Returned by [`GuardMgr::stored_samples`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct StoredSampleInfo {
+    /// The name under which this sample is stored.
+    pub name: String,
+    /// True if this version of Arti knows how to use this sample.
+    ///
+    /// Samples that we don't recognize were written by another version or
+    /// configuration of Arti.  We may discard them, according to our
+    /// [`SamplePrunePolicy`].
+    pub recognized: bool,
+    /// The format version with which this sample is stored.
+    ///
+    /// This is 0 for samples written before we recorded versions.  Samples
+    /// that we recognize are always written with our current version.
+    pub version: u32,
+    /// The last time at which this sample was in use, if we know it.
+    pub last_used: Option<SystemTime>,
+    /// The number of guards in this sample.
+    pub n_guards: usize,
+}
+
+/// A guard sample from our state file that this version of Arti does not
+/// recognize.
+///
+/// We only parse as much of it as we need to describe it and decide whether
+/// to discard it.
+#[derive(Debug, Deserialize)]
+struct UnrecognizedSample {
+    /// The guards in this sample.
+    guards: Vec<tor_persist::JsonValue>,
+    /// Versioning information for this sample.
+    #[serde(default)]
+    meta: SampleMeta,
+}
+
+impl UnrecognizedSample {
+    /// Try to interpret `value` as a guard sample.
+    ///
+    /// Return `None` if it is not one.
+    fn from_json(value: &tor_persist::JsonValue) -> Option<Self> {
+        Self::deserialize(value).ok()
+    }
+}
+
 /// The key (filename) we use for storing our persistent guard state in the
 /// `StateMgr`.
 ///
@@ -303,16 +518,27 @@ struct GuardSets {
 /// "default_guards" (before Arti 0.1.0).
 const STORAGE_KEY: &str = "guards";
 
+/// The key (filename) we use for storing the fallback directories that we
+/// have learned from the consensus.
+const LEARNED_FALLBACKS_KEY: &str = "learned_fallbacks";
+
+/// The largest number of clock skew observations that we remember for
+/// [`GuardMgr::skew_history`].
+const SKEW_HISTORY_LEN: usize = 128;
+
 /// A description of which circuits to retire because of a configuration change.
-///
-/// TODO(nickm): Eventually we will want to add a "Some" here, to support
-/// removing only those circuits that correspond to no-longer-usable guards.
 #[derive(Clone, Debug, Eq, PartialEq)]
 #[must_use]
 #[non_exhaustive]
 pub enum RetireCircuits {
     /// There's no need to retire any circuits.
     None,
+    /// Only the circuits whose first hop has any of these identities should
+    /// be retired.
+    ///
+    /// We return this when we are using bridges before and after the change,
+    /// but some of our bridges are no longer configured.
+    Some(Vec<RelayIds>),
     /// All circuits should be retired.
     All,
 }
@@ -328,50 +554,91 @@ impl<R: Runtime> GuardMgr<R> {
         config: &impl GuardMgrConfig,
     ) -> Result<Self, GuardMgrError>
     where
-        S: StateMgr + Send + Sync + 'static,
+        S: StateMgr + Clone + Send + Sync + 'static,
     {
-        let (ctrl, rcv) = mpsc::unbounded();
-        let storage: DynStorageHandle<GuardSets> = state_mgr.create_handle(STORAGE_KEY);
+        let (ctrl, rcv) = daemon::MsgSender::new();
+        let counters = Arc::clone(ctrl.counters());
+        let storage: DynStorageHandle<GuardSets> = state_mgr.clone().create_handle(STORAGE_KEY);
+        let learned_fallbacks_storage: DynStorageHandle<fallback::LearnedFallbacks> =
+            state_mgr.clone().create_handle(LEARNED_FALLBACKS_KEY);
+        let create_storage = Box::new(move |key| state_mgr.clone().create_handle(key));
         // TODO(nickm): We should do something about the old state in
         // `default_guards`.  Probably it would be best to delete it.  We could
         // try to migrate it instead, but that's beyond the stability guarantee
         // that we're getting at this stage of our (pre-0.1) development.
         let state = storage.load()?.unwrap_or_default();
+        let learned_fallbacks = learned_fallbacks_storage.load()?.unwrap_or_default();
 
         let (send_skew, recv_skew) = postage::watch::channel();
         let recv_skew = ClockSkewEvents { inner: recv_skew };
 
+        let configured_param_overrides = config.guard_param_overrides();
+        let param_overrides = configured_param_overrides.sanitized();
+        let mut params = GuardParams::default();
+        params.apply_overrides(&param_overrides);
+
+        let time = DynTimeProvider::new(runtime.clone());
         let inner = Arc::new(Mutex::new(GuardMgrInner {
+            last_primary_retry_time: time.now(),
+            time,
             guards: state,
             filter: GuardFilter::unfiltered(),
-            last_primary_retry_time: runtime.now(),
-            params: GuardParams::default(),
+            #[cfg(feature = "geoip")]
+            country_restrictions: config.guard_country_restrictions(),
+            sample_prune_policy: config.guard_sample_prune_policy(),
+            reachability_inference: config.reachability_inference(),
+            reachability: reachability::ReachabilityTracker::default(),
+            configured_param_overrides,
+            param_overrides,
+            params,
             ctrl,
             pending: HashMap::new(),
             waiting: Vec::new(),
             fallbacks: config.fallbacks().into(),
+            configured_fallbacks: config.fallbacks().clone(),
+            learned_fallbacks,
+            learned_fallbacks_storage,
+            learn_fallbacks: config.learn_fallbacks(),
+            authorities: config.authority_dirs().into(),
+            authorities_only: false,
             storage,
+            contexts: HashMap::new(),
+            active_context: None,
+            create_storage,
             send_skew,
             recv_skew,
+            skew_history: VecDeque::new(),
+            send_addr_changes: UnboundedBroadcaster::new(),
+            send_unreachable_addrs: UnboundedBroadcaster::new(),
+            send_primary_changes: UnboundedBroadcaster::new(),
+            last_primary_guard: None,
+            primary_guard_epoch: 0,
             netdir_provider: None,
+            #[cfg(any(test, feature = "testing"))]
+            recorder: None,
             #[cfg(feature = "bridge-client")]
             bridge_desc_provider: None,
             #[cfg(feature = "bridge-client")]
             configured_bridges: None,
         }));
+        {
+            let mut inner = inner.lock().expect("lock poisoned");
+            inner.set_authorities_only(config.authorities_only_bootstrap());
+            let (wallclock, _) = inner.current_time();
+            inner.rebuild_fallbacks(wallclock);
+        }
         #[cfg(feature = "bridge-client")]
         {
             let mut inner = inner.lock().expect("lock poisoned");
             // TODO(nickm): This calls `GuardMgrInner::update`. Will we mind doing so before any
             // providers are configured? I think not, but we should make sure.
-            let _: RetireCircuits =
-                inner.replace_bridge_config(config, runtime.wallclock(), runtime.now())?;
+            let (wallclock, now) = inner.current_time();
+            let _: RetireCircuits = inner.replace_bridge_config(config, wallclock, now)?;
         }
         {
             let weak_inner = Arc::downgrade(&inner);
-            let rt_clone = runtime.clone();
             runtime
-                .spawn(daemon::report_status_events(rt_clone, weak_inner, rcv))
+                .spawn(daemon::report_status_events(weak_inner, counters, rcv))
                 .map_err(|e| GuardMgrError::from_spawn("guard status event reporter", e))?;
         }
         {
@@ -384,6 +651,24 @@ impl<R: Runtime> GuardMgr<R> {
         Ok(GuardMgr { runtime, inner })
     }
 
+    /// Replace the clock that this guard manager uses for all of its
+    /// decisions: when guards expire, when to retry them, how long our
+    /// attempts to use them took, and so on.
+    ///
+    /// By default, we use our runtime's clock.  Tests can install a virtual
+    /// clock here, and embedders can install a clock that they control.
+    ///
+    /// Install the new clock before using this guard manager, if you can:
+    /// we don't convert any times that we have already recorded.  Our
+    /// background tasks still use the runtime to decide when to wake up.
+    pub fn install_time_provider(&self, time: DynTimeProvider) {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        inner.last_primary_retry_time = time.now();
+        inner.time = time;
+        let (wallclock, now) = inner.current_time();
+        inner.for_each_context(|this| this.update(wallclock, now));
+    }
+
     /// Install a [`NetDirProvider`] for use by this guard manager.
     ///
     /// It will be used to keep the guards up-to-date with changes from the
@@ -412,13 +697,8 @@ impl<R: Runtime> GuardMgr<R> {
             inner.netdir_provider = Some(weak_provider.clone());
         }
         let weak_inner = Arc::downgrade(&self.inner);
-        let rt_clone = self.runtime.clone();
         self.runtime
-            .spawn(daemon::keep_netdir_updated(
-                rt_clone,
-                weak_inner,
-                weak_provider,
-            ))
+            .spawn(daemon::keep_netdir_updated(weak_inner, weak_provider))
             .map_err(|e| GuardMgrError::from_spawn("periodic guard netdir updater", e))?;
         Ok(())
     }
@@ -447,13 +727,8 @@ impl<R: Runtime> GuardMgr<R> {
         }
 
         let weak_inner = Arc::downgrade(&self.inner);
-        let rt_clone = self.runtime.clone();
         self.runtime
-            .spawn(daemon::keep_bridge_descs_updated(
-                rt_clone,
-                weak_inner,
-                weak_provider,
-            ))
+            .spawn(daemon::keep_bridge_descs_updated(weak_inner, weak_provider))
             .map_err(|e| GuardMgrError::from_spawn("periodic guard netdir updater", e))?;
 
         Ok(())
@@ -465,6 +740,101 @@ impl<R: Runtime> GuardMgr<R> {
         let inner = self.inner.lock().expect("Poisoned lock");
         trace!("Flushing guard state to disk.");
         inner.storage.store(&inner.guards)?;
+        for context in inner.contexts.values() {
+            context.storage.store(&context.guards)?;
+        }
+        inner
+            .learned_fallbacks_storage
+            .store(&inner.learned_fallbacks)?;
+        Ok(())
+    }
+
+    /// Record that our guard state goes with the directory described by
+    /// `anchor`, or that we don't know which directory it goes with.
+    ///
+    /// The anchor is saved with the rest of our state, the next time we call
+    /// [`store_persistent_state`](GuardMgr::store_persistent_state).  A
+    /// directory manager should set it at shutdown, right before storing our
+    /// state, so that after a restart it can use
+    /// [`check_directory_pairing`](GuardMgr::check_directory_pairing) to tell
+    /// whether the restored directory matches the restored guards.
+    pub fn set_directory_anchor(&self, anchor: Option<DirectoryAnchor>) {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        inner.guards.dir_anchor = anchor;
+    }
+
+    /// Return the directory that our guard state goes with, if we know it.
+    pub fn directory_anchor(&self) -> Option<DirectoryAnchor> {
+        let inner = self.inner.lock().expect("Poisoned lock");
+        inner.guards.dir_anchor.clone()
+    }
+
+    /// Check whether `netdir`, whose consensus has the SHA3-256 digest
+    /// `consensus_digest`, is consistent with our guard state.
+    ///
+    /// We compare the directory with our [directory
+    /// anchor](GuardMgr::directory_anchor), and make sure that it lists all of
+    /// our primary guards.  If we are using bridges, we don't take our guards
+    /// from the directory, so we only compare it with the anchor.
+    pub fn check_directory_pairing(
+        &self,
+        netdir: &NetDir,
+        consensus_digest: &[u8; 32],
+    ) -> DirectoryPairing {
+        let inner = self.inner.lock().expect("Poisoned lock");
+        let n_missing = if inner.guards.active_set.universe_type() == UniverseType::NetDir {
+            inner.guards.active_guards().n_primary_not_listed_in(netdir)
+        } else {
+            0
+        };
+
+        if n_missing > 0 {
+            DirectoryPairing::MissingPrimaryGuards { n_missing }
+        } else {
+            match &inner.guards.dir_anchor {
+                None => DirectoryPairing::Unanchored,
+                Some(a) if a.consensus_digest() == consensus_digest => DirectoryPairing::Consistent,
+                Some(a) => DirectoryPairing::Changed { saved: a.clone() },
+            }
+        }
+    }
+
+    /// Return a description of every guard sample in our persistent state,
+    /// including samples written by other versions or configurations of Arti
+    /// that we don't recognize.
+    pub fn stored_samples(&self) -> Vec<StoredSampleInfo> {
+        let inner = self.inner.lock().expect("Poisoned lock");
+        inner.guards.stored_samples()
+    }
+
+    /// Return a portable copy of all of our guard samples, for use with
+    /// [`import_state`](GuardMgr::import_state) on another guard manager.
+    pub fn export_state(&self) -> GuardStateBlob {
+        let inner = self.inner.lock().expect("Poisoned lock");
+        GuardStateBlob::new(inner.guards.clone())
+    }
+
+    /// Replace all of our guard samples with those in `blob`, and save them
+    /// to the state manager.
+    ///
+    /// Any status that we have for guards in both our old samples and the new
+    /// ones is retained.  Circuits that we have already built are unaffected.
+    ///
+    /// Requires that we hold the lock on the state files: otherwise, our
+    /// next reload would discard the imported guards.  On error, our guard
+    /// samples are unchanged.
+    pub fn import_state(&self, blob: GuardStateBlob) -> Result<(), GuardMgrError> {
+        if !blob.is_supported() {
+            return Err(GuardMgrError::UnsupportedStateVersion {
+                version: blob.version(),
+            });
+        }
+        let new_guards = blob.into_samples();
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        inner.storage.store(&new_guards)?;
+        info!("Replaced our guard samples with imported ones.");
+        let (wallclock, now) = inner.current_time();
+        inner.replace_guards_with(new_guards, wallclock, now);
         Ok(())
     }
 
@@ -474,10 +844,15 @@ impl<R: Runtime> GuardMgr<R> {
     /// files.  If we have the lock, we only want to save.
     pub fn reload_persistent_state(&self) -> Result<(), GuardMgrError> {
         let mut inner = self.inner.lock().expect("Poisoned lock");
+        let (wallclock, now) = inner.current_time();
         if let Some(new_guards) = inner.storage.load()? {
-            inner.replace_guards_with(new_guards, self.runtime.wallclock(), self.runtime.now());
+            inner.replace_guards_with(new_guards, wallclock, now);
         }
-        Ok(())
+        if let Some(learned) = inner.learned_fallbacks_storage.load()? {
+            inner.learned_fallbacks = learned;
+            inner.rebuild_fallbacks(wallclock);
+        }
+        inner.reload_contexts(wallclock, now)
     }
 
     /// Switch from having an unowned persistent state to having an owned one.
@@ -487,10 +862,11 @@ impl<R: Runtime> GuardMgr<R> {
         let mut inner = self.inner.lock().expect("Poisoned lock");
         debug_assert!(inner.storage.can_store());
         let new_guards = inner.storage.load()?.unwrap_or_default();
-        let wallclock = self.runtime.wallclock();
-        let now = self.runtime.now();
+        let (wallclock, now) = inner.current_time();
         inner.replace_guards_with(new_guards, wallclock, now);
-        Ok(())
+        inner.learned_fallbacks = inner.learned_fallbacks_storage.load()?.unwrap_or_default();
+        inner.rebuild_fallbacks(wallclock);
+        inner.reload_contexts(wallclock, now)
     }
 
     /// Return true if `netdir` has enough information to safely become our new netdir.
@@ -514,6 +890,37 @@ impl<R: Runtime> GuardMgr<R> {
         inner.guards.active_guards_mut().mark_all_guards_retriable();
     }
 
+    /// Install `prober` to actively check whether our unreachable primary
+    /// guards have become reachable.
+    ///
+    /// Every so often, we ask `prober` about each of our primary guards that
+    /// we currently believe to be unreachable.  If it reports that it can
+    /// reach one, we make that guard retriable right away, instead of waiting
+    /// for its retry timer.
+    ///
+    /// Returns a [`TaskHandle`] for the probing task.  Callers should
+    /// [`suspend`](TaskHandle::suspend) it while we're dormant, so that we
+    /// don't keep waking up to probe, and [`resume`](TaskHandle::resume) it
+    /// afterwards.
+    ///
+    /// We only keep a weak reference to `prober`: we stop probing once it, or
+    /// the returned handle, is dropped.
+    pub fn install_prober(
+        &self,
+        prober: &Arc<dyn GuardProbe>,
+    ) -> Result<TaskHandle, GuardMgrError> {
+        let weak_inner = Arc::downgrade(&self.inner);
+        let (sched, handle) = TaskSchedule::new(self.runtime.clone());
+        self.runtime
+            .spawn(daemon::probe_unreachable_guards(
+                sched,
+                weak_inner,
+                Arc::downgrade(prober),
+            ))
+            .map_err(|e| GuardMgrError::from_spawn("guard reachability prober", e))?;
+        Ok(handle)
+    }
+
     /// Configure this guardmgr to use a fixed [`NetDir`] instead of a provider.
     ///
     /// This function is for testing only, and is exclusive with
@@ -525,15 +932,15 @@ impl<R: Runtime> GuardMgr<R> {
     #[cfg(any(test, feature = "testing"))]
     pub fn install_test_netdir(&self, netdir: &NetDir) {
         use tor_netdir::testprovider::TestNetDirProvider;
-        let wallclock = self.runtime.wallclock();
-        let now = self.runtime.now();
         let netdir_provider: Arc<dyn NetDirProvider> =
             Arc::new(TestNetDirProvider::from(netdir.clone()));
         self.install_netdir_provider(&netdir_provider)
             .expect("Couldn't install testing network provider");
 
         let mut inner = self.inner.lock().expect("Poisoned lock");
-        inner.update(wallclock, now);
+        let (wallclock, now) = inner.current_time();
+        inner.for_each_context(|inner| inner.update(wallclock, now));
+        inner.update_learned_fallbacks(wallclock);
     }
 
     /// Replace the configuration in this `GuardMgr` with `config`.
@@ -544,15 +951,67 @@ impl<R: Runtime> GuardMgr<R> {
         let mut inner = self.inner.lock().expect("Poisoned lock");
         // Change the set of configured fallbacks.
         {
-            let mut fallbacks: fallback::FallbackState = config.fallbacks().into();
-            std::mem::swap(&mut inner.fallbacks, &mut fallbacks);
-            inner.fallbacks.take_status_from(fallbacks);
+            inner.configured_fallbacks = config.fallbacks().clone();
+            inner.learn_fallbacks = config.learn_fallbacks();
+            let (wallclock, _) = inner.current_time();
+            inner.rebuild_fallbacks(wallclock);
+
+            let mut authorities: fallback::FallbackState = config.authority_dirs().into();
+            std::mem::swap(&mut inner.authorities, &mut authorities);
+            inner.authorities.take_status_from(authorities);
+        }
+        inner.set_authorities_only(config.authorities_only_bootstrap());
+        // Change the country restrictions on our guards.
+        #[cfg(feature = "geoip")]
+        {
+            let country_restrictions = config.guard_country_restrictions();
+            if country_restrictions != inner.country_restrictions {
+                inner.country_restrictions = country_restrictions;
+                let (wallclock, now) = inner.current_time();
+                inner.for_each_context(|this| this.update(wallclock, now));
+            }
+        }
+        // Change the policy for discarding unrecognized guard samples.
+        {
+            let sample_prune_policy = config.guard_sample_prune_policy();
+            if sample_prune_policy != inner.sample_prune_policy {
+                inner.sample_prune_policy = sample_prune_policy;
+                let (wallclock, now) = inner.current_time();
+                inner.for_each_context(|this| this.update(wallclock, now));
+            }
+        }
+        // Change the guard parameters that we override.
+        {
+            let param_overrides = config.guard_param_overrides();
+            if param_overrides != inner.configured_param_overrides {
+                inner.param_overrides = param_overrides.sanitized();
+                inner.configured_param_overrides = param_overrides;
+                // If we have a directory, `update` will replace these with its
+                // parameters, and then apply our overrides.
+                let mut params = GuardParams::default();
+                params.apply_overrides(&inner.param_overrides);
+                inner.params = params;
+                let (wallclock, now) = inner.current_time();
+                inner.for_each_context(|this| this.update(wallclock, now));
+            }
+        }
+        // Change whether we infer which addresses we can reach.  If we stop
+        // inferring, we forget our inferences.
+        {
+            let reachability_inference = config.reachability_inference();
+            if reachability_inference != inner.reachability_inference {
+                if !reachability_inference.enabled {
+                    inner.reachability.clear();
+                }
+                inner.reachability_inference = reachability_inference;
+                let (wallclock, now) = inner.current_time();
+                inner.for_each_context(|this| this.update(wallclock, now));
+            }
         }
         // If we are built to use bridges, change the bridge configuration.
         #[cfg(feature = "bridge-client")]
         {
-            let wallclock = self.runtime.wallclock();
-            let now = self.runtime.now();
+            let (wallclock, now) = inner.current_time();
             Ok(inner.replace_bridge_config(config, wallclock, now)?)
         }
         // If we are built to use bridges, change the bridge configuration.
@@ -565,9 +1024,8 @@ impl<R: Runtime> GuardMgr<R> {
     /// Replace the current [`GuardFilter`] used by this `GuardMgr`.
     // TODO should this be part of the config?
     pub fn set_filter(&self, filter: GuardFilter) {
-        let wallclock = self.runtime.wallclock();
-        let now = self.runtime.now();
         let mut inner = self.inner.lock().expect("Poisoned lock");
+        let (wallclock, now) = inner.current_time();
         inner.set_filter(filter, wallclock, now);
     }
 
@@ -588,68 +1046,189 @@ impl<R: Runtime> GuardMgr<R> {
         &self,
         usage: GuardUsage,
     ) -> Result<(FirstHop, GuardMonitor, GuardUsable), PickGuardError> {
-        let now = self.runtime.now();
-        let wallclock = self.runtime.wallclock();
-
         let mut inner = self.inner.lock().expect("Poisoned lock");
+        match usage.context.clone() {
+            None => inner.select_guard(usage),
+            Some(id) => inner
+                .with_context(Some(&id), |inner| inner.select_guard(usage))
+                .unwrap_or(Err(PickGuardError::UnknownContext(id))),
+        }
+    }
 
-        // (I am not 100% sure that we need to consider_all_retries here, but
-        // it should _probably_ not hurt.)
-        inner.guards.active_guards_mut().consider_all_retries(now);
+    /// Report which guards [`select_guard`](GuardMgr::select_guard) would
+    /// currently consider for `usage`, without selecting one.
+    ///
+    /// We return every guard in our active sample, in the order that we
+    /// prefer them.  `select_guard` would pick at random among the guards
+    /// that are [eligible](CandidateGuard::is_eligible); for each of the
+    /// others, we say why it would be passed over.
+    ///
+    /// This function doesn't record an attempt to use any guard.  Apart from
+    /// bringing our primary guards up to date (as `select_guard` would), it
+    /// doesn't change our state.  In particular, when no guard is eligible,
+    /// `select_guard` might extend our sample or use a fallback directory;
+    /// this function doesn't do either, and doesn't report what they would
+    /// give.
+    ///
+    /// Returns an empty list if `usage` names a guard context that we don't
+    /// have.
+    pub fn preview_selection(&self, usage: &GuardUsage) -> Vec<CandidateGuard> {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        match &usage.context {
+            None => inner.preview_selection(usage),
+            Some(id) => inner
+                .with_context(Some(id), |inner| inner.preview_selection(usage))
+                .unwrap_or_default(),
+        }
+    }
 
-        let (origin, guard) = inner.select_guard_with_expand(&usage, now, wallclock)?;
-        trace!(?guard, ?usage, "Guard selected");
+    /// Add a named guard context to this guard manager, with its own guard
+    /// samples, separate from those of every other context.
+    ///
+    /// We load the context's guards from the state manager, if we stored any
+    /// for it before, and store them there along with our other guards.  To
+    /// select a guard from this context, set
+    /// [`GuardUsageBuilder::context`] when calling
+    /// [`select_guard`](GuardMgr::select_guard).
+    ///
+    /// Does nothing if we already have a context with this name.
+    pub fn add_context(&self, id: &GuardContextId) -> Result<(), GuardMgrError> {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        if inner.contexts.contains_key(id) {
+            return Ok(());
+        }
+        let storage = (inner.create_storage)(id.storage_key());
+        let guards = storage.load()?.unwrap_or_default();
+        inner.contexts.insert(
+            id.clone(),
+            context::GuardContext {
+                guards,
+                last_primary_guard: None,
+                storage,
+            },
+        );
+        let (wallclock, now) = inner.current_time();
+        let _: Option<()> = inner.with_context(Some(id), |inner| inner.update(wallclock, now));
+        Ok(())
+    }
 
-        let (usable, usable_sender) = if origin.usable_immediately() {
-            (GuardUsable::new_usable_immediately(), None)
-        } else {
-            let (u, snd) = GuardUsable::new_uncertain();
-            (u, Some(snd))
+    /// Remove the named guard context `id` from this guard manager, after
+    /// storing its guards.
+    ///
+    /// The stored guards are kept, so that adding the context again later
+    /// restores them.  Requests for guards from this context that are still
+    /// pending are treated as unusable.
+    ///
+    /// Returns false if there was no such context.
+    pub fn remove_context(&self, id: &GuardContextId) -> Result<bool, GuardMgrError> {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        let Some(context) = inner.contexts.remove(id) else {
+            return Ok(false);
         };
-        let request_id = pending::RequestId::next();
-        let ctrl = inner.ctrl.clone();
-        let monitor = GuardMonitor::new(request_id, ctrl);
+        context.storage.store(&context.guards)?;
+        Ok(true)
+    }
 
-        // Note that the network can be down even if all the primary guards
-        // are not yet marked as unreachable.  But according to guard-spec we
-        // don't want to acknowledge the net as down before that point, since
-        // we don't mark all the primary guards as retriable unless
-        // we've been forced to non-primary guards.
-        let net_has_been_down =
-            if let Some(duration) = tor_proto::time_since_last_incoming_traffic() {
-                inner
-                    .guards
-                    .active_guards_mut()
-                    .all_primary_guards_are_unreachable()
-                    && duration >= inner.params.internet_down_timeout
-            } else {
-                // TODO: Is this the correct behavior in this case?
-                false
-            };
+    /// Return the names of our guard contexts, other than the default one.
+    pub fn contexts(&self) -> Vec<GuardContextId> {
+        let inner = self.inner.lock().expect("Poisoned lock");
+        let mut ids: Vec<_> = inner.contexts.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
 
-        let pending_request = pending::PendingRequest::new(
-            guard.first_hop_id(),
-            usage,
-            usable_sender,
-            net_has_been_down,
-        );
-        inner.pending.insert(request_id, pending_request);
+    /// Return information about the primary guards of the named guard
+    /// context `id`, in the same form as
+    /// [`primary_guards`](GuardMgr::primary_guards).
+    ///
+    /// Returns `None` if there is no such context.
+    pub fn context_primary_guards(&self, id: &GuardContextId) -> Option<Vec<GuardInfo>> {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        inner.with_context(Some(id), |inner| {
+            inner.guards.active_guards().primary_guard_infos()
+        })
+    }
 
-        match &guard.sample {
-            Some(sample) => {
-                let guard_id = GuardId::from_relay_ids(&guard);
-                inner
-                    .guards
-                    .guards_mut(sample)
-                    .record_attempt(&guard_id, now);
-            }
-            None => {
-                // We don't record attempts for fallbacks; we only care when
-                // they have failed.
-            }
+    /// Return up to `n` first hops that a directory manager should use for its
+    /// next one-hop directory requests, in the order that we prefer them.
+    ///
+    /// These are the guards that [`GuardMgr::select_guard`] would currently
+    /// choose among for a [`GuardUsageKind::OneHopDirectory`] usage: we take
+    /// into account each guard's reachability, our current filter, and the
+    /// directory parallelism limit from the consensus.  If we have no usable
+    /// directory guards, and we are not using bridges, we return usable
+    /// fallback directories instead.
+    ///
+    /// This function does not record any attempt to use the returned guards,
+    /// and does not extend the guard sample.  To actually build a circuit,
+    /// callers should still use [`GuardMgr::select_guard`], so that the
+    /// outcome is reported back to us.
+    pub fn preferred_dir_guards(&self, n: usize) -> Vec<FirstHop> {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        let inner = &mut *inner;
+        let now = inner.time.now();
+        // Retrying guards can change which ones should be primary, so we
+        // refresh our primary guards before we look at them.
+        let active_guards = inner.guards.active_guards_mut();
+        active_guards.consider_all_retries(now);
+        active_guards.select_primary_guards(&inner.params);
+        inner.notify_primary_guard_change();
+
+        let inner = &*inner;
+        let active_set = &inner.guards.active_set;
+        let guards =
+            inner
+                .guards
+                .guards(active_set)
+                .preferred_dir_guards(active_set, &inner.params, now, n);
+        if !guards.is_empty() || active_set.universe_type() != UniverseType::NetDir {
+            return guards;
         }
 
-        Ok((guard, monitor, usable))
+        let filt = inner.guards.active_guards().filter();
+        inner
+            .bootstrap_dirs()
+            .choose_multiple(&mut crate::util::rng(), now, filt, n)
+            .into_iter()
+            .filter_map(|fb| filt.modify_hop(fb.as_guard()).ok())
+            .collect()
+    }
+
+    /// Return a confirmed guard that we can try to use right away, before we
+    /// have any directory information.
+    ///
+    /// This lets a client that has run before start connecting to the network
+    /// as soon as it starts, instead of waiting for its directory to load.  We
+    /// choose our most preferred confirmed guard from our persistent state that
+    /// we don't know to be unusable.  The returned [`FirstHop`] is flagged as
+    /// [provisional](FirstHop::is_provisional): since we haven't checked it
+    /// against a directory, it may have moved or left the network.
+    ///
+    /// Return None if we already have a directory (in which case callers should
+    /// use [`GuardMgr::select_guard`]), if we are using bridges, or if we have
+    /// no confirmed guard that we believe to be usable.
+    ///
+    /// Unlike [`GuardMgr::select_guard`], this function doesn't record an
+    /// attempt to use the guard, and doesn't give the caller a
+    /// [`GuardMonitor`]: we learn nothing about the guard from how the caller
+    /// uses it.  Once a directory is available, callers should switch to
+    /// guards from [`GuardMgr::select_guard`].
+    pub fn provisional_first_hop(&self) -> Option<FirstHop> {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        if inner.guards.active_set.universe_type() != UniverseType::NetDir
+            || inner.timely_netdir().is_some()
+        {
+            return None;
+        }
+        let now = inner.time.now();
+        inner.guards.active_guards_mut().consider_all_retries(now);
+
+        let inner = &*inner;
+        let active_set = &inner.guards.active_set;
+        inner
+            .guards
+            .guards(active_set)
+            .provisional_guard(active_set, now)
     }
 
     /// Record that _after_ we built a circuit with a guard, something described
@@ -658,20 +1237,23 @@ impl<R: Runtime> GuardMgr<R> {
     where
         T: tor_linkspec::HasRelayIds + ?Sized,
     {
-        let now = self.runtime.now();
         let mut inner = self.inner.lock().expect("Poisoned lock");
+        let now = inner.time.now();
         let ids = inner.lookup_ids(identity);
         for id in ids {
             match &id.0 {
                 FirstHopIdInner::Guard(sample, id) => {
-                    inner
-                        .guards
-                        .guards_mut(sample)
-                        .record_failure(id, Some(external_failure), now);
+                    let inner = &mut *inner;
+                    inner.guards.guards_mut(sample).record_failure(
+                        id,
+                        Some(external_failure),
+                        &inner.params,
+                        now,
+                    );
                 }
                 FirstHopIdInner::Fallback(id) => {
                     if external_failure == ExternalActivity::DirCache {
-                        inner.fallbacks.note_failure(id, now);
+                        inner.bootstrap_dirs_mut().note_failure(id, now);
                     }
                 }
             }
@@ -685,8 +1267,8 @@ impl<R: Runtime> GuardMgr<R> {
         T: tor_linkspec::HasRelayIds + ?Sized,
     {
         let mut inner = self.inner.lock().expect("Poisoned lock");
-
-        inner.record_external_success(identity, external_activity, self.runtime.wallclock());
+        let wallclock = inner.time.wallclock();
+        inner.record_external_success(identity, external_activity, wallclock);
     }
 
     /// Return a stream of events about our estimated clock skew; these events
@@ -701,36 +1283,212 @@ impl<R: Runtime> GuardMgr<R> {
         inner.recv_skew.clone()
     }
 
-    /// Ensure that the message queue is flushed before proceeding to
-    /// the next step.  Used for testing.
-    #[cfg(test)]
-    async fn flush_msg_queue(&self) {
-        let (snd, rcv) = oneshot::channel();
-        let pingmsg = daemon::Msg::Ping(snd);
-        {
-            let inner = self.inner.lock().expect("Poisoned lock");
-            inner
-                .ctrl
-                .unbounded_send(pingmsg)
-                .expect("Guard observer task exited prematurely.");
-        }
-        let _ = rcv.await;
+    /// Return the clock skew observations that we've made most recently,
+    /// oldest first.
+    ///
+    /// These are the raw observations behind the estimates from
+    /// [`skew_events`](Self::skew_events).  We only remember a limited number
+    /// of them, and the history can include observations that are too old to
+    /// count towards the current estimate.
+    pub fn skew_history(&self) -> Vec<SkewObservation> {
+        let inner = self.inner.lock().expect("Poisoned lock");
+        inner.skew_history.iter().cloned().collect()
     }
-}
-
-/// An activity that can succeed or fail, and whose success or failure can be
-/// attributed to a guard.
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
-#[non_exhaustive]
-pub enum ExternalActivity {
-    /// The activity of using the guard as a directory cache.
-    DirCache,
-}
 
-impl GuardSets {
-    /// Return a reference to the currently active set of guards.
+    /// Return a stream of events about guards in our sample whose addresses
+    /// have changed.
     ///
-    /// (That's easy enough for now, since there is never more than one set of
+    /// Each event also tells you whether the guard has moved often enough
+    /// recently that its behavior is suspicious.
+    pub fn addr_change_events(&self) -> GuardAddrChangeEvents {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        let rcv = inner.send_addr_changes.subscribe();
+        GuardAddrChangeEvents { inner: rcv }
+    }
+
+    /// Return a stream of events that tell us when we decide that we can't
+    /// reach guards on some port and address family.
+    ///
+    /// We only make such decisions when our configuration enables
+    /// [`ReachabilityInference`].  Once we do, we stop choosing guards on
+    /// that port and family, until the decision expires.
+    pub fn unreachable_addr_events(&self) -> UnreachableAddrEvents {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        let rcv = inner.send_unreachable_addrs.subscribe();
+        UnreachableAddrEvents { inner: rcv }
+    }
+
+    /// Return a stream of events that tell us when our most-preferred primary
+    /// guard changes.
+    ///
+    /// This is meant for subsystems, such as circuit padding, that keep state
+    /// tied to the first hop of our circuits, and need to reset it when that
+    /// hop changes.  Unless `disclosure` is [`GuardIdDisclosure::Reveal`], the
+    /// events don't say which relay the new primary guard is.
+    pub fn primary_guard_events(&self, disclosure: GuardIdDisclosure) -> PrimaryGuardEvents {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        let rcv = inner.send_primary_changes.subscribe_with(disclosure);
+        PrimaryGuardEvents { inner: rcv }
+    }
+
+    /// Return diagnostic information about the queue of guard status reports
+    /// that this guard manager has not yet processed.
+    ///
+    /// If the queue is often long, then something is reporting statuses
+    /// faster than we can handle them.
+    pub fn status_queue_stats(&self) -> StatusQueueStats {
+        let inner = self.inner.lock().expect("Poisoned lock");
+        inner.ctrl.counters().snapshot()
+    }
+
+    /// Return long-term statistics about each guard in our current sample.
+    ///
+    /// These statistics record how often we have tried each guard, how often
+    /// it worked, and how quickly.  They are kept in our persistent state, so
+    /// they survive restarts.
+    ///
+    /// The statistics don't influence guard selection directly, but they can
+    /// help explain why we stopped preferring a given guard.
+    pub fn guard_stats(&self) -> Vec<GuardStatsEntry> {
+        let inner = self.inner.lock().expect("Poisoned lock");
+        inner.guards.active_guards().stats_entries()
+    }
+
+    /// Return a snapshot of our current primary guards, in order of
+    /// preference.
+    ///
+    /// These are the guards that we try first when we need a new circuit.
+    /// The list can be empty if we have no directory yet, or if our filter
+    /// excludes every guard in our sample.
+    pub fn primary_guards(&self) -> Vec<GuardInfo> {
+        let inner = self.inner.lock().expect("Poisoned lock");
+        inner.guards.active_guards().primary_guard_infos()
+    }
+
+    /// Return a snapshot of every guard in our current sample, in the order
+    /// in which we added them.
+    ///
+    /// Our primary guards are all in our sample; use
+    /// [`GuardInfo::is_primary`] to tell which ones they are.
+    pub fn sampled_guards(&self) -> Vec<GuardInfo> {
+        let inner = self.inner.lock().expect("Poisoned lock");
+        inner.guards.active_guards().sampled_guard_infos()
+    }
+
+    /// Return a snapshot of the status of each of our configured bridges, in
+    /// the order in which they are configured.
+    ///
+    /// Returns an empty list if we are not configured to use bridges.
+    #[cfg(feature = "bridge-client")]
+    pub fn bridge_status(&self) -> Vec<bridge::BridgeStatus> {
+        let inner = self.inner.lock().expect("Poisoned lock");
+        let Some(bridges) = &inner.configured_bridges else {
+            return Vec::new();
+        };
+        let descs = inner.latest_bridge_desc_list();
+        bridges
+            .iter()
+            .map(|bridge| {
+                use bridge::BridgeDescStatus as DS;
+                let descriptor = match descs.as_ref().and_then(|d| d.get(bridge)) {
+                    Some(Ok(desc)) => DS::Present(desc.clone()),
+                    Some(Err(e)) => DS::Failed(e.clone()),
+                    None => DS::Unknown,
+                };
+                let guard = inner.guards.bridges.get(&GuardId::from_relay_ids(bridge));
+                bridge::BridgeStatus::new(bridge.clone(), descriptor, guard)
+            })
+            .collect()
+    }
+
+    /// Return how much of the network's guard weight is held by the guards
+    /// in our current sample, and how much we allow it to hold.
+    ///
+    /// We recompute this whenever we get a new directory: if the guards in our
+    /// sample come to hold more than the allowed fraction, we remove some of
+    /// our unconfirmed guards.
+    ///
+    /// Return None if we have no directory, or if we are using bridges (which
+    /// have no weights).
+    pub fn sample_weight_fraction(&self) -> Option<SampleWeightFraction> {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        inner.with_opt_universe(|this, universe| {
+            this.guards
+                .active_guards()
+                .weight_fraction(&this.params, universe?)
+        })
+    }
+
+    /// Install `observer` to be told about changes to our guards: when we
+    /// sample, confirm, promote, demote, or expire a guard, and when a guard
+    /// fails.
+    ///
+    /// Replaces any observer that we had before.  Only the guards of the
+    /// default guard context are reported.
+    #[cfg(feature = "metrics")]
+    pub fn set_observer(&self, observer: &Arc<dyn GuardObserver>) {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        inner.guards.set_observer(observer);
+    }
+
+    /// Start recording this `GuardMgr`'s inputs, for later replay with
+    /// [`testing::replay::replay`].
+    ///
+    /// If we were already recording, discard what we had recorded so far.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn start_recording(&self) {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        let now = inner.time.now();
+        let mut recorder = testing::replay::Recorder::new(now);
+        if let Some(netdir) = inner.timely_netdir() {
+            recorder.note_netdir(now, &netdir);
+        }
+        inner.recorder = Some(recorder);
+    }
+
+    /// Stop recording this `GuardMgr`'s inputs, and return a redacted log of
+    /// them.
+    ///
+    /// Return None if we were not recording.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn finish_recording(&self) -> Option<testing::replay::ReplayLog> {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        inner.recorder.take().map(testing::replay::Recorder::finish)
+    }
+
+    /// Ensure that the message queue is flushed before proceeding to
+    /// the next step.  Used for testing.
+    ///
+    /// Once this returns, we have processed every report that was sent to a
+    /// [`GuardMonitor`] before it was called.
+    #[cfg(any(test, feature = "testing"))]
+    pub async fn flush_msg_queue(&self) {
+        let (snd, rcv) = oneshot::channel();
+        let pingmsg = daemon::Msg::Ping(snd);
+        {
+            let inner = self.inner.lock().expect("Poisoned lock");
+            inner
+                .ctrl
+                .send(pingmsg)
+                .expect("Guard observer task exited prematurely.");
+        }
+        let _ = rcv.await;
+    }
+}
+
+/// An activity that can succeed or fail, and whose success or failure can be
+/// attributed to a guard.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ExternalActivity {
+    /// The activity of using the guard as a directory cache.
+    DirCache,
+}
+
+impl GuardSets {
+    /// Return a reference to the currently active set of guards.
+    ///
+    /// (That's easy enough for now, since there is never more than one set of
     /// guards.  But eventually that will change, as we add support for more
     /// complex filter types, and for bridge relays. Those will use separate
     /// `GuardSet` instances, and this accessor will choose the right one.)
@@ -764,6 +1522,95 @@ impl GuardSets {
         }
     }
 
+    /// Return a description of every guard sample in this object, including
+    /// those that we don't recognize.
+    fn stored_samples(&self) -> Vec<StoredSampleInfo> {
+        use strum::IntoEnumIterator;
+        let recognized = GuardSetSelector::iter().map(|selector| {
+            let guards = self.guards(&selector);
+            StoredSampleInfo {
+                name: selector.storage_name().to_owned(),
+                recognized: true,
+                version: sample::SAMPLE_FORMAT_VERSION,
+                last_used: guards.last_used(),
+                n_guards: guards.n_stored_guards(),
+            }
+        });
+        let unrecognized = self.remaining.iter().filter_map(|(name, value)| {
+            let sample = UnrecognizedSample::from_json(value)?;
+            Some(StoredSampleInfo {
+                name: name.clone(),
+                recognized: false,
+                version: sample.meta.version,
+                last_used: sample.meta.last_used,
+                n_guards: sample.guards.len(),
+            })
+        });
+        recognized.chain(unrecognized).collect()
+    }
+
+    /// Discard the unrecognized guard samples that `policy` tells us not to
+    /// keep, as of `now`.
+    ///
+    /// Any unrecognized sample that doesn't say when it was last used is
+    /// marked as last used at `now`, so that it can expire later.
+    ///
+    /// Return the number of samples that we discarded.
+    fn prune_unrecognized(&mut self, policy: &SamplePrunePolicy, now: SystemTime) -> usize {
+        let mut samples = Vec::new();
+        for (name, value) in self.remaining.iter_mut() {
+            let Some(sample) = UnrecognizedSample::from_json(value) else {
+                continue;
+            };
+            let last_used = match sample.meta.last_used {
+                Some(t) => t,
+                None => {
+                    if let Some(obj) = value.as_object_mut() {
+                        let meta = obj
+                            .entry("meta")
+                            .or_insert_with(|| tor_persist::JsonValue::Object(Default::default()));
+                        if let Some(meta) = meta.as_object_mut() {
+                            meta.insert(
+                                "last_used".into(),
+                                humantime::format_rfc3339(now).to_string().into(),
+                            );
+                        }
+                    }
+                    now
+                }
+            };
+            samples.push((last_used, name.clone()));
+        }
+
+        // Most recently used first.
+        samples.sort_unstable_by(|a, b| b.cmp(a));
+        let expired = |last_used: SystemTime| {
+            policy.max_unused.is_some_and(|max_unused| {
+                now.duration_since(last_used)
+                    .is_ok_and(|unused| unused > max_unused)
+            })
+        };
+        let mut n_pruned = 0;
+        for (idx, (last_used, name)) in samples.into_iter().enumerate() {
+            let too_many = policy.max_unrecognized.is_some_and(|max| idx >= max);
+            if too_many || expired(last_used) {
+                self.remaining.remove(&name);
+                n_pruned += 1;
+            }
+        }
+        n_pruned
+    }
+
+    /// Replace the observer of every guard set in this object.
+    #[cfg(feature = "metrics")]
+    fn set_observer(&mut self, observer: &Arc<dyn GuardObserver>) {
+        use strum::IntoEnumIterator;
+        for sample in GuardSetSelector::iter() {
+            self.guards_mut(&sample)
+                .set_observer(Some(Arc::clone(observer)));
+        }
+    }
+
     /// Update all non-persistent state for the guards in this object with the
     /// state in `other`.
     fn copy_status_from(&mut self, mut other: GuardSets) {
@@ -779,6 +1626,139 @@ impl GuardSets {
 }
 
 impl GuardMgrInner {
+    /// Return the current wall-clock time and monotonic time, according to
+    /// our time provider.
+    fn current_time(&self) -> (SystemTime, Instant) {
+        (self.time.wallclock(), self.time.now())
+    }
+
+    /// Run `func` with the guards of the context `context` (or of the
+    /// default context, if `context` is `None`) in place of our active ones.
+    ///
+    /// Returns `None` if there is no such context.
+    fn with_context<F, T>(&mut self, context: Option<&GuardContextId>, func: F) -> Option<T>
+    where
+        F: FnOnce(&mut Self) -> T,
+    {
+        let Some(id) = context else {
+            return Some(func(self));
+        };
+        if self.active_context.as_ref() == Some(id) {
+            return Some(func(self));
+        }
+        debug_assert!(self.active_context.is_none());
+
+        let ctx = self.contexts.get_mut(id)?;
+        std::mem::swap(&mut self.guards, &mut ctx.guards);
+        std::mem::swap(&mut self.last_primary_guard, &mut ctx.last_primary_guard);
+        self.active_context = Some(id.clone());
+
+        let result = func(self);
+
+        self.active_context = None;
+        let ctx = self
+            .contexts
+            .get_mut(id)
+            .expect("Guard context removed while in use");
+        std::mem::swap(&mut self.guards, &mut ctx.guards);
+        std::mem::swap(&mut self.last_primary_guard, &mut ctx.last_primary_guard);
+        Some(result)
+    }
+
+    /// Run `func` once for the default context, and once for each of our
+    /// named contexts.
+    pub(crate) fn for_each_context<F>(&mut self, mut func: F)
+    where
+        F: FnMut(&mut Self),
+    {
+        func(self);
+        let ids: Vec<_> = self.contexts.keys().cloned().collect();
+        for id in ids {
+            let _: Option<()> = self.with_context(Some(&id), &mut func);
+        }
+    }
+
+    /// Replace the guards of each of our named contexts with those from the
+    /// state manager, if it has any.
+    fn reload_contexts(
+        &mut self,
+        wallclock: SystemTime,
+        now: Instant,
+    ) -> Result<(), GuardMgrError> {
+        let storages: Vec<_> = self
+            .contexts
+            .iter()
+            .map(|(id, ctx)| (id.clone(), Arc::clone(&ctx.storage)))
+            .collect();
+        for (id, storage) in storages {
+            if let Some(new_guards) = storage.load()? {
+                let _: Option<()> = self.with_context(Some(&id), |this| {
+                    this.replace_guards_with(new_guards, wallclock, now);
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace our fallback directories with our configured ones, plus those
+    /// that we have learned and may use as of `wallclock`.
+    ///
+    /// We keep the status of every fallback that we already had.
+    fn rebuild_fallbacks(&mut self, wallclock: SystemTime) {
+        // If our configured fallbacks have changed, we're probably on another
+        // network, and the fallbacks that we learned are no good to us.
+        if self
+            .learned_fallbacks
+            .retain_only_network(&self.configured_fallbacks)
+        {
+            self.store_learned_fallbacks();
+        }
+        let learned = if self.learn_fallbacks {
+            self.learned_fallbacks.usable(wallclock)
+        } else {
+            Vec::new()
+        };
+        let mut fallbacks =
+            fallback::FallbackState::with_learned(&self.configured_fallbacks, learned);
+        std::mem::swap(&mut self.fallbacks, &mut fallbacks);
+        self.fallbacks.take_status_from(fallbacks);
+    }
+
+    /// Learn fallback directories from the latest [`NetDir`], if we have one
+    /// and we're configured to learn them, and save them if they changed.
+    ///
+    /// We should call this whenever we get a new consensus.
+    pub(crate) fn update_learned_fallbacks(&mut self, wallclock: SystemTime) {
+        if let Some(netdir) = self.timely_netdir().filter(|_| self.learn_fallbacks) {
+            let changed = self.learned_fallbacks.update_from_netdir(
+                &mut crate::util::rng(),
+                &netdir,
+                wallclock,
+            );
+            if changed {
+                debug!(
+                    "Now tracking {} possible fallback directories from the consensus.",
+                    self.learned_fallbacks.len()
+                );
+                self.store_learned_fallbacks();
+            }
+        }
+        self.rebuild_fallbacks(wallclock);
+    }
+
+    /// Save our learned fallback directories, if we can.
+    fn store_learned_fallbacks(&self) {
+        if !self.learned_fallbacks_storage.can_store() {
+            return;
+        }
+        if let Err(e) = self
+            .learned_fallbacks_storage
+            .store(&self.learned_fallbacks)
+        {
+            warn!("Unable to save learned fallback directories: {}", e);
+        }
+    }
+
     /// Look up the latest [`NetDir`] (if there is one) from our
     /// [`NetDirProvider`] (if we have one).
     fn timely_netdir(&self) -> Option<Arc<NetDir>> {
@@ -828,7 +1808,11 @@ impl GuardMgrInner {
     fn latest_bridge_set(&self) -> Option<bridge::BridgeSet> {
         let bridge_config = self.configured_bridges.as_ref()?.clone();
         let bridge_descs = self.latest_bridge_desc_list();
-        Some(bridge::BridgeSet::new(bridge_config, bridge_descs))
+        Some(bridge::BridgeSet::new(
+            bridge_config,
+            bridge_descs,
+            self.time.wallclock(),
+        ))
     }
 
     /// Run a function that takes `&mut self` and an optional [`UniverseRef`].
@@ -865,6 +1849,16 @@ impl GuardMgrInner {
     /// of time, our configuration, and the relevant Universe for our active
     /// set.
     fn update(&mut self, wallclock: SystemTime, now: Instant) {
+        #[cfg(feature = "bridge-client")]
+        self.select_guard_set_based_on_bridges();
+        #[cfg(any(test, feature = "testing"))]
+        if self.recorder.is_some() {
+            if let Some(netdir) = self.timely_netdir() {
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.note_netdir(now, &netdir);
+                }
+            }
+        }
         self.with_opt_netdir(|this, netdir| {
             // Here we update our parameters from the latest NetDir, and check
             // whether we need to change to a (non)-restrictive GuardSet based
@@ -884,6 +1878,7 @@ impl GuardMgrInner {
                 wallclock,
                 this.guards.active_set.universe_type(),
                 this.guards.active_guards_mut(),
+                &mut this.send_addr_changes,
                 univ,
             );
             #[cfg(feature = "bridge-client")]
@@ -891,6 +1886,48 @@ impl GuardMgrInner {
             #[cfg(not(feature = "bridge-client"))]
             let _ = now;
         });
+
+        self.notify_primary_guard_change();
+        self.guards.active_guards_mut().note_used(wallclock);
+        let n_pruned = self
+            .guards
+            .prune_unrecognized(&self.sample_prune_policy, wallclock);
+        if n_pruned != 0 {
+            info!(
+                "Discarded {} unrecognized guard sample(s) from our state file.",
+                n_pruned
+            );
+        }
+    }
+
+    /// If the most-preferred primary guard of our active set has changed since
+    /// we last checked, tell everybody who is watching for primary guard
+    /// changes.
+    fn notify_primary_guard_change(&mut self) {
+        let primary = self.guards.active_guards().first_primary_guard().cloned();
+        if primary == self.last_primary_guard {
+            return;
+        }
+        if self.active_context.is_some() {
+            // We only report on the default context's guards: telling our
+            // watchers about another context's would link the two.
+            self.last_primary_guard = primary;
+            return;
+        }
+        self.primary_guard_epoch += 1;
+        let epoch = self.primary_guard_epoch;
+        self.send_primary_changes.send_with(|disclosure| {
+            let ids = match disclosure {
+                GuardIdDisclosure::Withhold => None,
+                GuardIdDisclosure::Reveal => primary.as_ref().map(|id| id.0.clone()),
+            };
+            PrimaryGuardChange {
+                epoch,
+                has_primary: primary.is_some(),
+                ids,
+            }
+        });
+        self.last_primary_guard = primary;
     }
 
     /// Replace our bridge configuration with the one from `new_config`.
@@ -901,6 +1938,7 @@ impl GuardMgrInner {
         wallclock: SystemTime,
         now: Instant,
     ) -> Result<RetireCircuits, GuardMgrConfigError> {
+        let old_bridges = self.configured_bridges.clone();
         match (&self.configured_bridges, new_config.bridges_enabled()) {
             (None, false) => {
                 assert_ne!(
@@ -923,30 +1961,41 @@ impl GuardMgrInner {
             }
             (_, true) => {
                 self.configured_bridges = Some(new_config.bridges().into());
-                self.guards.active_set = GuardSetSelector::Bridges;
             }
             (_, false) => {
                 self.configured_bridges = None;
-                self.guards.active_set = GuardSetSelector::Default;
             }
         }
 
         // If we have gotten here, we have changed the set of bridges, changed
         // which set is active, or changed them both.  We need to make sure that
-        // our `GuardSet` object is up-to-date with our configuration.
-        self.update(wallclock, now);
-
-        // We also need to tell the caller that its circuits are no good any
-        // more.
-        //
-        // TODO(nickm): Someday we can do this more judiciously by retuning
-        // "Some" in the case where we're still using bridges but our new bridge
-        // set contains different elements; see comment on RetireCircuits.
-        //
-        // TODO(nickm): We could also safely return RetireCircuits::None if we
-        // are using bridges, and our new bridge list is a superset of the older
-        // one.
-        Ok(RetireCircuits::All)
+        // the `GuardSet` objects of every context are up-to-date with our
+        // configuration: `update` switches each one to (or from) its bridge
+        // set.
+        self.for_each_context(|this| this.update(wallclock, now));
+
+        // We also need to tell the caller which of its circuits are no good
+        // any more.
+        let retire = match (&old_bridges, &self.configured_bridges) {
+            // We're still using bridges: only the circuits through bridges
+            // that are no longer configured need to go.
+            (Some(old), Some(new)) => {
+                let removed: Vec<RelayIds> = old
+                    .iter()
+                    .filter(|bridge| !new.contains(bridge))
+                    .map(RelayIds::from_relay_ids)
+                    .collect();
+                if removed.is_empty() {
+                    RetireCircuits::None
+                } else {
+                    RetireCircuits::Some(removed)
+                }
+            }
+            // We've started or stopped using bridges: none of our circuits
+            // start at a guard that we'd pick now.
+            _ => RetireCircuits::All,
+        };
+        Ok(retire)
     }
 
     /// Update our parameters, our selection (based on network parameters and
@@ -967,7 +2016,10 @@ impl GuardMgrInner {
         // is a bridge set.
         if let Some(netdir) = netdir {
             match GuardParams::try_from(netdir.params()) {
-                Ok(params) => self.params = params,
+                Ok(mut params) => {
+                    params.apply_overrides(&self.param_overrides);
+                    self.params = params;
+                }
                 Err(e) => warn!("Unusable guard parameters from consensus: {}", e),
             }
 
@@ -979,12 +2031,30 @@ impl GuardMgrInner {
         // TODO(nickm): We could use a "dirty" flag or something to decide
         // whether we need to call set_filter, if this comparison starts to show
         // up in profiles.
-        if self.guards.active_guards().filter() != &self.filter {
+        let filter = self.effective_filter();
+        if self.guards.active_guards().filter() != &filter {
             let restrictive = self.guards.active_set == GuardSetSelector::Restricted;
             self.guards
                 .active_guards_mut()
-                .set_filter(self.filter.clone(), restrictive);
+                .set_filter(filter, restrictive);
+        }
+    }
+
+    /// Return the filter that we should apply to our active guard set.
+    ///
+    /// This is our current filter, along with any country restrictions from
+    /// our configuration (unless we are using bridges), and any addresses
+    /// that we have decided we can't reach.
+    fn effective_filter(&self) -> GuardFilter {
+        let mut filter = self.filter.clone();
+        #[cfg(feature = "geoip")]
+        if self.guards.active_set.universe_type() == UniverseType::NetDir {
+            self.country_restrictions.add_to_filter(&mut filter);
         }
+        if let Some(patterns) = self.reachability.reachable_patterns() {
+            filter.push_reachable_addresses(patterns);
+        }
+        filter
     }
 
     /// Update the status of every guard in `active_guards`, and expand it as
@@ -997,11 +2067,15 @@ impl GuardMgrInner {
     /// We should call this whenever the contents of the universe have changed.
     ///
     /// We should also call this whenever a new GuardSet becomes active.
+    ///
+    /// If any guard's addresses have changed, we report that change to every
+    /// sender in `send_addr_changes`.
     fn update_guardset_internal<U: Universe>(
         params: &GuardParams,
         now: SystemTime,
         universe_type: UniverseType,
         active_guards: &mut GuardSet,
+        send_addr_changes: &mut UnboundedBroadcaster<GuardAddrChange>,
         universe: Option<&U>,
     ) -> ExtendedStatus {
         // Expire guards.  Do that early, in case doing so makes it clear that
@@ -1033,7 +2107,10 @@ impl GuardMgrInner {
                 // is missing, we just need to find a cache that has it.)
                 return ExtendedStatus::No;
             }
-            active_guards.update_status_from_dir(universe);
+            for change in active_guards.update_status_from_dir(universe) {
+                send_addr_changes.send(&change);
+            }
+            active_guards.trim_sample_to_weight_limit(params, universe);
             active_guards.extend_sample_as_needed(now, params, universe)
         } else {
             ExtendedStatus::No
@@ -1081,6 +2158,21 @@ impl GuardMgrInner {
         self.update(wallclock, now);
     }
 
+    /// Make our bridge set the active guard set if we have bridges configured,
+    /// and make sure that it isn't active if we don't.
+    ///
+    /// (Which guard set is active isn't persistent, so we need to do this for
+    /// every context, including those that we load after configuring bridges.)
+    #[cfg(feature = "bridge-client")]
+    fn select_guard_set_based_on_bridges(&mut self) {
+        let using_bridges = self.guards.active_set == GuardSetSelector::Bridges;
+        match (using_bridges, self.configured_bridges.is_some()) {
+            (false, true) => self.guards.active_set = GuardSetSelector::Bridges,
+            (true, false) => self.guards.active_set = GuardSetSelector::Default,
+            (true, true) | (false, false) => {}
+        }
+    }
+
     /// Update which guard set is active based on the current filter and the
     /// provided netdir.
     ///
@@ -1101,7 +2193,7 @@ impl GuardMgrInner {
             #[cfg(feature = "bridge-client")]
             GuardSetSelector::Bridges => return,
         };
-        let frac_permitted = self.filter.frac_bw_permitted(netdir);
+        let frac_permitted = self.effective_filter().frac_bw_permitted(netdir);
         let threshold = self.params.filter_threshold + offset;
         let new_choice = if frac_permitted < threshold {
             GuardSetSelector::Restricted
@@ -1149,8 +2241,12 @@ impl GuardMgrInner {
 
     /// Replace the current GuardFilter with `filter`.
     fn set_filter(&mut self, filter: GuardFilter, wallclock: SystemTime, now: Instant) {
+        #[cfg(any(test, feature = "testing"))]
+        if let Some(recorder) = &mut self.recorder {
+            recorder.note_filter(now, &filter);
+        }
         self.filter = filter;
-        self.update(wallclock, now);
+        self.for_each_context(|this| this.update(wallclock, now));
     }
 
     /// Called when the circuit manager reports (via [`GuardMonitor`]) that
@@ -1164,8 +2260,98 @@ impl GuardMgrInner {
         request_id: RequestId,
         status: GuardStatus,
         skew: Option<ClockSkew>,
-        runtime: &impl tor_rtcompat::SleepProvider,
     ) {
+        let (context, attempt) = match self.pending.get(&request_id) {
+            Some(pending) => (
+                pending.usage().context.clone(),
+                Some((
+                    RelayIds::from_relay_ids(pending.guard_id()),
+                    pending.addrs().to_vec(),
+                    pending.net_has_been_down(),
+                )),
+            ),
+            None => (None, None),
+        };
+        let handled = self.with_context(context.as_ref(), |this| {
+            this.handle_msg_in_context(request_id, status, skew);
+        });
+        if handled.is_none() {
+            // The guard came from a context that has since been removed.
+            if let Some(mut pending) = self.pending.remove(&request_id) {
+                pending.reply(false);
+            }
+        }
+        if let Some((guard, addrs, net_has_been_down)) = attempt {
+            self.note_reachability(&guard, &addrs, status, net_has_been_down);
+        }
+    }
+
+    /// Record the outcome of an attempt to reach the guard `guard` at `addrs`,
+    /// and stop using any addresses that we decide are unreachable as a
+    /// result.
+    ///
+    /// If `net_has_been_down` is true, the network had been down when we
+    /// gave out the guard.
+    fn note_reachability(
+        &mut self,
+        guard: &RelayIds,
+        addrs: &[SocketAddr],
+        status: GuardStatus,
+        net_has_been_down: bool,
+    ) {
+        let succeeded = match status {
+            GuardStatus::Success => true,
+            GuardStatus::Failure => false,
+            GuardStatus::AttemptAbandoned | GuardStatus::Indeterminate => return,
+        };
+        if succeeded && net_has_been_down && self.reachability.reachable_patterns().is_some() {
+            // The network was down, and now it's back: it may well be a
+            // different network, with different rules.
+            info!("Network has come back; forgetting which guard addresses we thought we couldn't reach.");
+            self.reachability.clear();
+            let (wallclock, now) = self.current_time();
+            self.for_each_context(|this| this.update(wallclock, now));
+        }
+        let now = self.time.now();
+        let inferences = self.reachability.note_outcome(
+            guard,
+            addrs,
+            succeeded,
+            now,
+            &self.reachability_inference,
+        );
+        if inferences.is_empty() {
+            return;
+        }
+        for inference in &inferences {
+            info!(
+                "Failed to reach {} different guards at {} addresses on port {}, and reached none there; not using guards there for a while.",
+                inference.n_failed_guards(),
+                if inference.is_ipv6() { "IPv6" } else { "IPv4" },
+                inference.port(),
+            );
+        }
+        for inference in &inferences {
+            self.send_unreachable_addrs.send(inference);
+        }
+        let (wallclock, now) = self.current_time();
+        self.for_each_context(|this| this.update(wallclock, now));
+    }
+
+    /// Implementation for `handle_msg`, within the context from which we
+    /// gave out the guard.
+    #[allow(clippy::cognitive_complexity)]
+    fn handle_msg_in_context(
+        &mut self,
+        request_id: RequestId,
+        status: GuardStatus,
+        skew: Option<ClockSkew>,
+    ) {
+        let time = self.time.clone();
+        #[cfg(any(test, feature = "testing"))]
+        if let Some(recorder) = &mut self.recorder {
+            recorder.note_outcome(time.now(), request_id, status);
+        }
         if let Some(mut pending) = self.pending.remove(&request_id) {
             // If there was a pending request matching this RequestId, great!
             let guard_id = pending.guard_id();
@@ -1173,15 +2359,19 @@ impl GuardMgrInner {
 
             // First, handle the skew report (if any)
             if let Some(skew) = skew {
-                let now = runtime.now();
+                let now = time.now();
                 let observation = skew::SkewObservation { skew, when: now };
+                if self.skew_history.len() >= SKEW_HISTORY_LEN {
+                    self.skew_history.pop_front();
+                }
+                self.skew_history.push_back(observation.clone());
 
                 match &guard_id.0 {
                     FirstHopIdInner::Guard(_, id) => {
                         self.guards.active_guards_mut().record_skew(id, observation);
                     }
                     FirstHopIdInner::Fallback(id) => {
-                        self.fallbacks.note_skew(id, observation);
+                        self.bootstrap_dirs_mut().note_skew(id, observation);
                     }
                 }
                 // TODO: We call this whenever we receive an observed clock
@@ -1191,13 +2381,29 @@ impl GuardMgrInner {
                 // an hour has passed, a given observation won't be up-to-date
                 // any more, and we might want to recalculate the skew
                 // accordingly.
-                self.update_skew(now);
+                //
+                // (Our estimate only uses the observations from the default
+                // context's guards, so we only recalculate it there.)
+                if self.active_context.is_none() {
+                    self.update_skew(now);
+                }
+            }
+
+            if let FirstHopIdInner::Guard(sample, id) = &guard_id.0 {
+                let latency = time.now().saturating_duration_since(pending.launched_at());
+                self.guards.guards_mut(sample).record_outcome_stats(
+                    id,
+                    status,
+                    latency,
+                    time.wallclock(),
+                );
             }
 
             match (status, &guard_id.0) {
                 (GuardStatus::Failure, FirstHopIdInner::Fallback(id)) => {
                     // We used a fallback, and we weren't able to build a circuit through it.
-                    self.fallbacks.note_failure(id, runtime.now());
+                    let now = time.now();
+                    self.bootstrap_dirs_mut().note_failure(id, now);
                 }
                 (_, FirstHopIdInner::Fallback(_)) => {
                     // We don't record any other kind of circuit activity if we
@@ -1209,7 +2415,7 @@ impl GuardMgrInner {
                     // succeed, tell the primary guards that they might be
                     // retriable.
                     if pending.net_has_been_down() {
-                        self.maybe_retry_primary_guards(runtime.now());
+                        self.maybe_retry_primary_guards(time.now());
                     }
 
                     // The guard succeeded.  Tell the GuardSet.
@@ -1217,25 +2423,28 @@ impl GuardMgrInner {
                         id,
                         &self.params,
                         None,
-                        runtime.wallclock(),
+                        time.wallclock(),
                     );
                     // Either tell the request whether the guard is
                     // usable, or schedule it as a "waiting" request.
-                    if let Some(usable) = self.guard_usability_status(&pending, runtime.now()) {
+                    if let Some(usable) = self.guard_usability_status(&pending, time.now()) {
                         trace!(?guard_id, usable, "Known usability status");
                         pending.reply(usable);
                     } else {
                         // This is the one case where we can't use the
                         // guard yet.
                         trace!(?guard_id, "Not able to answer right now");
-                        pending.mark_waiting(runtime.now());
+                        pending.mark_waiting(time.now());
                         self.waiting.push(pending);
                     }
                 }
                 (GuardStatus::Failure, FirstHopIdInner::Guard(sample, id)) => {
-                    self.guards
-                        .guards_mut(sample)
-                        .record_failure(id, None, runtime.now());
+                    self.guards.guards_mut(sample).record_failure(
+                        id,
+                        None,
+                        &self.params,
+                        time.now(),
+                    );
                     pending.reply(false);
                 }
                 (GuardStatus::AttemptAbandoned, FirstHopIdInner::Guard(sample, id)) => {
@@ -1261,11 +2470,12 @@ impl GuardMgrInner {
         self.guards
             .active_guards_mut()
             .select_primary_guards(&self.params);
+        self.notify_primary_guard_change();
 
         // Some waiting request may just have become ready (usable or
         // not); we need to give them the information they're waiting
         // for.
-        self.expire_and_answer_pending_requests(runtime.now());
+        self.expire_and_answer_pending_requests(time.now());
     }
 
     /// Helper to implement `GuardMgr::note_external_success()`.
@@ -1292,18 +2502,53 @@ impl GuardMgrInner {
                 }
                 FirstHopIdInner::Fallback(id) => {
                     if external_activity == ExternalActivity::DirCache {
-                        self.fallbacks.note_success(id);
+                        self.bootstrap_dirs_mut().note_success(id);
                     }
                 }
             }
         }
     }
 
+    /// Return the list of directories that we use when we have no guard
+    /// information: either our fallbacks, or (if we are configured to bootstrap
+    /// only from authorities) our directory authorities.
+    fn bootstrap_dirs(&self) -> &fallback::FallbackState {
+        if self.authorities_only {
+            &self.authorities
+        } else {
+            &self.fallbacks
+        }
+    }
+
+    /// As [`GuardMgrInner::bootstrap_dirs`], but return a mutable reference.
+    fn bootstrap_dirs_mut(&mut self) -> &mut fallback::FallbackState {
+        if self.authorities_only {
+            &mut self.authorities
+        } else {
+            &mut self.fallbacks
+        }
+    }
+
+    /// Change whether we bootstrap only from directory authorities, warning
+    /// the user about the consequences if we have just started doing so.
+    fn set_authorities_only(&mut self, authorities_only: bool) {
+        if authorities_only && !self.authorities_only {
+            warn!(
+                "Configured to bootstrap only from directory authorities. This puts extra load \
+                 on the authorities, and we will be unable to bootstrap whenever all {} of them \
+                 are unreachable. Don't do this unless you really need to.",
+                self.authorities.len()
+            );
+        }
+        self.authorities_only = authorities_only;
+    }
+
     /// Return an iterator over all of the clock skew observations we've made
-    /// for guards or fallbacks.
+    /// for guards, fallbacks, or authorities.
     fn skew_observations(&self) -> impl Iterator<Item = &skew::SkewObservation> {
         self.fallbacks
             .skew_observations()
+            .chain(self.authorities.skew_observations())
             .chain(self.guards.active_guards().skew_observations())
     }
 
@@ -1323,6 +2568,11 @@ impl GuardMgrInner {
     /// Return None if we can't yet give an answer about whether such
     /// a circuit is usable.
     fn guard_usability_status(&self, pending: &PendingRequest, now: Instant) -> Option<bool> {
+        if pending.usage().context != self.active_context {
+            // We can only answer using the guards of the context that the
+            // request came from.
+            return None;
+        }
         match &pending.guard_id().0 {
             FirstHopIdInner::Guard(sample, id) => self.guards.guards(sample).circ_usability_status(
                 id,
@@ -1416,7 +2666,7 @@ impl GuardMgrInner {
         }
 
         let id = ids::FallbackId::from_relay_ids(identity);
-        if self.fallbacks.contains(&id) {
+        if self.bootstrap_dirs().contains(&id) {
             vec.push(id.into());
         }
 
@@ -1426,11 +2676,114 @@ impl GuardMgrInner {
     /// Run any periodic events that update guard status, and return a
     /// duration after which periodic events should next be run.
     pub(crate) fn run_periodic_events(&mut self, wallclock: SystemTime, now: Instant) -> Duration {
-        self.update(wallclock, now);
-        self.expire_and_answer_pending_requests(now);
+        if self.reachability.expire(now, &self.reachability_inference) {
+            info!("Giving guard addresses that we thought we couldn't reach another chance.");
+        }
+        self.for_each_context(|this| {
+            this.update(wallclock, now);
+            this.expire_and_answer_pending_requests(now);
+        });
         Duration::from_secs(1) // TODO: Too aggressive.
     }
 
+    /// Implementation for [`GuardMgr::select_guard`], within whichever
+    /// context is currently active.
+    fn select_guard(
+        &mut self,
+        usage: GuardUsage,
+    ) -> Result<(FirstHop, GuardMonitor, GuardUsable), PickGuardError> {
+        let (wallclock, now) = self.current_time();
+
+        // (I am not 100% sure that we need to consider_all_retries here, but
+        // it should _probably_ not hurt.)
+        self.guards.active_guards_mut().consider_all_retries(now);
+
+        let (origin, guard) = self.select_guard_with_expand(&usage, now, wallclock)?;
+        trace!(?guard, ?usage, "Guard selected");
+
+        let (usable, usable_sender) = if origin.usable_immediately() {
+            (GuardUsable::new_usable_immediately(), None)
+        } else {
+            let (u, snd) = GuardUsable::new_uncertain();
+            (u, Some(snd))
+        };
+        let request_id = pending::RequestId::next();
+        let ctrl = self.ctrl.clone();
+        let monitor = GuardMonitor::new(request_id, ctrl);
+        #[cfg(any(test, feature = "testing"))]
+        if let Some(recorder) = &mut self.recorder {
+            recorder.note_select(now, request_id, &usage, &guard);
+        }
+
+        // Note that the network can be down even if all the primary guards
+        // are not yet marked as unreachable.  But according to guard-spec we
+        // don't want to acknowledge the net as down before that point, since
+        // we don't mark all the primary guards as retriable unless
+        // we've been forced to non-primary guards.
+        let net_has_been_down =
+            if let Some(duration) = tor_proto::time_since_last_incoming_traffic() {
+                self.guards
+                    .active_guards_mut()
+                    .all_primary_guards_are_unreachable()
+                    && duration >= self.params.internet_down_timeout
+            } else {
+                // TODO: Is this the correct behavior in this case?
+                false
+            };
+
+        // We only learn about reachability from guards that we connect to
+        // directly: fallbacks aren't restricted by what we infer, and a
+        // pluggable transport may not use the guard's addresses at all.
+        let addrs = match (
+            &guard.sample,
+            tor_linkspec::HasChanMethod::chan_method(&guard),
+        ) {
+            (Some(_), tor_linkspec::ChannelMethod::Direct(addrs)) => addrs,
+            (_, _) => Vec::new(),
+        };
+        let pending_request = pending::PendingRequest::new(
+            guard.first_hop_id(),
+            usage,
+            usable_sender,
+            net_has_been_down,
+            addrs,
+            now,
+        );
+        self.pending.insert(request_id, pending_request);
+
+        match &guard.sample {
+            Some(sample) => {
+                let guard_id = GuardId::from_relay_ids(&guard);
+                self.guards
+                    .guards_mut(sample)
+                    .record_attempt(&guard_id, now);
+            }
+            None => {
+                // We don't record attempts for fallbacks; we only care when
+                // they have failed.
+            }
+        }
+
+        Ok((guard, monitor, usable))
+    }
+
+    /// Implementation for [`GuardMgr::preview_selection`], within whichever
+    /// context is currently active.
+    fn preview_selection(&mut self, usage: &GuardUsage) -> Vec<CandidateGuard> {
+        let now = self.time.now();
+        // Don't report on primary guards that are out of date: select_guard
+        // would recompute them before choosing.
+        let active_guards = self.guards.active_guards_mut();
+        if active_guards.primary_guards_invalidated() {
+            active_guards.select_primary_guards(&self.params);
+            self.notify_primary_guard_change();
+        }
+        let active_set = &self.guards.active_set;
+        self.guards
+            .guards(active_set)
+            .preview_selection(usage, &self.params, now)
+    }
+
     /// Try to select a guard, expanding the sample if the first attempt fails.
     fn select_guard_with_expand(
         &mut self,
@@ -1463,8 +2816,10 @@ impl GuardMgrInner {
                 wallclock,
                 this.guards.active_set.universe_type(),
                 this.guards.active_guards_mut(),
+                &mut this.send_addr_changes,
                 Some(univ),
             );
+            this.notify_primary_guard_change();
             if extended == ExtendedStatus::Yes {
                 match this.select_guard_once(usage, now) {
                     Ok(res) => return Some(res),
@@ -1479,6 +2834,36 @@ impl GuardMgrInner {
             return Ok(res);
         }
 
+        // If the caller requires a particular guard, it may be one that we
+        // have never sampled: try adding it from the universe.  Either way, we
+        // can't use a fallback directory instead.
+        if let Some(required) = usage.required_ids().next() {
+            let res = self.with_opt_universe(|this, univ| {
+                let univ = univ?;
+                let added = this.guards.active_guards_mut().add_pinned_guard(
+                    required.as_ref(),
+                    wallclock,
+                    &this.params,
+                    univ,
+                );
+                if added == ExtendedStatus::No {
+                    return None;
+                }
+                this.guards
+                    .active_guards_mut()
+                    .select_primary_guards(&this.params);
+                this.notify_primary_guard_change();
+                match this.select_guard_once(usage, now) {
+                    Ok(res) => Some(res),
+                    Err(e) => {
+                        trace!("Couldn't select required guard after adding it: {}", e);
+                        None
+                    }
+                }
+            });
+            return res.ok_or(first_error);
+        }
+
         // Okay, that didn't work either.  If we were asked for a directory
         // guard, and we aren't using bridges, then we may be able to use a
         // fallback.
@@ -1523,7 +2908,8 @@ impl GuardMgrInner {
         Ok((list_kind, first_hop))
     }
 
-    /// Helper: Select a fallback directory.
+    /// Helper: Select a fallback directory (or, if we are configured to
+    /// bootstrap only from authorities, a directory authority).
     ///
     /// Called when we have no guard information to use. Return values are as
     /// for [`GuardMgr::select_guard()`]
@@ -1534,8 +2920,8 @@ impl GuardMgrInner {
         let filt = self.guards.active_guards().filter();
 
         let fallback = self
-            .fallbacks
-            .choose(&mut rand::thread_rng(), now, filt)?
+            .bootstrap_dirs()
+            .choose(&mut crate::util::rng(), now, filt)?
             .as_guard();
         let fallback = filt.modify_hop(fallback)?;
         Ok((sample::ListKind::Fallback, fallback))
@@ -1595,6 +2981,13 @@ struct GuardParams {
     /// What fraction of the guards determine that our filter is "very
     /// restrictive"?
     extreme_threshold: f64,
+    /// On what schedule should we retry guards that have failed?
+    retry: backoff::RetryParams,
+    /// How many attempts may be in flight through one primary guard before we
+    /// prefer another primary guard instead?
+    ///
+    /// If this is `None`, we don't spread attempts across primary guards.
+    max_attempts_in_flight: Option<usize>,
 }
 
 impl Default for GuardParams {
@@ -1615,6 +3008,8 @@ impl Default for GuardParams {
             internet_down_timeout: Duration::from_secs(600),
             filter_threshold: 0.2,
             extreme_threshold: 0.01,
+            retry: backoff::RetryParams::default(),
+            max_attempts_in_flight: None,
         }
     }
 }
@@ -1637,10 +3032,36 @@ impl TryFrom<&NetParameters> for GuardParams {
             internet_down_timeout: p.guard_internet_likely_down.try_into()?,
             filter_threshold: p.guard_meaningful_restriction.as_fraction(),
             extreme_threshold: p.guard_extreme_restriction.as_fraction(),
+            retry: p.try_into()?,
+            max_attempts_in_flight: Some(p.guard_max_attempts_in_flight)
+                .filter(|max| max.get() != 0)
+                .map(usize::try_from)
+                .transpose()?,
         })
     }
 }
 
+impl GuardParams {
+    /// Replace our values with any that are set in `overrides`.
+    fn apply_overrides(&mut self, overrides: &GuardParamOverrides) {
+        let GuardParamOverrides {
+            max_sample_size,
+            min_filtered_sample_size,
+            n_primary,
+            lifetime_unconfirmed,
+            lifetime_confirmed,
+            lifetime_unlisted,
+        } = overrides.clone();
+        self.max_sample_size = max_sample_size.unwrap_or(self.max_sample_size);
+        self.min_filtered_sample_size =
+            min_filtered_sample_size.unwrap_or(self.min_filtered_sample_size);
+        self.n_primary = n_primary.unwrap_or(self.n_primary);
+        self.lifetime_unconfirmed = lifetime_unconfirmed.unwrap_or(self.lifetime_unconfirmed);
+        self.lifetime_confirmed = lifetime_confirmed.unwrap_or(self.lifetime_confirmed);
+        self.lifetime_unlisted = lifetime_unlisted.unwrap_or(self.lifetime_unlisted);
+    }
+}
+
 /// Representation of a guard or fallback, as returned by [`GuardMgr::select_guard()`].
 #[derive(Debug, Clone)]
 pub struct FirstHop {
@@ -1648,6 +3069,9 @@ pub struct FirstHop {
     sample: Option<GuardSetSelector>,
     /// Information about connecting to (or through) this guard.
     inner: FirstHopInner,
+    /// True if we chose this guard from our persistent state alone, before
+    /// we had any directory information to check it against.
+    provisional: bool,
 }
 /// The enumeration inside a FirstHop that holds information about how to
 /// connect to (and possibly through) a guard or fallback.
@@ -1687,6 +3111,17 @@ impl FirstHop {
         }
     }
 
+    /// Return true if this is a provisional first hop, as returned by
+    /// [`GuardMgr::provisional_first_hop`].
+    ///
+    /// We chose a provisional first hop without any directory information.
+    /// Once a directory is available, the caller should make sure that the
+    /// guard is still listed (for example, with [`FirstHop::get_relay`])
+    /// before relying on it any further.
+    pub fn is_provisional(&self) -> bool {
+        self.provisional
+    }
+
     /// If possible, return a view of this object that can be used to build a circuit.
     pub fn as_circ_target(&self) -> Option<&OwnedCircTarget> {
         match &self.inner {
@@ -1804,6 +3239,12 @@ pub struct GuardUsage {
     /// The default is the empty list.
     #[builder(sub_builder, setter(custom))]
     restrictions: GuardRestrictionList,
+    /// The guard context from which to select the guard, or `None` for the
+    /// default context.
+    ///
+    /// See [`GuardMgr::add_context`].
+    #[builder(default, setter(strip_option))]
+    context: Option<GuardContextId>,
 }
 
 impl_standard_builder! { GuardUsage: !Deserialize }
@@ -1833,6 +3274,17 @@ impl GuardUsageBuilder {
     }
 }
 
+impl GuardUsage {
+    /// Return the identities that this usage requires its guard to have, from
+    /// its [`GuardRestriction::RequireId`] restrictions.
+    fn required_ids(&self) -> impl Iterator<Item = &RelayId> + '_ {
+        self.restrictions.iter().filter_map(|r| match r {
+            GuardRestriction::RequireId(id) => Some(id),
+            _ => None,
+        })
+    }
+}
+
 /// A restriction that applies to a single request for a guard.
 ///
 /// Restrictions differ from filters (see [`GuardFilter`]) in that
@@ -1847,6 +3299,17 @@ pub enum GuardRestriction {
     AvoidId(RelayId),
     /// Don't pick a guard with any of the provided Ed25519 identities.
     AvoidAllIds(RelayIdSet),
+    /// Only pick a guard with the provided identity.
+    ///
+    /// If that relay is not in our guard sample, but our current directory (or
+    /// bridge set) lists it as a possible guard, and our filter permits it,
+    /// we add it to the sample.  If we can't use that guard, the request
+    /// fails: we never fall back to a different guard, or to a fallback
+    /// directory.
+    ///
+    /// This is meant for deployments that must use a particular, approved
+    /// entry node.
+    RequireId(RelayId),
 }
 
 /// The kind of vanguards to use.
@@ -2016,6 +3479,650 @@ mod test {
         });
     }
 
+    #[test]
+    fn preview_selection() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, _statemgr, netdir) = init(rt);
+            guardmgr.install_test_netdir(&netdir);
+            guardmgr.inner.lock().unwrap().params.max_attempts_in_flight = Some(8);
+            let usage = GuardUsage::default();
+            let summary = |preview: &[CandidateGuard]| -> Vec<_> {
+                preview
+                    .iter()
+                    .map(|c| (c.info().ids().clone(), c.exclusion()))
+                    .collect()
+            };
+
+            // For data circuits, we use one primary guard at a time.
+            let preview = guardmgr.preview_selection(&usage);
+            assert!(preview.len() > 2);
+            assert!(preview[0].is_eligible());
+            assert!(preview[0].info().is_primary());
+            assert!(preview[1].info().is_primary());
+            assert_eq!(preview[1].exclusion(), Some(GuardExclusion::NotPreferred));
+            assert_eq!(preview.iter().filter(|c| c.is_eligible()).count(), 1);
+            // Previewing doesn't change anything.
+            assert_eq!(
+                summary(&guardmgr.preview_selection(&usage)),
+                summary(&preview)
+            );
+
+            // When we select a guard, we get the eligible one.
+            let mut monitors = Vec::new();
+            for _ in 0..8 {
+                let (guard, mon, _usable) = guardmgr.select_guard(usage.clone()).unwrap();
+                assert!(guard.same_relay_ids(preview[0].info().ids()));
+                monitors.push(mon);
+            }
+
+            // Now that it's busy, we'd use the other primary guard.
+            let preview2 = guardmgr.preview_selection(&usage);
+            assert_eq!(preview2[0].exclusion(), Some(GuardExclusion::Busy));
+            assert!(preview2[1].is_eligible());
+
+            // If our primary guards are out of date, we bring them up to date
+            // before we report on them.
+            let filter = {
+                let mut f = GuardFilter::default();
+                let addr = preview[2].info().addrs()[0];
+                f.push_reachable_addresses(vec![addr.to_string().parse().unwrap()]);
+                f
+            };
+            {
+                let mut inner = guardmgr.inner.lock().unwrap();
+                let active_guards = inner.guards.active_guards_mut();
+                active_guards.set_filter(filter, false);
+                assert!(active_guards.primary_guards_invalidated());
+            }
+            // (The busy guard may still pass the new filter, so we don't
+            // know which primary guard comes first.)
+            let preview3 = guardmgr.preview_selection(&usage);
+            assert!(preview3[0].info().is_primary());
+            assert!(preview3
+                .iter()
+                .filter(|c| c.info().is_primary())
+                .all(|c| c.exclusion() != Some(GuardExclusion::Filtered)));
+            assert_eq!(preview3.iter().filter(|c| c.is_eligible()).count(), 1);
+            assert!(!guardmgr
+                .inner
+                .lock()
+                .unwrap()
+                .guards
+                .active_guards()
+                .primary_guards_invalidated());
+
+            // We don't report anything for a context that we don't have.
+            let usage = GuardUsageBuilder::new()
+                .context(GuardContextId::new("unknown").unwrap())
+                .build()
+                .unwrap();
+            assert!(guardmgr.preview_selection(&usage).is_empty());
+        });
+    }
+
+    #[test]
+    fn guard_contexts() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, statemgr, netdir) = init(rt.clone());
+            let ctx = GuardContextId::new("a").unwrap();
+            let unknown = GuardContextId::new("b").unwrap();
+
+            // We can't use a context until it has been added.
+            let usage = GuardUsageBuilder::new()
+                .context(ctx.clone())
+                .build()
+                .unwrap();
+            assert!(matches!(
+                guardmgr.select_guard(usage.clone()),
+                Err(PickGuardError::UnknownContext(_))
+            ));
+            assert!(guardmgr.context_primary_guards(&ctx).is_none());
+
+            guardmgr.add_context(&ctx).unwrap();
+            guardmgr.add_context(&ctx).unwrap();
+            assert_eq!(guardmgr.contexts(), vec![ctx.clone()]);
+            guardmgr.install_test_netdir(&netdir);
+
+            let (id, mon, usable) = guardmgr.select_guard(usage.clone()).unwrap();
+            mon.succeeded();
+            assert!(usable.await.unwrap());
+            guardmgr.flush_msg_queue().await;
+
+            // The success was recorded in the context, not in the default set.
+            let primary = guardmgr.context_primary_guards(&ctx).unwrap();
+            assert!(primary
+                .iter()
+                .any(|g| g.ids().same_relay_ids(&id) && g.is_confirmed()));
+            assert!(!guardmgr
+                .primary_guards()
+                .iter()
+                .any(GuardInfo::is_confirmed));
+
+            let usage_unknown = GuardUsageBuilder::new().context(unknown).build().unwrap();
+            assert!(matches!(
+                guardmgr.select_guard(usage_unknown),
+                Err(PickGuardError::UnknownContext(_))
+            ));
+
+            // The context's guards are saved under their own key.
+            guardmgr.store_persistent_state().unwrap();
+            assert!(statemgr
+                .load::<serde_json::Value>("guards@a")
+                .unwrap()
+                .is_some());
+            drop(guardmgr);
+
+            let guardmgr2 =
+                GuardMgr::new(rt.clone(), statemgr.clone(), &TestConfig::default()).unwrap();
+            guardmgr2.add_context(&ctx).unwrap();
+            guardmgr2.install_test_netdir(&netdir);
+            let (id2, _mon, _usable) = guardmgr2.select_guard(usage).unwrap();
+            assert!(id2.same_relay_ids(&id));
+
+            assert!(guardmgr2.remove_context(&ctx).unwrap());
+            assert!(!guardmgr2.remove_context(&ctx).unwrap());
+            assert!(guardmgr2.contexts().is_empty());
+        });
+    }
+
+    #[test]
+    fn skew_history() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, _statemgr, netdir) = init(rt.clone());
+            guardmgr.install_test_netdir(&netdir);
+            assert!(guardmgr.skew_history().is_empty());
+
+            let minute = Duration::from_secs(60);
+            for n in 1..=(SKEW_HISTORY_LEN as u32 + 2) {
+                let (_id, mut mon, _usable) = guardmgr.select_guard(GuardUsage::default()).unwrap();
+                mon.skew(ClockSkew::Fast(minute * n));
+                mon.succeeded();
+            }
+            guardmgr.flush_msg_queue().await;
+
+            // We only keep the most recent observations, oldest first.
+            let history = guardmgr.skew_history();
+            assert_eq!(history.len(), SKEW_HISTORY_LEN);
+            assert_eq!(history[0].skew(), ClockSkew::Fast(minute * 3));
+            assert_eq!(
+                history[SKEW_HISTORY_LEN - 1].skew(),
+                ClockSkew::Fast(minute * (SKEW_HISTORY_LEN as u32 + 2))
+            );
+        });
+    }
+
+    #[test]
+    fn export_and_import() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, _statemgr, netdir) = init(rt.clone());
+            guardmgr.install_test_netdir(&netdir);
+            let (id, mon, _usable) = guardmgr.select_guard(GuardUsage::default()).unwrap();
+            mon.succeeded();
+            guardmgr.flush_msg_queue().await;
+
+            let blob = guardmgr.export_state();
+            assert_eq!(blob.version(), 1);
+            let json = serde_json::to_string(&blob).unwrap();
+
+            // A fresh guard manager will get the same guard after importing.
+            let (guardmgr2, statemgr2, _) = init(rt.clone());
+            guardmgr2.install_test_netdir(&netdir);
+            let blob: GuardStateBlob = serde_json::from_str(&json).unwrap();
+            guardmgr2.import_state(blob).unwrap();
+            let (id2, _mon, _usable) = guardmgr2.select_guard(GuardUsage::default()).unwrap();
+            assert!(id2.same_relay_ids(&id));
+
+            // ... and so will one that loads its state.
+            drop(guardmgr2);
+            let guardmgr3 =
+                GuardMgr::new(rt.clone(), statemgr2.clone(), &TestConfig::default()).unwrap();
+            guardmgr3.install_test_netdir(&netdir);
+            let (id3, _mon, _usable) = guardmgr3.select_guard(GuardUsage::default()).unwrap();
+            assert!(id3.same_relay_ids(&id));
+
+            // We reject blobs from the future.
+            let mut value: serde_json::Value = serde_json::from_str(&json).unwrap();
+            value["version"] = 99.into();
+            let blob: GuardStateBlob = serde_json::from_value(value).unwrap();
+            assert!(!blob.is_supported());
+            assert!(matches!(
+                guardmgr3.import_state(blob),
+                Err(GuardMgrError::UnsupportedStateVersion { version: 99 })
+            ));
+
+            // We can't import without the lock on our state.
+            let statemgr4 = TestingStateMgr::new();
+            let guardmgr4 = GuardMgr::new(rt.clone(), statemgr4, &TestConfig::default()).unwrap();
+            let blob: GuardStateBlob = serde_json::from_str(&json).unwrap();
+            assert!(guardmgr4.import_state(blob).is_err());
+            assert!(guardmgr4.stored_samples().iter().all(|s| s.n_guards == 0));
+        });
+    }
+
+    #[test]
+    fn virtual_clock() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, _statemgr, netdir) = init(rt.clone());
+            let time =
+                |v: &serde_json::Value| humantime::parse_rfc3339(v.as_str().unwrap()).unwrap();
+            let start = humantime::parse_rfc3339("2030-01-01T00:00:00Z").unwrap();
+            let clock = tor_rtmock::simple_time::SimpleMockTimeProvider::from_wallclock(start);
+            guardmgr.install_time_provider(DynTimeProvider::new(clock.clone()));
+            // We keep our own reference to the provider, so that the guard
+            // manager can still see the directory after our clock jumps.
+            let provider: Arc<dyn NetDirProvider> =
+                Arc::new(tor_netdir::testprovider::TestNetDirProvider::from(netdir));
+            guardmgr.install_netdir_provider(&provider).unwrap();
+
+            let (id, mon, _usable) = guardmgr.select_guard(GuardUsage::default()).unwrap();
+            mon.succeeded();
+            guardmgr.flush_msg_queue().await; // avoid race
+            let state = serde_json::to_value(guardmgr.export_state()).unwrap();
+            let guards = state["samples"]["default"]["guards"].as_array().unwrap();
+            let id_rsa = id.rsa_identity().unwrap().to_string();
+            let confirmed = guards
+                .iter()
+                .find(|g| id_rsa.contains(g["id"]["rsa"].as_str().unwrap()))
+                .unwrap();
+            // Our guard was confirmed according to our clock, not the
+            // runtime's.  (We randomize the time a little, for privacy.)
+            let confirmed_at = time(&confirmed["confirmed_at"]);
+            assert!(confirmed_at <= start);
+            assert!(confirmed_at > start - Duration::from_secs(86400 * 30));
+
+            // Once our clock says that our guards are too old, we replace them.
+            clock.advance(Duration::from_secs(86400 * 200));
+            guardmgr.set_filter(GuardFilter::unfiltered());
+            let state = serde_json::to_value(guardmgr.export_state()).unwrap();
+            assert!(state["samples"]["default"]["confirmed"]
+                .as_array()
+                .unwrap()
+                .is_empty());
+            let guards = state["samples"]["default"]["guards"].as_array().unwrap();
+            assert!(!guards.is_empty());
+            let cutoff = start + Duration::from_secs(86400 * 150);
+            assert!(guards.iter().all(|g| time(&g["added_at"]) > cutoff));
+        });
+    }
+
+    #[test]
+    fn import_ctor_guards() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, _statemgr, netdir) = init(rt.clone());
+            let candidates: Vec<_> = netdir
+                .relays()
+                .filter(|r| r.low_level_details().is_suitable_as_guard())
+                .map(|r| *r.rsa_id())
+                .take(3)
+                .collect();
+            let hex_id = |id: &tor_llcrypto::pk::rsa::RsaIdentity| {
+                id.as_bytes()
+                    .iter()
+                    .map(|b| format!("{:02X}", b))
+                    .collect::<String>()
+            };
+            // C Tor writes times without a time zone.
+            let days_ago = |n: u64| {
+                let t = SystemTime::now() - Duration::from_secs(86400 * n);
+                humantime::format_rfc3339_seconds(t)
+                    .to_string()
+                    .trim_end_matches('Z')
+                    .to_owned()
+            };
+            // The third candidate is our first confirmed guard.
+            let state = format!(
+                "Guard in=default rsa_id={} sampled_on={} sampled_idx=0\n\
+                 Guard in=default rsa_id={} sampled_on={} sampled_idx=1 \
+                   confirmed_on={} confirmed_idx=1\n\
+                 Guard in=default rsa_id={} sampled_on={} sampled_idx=2 \
+                   confirmed_on={} confirmed_idx=0\n",
+                hex_id(&candidates[0]),
+                days_ago(10),
+                hex_id(&candidates[1]),
+                days_ago(10),
+                days_ago(2),
+                hex_id(&candidates[2]),
+                days_ago(10),
+                days_ago(3),
+            );
+            let blob = import::from_ctor_state_str(&state).unwrap();
+            guardmgr.import_state(blob).unwrap();
+            guardmgr.install_test_netdir(&netdir);
+
+            let (id, _mon, _usable) = guardmgr.select_guard(GuardUsage::default()).unwrap();
+            assert_eq!(id.rsa_identity(), Some(&candidates[2]));
+            // We learned the guard's ed25519 identity from the directory.
+            assert!(id.ed_identity().is_some());
+        });
+    }
+
+    #[test]
+    fn sample_weight_fraction() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, _statemgr, netdir) = init(rt.clone());
+            assert!(guardmgr.sample_weight_fraction().is_none());
+
+            // (We can't use install_test_netdir here, since we need to keep
+            // the provider alive.)
+            let provider: Arc<dyn NetDirProvider> =
+                Arc::new(tor_netdir::testprovider::TestNetDirProvider::from(netdir));
+            guardmgr.install_netdir_provider(&provider).unwrap();
+            {
+                let mut inner = guardmgr.inner.lock().unwrap();
+                let (wallclock, now) = inner.current_time();
+                inner.update(wallclock, now);
+            }
+            let frac = guardmgr.sample_weight_fraction().unwrap();
+            assert!(frac.fraction > 0.0);
+            assert!(frac.fraction <= 1.0);
+            assert_eq!(frac.max_fraction, 0.2);
+        });
+    }
+
+    #[test]
+    fn guard_stats() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, statemgr, netdir) = init(rt.clone());
+            let usage = GuardUsage::default();
+            guardmgr.install_test_netdir(&netdir);
+            assert!(guardmgr.guard_stats().iter().all(|e| e.n_attempts() == 0));
+
+            let (id1, mon, _usable) = guardmgr.select_guard(usage.clone()).unwrap();
+            mon.failed();
+            guardmgr.flush_msg_queue().await; // avoid race
+            let (id2, mon, _usable) = guardmgr.select_guard(usage.clone()).unwrap();
+            mon.succeeded();
+            guardmgr.flush_msg_queue().await; // avoid race
+            assert!(!id1.same_relay_ids(&id2));
+
+            let find = |stats: &[GuardStatsEntry], id: &FirstHop| {
+                stats
+                    .iter()
+                    .find(|e| e.ids().same_relay_ids(id))
+                    .unwrap()
+                    .clone()
+            };
+            let stats = guardmgr.guard_stats();
+            let e1 = find(&stats, &id1);
+            assert_eq!(e1.n_attempts(), 1);
+            assert_eq!(e1.n_failures(), 1);
+            assert_eq!(e1.success_rate(), Some(0.0));
+            assert!(e1.last_failure().is_some());
+            assert!(!e1.is_reachable());
+            let e2 = find(&stats, &id2);
+            assert_eq!(e2.n_attempts(), 1);
+            assert_eq!(e2.n_successes(), 1);
+            assert_eq!(e2.success_rate(), Some(1.0));
+            assert!(e2.mean_success_latency().is_some());
+            assert!(e2.is_reachable());
+
+            // The statistics persist across restarts.
+            guardmgr.store_persistent_state().unwrap();
+            drop(guardmgr);
+            let guardmgr2 =
+                GuardMgr::new(rt.clone(), statemgr.clone(), &TestConfig::default()).unwrap();
+            guardmgr2.install_test_netdir(&netdir);
+            let stats = guardmgr2.guard_stats();
+            assert_eq!(find(&stats, &id1).n_failures(), 1);
+            assert_eq!(find(&stats, &id2).n_successes(), 1);
+        });
+    }
+
+    #[test]
+    fn guard_info() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, _statemgr, netdir) = init(rt.clone());
+            let usage = GuardUsage::default();
+            assert!(guardmgr.primary_guards().is_empty());
+            assert!(guardmgr.sampled_guards().is_empty());
+
+            guardmgr.install_test_netdir(&netdir);
+            let params = GuardParams::default();
+            let primary = guardmgr.primary_guards();
+            let sampled = guardmgr.sampled_guards();
+            assert!(!primary.is_empty());
+            assert!(primary.len() <= params.n_primary);
+            assert!(sampled.len() >= primary.len());
+            for g in &primary {
+                assert!(g.is_primary());
+                assert!(!g.is_confirmed());
+                assert_eq!(g.reachability(), GuardReachability::Untried);
+                assert!(g.has_dir_info());
+                assert!(!g.addrs().is_empty());
+                assert!(sampled
+                    .iter()
+                    .any(|s| s.is_primary() && s.ids().same_relay_ids(g.ids())));
+            }
+            assert_eq!(
+                sampled.iter().filter(|g| g.is_primary()).count(),
+                primary.len()
+            );
+
+            // Failing to use a guard makes it unreachable; using one
+            // successfully confirms it.
+            let (id1, mon, _usable) = guardmgr.select_guard(usage.clone()).unwrap();
+            mon.failed();
+            guardmgr.flush_msg_queue().await; // avoid race
+            let (id2, mon, _usable) = guardmgr.select_guard(usage.clone()).unwrap();
+            mon.succeeded();
+            guardmgr.flush_msg_queue().await; // avoid race
+            assert!(!id1.same_relay_ids(&id2));
+
+            let find = |infos: &[GuardInfo], id: &FirstHop| {
+                infos
+                    .iter()
+                    .find(|g| g.ids().same_relay_ids(id))
+                    .unwrap()
+                    .clone()
+            };
+            let sampled = guardmgr.sampled_guards();
+            let g1 = find(&sampled, &id1);
+            assert!(!g1.is_confirmed());
+            assert_eq!(g1.reachability(), GuardReachability::Unreachable);
+            let g2 = find(&sampled, &id2);
+            assert!(g2.is_confirmed());
+            assert_eq!(g2.reachability(), GuardReachability::Reachable);
+            assert!(find(&guardmgr.primary_guards(), &id2).is_primary());
+        });
+    }
+
+    #[test]
+    fn failure_blame() {
+        test_with_all_runtimes!(|rt| async move {
+            use tor_cell::chancell::msg::DestroyReason;
+            let (guardmgr, _statemgr, netdir) = init(rt.clone());
+            let usage = GuardUsage::default();
+            guardmgr.install_test_netdir(&netdir);
+            let reachability = |id: &FirstHop| {
+                guardmgr
+                    .sampled_guards()
+                    .iter()
+                    .find(|g| g.ids().same_relay_ids(id))
+                    .unwrap()
+                    .reachability()
+            };
+
+            // A circuit that failed beyond the guard doesn't make it unreachable.
+            let (id1, mon, _usable) = guardmgr.select_guard(usage.clone()).unwrap();
+            mon.failed_with(CircuitFailureCause::Destroyed {
+                hops_completed: 1,
+                reason: DestroyReason::CONNECTFAILED,
+            });
+            guardmgr.flush_msg_queue().await; // avoid race
+            assert_ne!(reachability(&id1), GuardReachability::Unreachable);
+
+            // A failed handshake does, even if it was reported as indeterminate.
+            let (id2, mut mon, _usable) = guardmgr.select_guard(usage.clone()).unwrap();
+            mon.cause(CircuitFailureCause::TlsHandshakeFailed);
+            mon.report(GuardStatus::Indeterminate);
+            guardmgr.flush_msg_queue().await; // avoid race
+            assert_eq!(reachability(&id2), GuardReachability::Unreachable);
+        });
+    }
+
+    #[test]
+    fn provisional_first_hop() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, statemgr, netdir) = init(rt.clone());
+            let usage = GuardUsage::default();
+            // We have no confirmed guards yet.
+            assert!(guardmgr.provisional_first_hop().is_none());
+
+            guardmgr.install_test_netdir(&netdir);
+            let (id, mon, _usable) = guardmgr.select_guard(usage).unwrap();
+            assert!(!id.is_provisional());
+            mon.succeeded();
+            guardmgr.flush_msg_queue().await; // avoid race
+            guardmgr.store_persistent_state().unwrap();
+            drop(guardmgr);
+
+            // After a restart, we can use our confirmed guard before we have a
+            // directory.
+            let guardmgr2 =
+                GuardMgr::new(rt.clone(), statemgr.clone(), &TestConfig::default()).unwrap();
+            let hop = guardmgr2.provisional_first_hop().unwrap();
+            assert!(hop.is_provisional());
+            assert!(hop.same_relay_ids(&id));
+            assert!(!hop.addrs().is_empty());
+
+            // Once we have a directory, there's no need to be provisional.
+            // (We can't use install_test_netdir here, since we need to keep
+            // the provider alive.)
+            let provider: Arc<dyn NetDirProvider> =
+                Arc::new(tor_netdir::testprovider::TestNetDirProvider::from(netdir));
+            guardmgr2.install_netdir_provider(&provider).unwrap();
+            assert!(guardmgr2.provisional_first_hop().is_none());
+        });
+    }
+
+    #[test]
+    fn directory_pairing() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, statemgr, netdir) = init(rt.clone());
+            guardmgr.install_test_netdir(&netdir);
+            let digest = [1; 32];
+            assert_eq!(
+                guardmgr.check_directory_pairing(&netdir, &digest),
+                DirectoryPairing::Unanchored
+            );
+
+            let anchor = DirectoryAnchor::new(digest, netdir.lifetime().valid_after());
+            guardmgr.set_directory_anchor(Some(anchor.clone()));
+            assert_eq!(
+                guardmgr.check_directory_pairing(&netdir, &digest),
+                DirectoryPairing::Consistent
+            );
+            assert_eq!(
+                guardmgr.check_directory_pairing(&netdir, &[2; 32]),
+                DirectoryPairing::Changed {
+                    saved: anchor.clone()
+                }
+            );
+
+            // A directory that lacks a primary guard's microdescriptor doesn't
+            // cover our primary guards, whatever its digest.
+            let primary = guardmgr.primary_guards();
+            let missing_idx = primary[0].ids().rsa_identity().unwrap().as_bytes()[0];
+            let netdir2 = tor_netdir::testnet::construct_custom_netdir(|idx, node, _| {
+                if idx == usize::from(missing_idx) {
+                    node.omit_md = true;
+                }
+            })
+            .unwrap()
+            .unwrap_if_sufficient()
+            .unwrap();
+            let pairing = guardmgr.check_directory_pairing(&netdir2, &digest);
+            assert_eq!(
+                pairing,
+                DirectoryPairing::MissingPrimaryGuards { n_missing: 1 }
+            );
+            assert!(!pairing.covers_primary_guards());
+
+            // The anchor persists across restarts.
+            guardmgr.store_persistent_state().unwrap();
+            drop(guardmgr);
+            let guardmgr2 =
+                GuardMgr::new(rt.clone(), statemgr.clone(), &TestConfig::default()).unwrap();
+            assert_eq!(guardmgr2.directory_anchor(), Some(anchor));
+        });
+    }
+
+    #[test]
+    fn record_and_replay() {
+        use testing::replay::{replay, ReplayEvent, ReplayLog, ReplayStatus};
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, _statemgr, netdir) = init(rt);
+            let usage = GuardUsage::default();
+            guardmgr.start_recording();
+            guardmgr.install_test_netdir(&netdir);
+
+            let (_, mon, _) = guardmgr.select_guard(usage.clone()).unwrap();
+            mon.failed();
+            guardmgr.flush_msg_queue().await; // avoid race
+            let (_, mon, _) = guardmgr.select_guard(usage.clone()).unwrap();
+            mon.succeeded();
+            guardmgr.flush_msg_queue().await; // avoid race
+            let (_, _mon, _) = guardmgr.select_guard(usage).unwrap();
+            guardmgr.set_filter(GuardFilter::unfiltered());
+
+            let log = guardmgr.finish_recording().unwrap();
+            assert!(guardmgr.finish_recording().is_none());
+            let kinds: Vec<_> = log
+                .events
+                .iter()
+                .map(|e| match &e.event {
+                    ReplayEvent::NetDir { .. } => "netdir",
+                    ReplayEvent::SetFilter { .. } => "filter",
+                    ReplayEvent::Select { .. } => "select",
+                    ReplayEvent::Outcome { .. } => "outcome",
+                })
+                .collect();
+            assert_eq!(
+                kinds,
+                ["netdir", "select", "outcome", "select", "outcome", "select", "filter"]
+            );
+            let ReplayEvent::Outcome { attempt, status } = &log.events[4].event else {
+                panic!("not an outcome");
+            };
+            assert_eq!((*attempt, *status), (1, ReplayStatus::Success));
+
+            // The log is redacted: it doesn't mention any real identities or
+            // addresses.
+            let json = serde_json::to_string(&log).unwrap();
+            for relay in netdir.relays() {
+                let rsa = relay.rsa_identity().unwrap().to_string();
+                assert!(!json.contains(rsa.trim_start_matches('$')));
+            }
+            let ReplayEvent::NetDir { relays, .. } = &log.events[0].event else {
+                panic!("not a netdir");
+            };
+            assert_eq!(relays.len(), netdir.relays().count());
+            for spec in relays {
+                assert!(netdir
+                    .relays()
+                    .all(|r| !r.addrs().contains(&SocketAddr::from((spec.addr, 9001)))));
+            }
+
+            // We can replay it exactly, and get the same results every time.
+            let log: ReplayLog = serde_json::from_str(&json).unwrap();
+            let outcome = replay(&log).unwrap();
+            assert_eq!(outcome.n_diverged, 0);
+            let picked: Vec<u32> = log
+                .events
+                .iter()
+                .filter_map(|e| match &e.event {
+                    ReplayEvent::Select { guard, .. } => Some(guard.unwrap()),
+                    _ => None,
+                })
+                .collect();
+            // The second attempt succeeded, so its guard is our only
+            // confirmed guard.
+            assert_eq!(outcome.snapshot.confirmed, vec![picked[1]]);
+            assert!(outcome.snapshot.sample.contains(&picked[0]));
+            assert_eq!(replay(&log).unwrap().snapshot, outcome.snapshot);
+        });
+    }
+
     #[test]
     fn simple_waiting() {
         // TODO(nickm): This test fails in rare cases; I suspect a
@@ -2062,6 +4169,532 @@ mod test {
         });
     }
 
+    #[test]
+    fn required_guard() {
+        test_with_all_runtimes!(|rt| async move {
+            use tor_netdir::testprovider::TestNetDirProvider;
+            let (guardmgr, _statemgr, netdir) = init(rt);
+            // (We keep the provider alive, so that the guard manager can look
+            // up guards that aren't in its sample.)
+            let provider: Arc<dyn NetDirProvider> =
+                Arc::new(TestNetDirProvider::from(netdir.clone()));
+            guardmgr.install_netdir_provider(&provider).unwrap();
+            {
+                let mut inner = guardmgr.inner.lock().unwrap();
+                let (wallclock, now) = inner.current_time();
+                inner.update(wallclock, now);
+            }
+            let require = |id: RelayId| {
+                let mut u = GuardUsageBuilder::new();
+                u.restrictions().push(GuardRestriction::RequireId(id));
+                u.build().unwrap()
+            };
+
+            // Every possible guard can be required, whether or not it was
+            // already in our sample.
+            let guard_ids: Vec<RelayId> = netdir
+                .relays()
+                .filter(|r| r.low_level_details().is_suitable_as_guard())
+                .map(|r| (*r.rsa_id()).into())
+                .collect();
+            assert!(guard_ids.len() > 5);
+            for id in &guard_ids {
+                let (guard, mon, _usable) = guardmgr.select_guard(require(id.clone())).unwrap();
+                assert!(guard.has_identity(id.as_ref()));
+                mon.attempt_abandoned();
+            }
+
+            // We don't substitute another guard for one we can't use.
+            let missing = RelayId::Rsa([0xff; 20].into());
+            assert!(guardmgr.select_guard(require(missing)).is_err());
+            let not_a_guard = netdir
+                .relays()
+                .find(|r| !r.low_level_details().is_suitable_as_guard())
+                .unwrap();
+            let not_a_guard = RelayId::from(*not_a_guard.rsa_id());
+            assert!(guardmgr.select_guard(require(not_a_guard.clone())).is_err());
+            let mut u = GuardUsageBuilder::new();
+            u.kind(GuardUsageKind::OneHopDirectory);
+            u.restrictions()
+                .push(GuardRestriction::RequireId(not_a_guard));
+            assert!(guardmgr.select_guard(u.build().unwrap()).is_err());
+        });
+    }
+
+    #[test]
+    fn batch_reports() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, _statemgr, netdir) = init(rt);
+            let u = GuardUsage::default();
+            guardmgr.install_test_netdir(&netdir);
+
+            // A failure reported in a batch is handled like any other.
+            let (id1, mon, _usable) = guardmgr.select_guard(u.clone()).unwrap();
+            GuardMonitor::report_batch([(mon, GuardStatus::Failure)]);
+            guardmgr.flush_msg_queue().await;
+            let (id2, mon, _usable) = guardmgr.select_guard(u.clone()).unwrap();
+            assert!(!id1.same_relay_ids(&id2));
+            mon.attempt_abandoned();
+
+            // Report a batch that's too big to handle under a single lock.
+            let monitors: Vec<_> = (0..40)
+                .map(|_| {
+                    let (_id, mon, _usable) = guardmgr.select_guard(u.clone()).unwrap();
+                    (mon, GuardStatus::AttemptAbandoned)
+                })
+                .collect();
+            GuardMonitor::report_batch(monitors);
+            guardmgr.flush_msg_queue().await;
+
+            let stats = guardmgr.status_queue_stats();
+            assert_eq!(stats.pending, 0);
+            assert!(stats.max_pending >= 40);
+            assert_eq!(stats.n_batches, 2);
+            assert_eq!(stats.n_handled, 42);
+            assert!(guardmgr.inner.lock().unwrap().pending.is_empty());
+        });
+    }
+
+    #[test]
+    fn dir_guards() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, _statemgr, netdir) = init(rt);
+
+            // With no netdir and no fallbacks, we have nothing to offer.
+            assert!(guardmgr.preferred_dir_guards(3).is_empty());
+
+            // With fallbacks but no netdir, we offer only fallbacks.
+            let cfg = TestConfig {
+                fallbacks: fallback::FallbackListBuilder::default().build().unwrap(),
+                ..Default::default()
+            };
+            let _ = guardmgr.reconfigure(&cfg).unwrap();
+            let hops = guardmgr.preferred_dir_guards(3);
+            assert!(!hops.is_empty());
+            assert!(hops.len() <= 3);
+            assert!(hops.iter().all(|h| h.sample.is_none()));
+
+            guardmgr.install_test_netdir(&netdir);
+            let dir_usage = GuardUsageBuilder::new()
+                .kind(GuardUsageKind::OneHopDirectory)
+                .build()
+                .unwrap();
+            let hops = guardmgr.preferred_dir_guards(10);
+            // Only primary guards, limited by dir_parallelism.
+            let params = guardmgr.inner.lock().unwrap().params.clone();
+            assert!(!hops.is_empty());
+            assert!(hops.len() <= params.dir_parallelism);
+            assert!(hops.iter().all(|h| h.sample.is_some()));
+            for (i, a) in hops.iter().enumerate() {
+                assert!(hops[i + 1..].iter().all(|b| !a.same_relay_ids(b)));
+            }
+            assert_eq!(guardmgr.preferred_dir_guards(1).len(), 1);
+            assert!(guardmgr.preferred_dir_guards(0).is_empty());
+
+            // Whatever select_guard picks should be one of the preferred guards.
+            let (id, mon, _usable) = guardmgr.select_guard(dir_usage).unwrap();
+            assert!(hops.iter().any(|h| h.same_relay_ids(&id)));
+            mon.attempt_abandoned();
+        });
+    }
+
+    #[test]
+    fn authorities_only() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, _statemgr, _netdir) = init(rt);
+            let dir_usage = GuardUsageBuilder::new()
+                .kind(GuardUsageKind::OneHopDirectory)
+                .build()
+                .unwrap();
+
+            let auth = |n: u8| {
+                let mut bld = fallback::FallbackDir::builder();
+                bld.rsa_identity([n; 20].into())
+                    .ed_identity([n; 32].into())
+                    .orports()
+                    .push(std::net::SocketAddrV4::new([10, 0, 0, n].into(), 9090).into());
+                bld.build().unwrap()
+            };
+            let authority_dirs = fallback::AuthorityDirList::from(vec![auth(1), auth(2)]);
+            let is_auth = |hop: &FirstHop| {
+                hop.rsa_identity() == Some(&[1; 20].into())
+                    || hop.rsa_identity() == Some(&[2; 20].into())
+            };
+
+            // Without the flag, we use the fallbacks, and not the authorities.
+            let mut cfg = TestConfig {
+                fallbacks: fallback::FallbackListBuilder::default().build().unwrap(),
+                authority_dirs,
+                ..Default::default()
+            };
+            let _ = guardmgr.reconfigure(&cfg).unwrap();
+            let hops = guardmgr.preferred_dir_guards(100);
+            assert!(!hops.is_empty());
+            assert!(!hops.iter().any(is_auth));
+
+            // With the flag, we only use the authorities.
+            cfg.authorities_only = true;
+            let _ = guardmgr.reconfigure(&cfg).unwrap();
+            let hops = guardmgr.preferred_dir_guards(100);
+            assert_eq!(hops.len(), 2);
+            assert!(hops.iter().all(is_auth));
+
+            // A failure on an authority is tracked on the authority list.
+            let (id, mon, _usable) = guardmgr.select_guard(dir_usage.clone()).unwrap();
+            assert!(is_auth(&id));
+            mon.failed();
+            guardmgr.flush_msg_queue().await; // avoid race
+            guardmgr.flush_msg_queue().await; // avoid race
+            let hops = guardmgr.preferred_dir_guards(100);
+            assert_eq!(hops.len(), 1);
+            assert!(!hops[0].same_relay_ids(&id));
+
+            // Once both authorities have failed, we don't go back to the fallbacks.
+            let (id2, mon, _usable) = guardmgr.select_guard(dir_usage.clone()).unwrap();
+            assert!(is_auth(&id2));
+            mon.failed();
+            guardmgr.flush_msg_queue().await; // avoid race
+            guardmgr.flush_msg_queue().await; // avoid race
+            assert!(guardmgr.preferred_dir_guards(100).is_empty());
+            assert!(matches!(
+                guardmgr.select_guard(dir_usage),
+                Err(PickGuardError::AllFallbacksDown { .. })
+            ));
+
+            // Turning the flag off puts us back on the fallbacks, whose
+            // status is unaffected.
+            cfg.authorities_only = false;
+            let _ = guardmgr.reconfigure(&cfg).unwrap();
+            let hops = guardmgr.preferred_dir_guards(100);
+            assert!(!hops.is_empty());
+            assert!(!hops.iter().any(is_auth));
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "bridge-client")]
+    fn bridge_reconfigure_retires() {
+        use bridge::BridgeConfig;
+
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, _statemgr, _netdir) = init(rt);
+            let bridges: Vec<BridgeConfig> = [
+                "38.229.33.83:80 0bac39417268b96b9f514e7f63fa6fba1a788955",
+                "198.51.100.7:443 1bac39417268b96b9f514e7f63fa6fba1a788955",
+                "198.51.100.8:443 2bac39417268b96b9f514e7f63fa6fba1a788955",
+            ]
+            .iter()
+            .map(|line| line.parse().unwrap())
+            .collect();
+            let with_bridges = |idxs: &[usize]| TestConfig {
+                bridges: idxs.iter().map(|i| bridges[*i].clone()).collect(),
+                ..Default::default()
+            };
+
+            // Starting to use bridges retires everything.
+            let retire = guardmgr.reconfigure(&with_bridges(&[0, 1])).unwrap();
+            assert_eq!(retire, RetireCircuits::All);
+            // Adding a bridge retires nothing.
+            let retire = guardmgr.reconfigure(&with_bridges(&[0, 1, 2])).unwrap();
+            assert_eq!(retire, RetireCircuits::None);
+            // Removing a bridge retires only the circuits through it.
+            let retire = guardmgr.reconfigure(&with_bridges(&[0, 2])).unwrap();
+            assert_eq!(
+                retire,
+                RetireCircuits::Some(vec![RelayIds::from_relay_ids(&bridges[1])])
+            );
+            // Stopping using bridges retires everything.
+            let retire = guardmgr.reconfigure(&TestConfig::default()).unwrap();
+            assert_eq!(retire, RetireCircuits::All);
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "bridge-client")]
+    fn bridge_contexts() {
+        use bridge::BridgeConfig;
+
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, _statemgr, _netdir) = init(rt);
+            let before = GuardContextId::new("before").unwrap();
+            let after = GuardContextId::new("after").unwrap();
+            let active_set = |id: &GuardContextId| {
+                let inner = guardmgr.inner.lock().unwrap();
+                inner.contexts[id].guards.active_set.clone()
+            };
+            let bridge: BridgeConfig = "38.229.33.83:80 0bac39417268b96b9f514e7f63fa6fba1a788955"
+                .parse()
+                .unwrap();
+            let with_bridges = TestConfig {
+                bridges: vec![bridge],
+                ..Default::default()
+            };
+
+            // A context that exists when we turn on bridges starts using them.
+            guardmgr.add_context(&before).unwrap();
+            assert_eq!(active_set(&before), GuardSetSelector::Default);
+            let _ = guardmgr.reconfigure(&with_bridges).unwrap();
+            assert_eq!(active_set(&before), GuardSetSelector::Bridges);
+
+            // So does a context that we add afterwards.
+            guardmgr.add_context(&after).unwrap();
+            assert_eq!(active_set(&after), GuardSetSelector::Bridges);
+
+            // When we turn bridges off, every context stops using them.
+            let _ = guardmgr.reconfigure(&TestConfig::default()).unwrap();
+            assert_eq!(active_set(&before), GuardSetSelector::Default);
+            assert_eq!(active_set(&after), GuardSetSelector::Default);
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "bridge-client")]
+    fn bridge_status() {
+        use bridge::{BridgeConfig, BridgeDescError, BridgeDescEvent, BridgeDescList};
+        use futures::stream::BoxStream;
+
+        /// An error for a bridge whose descriptor we couldn't fetch.
+        #[derive(Clone, Debug, thiserror::Error)]
+        #[error("no descriptor for you")]
+        struct NoDesc;
+        impl tor_error::HasKind for NoDesc {
+            fn kind(&self) -> tor_error::ErrorKind {
+                tor_error::ErrorKind::TorAccessFailed
+            }
+        }
+        impl tor_error::HasRetryTime for NoDesc {
+            fn retry_time(&self) -> tor_error::RetryTime {
+                tor_error::RetryTime::Never
+            }
+        }
+        impl BridgeDescError for NoDesc {}
+
+        /// A provider that has failed to get a descriptor for one bridge.
+        #[derive(Clone)]
+        struct Provider(Arc<BridgeDescList>);
+        impl bridge::BridgeDescProvider for Provider {
+            fn bridges(&self) -> Arc<BridgeDescList> {
+                self.0.clone()
+            }
+            fn events(&self) -> BoxStream<'static, BridgeDescEvent> {
+                Box::pin(futures::stream::pending())
+            }
+            fn set_bridges(&self, _bridges: &[BridgeConfig]) {}
+        }
+
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, _statemgr, _netdir) = init(rt);
+            assert!(guardmgr.bridge_status().is_empty());
+
+            let bridges: Vec<BridgeConfig> = [
+                "38.229.33.83:80 0bac39417268b96b9f514e7f63fa6fba1a788955",
+                "198.51.100.7:443 1bac39417268b96b9f514e7f63fa6fba1a788955",
+            ]
+            .iter()
+            .map(|line| line.parse().unwrap())
+            .collect();
+            let cfg = TestConfig {
+                bridges: bridges.clone(),
+                ..Default::default()
+            };
+            let _ = guardmgr.reconfigure(&cfg).unwrap();
+
+            // Without a descriptor provider, we know nothing about
+            // descriptors, and haven't tried to use either bridge.
+            let status = guardmgr.bridge_status();
+            assert_eq!(status.len(), 2);
+            for (st, bridge) in status.iter().zip(&bridges) {
+                assert_eq!(st.bridge(), bridge);
+                assert!(st.transport().is_builtin());
+                assert!(matches!(st.descriptor(), bridge::BridgeDescStatus::Unknown));
+                assert!(!st.has_descriptor());
+                assert!(st.last_attempt().is_none());
+                assert!(st.next_retry().is_none());
+            }
+
+            let descs: BridgeDescList = [(
+                bridges[1].clone(),
+                Err(Box::new(NoDesc) as Box<dyn BridgeDescError>),
+            )]
+            .into_iter()
+            .collect();
+            let provider: Arc<dyn bridge::BridgeDescProvider> = Arc::new(Provider(Arc::new(descs)));
+            guardmgr.install_bridge_desc_provider(&provider).unwrap();
+
+            // Try one of the bridges, and fail.
+            let dir_usage = GuardUsageBuilder::new()
+                .kind(GuardUsageKind::OneHopDirectory)
+                .build()
+                .unwrap();
+            let (id, mon, _usable) = guardmgr.select_guard(dir_usage).unwrap();
+            mon.failed();
+            guardmgr.flush_msg_queue().await;
+
+            let status = guardmgr.bridge_status();
+            assert!(matches!(
+                status[0].descriptor(),
+                bridge::BridgeDescStatus::Unknown
+            ));
+            assert!(matches!(
+                status[1].descriptor(),
+                bridge::BridgeDescStatus::Failed(_)
+            ));
+            let tried = status
+                .iter()
+                .find(|st| id.same_relay_ids(st.bridge()))
+                .unwrap();
+            assert_eq!(tried.reachability(), Some(GuardReachability::Unreachable));
+            assert!(tried.last_attempt().is_some());
+            assert!(tried.next_retry().is_some());
+        });
+    }
+
+    #[test]
+    fn param_overrides() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, _statemgr, netdir) = init(rt);
+            let day = Duration::from_secs(86400);
+            let params = || guardmgr.inner.lock().unwrap().params.clone();
+
+            // Overrides take effect before we have a directory...
+            let mut cfg = TestConfig::default();
+            cfg.param_overrides.n_primary = Some(4);
+            cfg.param_overrides.lifetime_confirmed = Some(day * 2);
+            let _ = guardmgr.reconfigure(&cfg).unwrap();
+            assert_eq!(params().n_primary, 4);
+            assert_eq!(params().lifetime_confirmed, day * 2);
+            assert_eq!(params().max_sample_size, 60);
+
+            // ... and after.  We keep our own reference to the provider, so
+            // that the guard manager can still see the directory when we
+            // reconfigure.
+            let provider: Arc<dyn NetDirProvider> =
+                Arc::new(tor_netdir::testprovider::TestNetDirProvider::from(netdir));
+            guardmgr.install_netdir_provider(&provider).unwrap();
+            cfg.param_overrides.lifetime_confirmed = Some(day * 3);
+            let _ = guardmgr.reconfigure(&cfg).unwrap();
+            assert_eq!(params().n_primary, 4);
+            assert_eq!(params().lifetime_confirmed, day * 3);
+            assert_eq!(params().min_filtered_sample_size, 5);
+
+            // Out-of-range values are clamped.
+            cfg.param_overrides.n_primary = Some(0);
+            cfg.param_overrides.lifetime_confirmed = Some(Duration::from_secs(1));
+            let _ = guardmgr.reconfigure(&cfg).unwrap();
+            assert_eq!(params().n_primary, 1);
+            assert_eq!(params().lifetime_confirmed, Duration::from_secs(3600));
+
+            // Removing the overrides puts the consensus parameters back.
+            cfg.param_overrides = GuardParamOverrides::default();
+            assert!(cfg.param_overrides.is_empty());
+            let _ = guardmgr.reconfigure(&cfg).unwrap();
+            assert_eq!(params().n_primary, 2);
+            assert_eq!(params().lifetime_confirmed, day * 60);
+        });
+    }
+
+    #[cfg(feature = "geoip")]
+    #[test]
+    fn country_restrictions() {
+        test_with_all_runtimes!(|rt| async move {
+            use tor_geoip::{CountryCode, GeoipDb};
+            let (guardmgr, _statemgr, _netdir) = init(rt);
+
+            // All the addresses in the test network are {0,1,2,3,4}.0.0.3:9001.
+            // Put 1.0.0.0/8 in the US.
+            let db = GeoipDb::new_from_legacy_format("16777216,33554431,US", "").unwrap();
+            let netdir = tor_netdir::testnet::construct_custom_netdir_with_geoip(|_, _, _| {}, &db)
+                .unwrap()
+                .unwrap_if_sufficient()
+                .unwrap();
+            let us: CountryCode = "US".parse().unwrap();
+            let us_addr: std::net::SocketAddr = "1.0.0.3:9001".parse().unwrap();
+
+            let mut cfg = TestConfig::default();
+            cfg.countries.required = vec![us];
+            let _ = guardmgr.reconfigure(&cfg).unwrap();
+            guardmgr.install_test_netdir(&netdir);
+            for _ in 0..5 {
+                let (guard, mon, _usable) = guardmgr.select_guard(GuardUsage::default()).unwrap();
+                assert_eq!(guard.addrs(), &[us_addr]);
+                mon.attempt_abandoned();
+            }
+
+            // Changing the configuration changes which guards we pick.
+            cfg.countries.required = vec![];
+            cfg.countries.excluded = vec![us];
+            let _ = guardmgr.reconfigure(&cfg).unwrap();
+            for _ in 0..5 {
+                let (guard, mon, _usable) = guardmgr.select_guard(GuardUsage::default()).unwrap();
+                assert_ne!(guard.addrs(), &[us_addr]);
+                mon.attempt_abandoned();
+            }
+        });
+    }
+
+    #[test]
+    fn prune_unrecognized_samples() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(2_000_000_000);
+        let day = Duration::from_secs(86400);
+        let json = format!(
+            r#"{{
+                "default": {{ "guards": [], "confirmed": [] }},
+                "legacy": {{ "guards": [ {{ "x": 1 }} ], "confirmed": [] }},
+                "recent": {{ "guards": [], "meta": {{ "version": 7, "last_used": "{}" }} }},
+                "stale": {{ "guards": [], "meta": {{ "version": 7, "last_used": "{}" }} }},
+                "not_a_sample": 17
+            }}"#,
+            humantime::format_rfc3339(now - day),
+            humantime::format_rfc3339(now - day * 400),
+        );
+        let mut sets: GuardSets = serde_json::from_str(&json).unwrap();
+
+        let find = |sets: &GuardSets, name: &str| {
+            sets.stored_samples()
+                .into_iter()
+                .find(|info| info.name == name)
+        };
+        let default = find(&sets, "default").unwrap();
+        assert!(default.recognized);
+        assert_eq!(default.version, sample::SAMPLE_FORMAT_VERSION);
+        let legacy = find(&sets, "legacy").unwrap();
+        assert!(!legacy.recognized);
+        assert_eq!(legacy.version, 0);
+        assert_eq!(legacy.last_used, None);
+        assert_eq!(legacy.n_guards, 1);
+        let recent = find(&sets, "recent").unwrap();
+        assert_eq!(recent.version, 7);
+        assert_eq!(recent.last_used, Some(now - day));
+        assert!(find(&sets, "not_a_sample").is_none());
+
+        // Only the stale sample is too old; the legacy sample's clock starts now.
+        assert_eq!(
+            sets.prune_unrecognized(&SamplePrunePolicy::default(), now),
+            1
+        );
+        assert!(find(&sets, "stale").is_none());
+        assert_eq!(find(&sets, "legacy").unwrap().last_used, Some(now));
+
+        // That survives a round trip through the state file.
+        let mut sets: GuardSets =
+            serde_json::from_str(&serde_json::to_string(&sets).unwrap()).unwrap();
+        assert_eq!(find(&sets, "legacy").unwrap().last_used, Some(now));
+        assert_eq!(
+            sets.prune_unrecognized(&SamplePrunePolicy::keep_all(), now),
+            0
+        );
+
+        // When there are too many, we keep the most recently used.
+        let policy = SamplePrunePolicy {
+            max_unused: None,
+            max_unrecognized: Some(1),
+        };
+        assert_eq!(sets.prune_unrecognized(&policy, now), 1);
+        assert!(find(&sets, "legacy").is_some());
+        assert!(find(&sets, "recent").is_none());
+        assert!(sets.remaining.contains_key("not_a_sample"));
+    }
+
     #[test]
     fn filtering_basics() {
         test_with_all_runtimes!(|rt| async move {
@@ -2083,6 +4716,132 @@ mod test {
         });
     }
 
+    #[test]
+    fn primary_guard_events() {
+        test_with_all_runtimes!(|rt| async move {
+            use futures::{FutureExt as _, StreamExt as _};
+            let (guardmgr, _statemgr, netdir) = init(rt);
+            let mut hidden = guardmgr.primary_guard_events(GuardIdDisclosure::Withhold);
+            let mut revealed = guardmgr.primary_guard_events(GuardIdDisclosure::Reveal);
+            let drain = |events: &mut PrimaryGuardEvents| {
+                std::iter::from_fn(|| events.next().now_or_never().flatten()).collect::<Vec<_>>()
+            };
+
+            guardmgr.install_test_netdir(&netdir);
+            let primary = {
+                let inner = guardmgr.inner.lock().unwrap();
+                inner.last_primary_guard.clone().unwrap()
+            };
+            let ev = drain(&mut hidden);
+            assert_eq!(ev.len(), 1);
+            assert_eq!(ev[0].epoch(), 1);
+            assert!(ev[0].has_primary_guard());
+            assert!(ev[0].relay_ids().is_none());
+            let ev = drain(&mut revealed);
+            assert_eq!(ev.len(), 1);
+            assert_eq!(ev[0].relay_ids(), Some(&primary.0));
+
+            // Nothing happens if the primary guard stays the same.
+            guardmgr.set_filter(GuardFilter::default());
+            assert!(drain(&mut hidden).is_empty());
+            assert!(drain(&mut revealed).is_empty());
+
+            // Pretend that we had no primary guard before, so that the current
+            // one counts as a change.
+            {
+                let mut inner = guardmgr.inner.lock().unwrap();
+                inner.last_primary_guard = None;
+                inner.notify_primary_guard_change();
+            }
+            let ev = drain(&mut revealed);
+            assert_eq!(ev.len(), 1);
+            assert_eq!(ev[0].epoch(), 2);
+            assert_eq!(ev[0].relay_ids(), Some(&primary.0));
+            assert_eq!(drain(&mut hidden)[0].epoch(), 2);
+
+            // Dropped receivers are forgotten the next time there's a change.
+            drop(hidden);
+            drop(revealed);
+            let mut inner = guardmgr.inner.lock().unwrap();
+            inner.last_primary_guard = None;
+            inner.notify_primary_guard_change();
+            assert!(inner.send_primary_changes.is_empty());
+            assert_eq!(inner.primary_guard_epoch, 3);
+        });
+    }
+
+    #[test]
+    fn reachability_inference() {
+        test_with_all_runtimes!(|rt| async move {
+            use futures::{FutureExt as _, StreamExt as _};
+            let (guardmgr, _statemgr, netdir) = init(rt);
+            let mut events = guardmgr.unreachable_addr_events();
+            let mut cfg = TestConfig::default();
+            cfg.reachability_inference.enabled = true;
+            cfg.reachability_inference.min_failed_guards = 2;
+            let _ = guardmgr.reconfigure(&cfg).unwrap();
+            guardmgr.install_test_netdir(&netdir);
+
+            // Every guard in the test network is on port 9001.
+            for _ in 0..3 {
+                let (guard, mon, _usable) = guardmgr.select_guard(GuardUsage::default()).unwrap();
+                assert_eq!(guard.addrs()[0].port(), 9001);
+                mon.failed();
+                // Make sure we record the failure before we pick again, so
+                // that we don't pick the same guard twice.
+                guardmgr.flush_msg_queue().await;
+            }
+            // Nothing has worked yet, so maybe we're just offline.
+            assert!(events.next().now_or_never().is_none());
+
+            // Once something on another port works, we stop using port 9001.
+            let elsewhere: SocketAddr = "192.0.2.1:443".parse().unwrap();
+            let other_guard = RelayIds::builder()
+                .rsa_identity([0xAA; 20].into())
+                .build()
+                .unwrap();
+            let note_success = |net_has_been_down| {
+                guardmgr.inner.lock().unwrap().note_reachability(
+                    &other_guard,
+                    &[elsewhere],
+                    GuardStatus::Success,
+                    net_has_been_down,
+                );
+            };
+            note_success(false);
+            let ev = events.next().now_or_never().flatten().unwrap();
+            assert_eq!(ev.port(), 9001);
+            assert!(!ev.is_ipv6());
+            assert_eq!(ev.n_failed_guards(), 3);
+            assert!(guardmgr.select_guard(GuardUsage::default()).is_err());
+
+            // If the network comes back after being down, we forget what we
+            // inferred, since it might be a different network.
+            note_success(true);
+            assert!(guardmgr.select_guard(GuardUsage::default()).is_ok());
+
+            // Turning inference off forgets what we inferred.
+            for n in 1..=2 {
+                let guard = RelayIds::builder()
+                    .rsa_identity([n; 20].into())
+                    .build()
+                    .unwrap();
+                let addr: SocketAddr = format!("192.0.2.{}:9001", n).parse().unwrap();
+                guardmgr.inner.lock().unwrap().note_reachability(
+                    &guard,
+                    &[addr],
+                    GuardStatus::Failure,
+                    false,
+                );
+            }
+            assert_eq!(events.next().now_or_never().flatten().unwrap().port(), 9001);
+            assert!(guardmgr.select_guard(GuardUsage::default()).is_err());
+            cfg.reachability_inference.enabled = false;
+            let _ = guardmgr.reconfigure(&cfg).unwrap();
+            assert!(guardmgr.select_guard(GuardUsage::default()).is_ok());
+        });
+    }
+
     #[test]
     fn external_status() {
         test_with_all_runtimes!(|rt| async move {
@@ -2134,4 +4893,105 @@ mod test {
         assert!(VanguardMode::Disabled < VanguardMode::Full);
         assert!(VanguardMode::Lite < VanguardMode::Full);
     }
+
+    #[test]
+    fn prober() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// A probe that can reach every guard, and counts how often it's asked.
+        struct AlwaysReachable(AtomicUsize);
+
+        #[async_trait::async_trait]
+        impl GuardProbe for AlwaysReachable {
+            async fn probe(&self, _guard: &GuardInfo) -> bool {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                true
+            }
+        }
+
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let (guardmgr, _statemgr, netdir) = init(rt.clone());
+            guardmgr.install_test_netdir(&netdir);
+
+            let (_id, mon, _usable) = guardmgr.select_guard(GuardUsage::default()).unwrap();
+            mon.failed();
+            guardmgr.flush_msg_queue().await; // avoid race
+            let n_unreachable = || {
+                guardmgr
+                    .primary_guards()
+                    .iter()
+                    .filter(|g| g.reachability() == GuardReachability::Unreachable)
+                    .count()
+            };
+            assert_eq!(n_unreachable(), 1);
+
+            let probe = Arc::new(AlwaysReachable(AtomicUsize::new(0)));
+            let prober: Arc<dyn GuardProbe> = probe.clone();
+            let handle = guardmgr.install_prober(&prober).unwrap();
+
+            // While we're suspended, we don't probe.
+            handle.suspend();
+            rt.advance_by(probe::PROBE_INTERVAL * 3).await;
+            rt.progress_until_stalled().await;
+            assert_eq!(probe.0.load(Ordering::SeqCst), 0);
+            assert_eq!(n_unreachable(), 1);
+
+            handle.resume();
+            rt.progress_until_stalled().await;
+            assert_eq!(probe.0.load(Ordering::SeqCst), 1);
+            assert_eq!(n_unreachable(), 0);
+        });
+    }
+
+    #[test]
+    fn learned_fallbacks() {
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let (guardmgr, statemgr, netdir) = init(rt.clone());
+            let n_configured = TestConfig::default().fallbacks().len();
+            assert_eq!(guardmgr.inner.lock().unwrap().fallbacks.len(), n_configured);
+
+            // We learn some fallbacks from the consensus, but they are too
+            // new to use.
+            guardmgr.install_test_netdir(&netdir);
+            let n_learned = {
+                let inner = guardmgr.inner.lock().unwrap();
+                assert_eq!(inner.fallbacks.len(), n_configured);
+                inner.learned_fallbacks.len()
+            };
+            assert!(n_learned > 0);
+            drop(guardmgr);
+
+            // They were saved, so once they are old enough, we use them
+            // right away, even before we have a directory.
+            rt.jump_wallclock(rt.wallclock() + fallback::LEARNED_FALLBACK_MIN_AGE);
+            let guardmgr2 = GuardMgr::new(rt.clone(), statemgr, &TestConfig::default()).unwrap();
+            {
+                let inner = guardmgr2.inner.lock().unwrap();
+                assert_eq!(inner.learned_fallbacks.len(), n_learned);
+                assert_eq!(inner.fallbacks.len(), n_configured + n_learned);
+            }
+
+            // We can be told not to use them.
+            let config = TestConfig {
+                disable_learned_fallbacks: true,
+                ..TestConfig::default()
+            };
+            let _ = guardmgr2.reconfigure(&config).unwrap();
+            {
+                let inner = guardmgr2.inner.lock().unwrap();
+                assert_eq!(inner.learned_fallbacks.len(), n_learned);
+                assert_eq!(inner.fallbacks.len(), n_configured);
+            }
+
+            // If our configured fallbacks change, we forget them.
+            let config = TestConfig {
+                fallbacks: fallback::FallbackListBuilder::default().build().unwrap(),
+                ..TestConfig::default()
+            };
+            let _ = guardmgr2.reconfigure(&config).unwrap();
+            let inner = guardmgr2.inner.lock().unwrap();
+            assert_eq!(inner.learned_fallbacks.len(), 0);
+            assert_eq!(inner.fallbacks.len(), config.fallbacks.len());
+        });
+    }
 }
@@ -0,0 +1,78 @@
+//! Reporting guard lifecycle events to an external observer.
+//!
+//! Operators who want to watch guard churn (for example, to export it to a
+//! metrics system) can install a [`GuardObserver`] with
+//! [`GuardMgr::set_observer`](crate::GuardMgr::set_observer).  We tell it
+//! about each guard that we add to our sample, confirm, promote to or demote
+//! from primary, see fail, or expire; along with the size of each of our guard
+//! lists afterwards, which are suitable for gauges.
+
+// We always keep track of an observer, but we only export these types (and
+// let anybody install one) with the `metrics` feature.
+#![cfg_attr(not(feature = "metrics"), allow(unreachable_pub))]
+
+use std::fmt::Debug;
+
+use tor_linkspec::RelayIds;
+
+/// An object that wants to know when our guards change.
+///
+/// Only the guards of the default guard context are reported.
+pub trait GuardObserver: Debug + Send + Sync {
+    /// Called whenever something described in `event` happens to one of our
+    /// guards.
+    ///
+    /// This is called while the guard manager is locked, so it must not call
+    /// back into the [`GuardMgr`](crate::GuardMgr), and it should not block.
+    fn guard_event(&self, event: &GuardEvent);
+}
+
+/// What happened to a guard, in a [`GuardEvent`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum GuardEventKind {
+    /// We added the guard to our sample.
+    Sampled,
+    /// We used the guard successfully for the first time, and added it to
+    /// our confirmed guards.
+    Confirmed,
+    /// The guard became one of our primary guards.
+    Promoted,
+    /// The guard stopped being one of our primary guards.
+    Demoted,
+    /// An attempt to use the guard failed.
+    Failed,
+    /// We removed the guard from our sample, since it had been there too
+    /// long or had been unlisted for too long.
+    Expired,
+    /// We removed the guard from our sample, since the sample held too much
+    /// of the network's guard weight, or since it was full and we needed room
+    /// for a guard that a caller required.
+    Trimmed,
+}
+
+/// The sizes of the lists in one of our guard samples.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct GuardCounts {
+    /// The number of guards in the sample.
+    pub n_sampled: usize,
+    /// The number of guards in the sample that are confirmed.
+    pub n_confirmed: usize,
+    /// The number of primary guards.
+    pub n_primary: usize,
+}
+
+/// A single event in the lifecycle of one of our guards.
+///
+/// Given to [`GuardObserver::guard_event`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct GuardEvent {
+    /// What happened.
+    pub kind: GuardEventKind,
+    /// The identities of the guard that it happened to.
+    pub guard: RelayIds,
+    /// The sizes of the lists in the guard's sample, after the event.
+    pub counts: GuardCounts,
+}
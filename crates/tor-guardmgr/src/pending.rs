@@ -7,13 +7,14 @@
 //! then the circuit manager can't know whether to use a circuit built
 //! through that guard until the guard manager tells it.  This is
 //! handled via [`GuardUsable`].
-use crate::{daemon, FirstHopId};
+use crate::{daemon, CircuitFailureCause, FirstHopId};
 
 use educe::Educe;
-use futures::{channel::mpsc::UnboundedSender, Future};
+use futures::Future;
 use oneshot_fused_workaround as oneshot;
 use pin_project::pin_project;
 use std::fmt::Debug;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::task::{Context, Poll};
@@ -128,6 +129,10 @@ pub struct GuardMonitor {
     /// If set, we will report the given clock skew as having been observed and
     /// authenticated from this guard or fallback.
     pending_skew: Option<ClockSkew>,
+    /// If set, the reason that the circuit failed.
+    ///
+    /// We use this to decide whether to blame the guard for a failure.
+    pending_cause: Option<CircuitFailureCause>,
     /// A sender that needs to get told when the attempt to use the guard is
     /// finished or abandoned.
     ///
@@ -135,17 +140,18 @@ pub struct GuardMonitor {
     /// here to indicate that we've already used the sender, and it can't
     /// be used again.
     #[educe(Debug(method = "skip_fmt"))]
-    snd: Option<UnboundedSender<daemon::Msg>>,
+    snd: Option<daemon::MsgSender>,
 }
 
 impl GuardMonitor {
     /// Create a new GuardMonitor object.
-    pub(crate) fn new(id: RequestId, snd: UnboundedSender<daemon::Msg>) -> Self {
+    pub(crate) fn new(id: RequestId, snd: daemon::MsgSender) -> Self {
         GuardMonitor {
             id,
             pending_status: GuardStatus::AttemptAbandoned,
             ignore_indeterminate: false,
             pending_skew: None,
+            pending_cause: None,
             snd: Some(snd),
         }
     }
@@ -170,6 +176,17 @@ impl GuardMonitor {
         self.report(GuardStatus::Failure);
     }
 
+    /// Report that the circuit could not be built successfully, because of
+    /// `cause`.
+    ///
+    /// Unlike [`failed`](GuardMonitor::failed), this lets the guard manager
+    /// decide whether the guard is to blame: for example, if the circuit
+    /// failed beyond the guard, we don't treat the guard as down.
+    pub fn failed_with(mut self, cause: CircuitFailureCause) {
+        self.cause(cause);
+        self.report(GuardStatus::Failure);
+    }
+
     /// Report that we did not try to build a circuit using the guard,
     /// or that we can't tell whether the guard is working.
     ///
@@ -194,6 +211,17 @@ impl GuardMonitor {
         self.pending_skew = Some(skew);
     }
 
+    /// Set the reason that the circuit failed, to be reported to the guard
+    /// manager along with a failure.
+    ///
+    /// If the cause shows that the guard is to blame, we report any failure
+    /// or indeterminate status as a failure.  If it shows that the circuit
+    /// failed beyond the guard, we report any failure as indeterminate.  (See
+    /// [`CircuitFailureCause::blame`].)
+    pub fn cause(&mut self, cause: CircuitFailureCause) {
+        self.pending_cause = Some(cause);
+    }
+
     /// Return the current pending status and "ignore indeterminate"
     /// status for this guard monitor.
     #[cfg(feature = "testing")]
@@ -215,17 +243,65 @@ impl GuardMonitor {
         self.report_impl(msg);
     }
 
+    /// Report a status for each of several guard monitors at once.
+    ///
+    /// This is equivalent to calling [`GuardMonitor::report`] on each monitor,
+    /// but it is more efficient when there are many reports: the guard
+    /// manager receives them as a single batch, and handles them a few at a
+    /// time so that it isn't locked for too long.
+    ///
+    /// Use this when you learn the outcome of many attempts at once: for
+    /// example, when retiring a large number of circuits after a change in
+    /// network conditions.
+    pub fn report_batch<I>(reports: I)
+    where
+        I: IntoIterator<Item = (GuardMonitor, GuardStatus)>,
+    {
+        let mut batch: Option<(daemon::MsgSender, Vec<daemon::StatusReport>)> = None;
+        for (mut monitor, status) in reports {
+            let (snd, report) = monitor.take_report(status);
+            match &mut batch {
+                Some((batch_snd, reports)) if batch_snd.same_receiver(&snd) => {
+                    reports.push(report);
+                }
+                _ => {
+                    // This monitor belongs to a different guard manager
+                    // from the previous ones (which is unusual).  Send what we
+                    // have, and start a new batch.
+                    if let Some((batch_snd, reports)) = batch.take() {
+                        let _ignore = batch_snd.send(daemon::Msg::StatusBatch(reports));
+                    }
+                    batch = Some((snd, vec![report]));
+                }
+            }
+        }
+        if let Some((batch_snd, reports)) = batch {
+            let _ignore = batch_snd.send(daemon::Msg::StatusBatch(reports));
+        }
+    }
+
     /// As [`GuardMonitor::report`], but take a &mut reference.
     fn report_impl(&mut self, msg: GuardStatus) {
+        let (snd, (id, msg, skew)) = self.take_report(msg);
+        let _ignore = snd.send(daemon::Msg::Status(id, msg, skew));
+    }
+
+    /// Consume the sender from this monitor, and return it along with the
+    /// report that we should send for the status `msg`.
+    fn take_report(&mut self, msg: GuardStatus) -> (daemon::MsgSender, daemon::StatusReport) {
+        let msg = match self.pending_cause {
+            Some(cause) => cause.blame().adjust(msg),
+            None => msg,
+        };
         let msg = match (msg, self.ignore_indeterminate) {
             (GuardStatus::Indeterminate, true) => GuardStatus::AttemptAbandoned,
             (m, _) => m,
         };
-        let _ignore = self
+        let snd = self
             .snd
             .take()
-            .expect("GuardMonitor initialized with no sender")
-            .unbounded_send(daemon::Msg::Status(self.id, msg, self.pending_skew));
+            .expect("GuardMonitor initialized with no sender");
+        (snd, (self.id, msg, self.pending_skew))
     }
 
     /// Report the pending message for his guard, whatever it is.
@@ -287,6 +363,8 @@ pub(crate) struct PendingRequest {
     /// (This is an option so that we can safely make reply() once-only.
     /// Otherwise we run into lifetime issues elsewhere.)
     usable: Option<oneshot::Sender<bool>>,
+    /// The time at which we gave out this guard.
+    launched_at: Instant,
     /// The time at which the circuit manager told us that this guard was
     /// successful.
     waiting_since: Option<Instant>,
@@ -296,6 +374,10 @@ pub(crate) struct PendingRequest {
     /// If this request succeeds, it probably means that the net has
     /// come back up.
     net_has_been_down: bool,
+    /// The addresses at which we told the circuit manager to reach the guard.
+    ///
+    /// This is empty if the guard is a fallback.
+    addrs: Vec<SocketAddr>,
 }
 
 impl PendingRequest {
@@ -305,13 +387,17 @@ impl PendingRequest {
         usage: crate::GuardUsage,
         usable: Option<oneshot::Sender<bool>>,
         net_has_been_down: bool,
+        addrs: Vec<SocketAddr>,
+        launched_at: Instant,
     ) -> Self {
         PendingRequest {
             guard_id,
             usage,
             usable,
+            launched_at,
             waiting_since: None,
             net_has_been_down,
+            addrs,
         }
     }
 
@@ -320,6 +406,17 @@ impl PendingRequest {
         &self.guard_id
     }
 
+    /// Return the time at which we gave out the guard.
+    pub(crate) fn launched_at(&self) -> Instant {
+        self.launched_at
+    }
+
+    /// Return the addresses at which we told the circuit manager to reach
+    /// the guard.
+    pub(crate) fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs[..]
+    }
+
     /// Return the usage for which we gave out the guard.
     pub(crate) fn usage(&self) -> &crate::GuardUsage {
         &self.usage
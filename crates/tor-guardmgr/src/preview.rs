@@ -0,0 +1,77 @@
+//! Reporting which guards we would consider for a usage, without picking one.
+//!
+//! When we're debugging why Arti picked (or didn't pick) some guard, or
+//! writing tests in a crate that uses a [`GuardMgr`](crate::GuardMgr), it
+//! helps to see the whole decision: every guard that we looked at, in the
+//! order that we prefer them, and why we passed over each one that we
+//! wouldn't use.  [`GuardMgr::preview_selection`](crate::GuardMgr::preview_selection)
+//! reports that as a list of [`CandidateGuard`]s.
+
+use crate::GuardInfo;
+
+/// A reason why we would not pick a guard from our sample.
+///
+/// When more than one of these applies, we report the first one, in the order
+/// that they are listed here.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum GuardExclusion {
+    /// The guard isn't listed in our latest directory, or is otherwise
+    /// disabled.
+    Unusable,
+    /// Our last attempt to use the guard failed, and it isn't yet time to try
+    /// it again.
+    Unreachable,
+    /// The guard isn't ready for this kind of usage yet: for example, it
+    /// recently failed to answer a directory request.
+    NotReady,
+    /// We're already trying the guard on an exploratory basis.
+    ExploratoryCircuitPending,
+    /// The guard doesn't meet the restrictions of the usage.
+    UnsuitableForUsage,
+    /// Our [`GuardFilter`](crate::GuardFilter) doesn't permit the guard.
+    Filtered,
+    /// The guard is a primary guard with too many attempts in flight, and we'd
+    /// use another primary guard instead.
+    Busy,
+    /// We'd be willing to use the guard, but we prefer others ahead of it in
+    /// the list.
+    NotPreferred,
+}
+
+/// A guard that we considered for a usage, and what we decided about it.
+///
+/// Returned by
+/// [`GuardMgr::preview_selection`](crate::GuardMgr::preview_selection).
+/// This is a snapshot: it doesn't change when the guard's status does.
+#[derive(Clone, Debug)]
+pub struct CandidateGuard {
+    /// What we know about the guard.
+    info: GuardInfo,
+    /// Why we wouldn't pick the guard, if we wouldn't.
+    exclusion: Option<GuardExclusion>,
+}
+
+impl CandidateGuard {
+    /// Construct a new `CandidateGuard`.
+    pub(crate) fn new(info: GuardInfo, exclusion: Option<GuardExclusion>) -> Self {
+        CandidateGuard { info, exclusion }
+    }
+
+    /// Return what we know about this guard.
+    pub fn info(&self) -> &GuardInfo {
+        &self.info
+    }
+
+    /// Return true if this guard is one of the guards that we would currently
+    /// choose among.
+    pub fn is_eligible(&self) -> bool {
+        self.exclusion.is_none()
+    }
+
+    /// Return the reason why we wouldn't pick this guard, or `None` if it is
+    /// [eligible](CandidateGuard::is_eligible).
+    pub fn exclusion(&self) -> Option<GuardExclusion> {
+        self.exclusion
+    }
+}
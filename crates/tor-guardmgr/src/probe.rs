@@ -0,0 +1,40 @@
+//! Hooks for actively checking whether unreachable guards have come back.
+//!
+//! Once we fail to connect to a guard, we normally wait for a retry timer
+//! before we try it again, and those timers back off as the failures
+//! continue.  On a censored or flaky network, that can leave us waiting long
+//! after our guards have become reachable again.
+//!
+//! An embedder with a cheap way to check whether a guard is reachable (a TCP
+//! connect, say, or a pluggable transport handshake) can install a
+//! [`GuardProbe`] with
+//! [`GuardMgr::install_prober`](crate::GuardMgr::install_prober).  From time
+//! to time, we ask it about each of our unreachable primary guards, and we
+//! make any guard that it can reach retriable right away.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::GuardInfo;
+
+/// How often we look for unreachable primary guards to probe.
+///
+/// This is shorter than the first retry delay for a primary guard, so that a
+/// probe can actually help.
+pub(crate) const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Something that can actively check whether we can reach a guard.
+///
+/// See [`GuardMgr::install_prober`](crate::GuardMgr::install_prober).
+#[async_trait]
+pub trait GuardProbe: Send + Sync {
+    /// Check whether we can reach `guard` now, and return true if we can.
+    ///
+    /// A probe that can't tell should return false: in that case, we keep
+    /// waiting for the guard's retry timer, as we would have without a probe.
+    ///
+    /// We only probe one guard at a time, so an implementation should
+    /// give up after a reasonable timeout.
+    async fn probe(&self, guard: &GuardInfo) -> bool;
+}
@@ -0,0 +1,434 @@
+//! Inferring which guard addresses we can reach from our connection attempts.
+//!
+//! Some networks only let traffic out on a few ports (often just 80 and 443),
+//! or only over IPv4.  Users on such networks can configure the addresses
+//! that they can reach, but many don't know that they need to.  If
+//! [`ReachabilityInference`] is enabled, we watch the outcome of each attempt
+//! to connect to a guard; once we have recently failed to reach several
+//! different guards on some port and address family, without reaching any
+//! guard there, while attempts elsewhere have succeeded, we stop choosing
+//! guards on that port and family, and tell anybody who is listening via
+//! [`GuardMgr::unreachable_addr_events`](crate::GuardMgr::unreachable_addr_events).
+//!
+//! These inferences don't last forever: networks change, and so do their
+//! rules.  We forget each failure, and each inference, after a while, and we
+//! forget everything if the network seems to have gone down and come back.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
+use tor_config::{impl_standard_builder, ConfigBuildError};
+use tor_linkspec::RelayIds;
+use tor_netdoc::types::policy::AddrPortPattern;
+
+use crate::events::UnreachableAddrInference;
+
+/// Configuration for inferring which guard addresses we can reach.
+///
+/// See the [module documentation](self) for what this does.
+///
+/// To make one, use [`ReachabilityInferenceBuilder`], or deserialize it from a
+/// string.
+#[derive(Debug, Clone, Builder, Eq, PartialEq)]
+#[builder(build_fn(validate = "Self::validate", error = "ConfigBuildError"))]
+#[builder(derive(Debug, Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct ReachabilityInference {
+    /// If true, we narrow our guard filter based on the outcome of our
+    /// connection attempts.
+    ///
+    /// The default is false.
+    #[builder(default)]
+    pub enabled: bool,
+    /// The number of different guards on a port and address family that we
+    /// must fail to reach, with no attempt there succeeding, before we decide
+    /// that we can't reach it.
+    ///
+    /// (We count guards, not attempts, so that a single guard that is down
+    /// can't convince us that its whole port is unreachable.)
+    ///
+    /// The default is 4.
+    #[builder(default = "4")]
+    pub min_failed_guards: usize,
+    /// How long we remember each success and failure, and how long we keep
+    /// each inference before we give its addresses another chance.
+    ///
+    /// The default is one hour.
+    #[builder(default = "Duration::from_secs(60 * 60)")]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    pub lifetime: Duration,
+}
+
+impl_standard_builder! { ReachabilityInference }
+
+impl ReachabilityInferenceBuilder {
+    /// Check that this builder's values make sense.
+    fn validate(&self) -> Result<(), ConfigBuildError> {
+        if self.min_failed_guards == Some(0) {
+            return Err(ConfigBuildError::Invalid {
+                field: "min_failed_guards".to_owned(),
+                problem: "must be at least 1".to_owned(),
+            });
+        }
+        if self.lifetime == Some(Duration::ZERO) {
+            return Err(ConfigBuildError::Invalid {
+                field: "lifetime".to_owned(),
+                problem: "must not be zero".to_owned(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A port and address family on which we might try to reach a guard.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
+struct AddrClass {
+    /// True if this is an IPv6 address.
+    ipv6: bool,
+    /// The port.
+    port: u16,
+}
+
+impl AddrClass {
+    /// Return the class of `addr`.
+    fn of(addr: &SocketAddr) -> Self {
+        AddrClass {
+            ipv6: addr.is_ipv6(),
+            port: addr.port(),
+        }
+    }
+}
+
+/// The recent outcomes of our attempts on a single [`AddrClass`].
+#[derive(Debug, Clone, Default)]
+struct ClassHistory {
+    /// Each guard that we have recently failed to reach, and when we most
+    /// recently failed to reach it.
+    failures: BTreeMap<RelayIds, Instant>,
+    /// When an attempt last succeeded, if one did so recently.
+    last_success: Option<Instant>,
+}
+
+/// The recent outcomes of our guard connection attempts, by port and address
+/// family, and the ones that we have decided are unreachable.
+#[derive(Debug, Default)]
+pub(crate) struct ReachabilityTracker {
+    /// The recent outcomes of our attempts.
+    history: BTreeMap<AddrClass, ClassHistory>,
+    /// The classes that we have decided we can't reach, and when we decided
+    /// so.
+    unreachable: BTreeMap<AddrClass, Instant>,
+}
+
+impl ReachabilityTracker {
+    /// Record the outcome of an attempt to connect to the guard `guard` at
+    /// `addrs`, at `now`.
+    ///
+    /// Return a description of any new inference that we made as a result.
+    ///
+    /// When a guard has more than one address, we don't know which one we
+    /// used.  So we count a failure against all of them, and a success for all
+    /// of them: that way, we can only decide that a class is unreachable if
+    /// no attempt that might have used it recently succeeded.
+    pub(crate) fn note_outcome(
+        &mut self,
+        guard: &RelayIds,
+        addrs: &[SocketAddr],
+        succeeded: bool,
+        now: Instant,
+        config: &ReachabilityInference,
+    ) -> Vec<UnreachableAddrInference> {
+        if !config.enabled {
+            return Vec::new();
+        }
+        self.expire(now, config);
+        let classes: BTreeSet<AddrClass> = addrs.iter().map(AddrClass::of).collect();
+        for class in &classes {
+            let history = self.history.entry(*class).or_default();
+            if succeeded {
+                // A success on a class outweighs every failure there: the
+                // guards that failed were probably down.
+                history.failures.clear();
+                history.last_success = Some(now);
+                self.unreachable.remove(class);
+            } else {
+                history.failures.insert(guard.clone(), now);
+            }
+        }
+        // (We check even after a success, since it may be our first evidence
+        // that the failures elsewhere are the network's fault rather than the
+        // guards'.)  If nothing has worked lately, we're probably offline, and
+        // we don't want to blame any particular port.
+        if !self.history.values().any(|h| h.last_success.is_some()) {
+            return Vec::new();
+        }
+        let newly_unreachable: Vec<_> = self
+            .history
+            .iter()
+            .filter(|(class, h)| {
+                h.last_success.is_none()
+                    && h.failures.len() >= config.min_failed_guards
+                    && !self.unreachable.contains_key(class)
+            })
+            .map(|(class, h)| (*class, h.failures.len()))
+            .collect();
+        let mut inferences = Vec::new();
+        for (class, n_failed_guards) in newly_unreachable {
+            self.unreachable.insert(class, now);
+            inferences.push(UnreachableAddrInference {
+                ipv6: class.ipv6,
+                port: class.port,
+                n_failed_guards,
+            });
+        }
+        inferences.sort_by_key(|i| (i.ipv6, i.port));
+        inferences
+    }
+
+    /// Forget every success, failure, and inference that is older than our
+    /// configured lifetime.
+    ///
+    /// Return true if we forgot any inferences, and so will now permit
+    /// addresses that we didn't before.
+    pub(crate) fn expire(&mut self, now: Instant, config: &ReachabilityInference) -> bool {
+        let is_recent = |when: &Instant| now.saturating_duration_since(*when) < config.lifetime;
+        self.history.retain(|_, h| {
+            h.failures.retain(|_, when| is_recent(when));
+            h.last_success = h.last_success.filter(is_recent);
+            !h.failures.is_empty() || h.last_success.is_some()
+        });
+        let n_before = self.unreachable.len();
+        self.unreachable.retain(|_, when| is_recent(when));
+        self.unreachable.len() != n_before
+    }
+
+    /// Forget everything that we have observed and inferred.
+    pub(crate) fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Return a list of patterns that permit every address that we haven't
+    /// decided is unreachable, or `None` if we haven't decided that any
+    /// addresses are unreachable.
+    pub(crate) fn reachable_patterns(&self) -> Option<Vec<AddrPortPattern>> {
+        if self.unreachable.is_empty() {
+            return None;
+        }
+        let mut patterns = Vec::new();
+        for (ipv6, all_addrs) in [(false, "0.0.0.0/0"), (true, "[::]/0")] {
+            let mut next_port: u32 = 1;
+            let excluded = self
+                .unreachable
+                .keys()
+                .filter(|c| c.ipv6 == ipv6)
+                .map(|c| u32::from(c.port));
+            // Add a range for the ports before each excluded port, and one for
+            // the ports after the last of them.
+            for end in excluded.chain(std::iter::once(65536)) {
+                if end > next_port {
+                    let pattern = format!("{}:{}-{}", all_addrs, next_port, end - 1);
+                    patterns.push(
+                        pattern
+                            .parse()
+                            .expect("Generated an invalid address pattern"),
+                    );
+                }
+                next_port = end + 1;
+            }
+        }
+        Some(patterns)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    fn addrs(s: &[&str]) -> Vec<SocketAddr> {
+        s.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    fn guard(n: u8) -> RelayIds {
+        RelayIds::builder()
+            .rsa_identity([n; 20].into())
+            .build()
+            .unwrap()
+    }
+
+    fn config(min_failed_guards: usize) -> ReachabilityInference {
+        ReachabilityInferenceBuilder::default()
+            .enabled(true)
+            .min_failed_guards(min_failed_guards)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn infer() {
+        let config = config(3);
+        let mut tracker = ReachabilityTracker::default();
+        let now = Instant::now();
+        let or9001 = addrs(&["192.0.2.1:9001"]);
+        let or443 = addrs(&["192.0.2.2:443"]);
+
+        // While nothing has worked, we blame nobody.
+        for n in 0..5 {
+            assert!(tracker
+                .note_outcome(&guard(n), &or9001, false, now, &config)
+                .is_empty());
+        }
+        assert!(tracker.reachable_patterns().is_none());
+
+        // Once port 443 works, we blame port 9001.
+        let inferred = tracker.note_outcome(&guard(10), &or443, true, now, &config);
+        assert_eq!(inferred.len(), 1);
+        assert_eq!(inferred[0].port(), 9001);
+        assert!(!inferred[0].is_ipv6());
+        assert_eq!(inferred[0].n_failed_guards(), 5);
+        // ... but only once.
+        assert!(tracker
+            .note_outcome(&guard(6), &or9001, false, now, &config)
+            .is_empty());
+
+        let patterns = tracker.reachable_patterns().unwrap();
+        let permits = |a: &str| {
+            let a: SocketAddr = a.parse().unwrap();
+            patterns.iter().any(|p| p.matches_sockaddr(&a))
+        };
+        assert!(permits("192.0.2.3:443"));
+        assert!(permits("192.0.2.3:9000"));
+        assert!(permits("192.0.2.3:9002"));
+        assert!(permits("[2001:db8::1]:9001"));
+        assert!(!permits("192.0.2.3:9001"));
+
+        tracker.clear();
+        assert!(tracker.reachable_patterns().is_none());
+    }
+
+    #[test]
+    fn distinct_guards() {
+        let config = config(3);
+        let mut tracker = ReachabilityTracker::default();
+        let now = Instant::now();
+        let or9001 = addrs(&["192.0.2.1:9001"]);
+        let or443 = addrs(&["192.0.2.2:443"]);
+        tracker.note_outcome(&guard(10), &or443, true, now, &config);
+
+        // However often one guard fails, it only counts once.
+        for _ in 0..10 {
+            assert!(tracker
+                .note_outcome(&guard(1), &or9001, false, now, &config)
+                .is_empty());
+        }
+        assert!(tracker
+            .note_outcome(&guard(2), &or9001, false, now, &config)
+            .is_empty());
+        let inferred = tracker.note_outcome(&guard(3), &or9001, false, now, &config);
+        assert_eq!(inferred.len(), 1);
+        assert_eq!(inferred[0].n_failed_guards(), 3);
+    }
+
+    #[test]
+    fn expire_and_reset() {
+        let config = config(2);
+        let lifetime = config.lifetime;
+        let mut tracker = ReachabilityTracker::default();
+        let now = Instant::now();
+        let or9001 = addrs(&["192.0.2.1:9001"]);
+        let or443 = addrs(&["192.0.2.2:443"]);
+        tracker.note_outcome(&guard(10), &or443, true, now, &config);
+
+        // Failures that are too far apart don't add up.
+        tracker.note_outcome(&guard(1), &or9001, false, now, &config);
+        let later = now + lifetime;
+        assert!(tracker
+            .note_outcome(&guard(2), &or9001, false, later, &config)
+            .is_empty());
+
+        // Neither does a success elsewhere that is too old.
+        assert!(tracker
+            .note_outcome(&guard(3), &or9001, false, later, &config)
+            .is_empty());
+        tracker.note_outcome(&guard(10), &or443, true, later, &config);
+        assert!(tracker.reachable_patterns().is_some());
+
+        // An inference lasts until its lifetime is over.
+        assert!(!tracker.expire(later + lifetime / 2, &config));
+        assert!(tracker.reachable_patterns().is_some());
+        assert!(tracker.expire(later + lifetime, &config));
+        assert!(tracker.reachable_patterns().is_none());
+
+        // A success on a class that we had given up on makes us use it again.
+        let later = later + lifetime;
+        tracker.note_outcome(&guard(10), &or443, true, later, &config);
+        tracker.note_outcome(&guard(1), &or9001, false, later, &config);
+        assert_eq!(
+            tracker
+                .note_outcome(&guard(2), &or9001, false, later, &config)
+                .len(),
+            1
+        );
+        tracker.note_outcome(&guard(3), &or9001, true, later, &config);
+        assert!(tracker.reachable_patterns().is_none());
+    }
+
+    #[test]
+    fn ambiguous() {
+        let config = config(2);
+        let mut tracker = ReachabilityTracker::default();
+        let now = Instant::now();
+        let dual = addrs(&["192.0.2.1:9001", "[2001:db8::1]:9001"]);
+        let v6 = addrs(&["[2001:db8::2]:9001"]);
+
+        // A success at a guard with two addresses counts for both of them,
+        // so we never decide that either is unreachable.
+        tracker.note_outcome(&guard(10), &dual, true, now, &config);
+        for n in 0..4 {
+            assert!(tracker
+                .note_outcome(&guard(n), &v6, false, now, &config)
+                .is_empty());
+            assert!(tracker
+                .note_outcome(&guard(n + 4), &dual, false, now, &config)
+                .is_empty());
+        }
+
+        // Nothing happens when we're disabled.
+        let mut tracker = ReachabilityTracker::default();
+        let disabled = ReachabilityInference::default();
+        tracker.note_outcome(&guard(10), &dual, true, now, &disabled);
+        for n in 0..10 {
+            assert!(tracker
+                .note_outcome(&guard(n), &v6, false, now, &disabled)
+                .is_empty());
+        }
+    }
+
+    #[test]
+    fn validate() {
+        assert!(ReachabilityInferenceBuilder::default()
+            .min_failed_guards(0)
+            .build()
+            .is_err());
+        assert!(ReachabilityInferenceBuilder::default()
+            .lifetime(Duration::ZERO)
+            .build()
+            .is_err());
+        assert!(!ReachabilityInference::default().enabled);
+    }
+}
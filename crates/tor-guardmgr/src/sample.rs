@@ -3,22 +3,29 @@
 
 mod candidate;
 
+use crate::events::GuardAddrChange;
 use crate::filter::GuardFilter;
-use crate::guard::{Guard, NewlyConfirmed, Reachable};
+use crate::guard::{Guard, GuardInfo, NewlyConfirmed, Reachable};
+use crate::observer::{GuardCounts, GuardEvent, GuardEventKind, GuardObserver};
+use crate::preview::{CandidateGuard, GuardExclusion};
 use crate::skew::SkewObservation;
+use crate::stats::{GuardStatsEntry, SampleWeightFraction};
+use crate::GuardStatus;
 use crate::{
     ids::GuardId, ExternalActivity, GuardParams, GuardUsage, GuardUsageKind, PickGuardError,
 };
 use crate::{FirstHop, GuardSetSelector};
-use tor_basic_utils::iter::{FilterCount, IteratorExt as _};
-use tor_linkspec::{ByRelayIds, HasRelayIds};
+use tor_basic_utils::iter::FilterCount;
+use tor_linkspec::{ByRelayIds, HasRelayIds, RelayIdRef};
+use tor_netdir::RelayWeight;
 
 use itertools::Itertools;
 use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
-use std::time::{Instant, SystemTime};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use tracing::{debug, info};
 
 #[allow(unused_imports)]
@@ -139,6 +146,20 @@ pub(crate) struct GuardSet {
     /// Fields from the state file that was used to make this `GuardSet` that
     /// this version of Arti doesn't understand.
     unknown_fields: HashMap<String, JsonValue>,
+
+    /// Guards from the state file that was used to make this `GuardSet` that
+    /// this version of Arti couldn't parse at all.
+    ///
+    /// We never use these guards, but we write them back unchanged, so that
+    /// a newer version of Arti can use them again.
+    unparsed_guards: Vec<JsonValue>,
+
+    /// Versioning information that we store alongside this sample.
+    meta: SampleMeta,
+
+    /// An observer that we tell about changes to the guards in this sample,
+    /// if we have one.
+    observer: Option<Arc<dyn GuardObserver>>,
 }
 
 /// Which of our lists did a given guard come from?
@@ -204,9 +225,11 @@ impl GuardSet {
             });
         }
 
+        // Update the sample first, since a guard may have learned new
+        // identities since we last looked at it.
+        fix_id_list(&self.guards, &mut self.sample);
         let sample_set: HashSet<_> = self.sample.iter().collect();
         self.guards.retain(|g| sample_set.contains(g.guard_id()));
-        fix_id_list(&self.guards, &mut self.sample);
         fix_id_list(&self.guards, &mut self.confirmed);
         fix_id_list(&self.guards, &mut self.primary);
     }
@@ -262,6 +285,7 @@ impl GuardSet {
     /// guards that we just loaded from state, and `other` is our old guards,
     /// which we are using only for their status information.
     pub(crate) fn copy_ephemeral_status_into_newly_loaded_state(&mut self, mut other: GuardSet) {
+        self.observer = other.observer.take();
         let old_guards = std::mem::take(&mut self.guards);
         self.guards = old_guards
             .into_values()
@@ -277,29 +301,108 @@ impl GuardSet {
             .collect();
     }
 
+    /// Replace the observer that we tell about changes to the guards in this
+    /// sample.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn set_observer(&mut self, observer: Option<Arc<dyn GuardObserver>>) {
+        self.observer = observer;
+    }
+
+    /// Tell our observer, if we have one, that `kind` has just happened to the
+    /// guard with `id`.
+    fn note_event(&self, kind: GuardEventKind, id: &GuardId) {
+        let Some(observer) = &self.observer else {
+            return;
+        };
+        observer.guard_event(&GuardEvent {
+            kind,
+            guard: id.0.clone(),
+            counts: GuardCounts {
+                n_sampled: self.sample.len(),
+                n_confirmed: self.confirmed.len(),
+                n_primary: self.primary.len(),
+            },
+        });
+    }
+
+    /// Record that this `GuardSet` was in use at `now`.
+    pub(crate) fn note_used(&mut self, now: SystemTime) {
+        self.meta.last_used = Some(now);
+    }
+
+    /// Return the last time at which this `GuardSet` was in use, if we know it.
+    pub(crate) fn last_used(&self) -> Option<SystemTime> {
+        self.meta.last_used
+    }
+
+    /// Return the number of guards in this `GuardSet`, including any that we
+    /// could not parse.
+    pub(crate) fn n_stored_guards(&self) -> usize {
+        self.guards.len() + self.unparsed_guards.len()
+    }
+
     /// Return a serializable state object that can be stored to disk
     /// to capture the current state of this GuardSet.
     fn get_state(&self) -> GuardSample<'_> {
         let guards = self
             .sample
             .iter()
-            .map(|id| Cow::Borrowed(self.guards.by_all_ids(id).expect("Inconsistent state")))
+            .map(|id| {
+                Futureproof::Understandable(Cow::Borrowed(
+                    self.guards.by_all_ids(id).expect("Inconsistent state"),
+                ))
+            })
+            .chain(
+                self.unparsed_guards
+                    .iter()
+                    .map(|g| Futureproof::Unknown(g.clone())),
+            )
             .collect();
 
         GuardSample {
             guards,
             confirmed: Cow::Borrowed(&self.confirmed),
+            meta: SampleMeta {
+                version: SAMPLE_FORMAT_VERSION,
+                ..self.meta.clone()
+            },
             remaining: self.unknown_fields.clone(),
         }
     }
 
+    /// Construct a new `GuardSet` containing `guards`, in sample order, of
+    /// which the ones listed in `confirmed` are confirmed, in confirmed order.
+    pub(crate) fn from_guards(guards: Vec<Guard>, confirmed: Vec<GuardId>) -> Self {
+        GuardSet::from_state(GuardSample {
+            guards: guards
+                .into_iter()
+                .map(|g| Futureproof::Understandable(Cow::Owned(g)))
+                .collect(),
+            confirmed: Cow::Owned(confirmed),
+            meta: SampleMeta::default(),
+            remaining: HashMap::new(),
+        })
+    }
+
     /// Reconstruct a guard state from its serialized representation.
     fn from_state(state: GuardSample<'_>) -> Self {
         let mut guards = ByRelayIds::new();
         let mut sample = Vec::new();
+        let mut unparsed_guards = Vec::new();
         for guard in state.guards {
-            sample.push(guard.guard_id().clone());
-            guards.insert(guard.into_owned());
+            match guard {
+                Futureproof::Understandable(guard) => {
+                    sample.push(guard.guard_id().clone());
+                    guards.insert(guard.into_owned());
+                }
+                Futureproof::Unknown(guard) => unparsed_guards.push(guard),
+            }
+        }
+        if !unparsed_guards.is_empty() {
+            info!(
+                "Ignoring {} guard(s) from our state file that we could not parse.",
+                unparsed_guards.len()
+            );
         }
         let confirmed = state.confirmed.into_owned();
         let primary = Vec::new();
@@ -312,6 +415,9 @@ impl GuardSet {
             filter_is_restrictive: false,
             primary_guards_invalidated: true,
             unknown_fields: state.remaining,
+            unparsed_guards,
+            meta: state.meta,
+            observer: None,
         };
 
         // Fix any inconsistencies in the stored representation.
@@ -357,7 +463,8 @@ impl GuardSet {
     /// sample (according to the current active filter), then add
     /// more, up to the limits allowed by the parameters.
     ///
-    /// This is the only function that adds new guards to the sample.
+    /// Apart from [`GuardSet::add_pinned_guard`], this is the only function
+    /// that adds new guards to the sample.
     ///
     /// Guards always start out un-confirmed.
     ///
@@ -415,6 +522,7 @@ impl GuardSet {
         let WeightThreshold {
             mut current_weight,
             maximum_weight,
+            ..
         } = dir.weight_threshold(&self.guards, params);
 
         // Ask the netdir for a set of guards we could use.
@@ -449,17 +557,192 @@ impl GuardSet {
                 // We've reached our target; no need to add more.
                 break;
             }
-            if self.active_filter.permits(&candidate.owned_target) {
-                n_filtered_usable += 1;
-            }
+            let id = GuardId::from_relay_ids(&candidate.owned_target);
             current_weight += weight;
             self.add_guard(candidate, now, params);
+            if self
+                .guards
+                .by_all_ids(&id)
+                .is_some_and(|g| self.active_filter.permits(g))
+            {
+                n_filtered_usable += 1;
+            }
             any_added = true;
         }
         self.assert_consistency();
         any_added
     }
 
+    /// If the guards in this sample now hold more than the fraction of
+    /// `dir`'s weight allowed by `params`, remove guards until they don't.
+    ///
+    /// We only enforce `max_sample_bw_fraction` when we add guards, but
+    /// weights change with every consensus, and so does the parameter itself.
+    /// Without this function, a sample could keep growing in importance
+    /// without ever growing in size.
+    ///
+    /// We never remove confirmed or primary guards, and we never shrink the
+    /// sample below `min_filtered_sample_size`.  We remove the most recently
+    /// sampled guards first.
+    ///
+    /// We use the same limit as [`GuardSet::extend_sample_as_needed`], which
+    /// keeps adding guards for as long as the sample is under the limit, and
+    /// so can end up over the limit by a single guard.  We only trim a sample
+    /// that would still be at or over the limit without its newest guard:
+    /// otherwise, the next extension would add back what we removed.
+    ///
+    /// Return the number of guards that we removed.
+    pub(crate) fn trim_sample_to_weight_limit<U: Universe>(
+        &mut self,
+        params: &GuardParams,
+        dir: &U,
+    ) -> usize {
+        self.assert_consistency();
+        let WeightThreshold {
+            current_weight,
+            maximum_weight,
+            ..
+        } = dir.weight_threshold(&self.guards, params);
+        if current_weight <= maximum_weight {
+            return 0;
+        }
+
+        // The guards in the sample, oldest first, with their weights, and
+        // whether we're willing to remove them.
+        let mut remaining: Vec<(&GuardId, RelayWeight, bool)> = self
+            .sample
+            .iter()
+            .map(|id| {
+                let weight = self
+                    .guards
+                    .by_all_ids(id)
+                    .and_then(|g| dir.guard_weight(g))
+                    .unwrap_or_else(|| RelayWeight::from(0));
+                let removable = weight != RelayWeight::from(0)
+                    && !self.confirmed.contains(id)
+                    && !self.primary.contains(id);
+                (id, weight, removable)
+            })
+            .collect();
+        // The total weight of the guards we've decided to remove.
+        let mut removed_weight = RelayWeight::from(0);
+        let mut to_remove = Vec::new();
+        while let Some(&(_, newest_weight, _)) = remaining.last() {
+            // Would extend_sample_inner have stopped before adding the newest
+            // guard?  If not, the sample is no heavier than it allows.
+            if current_weight < maximum_weight + removed_weight + newest_weight {
+                break;
+            }
+            if remaining.len() <= params.min_filtered_sample_size {
+                break;
+            }
+            let Some(pos) = remaining.iter().rposition(|(_, _, removable)| *removable) else {
+                break;
+            };
+            let (id, weight, _) = remaining.remove(pos);
+            removed_weight += weight;
+            to_remove.push(id.clone());
+        }
+
+        if to_remove.is_empty() {
+            return 0;
+        }
+        self.guards.retain(|g| !to_remove.contains(g.guard_id()));
+        self.sample.retain(|id| !to_remove.contains(id));
+        self.assert_consistency();
+        self.primary_guards_invalidated = true;
+        debug!(
+            n_trimmed = to_remove.len(),
+            "Removed guards from sample: it held too much of the network's guard weight."
+        );
+        for id in &to_remove {
+            self.note_event(GuardEventKind::Trimmed, id);
+        }
+        to_remove.len()
+    }
+
+    /// Return the fraction of `dir`'s guard weight held by the guards in this
+    /// sample, and the largest fraction that `params` allows.
+    ///
+    /// Return None if `dir` doesn't assign weights to its guards.
+    pub(crate) fn weight_fraction<U: Universe>(
+        &self,
+        params: &GuardParams,
+        dir: &U,
+    ) -> Option<SampleWeightFraction> {
+        let threshold = dir.weight_threshold(&self.guards, params);
+        let fraction = threshold
+            .current_weight
+            .checked_div(threshold.total_weight)?;
+        Some(SampleWeightFraction {
+            fraction,
+            max_fraction: params.max_sample_bw_fraction,
+        })
+    }
+
+    /// Add the member of `dir` with the identity `id` to the sample, so that
+    /// a caller who requires that guard can use it.
+    ///
+    /// Unlike [`GuardSet::extend_sample_as_needed`], this ignores our limit on
+    /// the weight of the sample: we only use it when a caller has asked for a
+    /// particular guard with
+    /// [`GuardRestriction::RequireId`](crate::GuardRestriction::RequireId), and
+    /// that guard may be a heavy one.  We do still respect
+    /// `max_sample_size`: if the sample is full, we make room by removing its
+    /// newest guard that is neither confirmed nor primary.  If every guard in
+    /// a full sample is confirmed or primary, we refuse to add the new one.
+    ///
+    /// We also refuse to add a relay that `dir` does not list as a possible
+    /// guard, or that our filter forbids.
+    ///
+    /// Return [`ExtendedStatus::Yes`](crate::ExtendedStatus::Yes) if we added
+    /// the guard.  If we didn't, the caller's request fails: we never fall
+    /// back to some other guard for a caller who requires a particular one.
+    pub(crate) fn add_pinned_guard<U: Universe>(
+        &mut self,
+        id: RelayIdRef<'_>,
+        now: SystemTime,
+        params: &GuardParams,
+        dir: &U,
+    ) -> crate::ExtendedStatus {
+        if self.guards.by_id(id).is_some() {
+            return crate::ExtendedStatus::No;
+        }
+        let Some(candidate) = dir.candidate_by_id(id).filter(|c| c.listed_as_guard) else {
+            return crate::ExtendedStatus::No;
+        };
+        let guard = Guard::from_candidate(candidate, now, params);
+        if !self.active_filter.permits(&guard) {
+            return crate::ExtendedStatus::No;
+        }
+        if self.guards.len() >= params.max_sample_size {
+            let Some(evict) = self
+                .sample
+                .iter()
+                .rev()
+                .find(|id| !self.confirmed.contains(id) && !self.primary.contains(id))
+                .cloned()
+            else {
+                debug!(
+                    "Sample is full of confirmed and primary guards; not adding required guard."
+                );
+                return crate::ExtendedStatus::No;
+            };
+            debug!(guard_id=?evict, "Removing guard from full sample to make room for a required guard.");
+            self.guards.retain(|g| g.guard_id() != &evict);
+            self.sample.retain(|id| id != &evict);
+            self.note_event(GuardEventKind::Trimmed, &evict);
+        }
+        let id = guard.guard_id().clone();
+        debug!(guard_id=?id, "Adding required guard to sample.");
+        self.sample.push(id.clone());
+        self.guards.insert(guard);
+        self.primary_guards_invalidated = true;
+        self.assert_consistency();
+        self.note_event(GuardEventKind::Sampled, &id);
+        crate::ExtendedStatus::Yes
+    }
+
     /// Add `relay` as a new guard.
     ///
     /// Does nothing if it is already a guard.
@@ -471,8 +754,9 @@ impl GuardSet {
         debug!(guard_id=?id, "Adding guard to sample.");
         let guard = Guard::from_candidate(relay, now, params);
         self.guards.insert(guard);
-        self.sample.push(id);
+        self.sample.push(id.clone());
         self.primary_guards_invalidated = true;
+        self.note_event(GuardEventKind::Sampled, &id);
     }
 
     /// Return the number of our primary guards that are missing directory
@@ -495,18 +779,46 @@ impl GuardSet {
             .count()
     }
 
+    /// Return the number of our primary guards that are not definitely listed
+    /// in `universe`.
+    ///
+    /// Unlike [`n_primary_without_id_info_in`](Self::n_primary_without_id_info_in),
+    /// this counts guards that the universe says are unlisted, as well as those
+    /// for which it lacks the information to tell.
+    pub(crate) fn n_primary_not_listed_in<U: Universe>(&self, universe: &U) -> usize {
+        self.primary
+            .iter()
+            .filter(|id| {
+                self.guards
+                    .by_all_ids(*id)
+                    .and_then(|g| g.listed_in(universe))
+                    != Some(true)
+            })
+            .count()
+    }
+
     /// Update the status of every guard  in this sample from a given source.
-    pub(crate) fn update_status_from_dir<U: Universe>(&mut self, dir: &U) {
+    ///
+    /// Return a list of the guards whose addresses changed.
+    pub(crate) fn update_status_from_dir<U: Universe>(&mut self, dir: &U) -> Vec<GuardAddrChange> {
+        let mut addr_changes = Vec::new();
         let old_guards = std::mem::take(&mut self.guards);
         self.guards = old_guards
             .into_values()
             .map(|mut guard| {
-                guard.update_from_universe(dir);
+                addr_changes.extend(guard.update_from_universe(dir));
                 guard
             })
             .collect();
         // Call "fix consistency", in case any guards got a new ID.
         self.fix_consistency();
+        addr_changes
+    }
+
+    /// Return the identity of our most-preferred primary guard, if we have any
+    /// primary guards.
+    pub(crate) fn first_primary_guard(&self) -> Option<&GuardId> {
+        self.primary.first()
     }
 
     /// Re-build the list of primary guards.
@@ -555,6 +867,12 @@ impl GuardSet {
 
         if self.primary != old_primary {
             debug!(old=?old_primary, new=?self.primary, "Updated primary guards.");
+            for id in old_primary.iter().filter(|id| !self.primary.contains(id)) {
+                self.note_event(GuardEventKind::Demoted, id);
+            }
+            for id in self.primary.iter().filter(|id| !old_primary.contains(id)) {
+                self.note_event(GuardEventKind::Promoted, id);
+            }
         }
 
         // Clear exploratory_circ_pending for all primary guards.
@@ -576,6 +894,15 @@ impl GuardSet {
     pub(crate) fn expire_old_guards(&mut self, params: &GuardParams, now: SystemTime) {
         self.assert_consistency();
         let n_pre = self.guards.len();
+        let expired: Vec<GuardId> = match self.observer {
+            Some(_) => self
+                .guards
+                .values()
+                .filter(|g| g.is_expired(params, now))
+                .map(|g| g.guard_id().clone())
+                .collect(),
+            None => Vec::new(),
+        };
         self.guards.retain(|g| !g.is_expired(params, now));
         let guards = &self.guards;
         self.sample.retain(|id| guards.by_all_ids(id).is_some());
@@ -588,6 +915,9 @@ impl GuardSet {
             debug!(n_expired, "Expired guards as too old.");
             self.primary_guards_invalidated = true;
         }
+        for id in &expired {
+            self.note_event(GuardEventKind::Expired, id);
+        }
     }
 
     /// Return an iterator over the Id for every Guard in the sample that
@@ -665,6 +995,12 @@ impl GuardSet {
         }
     }
 
+    /// Mark the guard with `guard_id` as `Unknown`, if it is `Unreachable`.
+    pub(crate) fn mark_guard_retriable(&mut self, guard_id: &GuardId) {
+        self.guards
+            .modify_by_all_ids(guard_id, |guard| guard.mark_retriable());
+    }
+
     /// Return true if all of our primary guards are currently marked
     /// unreachable.
     pub(crate) fn all_primary_guards_are_unreachable(&mut self) -> bool {
@@ -712,6 +1048,7 @@ impl GuardSet {
         now: SystemTime,
     ) {
         self.assert_consistency();
+        let mut confirmed = false;
         self.guards.modify_by_all_ids(guard_id, |guard| match how {
             Some(external) => guard.record_external_success(external),
             None => {
@@ -720,26 +1057,39 @@ impl GuardSet {
                 if newly_confirmed == NewlyConfirmed::Yes {
                     self.confirmed.push(guard_id.clone());
                     self.primary_guards_invalidated = true;
+                    confirmed = true;
                 }
             }
         });
         self.assert_consistency();
+        if confirmed {
+            self.note_event(GuardEventKind::Confirmed, guard_id);
+        }
     }
 
     /// Record that an attempt to use the guard with `guard_id` has just failed.
     ///
+    /// We use `params` to decide when to retry it.
     pub(crate) fn record_failure(
         &mut self,
         guard_id: &GuardId,
         how: Option<ExternalActivity>,
+        params: &GuardParams,
         now: Instant,
     ) {
         // TODO use instant uniformly for in-process, and systemtime for storage?
         let is_primary = self.guard_is_primary(guard_id);
-        self.guards.modify_by_all_ids(guard_id, |guard| match how {
-            Some(external) => guard.record_external_failure(external, now),
-            None => guard.record_failure(now, is_primary),
+        let mut found = false;
+        self.guards.modify_by_all_ids(guard_id, |guard| {
+            found = true;
+            match how {
+                Some(external) => guard.record_external_failure(external, now),
+                None => guard.record_failure(now, is_primary, &params.retry),
+            }
         });
+        if found {
+            self.note_event(GuardEventKind::Failed, guard_id);
+        }
     }
 
     /// Record that an attempt to use the guard with `guard_id` has
@@ -759,6 +1109,63 @@ impl GuardSet {
         });
     }
 
+    /// Record, in the long-term statistics for the guard with `guard_id`, that
+    /// an attempt to use it had the outcome `status`, `latency` after the
+    /// attempt began.
+    ///
+    /// We also stop counting the attempt as in flight.
+    pub(crate) fn record_outcome_stats(
+        &mut self,
+        guard_id: &GuardId,
+        status: GuardStatus,
+        latency: Duration,
+        now: SystemTime,
+    ) {
+        self.guards.modify_by_all_ids(guard_id, |guard| {
+            guard.stats_mut().note_outcome(status, latency, now);
+            guard.record_attempt_finished();
+        });
+    }
+
+    /// Return the long-term statistics for every guard in this sample.
+    pub(crate) fn stats_entries(&self) -> Vec<GuardStatsEntry> {
+        self.guards
+            .values()
+            .map(|guard| GuardStatsEntry {
+                ids: guard.guard_id().0.clone(),
+                is_primary: self.guard_is_primary(guard.guard_id()),
+                is_reachable: guard.reachable() != Reachable::Unreachable,
+                stats: guard.stats().clone(),
+            })
+            .collect()
+    }
+
+    /// Return a snapshot of each of our primary guards, in order of
+    /// preference.
+    pub(crate) fn primary_guard_infos(&self) -> Vec<GuardInfo> {
+        self.primary
+            .iter()
+            .filter_map(|id| self.guards.by_all_ids(id))
+            .map(|guard| guard.info(true))
+            .collect()
+    }
+
+    /// Return a snapshot of every guard in our sample, in the order in
+    /// which we added them.
+    pub(crate) fn sampled_guard_infos(&self) -> Vec<GuardInfo> {
+        self.sample
+            .iter()
+            .filter_map(|id| self.guards.by_all_ids(id))
+            .map(|guard| guard.info(self.guard_is_primary(guard.guard_id())))
+            .collect()
+    }
+
+    /// Return the sampled, confirmed, and primary guards, in that order.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn id_lists(&self) -> [&[GuardId]; 3] {
+        [&self.sample, &self.confirmed, &self.primary]
+    }
+
     /// Record that a given guard has told us about clock skew.
     pub(crate) fn record_skew(&mut self, guard_id: &GuardId, observation: SkewObservation) {
         self.guards
@@ -877,68 +1284,251 @@ impl GuardSet {
         now: Instant,
     ) -> Result<(ListKind, GuardId), PickGuardError> {
         debug_assert!(!self.primary_guards_invalidated);
+        let candidates = self.annotated_candidates(usage, params, now);
+        let options: Vec<_> = candidates
+            .iter()
+            .filter(|(_, _, exclusion)| exclusion.is_none())
+            .collect();
+
+        match options.choose(&mut crate::util::rng()) {
+            Some((src, g, _)) => Ok((*src, g.guard_id().clone())),
+            None => {
+                // Count how many guards were rejected by each of the filters in
+                // annotated_candidates, in order.  Each filter only sees the
+                // guards that the ones before it accepted.
+                let mut counts = [FilterCount::default(); 4];
+                for (_, _, exclusion) in &candidates {
+                    use GuardExclusion as GE;
+                    let stage = match exclusion {
+                        Some(GE::Unusable | GE::Unreachable | GE::NotReady) => 0,
+                        Some(GE::ExploratoryCircuitPending) => 1,
+                        Some(GE::UnsuitableForUsage) => 2,
+                        Some(GE::Filtered) => 3,
+                        Some(GE::Busy | GE::NotPreferred) | None => counts.len(),
+                    };
+                    for count in &mut counts[..stage] {
+                        count.n_accepted += 1;
+                    }
+                    if let Some(count) = counts.get_mut(stage) {
+                        count.n_rejected += 1;
+                    }
+                }
+                let [running, pending, suitable, filtered] = counts;
+
+                let retry_at = if running.n_accepted == 0 {
+                    self.next_retry(usage)
+                } else {
+                    None
+                };
+                Err(PickGuardError::AllGuardsDown {
+                    retry_at,
+                    running,
+                    pending,
+                    suitable,
+                    filtered,
+                })
+            }
+        }
+    }
+
+    /// Return every guard in this set, in preference order, along with
+    /// whether we would choose among it for `usage`, and if not, why not.
+    ///
+    /// [`pick_guard_id`](Self::pick_guard_id) chooses at random among the
+    /// guards that have no exclusion.
+    ///
+    /// We treat guards that are due for a retry as retriable, whether or not
+    /// we've marked them as retriable yet.
+    fn annotated_candidates(
+        &self,
+        usage: &GuardUsage,
+        params: &GuardParams,
+        now: Instant,
+    ) -> Vec<(ListKind, &Guard, Option<GuardExclusion>)> {
         let n_options = match usage.kind {
             GuardUsageKind::OneHopDirectory => params.dir_parallelism,
             GuardUsageKind::Data => params.data_parallelism,
         };
 
-        // Counts of how many elements were rejected by which of the filters
-        // below.
-        //
-        // Note that since we use `Iterator::take`, these counts won't cover the
-        // whole guard sample on the successful case: only in the failing case,
-        // when we fail to find any candidates.
-        let mut running = FilterCount::default();
-        let mut pending = FilterCount::default();
-        let mut suitable = FilterCount::default();
-        let mut filtered = FilterCount::default();
+        let mut candidates: Vec<_> = self
+            .preference_order()
+            .map(|(src, g)| {
+                // We report the first reason that applies, in the order that
+                // GuardExclusion lists them.
+                let exclusion = if !g.usable() {
+                    Some(GuardExclusion::Unusable)
+                } else if g.reachable_at(now) == Reachable::Unreachable {
+                    Some(GuardExclusion::Unreachable)
+                } else if !g.ready_for_usage(usage, now) {
+                    Some(GuardExclusion::NotReady)
+                } else if g.exploratory_circ_pending() {
+                    Some(GuardExclusion::ExploratoryCircuitPending)
+                } else if !g.conforms_to_usage(usage) {
+                    Some(GuardExclusion::UnsuitableForUsage)
+                } else if !self.active_filter.permits(g) {
+                    Some(GuardExclusion::Filtered)
+                } else {
+                    None
+                };
+                (src, g, exclusion)
+            })
+            .collect();
+
+        // If we have many attempts in flight through our favorite primary
+        // guards, we spread our load across the other primary guards, rather
+        // than piling every new attempt onto the same ones.
+        let is_idle_primary = |src: &ListKind, g: &Guard| {
+            src.is_primary()
+                && params
+                    .max_attempts_in_flight
+                    .map_or(true, |max| g.attempts_in_flight() < max)
+        };
+        let any_idle_primary = candidates
+            .iter()
+            .any(|(src, g, excl)| excl.is_none() && is_idle_primary(src, g));
+        let mut n_chosen = 0;
+        let mut chose_primary = false;
+        for (src, g, exclusion) in &mut candidates {
+            if exclusion.is_some() {
+                continue;
+            }
+            if any_idle_primary && !is_idle_primary(src, g) {
+                *exclusion = Some(if src.is_primary() {
+                    GuardExclusion::Busy
+                } else {
+                    GuardExclusion::NotPreferred
+                });
+                continue;
+            }
+            // We consider the first n_options such guards.  If there are any
+            // primary guards among them, we only consider those; otherwise,
+            // parallelism doesn't apply.
+            let limit = if chose_primary || src.is_primary() {
+                n_options
+            } else {
+                1
+            };
+            if n_chosen >= limit || (chose_primary && !src.is_primary()) {
+                *exclusion = Some(GuardExclusion::NotPreferred);
+                continue;
+            }
+            n_chosen += 1;
+            chose_primary |= src.is_primary();
+        }
+
+        candidates
+    }
+
+    /// Report every guard in this set, in preference order, along with
+    /// whether [`pick_guard`](Self::pick_guard) would currently choose among
+    /// it for `usage`, and if not, why not.
+    ///
+    /// Unlike `pick_guard`, this doesn't require that we've already marked
+    /// the guards that are due for a retry as retriable: we treat them as
+    /// retriable either way, and leave them as they are.  It does require
+    /// that our primary guards are up to date.
+    pub(crate) fn preview_selection(
+        &self,
+        usage: &GuardUsage,
+        params: &GuardParams,
+        now: Instant,
+    ) -> Vec<CandidateGuard> {
+        debug_assert!(!self.primary_guards_invalidated);
+        self.annotated_candidates(usage, params, now)
+            .into_iter()
+            .map(|(src, g, exclusion)| {
+                CandidateGuard::new(g.info_at(src.is_primary(), now), exclusion)
+            })
+            .collect()
+    }
+
+    /// Return true if something has happened that may have changed which
+    /// guards should be primary, and we haven't yet called
+    /// [`select_primary_guards`](Self::select_primary_guards).
+    pub(crate) fn primary_guards_invalidated(&self) -> bool {
+        self.primary_guards_invalidated
+    }
+
+    /// Return our most preferred confirmed guard that we currently believe to
+    /// be usable, converted to a provisional first hop.
+    ///
+    /// We use this before we have any directory information, so we can't check
+    /// whether the guard is still listed: we go only by what we remember about
+    /// it, and by our active filter.
+    pub(crate) fn provisional_guard(
+        &self,
+        sample_id: &GuardSetSelector,
+        now: Instant,
+    ) -> Option<FirstHop> {
+        let usage = GuardUsage::default();
+
+        self.confirmed
+            .iter()
+            .filter_map(|id| self.guards.by_all_ids(id))
+            .filter(|g| {
+                g.usable()
+                    && g.reachable() != Reachable::Unreachable
+                    && g.ready_for_usage(&usage, now)
+                    && g.conforms_to_usage(&usage)
+                    && self.active_filter.permits(*g)
+            })
+            .find_map(|g| {
+                let mut first_hop = g.get_external_rep(sample_id.clone());
+                first_hop.provisional = true;
+                self.active_filter.modify_hop(first_hop).ok()
+            })
+    }
+
+    /// Return up to `n` guards that we would currently be willing to use for a
+    /// one-hop directory request, in the order that we prefer them, converted
+    /// to a representation suitable for use as a first hop.
+    ///
+    /// The guards are chosen with the same restrictions as
+    /// [`pick_guard`](Self::pick_guard) uses for
+    /// [`GuardUsageKind::OneHopDirectory`]: if any primary guards are usable,
+    /// we return only primary guards, and no more than
+    /// `params.dir_parallelism` of them.  Otherwise, we return only our single
+    /// most preferred guard.
+    pub(crate) fn preferred_dir_guards(
+        &self,
+        sample_id: &GuardSetSelector,
+        params: &GuardParams,
+        now: Instant,
+        n: usize,
+    ) -> Vec<FirstHop> {
+        debug_assert!(!self.primary_guards_invalidated);
+        let usage = crate::GuardUsageBuilder::new()
+            .kind(GuardUsageKind::OneHopDirectory)
+            .build()
+            .expect("Unable to build directory usage");
 
         let mut options: Vec<_> = self
             .preference_order()
-            // Discard the guards that are down or unusable, and see if any
-            // are left.
-            .filter_cnt(&mut running, |(_, g)| {
+            .filter(|(_, g)| {
                 g.usable()
                     && g.reachable() != Reachable::Unreachable
-                    && g.ready_for_usage(usage, now)
+                    && g.ready_for_usage(&usage, now)
+                    && !g.exploratory_circ_pending()
+                    && g.conforms_to_usage(&usage)
+                    && self.active_filter.permits(*g)
             })
-            // Now remove those that are excluded because we're already trying
-            // them on an exploratory basis.
-            .filter_cnt(&mut pending, |(_, g)| !g.exploratory_circ_pending())
-            // ...or because they don't support the operation we're
-            // attempting...
-            .filter_cnt(&mut suitable, |(_, g)| g.conforms_to_usage(usage))
-            // ... or because we specifically filtered them out.
-            .filter_cnt(&mut filtered, |(_, g)| self.active_filter.permits(*g))
-            // We only consider the first n_options such guards.
-            .take(n_options)
+            .take(params.dir_parallelism)
             .collect();
 
         if options.iter().any(|(src, _)| src.is_primary()) {
-            // If there are any primary guards, we only consider those.
             options.retain(|(src, _)| src.is_primary());
         } else {
-            // If there are no primary guards, parallelism doesn't apply.
             options.truncate(1);
         }
 
-        match options.choose(&mut rand::thread_rng()) {
-            Some((src, g)) => Ok((*src, g.guard_id().clone())),
-            None => {
-                let retry_at = if running.n_accepted == 0 {
-                    self.next_retry(usage)
-                } else {
-                    None
-                };
-                Err(PickGuardError::AllGuardsDown {
-                    retry_at,
-                    running,
-                    pending,
-                    suitable,
-                    filtered,
-                })
-            }
-        }
+        options
+            .into_iter()
+            .take(n)
+            .filter_map(|(_, g)| {
+                let first_hop = g.get_external_rep(sample_id.clone());
+                self.active_filter.modify_hop(first_hop).ok()
+            })
+            .collect()
     }
 
     /// Return the guards whose bridge descriptors we should request, given our
@@ -979,20 +1569,50 @@ impl GuardSet {
 }
 
 use serde::Serializer;
-use tor_persist::JsonValue;
+use tor_persist::{Futureproof, JsonValue};
 
 /// State object used to serialize and deserialize a [`GuardSet`].
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct GuardSample<'a> {
-    /// Equivalent to `GuardSet.guards.values()`, except in sample order.
-    guards: Vec<Cow<'a, Guard>>,
+    /// Equivalent to `GuardSet.guards.values()`, except in sample order,
+    /// followed by `GuardSet.unparsed_guards`.
+    guards: Vec<Futureproof<Cow<'a, Guard>>>,
     /// The identities for the confirmed members of `guards`, in confirmed order.
     confirmed: Cow<'a, Vec<GuardId>>,
+    /// Versioning information for this sample.
+    #[serde(default)]
+    meta: SampleMeta,
     /// Other data from the state file that this version of Arti doesn't recognize.
     #[serde(flatten)]
     remaining: HashMap<String, JsonValue>,
 }
 
+/// The format version that we record in the [`SampleMeta`] of every guard
+/// sample we write.
+///
+/// Samples written before we recorded versions are treated as version 0.
+pub(crate) const SAMPLE_FORMAT_VERSION: u32 = 1;
+
+/// Versioning information stored alongside each guard sample.
+///
+/// We also read this from samples that this version of Arti does not
+/// otherwise recognize, so that we can decide when to discard them.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SampleMeta {
+    /// The format version with which this sample was written.
+    #[serde(default)]
+    pub(crate) version: u32,
+    /// The last time at which this sample was in use.
+    ///
+    /// For a sample that we don't recognize, and that doesn't say when it was
+    /// last used, this is instead the time at which we first noticed it.
+    #[serde(default, with = "humantime_serde")]
+    pub(crate) last_used: Option<SystemTime>,
+    /// Other data that this version of Arti doesn't recognize.
+    #[serde(flatten)]
+    remaining: HashMap<String, JsonValue>,
+}
+
 impl Serialize for GuardSet {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -1123,6 +1743,152 @@ mod test {
         assert!(samples[0] != samples[1] || samples[1] != samples[2]);
     }
 
+    /// Make a test network with 20 viable guards (numbered 20 through 39), in
+    /// which the relays in `heavy` have weight 10000, and everybody else has
+    /// weight 1000.
+    fn netdir_with_heavy_guards(heavy: &HashSet<usize>) -> NetDir {
+        tor_netdir::testnet::construct_custom_netdir(|idx, builder, _| {
+            let weight = if heavy.contains(&idx) { 10_000 } else { 1000 };
+            builder.rs.weight(RelayWeight::Measured(weight));
+            if idx >= 20 {
+                builder.rs.add_flags(RelayFlags::GUARD);
+                builder.rs.protos("DirCache=2".parse().unwrap());
+            } else {
+                builder.rs.protos("".parse().unwrap());
+            }
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap()
+    }
+
+    #[test]
+    fn trim_when_weights_shift() {
+        let netdir = netdir_with_heavy_guards(&HashSet::new());
+        let params = GuardParams {
+            min_filtered_sample_size: 10,
+            max_sample_bw_fraction: 0.5,
+            n_primary: 3,
+            ..GuardParams::default()
+        };
+        let now = SystemTime::now();
+
+        let mut guards = GuardSet::default();
+        guards.extend_sample_as_needed(now, &params, &netdir);
+        assert_eq!(guards.guards.len(), 10);
+        let frac = guards.weight_fraction(&params, &netdir).unwrap();
+        assert!((frac.fraction - 0.5).abs() < 1e-9);
+        assert_eq!(frac.max_fraction, 0.5);
+        // Nothing to trim yet.
+        assert_eq!(guards.trim_sample_to_weight_limit(&params, &netdir), 0);
+
+        let id1 = guards.sample[9].clone();
+        guards.record_success(&id1, &params, None, now);
+        guards.select_primary_guards(&params);
+        let primary = guards.primary.clone();
+        assert_eq!(primary.len(), 3);
+        assert!(primary.contains(&id1));
+
+        // Now every guard in our sample gets ten times as heavy: together they
+        // hold 100000 of 110000.
+        let sampled: HashSet<usize> = guards
+            .guards
+            .values()
+            .map(|g| g.rsa_identity().unwrap().as_bytes()[0] as usize)
+            .collect();
+        let inflated = netdir_with_heavy_guards(&sampled);
+        let frac = guards.weight_fraction(&params, &inflated).unwrap();
+        assert!((frac.fraction - 100.0 / 110.0).abs() < 1e-9);
+
+        // We only need to remove unconfirmed guards until removing another
+        // would take us under the limit of 55000: that's four of them.
+        let params = GuardParams {
+            min_filtered_sample_size: 5,
+            ..params
+        };
+        assert_eq!(guards.trim_sample_to_weight_limit(&params, &inflated), 4);
+        assert_eq!(guards.guards.len(), 6);
+        assert_eq!(guards.sample.len(), 6);
+        assert!(guards.sample.contains(&id1));
+        assert!(guards.confirmed.contains(&id1));
+        assert!(primary.iter().all(|id| guards.sample.contains(id)));
+        let frac = guards.weight_fraction(&params, &inflated).unwrap();
+        assert!((frac.fraction - 60.0 / 110.0).abs() < 1e-9);
+        guards.assert_consistency();
+
+        // That's stable: we don't trim or extend any further.
+        assert_eq!(guards.trim_sample_to_weight_limit(&params, &inflated), 0);
+        guards.extend_sample_as_needed(now, &params, &inflated);
+        assert_eq!(guards.guards.len(), 6);
+
+        // If the consensus lowers the limit, we trim more, but never below
+        // the minimum sample size, and never our primary guards.
+        let params = GuardParams {
+            max_sample_bw_fraction: 0.01,
+            ..params
+        };
+        assert_eq!(guards.trim_sample_to_weight_limit(&params, &inflated), 1);
+        assert_eq!(guards.guards.len(), 5);
+        assert!(primary.iter().all(|id| guards.sample.contains(id)));
+        assert_eq!(guards.trim_sample_to_weight_limit(&params, &inflated), 0);
+    }
+
+    #[test]
+    fn trim_matches_extend() {
+        let netdir = netdir_with_heavy_guards(&HashSet::new());
+        let params = GuardParams {
+            min_filtered_sample_size: 10,
+            max_sample_bw_fraction: 0.5,
+            ..GuardParams::default()
+        };
+        let now = SystemTime::now();
+        let mut guards = GuardSet::default();
+        guards.extend_sample_as_needed(now, &params, &netdir);
+        assert_eq!(guards.sample.len(), 10);
+        let params = GuardParams {
+            min_filtered_sample_size: 5,
+            ..params
+        };
+        let idx_of = |id: &GuardId| {
+            guards
+                .guards
+                .by_all_ids(id)
+                .unwrap()
+                .rsa_identity()
+                .unwrap()
+                .as_bytes()[0] as usize
+        };
+
+        // Make the newest guard in the sample heavy: now the sample holds 19000
+        // of 29000, over the limit of 14500.  But extend_sample_inner would
+        // have added that guard when the sample held only 9000, so we leave
+        // the sample alone.
+        let newest = idx_of(&guards.sample[9]);
+        let inflated = netdir_with_heavy_guards(&[newest].into_iter().collect());
+        let mut trimmed = guards.clone();
+        assert_eq!(trimmed.trim_sample_to_weight_limit(&params, &inflated), 0);
+        trimmed.extend_sample_as_needed(now, &params, &inflated);
+        assert_eq!(trimmed.sample, guards.sample);
+
+        // If the oldest guard is the heavy one, we trim until the sample
+        // would be under the limit of 14500 without its newest guard.  That
+        // takes four guards, and leaves us at 15000.
+        let oldest = idx_of(&guards.sample[0]);
+        let inflated = netdir_with_heavy_guards(&[oldest].into_iter().collect());
+        let mut trimmed = guards.clone();
+        assert_eq!(trimmed.trim_sample_to_weight_limit(&params, &inflated), 4);
+        assert_eq!(trimmed.sample[..], guards.sample[..6]);
+        let frac = trimmed.weight_fraction(&params, &inflated).unwrap();
+        assert!((frac.fraction - 15.0 / 29.0).abs() < 1e-9);
+
+        // Trimming and then extending changes nothing further.
+        let sample = trimmed.sample.clone();
+        trimmed.extend_sample_as_needed(now, &params, &inflated);
+        assert_eq!(trimmed.trim_sample_to_weight_limit(&params, &inflated), 0);
+        assert_eq!(trimmed.sample, sample);
+        trimmed.assert_consistency();
+    }
+
     #[test]
     fn persistence() {
         let netdir = netdir();
@@ -1167,6 +1933,54 @@ mod test {
         }
     }
 
+    #[test]
+    fn persistence_unknown_fields() {
+        use serde_json::json;
+        let netdir = netdir();
+        let params = GuardParams {
+            min_filtered_sample_size: 5,
+            ..GuardParams::default()
+        };
+        let t1 = SystemTime::now();
+        let t2 = t1 + Duration::from_secs(20);
+
+        let mut guards = GuardSet::default();
+        guards.extend_sample_as_needed(t1, &params, &netdir);
+        let id0 = guards.sample[0].clone();
+
+        // Pretend that a newer version of Arti wrote some fields that we
+        // don't know about, and a guard that we can't parse at all.
+        let mut state = serde_json::to_value(&guards).unwrap();
+        state["future_set_field"] = json!(["x"]);
+        state["guards"][0]["future_guard_field"] = json!({"a": 1});
+        state["guards"][0]["added_by"]["build"] = json!("deadbeef");
+        let weird_guard = json!({"id": 7, "new_format": true});
+        state["guards"]
+            .as_array_mut()
+            .unwrap()
+            .push(weird_guard.clone());
+
+        // Load the state, use the guards a little, and save them again.
+        let mut guards2: GuardSet = serde_json::from_value(state).unwrap();
+        assert_eq!(guards2.sample, guards.sample);
+        assert_eq!(guards2.unparsed_guards, vec![weird_guard.clone()]);
+        guards2.record_success(&id0, &params, None, t2);
+        let state2 = serde_json::to_value(&guards2).unwrap();
+
+        // Everything we didn't understand should still be there.
+        assert_eq!(state2["future_set_field"], json!(["x"]));
+        let saved = state2["guards"].as_array().unwrap();
+        assert_eq!(saved.len(), guards.sample.len() + 1);
+        assert_eq!(saved[0]["future_guard_field"], json!({"a": 1}));
+        assert_eq!(saved[0]["added_by"]["build"], json!("deadbeef"));
+        assert!(saved[0]["confirmed_at"].is_string());
+        assert_eq!(saved.last().unwrap(), &weird_guard);
+
+        // And it should survive another round trip.
+        let guards3: GuardSet = serde_json::from_value(state2.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&guards3).unwrap(), state2);
+    }
+
     #[test]
     fn select_primary() {
         let netdir = netdir();
@@ -1272,7 +2086,7 @@ mod test {
         assert_eq!(&id, &id1);
 
         guards.record_attempt(&id, i1);
-        guards.record_failure(&id, None, i1 + sec);
+        guards.record_failure(&id, None, &GuardParams::default(), i1 + sec);
 
         // Second guard: try it, and try it again, and have it fail.
         let (src, id) = guards.pick_guard_id(&usage, &params, i1 + sec).unwrap();
@@ -1286,8 +2100,8 @@ mod test {
         assert_eq!(id_x, id);
         assert_eq!(src, ListKind::Primary);
         guards.record_attempt(&id_x, i1 + sec * 2);
-        guards.record_failure(&id_x, None, i1 + sec * 3);
-        guards.record_failure(&id, None, i1 + sec * 4);
+        guards.record_failure(&id_x, None, &GuardParams::default(), i1 + sec * 3);
+        guards.record_failure(&id, None, &GuardParams::default(), i1 + sec * 4);
 
         // Third guard: this one won't be primary.
         let (src, id3) = guards.pick_guard_id(&usage, &params, i1 + sec * 4).unwrap();
@@ -1373,6 +2187,53 @@ mod test {
         );
     }
 
+    #[test]
+    fn attempts_in_flight() {
+        let netdir = netdir();
+        let params = GuardParams {
+            min_filtered_sample_size: 5,
+            n_primary: 2,
+            max_attempts_in_flight: Some(3),
+            ..GuardParams::default()
+        };
+        let now = Instant::now();
+        let usage = crate::GuardUsageBuilder::default().build().unwrap();
+
+        let mut guards = GuardSet::default();
+        guards.extend_sample_as_needed(SystemTime::now(), &params, &netdir);
+        guards.select_primary_guards(&params);
+        let id1 = guards.primary[0].clone();
+        let id2 = guards.primary[1].clone();
+
+        // We keep using our favorite primary guard until it has too many
+        // attempts in flight...
+        for _ in 0..3 {
+            let (src, id) = guards.pick_guard_id(&usage, &params, now).unwrap();
+            assert_eq!(src, ListKind::Primary);
+            assert_eq!(id, id1);
+            guards.record_attempt(&id, now);
+        }
+        // ... and then we use the next one.
+        for _ in 0..3 {
+            let (src, id) = guards.pick_guard_id(&usage, &params, now).unwrap();
+            assert_eq!(src, ListKind::Primary);
+            assert_eq!(id, id2);
+            guards.record_attempt(&id, now);
+        }
+        // When every primary guard is busy, we go back to the first one,
+        // rather than using a non-primary guard.
+        let (src, id) = guards.pick_guard_id(&usage, &params, now).unwrap();
+        assert_eq!(src, ListKind::Primary);
+        assert_eq!(id, id1);
+
+        // Once we hear how an attempt went, we can use the guard again.
+        let status = crate::GuardStatus::AttemptAbandoned;
+        guards.record_outcome_stats(&id2, status, Duration::ZERO, SystemTime::now());
+        guards.record_outcome_stats(&id1, status, Duration::ZERO, SystemTime::now());
+        let (_, id) = guards.pick_guard_id(&usage, &params, now).unwrap();
+        assert_eq!(id, id1);
+    }
+
     #[test]
     fn everybodys_down() {
         let netdir = netdir();
@@ -1396,7 +2257,7 @@ mod test {
         for _ in 0..5 {
             let (_, id) = guards.pick_guard_id(&usage, &params, inst).unwrap();
             guards.record_attempt(&id, inst);
-            guards.record_failure(&id, None, inst + sec);
+            guards.record_failure(&id, None, &GuardParams::default(), inst + sec);
 
             inst += sec * 2;
             st += sec * 2;
@@ -1435,7 +2296,7 @@ mod test {
             .pick_guard_id(&usage, &params, Instant::now())
             .unwrap();
         assert_eq!(kind, ListKind::Primary);
-        guards.record_failure(&p_id1, None, Instant::now());
+        guards.record_failure(&p_id1, None, &GuardParams::default(), Instant::now());
         assert!(!guards.all_primary_guards_are_unreachable());
 
         // Now let the other one fail.
@@ -1443,7 +2304,7 @@ mod test {
             .pick_guard_id(&usage, &params, Instant::now())
             .unwrap();
         assert_eq!(kind, ListKind::Primary);
-        guards.record_failure(&p_id2, None, Instant::now());
+        guards.record_failure(&p_id2, None, &GuardParams::default(), Instant::now());
         assert!(guards.all_primary_guards_are_unreachable());
 
         // Now mark the guards retriable.
@@ -1456,6 +2317,163 @@ mod test {
         assert_eq!(p_id3, p_id1);
     }
 
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn observer_events() {
+        use std::sync::Mutex;
+
+        /// An observer that remembers every event it sees.
+        #[derive(Debug, Default)]
+        struct Recorder(Mutex<Vec<GuardEvent>>);
+        impl GuardObserver for Recorder {
+            fn guard_event(&self, event: &GuardEvent) {
+                self.0.lock().unwrap().push(event.clone());
+            }
+        }
+        impl Recorder {
+            /// Return the kinds of the events that we've seen, and forget them.
+            fn take_kinds(&self) -> Vec<GuardEventKind> {
+                let events = std::mem::take(&mut *self.0.lock().unwrap());
+                events.into_iter().map(|e| e.kind).collect()
+            }
+        }
+
+        let netdir = netdir();
+        let params = GuardParams {
+            min_filtered_sample_size: 5,
+            n_primary: 2,
+            ..GuardParams::default()
+        };
+        let t1 = SystemTime::now();
+        let recorder = Arc::new(Recorder::default());
+        let mut guards = GuardSet::default();
+        guards.set_observer(Some(recorder.clone()));
+
+        guards.extend_sample_as_needed(t1, &params, &netdir);
+        let n_sampled = guards.sample.len();
+        assert_eq!(
+            recorder.take_kinds(),
+            vec![GuardEventKind::Sampled; n_sampled]
+        );
+
+        guards.select_primary_guards(&params);
+        assert_eq!(
+            recorder.take_kinds(),
+            vec![GuardEventKind::Promoted, GuardEventKind::Promoted]
+        );
+        let (p1, p2) = (guards.primary[0].clone(), guards.primary[1].clone());
+
+        // Confirming a guard that isn't primary moves it to the front.
+        let id = guards.sample[n_sampled - 1].clone();
+        guards.record_success(&id, &params, None, t1);
+        guards.record_success(&id, &params, None, t1);
+        guards.select_primary_guards(&params);
+        let events = std::mem::take(&mut *recorder.0.lock().unwrap());
+        let kinds: Vec<_> = events.iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                GuardEventKind::Confirmed,
+                GuardEventKind::Demoted,
+                GuardEventKind::Promoted
+            ]
+        );
+        assert_eq!(events[0].guard, id.0);
+        assert_eq!(events[1].guard, p2.0);
+        assert_eq!(events[2].guard, id.0);
+        assert_eq!(
+            events[2].counts,
+            GuardCounts {
+                n_sampled,
+                n_confirmed: 1,
+                n_primary: 2,
+            }
+        );
+
+        guards.record_failure(&p1, None, &params, Instant::now());
+        assert_eq!(recorder.take_kinds(), vec![GuardEventKind::Failed]);
+
+        guards.expire_old_guards(&params, t1 + Duration::from_secs(86400 * 200));
+        assert_eq!(
+            recorder.take_kinds(),
+            vec![GuardEventKind::Expired; n_sampled]
+        );
+
+        // Adding a guard that a caller requires counts as sampling it.
+        let p1_ed = p1.0.identity(tor_linkspec::RelayIdType::Ed25519).unwrap();
+        let added = guards.add_pinned_guard(p1_ed, t1, &params, &netdir);
+        assert_eq!(added, crate::ExtendedStatus::Yes);
+        assert_eq!(recorder.take_kinds(), vec![GuardEventKind::Sampled]);
+
+        // Trimming a sample that has become too heavy reports each guard
+        // that we remove.
+        let light = netdir_with_heavy_guards(&HashSet::new());
+        let params = GuardParams {
+            min_filtered_sample_size: 10,
+            max_sample_bw_fraction: 0.5,
+            ..GuardParams::default()
+        };
+        let mut guards = GuardSet::default();
+        guards.set_observer(Some(recorder.clone()));
+        guards.extend_sample_as_needed(t1, &params, &light);
+        let _ = recorder.take_kinds();
+        let sampled: HashSet<usize> = guards
+            .guards
+            .values()
+            .map(|g| g.rsa_identity().unwrap().as_bytes()[0] as usize)
+            .collect();
+        let inflated = netdir_with_heavy_guards(&sampled);
+        let params = GuardParams {
+            min_filtered_sample_size: 5,
+            ..params
+        };
+        let n_trimmed = guards.trim_sample_to_weight_limit(&params, &inflated);
+        assert!(n_trimmed > 0);
+        assert_eq!(
+            recorder.take_kinds(),
+            vec![GuardEventKind::Trimmed; n_trimmed]
+        );
+    }
+
+    #[test]
+    fn pinned_guards_respect_max_sample_size() {
+        let netdir = netdir();
+        let params = GuardParams {
+            max_sample_size: 3,
+            ..GuardParams::default()
+        };
+        let now = SystemTime::now();
+        let mut guards = GuardSet::default();
+
+        // Pin every relay in turn: the sample never grows past its limit.
+        let mut added = Vec::new();
+        for relay in netdir.relays() {
+            let ed = relay.identity(tor_linkspec::RelayIdType::Ed25519).unwrap();
+            if guards.add_pinned_guard(ed, now, &params, &netdir) == crate::ExtendedStatus::Yes {
+                added.push(ed.to_owned());
+            }
+            assert!(guards.guards.len() <= params.max_sample_size);
+            assert_eq!(guards.guards.len(), guards.sample.len());
+        }
+        assert!(added.len() > params.max_sample_size);
+        assert_eq!(guards.sample.len(), params.max_sample_size);
+
+        // Once every guard in the sample is confirmed, there is nothing we're
+        // willing to evict, so we refuse to add another.
+        for id in guards.sample.clone() {
+            guards.record_success(&id, &params, None, now);
+        }
+        let evicted = added
+            .iter()
+            .find(|ed| guards.guards.by_id(ed.as_ref()).is_none())
+            .unwrap();
+        assert_eq!(
+            guards.add_pinned_guard(evicted.as_ref(), now, &params, &netdir),
+            crate::ExtendedStatus::No
+        );
+        assert_eq!(guards.sample.len(), params.max_sample_size);
+    }
+
     #[test]
     fn count_missing_mds() {
         let netdir = netdir();
@@ -1511,7 +2529,7 @@ mod test {
         guards1.record_success(&id1, &params, None, SystemTime::now());
         guards2.record_success(&id2, &params, None, SystemTime::now());
         // Make a non-persistent change in guards2.
-        guards2.record_failure(&id2, None, Instant::now());
+        guards2.record_failure(&id2, None, &GuardParams::default(), Instant::now());
 
         // Copy status: make sure non-persistent status changed, and  persistent didn't.
         guards1.copy_ephemeral_status_into_newly_loaded_state(guards2);
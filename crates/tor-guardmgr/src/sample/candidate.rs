@@ -3,7 +3,9 @@
 
 use std::{sync::Arc, time::SystemTime};
 
-use tor_linkspec::{ByRelayIds, ChanTarget, HasRelayIds, OwnedChanTarget};
+#[cfg(feature = "geoip")]
+use tor_geoip::HasCountryCode as _;
+use tor_linkspec::{ByRelayIds, ChanTarget, HasRelayIds, OwnedChanTarget, RelayIdRef};
 use tor_netdir::{NetDir, Relay, RelayWeight};
 use tor_relay_selection::{RelayExclusion, RelaySelector, RelayUsage};
 
@@ -22,6 +24,14 @@ pub(crate) trait Universe {
     /// Return full information about a member of this universe for a given guard.
     fn status<T: ChanTarget>(&self, guard: &T) -> CandidateStatus<Candidate>;
 
+    /// Return a candidate for the member of this universe with the identity
+    /// `id`, if there is one.
+    ///
+    /// Unlike [`Universe::sample`], this returns members that are not
+    /// currently suitable as guards; their candidates are not
+    /// `listed_as_guard`.
+    fn candidate_by_id(&self, id: RelayIdRef<'_>) -> Option<Candidate>;
+
     /// Return an (approximate) timestamp describing when this universe was
     /// generated.
     ///
@@ -35,6 +45,13 @@ pub(crate) trait Universe {
     where
         T: HasRelayIds;
 
+    /// Return the weight that this universe gives to the given guard, if it
+    /// has one.
+    ///
+    /// This is the same weight that we count towards `current_weight` in
+    /// [`Universe::weight_threshold`].
+    fn guard_weight<T: HasRelayIds>(&self, guard: &T) -> Option<RelayWeight>;
+
     /// Return up to `n` of new candidate guards from this Universe.
     ///
     /// Only return elements that have no conflicts with identities in
@@ -79,6 +96,9 @@ pub(crate) struct Candidate {
     pub(crate) owned_target: OwnedChanTarget,
     /// How should we display information about this candidate if we select it?
     pub(crate) sensitivity: crate::guard::DisplayRule,
+    /// The country where this candidate is located, if we know it.
+    #[cfg(feature = "geoip")]
+    pub(crate) country: Option<tor_geoip::CountryCode>,
 }
 
 /// Information about how much of the universe we are using in a guard sample,
@@ -95,6 +115,11 @@ pub(crate) struct WeightThreshold {
     /// minimum number of guards; otherwise, were're willing to add a _single_
     /// guard that exceeds this threshold, but no more.
     pub(crate) maximum_weight: RelayWeight,
+    /// The total amount of weight in the universe that we could add to a
+    /// sample, in [`RelayWeight`].
+    ///
+    /// This is zero if the universe doesn't assign weights at all.
+    pub(crate) total_weight: RelayWeight,
 }
 
 impl Universe for NetDir {
@@ -116,6 +141,8 @@ impl Universe for NetDir {
                 owned_target: OwnedChanTarget::from_chan_target(&relay),
                 full_dir_info: true,
                 sensitivity: crate::guard::DisplayRule::Sensitive,
+                #[cfg(feature = "geoip")]
+                country: relay.country_code(),
             }),
             None => match NetDir::ids_listed(self, guard) {
                 Some(true) => panic!("ids_listed said true, but by_ids said none!"),
@@ -125,37 +152,51 @@ impl Universe for NetDir {
         }
     }
 
+    fn candidate_by_id(&self, id: RelayIdRef<'_>) -> Option<Candidate> {
+        let relay = NetDir::by_id(self, id)?;
+        Some(Candidate {
+            listed_as_guard: relay.low_level_details().is_suitable_as_guard(),
+            is_dir_cache: relay.low_level_details().is_dir_cache(),
+            owned_target: OwnedChanTarget::from_chan_target(&relay),
+            full_dir_info: true,
+            sensitivity: crate::guard::DisplayRule::Sensitive,
+            #[cfg(feature = "geoip")]
+            country: relay.country_code(),
+        })
+    }
+
     fn weight_threshold<T>(&self, sample: &ByRelayIds<T>, params: &GuardParams) -> WeightThreshold
     where
         T: HasRelayIds,
     {
         // When adding from a netdir, we impose a limit on the fraction of the
         // universe we're willing to add.
-        let maximum_weight = {
-            // TODO #504 - to convert this, we need tor_relay_selector to apply
-            // to UncheckedRelay.
-            let total_weight = self.total_weight(tor_netdir::WeightRole::Guard, |r| {
-                let d = r.low_level_details();
-                d.is_suitable_as_guard() && d.is_dir_cache()
-            });
-            total_weight
-                .ratio(params.max_sample_bw_fraction)
-                .unwrap_or(total_weight)
-        };
+        // TODO #504 - to convert this, we need tor_relay_selector to apply
+        // to UncheckedRelay.
+        let total_weight = self.total_weight(tor_netdir::WeightRole::Guard, |r| {
+            let d = r.low_level_details();
+            d.is_suitable_as_guard() && d.is_dir_cache()
+        });
+        let maximum_weight = total_weight
+            .ratio(params.max_sample_bw_fraction)
+            .unwrap_or(total_weight);
 
         let current_weight: tor_netdir::RelayWeight = sample
             .values()
-            .filter_map(|guard| {
-                self.weight_by_rsa_id(guard.rsa_identity()?, tor_netdir::WeightRole::Guard)
-            })
+            .filter_map(|guard| self.guard_weight(guard))
             .sum();
 
         WeightThreshold {
             current_weight,
             maximum_weight,
+            total_weight,
         }
     }
 
+    fn guard_weight<T: HasRelayIds>(&self, guard: &T) -> Option<RelayWeight> {
+        self.weight_by_rsa_id(guard.rsa_identity()?, tor_netdir::WeightRole::Guard)
+    }
+
     fn sample<T>(
         &self,
         pre_existing: &ByRelayIds<T>,
@@ -184,7 +225,7 @@ impl Universe for NetDir {
         );
         filter.add_to_selector(&mut sel);
 
-        let (relays, _outcome) = sel.select_n_relays(&mut rand::thread_rng(), n, self);
+        let (relays, _outcome) = sel.select_n_relays(&mut crate::util::rng(), n, self);
         // TODO: report _outcome somehow.
         relays
             .iter()
@@ -196,6 +237,8 @@ impl Universe for NetDir {
                         full_dir_info: true,
                         owned_target: OwnedChanTarget::from_chan_target(relay),
                         sensitivity: crate::guard::DisplayRule::Sensitive,
+                        #[cfg(feature = "geoip")]
+                        country: relay.country_code(),
                     },
                     // TODO: It would be better not to need this function.
                     weight(self, relay).unwrap_or_else(|| RelayWeight::from(0)),
@@ -234,6 +277,14 @@ impl Universe for UniverseRef {
         }
     }
 
+    fn candidate_by_id(&self, id: RelayIdRef<'_>) -> Option<Candidate> {
+        match self {
+            UniverseRef::NetDir(r) => r.candidate_by_id(id),
+            #[cfg(feature = "bridge-client")]
+            UniverseRef::BridgeSet(r) => r.candidate_by_id(id),
+        }
+    }
+
     fn timestamp(&self) -> SystemTime {
         match self {
             UniverseRef::NetDir(r) => r.timestamp(),
@@ -253,6 +304,14 @@ impl Universe for UniverseRef {
         }
     }
 
+    fn guard_weight<T: HasRelayIds>(&self, guard: &T) -> Option<RelayWeight> {
+        match self {
+            UniverseRef::NetDir(r) => r.guard_weight(guard),
+            #[cfg(feature = "bridge-client")]
+            UniverseRef::BridgeSet(r) => r.guard_weight(guard),
+        }
+    }
+
     fn sample<T>(
         &self,
         pre_existing: &ByRelayIds<T>,
@@ -7,13 +7,16 @@
 //     of bridges is very small, see if we can still use that to make a
 //     low-confidence value.
 
+use std::ops::RangeInclusive;
 use std::time::{Duration, Instant};
 
 use tor_proto::ClockSkew;
 
 /// A single observation related to reported clock skew.
+///
+/// Returned by [`GuardMgr::skew_history`](crate::GuardMgr::skew_history).
 #[derive(Debug, Clone)]
-pub(crate) struct SkewObservation {
+pub struct SkewObservation {
     /// The reported clock skew
     pub(crate) skew: ClockSkew,
     /// The time when we added this observation.
@@ -21,6 +24,16 @@ pub(crate) struct SkewObservation {
 }
 
 impl SkewObservation {
+    /// Return the clock skew that was reported.
+    pub fn skew(&self) -> ClockSkew {
+        self.skew
+    }
+
+    /// Return the time when we made this observation.
+    pub fn when(&self) -> Instant {
+        self.when
+    }
+
     /// Return true if this observation has been made more recently than
     /// `cutoff`. If cutoff is None, consider it's very far in the past.
     pub(crate) fn more_recent_than(&self, cutoff: Option<Instant>) -> bool {
@@ -39,6 +52,10 @@ pub struct SkewEstimate {
     n_observations: usize,
     /// A description of how confident we are.
     confidence: Confidence,
+    /// The lower end of a confidence interval for the skew.
+    interval_low: ClockSkew,
+    /// The upper end of a confidence interval for the skew.
+    interval_high: ClockSkew,
 }
 
 /// Subjective description of how sure we are that our clock is/isn't skewed.
@@ -56,6 +73,13 @@ enum Confidence {
 /// problem?
 const SIGNIFICANCE_THRESHOLD: Duration = Duration::from_secs(15 * 60);
 
+/// How many standard errors from the mean do we put the ends of our
+/// confidence interval?
+///
+/// (This value gives a 95% confidence interval, if the observations are
+/// normally distributed.)
+const INTERVAL_Z_SCORE: f64 = 1.96;
+
 impl std::fmt::Display for SkewEstimate {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         /// Format the whole-second part of `d`.
@@ -96,6 +120,25 @@ impl SkewEstimate {
         self.estimate
     }
 
+    /// Return the number of observations that contributed to this estimate.
+    ///
+    /// This doesn't count observations that were too old, or that we
+    /// discarded as outliers.
+    pub fn n_observations(&self) -> usize {
+        self.n_observations
+    }
+
+    /// Return a 95% confidence interval for the current clock skew.
+    ///
+    /// Unlike [`skew`](Self::skew), the ends of this interval are not rounded
+    /// to [`ClockSkew::None`] when they are too small to be worth reporting.
+    /// The interval assumes that our observations are normally distributed
+    /// around the real skew, which is not always so: a few relays with wrong
+    /// clocks can make it too narrow.
+    pub fn confidence_interval(&self) -> RangeInclusive<ClockSkew> {
+        self.interval_low..=self.interval_high
+    }
+
     /// Return true if this estimate is worth telling the user about.
     pub fn noteworthy(&self) -> bool {
         !matches!(self.estimate, ClockSkew::None) && !matches!(self.confidence, Confidence::None)
@@ -167,10 +210,19 @@ impl SkewEstimate {
             }
         };
 
+        // Use the standard error of the mean to find a confidence interval.
+        let margin = interval_margin(standard_deviation, n_observations);
+        let interval_low =
+            ClockSkew::from_secs_f64(mean - margin).expect("Somehow generated NaN clock skew‽");
+        let interval_high =
+            ClockSkew::from_secs_f64(mean + margin).expect("Somehow generated NaN clock skew‽");
+
         Some(SkewEstimate {
             estimate: estimate.if_above(SIGNIFICANCE_THRESHOLD),
             n_observations,
             confidence,
+            interval_low,
+            interval_high,
         })
     }
 }
@@ -228,6 +280,18 @@ fn mean_and_standard_deviation(values: &[f64]) -> (f64, f64) {
     (mean, variance.sqrt())
 }
 
+/// Return how far on either side of the mean our confidence interval should
+/// extend, given the standard deviation of `n` observations.
+///
+/// With fewer than two observations we can't say anything about their
+/// spread, so we return zero rather than NaN.
+fn interval_margin(standard_deviation: f64, n: usize) -> f64 {
+    if n < 2 || !standard_deviation.is_finite() {
+        return 0.0;
+    }
+    INTERVAL_Z_SCORE * standard_deviation / (n as f64).sqrt()
+}
+
 #[cfg(test)]
 mod test {
     // @@ begin test lint list maintained by maint/add_warning @@
@@ -388,6 +452,14 @@ mod test {
             est.to_string(),
             "slow by around 17m 7s (based on 8 recent observations, with some confidence)"
         );
+        assert_eq!(est.n_observations(), 8);
+
+        // The standard error is 7.67/sqrt(8) = 2.71 minutes, so the interval
+        // is about 5.3 minutes on either side of the mean.
+        let interval = est.confidence_interval();
+        assert_float_eq!(interval.start().as_secs_f64(), -1346.7, abs <= 1.0);
+        assert_float_eq!(interval.end().as_secs_f64(), -708.3, abs <= 1.0);
+        assert!(interval.start() < interval.end());
     }
 
     #[test]
@@ -406,5 +478,19 @@ mod test {
             est.to_string(),
             "not skewed by more than 15m (based on 8 recent observations, with high confidence)"
         );
+        assert_eq!(est.n_observations(), 8);
+
+        // The interval isn't rounded to zero, even though the estimate is.
+        let interval = est.confidence_interval();
+        assert!(matches!(interval.start(), ClockSkew::Slow(_)));
+        assert!(matches!(interval.end(), ClockSkew::Fast(_)));
+    }
+
+    #[test]
+    fn margin_with_few_observations() {
+        let (_, sd) = mean_and_standard_deviation(&[42.0]);
+        assert_eq!(interval_margin(sd, 1), 0.0);
+        assert_eq!(interval_margin(f64::NAN, 4), 0.0);
+        assert!(interval_margin(2.0, 4) > 0.0);
     }
 }
@@ -0,0 +1,266 @@
+//! Long-term statistics about how well each of our guards has worked.
+//!
+//! Unlike the rest of a guard's reachability status, these statistics are
+//! persistent: they are stored along with the guard itself, and they are never
+//! reset while the guard remains in our sample.  They don't affect which
+//! guards we choose; they exist so that users can see how each guard has
+//! behaved, and why we might have stopped using it.
+
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+use tor_linkspec::RelayIds;
+
+use crate::GuardStatus;
+
+/// A record of every attempt we've made to use a single guard.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub(crate) struct GuardStats {
+    /// The number of times we have given out this guard.
+    #[serde(default)]
+    n_attempts: u64,
+    /// The number of attempts that succeeded.
+    #[serde(default)]
+    n_successes: u64,
+    /// The number of attempts that failed.
+    #[serde(default)]
+    n_failures: u64,
+    /// The number of attempts that failed in a way we couldn't attribute to
+    /// the guard.
+    #[serde(default)]
+    n_indeterminate: u64,
+    /// The number of attempts that were abandoned before we learned whether
+    /// the guard worked.
+    #[serde(default)]
+    n_abandoned: u64,
+    /// The total time it took for our successful attempts to succeed.
+    #[serde(default, with = "humantime_serde")]
+    total_success_latency: Duration,
+    /// When, approximately, did an attempt last succeed?
+    #[serde(default, with = "humantime_serde")]
+    last_success: Option<SystemTime>,
+    /// When, approximately, did an attempt last fail?
+    #[serde(default, with = "humantime_serde")]
+    last_failure: Option<SystemTime>,
+}
+
+impl GuardStats {
+    /// Return true if we have never tried to use this guard.
+    pub(crate) fn is_empty(&self) -> bool {
+        self == &GuardStats::default()
+    }
+
+    /// Record that we have given out this guard.
+    pub(crate) fn note_attempt(&mut self) {
+        self.n_attempts = self.n_attempts.saturating_add(1);
+    }
+
+    /// Record that an attempt to use this guard had the outcome `status`,
+    /// `latency` after we gave it out.
+    pub(crate) fn note_outcome(&mut self, status: GuardStatus, latency: Duration, now: SystemTime) {
+        match status {
+            GuardStatus::Success => {
+                self.n_successes = self.n_successes.saturating_add(1);
+                self.total_success_latency = self.total_success_latency.saturating_add(latency);
+                self.last_success = Some(now);
+            }
+            GuardStatus::Failure => {
+                self.n_failures = self.n_failures.saturating_add(1);
+                self.last_failure = Some(now);
+            }
+            GuardStatus::Indeterminate => {
+                self.n_indeterminate = self.n_indeterminate.saturating_add(1);
+            }
+            GuardStatus::AttemptAbandoned => {
+                self.n_abandoned = self.n_abandoned.saturating_add(1);
+            }
+        }
+    }
+}
+
+/// Statistics about a single guard in our sample.
+///
+/// Returned by [`GuardMgr::guard_stats`](crate::GuardMgr::guard_stats).
+#[derive(Clone, Debug)]
+pub struct GuardStatsEntry {
+    /// The identities of the guard.
+    pub(crate) ids: RelayIds,
+    /// True if this is currently one of our primary guards.
+    pub(crate) is_primary: bool,
+    /// True if we believe this guard is currently reachable or might be.
+    pub(crate) is_reachable: bool,
+    /// The statistics themselves.
+    pub(crate) stats: GuardStats,
+}
+
+impl GuardStatsEntry {
+    /// Return the identities of this guard.
+    pub fn ids(&self) -> &RelayIds {
+        &self.ids
+    }
+
+    /// Return true if this is currently one of our primary guards.
+    ///
+    /// We prefer primary guards over all others.
+    pub fn is_primary(&self) -> bool {
+        self.is_primary
+    }
+
+    /// Return false if our most recent attempt to use this guard failed,
+    /// and we are waiting before we retry it.
+    pub fn is_reachable(&self) -> bool {
+        self.is_reachable
+    }
+
+    /// Return the number of times we have tried to use this guard.
+    pub fn n_attempts(&self) -> u64 {
+        self.stats.n_attempts
+    }
+
+    /// Return the number of attempts to use this guard that succeeded.
+    pub fn n_successes(&self) -> u64 {
+        self.stats.n_successes
+    }
+
+    /// Return the number of attempts to use this guard that failed.
+    pub fn n_failures(&self) -> u64 {
+        self.stats.n_failures
+    }
+
+    /// Return the number of attempts to use this guard that failed in a way
+    /// that we could not attribute to the guard.
+    pub fn n_indeterminate(&self) -> u64 {
+        self.stats.n_indeterminate
+    }
+
+    /// Return the number of attempts to use this guard that were abandoned
+    /// before we learned whether the guard worked.
+    pub fn n_abandoned(&self) -> u64 {
+        self.stats.n_abandoned
+    }
+
+    /// Return the fraction of our attempts to use this guard that succeeded,
+    /// out of those that definitely succeeded or failed.
+    ///
+    /// Return None if no attempt has definitely succeeded or failed.
+    pub fn success_rate(&self) -> Option<f64> {
+        let n_decided = self.stats.n_successes + self.stats.n_failures;
+        if n_decided == 0 {
+            None
+        } else {
+            #[allow(clippy::cast_precision_loss)]
+            Some(self.stats.n_successes as f64 / n_decided as f64)
+        }
+    }
+
+    /// Return the average time it took for our successful attempts to use this
+    /// guard to succeed.
+    ///
+    /// Return None if no attempt has succeeded.
+    pub fn mean_success_latency(&self) -> Option<Duration> {
+        let n = u32::try_from(self.stats.n_successes).unwrap_or(u32::MAX);
+        self.stats.total_success_latency.checked_div(n)
+    }
+
+    /// Return the approximate time of our last successful attempt to use this
+    /// guard, if any.
+    pub fn last_success(&self) -> Option<SystemTime> {
+        self.stats.last_success
+    }
+
+    /// Return the approximate time of our last failed attempt to use this
+    /// guard, if any.
+    pub fn last_failure(&self) -> Option<SystemTime> {
+        self.stats.last_failure
+    }
+}
+
+/// How much of the network's guard weight our current sample holds.
+///
+/// Returned by [`GuardMgr::sample_weight_fraction`](crate::GuardMgr::sample_weight_fraction).
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct SampleWeightFraction {
+    /// The fraction of the total weight of possible guards that the guards in
+    /// our sample hold, from 0.0 to 1.0.
+    pub fraction: f64,
+    /// The largest fraction that the consensus allows our sample to hold.
+    ///
+    /// `fraction` can exceed this if we need to in order to have enough
+    /// guards, or by the weight of a single guard.
+    pub max_fraction: f64,
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use float_eq::assert_float_eq;
+
+    #[test]
+    fn record_and_report() {
+        let now = SystemTime::now();
+        let sec = Duration::from_secs(1);
+        let mut stats = GuardStats::default();
+        assert!(stats.is_empty());
+
+        for _ in 0..5 {
+            stats.note_attempt();
+        }
+        stats.note_outcome(GuardStatus::Success, sec, now);
+        stats.note_outcome(GuardStatus::Success, 3 * sec, now + sec);
+        stats.note_outcome(GuardStatus::Failure, 10 * sec, now + 2 * sec);
+        stats.note_outcome(GuardStatus::Indeterminate, sec, now);
+        stats.note_outcome(GuardStatus::AttemptAbandoned, sec, now);
+        assert!(!stats.is_empty());
+
+        let entry = GuardStatsEntry {
+            ids: RelayIds::empty(),
+            is_primary: true,
+            is_reachable: false,
+            stats: stats.clone(),
+        };
+        assert_eq!(entry.n_attempts(), 5);
+        assert_eq!(entry.n_successes(), 2);
+        assert_eq!(entry.n_failures(), 1);
+        assert_eq!(entry.n_indeterminate(), 1);
+        assert_eq!(entry.n_abandoned(), 1);
+        assert_float_eq!(entry.success_rate().unwrap(), 2.0 / 3.0, abs <= 1e-9);
+        assert_eq!(entry.mean_success_latency(), Some(2 * sec));
+        assert_eq!(entry.last_success(), Some(now + sec));
+        assert_eq!(entry.last_failure(), Some(now + 2 * sec));
+
+        // Make sure we can round-trip through our storage format.
+        let json = serde_json::to_string(&stats).unwrap();
+        let stats2: GuardStats = serde_json::from_str(&json).unwrap();
+        assert_eq!(stats2.n_attempts, 5);
+        assert_eq!(stats2.total_success_latency, 4 * sec);
+
+        // Old state with no stats at all is fine.
+        let stats3: GuardStats = serde_json::from_str("{}").unwrap();
+        assert!(stats3.is_empty());
+    }
+
+    #[test]
+    fn no_outcomes() {
+        let entry = GuardStatsEntry {
+            ids: RelayIds::empty(),
+            is_primary: false,
+            is_reachable: true,
+            stats: GuardStats::default(),
+        };
+        assert!(entry.success_rate().is_none());
+        assert!(entry.mean_success_latency().is_none());
+    }
+}
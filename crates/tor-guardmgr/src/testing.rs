@@ -0,0 +1,6 @@
+//! Testing-only facilities for the guard manager.
+//!
+//! This module is only available when `tor-guardmgr` is built with the
+//! `testing` feature.  Nothing here is covered by semver.
+
+pub mod replay;
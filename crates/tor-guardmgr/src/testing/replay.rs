@@ -0,0 +1,843 @@
+//! Deterministic replay of recorded guard manager activity.
+//!
+//! A [`ReplayLog`] lists the inputs that a [`GuardMgr`] received: new network
+//! directories, filter changes, requests for guards, and the outcomes of those
+//! requests, each with the time that passed since the previous input.
+//! [`replay`] feeds these inputs to a new `GuardMgr` running on a
+//! [`MockRuntime`], with a seeded random number generator, and returns a
+//! [`GuardMgrSnapshot`] of the resulting guard state.  Replaying the same log
+//! always gives the same result.
+//!
+//! Logs can be written by hand, or captured from a running `GuardMgr` with
+//! [`GuardMgr::start_recording`] and [`GuardMgr::finish_recording`].
+//! Recorded logs are redacted: relays are identified only by small-integer
+//! pseudonyms, assigned in a random order, their addresses are replaced with
+//! synthetic ones that preserve which relays share a /16, and their weights
+//! are rounded to two significant bits.
+//!
+//! # Limitations
+//!
+//! * We only record the relay properties that matter for choosing guards:
+//!   whether a relay is suitable as a guard or as a directory cache, its guard
+//!   weight, and its IPv4 address.  Families, IPv6 addresses, exit policies
+//!   and consensus parameters are not recorded.
+//! * We only record filters on reachable addresses, and we don't record the
+//!   restrictions in a [`GuardUsage`].
+//! * Bridges and fallback directories are not supported.
+//! * A recorded instance uses a different random number generator than the
+//!   replay, so it builds a different sample.  When the log says which guard
+//!   the recorded instance picked for an attempt, we require the replay to
+//!   pick the same one.  If it can't, we pick whatever guard we can instead,
+//!   and count the divergence in [`ReplayOutcome::n_diverged`].
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime};
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom as _;
+use rand::{RngCore, SeedableRng as _};
+use serde::{Deserialize, Serialize};
+use tor_linkspec::{HasAddrs as _, HasRelayIds};
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdir::testprovider::TestNetDirProvider;
+use tor_netdir::{
+    MdReceiver as _, NetDir, NetDirProvider, PartialNetDir, RelayWeight as NetDirWeight, WeightRole,
+};
+use tor_netdoc::doc::microdesc::Microdesc;
+use tor_netdoc::doc::netstatus::{Lifetime, MdConsensus, RelayFlags, RelayWeight};
+use tor_netdoc::types::policy::AddrPortPattern;
+use tor_persist::TestingStateMgr;
+use tor_rtcompat::{BlockOn as _, SleepProvider as _};
+use tor_rtmock::MockRuntime;
+
+use crate::pending::RequestId;
+use crate::{
+    GuardFilter, GuardMgr, GuardMgrError, GuardMonitor, GuardRestriction, GuardStatus, GuardUsage,
+    GuardUsageBuilder, GuardUsageKind, PickGuardError, TestConfig,
+};
+
+/// A log of inputs to a [`GuardMgr`], to be replayed with [`replay`].
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct ReplayLog {
+    /// A seed for the random number generator that we use during replay.
+    #[serde(default)]
+    pub seed: u64,
+    /// The inputs to replay, in order.
+    #[serde(default)]
+    pub events: Vec<ReplayEntry>,
+    /// If present, the state that the `GuardMgr` must be in after the replay.
+    #[serde(default)]
+    pub expected: Option<GuardMgrSnapshot>,
+}
+
+/// A single input in a [`ReplayLog`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct ReplayEntry {
+    /// How much time passed between the previous input and this one.
+    #[serde(default, with = "humantime_serde")]
+    pub delay: Duration,
+    /// The input itself.
+    pub event: ReplayEvent,
+}
+
+impl ReplayEntry {
+    /// Return a new `ReplayEntry` for `event`, arriving `delay` after the
+    /// previous one.
+    pub fn new(delay: Duration, event: ReplayEvent) -> Self {
+        ReplayEntry { delay, event }
+    }
+}
+
+/// An input to a [`GuardMgr`].
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ReplayEvent {
+    /// A new network directory arrived.
+    NetDir {
+        /// The relays in the directory.
+        relays: Vec<RelaySpec>,
+        /// Consensus parameters to set in the directory.
+        #[serde(default)]
+        params: BTreeMap<String, i32>,
+    },
+    /// The filter on our guards changed.
+    SetFilter {
+        /// A list of reachable-address filters.
+        ///
+        /// Each element is a list of [`AddrPortPattern`]s, of which a guard
+        /// must match at least one; a guard must match every element.  If
+        /// this is empty, there is no filter.
+        reachable_addrs: Vec<Vec<String>>,
+    },
+    /// We asked the `GuardMgr` for a guard.
+    ///
+    /// Attempts are numbered from zero, in the order in which they appear.
+    Select {
+        /// True if we wanted a guard for a one-hop directory request.
+        #[serde(default)]
+        directory: bool,
+        /// The pseudonym of the guard that we picked when we recorded this
+        /// log, if known.
+        #[serde(default)]
+        guard: Option<u32>,
+    },
+    /// We reported the outcome of an attempt.
+    Outcome {
+        /// The number of the attempt.
+        attempt: usize,
+        /// The outcome.
+        status: ReplayStatus,
+    },
+}
+
+/// The outcome of an attempt to use a guard.
+///
+/// This corresponds to [`GuardStatus`].
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ReplayStatus {
+    /// See [`GuardStatus::Success`].
+    Success,
+    /// See [`GuardStatus::Failure`].
+    Failure,
+    /// See [`GuardStatus::Indeterminate`].
+    Indeterminate,
+    /// See [`GuardStatus::AttemptAbandoned`].
+    AttemptAbandoned,
+}
+
+impl From<GuardStatus> for ReplayStatus {
+    fn from(status: GuardStatus) -> Self {
+        match status {
+            GuardStatus::Success => ReplayStatus::Success,
+            GuardStatus::Failure => ReplayStatus::Failure,
+            GuardStatus::Indeterminate => ReplayStatus::Indeterminate,
+            GuardStatus::AttemptAbandoned => ReplayStatus::AttemptAbandoned,
+        }
+    }
+}
+
+impl From<ReplayStatus> for GuardStatus {
+    fn from(status: ReplayStatus) -> Self {
+        match status {
+            ReplayStatus::Success => GuardStatus::Success,
+            ReplayStatus::Failure => GuardStatus::Failure,
+            ReplayStatus::Indeterminate => GuardStatus::Indeterminate,
+            ReplayStatus::AttemptAbandoned => GuardStatus::AttemptAbandoned,
+        }
+    }
+}
+
+/// A description of a single relay in a [`ReplayEvent::NetDir`].
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct RelaySpec {
+    /// A pseudonym for this relay.
+    ///
+    /// We derive the relay's identities from this.
+    pub id: u32,
+    /// The relay's IPv4 address.
+    pub addr: Ipv4Addr,
+    /// True if the relay is suitable for use as a guard.
+    #[serde(default)]
+    pub guard: bool,
+    /// True if the relay is a directory cache.
+    #[serde(default)]
+    pub dir_cache: bool,
+    /// The relay's weight in the consensus.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+/// Return the default weight for a relay in a [`RelaySpec`].
+fn default_weight() -> u32 {
+    1000
+}
+
+impl RelaySpec {
+    /// Return a new `RelaySpec` for a guard and directory cache with the
+    /// pseudonym `id`, at `addr`.
+    pub fn new(id: u32, addr: Ipv4Addr) -> Self {
+        RelaySpec {
+            id,
+            addr,
+            guard: true,
+            dir_cache: true,
+            weight: default_weight(),
+        }
+    }
+}
+
+/// The state of a [`GuardMgr`]'s active guard sample, in terms of relay
+/// pseudonyms.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct GuardMgrSnapshot {
+    /// Every guard in the sample, in the order in which we added them.
+    pub sample: Vec<u32>,
+    /// The confirmed guards, in the order in which we confirmed them.
+    pub confirmed: Vec<u32>,
+    /// The primary guards, in order of preference.
+    pub primary: Vec<u32>,
+}
+
+/// The result of a successful [`replay`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ReplayOutcome {
+    /// The state of the `GuardMgr` after the replay.
+    pub snapshot: GuardMgrSnapshot,
+    /// The number of attempts for which we couldn't pick the guard that the
+    /// log says we picked, and picked another one instead.
+    pub n_diverged: usize,
+}
+
+/// An error from [`replay`].
+#[derive(Clone, Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ReplayError {
+    /// We couldn't create the `GuardMgr`.
+    #[error("Unable to create guard manager")]
+    Setup(#[source] Arc<GuardMgrError>),
+    /// A directory in the log was unusable.
+    #[error("Event {0}: unable to build a usable network directory")]
+    BadNetDir(usize),
+    /// A filter in the log was unparseable.
+    #[error("Event {0}: invalid address pattern {1:?}")]
+    BadFilter(usize, String),
+    /// We couldn't pick a guard.
+    #[error("Event {0}: unable to select a guard")]
+    NoGuard(usize, #[source] PickGuardError),
+    /// The log reported an outcome for an attempt that didn't exist, or that
+    /// already had an outcome.
+    #[error("Event {0}: no pending attempt {1}")]
+    UnknownAttempt(usize, usize),
+    /// The final state didn't match the one in the log.
+    #[error("Final state {found:?} did not match expected state {expected:?}")]
+    Mismatch {
+        /// The state in the log.
+        expected: GuardMgrSnapshot,
+        /// The state after replay.
+        found: GuardMgrSnapshot,
+    },
+}
+
+/// Replay `log` on a new [`GuardMgr`], and return its final state.
+///
+/// If `log` has an expected state, return an error if the final state doesn't
+/// match it.
+pub fn replay(log: &ReplayLog) -> Result<ReplayOutcome, ReplayError> {
+    let _rng = SeededRng::install(log.seed);
+    let runtime = MockRuntime::new();
+    let outcome = runtime.clone().block_on(replay_inner(runtime, log))?;
+    match &log.expected {
+        Some(expected) if expected != &outcome.snapshot => Err(ReplayError::Mismatch {
+            expected: expected.clone(),
+            found: outcome.snapshot,
+        }),
+        _ => Ok(outcome),
+    }
+}
+
+/// Helper for [`replay`]: run the replay on `runtime`.
+async fn replay_inner(runtime: MockRuntime, log: &ReplayLog) -> Result<ReplayOutcome, ReplayError> {
+    let statemgr = TestingStateMgr::new();
+    let guardmgr = GuardMgr::new(runtime.clone(), statemgr, &TestConfig::default())
+        .map_err(|e| ReplayError::Setup(Arc::new(e)))?;
+    let provider = Arc::new(TestNetDirProvider::new());
+    let dyn_provider: Arc<dyn NetDirProvider> = provider.clone();
+    guardmgr
+        .install_netdir_provider(&dyn_provider)
+        .map_err(|e| ReplayError::Setup(Arc::new(e)))?;
+
+    let mut monitors: Vec<Option<GuardMonitor>> = Vec::new();
+    let mut n_diverged = 0;
+    for (idx, entry) in log.events.iter().enumerate() {
+        runtime.advance_by(entry.delay).await;
+        match &entry.event {
+            ReplayEvent::NetDir { relays, params } => {
+                let netdir = build_netdir(relays, params, runtime.wallclock())
+                    .ok_or(ReplayError::BadNetDir(idx))?;
+                provider.set_netdir_and_notify(netdir).await;
+            }
+            ReplayEvent::SetFilter { reachable_addrs } => {
+                let mut filter = GuardFilter::unfiltered();
+                for patterns in reachable_addrs {
+                    let patterns = patterns
+                        .iter()
+                        .map(|p| {
+                            p.parse::<AddrPortPattern>()
+                                .map_err(|_| ReplayError::BadFilter(idx, p.clone()))
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    filter.push_reachable_addresses(patterns);
+                }
+                guardmgr.set_filter(filter);
+            }
+            ReplayEvent::Select { directory, guard } => {
+                let kind = if *directory {
+                    GuardUsageKind::OneHopDirectory
+                } else {
+                    GuardUsageKind::Data
+                };
+                let mut usage = GuardUsageBuilder::new();
+                usage.kind(kind);
+                let unpinned = usage.build().expect("Unable to build usage");
+                let picked = match guard {
+                    Some(id) => {
+                        usage
+                            .restrictions()
+                            .push(GuardRestriction::RequireId(rsa_for_pseudonym(*id).into()));
+                        let pinned = usage.build().expect("Unable to build usage");
+                        guardmgr.select_guard(pinned).ok()
+                    }
+                    None => None,
+                };
+                let (_first_hop, monitor, _usable) = match picked {
+                    Some(picked) => picked,
+                    None => {
+                        if guard.is_some() {
+                            n_diverged += 1;
+                        }
+                        guardmgr
+                            .select_guard(unpinned)
+                            .map_err(|e| ReplayError::NoGuard(idx, e))?
+                    }
+                };
+                monitors.push(Some(monitor));
+            }
+            ReplayEvent::Outcome { attempt, status } => {
+                let monitor = monitors
+                    .get_mut(*attempt)
+                    .and_then(Option::take)
+                    .ok_or(ReplayError::UnknownAttempt(idx, *attempt))?;
+                monitor.report((*status).into());
+            }
+        }
+        runtime.progress_until_stalled().await;
+    }
+
+    let snapshot = {
+        let inner = guardmgr.inner.lock().expect("Poisoned lock");
+        snapshot_with(&inner.guards.active_guards().id_lists(), |ids| {
+            ids.rsa_identity().and_then(pseudonym_from_rsa)
+        })
+    };
+    // Any attempts that are still pending get reported as abandoned when we
+    // drop their monitors; that's fine now that we have our snapshot.
+    drop(monitors);
+    drop(guardmgr);
+
+    Ok(ReplayOutcome {
+        snapshot,
+        n_diverged,
+    })
+}
+
+/// Build a [`GuardMgrSnapshot`] from the lists of guards in a sample, using
+/// `pseudonym` to find each guard's pseudonym.
+///
+/// Guards without a pseudonym are omitted.
+fn snapshot_with<F>(lists: &[&[crate::ids::GuardId]; 3], pseudonym: F) -> GuardMgrSnapshot
+where
+    F: Fn(&tor_linkspec::RelayIds) -> Option<u32>,
+{
+    let convert =
+        |list: &[crate::ids::GuardId]| list.iter().filter_map(|id| pseudonym(&id.0)).collect();
+    GuardMgrSnapshot {
+        sample: convert(lists[0]),
+        confirmed: convert(lists[1]),
+        primary: convert(lists[2]),
+    }
+}
+
+/// Return the RSA identity that we use for the relay with pseudonym `id`.
+fn rsa_for_pseudonym(id: u32) -> RsaIdentity {
+    let mut bytes = [0_u8; 20];
+    bytes[..4].copy_from_slice(&id.to_be_bytes());
+    bytes[19] = 0x1d;
+    bytes.into()
+}
+
+/// Return the pseudonym for a relay whose RSA identity is `rsa`, if it was
+/// generated with [`rsa_for_pseudonym`].
+fn pseudonym_from_rsa(rsa: &RsaIdentity) -> Option<u32> {
+    let bytes = rsa.as_bytes();
+    let id = u32::from_be_bytes(bytes[..4].try_into().expect("wrong slice length"));
+    (rsa == &rsa_for_pseudonym(id)).then_some(id)
+}
+
+/// Round `weight` down to its two most significant bits.
+///
+/// Exact weights would make it easy to match recorded relays up with the real
+/// ones in the consensus; rounded ones are still good enough to pick guards
+/// with about the same probabilities.
+fn bucket_weight(weight: u32) -> u32 {
+    let shift = (u32::BITS - weight.leading_zeros()).saturating_sub(2);
+    (weight >> shift) << shift
+}
+
+/// Build a [`NetDir`] containing `relays`, valid as of `now`.
+///
+/// Return None if the directory couldn't be built, or wasn't usable.
+fn build_netdir(
+    relays: &[RelaySpec],
+    params: &BTreeMap<String, i32>,
+    now: SystemTime,
+) -> Option<NetDir> {
+    let hour = Duration::from_secs(3600);
+    let lifetime = Lifetime::new(now, now + hour, now + 3 * hour).ok()?;
+    let mut bld = MdConsensus::builder();
+    bld.consensus_method(34)
+        .lifetime(lifetime)
+        .param("bwweightscale", 1)
+        .weights("".parse().ok()?);
+
+    let mut microdescs = Vec::new();
+    for relay in relays {
+        let rsa = rsa_for_pseudonym(relay.id);
+        let mut ed = [0xed_u8; 32];
+        ed[..4].copy_from_slice(&relay.id.to_be_bytes());
+        let md = Microdesc::builder()
+            .ntor_key((*b"----no ntor key for replayed dir").into())
+            .ed25519_id(ed.into())
+            .parse_ipv4_policy("reject 1-65535")
+            .ok()?
+            .testing_md()
+            .ok()?;
+
+        let mut flags = RelayFlags::RUNNING | RelayFlags::VALID;
+        if relay.guard {
+            flags |= RelayFlags::GUARD | RelayFlags::FAST | RelayFlags::STABLE;
+        }
+        let protos = if relay.dir_cache {
+            flags |= RelayFlags::V2DIR;
+            "DirCache=2"
+        } else {
+            ""
+        };
+        bld.rs()
+            .identity(rsa)
+            .add_or_port(SocketAddr::from((relay.addr, 9001)))
+            .protos(protos.parse().ok()?)
+            .set_flags(flags)
+            .weight(RelayWeight::Measured(relay.weight))
+            .doc_digest(*md.digest())
+            .build_into(&mut bld)
+            .ok()?;
+        microdescs.push(md);
+    }
+    let consensus = bld.testing_consensus().ok()?;
+
+    let params = params.iter().map(|(k, v)| (k.clone(), *v)).collect();
+    let mut partial = PartialNetDir::new(consensus, Some(&params));
+    for md in microdescs {
+        partial.add_microdesc(md);
+    }
+    partial.unwrap_if_sufficient().ok()
+}
+
+thread_local! {
+    /// A seeded random number generator to use instead of `thread_rng()`, if
+    /// a replay is in progress on this thread.
+    static SEEDED_RNG: RefCell<Option<StdRng>> = const { RefCell::new(None) };
+}
+
+/// A guard object that makes this crate use a seeded random number
+/// generator on the current thread, for as long as it exists.
+struct SeededRng;
+
+impl SeededRng {
+    /// Start using a random number generator seeded with `seed`.
+    fn install(seed: u64) -> Self {
+        SEEDED_RNG.with(|rng| *rng.borrow_mut() = Some(StdRng::seed_from_u64(seed)));
+        SeededRng
+    }
+}
+
+impl Drop for SeededRng {
+    fn drop(&mut self) {
+        SEEDED_RNG.with(|rng| *rng.borrow_mut() = None);
+    }
+}
+
+/// The random number generator returned by [`crate::util::rng`].
+///
+/// This is `thread_rng()`, unless a [`replay`] is in progress on the current
+/// thread.
+///
+/// We deliberately don't implement `CryptoRng` for this type: during a
+/// replay, it is seeded with a well-known value.
+pub(crate) struct CrateRng;
+
+impl CrateRng {
+    /// Run `f` on whichever random number generator we should be using.
+    fn with<T>(f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+        SEEDED_RNG.with(|seeded| match seeded.borrow_mut().as_mut() {
+            Some(rng) => f(rng),
+            None => f(&mut rand::thread_rng()),
+        })
+    }
+}
+
+impl RngCore for CrateRng {
+    fn next_u32(&mut self) -> u32 {
+        Self::with(|rng| rng.next_u32())
+    }
+    fn next_u64(&mut self) -> u64 {
+        Self::with(|rng| rng.next_u64())
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        Self::with(|rng| rng.fill_bytes(dest));
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        Self::with(|rng| rng.try_fill_bytes(dest))
+    }
+}
+
+/// A recorder that captures a [`ReplayLog`] from a running [`GuardMgr`].
+///
+/// See [`GuardMgr::start_recording`].
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    /// The time of the most recent event.
+    last_event: Instant,
+    /// The events we've recorded so far.
+    events: Vec<ReplayEntry>,
+    /// The pseudonym for each relay we've seen.
+    pseudonyms: HashMap<RsaIdentity, u32>,
+    /// The synthetic /16 for each real /16 we've seen.
+    subnets: HashMap<[u8; 2], u16>,
+    /// The attempt number for each request we've seen.
+    attempts: HashMap<RequestId, usize>,
+    /// The number of attempts we've seen.
+    n_attempts: usize,
+    /// The most recent directory that we recorded.
+    last_netdir: Weak<NetDir>,
+}
+
+impl Recorder {
+    /// Return a new `Recorder` that starts recording at `now`.
+    pub(crate) fn new(now: Instant) -> Self {
+        Recorder {
+            last_event: now,
+            events: Vec::new(),
+            pseudonyms: HashMap::new(),
+            subnets: HashMap::new(),
+            attempts: HashMap::new(),
+            n_attempts: 0,
+            last_netdir: Weak::new(),
+        }
+    }
+
+    /// Record that `event` happened at `now`.
+    fn push(&mut self, now: Instant, event: ReplayEvent) {
+        let delay = now.saturating_duration_since(self.last_event);
+        self.last_event = self.last_event.max(now);
+        self.events.push(ReplayEntry { delay, event });
+    }
+
+    /// Return the pseudonym for the relay with RSA identity `rsa`, allocating
+    /// one if necessary.
+    ///
+    /// We allocate pseudonyms in order, so the caller must present new relays
+    /// in a random order: otherwise, the pseudonyms would reveal the order of
+    /// the relays' identities.
+    fn pseudonym(&mut self, rsa: &RsaIdentity) -> u32 {
+        let next = u32::try_from(self.pseudonyms.len()).unwrap_or(u32::MAX);
+        *self.pseudonyms.entry(*rsa).or_insert(next)
+    }
+
+    /// Return a synthetic address to use instead of `addr`.
+    ///
+    /// Relays that share a /16 get synthetic addresses that share a /16, and
+    /// each relay gets a distinct address.
+    fn redact_addr(&mut self, addr: Ipv4Addr, pseudonym: u32) -> Ipv4Addr {
+        let [a, b, ..] = addr.octets();
+        // We start at 1, to avoid putting relays in 0.0.0.0/16.
+        let next = u16::try_from(self.subnets.len() + 1).unwrap_or(u16::MAX);
+        let [s1, s2] = self.subnets.entry([a, b]).or_insert(next).to_be_bytes();
+        let [_, _, h1, h2] = pseudonym.to_be_bytes();
+        Ipv4Addr::new(s1, s2, h1, h2)
+    }
+
+    /// Record `netdir` at `now`, if it's not the one we recorded last.
+    pub(crate) fn note_netdir(&mut self, now: Instant, netdir: &Arc<NetDir>) {
+        if std::ptr::eq(self.last_netdir.as_ptr(), Arc::as_ptr(netdir)) {
+            return;
+        }
+        self.last_netdir = Arc::downgrade(netdir);
+
+        let mut netdir_relays: Vec<_> = netdir.relays().collect();
+        netdir_relays.shuffle(&mut rand::thread_rng());
+        let mut relays = Vec::new();
+        for relay in netdir_relays {
+            let Some(rsa) = relay.rsa_identity() else {
+                continue;
+            };
+            let Some(addr) = relay.addrs().iter().find_map(|a| match a {
+                SocketAddr::V4(a) => Some(*a.ip()),
+                SocketAddr::V6(_) => None,
+            }) else {
+                continue;
+            };
+            let id = self.pseudonym(rsa);
+            let details = relay.low_level_details();
+            let weight = netdir
+                .relay_weight(&relay, WeightRole::Guard)
+                .checked_div(NetDirWeight::from(1))
+                .unwrap_or(0.0);
+            relays.push(RelaySpec {
+                id,
+                addr: self.redact_addr(addr, id),
+                guard: details.is_suitable_as_guard(),
+                dir_cache: details.is_dir_cache(),
+                // This is a saturating conversion.
+                #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                weight: bucket_weight(weight as u32),
+            });
+        }
+        relays.sort_by_key(|spec| spec.id);
+        self.push(
+            now,
+            ReplayEvent::NetDir {
+                relays,
+                params: BTreeMap::new(),
+            },
+        );
+    }
+
+    /// Record that our filter changed to `filter` at `now`.
+    pub(crate) fn note_filter(&mut self, now: Instant, filter: &GuardFilter) {
+        let reachable_addrs = filter
+            .reachable_addr_patterns()
+            .map(|patterns| patterns.iter().map(ToString::to_string).collect())
+            .collect();
+        self.push(now, ReplayEvent::SetFilter { reachable_addrs });
+    }
+
+    /// Record that we picked `guard` for `usage` in request `id`, at `now`.
+    pub(crate) fn note_select<T: HasRelayIds + ?Sized>(
+        &mut self,
+        now: Instant,
+        id: RequestId,
+        usage: &GuardUsage,
+        guard: &T,
+    ) {
+        let directory = usage.kind == GuardUsageKind::OneHopDirectory;
+        let guard = guard.rsa_identity().map(|rsa| self.pseudonym(rsa));
+        self.attempts.insert(id, self.n_attempts);
+        self.n_attempts += 1;
+        self.push(now, ReplayEvent::Select { directory, guard });
+    }
+
+    /// Record that request `id` had the outcome `status` at `now`.
+    pub(crate) fn note_outcome(&mut self, now: Instant, id: RequestId, status: GuardStatus) {
+        if let Some(attempt) = self.attempts.remove(&id) {
+            let status = status.into();
+            self.push(now, ReplayEvent::Outcome { attempt, status });
+        }
+    }
+
+    /// Finish recording, and return the log.
+    ///
+    /// We don't include an expected final state, since the state after replay
+    /// may legitimately differ from the one we recorded.
+    pub(crate) fn finish(self) -> ReplayLog {
+        ReplayLog {
+            seed: 0,
+            events: self.events,
+            expected: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    /// Return a log that builds a sample of guards and uses some of them.
+    fn sample_log(seed: u64) -> ReplayLog {
+        let sec = Duration::from_secs(1);
+        let relays = (0..20)
+            .map(|id| RelaySpec::new(id, Ipv4Addr::new(10, id as u8, 0, 1)))
+            .collect();
+        let params = [
+            ("guard-min-filtered-sample-size", 5),
+            ("guard-n-primary-guards", 2),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+        let select = ReplayEvent::Select {
+            directory: false,
+            guard: None,
+        };
+        let outcome = |attempt, status| ReplayEvent::Outcome { attempt, status };
+        let events = vec![
+            ReplayEntry::new(sec, ReplayEvent::NetDir { relays, params }),
+            ReplayEntry::new(sec, select.clone()),
+            ReplayEntry::new(sec, outcome(0, ReplayStatus::Failure)),
+            ReplayEntry::new(sec, select.clone()),
+            ReplayEntry::new(sec, outcome(1, ReplayStatus::Success)),
+            ReplayEntry::new(10 * sec, select),
+            ReplayEntry::new(sec, outcome(2, ReplayStatus::Indeterminate)),
+        ];
+        ReplayLog {
+            seed,
+            events,
+            expected: None,
+        }
+    }
+
+    #[test]
+    fn deterministic() {
+        let mut log = sample_log(7);
+        let first = replay(&log).unwrap();
+        assert_eq!(first.snapshot.sample.len(), 5);
+        assert_eq!(first.snapshot.primary.len(), 2);
+        assert_eq!(first.snapshot.confirmed.len(), 1);
+        assert_eq!(first.n_diverged, 0);
+        for _ in 0..3 {
+            assert_eq!(replay(&log).unwrap().snapshot, first.snapshot);
+        }
+
+        // A log with an expected state is checked against it.
+        log.expected = Some(first.snapshot.clone());
+        replay(&log).unwrap();
+        let mut wrong = first.snapshot.clone();
+        wrong.confirmed.clear();
+        log.expected = Some(wrong);
+        assert!(matches!(replay(&log), Err(ReplayError::Mismatch { .. })));
+
+        // Some other seed picks a different sample.
+        let differs =
+            (8..20).any(|seed| replay(&sample_log(seed)).unwrap().snapshot != first.snapshot);
+        assert!(differs);
+    }
+
+    #[test]
+    fn serde_format() {
+        let json = r#"{
+            "seed": 3,
+            "events": [
+                {"delay": "5s", "event": {"type": "net_dir", "relays": [
+                    {"id": 1, "addr": "10.0.0.1", "guard": true, "dir_cache": true},
+                    {"id": 2, "addr": "10.1.0.1", "guard": true, "weight": 50}
+                ]}},
+                {"event": {"type": "set_filter", "reachable_addrs": [["10.0.0.0/16:*"]]}},
+                {"event": {"type": "select"}},
+                {"delay": "1m", "event": {"type": "outcome", "attempt": 0, "status": "success"}}
+            ]
+        }"#;
+        let log: ReplayLog = serde_json::from_str(json).unwrap();
+        assert_eq!(log.seed, 3);
+        assert_eq!(log.events[0].delay, Duration::from_secs(5));
+        assert_eq!(log.events[1].delay, Duration::ZERO);
+        let ReplayEvent::NetDir { relays, .. } = &log.events[0].event else {
+            panic!("not a netdir");
+        };
+        assert_eq!(relays[0].weight, 1000);
+        assert!(!relays[1].dir_cache);
+
+        let outcome = replay(&log).unwrap();
+        assert_eq!(outcome.snapshot.sample, vec![1]);
+        assert_eq!(outcome.snapshot.confirmed, vec![1]);
+
+        // Errors in the log are reported.
+        let mut bad = log.clone();
+        bad.events.truncate(3);
+        bad.events.push(ReplayEntry::new(
+            Duration::ZERO,
+            ReplayEvent::Outcome {
+                attempt: 5,
+                status: ReplayStatus::Failure,
+            },
+        ));
+        assert!(matches!(
+            replay(&bad),
+            Err(ReplayError::UnknownAttempt(3, 5))
+        ));
+    }
+
+    #[test]
+    fn pseudonyms() {
+        for id in [0, 1, 77, u32::MAX] {
+            assert_eq!(pseudonym_from_rsa(&rsa_for_pseudonym(id)), Some(id));
+        }
+        assert_eq!(pseudonym_from_rsa(&[0x42; 20].into()), None);
+    }
+
+    #[test]
+    fn weight_buckets() {
+        assert_eq!(bucket_weight(0), 0);
+        assert_eq!(bucket_weight(1), 1);
+        assert_eq!(bucket_weight(3), 3);
+        assert_eq!(bucket_weight(5), 4);
+        assert_eq!(bucket_weight(7), 6);
+        assert_eq!(bucket_weight(1000), 768);
+        assert_eq!(bucket_weight(1023), 768);
+        assert_eq!(bucket_weight(1024), 1024);
+        assert_eq!(bucket_weight(u32::MAX), 3 << 30);
+    }
+}
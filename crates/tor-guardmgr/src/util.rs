@@ -4,6 +4,26 @@ use rand::Rng;
 use std::time::{Duration, SystemTime};
 use tor_basic_utils::RngExt as _;
 
+/// Return the random number generator that this crate should use.
+///
+/// This is `thread_rng()`, except that when the `testing` feature is enabled,
+/// [`testing::replay`](crate::testing::replay) can replace it with a seeded
+/// generator for the current thread.
+#[cfg(not(any(test, feature = "testing")))]
+pub(crate) fn rng() -> rand::rngs::ThreadRng {
+    rand::thread_rng()
+}
+
+/// Return the random number generator that this crate should use.
+///
+/// This is `thread_rng()`, except that when the `testing` feature is enabled,
+/// [`testing::replay`](crate::testing::replay) can replace it with a seeded
+/// generator for the current thread.
+#[cfg(any(test, feature = "testing"))]
+pub(crate) fn rng() -> crate::testing::replay::CrateRng {
+    crate::testing::replay::CrateRng
+}
+
 /// Return a random time within the range `when-max ..= when`.
 ///
 /// Uses a uniform distribution; saturates at UNIX_EPOCH.  Rounds down to the
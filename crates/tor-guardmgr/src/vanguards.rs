@@ -6,6 +6,7 @@
 
 pub mod config;
 mod err;
+mod events;
 mod set;
 
 use std::sync::{Arc, RwLock, Weak};
@@ -19,7 +20,7 @@ use postage::stream::Stream as _;
 use postage::watch;
 use rand::RngCore;
 
-use tor_async_utils::PostageWatchSenderExt as _;
+use tor_async_utils::{PostageWatchSenderExt as _, UnboundedBroadcaster};
 use tor_config::ReconfigureError;
 use tor_error::{error_report, internal, into_internal};
 use tor_netdir::{DirEvent, NetDir, NetDirProvider, Timeliness};
@@ -35,6 +36,7 @@ use set::VanguardSets;
 use crate::VanguardConfig;
 pub use config::VanguardParams;
 pub use err::VanguardMgrError;
+pub use events::{RotationReason, VanguardRotation, VanguardRotationEvents};
 pub use set::Vanguard;
 
 /// The key used for storing the vanguard sets to persistent storage using `StateMgr`.
@@ -102,6 +104,8 @@ struct Inner {
     has_onion_svc: bool,
     /// A channel for sending VanguardConfig changes to the vanguard maintenance task.
     config_tx: watch::Sender<VanguardConfig>,
+    /// A list of the channels on which we report that we have rotated vanguards.
+    send_rotations: UnboundedBroadcaster<VanguardRotation>,
 }
 
 /// Whether the [`VanguardMgr::maintain_vanguard_sets`] task
@@ -159,6 +163,7 @@ impl<R: Runtime> VanguardMgr<R> {
             vanguard_sets,
             has_onion_svc,
             config_tx,
+            send_rotations: UnboundedBroadcaster::new(),
         };
 
         Ok(Self {
@@ -303,6 +308,22 @@ impl<R: Runtime> VanguardMgr<R> {
         relay.ok_or(VanguardMgrError::NoSuitableRelay(layer))
     }
 
+    /// Return a stream of events that tell us when we stop using some of our
+    /// vanguards.
+    ///
+    /// We remove vanguards from our sets when their lifetimes expire, and when
+    /// they are no longer listed in the consensus.  The circuit manager can use
+    /// these events to retire any circuits that were built through the removed
+    /// vanguards.
+    ///
+    /// Replacement vanguards are chosen as soon as we have directory
+    /// information, and are not reported.
+    pub fn rotation_events(&self) -> VanguardRotationEvents {
+        let mut inner = self.inner.write().expect("poisoned lock");
+        let rcv = inner.send_rotations.subscribe();
+        VanguardRotationEvents { inner: rcv }
+    }
+
     /// The vanguard set management task.
     ///
     /// This is a background task that:
@@ -429,11 +450,12 @@ impl<R: Runtime> VanguardMgr<R> {
         let inner = &mut *inner;
 
         let vanguard_sets = &mut inner.vanguard_sets;
-        let expired_count = vanguard_sets.remove_expired(now);
+        let expired = vanguard_sets.remove_expired(now);
 
-        if expired_count > 0 {
+        if !expired.is_empty() {
             info!("Rotating vanguards");
         }
+        inner.notify_rotations(&expired);
 
         if let Some(netdir) = Self::timely_netdir(netdir_provider)? {
             // If we have a NetDir, replenish the vanguard sets that don't have enough vanguards.
@@ -483,7 +505,8 @@ impl Inner {
         // Update our params with the new values.
         self.update_params(params.clone());
 
-        self.vanguard_sets.remove_unlisted(netdir);
+        let unlisted = self.vanguard_sets.remove_unlisted(netdir);
+        self.notify_rotations(&unlisted);
 
         // If we loaded some vanguards from persistent storage but we still need more,
         // we select them here.
@@ -502,6 +525,13 @@ impl Inner {
         Ok(())
     }
 
+    /// Tell everybody who is watching for vanguard rotations about `rotations`.
+    fn notify_rotations(&mut self, rotations: &[VanguardRotation]) {
+        for rotation in rotations {
+            self.send_rotations.send(rotation);
+        }
+    }
+
     /// Update our vanguard params.
     fn update_params(&mut self, new_params: VanguardParams) {
         self.params = new_params;
@@ -1093,6 +1123,43 @@ mod test {
         });
     }
 
+    #[test]
+    fn rotation_events() {
+        MockRuntime::test_with_various(|rt| async move {
+            let vanguardmgr = VanguardMgr::new_testing(&rt, VanguardMode::Lite).unwrap();
+            let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+            let mut events = vanguardmgr.rotation_events();
+
+            let _netdir_provider = vanguardmgr.init_vanguard_sets(&netdir).await.unwrap();
+            // Choosing our first vanguards isn't a rotation.
+            assert!(events.next().now_or_never().is_none());
+
+            // Find the RelayIds of the vanguard that is due to expire next
+            let vanguard_id = {
+                let inner = vanguardmgr.inner.read().unwrap();
+                let next_expiry = inner.vanguard_sets.next_expiry().unwrap();
+                inner
+                    .l2_vanguards()
+                    .iter()
+                    .find(|v| v.when == next_expiry)
+                    .cloned()
+                    .unwrap()
+                    .id
+            };
+            let lifetime = duration_until_expiry(&vanguard_id, &vanguardmgr, &rt, Layer2);
+            rt.advance_by(lifetime).await.unwrap();
+            rt.progress_until_stalled().await;
+
+            let rotation = events.next().now_or_never().unwrap().unwrap();
+            assert_eq!(rotation.layer(), Layer2);
+            assert_eq!(rotation.reason(), RotationReason::Expired);
+            assert!(rotation.retired().contains(&vanguard_id));
+            // The expired vanguard was replaced.
+            let params = VanguardParams::try_from(netdir.params()).unwrap();
+            assert_eq!(vanguard_count(&vanguardmgr), params.l2_pool_size());
+        });
+    }
+
     #[test]
     fn full_vanguards_persistence() {
         MockRuntime::test_with_various(|rt| async move {
@@ -0,0 +1,94 @@
+//! Notifications about changes to our vanguard sets.
+
+use std::{pin::Pin, task::Poll};
+
+use educe::Educe;
+use futures::channel::mpsc;
+use futures::{Stream, StreamExt as _};
+use tor_basic_utils::skip_fmt;
+use tor_linkspec::RelayIds;
+
+use super::Layer;
+
+/// Why some vanguards were removed from one of our vanguard sets.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum RotationReason {
+    /// The vanguards reached the end of their lifetimes.
+    Expired,
+    /// The vanguards are no longer listed in the consensus.
+    Unlisted,
+}
+
+/// A notification that we have stopped using some vanguards.
+///
+/// Circuits that were built through these vanguards should no longer be used
+/// for new streams: the circuit manager should retire them, and build new ones
+/// through our current vanguards.
+#[derive(Clone, Debug)]
+pub struct VanguardRotation {
+    /// The layer from which the vanguards were removed.
+    pub(super) layer: Layer,
+    /// Why the vanguards were removed.
+    pub(super) reason: RotationReason,
+    /// The identities of the vanguards that were removed.
+    pub(super) retired: Vec<RelayIds>,
+}
+
+impl VanguardRotation {
+    /// Return one `VanguardRotation` for each layer from which we removed
+    /// any vanguards, for the given `reason`.
+    pub(super) fn from_removed(
+        reason: RotationReason,
+        l2_retired: Vec<RelayIds>,
+        l3_retired: Vec<RelayIds>,
+    ) -> Vec<Self> {
+        [(Layer::Layer2, l2_retired), (Layer::Layer3, l3_retired)]
+            .into_iter()
+            .filter(|(_, retired)| !retired.is_empty())
+            .map(|(layer, retired)| VanguardRotation {
+                layer,
+                reason,
+                retired,
+            })
+            .collect()
+    }
+
+    /// Return the layer from which the vanguards were removed.
+    pub fn layer(&self) -> Layer {
+        self.layer
+    }
+
+    /// Return the reason for which the vanguards were removed.
+    pub fn reason(&self) -> RotationReason {
+        self.reason
+    }
+
+    /// Return the identities of the vanguards that were removed.
+    pub fn retired(&self) -> &[RelayIds] {
+        &self.retired[..]
+    }
+}
+
+/// A stream of [`VanguardRotation`] events.
+///
+/// This stream is not lossy: every rotation that happens after the stream is
+/// created will be delivered.
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct VanguardRotationEvents {
+    /// The receiver that we're wrapping.
+    #[educe(Debug(method = "skip_fmt"))]
+    pub(super) inner: mpsc::UnboundedReceiver<VanguardRotation>,
+}
+
+impl Stream for VanguardRotationEvents {
+    type Item = VanguardRotation;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
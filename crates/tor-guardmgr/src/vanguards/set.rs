@@ -17,7 +17,7 @@ use tracing::{debug, trace};
 
 use crate::{VanguardMgrError, VanguardMode};
 
-use super::VanguardParams;
+use super::{RotationReason, VanguardParams, VanguardRotation};
 
 /// A vanguard relay.
 #[derive(Clone, amplify::Getters)]
@@ -104,20 +104,22 @@ impl VanguardSets {
 
     /// Remove the vanguards that are expired at the specified timestamp.
     ///
-    /// Returns the number of vanguards that were removed.
-    pub(super) fn remove_expired(&mut self, now: SystemTime) -> usize {
+    /// Returns a [`VanguardRotation`] for each set that has changed.
+    pub(super) fn remove_expired(&mut self, now: SystemTime) -> Vec<VanguardRotation> {
         let l2_expired = self.l2_vanguards.remove_expired(now);
         let l3_expired = self.l3_vanguards.remove_expired(now);
 
-        l2_expired + l3_expired
+        VanguardRotation::from_removed(RotationReason::Expired, l2_expired, l3_expired)
     }
 
     /// Remove the vanguards that are no longer listed in `netdir`.
     ///
-    /// Returns whether either of the two sets have changed.
-    pub(super) fn remove_unlisted(&mut self, netdir: &NetDir) {
-        self.l2_vanguards.remove_unlisted(netdir);
-        self.l3_vanguards.remove_unlisted(netdir);
+    /// Returns a [`VanguardRotation`] for each set that has changed.
+    pub(super) fn remove_unlisted(&mut self, netdir: &NetDir) -> Vec<VanguardRotation> {
+        let l2_unlisted = self.l2_vanguards.remove_unlisted(netdir);
+        let l3_unlisted = self.l3_vanguards.remove_unlisted(netdir);
+
+        VanguardRotation::from_removed(RotationReason::Unlisted, l2_unlisted, l3_unlisted)
     }
 
     /// Replenish the vanguard sets if necessary, using the directory information
@@ -310,8 +312,8 @@ impl VanguardSet {
 
     /// Remove the vanguards that are no longer listed in `netdir`
     ///
-    /// Returns the number of vanguards that were unlisted.
-    fn remove_unlisted(&mut self, netdir: &NetDir) -> usize {
+    /// Returns the identities of the vanguards that were unlisted.
+    fn remove_unlisted(&mut self, netdir: &NetDir) -> Vec<RelayIds> {
         self.retain(|v| {
             let cond = netdir.ids_listed(&v.id) != Some(false);
 
@@ -325,8 +327,8 @@ impl VanguardSet {
 
     /// Remove the vanguards that are expired at the specified timestamp.
     ///
-    /// Returns the number of vanguards that expired.
-    fn remove_expired(&mut self, now: SystemTime) -> usize {
+    /// Returns the identities of the vanguards that expired.
+    fn remove_expired(&mut self, now: SystemTime) -> Vec<RelayIds> {
         self.retain(|v| {
             let cond = v.when > now;
 
@@ -338,14 +340,16 @@ impl VanguardSet {
         })
     }
 
-    /// A wrapper around [`Vec::retain`] that returns the number of discarded elements.
-    fn retain<F>(&mut self, f: F) -> usize
+    /// Like [`Vec::retain`], but returns the identities of the discarded elements.
+    fn retain<F>(&mut self, mut f: F) -> Vec<RelayIds>
     where
         F: FnMut(&TimeBoundVanguard) -> bool,
     {
-        let old_len = self.vanguards.len();
-        self.vanguards.retain(f);
-        old_len - self.vanguards.len()
+        let (kept, discarded): (Vec<_>, Vec<_>) = std::mem::take(&mut self.vanguards)
+            .into_iter()
+            .partition(|v| f(v));
+        self.vanguards = kept;
+        discarded.into_iter().map(|v| v.id).collect()
     }
 
     /// Find the timestamp of the vanguard that is due to expire next.
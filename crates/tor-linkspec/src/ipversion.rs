@@ -0,0 +1,19 @@
+//! A preference for which IP version to use.
+
+/// A preference for IPv4 vs IPv6 addresses.
+///
+/// We use this when asking an exit to open a stream, and when deciding
+/// which exits can open it.
+#[derive(Clone, Default, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum IpVersionPreference {
+    /// Only IPv4 is allowed.
+    Ipv4Only,
+    /// IPv4 and IPv6 are both allowed, and IPv4 is preferred.
+    #[default]
+    Ipv4Preferred,
+    /// IPv4 and IPv6 are both allowed, and IPv6 is preferred.
+    Ipv6Preferred,
+    /// Only IPv6 is allowed.
+    Ipv6Only,
+}
@@ -46,6 +46,7 @@
 pub mod decode;
 #[macro_use]
 mod ids;
+mod ipversion;
 mod ls;
 mod owned;
 mod traits;
@@ -58,6 +59,7 @@ pub use ids::{
     set::RelayIdSet,
     RelayId, RelayIdError, RelayIdRef, RelayIdType, RelayIdTypeIter,
 };
+pub use ipversion::IpVersionPreference;
 pub use ls::{EncodedLinkSpec, LinkSpec, LinkSpecType};
 pub use owned::{
     IntoOwnedChanTarget, LoggedChanTarget, OwnedChanTarget, OwnedChanTargetBuilder,
@@ -467,11 +467,16 @@ impl PtTargetSettings {
         // Unfortunately the spec is not very clear about the valid syntax.
         // https://gitlab.torproject.org/tpo/core/torspec/-/issues/173
         //
-        // For now we reject things that cannot be represented in a bridge line
-        if k.find(|c: char| c == '=' || c.is_whitespace()).is_some() {
+        // For now we reject keys that would be awkward in a bridge line, and
+        // anything containing NUL, which can't go through a SOCKS4 handshake.
+        // (Values with whitespace can be represented in a bridge line by
+        // quoting them.)
+        if k.find(|c: char| c == '=' || c == '\0' || c.is_whitespace())
+            .is_some()
+        {
             return Err(PtTargetInvalidSetting::Key(k.to_string()));
         }
-        if v.find(|c: char| c.is_whitespace()).is_some() {
+        if v.contains('\0') {
             return Err(PtTargetInvalidSetting::Value(v.to_string()));
         }
         Ok(())
@@ -530,14 +535,14 @@ pub struct PtTarget {
 #[derive(Error, Clone, Debug, Eq, PartialEq)]
 #[non_exhaustive]
 pub enum PtTargetInvalidSetting {
-    /// Currently: the key contains whitespace or `=`
+    /// Currently: the key contains whitespace, NUL, or `=`
     ///
     /// Will probably be generated for a greater variety of values
     /// when the spec is more nailed down.
     #[error("key {0:?} has invalid or unsupported syntax")]
     Key(String),
 
-    /// Currently: the value contains whitespace
+    /// Currently: the value contains NUL
     ///
     /// Will probably be generated for a greater variety of values
     /// when the spec is more nailed down.
@@ -913,6 +918,10 @@ mod test {
         assert!(matches!(s, Err(PtTargetInvalidSetting::Key(_))));
 
         let v = vec![("abc".into(), "d ef".into())];
+        let s = PtTargetSettings::try_from(v.clone()).unwrap();
+        assert_eq!(Vec::<_>::from(s), v);
+
+        let v = vec![("abc".into(), "d\0ef".into())];
         let s = PtTargetSettings::try_from(v);
         assert!(matches!(s, Err(PtTargetInvalidSetting::Value(_))));
     }
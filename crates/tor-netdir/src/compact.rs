@@ -0,0 +1,110 @@
+//! Compact, immutable indices into the relays of a consensus.
+//!
+//! A [`NetDir`](crate::NetDir) keeps several maps from a key (such as an
+//! identity or an IP address) to the positions of routerstatuses in its
+//! consensus.  Most of them never change once the directory is built, so
+//! instead of a `HashMap` (which usually has a good deal of unused capacity,
+//! and needs a separate allocation for every multi-valued entry), we keep
+//! them as sorted arrays, and look keys up by binary search.
+
+use crate::RouterStatusIdx;
+
+/// An immutable map from keys to routerstatus indices, stored as a single
+/// sorted array.
+///
+/// A key may map to more than one index: [`SortedIndex::get_all`] returns
+/// all of them, in the order in which they were given to us.
+#[derive(Clone, Debug)]
+pub(crate) struct SortedIndex<K> {
+    /// The entries in this index, sorted by key, and then in the order in
+    /// which we got them.
+    ///
+    /// We store the indices as `u32` to save space: no consensus comes close
+    /// to listing 2^32 relays.
+    entries: Box<[(K, u32)]>,
+}
+
+impl<K: Ord> SortedIndex<K> {
+    /// Return the entries for `key`.
+    fn entries_for(&self, key: &K) -> &[(K, u32)] {
+        let start = self.entries.partition_point(|(k, _)| k < key);
+        let len = self.entries[start..].partition_point(|(k, _)| k == key);
+        &self.entries[start..start + len]
+    }
+
+    /// Return the first index for `key`, if there is one.
+    pub(crate) fn get(&self, key: &K) -> Option<RouterStatusIdx> {
+        self.entries_for(key).first().map(|(_, idx)| to_rsidx(*idx))
+    }
+
+    /// Return true if there is any index for `key`.
+    pub(crate) fn contains_key(&self, key: &K) -> bool {
+        !self.entries_for(key).is_empty()
+    }
+
+    /// Return every index for `key`, in the order in which we got them.
+    pub(crate) fn get_all(&self, key: &K) -> impl Iterator<Item = RouterStatusIdx> + '_ {
+        self.entries_for(key).iter().map(|(_, idx)| to_rsidx(*idx))
+    }
+}
+
+impl<K: Ord> FromIterator<(K, RouterStatusIdx)> for SortedIndex<K> {
+    fn from_iter<I: IntoIterator<Item = (K, RouterStatusIdx)>>(iter: I) -> Self {
+        let mut entries: Vec<(K, u32)> = iter
+            .into_iter()
+            .map(|(k, rsidx)| {
+                let idx = u32::try_from(usize::from(rsidx)).expect("Too many relays in consensus");
+                (k, idx)
+            })
+            .collect();
+        // (This sort is stable, so entries with the same key stay in order.)
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        SortedIndex {
+            entries: entries.into_boxed_slice(),
+        }
+    }
+}
+
+/// Convert an index from a [`SortedIndex`] back into a `RouterStatusIdx`.
+fn to_rsidx(idx: u32) -> RouterStatusIdx {
+    // On every platform we support, a u32 fits in a usize.
+    RouterStatusIdx::from(idx as usize)
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn lookups() {
+        let idx = |n: usize| RouterStatusIdx::from(n);
+        let index: SortedIndex<&str> = [("c", 0), ("a", 1), ("c", 2), ("b", 3), ("c", 4)]
+            .into_iter()
+            .map(|(k, n)| (k, idx(n)))
+            .collect();
+
+        assert_eq!(index.get(&"a"), Some(idx(1)));
+        assert_eq!(index.get(&"c"), Some(idx(0)));
+        assert_eq!(index.get(&"d"), None);
+        assert!(index.contains_key(&"b"));
+        assert!(!index.contains_key(&"0"));
+        assert_eq!(
+            index.get_all(&"c").collect::<Vec<_>>(),
+            vec![idx(0), idx(2), idx(4)]
+        );
+        assert_eq!(index.get_all(&"z").count(), 0);
+    }
+}
@@ -0,0 +1,233 @@
+//! Country-aware helpers for a [`NetDir`].
+//!
+//! This module has helpers to pick relays in (or outside of) particular
+//! countries, and to cross-check the country codes that we derive from GeoIP
+//! against countries declared by some other source.
+
+use std::collections::BTreeMap;
+
+use tor_geoip::{CountryCode, GeoipDb, HasCountryCode};
+use tor_linkspec::HasAddrs as _;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdoc::doc::netstatus::RouterStatus as _;
+
+use crate::{NetDir, Relay, UncheckedRelay, WeightRole};
+
+/// A relay whose GeoIP-derived country differs from its declared country.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct CountryDisagreement {
+    /// The RSA identity of the relay.
+    pub rsa_id: RsaIdentity,
+    /// The country that our GeoIP database puts the relay in.
+    pub geoip: CountryCode,
+    /// The country that the relay was declared to be in.
+    pub declared: CountryCode,
+}
+
+/// The result of checking declared countries against GeoIP for a whole
+/// [`NetDir`].
+///
+/// Returned by [`NetDir::check_declared_countries`].
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct CountryCheckReport {
+    /// The number of relays with a declared country.
+    pub n_declared: usize,
+    /// The number of relays with a declared country that matched GeoIP.
+    pub n_agree: usize,
+    /// The number of relays with a declared country, for which GeoIP gave us
+    /// no country at all.
+    pub n_unknown: usize,
+    /// Every relay whose declared country did not match GeoIP.
+    pub disagreements: Vec<CountryDisagreement>,
+}
+
+impl CountryCheckReport {
+    /// Return the fraction of relays with a known country (from both sources)
+    /// for which the two sources disagree.
+    ///
+    /// Returns `None` if there were no such relays.
+    pub fn disagreement_fraction(&self) -> Option<f64> {
+        let n_known = self.n_agree + self.disagreements.len();
+        (n_known > 0).then(|| self.disagreements.len() as f64 / n_known as f64)
+    }
+}
+
+/// How many relays of each kind are in a single country.
+///
+/// Part of a [`GeoStats`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct CountryRelayCounts {
+    /// The number of [usable](NetDir#usable) relays.
+    pub n_relays: usize,
+    /// The number of usable relays that allow exiting to at least one port,
+    /// and aren't flagged as bad exits.
+    pub n_exits: usize,
+    /// The number of usable relays that are suitable for use as guards.
+    pub n_guards: usize,
+    /// The total bandwidth of the usable relays, as used for weighting them,
+    /// before applying any role-specific weights.
+    pub total_weight: u64,
+}
+
+impl CountryRelayCounts {
+    /// Add `relay`, with the bandwidth `weight`, to these counts.
+    fn add(&mut self, relay: &Relay<'_>, weight: u64) {
+        let details = relay.low_level_details();
+        self.n_relays += 1;
+        if details.policies_allow_some_port() {
+            self.n_exits += 1;
+        }
+        if details.is_suitable_as_guard() {
+            self.n_guards += 1;
+        }
+        self.total_weight += weight;
+    }
+}
+
+/// A summary of how many relays of each kind a [`NetDir`] has in each country.
+///
+/// This is meant for user interfaces that let the user pick a country: for
+/// instance, a list of exit countries can leave out or gray out countries
+/// with no exits.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GeoStats {
+    /// The counts for each country with at least one relay.
+    countries: BTreeMap<CountryCode, CountryRelayCounts>,
+    /// The counts for relays whose country we couldn't determine.
+    unknown: CountryRelayCounts,
+}
+
+impl GeoStats {
+    /// Count the [usable](NetDir#usable) relays in `netdir`, by the countries
+    /// that `db` places them in.
+    ///
+    /// We look up every relay in `db`, whether or not `netdir` was built with
+    /// a GeoIP database; see [`GeoipDb::lookup_country_code_multi`] for how we
+    /// handle relays with more than one address.
+    pub fn compute(netdir: &NetDir, db: &GeoipDb) -> Self {
+        let mut stats = GeoStats::default();
+        for relay in netdir.relays() {
+            let weight = u64::from(netdir.weights.bandwidth_of(relay.rs));
+            let cc = db.lookup_country_code_multi(relay.addrs().iter().map(|a| a.ip()));
+            let counts = match cc {
+                Some(cc) => stats.countries.entry(*cc).or_default(),
+                None => &mut stats.unknown,
+            };
+            counts.add(&relay, weight);
+        }
+        stats
+    }
+
+    /// Return the counts for the country `cc`, or `None` if it has no relays.
+    pub fn get(&self, cc: CountryCode) -> Option<&CountryRelayCounts> {
+        self.countries.get(&cc)
+    }
+
+    /// Return the counts for every country with at least one relay, in order
+    /// of country code.
+    pub fn countries(&self) -> impl Iterator<Item = (CountryCode, &CountryRelayCounts)> + '_ {
+        self.countries.iter().map(|(cc, counts)| (*cc, counts))
+    }
+
+    /// Return the counts for the relays whose country we couldn't determine.
+    pub fn unknown(&self) -> &CountryRelayCounts {
+        &self.unknown
+    }
+
+    /// Return true if the country `cc` has at least one exit relay.
+    pub fn has_exits(&self, cc: CountryCode) -> bool {
+        self.get(cc).is_some_and(|counts| counts.n_exits > 0)
+    }
+}
+
+#[cfg_attr(docsrs, doc(cfg(feature = "geoip")))]
+impl NetDir {
+    /// Choose a random relay in the country `cc` that matches `usable`.
+    ///
+    /// Each relay is chosen with probability proportional to its weight in
+    /// `role`, as in [`NetDir::pick_relay`].
+    ///
+    /// Returns `None` if no relay in `cc` matches `usable`; this is always the
+    /// case if this directory was built without a GeoIP database (see
+    /// [`NetDir::has_country_codes`]).
+    pub fn pick_relay_in_country<'a, R, P>(
+        &'a self,
+        rng: &mut R,
+        role: WeightRole,
+        cc: CountryCode,
+        mut usable: P,
+    ) -> Option<Relay<'a>>
+    where
+        R: rand::Rng,
+        P: FnMut(&Relay<'a>) -> bool,
+    {
+        self.pick_relay(rng, role, |r| r.country_code() == Some(cc) && usable(r))
+    }
+
+    /// Choose `n` distinct relays at random, none of which are in any of the
+    /// countries in `excluded`, and all of which match `usable`.
+    ///
+    /// Each relay is chosen with probability proportional to its weight in
+    /// `role`, as in [`NetDir::pick_n_relays`].
+    ///
+    /// Relays whose country we don't know are excluded too, since we can't be
+    /// sure that they are outside the excluded countries.  (This means that
+    /// this function returns no relays if this directory was built without a
+    /// GeoIP database.)  If `excluded` is empty, no relays are excluded on
+    /// the basis of their country.
+    pub fn pick_n_relays_excluding_countries<'a, R, P>(
+        &'a self,
+        rng: &mut R,
+        n: usize,
+        role: WeightRole,
+        excluded: &[CountryCode],
+        mut usable: P,
+    ) -> Vec<Relay<'a>>
+    where
+        R: rand::Rng,
+        P: FnMut(&Relay<'a>) -> bool,
+    {
+        self.pick_n_relays(rng, n, role, |r| {
+            let allowed =
+                excluded.is_empty() || r.country_code().is_some_and(|cc| !excluded.contains(&cc));
+            allowed && usable(r)
+        })
+    }
+
+    /// Compare the country that our GeoIP database assigns to each relay
+    /// against the country returned for that relay by `declared`, and report
+    /// any disagreements.
+    ///
+    /// Neither the consensus nor microdescriptors carry location information,
+    /// so the caller must supply the declared countries from somewhere else
+    /// (for example, from router descriptors or a relay metadata service).
+    /// Relays for which `declared` returns `None` are not checked.
+    ///
+    /// If this directory was built without a GeoIP database (see
+    /// [`NetDir::has_country_codes`]), every checked relay counts as unknown.
+    pub fn check_declared_countries<F>(&self, declared: F) -> CountryCheckReport
+    where
+        F: Fn(&UncheckedRelay<'_>) -> Option<CountryCode>,
+    {
+        let mut report = CountryCheckReport::default();
+        for relay in self.all_relays() {
+            let Some(declared) = declared(&relay) else {
+                continue;
+            };
+            report.n_declared += 1;
+            match relay.country_code() {
+                None => report.n_unknown += 1,
+                Some(geoip) if geoip == declared => report.n_agree += 1,
+                Some(geoip) => report.disagreements.push(CountryDisagreement {
+                    rsa_id: *relay.rs.rsa_identity(),
+                    geoip,
+                    declared,
+                }),
+            }
+        }
+        report
+    }
+}
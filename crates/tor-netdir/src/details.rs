@@ -34,9 +34,10 @@
 use std::sync::Arc;
 
 use tor_linkspec::HasRelayIds;
-use tor_netdoc::{doc::netstatus, types::policy::PortPolicy};
+use tor_netdoc::types::policy::PortPolicy;
 
-use crate::{Relay, SubnetConfig};
+use crate::role::rs_is_dir_cache;
+use crate::{PortCoverage, Relay, RelayRole, SubnetConfig};
 
 /// A view for lower-level details about a [`Relay`].
 ///
@@ -49,16 +50,32 @@ pub struct RelayDetails<'a>(pub(crate) &'a super::Relay<'a>);
 impl<'a> RelayDetails<'a> {
     /// Return true if this relay allows exiting to `port` on IPv4.
     pub fn supports_exit_port_ipv4(&self, port: u16) -> bool {
-        self.ipv4_policy().allows_port(port)
+        self.ipv4_port_coverage().allows_port(port)
     }
     /// Return true if this relay allows exiting to `port` on IPv6.
     pub fn supports_exit_port_ipv6(&self, port: u16) -> bool {
-        self.ipv6_policy().allows_port(port)
+        self.ipv6_port_coverage().allows_port(port)
+    }
+    /// Return the set of ports to which this relay allows exiting on IPv4.
+    ///
+    /// This is precomputed from [`RelayDetails::ipv4_policy`], and so is
+    /// empty if the relay has been marked BadExit.
+    pub fn ipv4_port_coverage(&self) -> &'a PortCoverage {
+        &self.0.coverage.ipv4
+    }
+    /// Return the set of ports to which this relay allows exiting on IPv6.
+    ///
+    /// This is precomputed from [`RelayDetails::ipv6_policy`], and so is
+    /// empty if the relay has been marked BadExit.
+    pub fn ipv6_port_coverage(&self) -> &'a PortCoverage {
+        &self.0.coverage.ipv6
     }
     /// Return true if this relay is suitable for use as a directory
     /// cache.
+    ///
+    /// Equivalent to `usable_for(&RelayRole::DirCache)`.
     pub fn is_dir_cache(&self) -> bool {
-        rs_is_dir_cache(self.0.rs)
+        self.0.usable_for(&RelayRole::DirCache)
     }
     /// Return true if this relay has the "Fast" flag.
     ///
@@ -75,11 +92,17 @@ impl<'a> RelayDetails<'a> {
         self.0.rs.is_flagged_stable()
     }
     /// Return true if this relay is a potential HS introduction point
+    ///
+    /// Equivalent to `usable_for(&RelayRole::IntroPoint)`.
     pub fn is_hs_intro_point(&self) -> bool {
-        self.is_flagged_fast() && self.0.rs.is_flagged_stable()
+        self.0.usable_for(&RelayRole::IntroPoint)
     }
     /// Return true if this relay is suitable for use as a newly sampled guard,
     /// or for continuing to use as a guard.
+    ///
+    /// This only checks the relay's flags: unlike
+    /// `usable_for(&RelayRole::Guard)`, it does not check whether the relay is
+    /// a directory cache.
     pub fn is_suitable_as_guard(&self) -> bool {
         self.0.rs.is_flagged_guard() && self.is_flagged_fast() && self.is_flagged_stable()
     }
@@ -102,6 +125,14 @@ impl<'a> RelayDetails<'a> {
         }
         self.0.md.family().contains(other.rsa_id()) && other.md.family().contains(self.0.rsa_id())
     }
+    /// Return true if both relays are in the same family, or in the same
+    /// subnet as configured by `subnet_config`.
+    ///
+    /// This is the check that path selection uses to decide whether two
+    /// relays may share a circuit.
+    pub fn in_same_extended_family(&self, other: &Relay<'_>, subnet_config: &SubnetConfig) -> bool {
+        self.in_same_family(other) || self.in_same_subnet(other, subnet_config)
+    }
 
     /// Return true if there are any ports for which this Relay can be
     /// used for exit traffic.
@@ -169,9 +200,3 @@ impl<'a> UncheckedRelayDetails<'a> {
         rs_is_dir_cache(self.0.rs)
     }
 }
-
-/// Return true if `rs` is usable as a directory cache.
-fn rs_is_dir_cache(rs: &netstatus::MdConsensusRouterStatus) -> bool {
-    use tor_protover::ProtoKind;
-    rs.is_flagged_v2dir() && rs.protovers().supports_known_subver(ProtoKind::DirCache, 2)
-}
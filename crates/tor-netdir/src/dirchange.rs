@@ -0,0 +1,362 @@
+//! Detailed descriptions of changes to a network directory.
+//!
+//! A [`DirEvent`] only tells its listeners that _something_ has changed;
+//! to find out what, they need to re-scan the whole [`NetDir`].  The types in
+//! this module let a [`NetDirProvider`](crate::NetDirProvider) say which relays
+//! and microdescriptors changed, so that listeners can react incrementally.
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use itertools::Itertools as _;
+
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdoc::doc::microdesc::MdDigest;
+use tor_netdoc::doc::netstatus::{RelayFlags, RelayWeight, RouterStatus as _};
+
+use crate::{ConsensusRelays as _, DirEvent, NetDir};
+
+/// A detailed event that a [`NetDirProvider`](crate::NetDirProvider) can
+/// broadcast to describe a change in its directory.
+///
+/// Returned by
+/// [`NetDirProvider::detailed_events`](crate::NetDirProvider::detailed_events).
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum DetailedDirEvent {
+    /// A new consensus has been received, and has enough information to be
+    /// used.
+    ///
+    /// The change lists the relays that were added to or removed from the
+    /// directory, compared to the previous consensus.  The new directory may
+    /// already contain microdescriptors: these are not reported separately.
+    NewConsensus(Arc<RelayListChange>),
+
+    /// New microdescriptors have been received for the current consensus.
+    ///
+    /// The list contains the digest of every microdescriptor that was
+    /// added to the directory.
+    NewDescriptors(Arc<[MdDigest]>),
+
+    /// The network parameters changed without a new consensus: for example,
+    /// because of a configuration change.
+    NewParameters,
+
+    /// The directory changed, but the provider cannot say how.
+    ///
+    /// Listeners should treat this like the corresponding [`DirEvent`], and
+    /// re-scan the directory.
+    Unspecified(DirEvent),
+}
+
+/// The relays that were added and removed when one consensus replaced another.
+///
+/// Relays are identified by their RSA identities, since those are the only
+/// identities listed in the consensus.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RelayListChange {
+    /// Relays that are listed in the new consensus but not the old one,
+    /// in sorted order.
+    added: Vec<RsaIdentity>,
+    /// Relays that were listed in the old consensus but not the new one,
+    /// in sorted order.
+    removed: Vec<RsaIdentity>,
+}
+
+impl RelayListChange {
+    /// Compute the change in listed relays from `old` to `new`.
+    ///
+    /// If `old` is `None`, every relay in `new` counts as added.
+    pub fn between(old: Option<&NetDir>, new: &NetDir) -> Self {
+        /// Return the RSA identity of every relay listed in `nd`.
+        fn ids(nd: &NetDir) -> HashSet<&RsaIdentity> {
+            nd.all_relays().map(|r| r.rs.rsa_identity()).collect()
+        }
+        let new_ids = ids(new);
+        let old_ids = old.map(ids).unwrap_or_default();
+        RelayListChange {
+            added: new_ids
+                .difference(&old_ids)
+                .map(|id| **id)
+                .sorted()
+                .collect(),
+            removed: old_ids
+                .difference(&new_ids)
+                .map(|id| **id)
+                .sorted()
+                .collect(),
+        }
+    }
+
+    /// Return the relays that were added in the new consensus, in sorted order.
+    pub fn added(&self) -> &[RsaIdentity] {
+        &self.added[..]
+    }
+
+    /// Return the relays that were removed in the new consensus, in sorted
+    /// order.
+    pub fn removed(&self) -> &[RsaIdentity] {
+        &self.removed[..]
+    }
+
+    /// Return true if no relays were added or removed.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// A relay whose flags differ between two directories.
+///
+/// Returned by [`NetDirDiff::flags_changed`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct FlagChange {
+    /// The RSA identity of the relay.
+    pub rsa_id: RsaIdentity,
+    /// The relay's flags in the old directory.
+    pub old: RelayFlags,
+    /// The relay's flags in the new directory.
+    pub new: RelayFlags,
+}
+
+/// A relay whose consensus weight differs between two directories.
+///
+/// Returned by [`NetDirDiff::weight_changed`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct WeightChange {
+    /// The RSA identity of the relay.
+    pub rsa_id: RsaIdentity,
+    /// The relay's weight in the old directory.
+    pub old: RelayWeight,
+    /// The relay's weight in the new directory.
+    pub new: RelayWeight,
+}
+
+/// The differences in the relays listed by two [`NetDir`]s.
+///
+/// Returned by [`NetDir::diff`].
+///
+/// Relays are identified by their RSA identities, and each category is
+/// listed in the order in which the relays appear in their consensus.
+#[derive(Clone, Debug, Default)]
+pub struct NetDirDiff {
+    /// Relays listed only in the new directory.
+    appeared: Vec<RsaIdentity>,
+    /// Relays listed only in the old directory.
+    disappeared: Vec<RsaIdentity>,
+    /// Relays listed in both directories, with different flags.
+    flags_changed: Vec<FlagChange>,
+    /// Relays listed in both directories, with different weights.
+    weight_changed: Vec<WeightChange>,
+}
+
+impl NetDirDiff {
+    /// Return an iterator over the relays that are listed in the new
+    /// directory, but not in the old one.
+    pub fn appeared(&self) -> impl Iterator<Item = &RsaIdentity> + '_ {
+        self.appeared.iter()
+    }
+
+    /// Return an iterator over the relays that are listed in the old
+    /// directory, but not in the new one.
+    pub fn disappeared(&self) -> impl Iterator<Item = &RsaIdentity> + '_ {
+        self.disappeared.iter()
+    }
+
+    /// Return an iterator over the relays that are listed in both
+    /// directories, but whose flags have changed.
+    pub fn flags_changed(&self) -> impl Iterator<Item = &FlagChange> + '_ {
+        self.flags_changed.iter()
+    }
+
+    /// Return an iterator over the relays that are listed in both
+    /// directories, but whose weights have changed.
+    ///
+    /// A change from a measured weight to an unmeasured weight of the same
+    /// value counts as a change.
+    pub fn weight_changed(&self) -> impl Iterator<Item = &WeightChange> + '_ {
+        self.weight_changed.iter()
+    }
+
+    /// Return true if the two directories list the same relays, with the same
+    /// flags and weights.
+    pub fn is_empty(&self) -> bool {
+        self.appeared.is_empty()
+            && self.disappeared.is_empty()
+            && self.flags_changed.is_empty()
+            && self.weight_changed.is_empty()
+    }
+}
+
+/// A number identifying the contents of a [`NetDir`].
+///
+/// Every `NetDir` gets a new generation when it is constructed, and again
+/// whenever its contents change.  Generations increase monotonically over the
+/// lifetime of the process, so a later version of a directory always has a
+/// greater generation than an earlier one.  Two `NetDir`s with the same
+/// generation have the same contents.
+///
+/// Returned by [`NetDir::generation`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, derive_more::Display)]
+pub struct NetDirGeneration(u64);
+
+impl NetDirGeneration {
+    /// Return a new generation, greater than every generation returned before.
+    pub(crate) fn next() -> Self {
+        /// The next generation to hand out.
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        NetDirGeneration(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// A summary of how much a directory changed between two versions.
+///
+/// This is much smaller and cheaper to compute than a [`NetDirDiff`]: it only
+/// says how many relays changed, so that a cache built from one version of a
+/// directory can decide cheaply whether it needs to be rebuilt.
+///
+/// Returned by [`NetDirChangeSummary::between`], and by
+/// [`NetDirProvider::change_summary`](crate::NetDirProvider::change_summary).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct NetDirChangeSummary {
+    /// The number of relays listed only in the newer version.
+    pub n_added: usize,
+    /// The number of relays listed only in the older version.
+    pub n_removed: usize,
+    /// The number of relays listed in both versions, whose flags, weights, or
+    /// microdescriptors changed.
+    ///
+    /// This includes relays whose microdescriptors arrived.
+    pub n_changed: usize,
+    /// True if the network parameters changed.
+    pub params_changed: bool,
+}
+
+impl NetDirChangeSummary {
+    /// Summarize the changes from `old` to `new`.
+    ///
+    /// If `old` is `None`, every relay in `new` counts as added, and the
+    /// parameters count as changed.
+    ///
+    /// This takes time linear in the number of relays in the two directories,
+    /// but needs no hash lookups if they share a consensus.
+    pub fn between(old: Option<&NetDir>, new: &NetDir) -> Self {
+        let Some(old) = old else {
+            return NetDirChangeSummary {
+                n_added: new.c_relays().len(),
+                params_changed: true,
+                ..Default::default()
+            };
+        };
+        let mut summary = NetDirChangeSummary {
+            params_changed: old.params != new.params,
+            ..Default::default()
+        };
+
+        if Arc::ptr_eq(&old.consensus, &new.consensus) {
+            summary.n_changed = old
+                .mds
+                .iter()
+                .zip(new.mds.iter())
+                .filter(|(old_md, new_md)| old_md.is_some() != new_md.is_some())
+                .count();
+            return summary;
+        }
+
+        let mut n_matched = 0;
+        for (new_idx, new_rs) in new.c_relays().iter_enumerated() {
+            let Some(old_idx) = old.rsidx_by_rsa.get(new_rs.rsa_identity()) else {
+                summary.n_added += 1;
+                continue;
+            };
+            n_matched += 1;
+            let old_rs = &old.c_relays()[old_idx];
+            if old_rs.flags() != new_rs.flags()
+                || old_rs.weight() != new_rs.weight()
+                || old_rs.md_digest() != new_rs.md_digest()
+                || old.mds[old_idx].is_some() != new.mds[new_idx].is_some()
+            {
+                summary.n_changed += 1;
+            }
+        }
+        summary.n_removed = old.c_relays().len().saturating_sub(n_matched);
+        summary
+    }
+
+    /// Return a summary of the changes described by `self`, followed by those
+    /// described by `later`.
+    ///
+    /// The counts in the result are upper bounds: for example, a relay that
+    /// was added and then removed again counts as both.  But the result
+    /// [is empty](NetDirChangeSummary::is_empty) only if both summaries are.
+    pub fn followed_by(&self, later: &NetDirChangeSummary) -> Self {
+        NetDirChangeSummary {
+            n_added: self.n_added + later.n_added,
+            n_removed: self.n_removed + later.n_removed,
+            n_changed: self.n_changed + later.n_changed,
+            params_changed: self.params_changed || later.params_changed,
+        }
+    }
+
+    /// Return true if no relays or parameters changed.
+    pub fn is_empty(&self) -> bool {
+        self.n_added == 0 && self.n_removed == 0 && self.n_changed == 0 && !self.params_changed
+    }
+}
+
+impl NetDir {
+    /// Return the generation of this directory's contents.
+    ///
+    /// A cache built from this directory can remember its generation, and
+    /// later ask a [`NetDirProvider`](crate::NetDirProvider) for a
+    /// [summary](crate::NetDirProvider::change_summary) of what has changed
+    /// since then.
+    pub fn generation(&self) -> NetDirGeneration {
+        self.generation
+    }
+
+    /// Give this directory a new generation, since its contents have changed.
+    pub(crate) fn note_changed(&mut self) {
+        self.generation = NetDirGeneration::next();
+    }
+
+    /// Compare the relays listed in this directory with those listed in
+    /// `other`, treating this directory as the older of the two.
+    ///
+    /// This takes time linear in the number of relays in the two directories.
+    pub fn diff(&self, other: &NetDir) -> NetDirDiff {
+        let mut diff = NetDirDiff::default();
+        for new_rs in other.c_relays() {
+            let rsa_id = new_rs.rsa_identity();
+            let Some(old_rs) = self.rsidx_by_rsa.get(rsa_id).map(|i| &self.c_relays()[i]) else {
+                diff.appeared.push(*rsa_id);
+                continue;
+            };
+            if old_rs.flags() != new_rs.flags() {
+                diff.flags_changed.push(FlagChange {
+                    rsa_id: *rsa_id,
+                    old: *old_rs.flags(),
+                    new: *new_rs.flags(),
+                });
+            }
+            if old_rs.weight() != new_rs.weight() {
+                diff.weight_changed.push(WeightChange {
+                    rsa_id: *rsa_id,
+                    old: *old_rs.weight(),
+                    new: *new_rs.weight(),
+                });
+            }
+        }
+        diff.disappeared = self
+            .c_relays()
+            .iter()
+            .map(|rs| rs.rsa_identity())
+            .filter(|rsa_id| !other.rsidx_by_rsa.contains_key(*rsa_id))
+            .copied()
+            .collect();
+        diff
+    }
+}
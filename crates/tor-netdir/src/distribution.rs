@@ -0,0 +1,228 @@
+//! Summaries of how selection weight is distributed among relays.
+//!
+//! Researchers and network-health tools often want to know not just how much
+//! weight the network has for some role, but how evenly it is spread: how much
+//! the top few relays carry, what a typical relay gets, and so on.  A
+//! [`WeightDistribution`] holds the weights that a [`NetDir`](crate::NetDir)
+//! would use for each usable relay, and answers those questions.
+
+use std::num::NonZeroUsize;
+
+use crate::RelayWeight;
+
+/// The distribution of selection weight for some role among the usable relays
+/// in a [`NetDir`](crate::NetDir).
+///
+/// Returned by [`NetDir::bandwidth_distribution`](crate::NetDir::bandwidth_distribution).
+/// This is a snapshot: it doesn't change when the directory does.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct WeightDistribution {
+    /// The weight of every relay, in ascending order.
+    sorted: Vec<u64>,
+    /// The sum of `sorted`.
+    total: u64,
+}
+
+/// One bucket of a histogram returned by [`WeightDistribution::histogram`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct WeightBucket {
+    /// The lowest weight that belongs in this bucket.
+    pub low: RelayWeight,
+    /// The highest weight that belongs in this bucket.
+    pub high: RelayWeight,
+    /// The number of relays whose weight is in this bucket.
+    pub n_relays: usize,
+}
+
+impl WeightDistribution {
+    /// Construct a new WeightDistribution from the weights of a set of relays.
+    pub(crate) fn from_weights(weights: impl IntoIterator<Item = u64>) -> Self {
+        let mut sorted: Vec<u64> = weights.into_iter().collect();
+        sorted.sort_unstable();
+        let total = sorted.iter().sum();
+        WeightDistribution { sorted, total }
+    }
+
+    /// Return the number of relays in this distribution.
+    pub fn n_relays(&self) -> usize {
+        self.sorted.len()
+    }
+
+    /// Return the total weight of every relay in this distribution.
+    pub fn total(&self) -> RelayWeight {
+        RelayWeight(self.total)
+    }
+
+    /// Return the largest weight of any relay in this distribution, or None
+    /// if there are no relays.
+    pub fn max(&self) -> Option<RelayWeight> {
+        self.sorted.last().copied().map(RelayWeight)
+    }
+
+    /// Return the weight of the relay at percentile `pct`, using the
+    /// nearest-rank method.
+    ///
+    /// That is, return the smallest weight such that at least `pct` percent of
+    /// the relays have that weight or less.  A `pct` of 50 gives the median;
+    /// a `pct` of 0 gives the smallest weight.
+    ///
+    /// Return None if there are no relays, or if `pct` is not between 0 and
+    /// 100 inclusive.
+    pub fn percentile(&self, pct: f64) -> Option<RelayWeight> {
+        if self.sorted.is_empty() || !(0.0..=100.0).contains(&pct) {
+            return None;
+        }
+        let n = self.sorted.len();
+        let rank = ((pct / 100.0) * n as f64).ceil() as usize;
+        let idx = rank.clamp(1, n) - 1;
+        Some(RelayWeight(self.sorted[idx]))
+    }
+
+    /// Return the fraction of the total weight that is held by the `n`
+    /// relays with the most weight.
+    ///
+    /// Return None if the total weight is zero.
+    pub fn top_n_fraction(&self, n: usize) -> Option<f64> {
+        let top: u64 = self.sorted.iter().rev().take(n).sum();
+        RelayWeight(top).checked_div(self.total())
+    }
+
+    /// Return the Gini coefficient of this distribution.
+    ///
+    /// This is 0 when every relay has the same weight, and approaches 1 as
+    /// the weight becomes concentrated in a single relay.
+    ///
+    /// Return None if the total weight is zero.
+    pub fn gini(&self) -> Option<f64> {
+        if self.total == 0 {
+            return None;
+        }
+        let n = self.sorted.len() as f64;
+        // With the weights x_1..x_n in ascending order, the Gini coefficient is
+        //    2 * sum(i * x_i) / (n * sum(x_i))  -  (n + 1) / n.
+        let weighted_sum: f64 = self
+            .sorted
+            .iter()
+            .enumerate()
+            .map(|(i, w)| (i + 1) as f64 * *w as f64)
+            .sum();
+        let g = (2.0 * weighted_sum) / (n * self.total as f64) - (n + 1.0) / n;
+        Some(g.max(0.0))
+    }
+
+    /// Divide the range from zero to the largest weight into `n_buckets`
+    /// buckets of equal width, and return the number of relays in each.
+    ///
+    /// Return an empty list if there are no relays.
+    pub fn histogram(&self, n_buckets: NonZeroUsize) -> Vec<WeightBucket> {
+        let Some(max) = self.sorted.last().copied() else {
+            return Vec::new();
+        };
+        let n_buckets = n_buckets.get() as u64;
+        // Round up, so that the last bucket includes `max`.
+        let width = (max / n_buckets).saturating_add(1);
+        let mut buckets: Vec<WeightBucket> = (0..n_buckets)
+            .map(|i| WeightBucket {
+                low: RelayWeight(i * width),
+                high: RelayWeight((i + 1).saturating_mul(width) - 1),
+                n_relays: 0,
+            })
+            .collect();
+        for w in &self.sorted {
+            let idx = usize::try_from(w / width).expect("bucket index out of range");
+            buckets[idx].n_relays += 1;
+        }
+        buckets
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use float_eq::assert_float_eq;
+
+    #[test]
+    fn empty() {
+        let d = WeightDistribution::from_weights([]);
+        assert_eq!(d.n_relays(), 0);
+        assert_eq!(d.total(), RelayWeight(0));
+        assert_eq!(d.max(), None);
+        assert_eq!(d.percentile(50.0), None);
+        assert_eq!(d.top_n_fraction(3), None);
+        assert_eq!(d.gini(), None);
+        assert!(d.histogram(NonZeroUsize::new(4).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn percentiles() {
+        let d = WeightDistribution::from_weights([50, 10, 40, 20, 30]);
+        assert_eq!(d.n_relays(), 5);
+        assert_eq!(d.total(), RelayWeight(150));
+        assert_eq!(d.max(), Some(RelayWeight(50)));
+        assert_eq!(d.percentile(0.0), Some(RelayWeight(10)));
+        assert_eq!(d.percentile(20.0), Some(RelayWeight(10)));
+        assert_eq!(d.percentile(21.0), Some(RelayWeight(20)));
+        assert_eq!(d.percentile(50.0), Some(RelayWeight(30)));
+        assert_eq!(d.percentile(100.0), Some(RelayWeight(50)));
+        assert_eq!(d.percentile(100.5), None);
+        assert_eq!(d.percentile(-1.0), None);
+        assert_eq!(d.percentile(f64::NAN), None);
+
+        assert_float_eq!(d.top_n_fraction(1).unwrap(), 50.0 / 150.0, abs <= 1e-9);
+        assert_float_eq!(d.top_n_fraction(2).unwrap(), 90.0 / 150.0, abs <= 1e-9);
+        assert_float_eq!(d.top_n_fraction(99).unwrap(), 1.0, abs <= 1e-9);
+    }
+
+    #[test]
+    fn gini() {
+        let even = WeightDistribution::from_weights([7, 7, 7, 7]);
+        assert_float_eq!(even.gini().unwrap(), 0.0, abs <= 1e-9);
+
+        let lopsided = WeightDistribution::from_weights([0, 0, 0, 100]);
+        assert_float_eq!(lopsided.gini().unwrap(), 0.75, abs <= 1e-9);
+
+        let d = WeightDistribution::from_weights([1, 2, 3, 4]);
+        assert_float_eq!(d.gini().unwrap(), 0.25, abs <= 1e-9);
+
+        let zeros = WeightDistribution::from_weights([0, 0]);
+        assert_eq!(zeros.gini(), None);
+    }
+
+    #[test]
+    fn histogram() {
+        let d = WeightDistribution::from_weights([0, 1, 5, 9, 10, 19]);
+        let h = d.histogram(NonZeroUsize::new(2).unwrap());
+        assert_eq!(h.len(), 2);
+        assert_eq!(
+            (h[0].low, h[0].high, h[0].n_relays),
+            (RelayWeight(0), RelayWeight(9), 4)
+        );
+        assert_eq!(
+            (h[1].low, h[1].high, h[1].n_relays),
+            (RelayWeight(10), RelayWeight(19), 2)
+        );
+
+        let h = d.histogram(NonZeroUsize::new(100).unwrap());
+        assert_eq!(h.len(), 100);
+        assert_eq!(h.iter().map(|b| b.n_relays).sum::<usize>(), 6);
+        assert_eq!(h[99].high, RelayWeight(99));
+
+        let zeros = WeightDistribution::from_weights([0, 0, 0]);
+        let h = zeros.histogram(NonZeroUsize::new(3).unwrap());
+        assert_eq!(h[0].n_relays, 3);
+    }
+}
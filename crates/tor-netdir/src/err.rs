@@ -22,6 +22,19 @@ pub enum Error {
     /// We received a consensus document that should be impossible.
     #[error("Invalid information from consensus document: {0}")]
     InvalidConsensus(&'static str),
+    /// We received a consensus that lists more relays than we are willing to
+    /// accept.
+    #[error("Consensus lists {n_relays} relays, but we only accept {max_relays}")]
+    ConsensusTooLarge {
+        /// The number of relays in the consensus.
+        n_relays: usize,
+        /// The largest number of relays that we accept.
+        max_relays: usize,
+    },
+    /// We were given a NetDir snapshot that we couldn't decode.
+    #[cfg(feature = "snapshot")]
+    #[error("Invalid NetDir snapshot")]
+    InvalidSnapshot(#[source] tor_bytes::Error),
 }
 
 impl HasKind for Error {
@@ -33,6 +46,9 @@ impl HasKind for Error {
             E::DirNotYetValid => EK::ClockSkew,
             E::NotEnoughInfo | E::NoInfo => EK::BootstrapRequired,
             E::InvalidConsensus(_) => EK::TorProtocolViolation,
+            E::ConsensusTooLarge { .. } => EK::LocalResourceExhausted,
+            #[cfg(feature = "snapshot")]
+            E::InvalidSnapshot(_) => EK::BadApiUsage,
         }
     }
 }
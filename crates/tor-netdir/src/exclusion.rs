@@ -0,0 +1,141 @@
+//! Helpers to find relays that can share a path with relays we have already
+//! chosen.
+//!
+//! Tor's path selection rules say that no two relays on a circuit may be in
+//! the same family, or in the same subnet.  Some callers also want to keep
+//! relays from the same country off a single path.  A [`PathExclusion`]
+//! applies all of these rules in one place, so that every caller gets the
+//! same answer.
+
+use std::fmt;
+
+#[cfg(feature = "geoip")]
+use tor_geoip::HasCountryCode;
+use tor_linkspec::HasRelayIds as _;
+
+use crate::{NetDir, Relay, SubnetConfig};
+
+/// A set of relays that we have already chosen for a path, and the rules
+/// that decide which other relays may join them.
+///
+/// A relay is excluded if:
+///  * it is in the same family as any chosen relay (every relay is in the
+///    same family as itself);
+///  * any of its addresses is in the same subnet as an address of any
+///    chosen relay, according to our [`SubnetConfig`]; or
+///  * we were told to [exclude same-country relays](PathExclusion::exclude_same_country),
+///    and it is in the same country as any chosen relay.
+///
+/// Use [`NetDir::relays_permitted_by`] to find the relays that are not
+/// excluded.
+#[derive(Clone)]
+pub struct PathExclusion<'a> {
+    /// The relays that we have already chosen.
+    chosen: Vec<Relay<'a>>,
+    /// The configuration to use when deciding whether two addresses are in the
+    /// same subnet.
+    subnet_config: SubnetConfig,
+    /// If true, we also exclude relays in the same country as a chosen relay.
+    #[cfg(feature = "geoip")]
+    same_country: bool,
+}
+
+impl<'a> fmt::Debug for PathExclusion<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("PathExclusion");
+        d.field(
+            "chosen",
+            &self
+                .chosen
+                .iter()
+                .map(|r| r.display_relay_ids().to_string())
+                .collect::<Vec<_>>(),
+        )
+        .field("subnet_config", &self.subnet_config);
+        #[cfg(feature = "geoip")]
+        d.field("same_country", &self.same_country);
+        d.finish()
+    }
+}
+
+impl<'a> PathExclusion<'a> {
+    /// Construct a new `PathExclusion` for the relays in `chosen`, using
+    /// `subnet_config` to decide which relays are in the same subnet.
+    pub fn new(chosen: &[Relay<'a>], subnet_config: &SubnetConfig) -> Self {
+        PathExclusion {
+            chosen: chosen.to_vec(),
+            subnet_config: *subnet_config,
+            #[cfg(feature = "geoip")]
+            same_country: false,
+        }
+    }
+
+    /// Also exclude every relay that is in the same country as a chosen relay.
+    ///
+    /// Relays whose country we don't know are never excluded for this reason.
+    #[cfg(feature = "geoip")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "geoip")))]
+    pub fn exclude_same_country(mut self) -> Self {
+        self.same_country = true;
+        self
+    }
+
+    /// Add `relay` to the set of chosen relays.
+    pub fn push(&mut self, relay: Relay<'a>) {
+        self.chosen.push(relay);
+    }
+
+    /// Return true if `relay` may share a path with every chosen relay.
+    pub fn permits(&self, relay: &Relay<'_>) -> bool {
+        self.chosen.iter().all(|chosen| {
+            !relay
+                .low_level_details()
+                .in_same_extended_family(chosen, &self.subnet_config)
+                && !self.in_same_country(relay, chosen)
+        })
+    }
+
+    /// Return true if we are excluding same-country relays, and `r1` and `r2`
+    /// are both in the same known country.
+    #[cfg(feature = "geoip")]
+    fn in_same_country(&self, r1: &Relay<'_>, r2: &Relay<'_>) -> bool {
+        self.same_country
+            && matches!(
+                (r1.country_code(), r2.country_code()),
+                (Some(a), Some(b)) if a == b
+            )
+    }
+
+    /// Return true if we are excluding same-country relays, and `r1` and `r2`
+    /// are both in the same known country.
+    ///
+    /// (Without the `geoip` feature, we never know any relay's country.)
+    #[cfg(not(feature = "geoip"))]
+    fn in_same_country(&self, _r1: &Relay<'_>, _r2: &Relay<'_>) -> bool {
+        false
+    }
+}
+
+impl NetDir {
+    /// Return an iterator over every [usable](NetDir#usable) relay that is
+    /// not in the same family or subnet as any relay in `chosen`.
+    ///
+    /// This is a shortcut for [`relays_permitted_by`](NetDir::relays_permitted_by)
+    /// with a new [`PathExclusion`].
+    pub fn relays_compatible_with<'a>(
+        &'a self,
+        chosen: &[Relay<'a>],
+        subnet_config: &SubnetConfig,
+    ) -> impl Iterator<Item = Relay<'a>> + 'a {
+        self.relays_permitted_by(PathExclusion::new(chosen, subnet_config))
+    }
+
+    /// Return an iterator over every [usable](NetDir#usable) relay that
+    /// `exclusion` permits.
+    pub fn relays_permitted_by<'a>(
+        &'a self,
+        exclusion: PathExclusion<'a>,
+    ) -> impl Iterator<Item = Relay<'a>> + 'a {
+        self.relays().filter(move |r| exclusion.permits(r))
+    }
+}
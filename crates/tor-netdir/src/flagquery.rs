@@ -0,0 +1,131 @@
+//! Bulk queries for relays based on their consensus flags.
+//!
+//! When a [`NetDir`] is built, we compute a bitset for each flag, recording
+//! which relays have that flag.  A [`RelayFlagQuery`] can then find every
+//! relay matching a combination of flags by intersecting those bitsets,
+//! rather than by checking each relay's flags one at a time.
+
+use bitvec::prelude::*;
+use tor_netdoc::doc::netstatus::{MdConsensusRouterStatus, RelayFlags};
+use typed_index_collections::TiSlice;
+
+use crate::{ConsensusRelays as _, NetDir, Relay, RouterStatusIdx, UncheckedRelay};
+
+/// The number of distinct flags that a `RelayFlags` can hold.
+const N_FLAG_BITS: usize = u16::BITS as usize;
+
+/// A per-flag index of the relays in a consensus.
+#[derive(Clone, Debug)]
+pub(crate) struct FlagIndex {
+    /// For each bit position in `RelayFlags`, a bitset with one bit per
+    /// routerstatus, set if that routerstatus has the corresponding flag.
+    by_flag: Vec<BitVec>,
+    /// The number of routerstatuses in the consensus.
+    n_relays: usize,
+}
+
+impl FlagIndex {
+    /// Construct a new `FlagIndex` for the routerstatuses in `relays`.
+    pub(crate) fn new(relays: &TiSlice<RouterStatusIdx, MdConsensusRouterStatus>) -> Self {
+        let n_relays = relays.len();
+        let mut by_flag = vec![bitvec![0; n_relays]; N_FLAG_BITS];
+        for (idx, rs) in relays.iter().enumerate() {
+            let bits = rs.flags().bits();
+            for (bit, set) in by_flag.iter_mut().enumerate() {
+                if bits & (1 << bit) != 0 {
+                    set.set(idx, true);
+                }
+            }
+        }
+        FlagIndex { by_flag, n_relays }
+    }
+
+    /// Return a bitset of the routerstatuses that match `query`.
+    fn matching(&self, query: &RelayFlagQuery) -> BitVec {
+        let mut result = bitvec![1; self.n_relays];
+        for (bit, set) in self.by_flag.iter().enumerate() {
+            let mask = 1 << bit;
+            if query.required.bits() & mask != 0 {
+                result &= set.as_bitslice();
+            } else if query.forbidden.bits() & mask != 0 {
+                result &= !set.clone();
+            }
+        }
+        result
+    }
+}
+
+/// A query for relays that have (or lack) a given set of
+/// [`RelayFlags`].
+///
+/// Use [`NetDir::relays_with_flags`] to run the query.  For example, to find
+/// every relay that is `Fast` and `Stable` but not `BadExit`, use
+/// `RelayFlagQuery::new().require(RelayFlags::FAST | RelayFlags::STABLE).forbid(RelayFlags::BAD_EXIT)`.
+#[derive(Clone, Debug)]
+pub struct RelayFlagQuery {
+    /// Flags that a relay must have.
+    required: RelayFlags,
+    /// Flags that a relay must not have.
+    forbidden: RelayFlags,
+}
+
+impl Default for RelayFlagQuery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RelayFlagQuery {
+    /// Return a new query that matches every relay.
+    pub fn new() -> Self {
+        RelayFlagQuery {
+            required: RelayFlags::empty(),
+            forbidden: RelayFlags::empty(),
+        }
+    }
+
+    /// Require that matching relays have every flag in `flags`.
+    pub fn require(mut self, flags: RelayFlags) -> Self {
+        self.required |= flags;
+        self
+    }
+
+    /// Require that matching relays have none of the flags in `flags`.
+    ///
+    /// If a flag is both required and forbidden, it is required.
+    pub fn forbid(mut self, flags: RelayFlags) -> Self {
+        self.forbidden |= flags;
+        self
+    }
+
+    /// Return true if a relay with `flags` matches this query.
+    pub fn matches(&self, flags: RelayFlags) -> bool {
+        flags.contains(self.required) && !flags.intersects(self.forbidden - self.required)
+    }
+}
+
+impl NetDir {
+    /// Return an iterator over every relay, including unusable ones, whose
+    /// flags match `query`.
+    pub fn all_relays_with_flags<'a>(
+        &'a self,
+        query: &RelayFlagQuery,
+    ) -> impl Iterator<Item = UncheckedRelay<'a>> + 'a {
+        let matching: Vec<usize> = self.flag_index.matching(query).iter_ones().collect();
+        let relays = self.c_relays();
+        matching.into_iter().map(move |idx| {
+            let rsidx = RouterStatusIdx(idx);
+            self.relay_from_rs_and_rsidx(&relays[rsidx], rsidx)
+        })
+    }
+
+    /// Return an iterator over every [usable](NetDir#usable) relay whose flags
+    /// match `query`.
+    pub fn relays_with_flags<'a>(
+        &'a self,
+        query: &RelayFlagQuery,
+    ) -> impl Iterator<Item = Relay<'a>> + 'a {
+        self.all_relays_with_flags(query)
+            .filter_map(UncheckedRelay::into_relay)
+    }
+}
@@ -82,6 +82,12 @@ impl HsDirParams {
         self.srv_lifespan.start
     }
 
+    /// Return the time at which the shared random value for this time period
+    /// stops being the most recent one.
+    pub fn end_of_shared_rand_period(&self) -> SystemTime {
+        self.srv_lifespan.end
+    }
+
     /// Return an opaque offset for `when` from the start of the shared-random-value protocol
     /// period corresponding to the SRV for this time period.
     ///
@@ -559,6 +565,9 @@ mod test {
             srv_lifespan,
         };
 
+        assert_eq!(params.start_of_shard_rand_period(), srv_start);
+        assert_eq!(params.end_of_shared_rand_period(), srv_end);
+
         let before_srv_period = t("1985-10-25T08:59:00Z");
         let after_srv_period = t("1985-10-26T10:19:00Z");
         assert!(params.offset_within_srv_period(before_srv_period).is_none());
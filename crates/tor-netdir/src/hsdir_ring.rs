@@ -41,8 +41,11 @@ use crate::{NetDir, RouterStatusIdx};
 ///
 /// Note that this is _not_ an index into any array; it is instead an index into
 /// a space of possible values in a (virtual!) ring of 2^256 elements.
+///
+/// Returned by [`NetDir::hsdir_ring_entries`] and [`NetDir::hsdir_position`],
+/// so that you can see where an onion service's descriptors are stored.
 #[derive(Copy, Clone, Eq, Hash, PartialEq, Ord, PartialOrd, AsRef)]
-pub(crate) struct HsDirIndex(#[as_ref] [u8; 32]);
+pub struct HsDirIndex(#[as_ref] [u8; 32]);
 
 impl_debug_hex! { HsDirIndex .0 }
 
@@ -243,6 +246,11 @@ impl HsDirRing {
             .take(spread)
     }
 
+    /// Iterate over the entries of this ring, in ring order.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = &(HsDirIndex, RouterStatusIdx)> {
+        self.ring.iter()
+    }
+
     /// Return the time period for which this ring applies.
     pub(crate) fn time_period(&self) -> TimePeriod {
         self.params.time_period
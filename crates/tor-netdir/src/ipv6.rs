@@ -0,0 +1,211 @@
+//! Finding and preferring relays that we can reach over IPv6.
+//!
+//! A client on an IPv6-only network can only connect to relays that list an
+//! IPv6 ORPort.  Most relays don't, so such a client needs to restrict (or at
+//! least bias) its choice of first hop to the ones that do; and it should
+//! weight them relative to each other, not relative to relays that it can't
+//! reach at all.
+
+use std::net::SocketAddr;
+
+use rand::seq::SliceRandom as _;
+
+use crate::params::NetParameters;
+use crate::{NetDir, Relay, WeightRole};
+
+/// How strongly to prefer relays with an IPv6 ORPort when choosing relays.
+///
+/// Used with
+/// [`NetDir::pick_relay_with_ipv6_preference`](crate::NetDir::pick_relay_with_ipv6_preference).
+///
+/// When we choose a relay, we multiply the weight of every relay without an
+/// IPv6 ORPort by `1 - percent/100`.  A preference of 0% makes no change to
+/// the weights; a preference of 100% only ever chooses relays with IPv6.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Ipv6Preference {
+    /// How strongly we prefer IPv6, from 0 to 100.
+    percent: u8,
+}
+
+impl Ipv6Preference {
+    /// Return a new `Ipv6Preference` that prefers relays with IPv6 by
+    /// `percent`.
+    ///
+    /// We clamp `percent` to be no more than 100.
+    pub fn new(percent: u8) -> Self {
+        Ipv6Preference {
+            percent: percent.min(100),
+        }
+    }
+
+    /// Return an `Ipv6Preference` that only chooses relays with IPv6, for
+    /// building paths from an IPv6-only host.
+    pub fn ipv6_only() -> Self {
+        Self::new(100)
+    }
+
+    /// Return the `Ipv6Preference` that the consensus parameters in `params`
+    /// recommend.
+    pub fn from_params(params: &NetParameters) -> Self {
+        let percent = params.ipv6_preference.as_percent().get();
+        // The parameter is bounded to 0..=100, so this can't fail.
+        Self::new(u8::try_from(percent).unwrap_or(100))
+    }
+
+    /// Return how strongly we prefer relays with IPv6, as a percentage.
+    pub fn percent(&self) -> u8 {
+        self.percent
+    }
+
+    /// Return `weight`, adjusted for a relay that has an IPv6 ORPort if
+    /// `has_ipv6` is true.
+    pub(crate) fn adjust(&self, weight: u64, has_ipv6: bool) -> u64 {
+        if has_ipv6 {
+            weight
+        } else {
+            // Dividing first keeps us from overflowing.
+            weight / 100 * u64::from(100 - self.percent)
+                + weight % 100 * u64::from(100 - self.percent) / 100
+        }
+    }
+}
+
+impl Default for Ipv6Preference {
+    /// Return an `Ipv6Preference` that makes no change to the weights.
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl<'a> Relay<'a> {
+    /// Return true if this relay lists an IPv6 ORPort.
+    pub fn has_ipv6(&self) -> bool {
+        self.ipv6_orports().next().is_some()
+    }
+
+    /// Return an iterator over this relay's IPv6 ORPorts.
+    pub fn ipv6_orports(&self) -> impl Iterator<Item = &'a SocketAddr> + 'a {
+        self.rs.addrs().iter().filter(|a| a.is_ipv6())
+    }
+}
+
+impl NetDir {
+    /// Return an iterator over every [usable](NetDir#usable) relay that lists
+    /// an IPv6 ORPort.
+    pub fn relays_with_ipv6(&self) -> impl Iterator<Item = Relay<'_>> {
+        self.relays().filter(Relay::has_ipv6)
+    }
+
+    /// Choose a relay at random, preferring relays with an IPv6 ORPort
+    /// according to `preference`.
+    ///
+    /// This is like [`pick_relay`](NetDir::pick_relay), except that we
+    /// adjust the weight of every relay without IPv6.  With
+    /// [`Ipv6Preference::ipv6_only`], this is the same as calling `pick_relay`
+    /// with a `usable` predicate that also checks [`Relay::has_ipv6`].
+    pub fn pick_relay_with_ipv6_preference<'a, R, P>(
+        &'a self,
+        rng: &mut R,
+        role: WeightRole,
+        preference: &Ipv6Preference,
+        usable: P,
+    ) -> Option<Relay<'a>>
+    where
+        R: rand::Rng,
+        P: FnMut(&Relay<'a>) -> bool,
+    {
+        let relays: Vec<_> = self.relays().filter(usable).collect();
+        // NOTE: See discussion in pick_relay().
+        relays[..]
+            .choose_weighted(rng, |r| {
+                preference.adjust(self.weights.weight_rs_for_role(r.rs, role), r.has_ipv6())
+            })
+            .ok()
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::testnet::construct_custom_netdir;
+    use tor_basic_utils::test_rng::testing_rng;
+
+    #[test]
+    fn adjust() {
+        assert_eq!(Ipv6Preference::default().adjust(1000, false), 1000);
+        assert_eq!(Ipv6Preference::new(75).adjust(1000, false), 250);
+        assert_eq!(Ipv6Preference::new(75).adjust(1000, true), 1000);
+        assert_eq!(Ipv6Preference::new(200).percent(), 100);
+        assert_eq!(Ipv6Preference::ipv6_only().adjust(1000, false), 0);
+        assert_eq!(
+            Ipv6Preference::new(50).adjust(u64::MAX, false),
+            u64::MAX / 2
+        );
+
+        let params = NetParameters::default();
+        assert_eq!(Ipv6Preference::from_params(&params).percent(), 0);
+    }
+
+    #[test]
+    fn ipv6_relays() {
+        // Every third relay has an IPv6 ORPort.
+        let netdir = construct_custom_netdir(|pos, nb, _| {
+            if pos % 3 == 0 {
+                let addr: SocketAddr = format!("[2001:db8::{}]:9001", pos).parse().unwrap();
+                nb.rs.add_or_port(addr);
+            }
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+
+        let with_ipv6: Vec<_> = netdir.relays_with_ipv6().collect();
+        assert_eq!(with_ipv6.len(), 14);
+        for r in &with_ipv6 {
+            assert_eq!(r.ipv6_orports().count(), 1);
+        }
+        assert_eq!(netdir.relays().filter(|r| !r.has_ipv6()).count(), 26);
+
+        let mut rng = testing_rng();
+        for _ in 0..50 {
+            let r = netdir
+                .pick_relay_with_ipv6_preference(
+                    &mut rng,
+                    WeightRole::Middle,
+                    &Ipv6Preference::ipv6_only(),
+                    |_| true,
+                )
+                .unwrap();
+            assert!(r.has_ipv6());
+        }
+        // With no preference, we choose relays without IPv6 too.
+        let n_without = (0..200)
+            .filter(|_| {
+                !netdir
+                    .pick_relay_with_ipv6_preference(
+                        &mut rng,
+                        WeightRole::Middle,
+                        &Ipv6Preference::default(),
+                        |_| true,
+                    )
+                    .unwrap()
+                    .has_ipv6()
+            })
+            .count();
+        assert!(n_without > 0);
+    }
+}
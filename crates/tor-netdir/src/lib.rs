@@ -42,13 +42,39 @@
 #![allow(clippy::needless_lifetimes)] // See arti#1765
 //! <!-- @@ end lint list maintained by maint/add_warning @@ -->
 
+mod compact;
+#[cfg(feature = "geoip")]
+mod country;
 pub mod details;
+mod dirchange;
+mod distribution;
 mod err;
+mod exclusion;
+mod flagquery;
 #[cfg(feature = "hs-common")]
 mod hsdir_params;
 #[cfg(feature = "hs-common")]
 mod hsdir_ring;
+mod ipv6;
+mod limits;
+mod mdpool;
+mod metadata;
+#[cfg(feature = "ns-consensus")]
+mod nsdir;
+mod operator;
+#[cfg(feature = "overload")]
+mod overload;
 pub mod params;
+mod pathpolicy;
+mod portcoverage;
+mod protosupport;
+mod relaystats;
+mod role;
+mod search;
+mod seeded;
+#[cfg(feature = "snapshot")]
+mod snapshot;
+mod watch;
 mod weight;
 
 #[cfg(any(test, feature = "testing"))]
@@ -61,10 +87,12 @@ use async_trait::async_trait;
 use itertools::chain;
 use static_assertions::const_assert;
 use tor_linkspec::{
-    ChanTarget, DirectChanMethodsHelper, HasAddrs, HasRelayIds, RelayIdRef, RelayIdType,
+    ChanTarget, DirectChanMethodsHelper, HasAddrs, HasRelayIds, IpVersionPreference, RelayIdRef,
+    RelayIdType,
 };
 use tor_llcrypto as ll;
 use tor_llcrypto::pk::{ed25519::Ed25519Identity, rsa::RsaIdentity};
+use tor_netdoc::doc::bwfile::BandwidthFile;
 use tor_netdoc::doc::microdesc::{MdDigest, Microdesc};
 use tor_netdoc::doc::netstatus::{self, MdConsensus, MdConsensusRouterStatus, RouterStatus};
 #[cfg(feature = "hs-common")]
@@ -76,7 +104,7 @@ use num_enum::{IntoPrimitive, TryFromPrimitive};
 use rand::seq::SliceRandom;
 use serde::Deserialize;
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
 use std::sync::Arc;
 use strum::{EnumCount, EnumIter};
@@ -91,7 +119,26 @@ use {
     tor_hscrypto::{pk::HsBlindId, time::TimePeriod},
 };
 
+pub use dirchange::{
+    DetailedDirEvent, FlagChange, NetDirChangeSummary, NetDirDiff, NetDirGeneration,
+    RelayListChange, WeightChange,
+};
+pub use distribution::{WeightBucket, WeightDistribution};
 pub use err::Error;
+pub use exclusion::PathExclusion;
+pub use flagquery::RelayFlagQuery;
+pub use ipv6::Ipv6Preference;
+pub use limits::{NetDirLimits, OversizePolicy};
+pub use mdpool::MicrodescPool;
+pub use metadata::{ConsensusMetadata, VotingSchedule};
+pub use operator::OperatorCluster;
+pub use pathpolicy::PathPolicy;
+pub use portcoverage::PortCoverage;
+pub use protosupport::ProtocolSupportSummary;
+pub use relaystats::UsableRelayStats;
+pub use role::{ExitPort, RelayRole};
+pub use seeded::{SelectionSession, WeightTable};
+pub use watch::{RelayChange, RelayListing};
 pub use weight::WeightRole;
 /// A Result using the Error type from the tor-netdir crate
 pub type Result<T> = std::result::Result<T, Error>;
@@ -101,11 +148,23 @@ pub use err::OnionDirLookupError;
 
 use params::NetParameters;
 #[cfg(feature = "geoip")]
-use tor_geoip::{CountryCode, GeoipDb, HasCountryCode};
+use tor_geoip::{AsNumber, CountryCode, GeoipDb, GeoipDbManager, HasAsn, HasCountryCode};
 
+#[cfg(feature = "geoip")]
+#[cfg_attr(docsrs, doc(cfg(feature = "geoip")))]
+pub use country::{CountryCheckReport, CountryDisagreement, CountryRelayCounts, GeoStats};
 #[cfg(feature = "hs-common")]
 #[cfg_attr(docsrs, doc(cfg(feature = "hs-common")))]
 pub use hsdir_params::HsDirParams;
+#[cfg(feature = "hs-common")]
+#[cfg_attr(docsrs, doc(cfg(feature = "hs-common")))]
+pub use hsdir_ring::HsDirIndex;
+#[cfg(feature = "ns-consensus")]
+#[cfg_attr(docsrs, doc(cfg(feature = "ns-consensus")))]
+pub use nsdir::{NsNetDir, PartialNsNetDir};
+
+#[cfg(feature = "overload")]
+pub use overload::{OverloadStatus, OverloadWeighting};
 
 /// Index into the consensus relays
 ///
@@ -306,6 +365,21 @@ pub enum HsDirOp {
     Download,
 }
 
+/// Which of the hidden service directory "spread" parameters to look up.
+///
+/// See [`NetDir::hs_dir_spread`].
+#[cfg(feature = "hs-common")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HsDirSpread {
+    /// The number of directories from which to fetch a descriptor at each
+    /// replica position (`hsdir_spread_fetch`).
+    Fetch,
+    /// The number of directories to which to upload a descriptor at each
+    /// replica position (`hsdir_spread_store`).
+    Store,
+}
+
 /// A view of the Tor directory, suitable for use in building circuits.
 ///
 /// Abstractly, a [`NetDir`] is a set of usable public [`Relay`]s, each of which
@@ -345,6 +419,24 @@ pub struct NetDir {
     params: NetParameters,
     /// Map from routerstatus index, to that routerstatus's microdescriptor (if we have one.)
     mds: TiVec<RouterStatusIdx, Option<Arc<Microdesc>>>,
+    /// Map from routerstatus index, to the port coverage of that routerstatus's
+    /// exit policies.
+    ///
+    /// An entry here is present if and only if the corresponding entry in
+    /// `mds` is present.
+    exit_coverage: TiVec<RouterStatusIdx, Option<portcoverage::ExitCoverage>>,
+    /// The port coverage of every distinct exit policy in `mds`.
+    coverage_cache: portcoverage::CoverageCache,
+    /// The generation of this directory's contents.
+    ///
+    /// We replace this whenever the contents change.
+    generation: NetDirGeneration,
+    /// Index of the relays in `mds` that allow exiting to any port, by their
+    /// exit policies.
+    ///
+    /// We keep this up to date whenever a relay gains or loses its
+    /// microdescriptor.  It's in an `Arc` so that cloning a `NetDir` is cheap.
+    exit_index: Arc<portcoverage::ExitIndex>,
     /// Map from SHA256 of _missing_ microdescriptors to the index of their
     /// corresponding routerstatus.
     rsidx_by_missing: HashMap<MdDigest, RouterStatusIdx>,
@@ -364,8 +456,22 @@ pub struct NetDir {
     /// Map from RSA identity to index of the routerstatus.
     ///
     /// This is constructed at the same time as the NetDir object, so it
-    /// can be immutable.
-    rsidx_by_rsa: Arc<HashMap<RsaIdentity, RouterStatusIdx>>,
+    /// can be immutable; we store it as a compact sorted array.
+    rsidx_by_rsa: Arc<compact::SortedIndex<RsaIdentity>>,
+    /// Per-flag bitsets over the routerstatuses in the consensus.
+    ///
+    /// Like `rsidx_by_rsa`, this is constructed at the same time as the
+    /// NetDir object, and is immutable.
+    flag_index: Arc<flagquery::FlagIndex>,
+    /// Map from IP address to the indices of the routerstatuses that list an
+    /// ORPort on that address, in consensus order.
+    ///
+    /// Like `rsidx_by_rsa`, this is constructed at the same time as the
+    /// NetDir object, and is immutable.
+    rsidx_by_ip: Arc<compact::SortedIndex<IpAddr>>,
+    /// The pool through which we share our microdescriptors with other
+    /// directories, if we have one.
+    md_pool: Option<MicrodescPool>,
 
     /// Hash ring(s) describing the onion service directory.
     ///
@@ -388,7 +494,36 @@ pub struct NetDir {
     ///
     /// This is indexed by the `RouterStatusIdx` (i.e. a router idx of zero has
     /// the country code at position zero in this array).
-    country_codes: Vec<Option<CountryCode>>,
+    ///
+    /// This is `None` if we were not given a GeoIP database when this
+    /// directory was constructed.
+    country_codes: Option<Vec<Option<CountryCode>>>,
+
+    #[cfg(feature = "geoip")]
+    /// Autonomous system numbers for each router in our consensus.
+    ///
+    /// This is indexed and populated in the same way as `country_codes`.
+    asns: Option<Vec<Option<AsNumber>>>,
+
+    /// The limits on how many microdescriptors we will retain.
+    md_budget: limits::MdBudget,
+
+    /// Statistics about the relays in this directory that are usable.
+    ///
+    /// We keep this up to date whenever a relay gains or loses its
+    /// microdescriptor.  It's in an `Arc` so that handing out a snapshot is
+    /// cheap; we only copy it if we change it while a snapshot is alive.
+    usable_stats: Arc<relaystats::UsableRelayStats>,
+
+    #[cfg(feature = "overload")]
+    /// Map from routerstatus index to the overload report in that relay's
+    /// router descriptor, for the relays whose descriptors we've been told
+    /// about and which report overload.
+    overload: HashMap<RouterStatusIdx, OverloadStatus>,
+
+    /// Map from routerstatus index to the bandwidth measured for that relay
+    /// in the latest bandwidth file we've been given, in kilobytes per second.
+    measured_bandwidths: HashMap<RouterStatusIdx, u32>,
 }
 
 /// Collection of hidden service directories (or parameters for them)
@@ -493,7 +628,10 @@ pub enum DirEvent {
     ///
     /// This event is also broadcast when a new set of consensus parameters is
     /// available, even if that set of parameters comes from a configuration
-    /// change rather than from the latest consensus.
+    /// change rather than from the latest consensus, when the relays in
+    /// the current directory have been looked up again in a new GeoIP
+    /// database, and when we've discarded the current directory because we
+    /// switched to another network.
     NewConsensus,
 
     /// New descriptors have been received for the current consensus.
@@ -503,6 +641,31 @@ pub enum DirEvent {
     NewDescriptors,
 }
 
+/// Helper: look up the country code and autonomous system number of every
+/// relay in `consensus`, in `db`.
+///
+/// The results are indexed by `RouterStatusIdx`.
+#[cfg(feature = "geoip")]
+fn geoip_annotations(
+    consensus: &MdConsensus,
+    db: &GeoipDb,
+) -> (Vec<Option<CountryCode>>, Vec<Option<AsNumber>>) {
+    let country_codes = consensus
+        .c_relays()
+        .iter()
+        .map(|rs| {
+            db.lookup_country_code_multi(rs.addrs().iter().map(|x| x.ip()))
+                .cloned()
+        })
+        .collect();
+    let asns = consensus
+        .c_relays()
+        .iter()
+        .map(|rs| db.lookup_asn_multi(rs.addrs().iter().map(|x| x.ip())))
+        .collect();
+    (country_codes, asns)
+}
+
 /// The network directory provider is shutting down without giving us the
 /// netdir we asked for.
 #[derive(Clone, Copy, Debug, thiserror::Error)]
@@ -577,11 +740,65 @@ pub trait NetDirProvider: UpcastArcNetDirProvider + Send + Sync {
     /// occurred at least once.
     fn events(&self) -> BoxStream<'static, DirEvent>;
 
+    /// Return a new asynchronous stream that will receive a detailed
+    /// description of every change to the directory.
+    ///
+    /// Unlike [`events`](NetDirProvider::events), this stream does not batch
+    /// events together: it reports which relays were added or removed by each
+    /// new consensus, and which microdescriptors arrived, so that listeners
+    /// don't need to re-scan the whole [`NetDir`] after every change.
+    ///
+    /// The default implementation yields a
+    /// [`DetailedDirEvent::Unspecified`] for every item from
+    /// [`events`](NetDirProvider::events).
+    fn detailed_events(&self) -> BoxStream<'static, DetailedDirEvent> {
+        Box::pin(self.events().map(DetailedDirEvent::Unspecified))
+    }
+
     /// Return the latest network parameters.
     ///
     /// If we have no directory, return a reasonable set of defaults.
     fn params(&self) -> Arc<dyn AsRef<NetParameters>>;
 
+    /// Return a summary of the changes between the directory with the given
+    /// `generation`, and the latest one that this provider has published.
+    ///
+    /// A listener that keeps a cache built from a [`NetDir`] can use this
+    /// after each [`DirEvent`] to decide cheaply whether it needs to rebuild
+    /// its cache.
+    ///
+    /// Returns `None` if the provider can't say what has changed: for
+    /// example, because it never published a directory with `generation`, or
+    /// because it no longer remembers that far back.  In that case, listeners
+    /// should assume that everything has changed.
+    ///
+    /// The default implementation always returns `None`.
+    fn change_summary(&self, generation: NetDirGeneration) -> Option<NetDirChangeSummary> {
+        let _ = generation;
+        None
+    }
+
+    /// Return a new asynchronous stream that reports every change in the
+    /// flags, addresses, or listing status of the relay with `ids`.
+    ///
+    /// The first change reported is relative to the relay's listing in our
+    /// directory when this method is called; if we don't have a directory
+    /// yet, it's relative to the first one that we get.  Consensuses that
+    /// don't change anything about the relay are not reported.
+    ///
+    /// If `ids` has no RSA identity, we can only tell whether the relay is
+    /// listed once we have its microdescriptor; until then, it counts as
+    /// unchanged.
+    ///
+    /// The stream ends when this provider is dropped, or when its
+    /// [`events`](NetDirProvider::events) stream ends.
+    fn watch_relay(self: Arc<Self>, ids: tor_linkspec::RelayIds) -> BoxStream<'static, RelayChange>
+    where
+        Self: 'static,
+    {
+        watch::watch_relay(self.upcast_arc(), ids)
+    }
+
     /// Get a NetDir from `provider`, waiting until one exists.
     async fn wait_for_netdir(
         &self,
@@ -663,9 +880,17 @@ where
         self.deref().events()
     }
 
+    fn detailed_events(&self) -> BoxStream<'static, DetailedDirEvent> {
+        self.deref().detailed_events()
+    }
+
     fn params(&self) -> Arc<dyn AsRef<NetParameters>> {
         self.deref().params()
     }
+
+    fn change_summary(&self, generation: NetDirGeneration) -> Option<NetDirChangeSummary> {
+        self.deref().change_summary(generation)
+    }
 }
 
 /// Helper trait: allows any `Arc<X>` to be upcast to a `Arc<dyn
@@ -723,9 +948,19 @@ pub struct Relay<'a> {
     rs: &'a netstatus::MdConsensusRouterStatus,
     /// A microdescriptor for this relay.
     md: &'a Microdesc,
+    /// The port coverage of this relay's exit policies.
+    coverage: &'a portcoverage::ExitCoverage,
     /// The country code this relay is in, if we know one.
     #[cfg(feature = "geoip")]
     cc: Option<CountryCode>,
+    /// The autonomous system this relay is in, if we know one.
+    #[cfg(feature = "geoip")]
+    asn: Option<AsNumber>,
+    /// This relay's report that it is overloaded, if we know of one.
+    #[cfg(feature = "overload")]
+    overload: Option<OverloadStatus>,
+    /// The bandwidth measured for this relay, if we know it.
+    measured_bw: Option<u32>,
 }
 
 /// A relay that we haven't checked for validity or usability in
@@ -736,9 +971,20 @@ pub struct UncheckedRelay<'a> {
     rs: &'a netstatus::MdConsensusRouterStatus,
     /// A microdescriptor for this relay, if there is one.
     md: Option<&'a Microdesc>,
+    /// The port coverage of this relay's exit policies, if we have a
+    /// microdescriptor.
+    coverage: Option<&'a portcoverage::ExitCoverage>,
     /// The country code this relay is in, if we know one.
     #[cfg(feature = "geoip")]
     cc: Option<CountryCode>,
+    /// The autonomous system this relay is in, if we know one.
+    #[cfg(feature = "geoip")]
+    asn: Option<AsNumber>,
+    /// This relay's report that it is overloaded, if we know of one.
+    #[cfg(feature = "overload")]
+    overload: Option<OverloadStatus>,
+    /// The bandwidth measured for this relay, if we know it.
+    measured_bw: Option<u32>,
 }
 
 /// A partial or full network directory that we can download
@@ -787,6 +1033,21 @@ impl PartialNetDir {
         Self::new_inner(consensus, replacement_params, Some(geoip_db))
     }
 
+    /// Create a new PartialNetDir with GeoIP support, using whichever database
+    /// `geoip_mgr` holds right now.
+    ///
+    /// Later replacements of the manager's database have no effect on this
+    /// directory: they are used by the next directory that we build.
+    #[cfg(feature = "geoip")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "geoip")))]
+    pub fn new_with_geoip_manager(
+        consensus: MdConsensus,
+        replacement_params: Option<&netstatus::NetParams<i32>>,
+        geoip_mgr: &GeoipDbManager,
+    ) -> Self {
+        Self::new_with_geoip(consensus, replacement_params, &geoip_mgr.current())
+    }
+
     /// Implementation of the `new()` functions.
     fn new_inner(
         consensus: MdConsensus,
@@ -812,6 +1073,47 @@ impl PartialNetDir {
         // Compute the weights we'll want to use for these relays.
         let weights = weight::WeightSet::from_consensus(&consensus, &params);
 
+        #[cfg(feature = "geoip")]
+        let (country_codes, asns) = match geoip_db {
+            Some(db) => {
+                let (country_codes, asns) = geoip_annotations(&consensus, db);
+                (Some(country_codes), Some(asns))
+            }
+            None => (None, None),
+        };
+
+        #[cfg(feature = "hs-common")]
+        let hsdir_params = HsDirParams::compute(&consensus, &params).expect("Invalid consensus!");
+        // TODO: It's a bit ugly to use expect above, but this function does
+        // not return a Result. On the other hand, the error conditions under which
+        // HsDirParams::compute can return Err are _very_ narrow and hard to
+        // hit; see documentation in that function.  As such, we probably
+        // don't need to have this return a Result.
+
+        Self::from_parts(
+            consensus,
+            params,
+            weights,
+            #[cfg(feature = "hs-common")]
+            hsdir_params,
+            #[cfg(feature = "geoip")]
+            country_codes,
+            #[cfg(feature = "geoip")]
+            asns,
+        )
+    }
+
+    /// Helper: Create a new PartialNetDir from a consensus, once we know its
+    /// parameters, its weights, its onion service directory parameters, and
+    /// (if we have them) its GeoIP annotations.
+    fn from_parts(
+        consensus: MdConsensus,
+        params: NetParameters,
+        weights: weight::WeightSet,
+        #[cfg(feature = "hs-common")] hsdir_params: HsDirs<HsDirParams>,
+        #[cfg(feature = "geoip")] country_codes: Option<Vec<Option<CountryCode>>>,
+        #[cfg(feature = "geoip")] asns: Option<Vec<Option<AsNumber>>>,
+    ) -> Self {
         let n_relays = consensus.c_relays().len();
 
         let rsidx_by_missing = consensus
@@ -826,46 +1128,49 @@ impl PartialNetDir {
             .map(|(rsidx, rs)| (*rs.rsa_identity(), rsidx))
             .collect();
 
-        #[cfg(feature = "geoip")]
-        let country_codes = if let Some(db) = geoip_db {
-            consensus
-                .c_relays()
-                .iter()
-                .map(|rs| {
-                    let ret = db
-                        .lookup_country_code_multi(rs.addrs().iter().map(|x| x.ip()))
-                        .cloned();
-                    ret
-                })
-                .collect()
-        } else {
-            Default::default()
-        };
+        let flag_index = Arc::new(flagquery::FlagIndex::new(consensus.c_relays()));
+
+        let mut ip_entries: Vec<(IpAddr, RouterStatusIdx)> = Vec::new();
+        for (rsidx, rs) in consensus.c_relays().iter_enumerated() {
+            let first = ip_entries.len();
+            for addr in rs.addrs() {
+                // A relay may list more than one port on the same address.
+                if !ip_entries[first..].contains(&(addr.ip(), rsidx)) {
+                    ip_entries.push((addr.ip(), rsidx));
+                }
+            }
+        }
+        let rsidx_by_ip: compact::SortedIndex<IpAddr> = ip_entries.into_iter().collect();
 
         #[cfg(feature = "hs-common")]
-        let hsdir_rings = Arc::new({
-            let params = HsDirParams::compute(&consensus, &params).expect("Invalid consensus!");
-            // TODO: It's a bit ugly to use expect above, but this function does
-            // not return a Result. On the other hand, the error conditions under which
-            // HsDirParams::compute can return Err are _very_ narrow and hard to
-            // hit; see documentation in that function.  As such, we probably
-            // don't need to have this return a Result.
-
-            params.map(HsDirRing::empty_from_params)
-        });
+        let hsdir_rings = Arc::new(hsdir_params.map(HsDirRing::empty_from_params));
 
         let netdir = NetDir {
             consensus: Arc::new(consensus),
             params,
             mds: vec![None; n_relays].into(),
+            exit_coverage: vec![None; n_relays].into(),
+            coverage_cache: Default::default(),
+            exit_index: Default::default(),
+            generation: NetDirGeneration::next(),
             rsidx_by_missing,
             rsidx_by_rsa: Arc::new(rsidx_by_rsa),
+            flag_index,
+            rsidx_by_ip: Arc::new(rsidx_by_ip),
+            md_pool: None,
             rsidx_by_ed: HashMap::with_capacity(n_relays),
             #[cfg(feature = "hs-common")]
             hsdir_rings,
             weights,
             #[cfg(feature = "geoip")]
             country_codes,
+            #[cfg(feature = "geoip")]
+            asns,
+            md_budget: limits::MdBudget::default(),
+            usable_stats: Default::default(),
+            #[cfg(feature = "overload")]
+            overload: HashMap::new(),
+            measured_bandwidths: HashMap::new(),
         };
 
         PartialNetDir {
@@ -875,23 +1180,120 @@ impl PartialNetDir {
         }
     }
 
+    /// Apply `limits` to this PartialNetDir, and to the NetDir that it
+    /// becomes.
+    ///
+    /// Return an error if the consensus lists more relays than `limits`
+    /// allows, and `limits` says to reject such a consensus.
+    ///
+    /// This should be called before adding any microdescriptors.  If any are
+    /// already present, those that exceed the limits are discarded.
+    pub fn with_limits(mut self, limits: &NetDirLimits) -> Result<Self> {
+        let n_relays = self.netdir.c_relays().len();
+        if let Some(max_relays) = limits.max_relays {
+            if n_relays > max_relays {
+                match limits.policy {
+                    OversizePolicy::Reject => {
+                        return Err(Error::ConsensusTooLarge {
+                            n_relays,
+                            max_relays,
+                        })
+                    }
+                    OversizePolicy::KeepHighestWeight => {
+                        warn!(
+                            "Consensus lists {} relays; only using the {} with the highest weight.",
+                            n_relays, max_relays
+                        );
+                        self.netdir.keep_highest_weight(max_relays);
+                    }
+                }
+            }
+        }
+
+        self.netdir.md_budget = limits::MdBudget::new(limits);
+        let present: Vec<RouterStatusIdx> = self
+            .netdir
+            .mds
+            .iter_enumerated()
+            .filter_map(|(rsidx, md)| md.as_ref().map(|_| rsidx))
+            .collect();
+        for rsidx in present {
+            let netdir = &mut self.netdir;
+            let admitted = match &netdir.mds[rsidx] {
+                Some(md) => netdir.md_budget.try_admit(md),
+                None => true,
+            };
+            if !admitted {
+                netdir.forget_relay(rsidx);
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Share this PartialNetDir's microdescriptors, and those of the NetDir
+    /// that it becomes, through `pool`.
+    ///
+    /// Directories that use the same pool hold a single copy of each
+    /// microdescriptor that they have in common, however many times it was
+    /// parsed.
+    pub fn with_md_pool(mut self, pool: MicrodescPool) -> Self {
+        for md in self.netdir.mds.iter_mut().flatten() {
+            *md = pool.intern_arc(md.clone());
+        }
+        self.netdir.md_pool = Some(pool);
+        self
+    }
+
     /// Return the declared lifetime of this PartialNetDir.
     pub fn lifetime(&self) -> &netstatus::Lifetime {
         self.netdir.lifetime()
     }
 
+    /// Return a snapshot of our statistics about the relays in this directory
+    /// that are usable so far.
+    ///
+    /// See [`NetDir::usable_relay_stats`].
+    pub fn usable_relay_stats(&self) -> Arc<UsableRelayStats> {
+        self.netdir.usable_relay_stats()
+    }
+
     /// Record a previous netdir, which can be used for reusing cached information
     //
     // Fills in as many missing microdescriptors as possible in this
     // netdir, using the microdescriptors from the previous netdir.
     //
     // With HS enabled, stores the netdir for reuse of relay hash ring index values.
+    //
+    // If this netdir has no microdescriptor pool, it adopts the previous one's.
     #[allow(clippy::needless_pass_by_value)] // prev might, or might not, be stored
     pub fn fill_from_previous_netdir(&mut self, prev: Arc<NetDir>) {
+        if self.netdir.md_pool.is_none() {
+            self.netdir.md_pool = prev.md_pool.clone();
+        }
         for md in prev.mds.iter().flatten() {
             self.netdir.add_arc_microdesc(md.clone());
         }
 
+        // Keep any overload reports that we haven't replaced.
+        #[cfg(feature = "overload")]
+        for (prev_idx, status) in &prev.overload {
+            let rsa_id = prev.c_relays()[*prev_idx].rsa_identity();
+            if let Some(idx) = self.netdir.rsidx_by_rsa.get(rsa_id) {
+                self.netdir.overload.entry(idx).or_insert(*status);
+            }
+        }
+
+        // Keep the measured bandwidths, unless we've been given new ones.
+        if self.netdir.measured_bandwidths.is_empty() {
+            for (prev_idx, bw) in &prev.measured_bandwidths {
+                let rsa_id = prev.c_relays()[*prev_idx].rsa_identity();
+                if let Some(idx) = self.netdir.rsidx_by_rsa.get(rsa_id) {
+                    self.netdir.measured_bandwidths.insert(idx, *bw);
+                }
+            }
+        }
+
         #[cfg(feature = "hs-common")]
         {
             self.prev_netdir = Some(prev);
@@ -916,6 +1318,45 @@ impl PartialNetDir {
     pub fn have_enough_paths(&self) -> bool {
         self.netdir.have_enough_paths()
     }
+
+    /// Record whether the relay described by `rd` reports that it is
+    /// overloaded.
+    ///
+    /// See [`NetDir::note_overload_from_routerdesc`].
+    #[cfg(feature = "overload")]
+    pub fn note_overload_from_routerdesc(
+        &mut self,
+        rd: &tor_netdoc::doc::routerdesc::RouterDesc,
+    ) -> bool {
+        self.netdir.note_overload_from_routerdesc(rd)
+    }
+
+    /// Replace the measured bandwidths in this directory with those in
+    /// `file`.
+    ///
+    /// See [`NetDir::set_measured_bandwidths`].
+    pub fn set_measured_bandwidths(&mut self, file: &BandwidthFile) -> usize {
+        self.netdir.set_measured_bandwidths(file)
+    }
+    /// Return up to `limit` digests of the missing microdescriptors that we
+    /// most need in order to have enough paths.
+    ///
+    /// The digests are chosen greedily by weight: each one is for the relay
+    /// whose microdescriptor would most increase the fraction of paths we can
+    /// build, given the ones listed before it.  We stop listing digests once
+    /// the listed microdescriptors would be enough to make
+    /// [`have_enough_paths`](PartialNetDir::have_enough_paths) true.
+    ///
+    /// A downloader can fetch these microdescriptors first, so that this
+    /// directory becomes usable sooner.  If we already have enough paths,
+    /// the result is empty.
+    ///
+    /// This takes time proportional to `limit` times the number of missing
+    /// microdescriptors.
+    pub fn critical_missing_mds(&self, limit: usize) -> Vec<MdDigest> {
+        self.netdir.critical_missing_mds(limit)
+    }
+
     /// If this directory has enough information to build multihop
     /// circuits, return it.
     pub fn unwrap_if_sufficient(
@@ -949,6 +1390,51 @@ impl NetDir {
         self.consensus.lifetime()
     }
 
+    /// Stop using the relay at `rsidx`: discard its microdescriptor if we
+    /// have one, and never ask for one again.
+    fn forget_relay(&mut self, rsidx: RouterStatusIdx) {
+        self.note_changed();
+        self.note_usability(rsidx, false);
+        let digest = *self.c_relays()[rsidx].md_digest();
+        self.rsidx_by_missing.remove(&digest);
+        self.exit_coverage[rsidx] = None;
+        if let Some(md) = self.mds[rsidx].take() {
+            Arc::make_mut(&mut self.exit_index).remove(rsidx, &md);
+            if self.rsidx_by_ed.get(md.ed25519_id()) == Some(&rsidx) {
+                self.rsidx_by_ed.remove(md.ed25519_id());
+            }
+        }
+    }
+
+    /// Stop using all but the `n` relays with the highest bandwidth in this
+    /// NetDir.
+    fn keep_highest_weight(&mut self, n: usize) {
+        let mut by_weight: Vec<(RouterStatusIdx, u32)> = self
+            .c_relays()
+            .iter_enumerated()
+            .map(|(rsidx, rs)| (rsidx, self.weights.bandwidth_of(rs)))
+            .collect();
+        // Heaviest first; break ties by position, so that this is deterministic.
+        by_weight.sort_by_key(|&(rsidx, w)| (std::cmp::Reverse(w), rsidx));
+        for (rsidx, _) in by_weight.into_iter().skip(n) {
+            self.forget_relay(rsidx);
+        }
+    }
+
+    /// Return true if we have refused any microdescriptors for this NetDir
+    /// because of the limits set with [`PartialNetDir::with_limits`].
+    pub fn md_limit_reached(&self) -> bool {
+        self.md_budget.exhausted()
+    }
+
+    /// Return the pool through which this NetDir shares its
+    /// microdescriptors, if it has one.
+    ///
+    /// See [`PartialNetDir::with_md_pool`].
+    pub fn md_pool(&self) -> Option<&MicrodescPool> {
+        self.md_pool.as_ref()
+    }
+
     /// Add `md` to this NetDir.
     ///
     /// Return true if we wanted it, and false otherwise.
@@ -956,13 +1442,32 @@ impl NetDir {
         if let Some(rsidx) = self.rsidx_by_missing.remove(md.digest()) {
             assert_eq!(self.c_relays()[rsidx].md_digest(), md.digest());
 
+            // If we can't afford to keep this one, we don't want it after all.
+            // (We've removed it from the missing list, so we won't ask for it
+            // again.)
+            if !self.md_budget.try_admit(&md) {
+                return false;
+            }
+
+            let md = match &self.md_pool {
+                Some(pool) => pool.intern_arc(md),
+                None => md,
+            };
+
             // There should never be two approved MDs in the same
             // consensus listing the same ID... but if there is,
             // we'll let the most recent one win.
             self.rsidx_by_ed.insert(*md.ed25519_id(), rsidx);
 
+            let bad_exit = self.c_relays()[rsidx].is_flagged_bad_exit();
+            let coverage = self.coverage_cache.exit_coverage(&md, bad_exit);
+            Arc::make_mut(&mut self.exit_index).insert(rsidx, &md, &coverage);
+            self.exit_coverage[rsidx] = Some(coverage);
+
             // Happy path: we did indeed want this one.
             self.mds[rsidx] = Some(md);
+            self.note_changed();
+            self.note_usability(rsidx, true);
 
             // Save some space in the missing-descriptor list.
             if self.rsidx_by_missing.len() < self.rsidx_by_missing.capacity() / 4 {
@@ -976,6 +1481,32 @@ impl NetDir {
         false
     }
 
+    /// If the relay at `rsidx` is usable, record in our statistics that it
+    /// has just become usable (if `added` is true), or that it is about to
+    /// stop being usable (if `added` is false).
+    fn note_usability(&mut self, rsidx: RouterStatusIdx, added: bool) {
+        let relay = self.relay_from_rs_and_rsidx(&self.c_relays()[rsidx], rsidx);
+        if !relay.is_usable() {
+            return;
+        }
+        let key = relaystats::StatsKey::for_relay(&relay);
+        let stats = Arc::make_mut(&mut self.usable_stats);
+        if added {
+            stats.add(key);
+        } else {
+            stats.remove(key);
+        }
+    }
+
+    /// Return a snapshot of our statistics about the usable relays in this
+    /// directory.
+    ///
+    /// This is cheap: we keep these statistics up to date as relays become
+    /// usable, so this doesn't need to look at every relay.
+    pub fn usable_relay_stats(&self) -> Arc<UsableRelayStats> {
+        Arc::clone(&self.usable_stats)
+    }
+
     /// Construct a (possibly invalid) Relay object from a routerstatus and its
     /// index within the consensus.
     fn relay_from_rs_and_rsidx<'a>(
@@ -992,14 +1523,48 @@ impl NetDir {
         UncheckedRelay {
             rs,
             md,
+            coverage: self.exit_coverage[rsidx].as_ref(),
+            #[cfg(feature = "geoip")]
+            cc: self.country_code_by_rsidx(rsidx),
             #[cfg(feature = "geoip")]
-            cc: self.country_codes.get(rsidx.0).copied().flatten(),
+            asn: self.asn_by_rsidx(rsidx),
+            #[cfg(feature = "overload")]
+            overload: self.overload.get(&rsidx).copied(),
+            measured_bw: self.measured_bandwidths.get(&rsidx).copied(),
         }
     }
 
-    /// Return the value of the hsdir_n_replicas param.
+    /// Return the country code for the relay at `rsidx`, if we know one.
+    #[cfg(feature = "geoip")]
+    fn country_code_by_rsidx(&self, rsidx: RouterStatusIdx) -> Option<CountryCode> {
+        self.country_codes.as_ref()?.get(rsidx.0).copied().flatten()
+    }
+
+    /// Return the AS number for the relay at `rsidx`, if we know one.
+    #[cfg(feature = "geoip")]
+    fn asn_by_rsidx(&self, rsidx: RouterStatusIdx) -> Option<AsNumber> {
+        self.asns.as_ref()?.get(rsidx.0).copied().flatten()
+    }
+
+    /// Return true if this NetDir was constructed with a GeoIP database.
+    ///
+    /// If this returns false, then every relay in this directory will
+    /// report a [`country_code()`](HasCountryCode::country_code) of `None`,
+    /// and any predicate that looks for a particular country will match
+    /// nothing.  Callers that want to distinguish "this relay's location is
+    /// unknown" from "we never tried to find out" should check this first.
+    #[cfg(feature = "geoip")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "geoip")))]
+    pub fn has_country_codes(&self) -> bool {
+        self.country_codes.is_some()
+    }
+
+    /// Return the value of the `hsdir_n_replicas` consensus parameter.
+    ///
+    /// This is the number of places on each hash ring at which an onion
+    /// service's descriptor is stored.
     #[cfg(feature = "hs-common")]
-    fn n_replicas(&self) -> u8 {
+    pub fn hs_dir_n_replicas(&self) -> u8 {
         self.params
             .hsdir_n_replicas
             .get()
@@ -1007,13 +1572,16 @@ impl NetDir {
             .expect("BoundedInt did not enforce bounds")
     }
 
-    /// Return the spread parameter for the specified `op`.
+    /// Return the value of the `which` spread parameter.
+    ///
+    /// This is the number of hidden service directories used at each replica
+    /// position: `hsdir_spread_fetch` when downloading, and
+    /// `hsdir_spread_store` when uploading.
     #[cfg(feature = "hs-common")]
-    fn spread(&self, op: HsDirOp) -> usize {
-        let spread = match op {
-            HsDirOp::Download => self.params.hsdir_spread_fetch,
-            #[cfg(feature = "hs-service")]
-            HsDirOp::Upload => self.params.hsdir_spread_store,
+    pub fn hs_dir_spread(&self, which: HsDirSpread) -> usize {
+        let spread = match which {
+            HsDirSpread::Fetch => self.params.hsdir_spread_fetch,
+            HsDirSpread::Store => self.params.hsdir_spread_store,
         };
 
         spread
@@ -1040,7 +1608,7 @@ impl NetDir {
         ring: &'h HsDirRing,
         spread: usize,
     ) -> impl Iterator<Item = Relay<'r>> + 'h {
-        let n_replicas = self.n_replicas();
+        let n_replicas = self.hs_dir_n_replicas();
 
         (1..=n_replicas) // 1-indexed !
             .flat_map({
@@ -1069,6 +1637,36 @@ impl NetDir {
             })
     }
 
+    /// Look up every relay in this directory again in `geoip_db`, replacing
+    /// the country codes and autonomous system numbers that we had for them.
+    ///
+    /// Use this when a new GeoIP database becomes available, to avoid waiting
+    /// for the next consensus.
+    ///
+    /// Return the number of relays whose country code or AS number changed.
+    #[cfg(feature = "geoip")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "geoip")))]
+    pub fn replace_geoip_db(&mut self, geoip_db: &GeoipDb) -> usize {
+        let (country_codes, asns) = geoip_annotations(&self.consensus, geoip_db);
+        let n_changed = (0..country_codes.len())
+            .filter(|&idx| {
+                let rsidx = RouterStatusIdx(idx);
+                self.country_code_by_rsidx(rsidx) != country_codes[idx]
+                    || self.asn_by_rsidx(rsidx) != asns[idx]
+            })
+            .count();
+        self.country_codes = Some(country_codes);
+        self.asns = Some(asns);
+        // Our statistics count the usable relays in each country, so we have
+        // to recompute them.
+        self.usable_stats = Default::default();
+        for idx in 0..self.c_relays().len() {
+            self.note_usability(RouterStatusIdx(idx), true);
+        }
+        self.note_changed();
+        n_changed
+    }
+
     /// Replace the overridden parameters in this netdir with `new_replacement`.
     ///
     /// After this function is done, the netdir's parameters will be those in
@@ -1083,6 +1681,74 @@ impl NetDir {
         }
 
         self.params = new_params;
+        self.note_changed();
+    }
+
+    /// Record whether the relay described by `rd` reports that it is
+    /// overloaded.
+    ///
+    /// Microdescriptors don't say whether a relay is overloaded, so this is
+    /// the only way for a `NetDir` to learn it.  A report in `rd` replaces
+    /// any that we had for the same relay; if `rd` doesn't report overload,
+    /// we forget any earlier report.
+    ///
+    /// Return false if this directory doesn't list the relay, or if we
+    /// know the relay's Ed25519 identity and `rd` disagrees with it.  In that
+    /// case, we ignore `rd`.
+    #[cfg(feature = "overload")]
+    pub fn note_overload_from_routerdesc(
+        &mut self,
+        rd: &tor_netdoc::doc::routerdesc::RouterDesc,
+    ) -> bool {
+        let Some(rsidx) = self.rsidx_by_rsa.get(rd.rsa_identity()) else {
+            return false;
+        };
+        if let Some(md) = &self.mds[rsidx] {
+            if md.ed25519_id() != rd.ed_identity() {
+                return false;
+            }
+        }
+        match rd.overload_general() {
+            Some(when) => {
+                self.overload.insert(rsidx, OverloadStatus::new(when));
+            }
+            None => {
+                self.overload.remove(&rsidx);
+            }
+        }
+        self.note_changed();
+        true
+    }
+
+    /// Replace the measured bandwidths in this directory with those in
+    /// `file`, a bandwidth file from a bandwidth scanner.
+    ///
+    /// The measurements are only for information: we don't use them to weight
+    /// our path selection, which always follows the consensus.  They are
+    /// available from [`Relay::measured_bandwidth`].
+    ///
+    /// We skip any entry that the scanner says it didn't actually measure, any
+    /// entry for a relay that this directory doesn't list, and any entry
+    /// whose Ed25519 identity disagrees with the relay's microdescriptor.
+    /// Return the number of relays for which we recorded a measurement.
+    pub fn set_measured_bandwidths(&mut self, file: &BandwidthFile) -> usize {
+        self.measured_bandwidths.clear();
+        for entry in file.relays() {
+            if entry.is_unmeasured() {
+                continue;
+            }
+            let Some(rsidx) = self.rsidx_by_rsa.get(entry.rsa_identity()) else {
+                continue;
+            };
+            if let (Some(md), Some(ed_id)) = (&self.mds[rsidx], entry.ed_identity()) {
+                if md.ed25519_id() != ed_id {
+                    continue;
+                }
+            }
+            self.measured_bandwidths.insert(rsidx, entry.bandwidth());
+        }
+        self.note_changed();
+        self.measured_bandwidths.len()
     }
 
     /// Return an iterator over all Relay objects, including invalid ones
@@ -1099,6 +1765,33 @@ impl NetDir {
         self.all_relays().filter_map(UncheckedRelay::into_relay)
     }
 
+    /// Return an iterator over every [usable](NetDir#usable) Relay that allows
+    /// exiting to `port`, in consensus order.
+    ///
+    /// With [`IpVersionPreference::Ipv4Only`] or
+    /// [`IpVersionPreference::Ipv6Only`], we only return relays that allow
+    /// exiting to `port` on that IP version; otherwise, we return relays that
+    /// allow it on either.
+    ///
+    /// This is much cheaper than checking the policy of every relay: we keep an
+    /// index of relays grouped by their exit policies, so we only need to check
+    /// each distinct policy once.
+    pub fn exits_allowing(
+        &self,
+        port: u16,
+        ip_version: IpVersionPreference,
+    ) -> impl Iterator<Item = Relay<'_>> + '_ {
+        let (ipv4, ipv6) = match ip_version {
+            IpVersionPreference::Ipv4Only => (true, false),
+            IpVersionPreference::Ipv6Only => (false, true),
+            _ => (true, true),
+        };
+        self.exit_index
+            .relays_allowing(port, ipv4, ipv6)
+            .into_iter()
+            .filter_map(move |rsidx| self.relay_by_rs_idx(rsidx))
+    }
+
     /// Look up a relay's `MicroDesc` by its `RouterStatusIdx`
     #[cfg_attr(not(feature = "hs-common"), allow(dead_code))]
     pub(crate) fn md_by_rsidx(&self, rsidx: RouterStatusIdx) -> Option<&Microdesc> {
@@ -1135,6 +1828,30 @@ impl NetDir {
         Some(answer)
     }
 
+    /// Return the [usable](NetDir#usable) relay that has an ORPort at `addr`,
+    /// if there is one.
+    ///
+    /// The authorities don't list two relays with the same ORPort, so there
+    /// should be at most one such relay.  If there are more, we return the
+    /// one that the consensus lists first.
+    ///
+    /// Unlike searching [`relays`](NetDir::relays), this uses an index, so it
+    /// is cheap enough to call for every connection that you observe.
+    pub fn by_addr(&self, addr: &SocketAddr) -> Option<Relay<'_>> {
+        self.relays_with_ip(&addr.ip())
+            .find(|relay| relay.addrs().contains(addr))
+    }
+
+    /// Return an iterator over every [usable](NetDir#usable) relay that has an
+    /// ORPort on `ip`, in the order that the consensus lists them.
+    ///
+    /// Like [`by_addr`](NetDir::by_addr), this uses an index.
+    pub fn relays_with_ip(&self, ip: &IpAddr) -> impl Iterator<Item = Relay<'_>> + '_ {
+        self.rsidx_by_ip
+            .get_all(ip)
+            .filter_map(|rsidx| self.relay_by_rs_idx(rsidx))
+    }
+
     /// Obtain a `Relay` given a `RouterStatusIdx`
     ///
     /// Differs from `relay_from_rs_and_rsi` as follows:
@@ -1145,15 +1862,20 @@ impl NetDir {
     ///
     /// `None` could be returned here, even with a valid `rsi`,
     /// if `rsi` refers to an [unusable](NetDir#usable) relay.
-    #[cfg_attr(not(feature = "hs-common"), allow(dead_code))]
     pub(crate) fn relay_by_rs_idx(&self, rs_idx: RouterStatusIdx) -> Option<Relay<'_>> {
         let rs = self.c_relays().get(rs_idx)?;
         let md = self.mds.get(rs_idx)?.as_deref();
         UncheckedRelay {
             rs,
             md,
+            coverage: self.exit_coverage.get(rs_idx)?.as_ref(),
             #[cfg(feature = "geoip")]
-            cc: self.country_codes.get(rs_idx.0).copied().flatten(),
+            cc: self.country_code_by_rsidx(rs_idx),
+            #[cfg(feature = "geoip")]
+            asn: self.asn_by_rsidx(rs_idx),
+            #[cfg(feature = "overload")]
+            overload: self.overload.get(&rs_idx).copied(),
+            measured_bw: self.measured_bandwidths.get(&rs_idx).copied(),
         }
         .into_relay()
     }
@@ -1324,7 +2046,7 @@ impl NetDir {
     #[cfg_attr(feature = "experimental-api", visibility::make(pub))]
     #[cfg_attr(docsrs, doc(cfg(feature = "experimental-api")))]
     fn by_rsa_id_unchecked(&self, rsa_id: &RsaIdentity) -> Option<UncheckedRelay<'_>> {
-        let rsidx = *self.rsidx_by_rsa.get(rsa_id)?;
+        let rsidx = self.rsidx_by_rsa.get(rsa_id)?;
         let rs = self.c_relays().get(rsidx).expect("Corrupt index");
         assert_eq!(rs.rsa_identity(), rsa_id);
         Some(self.relay_from_rs_and_rsidx(rs, rsidx))
@@ -1449,6 +2171,110 @@ impl NetDir {
         };
         f_g * f_m * f_e
     }
+
+    /// Return up to `limit` digests of missing microdescriptors, chosen
+    /// greedily so that each one would most increase our
+    /// [`frac_usable_paths`](NetDir::frac_usable_paths), given the ones
+    /// before it.
+    ///
+    /// We stop early once the listed microdescriptors would give us enough
+    /// paths, or once no missing microdescriptor would help.
+    ///
+    /// This takes time proportional to `limit` times the number of missing
+    /// microdescriptors.
+    fn critical_missing_mds(&self, limit: usize) -> Vec<MdDigest> {
+        /// The number of roles that `frac_usable_paths` multiplies together.
+        const N_ROLES: usize = 3;
+        let has_exits = self.all_relays().any(|u| u.rs.is_flagged_exit());
+        /// A role, and a predicate telling whether a relay can fill it.
+        type RoleFilter<'f> = (WeightRole, &'f dyn Fn(&UncheckedRelay<'_>) -> bool);
+        let roles: [RoleFilter<'_>; N_ROLES] = [
+            (WeightRole::Guard, &|u| {
+                u.low_level_details().is_suitable_as_guard()
+            }),
+            (WeightRole::Middle, &|_| true),
+            // If there are no exits at all, frac_usable_paths uses the
+            // middle fraction in place of the exit fraction.
+            (WeightRole::Exit, &|u| !has_exits || u.rs.is_flagged_exit()),
+        ];
+
+        // As in frac_for_role, we count each relay's weight towards each
+        // role, or 1 for each relay if no relay has any weight in the role.
+        let mut any_weight = [false; N_ROLES];
+        for r in self.all_relays() {
+            for (i, (role, usable)) in roles.iter().enumerate() {
+                any_weight[i] |= usable(&r) && self.weights.weight_rs_for_role(r.rs, *role) > 0;
+            }
+        }
+        let amounts = |r: &UncheckedRelay<'_>| -> [f64; N_ROLES] {
+            let mut amounts = [0.0; N_ROLES];
+            for (i, (role, usable)) in roles.iter().enumerate() {
+                if usable(r) {
+                    amounts[i] = if any_weight[i] {
+                        self.weights.weight_rs_for_role(r.rs, *role) as f64
+                    } else {
+                        1.0
+                    };
+                }
+            }
+            amounts
+        };
+
+        let mut total = [0.0; N_ROLES];
+        let mut have = [0.0; N_ROLES];
+        let mut candidates = Vec::new();
+        for r in self.all_relays() {
+            let amounts = amounts(&r);
+            for i in 0..N_ROLES {
+                total[i] += amounts[i];
+            }
+            if r.is_usable() {
+                for i in 0..N_ROLES {
+                    have[i] += amounts[i];
+                }
+            } else if self.rsidx_by_missing.contains_key(r.rs.md_digest())
+                && r.rs.ed25519_id_is_usable()
+            {
+                candidates.push((*r.rs.md_digest(), amounts));
+            }
+        }
+        let frac = |have: &[f64; N_ROLES]| -> f64 {
+            have.iter()
+                .zip(total.iter())
+                .map(|(h, t)| if *t > 0.0 { h / t } else { 0.0 })
+                .product()
+        };
+
+        let min_frac_paths: f64 = self.params().min_circuit_path_threshold.as_fraction();
+        let mut current = frac(&have);
+        let mut chosen = Vec::new();
+        while chosen.len() < limit && current < min_frac_paths {
+            let with = |amounts: &[f64; N_ROLES]| {
+                let mut h = have;
+                for i in 0..N_ROLES {
+                    h[i] += amounts[i];
+                }
+                frac(&h)
+            };
+            let best = candidates
+                .iter()
+                .enumerate()
+                .map(|(idx, (_, amounts))| (idx, with(amounts)))
+                .max_by(|(_, a), (_, b)| a.total_cmp(b));
+            match best {
+                Some((idx, f)) if f > current => {
+                    let (digest, amounts) = candidates.swap_remove(idx);
+                    for i in 0..N_ROLES {
+                        have[i] += amounts[i];
+                    }
+                    current = f;
+                    chosen.push(digest);
+                }
+                _ => break,
+            }
+        }
+        chosen
+    }
     /// Return true if there is enough information in this NetDir to build
     /// multihop circuits.
     fn have_enough_paths(&self) -> bool {
@@ -1519,17 +2345,52 @@ impl NetDir {
             .cloned()
     }
 
-    /// Choose `n` relay at random.
+    /// Choose a relay at random, de-prioritizing overloaded relays.
     ///
-    /// Each relay is chosen with probability proportional to its weight
-    /// in the role `role`, and is only selected if the predicate `usable`
-    /// returns true for it.
-    ///
-    /// Relays are chosen without replacement: no relay will be
-    /// returned twice. Therefore, the resulting vector may be smaller
-    /// than `n` if we happen to have fewer than `n` appropriate relays.
+    /// This is like [`pick_relay`](NetDir::pick_relay), except that we adjust
+    /// the weight of each relay that has reported overload recently (as of
+    /// when this directory became valid) according to `weighting`.
     ///
-    /// This function returns an empty vector if (and only if) there
+    /// We only know about the overload reports that we have been given with
+    /// [`note_overload_from_routerdesc`](NetDir::note_overload_from_routerdesc);
+    /// without any, this behaves exactly like `pick_relay`.
+    #[cfg(feature = "overload")]
+    pub fn pick_relay_with_overload_weighting<'a, R, P>(
+        &'a self,
+        rng: &mut R,
+        role: WeightRole,
+        weighting: &OverloadWeighting,
+        usable: P,
+    ) -> Option<Relay<'a>>
+    where
+        R: rand::Rng,
+        P: FnMut(&Relay<'a>) -> bool,
+    {
+        let valid_after = self.lifetime().valid_after();
+        let relays: Vec<_> = self.relays().filter(usable).collect();
+        // NOTE: See discussion in pick_relay().
+        relays[..]
+            .choose_weighted(rng, |r| {
+                let overloaded = r
+                    .overload
+                    .is_some_and(|status| status.is_recent_at(valid_after));
+                weighting.adjust(self.weights.weight_rs_for_role(r.rs, role), overloaded)
+            })
+            .ok()
+            .cloned()
+    }
+
+    /// Choose `n` relay at random.
+    ///
+    /// Each relay is chosen with probability proportional to its weight
+    /// in the role `role`, and is only selected if the predicate `usable`
+    /// returns true for it.
+    ///
+    /// Relays are chosen without replacement: no relay will be
+    /// returned twice. Therefore, the resulting vector may be smaller
+    /// than `n` if we happen to have fewer than `n` appropriate relays.
+    ///
+    /// This function returns an empty vector if (and only if) there
     /// are no relays with nonzero weight where `usable` returned
     /// true.
     pub fn pick_n_relays<'a, R, P>(
@@ -1567,6 +2428,12 @@ impl NetDir {
     /// Note: because this function is used to assess the total
     /// properties of the consensus, the `usable` predicate takes a
     /// [`RouterStatus`] rather than a [`Relay`].
+    ///
+    /// Since [`UncheckedRelay`] implements `HasCountryCode` when the `geoip`
+    /// feature is enabled, `usable` may restrict the relays by country, as in
+    /// `total_weight(role, |r| r.country_code() == Some(cc))`.  If this
+    /// directory was built without a GeoIP database, such a predicate matches
+    /// no relays; see [`NetDir::has_country_codes`].
     pub fn total_weight<P>(&self, role: WeightRole, usable: P) -> RelayWeight
     where
         P: Fn(&UncheckedRelay<'_>) -> bool,
@@ -1584,6 +2451,46 @@ impl NetDir {
             .sum()
     }
 
+    /// Compute the total weight for `role` of the relays matching `usable`,
+    /// broken down by country.
+    ///
+    /// Relays whose country is unknown are counted under the `None` key.  If
+    /// this directory was built without a GeoIP database, all the matching
+    /// weight is therefore reported under `None`.
+    ///
+    /// Countries with no matching relays do not appear in the result.
+    #[cfg(feature = "geoip")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "geoip")))]
+    pub fn total_weight_by_country<P>(
+        &self,
+        role: WeightRole,
+        usable: P,
+    ) -> HashMap<Option<CountryCode>, RelayWeight>
+    where
+        P: Fn(&UncheckedRelay<'_>) -> bool,
+    {
+        let mut result = HashMap::new();
+        for unchecked in self.all_relays().filter(|r| usable(r)) {
+            let w = RelayWeight(self.weights.weight_rs_for_role(unchecked.rs, role));
+            *result
+                .entry(unchecked.country_code())
+                .or_insert(RelayWeight(0)) += w;
+        }
+        result
+    }
+
+    /// Return the distribution of selection weight for `role` among the
+    /// usable relays in this directory.
+    ///
+    /// The result can answer questions about percentiles, concentration, and
+    /// total weight, without recomputing the weight of every relay.
+    pub fn bandwidth_distribution(&self, role: WeightRole) -> WeightDistribution {
+        WeightDistribution::from_weights(
+            self.relays()
+                .map(|r| self.weights.weight_rs_for_role(r.rs, role)),
+        )
+    }
+
     /// Compute the weight with which a relay with ID `rsa_id` would be
     /// selected for a given `role`.
     ///
@@ -1630,6 +2537,17 @@ impl NetDir {
         self.hsdir_rings.current.time_period()
     }
 
+    /// Return the [`HsDirParams`] of the current hidden service directory
+    /// "time period".
+    ///
+    /// These are the parameters we use when acting as a hidden service client:
+    /// their time period is the one returned by
+    /// [`.hs_time_period`](NetDir::hs_time_period).
+    #[cfg(feature = "hs-common")]
+    pub fn hs_dir_params(&self) -> &HsDirParams {
+        self.hsdir_rings.current.params()
+    }
+
     /// Return the [`HsDirParams`] of all the relevant hidden service directory "time periods"
     ///
     /// This includes the current time period (as from
@@ -1644,6 +2562,64 @@ impl NetDir {
             .collect()
     }
 
+    /// Return the hidden service directory ring for `period`, if we have one.
+    #[cfg(feature = "hs-common")]
+    fn hsdir_ring_for(&self, period: TimePeriod) -> Option<&HsDirRing> {
+        self.hsdir_rings
+            .iter()
+            .find(|ring| ring.time_period() == period)
+    }
+
+    /// Return the entries of the hidden service directory ring for `period`,
+    /// in ring order.
+    ///
+    /// Each entry is a relay with the HSDir flag, along with its position on
+    /// the ring.
+    ///
+    /// Returns `None` if this `NetDir` has no ring for `period`.  A client
+    /// only has a ring for [`.hs_time_period`](NetDir::hs_time_period); an
+    /// onion service also has one for each of the time periods in
+    /// [`.hs_all_time_periods`](NetDir::hs_all_time_periods).
+    ///
+    /// This is meant for diagnostics: to pick directories to talk to, use
+    /// [`.hs_dirs_download`](NetDir::hs_dirs_download) or
+    /// [`.hs_dirs_upload`](NetDir::hs_dirs_upload) instead.
+    #[cfg(feature = "hs-common")]
+    pub fn hsdir_ring_entries(
+        &self,
+        period: TimePeriod,
+    ) -> Option<impl Iterator<Item = (HsDirIndex, Relay<'_>)> + '_> {
+        let ring = self.hsdir_ring_for(period)?;
+        Some(ring.entries().filter_map(|(hsdir_idx, rs_idx)| {
+            // This ought not to be None but let's not panic or bail if it is
+            Some((*hsdir_idx, self.relay_by_rs_idx(*rs_idx)?))
+        }))
+    }
+
+    /// Return the position on the hidden service directory ring for `period`
+    /// where we look for replica number `replica` of the descriptors for `hsid`.
+    ///
+    /// That replica is stored on the first relays in
+    /// [`.hsdir_ring_entries`](NetDir::hsdir_ring_entries) at or after this
+    /// position, wrapping around at the end of the ring.  Replicas are
+    /// numbered starting at 1, up to the `hsdir_n_replicas` network parameter.
+    ///
+    /// Returns `None` if this `NetDir` has no ring for `period`.
+    #[cfg(feature = "hs-common")]
+    pub fn hsdir_position(
+        &self,
+        hsid: &HsBlindId,
+        replica: u8,
+        period: TimePeriod,
+    ) -> Option<HsDirIndex> {
+        let ring = self.hsdir_ring_for(period)?;
+        Some(hsdir_ring::service_hsdir_index(
+            hsid,
+            replica,
+            ring.params(),
+        ))
+    }
+
     /// Return the relays in this network directory that will be used as hidden service directories
     ///
     /// These are suitable to retrieve a given onion service's descriptor at a given time period.
@@ -1674,7 +2650,7 @@ impl NetDir {
         // 7. Shuffle Dirs
         // 8. return Dirs.
 
-        let spread = self.spread(HsDirOp::Download);
+        let spread = self.hs_dir_spread(HsDirSpread::Fetch);
 
         // When downloading, only look at relays on current ring.
         let ring = &self.hsdir_rings.current;
@@ -1718,7 +2694,7 @@ impl NetDir {
         //         adding them to Dirs until we have added `spread` new elements
         //         that were not there before.
         // 3. return Dirs.
-        let spread = self.spread(HsDirOp::Upload);
+        let spread = self.hs_dir_spread(HsDirSpread::Store);
 
         // For each HsBlindId, determine which HsDirRing to use.
         let rings = self
@@ -1859,7 +2835,14 @@ impl MdReceiver for NetDir {
         Box::new(self.rsidx_by_missing.keys())
     }
     fn add_microdesc(&mut self, md: Microdesc) -> bool {
-        self.add_arc_microdesc(Arc::new(md))
+        if !self.rsidx_by_missing.contains_key(md.digest()) {
+            return false;
+        }
+        let md = match &self.md_pool {
+            Some(pool) => pool.intern(md),
+            None => Arc::new(md),
+        };
+        self.add_arc_microdesc(md)
     }
     fn n_missing(&self) -> usize {
         self.rsidx_by_missing.len()
@@ -1890,8 +2873,14 @@ impl<'a> UncheckedRelay<'a> {
             Some(Relay {
                 rs: self.rs,
                 md: self.md?,
+                coverage: self.coverage?,
                 #[cfg(feature = "geoip")]
                 cc: self.cc,
+                #[cfg(feature = "geoip")]
+                asn: self.asn,
+                #[cfg(feature = "overload")]
+                overload: self.overload,
+                measured_bw: self.measured_bw,
             })
         } else {
             None
@@ -1931,6 +2920,30 @@ impl<'a> Relay<'a> {
         self.rs.rsa_identity()
     }
 
+    /// Return this relay's report that it has recently been overloaded, if it
+    /// has made one.
+    ///
+    /// We only know about reports from router descriptors that our directory
+    /// has been given: see
+    /// [`NetDir::note_overload_from_routerdesc`].  The report may be stale;
+    /// use [`OverloadStatus::is_recent_at`] to check.
+    #[cfg(feature = "overload")]
+    pub fn overload_status(&self) -> Option<OverloadStatus> {
+        self.overload
+    }
+
+    /// Return the bandwidth that a bandwidth scanner measured for this relay,
+    /// in kilobytes per second, if we know it.
+    ///
+    /// This is distinct from the relay's [weight](tor_netdoc::doc::netstatus::RelayWeight)
+    /// in the consensus, which the directory authorities derive from such
+    /// measurements.  We only know about measurements from a bandwidth file
+    /// that our directory has been given: see
+    /// [`NetDir::set_measured_bandwidths`].
+    pub fn measured_bandwidth(&self) -> Option<u32> {
+        self.measured_bw
+    }
+
     /// Return a reference to this relay's "router status" entry in
     /// the consensus.
     ///
@@ -1982,6 +2995,13 @@ impl<'a> HasCountryCode for Relay<'a> {
         self.cc
     }
 }
+#[cfg(feature = "geoip")]
+#[cfg_attr(docsrs, doc(cfg(feature = "geoip")))]
+impl<'a> HasAsn for Relay<'a> {
+    fn asn(&self) -> Option<AsNumber> {
+        self.asn
+    }
+}
 impl<'a> tor_linkspec::HasRelayIdsLegacy for Relay<'a> {
     fn ed_identity(&self) -> &Ed25519Identity {
         self.id()
@@ -2008,6 +3028,12 @@ impl<'a> HasCountryCode for UncheckedRelay<'a> {
         self.cc
     }
 }
+#[cfg(feature = "geoip")]
+impl<'a> HasAsn for UncheckedRelay<'a> {
+    fn asn(&self) -> Option<AsNumber> {
+        self.asn
+    }
+}
 
 impl<'a> DirectChanMethodsHelper for Relay<'a> {}
 impl<'a> ChanTarget for Relay<'a> {}
@@ -2052,6 +3078,111 @@ mod test {
         HsBlindId::from(hsid)
     }
 
+    /// Return the GeoIP database that [`geoip_test_netdir`] uses: it puts
+    /// `fe80:dead:beef::1` in the US, and `fe80:feed:eeee::1` in Germany.
+    #[cfg(feature = "geoip")]
+    fn geoip_test_db() -> GeoipDb {
+        let src_v6 = r#"
+        fe80:dead:beef::,fe80:dead:ffff::,US
+        fe80:feed:eeee::,fe80:feed:ffff::,DE
+        "#;
+        GeoipDb::new_from_legacy_format("", src_v6).unwrap()
+    }
+
+    /// Return a test directory in which the relays at the positions in `us`
+    /// are in the US, and the ones at the positions in `de` are in Germany,
+    /// according to [`geoip_test_db`].
+    #[cfg(feature = "geoip")]
+    fn geoip_test_netdir(us: &[usize], de: &[usize]) -> NetDir {
+        construct_custom_netdir_with_geoip(geoip_test_customizer(us, de), &geoip_test_db())
+            .unwrap()
+            .unwrap_if_sufficient()
+            .unwrap()
+    }
+
+    /// As [`geoip_test_netdir`], but without a GeoIP database, so that no
+    /// relay has a country code.
+    #[cfg(feature = "geoip")]
+    fn geoip_test_netdir_without_db(us: &[usize], de: &[usize]) -> NetDir {
+        construct_custom_netdir(geoip_test_customizer(us, de))
+            .unwrap()
+            .unwrap_if_sufficient()
+            .unwrap()
+    }
+
+    /// Return a function to give the relays at the positions in `us` and
+    /// `de` the addresses that [`geoip_test_db`] puts in those countries.
+    #[cfg(feature = "geoip")]
+    fn geoip_test_customizer<'a>(
+        us: &'a [usize],
+        de: &'a [usize],
+    ) -> impl FnMut(
+        usize,
+        &mut NodeBuilders,
+        &mut tor_netdoc::doc::netstatus::ConsensusBuilder<MdConsensusRouterStatus>,
+    ) + 'a {
+        move |pos, n, _| {
+            if us.contains(&pos) {
+                n.rs.add_or_port("[fe80:dead:beef::1]:42".parse().unwrap());
+            } else if de.contains(&pos) {
+                n.rs.add_or_port("[fe80:feed:eeee::1]:42".parse().unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn critical_missing_mds() {
+        let (consensus, microdescs) = construct_network().unwrap();
+        let digests: Vec<MdDigest> = microdescs.iter().map(|md| *md.digest()).collect();
+        let mds_by_digest: HashMap<_, _> = microdescs
+            .iter()
+            .map(|md| (*md.digest(), md.clone()))
+            .collect();
+        let dir = PartialNetDir::new(consensus, None);
+
+        // Return a copy of `dir` with the microdescriptors in `digests`.
+        let with_mds = |digests: &[MdDigest]| {
+            let mut dir = dir.clone();
+            for d in digests {
+                assert!(dir.add_microdesc(mds_by_digest[d].clone()));
+            }
+            dir
+        };
+        // Return how many microdescriptors we need to add from `order`
+        // before we have enough paths.
+        let n_needed = |order: &[MdDigest]| {
+            (0..=order.len())
+                .find(|n| with_mds(&order[..*n]).have_enough_paths())
+                .unwrap()
+        };
+
+        let critical = dir.critical_missing_mds(usize::MAX);
+        assert!(!critical.is_empty());
+        assert_eq!(
+            critical.iter().collect::<HashSet<_>>().len(),
+            critical.len()
+        );
+        // The most useful relay is the heaviest guard-and-exit relay.
+        assert_eq!(critical[0], digests[39]);
+        // These are exactly enough.
+        assert_eq!(n_needed(&critical), critical.len());
+        // ...which is fewer than we'd need if we downloaded them in order.
+        assert!(critical.len() < n_needed(&digests));
+
+        // We respect the limit.
+        assert_eq!(dir.critical_missing_mds(3), critical[..3]);
+        assert!(dir.critical_missing_mds(0).is_empty());
+
+        // We take the microdescriptors we have into account.
+        let partial = with_mds(&critical[..2]);
+        assert_eq!(partial.critical_missing_mds(usize::MAX), critical[2..]);
+
+        // Once we have enough, nothing is critical.
+        let enough = with_mds(&critical);
+        assert!(enough.have_enough_paths());
+        assert!(enough.critical_missing_mds(usize::MAX).is_empty());
+    }
+
     // Basic functionality for a partial netdir: Add microdescriptors,
     // then you have a netdir.
     #[test]
@@ -2099,6 +3230,225 @@ mod test {
         };
     }
 
+    /// Construct the usual test network, plus `n_extra` relays with very low
+    /// weight, for which there are no microdescriptors.
+    fn construct_oversized_network(n_extra: u32) -> (MdConsensus, Vec<Microdesc>) {
+        use tor_netdoc::doc::netstatus::{RelayFlags, RelayWeight};
+        testnet::construct_custom_network(
+            |idx, _node, bld| {
+                if idx != 0 {
+                    return;
+                }
+                for n in 0..n_extra {
+                    let mut id = [0xff; 20];
+                    id[..4].copy_from_slice(&n.to_be_bytes());
+                    let mut digest = [0xff; 32];
+                    digest[..4].copy_from_slice(&n.to_be_bytes());
+                    bld.rs()
+                        .identity(id.into())
+                        .add_or_port("127.0.0.1:9001".parse().unwrap())
+                        .protos("".parse().unwrap())
+                        .set_flags(RelayFlags::RUNNING | RelayFlags::VALID)
+                        .weight(RelayWeight::Measured(1))
+                        .doc_digest(digest)
+                        .build_into(bld)
+                        .unwrap();
+                }
+            },
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn limit_relays() {
+        let (consensus, microdescs) = construct_oversized_network(2000);
+        assert_eq!(consensus.c_relays().len(), 2040);
+
+        // By default, there are no limits.
+        let dir = PartialNetDir::new(consensus.clone(), None)
+            .with_limits(&NetDirLimits::default())
+            .unwrap();
+        assert_eq!(dir.n_missing(), 2040);
+
+        // We can reject an oversized consensus outright...
+        let mut limits = NetDirLimits {
+            max_relays: Some(100),
+            ..Default::default()
+        };
+        let err = PartialNetDir::new(consensus.clone(), None)
+            .with_limits(&limits)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::ConsensusTooLarge {
+                n_relays: 2040,
+                max_relays: 100
+            }
+        ));
+
+        // ... or just use the heaviest relays.
+        limits.max_relays = Some(40);
+        limits.policy = OversizePolicy::KeepHighestWeight;
+        let mut dir = PartialNetDir::new(consensus.clone(), None)
+            .with_limits(&limits)
+            .unwrap();
+        assert_eq!(dir.n_missing(), 40);
+        for md in microdescs.iter() {
+            assert!(dir.add_microdesc(md.clone()));
+        }
+        let dir = dir.unwrap_if_sufficient().unwrap();
+        assert_eq!(dir.relays().count(), 40);
+        assert!(!dir.md_limit_reached());
+
+        // If we keep fewer, we lose the lightest of the real relays.
+        limits.max_relays = Some(20);
+        let mut dir = PartialNetDir::new(consensus, None)
+            .with_limits(&limits)
+            .unwrap();
+        assert_eq!(dir.n_missing(), 20);
+        for md in microdescs {
+            dir.add_microdesc(md);
+        }
+        let dir = dir.netdir;
+        assert_eq!(dir.relays().count(), 20);
+        // Relays with idx % 10 >= 5 are the heaviest ones.
+        assert!(dir.relays().all(|r| r.rsa_id().as_bytes()[0] % 10 >= 5));
+    }
+
+    #[test]
+    fn limit_mds() {
+        let (consensus, microdescs) = construct_network().unwrap();
+
+        // Limit the number of microdescriptors.
+        let limits = NetDirLimits {
+            max_microdescs: Some(25),
+            ..Default::default()
+        };
+        let mut dir = PartialNetDir::new(consensus.clone(), None)
+            .with_limits(&limits)
+            .unwrap();
+        let n_wanted = microdescs
+            .iter()
+            .filter(|md| dir.add_microdesc((*md).clone()))
+            .count();
+        assert_eq!(n_wanted, 25);
+        // We don't keep asking for the ones we refused.
+        assert_eq!(dir.n_missing(), 0);
+        assert!(dir.netdir.md_limit_reached());
+        assert_eq!(dir.netdir.mds.iter().flatten().count(), 25);
+
+        // Limit the number of bytes.
+        let limits = NetDirLimits {
+            max_md_bytes: Some(limits::approx_md_size(&microdescs[0]) * 3),
+            ..Default::default()
+        };
+        let mut dir = PartialNetDir::new(consensus.clone(), None)
+            .with_limits(&limits)
+            .unwrap();
+        for md in microdescs.iter() {
+            dir.add_microdesc(md.clone());
+        }
+        let n_kept = dir.netdir.mds.iter().flatten().count();
+        assert!((1..=3).contains(&n_kept));
+        assert!(dir.netdir.md_limit_reached());
+
+        // Limits apply to microdescriptors that are already present.
+        let mut full = PartialNetDir::new(consensus.clone(), None);
+        for md in microdescs {
+            full.add_microdesc(md);
+        }
+        let full = full.unwrap_if_sufficient().unwrap();
+        let limits = NetDirLimits {
+            max_microdescs: Some(10),
+            ..Default::default()
+        };
+        let mut dir = PartialNetDir::new(consensus, None);
+        dir.fill_from_previous_netdir(Arc::new(full));
+        let dir = dir.with_limits(&limits).unwrap();
+        assert_eq!(dir.netdir.mds.iter().flatten().count(), 10);
+        assert_eq!(dir.netdir.rsidx_by_ed.len(), 10);
+        assert_eq!(dir.n_missing(), 0);
+    }
+
+    #[test]
+    fn lookup_by_addr() {
+        let (consensus, microdescs) = construct_network().unwrap();
+        let mut dir = PartialNetDir::new(consensus, None);
+        // Leave out some microdescriptors, so that some relays are unusable.
+        for md in microdescs.into_iter().step_by(2) {
+            dir.add_microdesc(md);
+        }
+        let dir = dir.netdir;
+
+        for n in 0..5 {
+            let ip: IpAddr = [n, 0, 0, 3].into();
+            let expected: Vec<_> = dir
+                .relays()
+                .filter(|r| r.addrs().iter().any(|a| a.ip() == ip))
+                .map(|r| *r.rsa_id())
+                .collect();
+            assert!(!expected.is_empty());
+            let found: Vec<_> = dir.relays_with_ip(&ip).map(|r| *r.rsa_id()).collect();
+            assert_eq!(found, expected);
+
+            let addr = SocketAddr::new(ip, 9001);
+            assert_eq!(dir.by_addr(&addr).unwrap().rsa_id(), &expected[0]);
+            assert!(dir.by_addr(&SocketAddr::new(ip, 9002)).is_none());
+        }
+
+        let unknown: IpAddr = [192, 0, 2, 1].into();
+        assert_eq!(dir.relays_with_ip(&unknown).count(), 0);
+        assert!(dir.by_addr(&SocketAddr::new(unknown, 9001)).is_none());
+    }
+
+    #[test]
+    fn usable_relay_stats() {
+        use tor_netdoc::doc::netstatus::RelayFlags;
+        let (consensus, microdescs) = construct_network().unwrap();
+        let mut dir = PartialNetDir::new(consensus.clone(), None);
+        let empty = dir.usable_relay_stats();
+        assert_eq!(empty.n_usable(), 0);
+
+        let queries = [
+            RelayFlagQuery::new(),
+            RelayFlagQuery::new().require(RelayFlags::EXIT),
+            RelayFlagQuery::new()
+                .require(RelayFlags::GUARD)
+                .forbid(RelayFlags::EXIT),
+        ];
+        let check = |dir: &PartialNetDir| {
+            let stats = dir.usable_relay_stats();
+            assert_eq!(stats.n_usable(), dir.netdir.relays().count());
+            for q in &queries {
+                assert_eq!(
+                    stats.n_with_flags(q),
+                    dir.netdir.relays_with_flags(q).count()
+                );
+            }
+        };
+
+        for (n, md) in microdescs.iter().enumerate() {
+            dir.add_microdesc(md.clone());
+            assert_eq!(dir.usable_relay_stats().n_usable(), n + 1);
+            check(&dir);
+        }
+        // Adding the same microdescriptor again changes nothing.
+        dir.add_microdesc(microdescs[0].clone());
+        assert_eq!(dir.usable_relay_stats().n_usable(), 40);
+        // Snapshots don't change.
+        assert_eq!(empty.n_usable(), 0);
+
+        // Relays that we forget are no longer counted.
+        let limits = NetDirLimits {
+            max_microdescs: Some(10),
+            ..Default::default()
+        };
+        let dir = dir.with_limits(&limits).unwrap();
+        assert_eq!(dir.usable_relay_stats().n_usable(), 10);
+        check(&dir);
+    }
+
     #[test]
     fn override_params() {
         let (consensus, _microdescs) = construct_network().unwrap();
@@ -2135,6 +3485,42 @@ mod test {
         assert_eq!(dir.missing_microdescs().count(), 2);
     }
 
+    #[test]
+    fn shared_md_pool() {
+        let (consensus, microdescs) = construct_network().unwrap();
+        let pool = MicrodescPool::new();
+
+        let mut dir = PartialNetDir::new(consensus.clone(), None).with_md_pool(pool.clone());
+        for md in &microdescs {
+            assert!(dir.add_microdesc(md.clone()));
+        }
+        let dir1 = Arc::new(dir.unwrap_if_sufficient().unwrap());
+        assert_eq!(pool.len(), 40);
+
+        // A directory that uses the same pool shares dir1's microdescriptors,
+        // even though it gets its own copies of them.
+        let mut dir = PartialNetDir::new(consensus.clone(), None).with_md_pool(pool.clone());
+        for md in &microdescs {
+            assert!(dir.add_microdesc(md.clone()));
+        }
+        let dir2 = dir.unwrap_if_sufficient().unwrap();
+        let rsidx = RouterStatusIdx::from(0);
+        assert!(Arc::ptr_eq(
+            dir1.mds[rsidx].as_ref().unwrap(),
+            dir2.mds[rsidx].as_ref().unwrap()
+        ));
+        assert_eq!(pool.len(), 40);
+
+        // A directory filled from dir1 adopts its pool.
+        let mut dir = PartialNetDir::new(consensus, None);
+        dir.fill_from_previous_netdir(Arc::clone(&dir1));
+        let dir3 = dir.unwrap_if_sufficient().unwrap();
+        assert!(dir3.md_pool().is_some());
+
+        drop((dir1, dir2, dir3));
+        assert!(pool.is_empty());
+    }
+
     #[test]
     fn path_count() {
         let low_threshold = "min_paths_for_circs_pct=64".parse().unwrap();
@@ -2245,6 +3631,96 @@ mod test {
         assert_float_eq!(picked_f[39], (10.0 / 110.0), abs <= tolerance);
     }
 
+    #[test]
+    #[cfg(feature = "overload")]
+    fn test_pick_with_overload() {
+        let mut dir = construct_netdir().unwrap_if_sufficient().unwrap();
+        let valid_after = dir.lifetime().valid_after();
+        let idx19 = dir.rsidx_by_rsa.get(&RsaIdentity::from([19; 20])).unwrap();
+        let idx38 = dir.rsidx_by_rsa.get(&RsaIdentity::from([38; 20])).unwrap();
+        dir.overload.insert(idx19, OverloadStatus::new(valid_after));
+        // This report is too old to count.
+        dir.overload.insert(
+            idx38,
+            OverloadStatus::new(valid_after - Duration::from_secs(86400 * 4)),
+        );
+        assert!(dir
+            .by_id(&RsaIdentity::from([19; 20]))
+            .unwrap()
+            .overload_status()
+            .unwrap()
+            .is_recent_at(valid_after));
+        assert!(dir
+            .by_id(&RsaIdentity::from([39; 20]))
+            .unwrap()
+            .overload_status()
+            .is_none());
+
+        let (mut rng, total, tolerance) = testing_rng_with_tolerances();
+        let weighting = OverloadWeighting::new(0.5);
+
+        let mut picked = [0_isize; 40];
+        for _ in 0..total {
+            let r = dir.pick_relay_with_overload_weighting(
+                &mut rng,
+                WeightRole::Middle,
+                &weighting,
+                |r| r.low_level_details().supports_exit_port_ipv4(80),
+            );
+            let r = r.unwrap();
+            let id_byte = r.identity(RelayIdType::Rsa).unwrap().as_bytes()[0];
+            picked[id_byte as usize] += 1;
+        }
+        picked[0..10].iter().for_each(|x| assert_eq!(*x, 0));
+        picked[20..30].iter().for_each(|x| assert_eq!(*x, 0));
+
+        let picked_f: Vec<_> = picked.iter().map(|x| *x as f64 / total as f64).collect();
+
+        // Relay 19 has half its usual weight; the others are unchanged.
+        assert_float_eq!(picked_f[19], (5.0 / 105.0), abs <= tolerance);
+        assert_float_eq!(picked_f[38], (9.0 / 105.0), abs <= tolerance);
+        assert_float_eq!(picked_f[39], (10.0 / 105.0), abs <= tolerance);
+    }
+
+    #[test]
+    fn measured_bandwidths() {
+        let bwfile = BandwidthFile::parse(
+            "1523911758
+version=1.4.0
+=====
+bw=760 node_id=$0101010101010101010101010101010101010101 master_key_ed25519=AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE
+bw=38 node_id=$0202020202020202020202020202020202020202 master_key_ed25519=CQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQk
+bw=12 node_id=$0303030303030303030303030303030303030303
+bw=1 node_id=$0404040404040404040404040404040404040404 unmeasured=1
+bw=99 node_id=$AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA
+",
+        )
+        .unwrap();
+        let measured = |dir: &NetDir, idx: u8| {
+            dir.by_id(&RsaIdentity::from([idx; 20]))
+                .unwrap()
+                .measured_bandwidth()
+        };
+
+        let mut dir = construct_netdir().unwrap_if_sufficient().unwrap();
+        assert_eq!(measured(&dir, 1), None);
+
+        // Relay 2's Ed25519 identity doesn't match, relay 4 wasn't actually
+        // measured, and relay 0xAA isn't listed.
+        assert_eq!(dir.set_measured_bandwidths(&bwfile), 2);
+        assert_eq!(measured(&dir, 1), Some(760));
+        assert_eq!(measured(&dir, 2), None);
+        assert_eq!(measured(&dir, 3), Some(12));
+        assert_eq!(measured(&dir, 4), None);
+
+        // A new directory keeps the measurements from the one it replaces.
+        let mut partial = construct_netdir();
+        partial.fill_from_previous_netdir(Arc::new(dir));
+        let dir = partial.unwrap_if_sufficient().unwrap();
+        assert_eq!(measured(&dir, 1), Some(760));
+        assert_eq!(measured(&dir, 3), Some(12));
+    }
+
     #[test]
     fn test_pick_multiple() {
         // This is mostly a copy of test_pick, except that it uses
@@ -2446,6 +3922,139 @@ mod test {
         assert!(!r15.low_level_details().in_same_subnet(&r20, &subnet_config));
     }
 
+    #[test]
+    fn relays_compatible_with() {
+        let netdir = construct_netdir().unwrap_if_sufficient().unwrap();
+        let subnet_config = SubnetConfig::default();
+        let relay = |id: u8| netdir.by_id(&Ed25519Identity::from([id; 32])).unwrap();
+        let ids = |chosen: &[Relay<'_>]| -> Vec<u8> {
+            netdir
+                .relays_compatible_with(chosen, &subnet_config)
+                .map(|r| r.id().as_bytes()[0])
+                .collect()
+        };
+
+        // Nothing chosen: every relay is compatible.
+        assert_eq!(ids(&[]).len(), 40);
+
+        // In the test network, relay N is in a family with relay N^1, and in
+        // the same /16 as relay N+5.
+        let compatible = ids(&[relay(0)]);
+        assert_eq!(compatible.len(), 31);
+        assert!(!compatible.contains(&0));
+        assert!(!compatible.contains(&1));
+        assert!(!compatible.contains(&35));
+        assert!(compatible.contains(&4));
+
+        let compatible = ids(&[relay(0), relay(2)]);
+        assert_eq!(compatible.len(), 22);
+        assert!(!compatible.contains(&3));
+        assert!(compatible.iter().all(|id| id % 5 != 0 && id % 5 != 2));
+
+        // Adding relays one at a time gives the same answer.
+        let mut exclusion = PathExclusion::new(&[relay(0)], &subnet_config);
+        exclusion.push(relay(2));
+        let pushed: Vec<u8> = netdir
+            .relays_permitted_by(exclusion)
+            .map(|r| r.id().as_bytes()[0])
+            .collect();
+        assert_eq!(pushed, compatible);
+
+        // With subnets disabled, only families matter.
+        let compatible: Vec<_> = netdir
+            .relays_compatible_with(&[relay(0)], &SubnetConfig::no_addresses_match())
+            .collect();
+        assert_eq!(compatible.len(), 38);
+    }
+
+    #[test]
+    fn pick_path() {
+        use tor_netdoc::doc::netstatus::RelayFlags;
+
+        let netdir = construct_netdir().unwrap_if_sufficient().unwrap();
+        let mut rng = test_rng::testing_rng();
+        let subnet_config = SubnetConfig::default();
+        // Check that no two relays in `path` are in the same family or subnet.
+        let assert_compatible = |path: &[Relay<'_>]| {
+            for i in 1..path.len() {
+                let exclusion = PathExclusion::new(&path[..i], &subnet_config);
+                assert!(exclusion.permits(&path[i]));
+            }
+        };
+
+        let policy = PathPolicy::new();
+        assert!(netdir.pick_path(&mut rng, &policy, 0).is_none());
+        assert!(netdir.pick_path(&mut rng, &policy, 1).is_none());
+
+        for n_hops in 2..=4 {
+            for _ in 0..20 {
+                let path = netdir.pick_path(&mut rng, &policy, n_hops).unwrap();
+                assert_eq!(path.len(), n_hops);
+                assert!(path[0].usable_for(&RelayRole::Guard));
+                assert!(path[n_hops - 1].usable_for(&RelayRole::Exit(&[])));
+                assert_compatible(&path);
+            }
+        }
+
+        // In the test network, only the even-numbered exits allow port 22.
+        let ssh = [ExitPort::ipv4(22)];
+        let policy = PathPolicy::new().exit_ports(&ssh);
+        for _ in 0..20 {
+            let path = netdir.pick_path(&mut rng, &policy, 3).unwrap();
+            let exit_id = path[2].id().as_bytes()[0];
+            assert_eq!(exit_id % 2, 0);
+            assert!(path[2].usable_for(&RelayRole::Exit(&ssh)));
+            assert_compatible(&path);
+        }
+
+        // Internal paths can end at relays that aren't exits.
+        let policy = PathPolicy::new().internal();
+        let mut saw_non_exit = false;
+        for _ in 0..50 {
+            let path = netdir.pick_path(&mut rng, &policy, 3).unwrap();
+            assert!(path[0].usable_for(&RelayRole::Guard));
+            saw_non_exit |= !path[2].low_level_details().policies_allow_some_port();
+            assert_compatible(&path);
+        }
+        assert!(saw_non_exit);
+
+        // Every relay in the test network is Stable, but none of the guards
+        // are HSDirs.
+        let policy = PathPolicy::new().require_flags(RelayFlags::STABLE);
+        assert!(netdir.pick_path(&mut rng, &policy, 3).is_some());
+        let policy = PathPolicy::new().require_flags(RelayFlags::HSDIR);
+        assert!(netdir.pick_path(&mut rng, &policy, 3).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "geoip")]
+    fn relays_compatible_by_country() {
+        let netdir = geoip_test_netdir(&[11, 12], &[13]);
+        let relay = |id: u8| netdir.by_id(&Ed25519Identity::from([id; 32])).unwrap();
+        let subnet_config = SubnetConfig::no_addresses_match();
+
+        let permitted = |exclusion| -> Vec<u8> {
+            netdir
+                .relays_permitted_by(exclusion)
+                .map(|r| r.id().as_bytes()[0])
+                .collect()
+        };
+
+        // Without country exclusion, only relay 11's family is excluded.
+        let plain = permitted(PathExclusion::new(&[relay(11)], &subnet_config));
+        assert_eq!(plain.len(), 38);
+        assert!(plain.contains(&12));
+
+        // With it, relay 12 is excluded too, since it is also in the US.
+        // Relays with no known country are still permitted.
+        let by_country =
+            permitted(PathExclusion::new(&[relay(11)], &subnet_config).exclude_same_country());
+        assert_eq!(by_country.len(), 37);
+        assert!(!by_country.contains(&12));
+        assert!(by_country.contains(&13));
+        assert!(by_country.contains(&0));
+    }
+
     #[test]
     fn test_badexit() {
         // make a netdir where relays 10-19 are badexit, and everybody
@@ -2487,6 +4096,149 @@ mod test {
             .low_level_details()
             .ipv6_declared_policy()
             .allows_some_port());
+
+        let (d12, d32) = (e12.low_level_details(), e32.low_level_details());
+        assert!(!d12.ipv4_port_coverage().allows_some_port());
+        assert!(!d12.ipv6_port_coverage().allows_some_port());
+        assert_eq!(
+            d32.ipv4_port_coverage(),
+            &PortCoverage::from_policy(&d32.ipv4_policy())
+        );
+        assert_eq!(d32.ipv6_port_coverage().n_allowed_ports(), 1);
+
+        // Relays with the same policies share the same coverage.
+        let e33 = netdir.by_id(&Ed25519Identity::from([33; 32])).unwrap();
+        assert!(std::ptr::eq(
+            d32.ipv6_port_coverage(),
+            e33.low_level_details().ipv6_port_coverage()
+        ));
+
+        // We can find the ports that a whole path allows.
+        let path = PortCoverage::intersection([
+            d32.ipv6_port_coverage(),
+            e33.low_level_details().ipv6_port_coverage(),
+            d12.ipv6_port_coverage(),
+        ]);
+        assert!(!path.allows_some_port());
+    }
+
+    #[test]
+    fn exits_allowing() {
+        // Relays 10-19 are bad exits; relays 30-39 exit to 443 on IPv6.
+        use tor_netdoc::doc::netstatus::RelayFlags;
+        let netdir = construct_custom_netdir(|pos, nb, _| {
+            if (10..20).contains(&pos) {
+                nb.rs.add_flags(RelayFlags::BAD_EXIT);
+            }
+            if (30..40).contains(&pos) {
+                nb.md.parse_ipv6_policy("accept 443").unwrap();
+            }
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+
+        let ids = |relays: Vec<Relay<'_>>| -> Vec<Ed25519Identity> {
+            relays.iter().map(|r| *r.id()).collect()
+        };
+        for port in [0, 22, 80, 443, 555, 1000] {
+            for (pref, ipv4, ipv6) in [
+                (IpVersionPreference::Ipv4Only, true, false),
+                (IpVersionPreference::Ipv6Only, false, true),
+                (IpVersionPreference::Ipv4Preferred, true, true),
+                (IpVersionPreference::Ipv6Preferred, true, true),
+            ] {
+                let expected = netdir
+                    .relays()
+                    .filter(|r| {
+                        let d = r.low_level_details();
+                        (ipv4 && d.supports_exit_port_ipv4(port))
+                            || (ipv6 && d.supports_exit_port_ipv6(port))
+                    })
+                    .collect();
+                let found = netdir.exits_allowing(port, pref).collect();
+                assert_eq!(ids(found), ids(expected), "{port} {pref:?}");
+            }
+        }
+
+        let v6_https = ids(netdir
+            .exits_allowing(443, IpVersionPreference::Ipv6Only)
+            .collect());
+        assert_eq!(v6_https.len(), 10);
+        assert!(v6_https.contains(&Ed25519Identity::from([33; 32])));
+        assert_eq!(netdir.exits_allowing(0, Default::default()).count(), 0);
+    }
+
+    #[test]
+    fn relay_roles() {
+        // Relays 10-19 are bad exits; relays 30-39 exit to 443 on IPv6.
+        use tor_netdoc::doc::netstatus::RelayFlags;
+        let netdir = construct_custom_netdir(|pos, nb, _| {
+            if (10..20).contains(&pos) {
+                nb.rs.add_flags(RelayFlags::BAD_EXIT);
+            }
+            if (30..40).contains(&pos) {
+                nb.md.parse_ipv6_policy("accept 443").unwrap();
+            }
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+        let relay = |idx: u8| netdir.by_id(&Ed25519Identity::from([idx; 32])).unwrap();
+        let (r0, r1, r12, r20, r21, r31, r32) = (
+            relay(0),
+            relay(1),
+            relay(12),
+            relay(20),
+            relay(21),
+            relay(31),
+            relay(32),
+        );
+        let https = [ExitPort::ipv4(443)];
+        let ssh = [ExitPort::ipv4(22)];
+        let https6 = [ExitPort::ipv4(443), ExitPort::ipv6(443)];
+
+        // Everybody in the test network is Fast and Stable.
+        for r in [&r0, &r1, &r12, &r20, &r31] {
+            assert!(r.usable_for(&RelayRole::Middle));
+            assert!(r.usable_for(&RelayRole::IntroPoint));
+            assert!(r.usable_for(&RelayRole::RendPoint));
+        }
+
+        // Only even-numbered relays are directory caches.
+        assert!(r0.usable_for(&RelayRole::DirCache));
+        assert!(!r1.usable_for(&RelayRole::DirCache));
+
+        // Guards need the flag and must be directory caches.
+        assert!(!r0.usable_for(&RelayRole::Guard));
+        assert!(r20.usable_for(&RelayRole::Guard));
+        assert!(!r21.usable_for(&RelayRole::Guard));
+        assert!(r32.usable_for(&RelayRole::Guard));
+
+        assert!(r0.usable_for(&RelayRole::HsDir));
+        assert!(!r20.usable_for(&RelayRole::HsDir));
+
+        // Exits must have a policy that allows the ports, and not be BadExit.
+        assert!(!r0.usable_for(&RelayRole::Exit(&[])));
+        assert!(!r12.usable_for(&RelayRole::Exit(&[])));
+        assert!(!r12.usable_for(&RelayRole::Exit(&https)));
+        assert!(r31.usable_for(&RelayRole::Exit(&[])));
+        assert!(r31.usable_for(&RelayRole::Exit(&https)));
+        assert!(!r31.usable_for(&RelayRole::Exit(&ssh)));
+        assert!(r31.usable_for(&RelayRole::Exit(&https6)));
+        assert!(r32.usable_for(&RelayRole::Exit(&ssh)));
+        assert!(!r32.usable_for(&RelayRole::Exit(&[ExitPort::ipv6(22)])));
+
+        // The low-level helpers agree.
+        for r in [&r0, &r1, &r12, &r20, &r31] {
+            let d = r.low_level_details();
+            assert_eq!(d.is_dir_cache(), r.usable_for(&RelayRole::DirCache));
+            assert_eq!(d.is_hs_intro_point(), r.usable_for(&RelayRole::IntroPoint));
+            assert_eq!(
+                d.is_suitable_as_guard() && d.is_dir_cache(),
+                r.usable_for(&RelayRole::Guard)
+            );
+        }
     }
 
     #[cfg(feature = "experimental-api")]
@@ -2673,6 +4425,26 @@ mod test {
             .is_none());
     }
 
+    #[test]
+    fn bandwidth_distribution() {
+        let netdir = construct_netdir().unwrap_if_sufficient().unwrap();
+
+        let dist = netdir.bandwidth_distribution(WeightRole::Guard);
+        assert_eq!(dist.n_relays(), netdir.relays().count());
+        let total = netdir
+            .relays()
+            .map(|r| netdir.relay_weight(&r, WeightRole::Guard))
+            .sum();
+        assert_eq!(dist.total(), total);
+        let max = netdir
+            .relays()
+            .map(|r| netdir.relay_weight(&r, WeightRole::Guard))
+            .max();
+        assert_eq!(dist.max(), max);
+        assert_eq!(dist.percentile(100.0), max);
+        assert!(dist.gini().unwrap() > 0.0);
+    }
+
     #[test]
     fn family_list() {
         let netdir = construct_custom_netdir(|pos, n, _| {
@@ -2754,6 +4526,559 @@ mod test {
         assert_eq!(r3.cc.as_ref().map(|x| x.as_ref()), Some("US"));
     }
 
+    #[test]
+    #[cfg(feature = "geoip")]
+    fn usable_relay_stats_by_country() {
+        let src_v6 = r#"
+        fe80:dead:beef::,fe80:dead:ffff::,US
+        fe80:feed:eeee::,fe80:feed:ffff::,DE
+        "#;
+        let db = GeoipDb::new_from_legacy_format("", src_v6).unwrap();
+
+        let netdir = construct_custom_netdir_with_geoip(
+            |pos, n, _| {
+                if pos < 3 {
+                    n.rs.add_or_port("[fe80:dead:beef::1]:42".parse().unwrap());
+                } else if pos < 5 {
+                    n.rs.add_or_port("[fe80:feed:eeee::1]:42".parse().unwrap());
+                }
+            },
+            &db,
+        )
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+
+        let stats = netdir.usable_relay_stats();
+        let us = "US".parse::<CountryCode>().unwrap();
+        let de = "DE".parse::<CountryCode>().unwrap();
+        assert_eq!(stats.n_in_country(us), 3);
+        assert_eq!(stats.n_in_country(de), 2);
+        assert_eq!(stats.n_in_country("FR".parse::<CountryCode>().unwrap()), 0);
+        assert_eq!(stats.n_without_country(), 35);
+        let mut countries: Vec<_> = stats.countries().collect();
+        countries.sort();
+        assert_eq!(countries, vec![(de, 2), (us, 3)]);
+    }
+
+    #[test]
+    #[cfg(feature = "geoip")]
+    fn relay_has_asn() {
+        let src_v6 = r#"
+        fe80:dead:beef::,fe80:dead:ffff::,US,64500
+        fe80:feed:eeee::1,fe80:feed:eeee::2,DE,64501
+        fe80:feed:eeee::2,fe80:feed:ffff::,DE,64502
+        "#;
+        let db = GeoipDb::new_from_legacy_format("", src_v6).unwrap();
+
+        let netdir = construct_custom_netdir_with_geoip(
+            |pos, n, _| {
+                if pos == 0x01 {
+                    n.rs.add_or_port("[fe80:dead:beef::1]:42".parse().unwrap());
+                }
+                if pos == 0x02 {
+                    n.rs.add_or_port("[fe80:feed:eeee::1]:42".parse().unwrap());
+                    n.rs.add_or_port("[fe80:feed:eeee::2]:42".parse().unwrap());
+                }
+            },
+            &db,
+        )
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+
+        let asn = |id: u8| {
+            netdir
+                .by_id(&Ed25519Identity::from([id; 32]))
+                .unwrap()
+                .asn()
+                .map(|a| a.get())
+        };
+        // No GeoIP data available -> None
+        assert_eq!(asn(0), None);
+        // Exactly one match -> Some
+        assert_eq!(asn(1), Some(64500));
+        // Conflicting matches -> None, even though the countries agree.
+        assert_eq!(asn(2), None);
+        let r2 = netdir.by_id(&Ed25519Identity::from([2; 32])).unwrap();
+        assert_eq!(
+            r2.country_code().map(|cc| cc.to_string()),
+            Some("DE".into())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "geoip")]
+    fn netdir_from_geoip_manager() {
+        use tor_geoip::GeoipDbManager;
+
+        let db_us =
+            GeoipDb::new_from_legacy_format("", "fe80:dead:beef::,fe80:dead:ffff::,US").unwrap();
+        let db_de =
+            GeoipDb::new_from_legacy_format("", "fe80:dead:beef::,fe80:dead:ffff::,DE").unwrap();
+        let mgr = GeoipDbManager::new(Arc::new(db_us));
+
+        let build = || {
+            let (consensus, microdescs) = testnet::construct_custom_network(
+                |pos, n, _| {
+                    if pos == 0x01 {
+                        n.rs.add_or_port("[fe80:dead:beef::1]:42".parse().unwrap());
+                    }
+                },
+                None,
+            )
+            .unwrap();
+            let mut dir = PartialNetDir::new_with_geoip_manager(consensus, None, &mgr);
+            for md in microdescs {
+                dir.add_microdesc(md);
+            }
+            dir.unwrap_if_sufficient().unwrap()
+        };
+        let cc = |netdir: &NetDir| {
+            netdir
+                .by_id(&Ed25519Identity::from([1; 32]))
+                .unwrap()
+                .country_code()
+                .map(|cc| cc.to_string())
+        };
+
+        let old_dir = build();
+        assert_eq!(cc(&old_dir), Some("US".into()));
+
+        // Only directories built after the replacement see the new data.
+        mgr.replace(Arc::new(db_de));
+        let new_dir = build();
+        assert_eq!(cc(&new_dir), Some("DE".into()));
+        assert_eq!(cc(&old_dir), Some("US".into()));
+    }
+
+    #[test]
+    #[cfg(feature = "geoip")]
+    fn netdir_replace_geoip_db() {
+        let db_de =
+            GeoipDb::new_from_legacy_format("", "fe80:dead:beef::,fe80:dead:ffff::,DE").unwrap();
+        let (consensus, microdescs) = testnet::construct_custom_network(
+            |pos, n, _| {
+                if pos == 0x01 {
+                    n.rs.add_or_port("[fe80:dead:beef::1]:42".parse().unwrap());
+                }
+            },
+            None,
+        )
+        .unwrap();
+        let mut dir = PartialNetDir::new(consensus, None);
+        for md in microdescs {
+            dir.add_microdesc(md);
+        }
+        let mut netdir = dir.unwrap_if_sufficient().unwrap();
+        let id = Ed25519Identity::from([1; 32]);
+        assert!(!netdir.has_country_codes());
+        assert_eq!(netdir.usable_relay_stats().countries().count(), 0);
+        assert_eq!(netdir.by_id(&id).unwrap().country_code(), None);
+
+        assert_eq!(netdir.replace_geoip_db(&db_de), 1);
+        assert!(netdir.has_country_codes());
+        assert_eq!(
+            netdir
+                .by_id(&id)
+                .unwrap()
+                .country_code()
+                .map(|cc| cc.to_string()),
+            Some("DE".into())
+        );
+        // Nothing changes if we install the same database again.
+        assert_eq!(netdir.replace_geoip_db(&db_de), 0);
+
+        // Our statistics follow the new database...
+        let de: CountryCode = "DE".parse().unwrap();
+        let stats = netdir.usable_relay_stats();
+        assert_eq!(stats.n_in_country(de), 1);
+        assert_eq!(stats.n_without_country(), stats.n_usable() - 1);
+        // ...so that they stay right when a relay stops being usable.
+        let rsidx = netdir.rsidx_by_ed[&id];
+        netdir.forget_relay(rsidx);
+        let stats = netdir.usable_relay_stats();
+        assert_eq!(stats.n_in_country(de), 0);
+        assert_eq!(stats.n_without_country(), stats.n_usable());
+    }
+
+    #[test]
+    #[cfg(feature = "geoip")]
+    fn weight_by_country() {
+        let us: CountryCode = "US".parse().unwrap();
+        let de: CountryCode = "DE".parse().unwrap();
+
+        // Relays 20 and 21 are in the US; 22 is in Germany.
+        let netdir = geoip_test_netdir(&[20, 21], &[22]);
+        assert!(netdir.has_country_codes());
+
+        // Guard weights for 0x20, 0x21, 0x22 are 1000, 2000, 3000.
+        let w_us = netdir.total_weight(WeightRole::Guard, |r| r.country_code() == Some(us));
+        assert_eq!(w_us, RelayWeight(3_000));
+
+        let by_cc = netdir.total_weight_by_country(WeightRole::Guard, |r| {
+            r.low_level_details().is_suitable_as_guard()
+        });
+        assert_eq!(by_cc.get(&Some(us)), Some(&RelayWeight(3_000)));
+        assert_eq!(by_cc.get(&Some(de)), Some(&RelayWeight(3_000)));
+        assert_eq!(by_cc.get(&None), Some(&RelayWeight(104_000)));
+        assert_eq!(by_cc.len(), 3);
+
+        // Without a database, nothing matches any country.
+        let netdir = geoip_test_netdir_without_db(&[20, 21], &[22]);
+        assert!(!netdir.has_country_codes());
+        let w_us = netdir.total_weight(WeightRole::Guard, |r| r.country_code() == Some(us));
+        assert_eq!(w_us, RelayWeight(0));
+        let by_cc = netdir.total_weight_by_country(WeightRole::Guard, |r| {
+            r.low_level_details().is_suitable_as_guard()
+        });
+        assert_eq!(by_cc.len(), 1);
+        assert_eq!(by_cc.get(&None), Some(&RelayWeight(110_000)));
+    }
+
+    #[test]
+    #[cfg(feature = "geoip")]
+    fn geo_stats() {
+        let us: CountryCode = "US".parse().unwrap();
+        let de: CountryCode = "DE".parse().unwrap();
+        let fr: CountryCode = "FR".parse().unwrap();
+
+        // Relays 10 and 11 are exits in the US; 22 is a guard in Germany.
+        // We build this directory without a database, to make sure that we
+        // use the one that we're given.
+        let netdir = geoip_test_netdir_without_db(&[10, 11], &[22]);
+        let stats = GeoStats::compute(&netdir, &geoip_test_db());
+
+        let in_us = stats.get(us).unwrap();
+        assert_eq!(in_us.n_relays, 2);
+        assert_eq!(in_us.n_exits, 2);
+        assert_eq!(in_us.n_guards, 0);
+        assert_eq!(in_us.total_weight, 3_000);
+        let in_de = stats.get(de).unwrap();
+        assert_eq!(in_de.n_relays, 1);
+        assert_eq!(in_de.n_exits, 0);
+        assert_eq!(in_de.n_guards, 1);
+        assert_eq!(in_de.total_weight, 3_000);
+        assert!(stats.get(fr).is_none());
+
+        assert!(stats.has_exits(us));
+        assert!(!stats.has_exits(de));
+        assert!(!stats.has_exits(fr));
+        assert_eq!(
+            stats.countries().map(|(cc, _)| cc).collect::<Vec<_>>(),
+            vec![de, us]
+        );
+        assert_eq!(stats.unknown().n_relays, 40 - 3);
+        let total: u64 = stats
+            .countries()
+            .map(|(_, c)| c.total_weight)
+            .chain(std::iter::once(stats.unknown().total_weight))
+            .sum();
+        assert_eq!(total, 220_000);
+    }
+
+    #[test]
+    fn relay_list_change() {
+        let full = construct_netdir().unwrap_if_sufficient().unwrap();
+        let partial = construct_custom_netdir(|pos, nb, _| {
+            nb.omit_rs = pos == 0 || pos == 1;
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+
+        let c = RelayListChange::between(None, &full);
+        assert_eq!(c.added().len(), 40);
+        assert!(c.removed().is_empty());
+
+        let c = RelayListChange::between(Some(&full), &full);
+        assert!(c.is_empty());
+
+        let c = RelayListChange::between(Some(&full), &partial);
+        assert!(c.added().is_empty());
+        assert_eq!(c.removed(), &[[0; 20].into(), [1; 20].into()]);
+
+        let c = RelayListChange::between(Some(&partial), &full);
+        assert_eq!(c.added().len(), 2);
+        assert!(c.removed().is_empty());
+    }
+
+    #[test]
+    fn netdir_diff() {
+        use tor_netdoc::doc::netstatus::{RelayFlags, RelayWeight};
+        let old = construct_netdir().unwrap_if_sufficient().unwrap();
+        assert!(old.diff(&old).is_empty());
+
+        let new = construct_custom_netdir(|pos, nb, _| {
+            match pos {
+                0 | 1 => nb.omit_rs = true,
+                5 => {
+                    nb.rs.add_flags(RelayFlags::BAD_EXIT);
+                }
+                6 => {
+                    nb.rs.weight(RelayWeight::Unmeasured(7));
+                }
+                _ => {}
+            };
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+
+        let diff = old.diff(&new);
+        assert!(!diff.is_empty());
+        assert_eq!(diff.appeared().count(), 0);
+        let gone: Vec<_> = diff.disappeared().copied().collect();
+        assert_eq!(gone, vec![[0; 20].into(), [1; 20].into()]);
+
+        let flags: Vec<_> = diff.flags_changed().collect();
+        assert_eq!(flags.len(), 1);
+        assert_eq!(flags[0].rsa_id, [5; 20].into());
+        assert!(!flags[0].old.contains(RelayFlags::BAD_EXIT));
+        assert!(flags[0].new.contains(RelayFlags::BAD_EXIT));
+
+        let weights: Vec<_> = diff.weight_changed().collect();
+        assert_eq!(weights.len(), 1);
+        assert_eq!(weights[0].rsa_id, [6; 20].into());
+        assert_eq!(weights[0].new, RelayWeight::Unmeasured(7));
+
+        // The reverse diff swaps old and new.
+        let rev = new.diff(&old);
+        assert_eq!(rev.appeared().count(), 2);
+        assert_eq!(rev.disappeared().count(), 0);
+        assert_eq!(
+            rev.weight_changed().next().unwrap().old,
+            RelayWeight::Unmeasured(7)
+        );
+    }
+
+    #[test]
+    fn change_summary() {
+        use tor_netdoc::doc::netstatus::{RelayFlags, RelayWeight};
+        let old = construct_netdir().unwrap_if_sufficient().unwrap();
+        assert_eq!(old.clone().generation(), old.generation());
+        assert!(NetDirChangeSummary::between(Some(&old), &old).is_empty());
+        let s = NetDirChangeSummary::between(None, &old);
+        assert_eq!(s.n_added, 40);
+        assert!(s.params_changed);
+
+        let new = construct_custom_netdir(|pos, nb, _| {
+            match pos {
+                0 | 1 => nb.omit_rs = true,
+                5 => {
+                    nb.rs.add_flags(RelayFlags::BAD_EXIT);
+                }
+                6 => {
+                    nb.rs.weight(RelayWeight::Unmeasured(7));
+                }
+                _ => {}
+            };
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+        assert!(new.generation() > old.generation());
+        // (Every remaining relay counts as changed, since each test network
+        // gets fresh microdescriptor digests.)
+        let s = NetDirChangeSummary::between(Some(&old), &new);
+        assert_eq!((s.n_added, s.n_removed, s.n_changed), (0, 2, 38));
+        assert!(!s.params_changed);
+
+        // Changing the parameters gives a new generation.
+        let mut changed = old.clone();
+        let mut params = netstatus::NetParams::default();
+        params.set("circwindow".into(), 500);
+        changed.replace_overridden_parameters(&params);
+        assert!(changed.generation() > new.generation());
+        let s2 = NetDirChangeSummary::between(Some(&old), &changed);
+        assert_eq!((s2.n_added, s2.n_removed, s2.n_changed), (0, 0, 0));
+        assert!(s2.params_changed);
+
+        // Combined summaries add up.
+        let both = s.followed_by(&s2);
+        assert_eq!((both.n_removed, both.n_changed), (2, 38));
+        assert!(both.params_changed);
+        assert!(NetDirChangeSummary::default()
+            .followed_by(&NetDirChangeSummary::default())
+            .is_empty());
+
+        // Microdescriptors arriving count as changes.
+        let (consensus, mut microdescs) = construct_network().unwrap();
+        let last = microdescs.pop().unwrap();
+        let mut partial = PartialNetDir::new(consensus, None);
+        for md in microdescs {
+            partial.add_microdesc(md);
+        }
+        let mut partial = partial.unwrap_if_sufficient().unwrap();
+        let before = partial.clone();
+        partial.add_microdesc(last);
+        assert!(partial.generation() > before.generation());
+        let s = NetDirChangeSummary::between(Some(&before), &partial);
+        assert_eq!((s.n_added, s.n_removed, s.n_changed), (0, 0, 1));
+    }
+
+    #[test]
+    fn flag_queries() {
+        use tor_netdoc::doc::netstatus::RelayFlags;
+        let netdir = construct_custom_netdir(|pos, nb, _| {
+            if (10..20).contains(&pos) {
+                nb.rs.add_flags(RelayFlags::BAD_EXIT);
+            }
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+
+        let queries = [
+            RelayFlagQuery::new(),
+            RelayFlagQuery::new().require(RelayFlags::FAST | RelayFlags::STABLE),
+            RelayFlagQuery::new()
+                .require(RelayFlags::EXIT)
+                .forbid(RelayFlags::BAD_EXIT),
+            RelayFlagQuery::new().forbid(RelayFlags::GUARD | RelayFlags::EXIT),
+            // Required flags win over forbidden ones.
+            RelayFlagQuery::new()
+                .require(RelayFlags::BAD_EXIT)
+                .forbid(RelayFlags::BAD_EXIT),
+        ];
+        for q in &queries {
+            let expected: Vec<_> = netdir
+                .all_relays()
+                .filter(|r| q.matches(*r.rs.flags()))
+                .map(|r| *r.rs.rsa_identity())
+                .collect();
+            let got: Vec<_> = netdir
+                .all_relays_with_flags(q)
+                .map(|r| *r.rs.rsa_identity())
+                .collect();
+            assert_eq!(got, expected);
+            assert_eq!(
+                netdir.relays_with_flags(q).count(),
+                netdir.relays().filter(|r| q.matches(*r.rs.flags())).count()
+            );
+        }
+
+        let bad = RelayFlagQuery::new().require(RelayFlags::BAD_EXIT);
+        assert_eq!(netdir.relays_with_flags(&bad).count(), 10);
+        assert_eq!(netdir.relays_with_flags(&queries[4]).count(), 10);
+        assert_eq!(netdir.relays_with_flags(&queries[0]).count(), 40);
+    }
+
+    #[test]
+    #[cfg(feature = "geoip")]
+    fn pick_by_country() {
+        let us: CountryCode = "US".parse().unwrap();
+        let de: CountryCode = "DE".parse().unwrap();
+        let fr: CountryCode = "FR".parse().unwrap();
+
+        // Relays 20 and 21 are in the US; 22 is in Germany.
+        let netdir = geoip_test_netdir(&[20, 21], &[22]);
+        let mut rng = tor_basic_utils::test_rng::testing_rng();
+
+        for _ in 0..20 {
+            let r = netdir
+                .pick_relay_in_country(&mut rng, WeightRole::Middle, us, |_| true)
+                .unwrap();
+            assert_eq!(r.country_code(), Some(us));
+        }
+        assert!(netdir
+            .pick_relay_in_country(&mut rng, WeightRole::Middle, fr, |_| true)
+            .is_none());
+        assert!(netdir
+            .pick_relay_in_country(&mut rng, WeightRole::Middle, de, |r| r.rsa_id().as_bytes()
+                [0]
+                != 22)
+            .is_none());
+
+        // Excluding the US leaves only the German relay, since we exclude
+        // relays in unknown countries.
+        let picked = netdir.pick_n_relays_excluding_countries(
+            &mut rng,
+            40,
+            WeightRole::Middle,
+            &[us],
+            |_| true,
+        );
+        assert_eq!(picked.len(), 1);
+        assert_eq!(picked[0].country_code(), Some(de));
+
+        // Excluding no countries excludes nobody.
+        let picked =
+            netdir
+                .pick_n_relays_excluding_countries(&mut rng, 40, WeightRole::Middle, &[], |_| true);
+        assert_eq!(picked.len(), netdir.relays().count());
+    }
+
+    #[test]
+    #[cfg(feature = "geoip")]
+    fn pick_path_by_country() {
+        let us: CountryCode = "US".parse().unwrap();
+        let de: CountryCode = "DE".parse().unwrap();
+
+        // Relays 0, 1, 4, 5, 8, 9... are in the US; the others are in
+        // Germany.
+        let (in_us, in_de): (Vec<usize>, Vec<usize>) = (0..40).partition(|pos| pos % 4 < 2);
+        let netdir = geoip_test_netdir(&in_us, &in_de);
+        let mut rng = tor_basic_utils::test_rng::testing_rng();
+        // Every relay in each country shares an IPv6 subnet, so don't look
+        // at subnets here.
+        let base = PathPolicy::new().subnet_config(SubnetConfig::no_addresses_match());
+
+        let policy = base.clone().exclude_countries(&[de]);
+        for _ in 0..20 {
+            let path = netdir.pick_path(&mut rng, &policy, 3).unwrap();
+            assert!(path.iter().all(|r| r.country_code() == Some(us)));
+        }
+        let policy = base.clone().exclude_countries(&[de, us]);
+        assert!(netdir.pick_path(&mut rng, &policy, 3).is_none());
+
+        // With only two countries, we can't build a three-hop path through
+        // distinct countries.
+        let policy = base.distinct_countries();
+        for _ in 0..20 {
+            let path = netdir.pick_path(&mut rng, &policy, 2).unwrap();
+            assert_ne!(path[0].country_code(), path[1].country_code());
+        }
+        assert!(netdir.pick_path(&mut rng, &policy, 3).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "geoip")]
+    fn declared_countries() {
+        let us: CountryCode = "US".parse().unwrap();
+        let de: CountryCode = "DE".parse().unwrap();
+
+        // Relays 20-23 all claim to be in the US.
+        let declared = |r: &UncheckedRelay<'_>| {
+            let idx = r.rsa_identity().unwrap().as_bytes()[0];
+            (20..24).contains(&idx).then_some(us)
+        };
+
+        // Relays 20 and 21 are in the US; 22 is in Germany.
+        let netdir = geoip_test_netdir(&[20, 21], &[22]);
+        let report = netdir.check_declared_countries(declared);
+        assert_eq!(report.n_declared, 4);
+        assert_eq!(report.n_agree, 2);
+        assert_eq!(report.n_unknown, 1);
+        assert_eq!(
+            report.disagreements,
+            vec![CountryDisagreement {
+                rsa_id: [22; 20].into(),
+                geoip: de,
+                declared: us,
+            }]
+        );
+        assert_eq!(report.disagreement_fraction(), Some(1.0 / 3.0));
+
+        // Without a database, we can't check anything.
+        let netdir = geoip_test_netdir_without_db(&[20, 21], &[22]);
+        let report = netdir.check_declared_countries(declared);
+        assert_eq!(report.n_declared, 4);
+        assert_eq!(report.n_unknown, 4);
+        assert!(report.disagreements.is_empty());
+        assert_eq!(report.disagreement_fraction(), None);
+    }
+
     #[test]
     #[cfg(feature = "hs-common")]
     #[allow(deprecated)]
@@ -2799,6 +5124,54 @@ mod test {
             assert_eq!(unique.len(), relays.len());
         }
 
+        assert_eq!(
+            netdir.hs_dir_params().time_period(),
+            netdir.hs_time_period()
+        );
+        #[cfg(feature = "hs-service")]
+        assert_eq!(&netdir.hs_all_time_periods()[0], netdir.hs_dir_params());
+        assert_eq!(netdir.hs_dir_n_replicas(), 2);
+        assert_eq!(
+            netdir.hs_dir_spread(HsDirSpread::Fetch),
+            HSDIR_SPREAD_FETCH as usize
+        );
+        assert_eq!(
+            netdir.hs_dir_spread(HsDirSpread::Store),
+            HSDIR_SPREAD_STORE as usize
+        );
+
+        // The ring is in order, and holds every relay with the HSDir flag.
+        let period = netdir.hs_time_period();
+        let entries = netdir.hsdir_ring_entries(period).unwrap().collect_vec();
+        assert_eq!(entries.len(), 10);
+        assert!(entries.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(entries.iter().all(|(_, r)| r.rs.is_flagged_hsdir()));
+        assert!(netdir.hsdir_ring_entries(period.next().unwrap()).is_none());
+
+        // When fetching, each replica uses the first relays at or after its
+        // position on the ring that no lower-numbered replica has used.
+        let mut expected = HashSet::new();
+        for replica in 1..=2 {
+            let pos = netdir.hsdir_position(&hsid, replica, period).unwrap();
+            let start = entries.iter().position(|(idx, _)| *idx >= pos).unwrap_or(0);
+            let chosen = (0..entries.len())
+                .map(|i| *entries[(start + i) % entries.len()].1.id())
+                .filter(|id| !expected.contains(id))
+                .take(HSDIR_SPREAD_FETCH as usize)
+                .collect_vec();
+            expected.extend(chosen);
+        }
+        let fetched = netdir
+            .hs_dirs_download(hsid, period, &mut testing_rng())
+            .unwrap()
+            .iter()
+            .map(|relay| *relay.id())
+            .collect::<HashSet<_>>();
+        assert_eq!(fetched, expected);
+        assert!(netdir
+            .hsdir_position(&hsid, 1, period.next().unwrap())
+            .is_none());
+
         // TODO: come up with a test that checks that HsDirRing::ring_items_at() skips over the
         // expected relays.
         //
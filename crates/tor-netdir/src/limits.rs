@@ -0,0 +1,118 @@
+//! Limits on how much directory information a network directory will hold.
+//!
+//! A broken or malicious consensus could list an absurd number of relays, and
+//! thereby make us try to download and retain an absurd number of
+//! microdescriptors.  On small devices, that could exhaust our memory.  The
+//! types in this module let the caller bound how much we will accept.
+
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdoc::doc::microdesc::Microdesc;
+use tor_netdoc::types::family::RelayFamily;
+use tracing::warn;
+
+/// Limits on the directory information that a [`PartialNetDir`](crate::PartialNetDir)
+/// (and the [`NetDir`](crate::NetDir) that it becomes) will accept.
+///
+/// By default, there are no limits.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct NetDirLimits {
+    /// The largest number of relays that we will accept in a consensus.
+    ///
+    /// What we do with a consensus that lists more relays than this depends
+    /// on `policy`.
+    pub max_relays: Option<usize>,
+    /// The largest number of microdescriptors that we will retain.
+    ///
+    /// Once we have this many, we refuse any others, and stop asking for
+    /// them.
+    pub max_microdescs: Option<usize>,
+    /// The largest total number of bytes (approximately) that we will use to
+    /// store microdescriptors.
+    ///
+    /// Once we would exceed this, we refuse any others, and stop asking for
+    /// them.
+    pub max_md_bytes: Option<usize>,
+    /// What to do with a consensus that lists more than `max_relays` relays.
+    pub policy: OversizePolicy,
+}
+
+/// What to do with a consensus that lists too many relays.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum OversizePolicy {
+    /// Reject the consensus entirely, with
+    /// [`Error::ConsensusTooLarge`](crate::Error::ConsensusTooLarge).
+    #[default]
+    Reject,
+    /// Accept the consensus, but only use the relays with the highest
+    /// bandwidth weights.  We will never download microdescriptors for the
+    /// others, and so they will never be usable.
+    KeepHighestWeight,
+}
+
+/// The remaining budget for microdescriptors in a single
+/// [`NetDir`](crate::NetDir).
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MdBudget {
+    /// The largest number of microdescriptors to retain, if any.
+    max_mds: Option<usize>,
+    /// The largest approximate number of bytes to retain, if any.
+    max_bytes: Option<usize>,
+    /// The number of microdescriptors we have admitted so far.
+    n_mds: usize,
+    /// The approximate number of bytes we have admitted so far.
+    n_bytes: usize,
+    /// True if we have already warned about running out of budget.
+    warned: bool,
+}
+
+impl MdBudget {
+    /// Return a new `MdBudget` to enforce the limits in `limits`.
+    pub(crate) fn new(limits: &NetDirLimits) -> Self {
+        MdBudget {
+            max_mds: limits.max_microdescs,
+            max_bytes: limits.max_md_bytes,
+            ..Default::default()
+        }
+    }
+
+    /// Return true if `md` fits within this budget, and deduct it.
+    ///
+    /// Return false (and warn, the first time) if it does not.
+    pub(crate) fn try_admit(&mut self, md: &Microdesc) -> bool {
+        let size = approx_md_size(md);
+        let too_many = self.max_mds.is_some_and(|max| self.n_mds >= max);
+        let too_big = self
+            .max_bytes
+            .is_some_and(|max| self.n_bytes.saturating_add(size) > max);
+        if too_many || too_big {
+            if !self.warned {
+                warn!(
+                    "Refusing microdescriptors beyond our configured limits ({} microdescriptors, about {} bytes).",
+                    self.n_mds, self.n_bytes
+                );
+                self.warned = true;
+            }
+            return false;
+        }
+        self.n_mds += 1;
+        self.n_bytes += size;
+        true
+    }
+
+    /// Return true if this budget has refused any microdescriptor.
+    pub(crate) fn exhausted(&self) -> bool {
+        self.warned
+    }
+}
+
+/// Return an approximation of the number of bytes that we use to store `md`.
+///
+/// This is an overestimate: it counts the family in full, even though
+/// families are often shared between microdescriptors.
+pub(crate) fn approx_md_size(md: &Microdesc) -> usize {
+    std::mem::size_of::<Microdesc>()
+        + std::mem::size_of::<RelayFamily>()
+        + md.family().members().count() * std::mem::size_of::<RsaIdentity>()
+}
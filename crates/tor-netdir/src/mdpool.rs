@@ -0,0 +1,144 @@
+//! A pool of microdescriptors that consecutive directories can share.
+//!
+//! Each [`NetDir`](crate::NetDir) holds its microdescriptors behind `Arc`s.
+//! When a new consensus arrives, most of its relays have the same
+//! microdescriptors as before; but if we parse those microdescriptors again
+//! (for example, because we load them from a cache), we hold two copies of
+//! each one for as long as both directories are alive.  A [`MicrodescPool`]
+//! interns microdescriptors by digest, so that all the directories that use
+//! the same pool share a single allocation for each one.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+use tor_netdoc::doc::microdesc::{MdDigest, Microdesc};
+
+/// The smallest number of entries at which we look for dead entries to
+/// remove from a [`MicrodescPool`].
+const MIN_PRUNE_THRESHOLD: usize = 1024;
+
+/// A shared pool of microdescriptors, keyed by digest.
+///
+/// The pool only holds weak references: a microdescriptor is freed as soon as
+/// no directory uses it any more.
+///
+/// Cloning a `MicrodescPool` gives another handle to the same pool.  To have
+/// consecutive directories share their microdescriptors, give each
+/// [`PartialNetDir`](crate::PartialNetDir) the same pool with
+/// [`PartialNetDir::with_md_pool`](crate::PartialNetDir::with_md_pool); a
+/// directory that has no pool of its own adopts the pool of the directory
+/// that it is filled from.
+#[derive(Clone, Debug, Default)]
+pub struct MicrodescPool {
+    /// The contents of the pool.
+    inner: Arc<Mutex<PoolInner>>,
+}
+
+/// The contents of a [`MicrodescPool`].
+#[derive(Debug, Default)]
+struct PoolInner {
+    /// The microdescriptors in the pool, by digest.
+    ///
+    /// Some of these may be dead.
+    mds: HashMap<MdDigest, Weak<Microdesc>>,
+    /// When `mds` has this many entries, we remove its dead entries.
+    prune_at: usize,
+}
+
+impl MicrodescPool {
+    /// Create a new, empty `MicrodescPool`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return a shared reference to `md`.
+    ///
+    /// If the pool already has a microdescriptor with the same digest, we
+    /// return that one, and drop `md`.  Otherwise, we add `md` to the pool.
+    pub fn intern(&self, md: Microdesc) -> Arc<Microdesc> {
+        let digest = *md.digest();
+        self.intern_with(digest, || Arc::new(md))
+    }
+
+    /// As [`intern`](MicrodescPool::intern), but for a microdescriptor that
+    /// is already in an `Arc`.
+    pub fn intern_arc(&self, md: Arc<Microdesc>) -> Arc<Microdesc> {
+        let digest = *md.digest();
+        self.intern_with(digest, || md)
+    }
+
+    /// Return the number of distinct microdescriptors in the pool that some
+    /// directory is still using.
+    pub fn len(&self) -> usize {
+        let inner = self.inner.lock().expect("poisoned lock");
+        inner
+            .mds
+            .values()
+            .filter(|md| md.strong_count() > 0)
+            .count()
+    }
+
+    /// Return true if no directory is using any microdescriptor in the pool.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Helper: return the microdescriptor in the pool with `digest`, or add
+    /// the one that `make` returns.
+    fn intern_with<F>(&self, digest: MdDigest, make: F) -> Arc<Microdesc>
+    where
+        F: FnOnce() -> Arc<Microdesc>,
+    {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        if let Some(md) = inner.mds.get(&digest).and_then(Weak::upgrade) {
+            return md;
+        }
+        let md = make();
+        inner.mds.insert(digest, Arc::downgrade(&md));
+        if inner.mds.len() >= inner.prune_at {
+            inner.mds.retain(|_, md| md.strong_count() > 0);
+            inner.prune_at = std::cmp::max(inner.mds.len() * 2, MIN_PRUNE_THRESHOLD);
+        }
+        md
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::testnet;
+
+    #[test]
+    fn interning() {
+        let (_, mds) = testnet::construct_network().unwrap();
+        let pool = MicrodescPool::new();
+        assert!(pool.is_empty());
+
+        let a = pool.intern(mds[0].clone());
+        let b = pool.intern(mds[0].clone());
+        assert!(Arc::ptr_eq(&a, &b));
+        let c = pool.intern_arc(Arc::new(mds[1].clone()));
+        assert!(!Arc::ptr_eq(&a, &c));
+        assert!(Arc::ptr_eq(&c, &pool.clone().intern(mds[1].clone())));
+        assert_eq!(pool.len(), 2);
+
+        // Once nobody uses a microdescriptor, the pool forgets it.
+        drop((a, b));
+        assert_eq!(pool.len(), 1);
+        let d = pool.intern(mds[0].clone());
+        assert_eq!(Arc::strong_count(&d), 1);
+    }
+}
@@ -0,0 +1,147 @@
+//! Information about the consensus behind a [`NetDir`], other than its relays.
+//!
+//! Tools that debug the HSDir ring or check the health of the consensus need
+//! to see header fields like the shared random values and the consensus
+//! method.  [`NetDir::consensus_metadata`] gives them access to these without
+//! having to keep the raw consensus around.
+
+use std::time::{Duration, SystemTime};
+
+use tor_netdoc::doc::netstatus::{MdConsensus, SharedRandStatus};
+
+use crate::NetDir;
+
+/// Header and signature information from the consensus of a [`NetDir`].
+///
+/// Returned by [`NetDir::consensus_metadata`].
+#[derive(Clone, Copy, Debug)]
+pub struct ConsensusMetadata<'a> {
+    /// The consensus that we're describing.
+    consensus: &'a MdConsensus,
+}
+
+/// When the consensus of a [`NetDir`] is valid, and how the authorities
+/// schedule their votes.
+///
+/// Returned by [`ConsensusMetadata::voting_schedule`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct VotingSchedule {
+    /// The time at which the consensus became valid.
+    pub valid_after: SystemTime,
+    /// The time at which we expect the next consensus.
+    pub fresh_until: SystemTime,
+    /// The time after which the consensus is no longer valid.
+    pub valid_until: SystemTime,
+    /// The interval between one consensus and the next.
+    pub voting_interval: Duration,
+    /// How long the authorities wait for votes to propagate, if the
+    /// consensus says.
+    pub vote_delay: Option<Duration>,
+    /// How long the authorities wait for signatures to propagate, if the
+    /// consensus says.
+    pub dist_delay: Option<Duration>,
+}
+
+impl<'a> ConsensusMetadata<'a> {
+    /// Return the shared random value for the current shared random period,
+    /// if the consensus has one.
+    pub fn shared_rand_cur(&self) -> Option<&'a SharedRandStatus> {
+        self.consensus.shared_rand_cur()
+    }
+
+    /// Return the shared random value for the previous shared random period,
+    /// if the consensus has one.
+    pub fn shared_rand_prev(&self) -> Option<&'a SharedRandStatus> {
+        self.consensus.shared_rand_prev()
+    }
+
+    /// Return the consensus method that the authorities used.
+    pub fn consensus_method(&self) -> u32 {
+        self.consensus.consensus_method()
+    }
+
+    /// Return the flags that the authorities voted on.
+    pub fn known_flags(&self) -> &'a [String] {
+        self.consensus.known_flags()
+    }
+
+    /// Return the number of authority signatures that were listed on the
+    /// consensus.
+    ///
+    /// We only accept a consensus when enough of these are valid, but this
+    /// count includes any that we couldn't check.
+    pub fn n_signatures(&self) -> usize {
+        self.consensus.n_signatures()
+    }
+
+    /// Return the voting schedule that the consensus declares.
+    pub fn voting_schedule(&self) -> VotingSchedule {
+        let lifetime = self.consensus.lifetime();
+        let delays = self.consensus.voting_delay();
+        let secs = |s: u32| Duration::from_secs(s.into());
+        VotingSchedule {
+            valid_after: lifetime.valid_after(),
+            fresh_until: lifetime.fresh_until(),
+            valid_until: lifetime.valid_until(),
+            voting_interval: lifetime.voting_period(),
+            vote_delay: delays.map(|(vote, _)| secs(vote)),
+            dist_delay: delays.map(|(_, dist)| secs(dist)),
+        }
+    }
+}
+
+impl NetDir {
+    /// Return information from the header and signatures of this directory's
+    /// consensus.
+    ///
+    /// Note that if this directory was built from an ns-flavored consensus,
+    /// this describes the consensus that it was converted from.
+    pub fn consensus_metadata(&self) -> ConsensusMetadata<'_> {
+        ConsensusMetadata {
+            consensus: &self.consensus,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::testnet::construct_netdir;
+
+    #[test]
+    fn metadata() {
+        let netdir = construct_netdir().unwrap_if_sufficient().unwrap();
+        let meta = netdir.consensus_metadata();
+
+        assert_eq!(meta.consensus_method(), 34);
+        assert!(meta.shared_rand_cur().is_none());
+        assert!(meta.shared_rand_prev().is_none());
+        // We built this consensus ourselves, so it has no known-flags line
+        // and no signatures.
+        assert!(meta.known_flags().is_empty());
+        assert_eq!(meta.n_signatures(), 0);
+
+        let lifetime = netdir.lifetime();
+        let schedule = meta.voting_schedule();
+        assert_eq!(schedule.valid_after, lifetime.valid_after());
+        assert_eq!(schedule.fresh_until, lifetime.fresh_until());
+        assert_eq!(schedule.valid_until, lifetime.valid_until());
+        assert_eq!(schedule.voting_interval, Duration::from_secs(43200));
+        assert_eq!(schedule.vote_delay, None);
+        assert_eq!(schedule.dist_delay, None);
+    }
+}
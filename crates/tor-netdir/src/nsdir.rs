@@ -0,0 +1,334 @@
+//! Support for building a [`NetDir`] from an "ns"-flavored consensus.
+//!
+//! Clients use microdescriptor consensuses, and the rest of this crate is
+//! written in terms of them.  But research tools and relay-side code sometimes
+//! need to work with a full "ns"-flavored consensus and the router descriptors
+//! that it lists.
+//!
+//! Rather than duplicate our weighting, family, and path selection code for
+//! those documents, we translate them into the form that a [`NetDir`] already
+//! understands (see [`NsConsensus::to_md_consensus`] and
+//! [`Microdesc::from_routerdesc`]), and keep the router descriptors alongside
+//! the resulting `NetDir`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdoc::doc::microdesc::Microdesc;
+use tor_netdoc::doc::netstatus::{self, NsConsensus, RouterStatus as _};
+use tor_netdoc::doc::routerdesc::{RdDigest, RouterDesc};
+
+use crate::{MdReceiver as _, NetDir, PartialNetDir, Relay};
+
+/// A partially built [`NsNetDir`]: it can't be unwrapped until it has enough
+/// router descriptors to build safe paths.
+#[derive(Debug, Clone)]
+pub struct PartialNsNetDir {
+    /// The directory that we're building from our translated documents.
+    partial: PartialNetDir,
+    /// The consensus that we're building from.
+    consensus: Arc<NsConsensus>,
+    /// The router descriptors that we have so far, by the RSA identity of
+    /// their relays.
+    routerdescs: HashMap<RsaIdentity, Arc<RouterDesc>>,
+}
+
+/// A view of the Tor network built from an "ns"-flavored consensus and its
+/// router descriptors.
+///
+/// Use [`netdir`](NsNetDir::netdir) to weight and select relays exactly as you
+/// would with a `NetDir` built from a microdescriptor consensus, and
+/// [`routerdesc`](NsNetDir::routerdesc) to get the full router descriptor for
+/// any of those relays.
+///
+/// # Limitations
+///
+/// Each relay's IPv4 exit policy in the `NetDir` is a summary of the one in its
+/// router descriptor, computed with
+/// [`AddrPolicy::ipv4_summary`](tor_netdoc::types::policy::AddrPolicy::ipv4_summary).
+/// It may differ slightly from the summary that the authorities would have put
+/// in its microdescriptor.  Callers that care about particular addresses
+/// should check the full policy in the router descriptor.
+#[derive(Debug, Clone)]
+pub struct NsNetDir {
+    /// The directory built from our translated documents.
+    netdir: NetDir,
+    /// The consensus that we were built from.
+    consensus: Arc<NsConsensus>,
+    /// The router descriptors that we have, by the RSA identity of their
+    /// relays.
+    routerdescs: HashMap<RsaIdentity, Arc<RouterDesc>>,
+}
+
+impl PartialNsNetDir {
+    /// Create a new PartialNsNetDir with a given consensus, and no router
+    /// descriptors loaded.
+    ///
+    /// If `replacement_params` is provided, override network parameters from
+    /// the consensus with those from `replacement_params`.
+    pub fn new(
+        consensus: NsConsensus,
+        replacement_params: Option<&netstatus::NetParams<i32>>,
+    ) -> Self {
+        let partial = PartialNetDir::new(consensus.to_md_consensus(), replacement_params);
+        PartialNsNetDir {
+            partial,
+            consensus: Arc::new(consensus),
+            routerdescs: HashMap::new(),
+        }
+    }
+
+    /// Return the declared lifetime of this PartialNsNetDir.
+    pub fn lifetime(&self) -> &netstatus::Lifetime {
+        self.partial.lifetime()
+    }
+
+    /// Return an iterator over the digests of all the router descriptors that
+    /// this directory is missing.
+    pub fn missing_routerdescs(&self) -> impl Iterator<Item = &RdDigest> + '_ {
+        self.consensus
+            .relays()
+            .iter()
+            .filter(|rs| !self.routerdescs.contains_key(rs.rsa_identity()))
+            .map(|rs| rs.rd_digest())
+    }
+
+    /// Return the number of missing router descriptors.
+    pub fn n_missing(&self) -> usize {
+        self.partial.n_missing()
+    }
+
+    /// Add a router descriptor to this directory, if it was wanted.
+    ///
+    /// Return true if it was indeed wanted.
+    pub fn add_routerdesc(&mut self, rd: RouterDesc) -> bool {
+        let wanted = self.partial.add_microdesc(Microdesc::from_routerdesc(&rd));
+        if wanted {
+            #[cfg(feature = "overload")]
+            self.partial.note_overload_from_routerdesc(&rd);
+            self.routerdescs.insert(*rd.rsa_identity(), Arc::new(rd));
+        }
+        wanted
+    }
+
+    /// Return true if there is enough information in this directory to build
+    /// multihop paths.
+    pub fn have_enough_paths(&self) -> bool {
+        self.partial.have_enough_paths()
+    }
+
+    /// If this directory has enough information to build multihop circuits,
+    /// return it.
+    pub fn unwrap_if_sufficient(self) -> Result<NsNetDir, PartialNsNetDir> {
+        let PartialNsNetDir {
+            partial,
+            consensus,
+            routerdescs,
+        } = self;
+        match partial.unwrap_if_sufficient() {
+            Ok(netdir) => Ok(NsNetDir {
+                netdir,
+                consensus,
+                routerdescs,
+            }),
+            Err(partial) => Err(PartialNsNetDir {
+                partial,
+                consensus,
+                routerdescs,
+            }),
+        }
+    }
+}
+
+impl NsNetDir {
+    /// Return the [`NetDir`] that we use to weight and select relays.
+    pub fn netdir(&self) -> &NetDir {
+        &self.netdir
+    }
+
+    /// Return the "ns"-flavored consensus that this directory was built from.
+    pub fn ns_consensus(&self) -> &NsConsensus {
+        &self.consensus
+    }
+
+    /// Return the router descriptor for `relay`.
+    ///
+    /// `relay` should come from this directory's [`netdir`](NsNetDir::netdir).
+    /// Since every usable relay there has a router descriptor, this only
+    /// returns `None` for relays from some other directory.
+    pub fn routerdesc(&self, relay: &Relay<'_>) -> Option<&RouterDesc> {
+        self.routerdesc_by_rsa_id(relay.rsa_id())
+    }
+
+    /// Return the router descriptor for the relay with RSA identity `rsa_id`,
+    /// if we have one.
+    pub fn routerdesc_by_rsa_id(&self, rsa_id: &RsaIdentity) -> Option<&RouterDesc> {
+        self.routerdescs.get(rsa_id).map(Arc::as_ref)
+    }
+}
+
+impl AsRef<NetDir> for NsNetDir {
+    fn as_ref(&self) -> &NetDir {
+        &self.netdir
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::{SubnetConfig, WeightRole};
+    use std::time::{Duration, SystemTime};
+    use tor_checkable::{SelfSigned, Timebound};
+    use tor_linkspec::HasRelayIds as _;
+    use tor_netdoc::doc::netstatus::{Lifetime, RelayFlags, RelayWeight};
+
+    fn routerdesc(text: &str) -> RouterDesc {
+        RouterDesc::parse(text)
+            .unwrap()
+            .check_signature()
+            .unwrap()
+            .dangerously_assume_timely()
+    }
+
+    /// Build an ns consensus that lists `rd` with a high weight, and another
+    /// relay (whose descriptor we don't have) with a very low one.
+    fn consensus_for(rd: &RouterDesc) -> NsConsensus {
+        let now = SystemTime::now();
+        let one_day = Duration::new(86400, 0);
+        let flags = RelayFlags::RUNNING
+            | RelayFlags::VALID
+            | RelayFlags::FAST
+            | RelayFlags::STABLE
+            | RelayFlags::GUARD;
+
+        let mut bld = NsConsensus::builder();
+        bld.consensus_method(34)
+            .lifetime(Lifetime::new(now, now + one_day / 2, now + one_day).unwrap())
+            .param("bwweightscale", 1)
+            .weights("".parse().unwrap());
+        let mut rs = bld.rs();
+        rs.identity(*rd.rsa_identity())
+            .add_or_port(rd.or_ports().next().unwrap())
+            .doc_digest(*rd.digest())
+            .protos("".parse().unwrap())
+            .set_flags(flags)
+            .weight(RelayWeight::Measured(10000));
+        rs.build_into(&mut bld).unwrap();
+        let mut rs = bld.rs();
+        rs.identity([9; 20].into())
+            .add_or_port("192.0.2.9:9001".parse().unwrap())
+            .doc_digest([9; 20])
+            .protos("".parse().unwrap())
+            .set_flags(flags)
+            .weight(RelayWeight::Measured(1));
+        rs.build_into(&mut bld).unwrap();
+        bld.testing_consensus().unwrap()
+    }
+
+    #[test]
+    fn build_from_ns_consensus() {
+        let rd = routerdesc(include_str!("../testdata/routerdesc1.txt"));
+        let unlisted = routerdesc(include_str!("../testdata/routerdesc2.txt"));
+        let consensus = consensus_for(&rd);
+
+        let mut dir = PartialNsNetDir::new(consensus, None);
+        assert_eq!(dir.n_missing(), 2);
+        let mut missing: Vec<_> = dir.missing_routerdescs().copied().collect();
+        missing.sort();
+        assert_eq!(missing, vec![[9; 20], *rd.digest()]);
+        assert!(!dir.have_enough_paths());
+
+        assert!(!dir.add_routerdesc(unlisted));
+        assert!(dir.add_routerdesc(rd.clone()));
+        assert!(!dir.add_routerdesc(rd.clone()));
+        assert_eq!(dir.n_missing(), 1);
+        assert_eq!(
+            dir.missing_routerdescs().collect::<Vec<_>>(),
+            vec![&[9; 20]]
+        );
+        assert!(dir.have_enough_paths());
+
+        let dir = dir.unwrap_if_sufficient().unwrap();
+        assert_eq!(dir.ns_consensus().relays().len(), 2);
+
+        // The NetDir has the relay whose descriptor we have, and weights it
+        // according to the consensus.
+        let netdir = dir.netdir();
+        let relay = netdir.by_id(rd.rsa_identity()).unwrap();
+        assert_eq!(relay.ed_identity(), Some(rd.ed_identity()));
+        assert_eq!(netdir.relays().count(), 1);
+        assert_eq!(
+            netdir.relay_weight(&relay, WeightRole::Guard),
+            netdir.total_weight(WeightRole::Guard, |u| u.is_usable())
+        );
+        assert!(netdir.by_id(&RsaIdentity::from([9; 20])).is_none());
+
+        // Family information comes from the router descriptor.
+        let family: Vec<_> = relay.md().family().members().copied().collect();
+        assert_eq!(family.len(), 2);
+        assert_eq!(
+            netdir
+                .relays_compatible_with(std::slice::from_ref(&relay), &SubnetConfig::default())
+                .count(),
+            0
+        );
+
+        // "reject *:*"
+        assert!(!relay.low_level_details().supports_exit_port_ipv4(80));
+
+        let found = dir.routerdesc(&relay).unwrap();
+        assert_eq!(found.digest(), rd.digest());
+        assert!(dir
+            .routerdesc_by_rsa_id(&RsaIdentity::from([9; 20]))
+            .is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "overload")]
+    fn overload_from_routerdesc() {
+        let text = include_str!("../testdata/routerdesc1.txt");
+        let rd = routerdesc(text);
+        assert!(rd.overload_general().is_none());
+        // Adding a line invalidates the signatures, so don't check them.
+        let overloaded: RouterDesc = RouterDesc::parse(&text.replace(
+            "published 2022-11-14 19:58:52\n",
+            "published 2022-11-14 19:58:52\noverload-general 1 2022-11-14 18:00:00\n",
+        ))
+        .unwrap()
+        .dangerously_assume_wellsigned()
+        .dangerously_assume_timely();
+
+        let mut dir = PartialNsNetDir::new(consensus_for(&overloaded), None);
+        assert!(dir.add_routerdesc(overloaded.clone()));
+        let dir = dir.unwrap_if_sufficient().unwrap();
+        let relay = dir.netdir().by_id(rd.rsa_identity()).unwrap();
+        let status = relay.overload_status().unwrap();
+        assert_eq!(
+            status.last_overloaded(),
+            humantime::parse_rfc3339("2022-11-14T18:00:00Z").unwrap()
+        );
+
+        // A newer descriptor without the line clears the report.
+        let mut netdir = dir.netdir().clone();
+        assert!(netdir.note_overload_from_routerdesc(&rd));
+        let relay = netdir.by_id(rd.rsa_identity()).unwrap();
+        assert!(relay.overload_status().is_none());
+        // We ignore descriptors for relays that aren't listed.
+        let unlisted = routerdesc(include_str!("../testdata/routerdesc2.txt"));
+        assert!(!netdir.note_overload_from_routerdesc(&unlisted));
+    }
+}
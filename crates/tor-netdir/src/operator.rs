@@ -0,0 +1,214 @@
+//! Grouping relays into clusters that are probably run by the same operator.
+//!
+//! Research on path selection, and tools that monitor the health of the
+//! network, need to know how much of the network each operator controls,
+//! rather than how much each relay does.  We can't know who runs a relay, but
+//! relays that declare each other as family members have told us that they
+//! share an operator.  [`NetDir::operator_clusters`] joins such relays
+//! (transitively) into [`OperatorCluster`]s, and reports the total weight of
+//! each.
+//!
+//! Relays that don't declare any families, or whose family members don't
+//! declare them back, each form a cluster on their own.
+
+use std::collections::HashMap;
+
+use tor_llcrypto::pk::rsa::RsaIdentity;
+
+use crate::{NetDir, RelayWeight, WeightRole};
+
+/// A set of usable relays that appear to share an operator.
+///
+/// Returned by [`NetDir::operator_clusters`].
+/// This is a snapshot: it doesn't change when the directory does.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OperatorCluster {
+    /// The RSA identities of the relays in this cluster, in ascending order.
+    members: Vec<RsaIdentity>,
+    /// The sum of the weights of the relays in this cluster.
+    total_weight: RelayWeight,
+}
+
+impl OperatorCluster {
+    /// Return the RSA identities of the relays in this cluster, in ascending
+    /// order.
+    pub fn members(&self) -> &[RsaIdentity] {
+        &self.members[..]
+    }
+
+    /// Return the number of relays in this cluster.
+    pub fn n_relays(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Return the total selection weight of the relays in this cluster, for
+    /// the role that we were asked about.
+    pub fn total_weight(&self) -> RelayWeight {
+        self.total_weight
+    }
+}
+
+/// A minimal union-find structure over the indices `0..n`.
+struct DisjointSets {
+    /// The parent of each index; an index that is its own parent is the
+    /// representative of its set.
+    parent: Vec<usize>,
+}
+
+impl DisjointSets {
+    /// Return a new `DisjointSets` where each index in `0..n` is alone in its
+    /// own set.
+    fn new(n: usize) -> Self {
+        DisjointSets {
+            parent: (0..n).collect(),
+        }
+    }
+
+    /// Return the representative of the set containing `idx`.
+    fn find(&mut self, mut idx: usize) -> usize {
+        while self.parent[idx] != idx {
+            // Path halving keeps the trees shallow.
+            self.parent[idx] = self.parent[self.parent[idx]];
+            idx = self.parent[idx];
+        }
+        idx
+    }
+
+    /// Join the sets containing `a` and `b`.
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.parent[a] = b;
+        }
+    }
+}
+
+impl NetDir {
+    /// Group the [usable](NetDir#usable) relays in this directory into
+    /// clusters that appear to share an operator, and return each cluster
+    /// with its total selection weight for `role`.
+    ///
+    /// Two relays are in the same cluster if they are in the same family (see
+    /// [`RelayDetails::in_same_family`](crate::details::RelayDetails::in_same_family)),
+    /// or if they are both in the same family as some third relay in the
+    /// cluster.  Every usable relay belongs to exactly one cluster.
+    ///
+    /// The clusters are returned in descending order of weight.
+    ///
+    /// # Limitations
+    ///
+    /// We only know about families that relays declare in their
+    /// microdescriptors, so we can't notice an operator who doesn't declare
+    /// their relays as a family.
+    pub fn operator_clusters(&self, role: WeightRole) -> Vec<OperatorCluster> {
+        let relays: Vec<_> = self.relays().collect();
+        let index: HashMap<&RsaIdentity, usize> = relays
+            .iter()
+            .enumerate()
+            .map(|(idx, r)| (r.rsa_id(), idx))
+            .collect();
+
+        let mut sets = DisjointSets::new(relays.len());
+        for (idx, relay) in relays.iter().enumerate() {
+            for other_id in relay.md.family().members() {
+                let Some(&other_idx) = index.get(other_id) else {
+                    continue;
+                };
+                if relays[other_idx].md.family().contains(relay.rsa_id()) {
+                    sets.union(idx, other_idx);
+                }
+            }
+        }
+
+        let mut clusters: HashMap<usize, OperatorCluster> = HashMap::new();
+        for (idx, relay) in relays.iter().enumerate() {
+            let cluster = clusters
+                .entry(sets.find(idx))
+                .or_insert_with(|| OperatorCluster {
+                    members: Vec::new(),
+                    total_weight: RelayWeight(0),
+                });
+            cluster.members.push(*relay.rsa_id());
+            cluster.total_weight.0 += self.weights.weight_rs_for_role(relay.rs, role);
+        }
+
+        let mut clusters: Vec<_> = clusters
+            .into_values()
+            .map(|mut cluster| {
+                cluster.members.sort();
+                cluster
+            })
+            .collect();
+        clusters.sort_by(|a, b| {
+            b.total_weight
+                .cmp(&a.total_weight)
+                .then_with(|| a.members.cmp(&b.members))
+        });
+        clusters
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::testnet::{construct_netdir, NetDirBuilder};
+    use tor_netdoc::doc::netstatus::RelayFlags;
+
+    #[test]
+    fn clusters() {
+        let mut bld = NetDirBuilder::new();
+        for idx in 0..6_u8 {
+            bld.relay([idx; 20].into(), [idx; 32].into())
+                .add_flags(RelayFlags::GUARD | RelayFlags::EXIT);
+        }
+        // Relay 2 joins these two families into one cluster.
+        bld.family([[0; 20].into(), [1; 20].into(), [2; 20].into()])
+            .family([[2; 20].into(), [3; 20].into()]);
+        let netdir = bld.build().unwrap();
+
+        let clusters = netdir.operator_clusters(WeightRole::Guard);
+        assert_eq!(clusters.len(), 3);
+        let ids = |range: std::ops::Range<u8>| -> Vec<RsaIdentity> {
+            range.map(|idx| [idx; 20].into()).collect()
+        };
+        assert_eq!(clusters[0].members(), &ids(0..4)[..]);
+        assert_eq!(clusters[1].members(), &ids(4..5)[..]);
+        assert_eq!(clusters[2].members(), &ids(5..6)[..]);
+
+        let weight = |idx: u8| {
+            let relay = netdir.by_rsa_id(&[idx; 20].into()).unwrap();
+            netdir.relay_weight(&relay, WeightRole::Guard)
+        };
+        let total: u64 = (0..4).map(|idx| weight(idx).0).sum();
+        assert_eq!(clusters[0].total_weight(), RelayWeight(total));
+        assert_eq!(clusters[1].total_weight(), weight(4));
+    }
+
+    #[test]
+    fn testnet_pairs() {
+        // In the default test network, each relay is in a family with the
+        // one next to it.
+        let netdir = construct_netdir().unwrap_if_sufficient().unwrap();
+        let clusters = netdir.operator_clusters(WeightRole::Middle);
+        assert_eq!(clusters.len(), 20);
+        assert!(clusters.iter().all(|c| c.n_relays() == 2));
+        let total: u64 = clusters.iter().map(|c| c.total_weight().0).sum();
+        assert_eq!(
+            RelayWeight(total),
+            netdir.bandwidth_distribution(WeightRole::Middle).total()
+        );
+    }
+}
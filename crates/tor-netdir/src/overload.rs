@@ -0,0 +1,163 @@
+//! Relays' reports that they are overloaded, and how we take them into account
+//! when choosing relays.
+//!
+//! A relay that has recently been overloaded says so in its router descriptor,
+//! with an `overload-general` line giving the hour at which it was last
+//! overloaded.  Microdescriptors don't carry this information, so a
+//! [`NetDir`](crate::NetDir) only knows about it if it is told, with
+//! [`NetDir::note_overload_from_routerdesc`](crate::NetDir::note_overload_from_routerdesc).
+//! (A [`PartialNsNetDir`](crate::PartialNsNetDir) does this for every router
+//! descriptor it receives.)
+//!
+//! We don't stop using overloaded relays: they are still listed in the
+//! consensus, and the authorities' weights already account for their
+//! measured capacity.  Instead, callers who want to spread load away from
+//! relays signaling distress can choose relays with an [`OverloadWeighting`],
+//! which reduces an overloaded relay's weight by a bounded amount.
+
+use std::time::{Duration, SystemTime};
+
+/// How long after a relay was last overloaded does it keep reporting that?
+///
+/// (From dir-spec: a relay removes the `overload-general` line from its
+/// descriptor once it has not been overloaded for 72 hours.)
+const OVERLOAD_REPORT_LIFETIME: Duration = Duration::from_secs(72 * 60 * 60);
+
+/// A relay's report that it has recently been overloaded.
+///
+/// Returned by [`Relay::overload_status`](crate::Relay::overload_status).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OverloadStatus {
+    /// The time at which the relay says it was last overloaded, rounded down
+    /// to the hour.
+    last_overloaded: SystemTime,
+}
+
+impl OverloadStatus {
+    /// Construct a new `OverloadStatus` for a relay that was last overloaded at
+    /// `last_overloaded`.
+    pub(crate) fn new(last_overloaded: SystemTime) -> Self {
+        OverloadStatus { last_overloaded }
+    }
+
+    /// Return the time at which the relay says it was last overloaded.
+    ///
+    /// Relays round this down to the hour.
+    pub fn last_overloaded(&self) -> SystemTime {
+        self.last_overloaded
+    }
+
+    /// Return true if this report is recent enough, as of `when`, that the
+    /// relay would still be making it.
+    ///
+    /// A relay stops reporting overload 72 hours after it was last overloaded;
+    /// we ignore older reports, which can come from stale descriptors.
+    pub fn is_recent_at(&self, when: SystemTime) -> bool {
+        match when.duration_since(self.last_overloaded) {
+            Ok(elapsed) => elapsed <= OVERLOAD_REPORT_LIFETIME,
+            // A report from the future is as recent as it gets.
+            Err(_) => true,
+        }
+    }
+}
+
+/// How much to de-prioritize overloaded relays when choosing relays.
+///
+/// Used with [`NetDir::pick_relay_with_overload_weighting`](crate::NetDir::pick_relay_with_overload_weighting).
+///
+/// When we choose a relay, we multiply the weight of every relay with a recent
+/// overload report by [`multiplier`](OverloadWeighting::multiplier).  The
+/// multiplier is never less than [`OverloadWeighting::MIN_MULTIPLIER`]: our
+/// choices have to stay close to the consensus weights, so that relays
+/// (honest or not) that report overload can only move a limited amount of
+/// traffic onto the rest of the network.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OverloadWeighting {
+    /// The amount by which to multiply an overloaded relay's weight.
+    multiplier: f64,
+}
+
+impl OverloadWeighting {
+    /// The smallest multiplier that we allow.
+    pub const MIN_MULTIPLIER: f64 = 0.5;
+
+    /// Return a new `OverloadWeighting` that multiplies the weights of
+    /// overloaded relays by `multiplier`.
+    ///
+    /// We clamp `multiplier` to lie between
+    /// [`MIN_MULTIPLIER`](OverloadWeighting::MIN_MULTIPLIER) and 1.0.  If it
+    /// is NaN, we use 1.0, which makes no change to the weights.
+    pub fn new(multiplier: f64) -> Self {
+        let multiplier = if multiplier.is_nan() {
+            1.0
+        } else {
+            multiplier.clamp(Self::MIN_MULTIPLIER, 1.0)
+        };
+        OverloadWeighting { multiplier }
+    }
+
+    /// Return the amount by which we multiply an overloaded relay's weight.
+    pub fn multiplier(&self) -> f64 {
+        self.multiplier
+    }
+
+    /// Return `weight`, adjusted for a relay that is overloaded if
+    /// `overloaded` is true.
+    pub(crate) fn adjust(&self, weight: u64, overloaded: bool) -> u64 {
+        if overloaded {
+            // Precision loss is fine here: weights are approximate anyway.
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let adjusted = (weight as f64 * self.multiplier) as u64;
+            adjusted
+        } else {
+            weight
+        }
+    }
+}
+
+impl Default for OverloadWeighting {
+    fn default() -> Self {
+        Self::new(Self::MIN_MULTIPLIER)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn recent() {
+        let t = SystemTime::now();
+        let status = OverloadStatus::new(t);
+        assert!(status.is_recent_at(t));
+        assert!(status.is_recent_at(t - Duration::from_secs(3600)));
+        assert!(status.is_recent_at(t + OVERLOAD_REPORT_LIFETIME));
+        assert!(!status.is_recent_at(t + OVERLOAD_REPORT_LIFETIME + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn weighting() {
+        assert_eq!(OverloadWeighting::default().multiplier(), 0.5);
+        assert_eq!(OverloadWeighting::new(0.75).multiplier(), 0.75);
+        assert_eq!(OverloadWeighting::new(0.0).multiplier(), 0.5);
+        assert_eq!(OverloadWeighting::new(2.0).multiplier(), 1.0);
+        assert_eq!(OverloadWeighting::new(f64::NAN).multiplier(), 1.0);
+
+        let w = OverloadWeighting::new(0.75);
+        assert_eq!(w.adjust(1000, false), 1000);
+        assert_eq!(w.adjust(1000, true), 750);
+    }
+}
@@ -86,6 +86,63 @@ impl FromInt32Saturating for SendMeVersion {
     }
 }
 
+/// An object that can be turned back into an i32 for
+/// [`FromInt32Saturating`] to reconstruct.
+#[cfg(feature = "snapshot")]
+pub(crate) trait ToInt32 {
+    /// Return the i32 value that this object represents.
+    fn to_i32(&self) -> i32;
+}
+
+#[cfg(feature = "snapshot")]
+impl ToInt32 for i32 {
+    fn to_i32(&self) -> i32 {
+        *self
+    }
+}
+#[cfg(feature = "snapshot")]
+impl<const L: i32, const H: i32> ToInt32 for BoundedInt32<L, H> {
+    fn to_i32(&self) -> i32 {
+        self.get()
+    }
+}
+#[cfg(feature = "snapshot")]
+impl<T: Copy + Into<f64> + ToInt32> ToInt32 for Percentage<T> {
+    fn to_i32(&self) -> i32 {
+        self.as_percent().to_i32()
+    }
+}
+#[cfg(feature = "snapshot")]
+impl<T: Copy + ToInt32> ToInt32 for IntegerMilliseconds<T> {
+    fn to_i32(&self) -> i32 {
+        self.as_millis().to_i32()
+    }
+}
+#[cfg(feature = "snapshot")]
+impl<T: Copy + ToInt32> ToInt32 for IntegerSeconds<T> {
+    fn to_i32(&self) -> i32 {
+        self.as_secs().to_i32()
+    }
+}
+#[cfg(feature = "snapshot")]
+impl<T: Copy + ToInt32> ToInt32 for IntegerMinutes<T> {
+    fn to_i32(&self) -> i32 {
+        self.as_minutes().to_i32()
+    }
+}
+#[cfg(feature = "snapshot")]
+impl<T: Copy + ToInt32> ToInt32 for IntegerDays<T> {
+    fn to_i32(&self) -> i32 {
+        self.as_days().to_i32()
+    }
+}
+#[cfg(feature = "snapshot")]
+impl ToInt32 for SendMeVersion {
+    fn to_i32(&self) -> i32 {
+        self.get().into()
+    }
+}
+
 /// A macro to help us declare the net parameters object.  It lets us
 /// put the information about each parameter in just one place, even
 /// though it will later get split between the struct declaration, the
@@ -135,6 +192,12 @@ macro_rules! declare_net_parameters {
                 }
                 true
             }
+            /// Return the name and value of every parameter, such that
+            /// calling `set_saturating` with them would recreate this object.
+            #[cfg(feature = "snapshot")]
+            pub(crate) fn to_values(&self) -> Vec<(&'static str, i32)> {
+                vec![ $( ($p_string, self.$p_name.to_i32()) ),* ]
+            }
         }
     }
 }
@@ -143,7 +206,7 @@ declare_net_parameters! {
 
 /// This structure holds recognized configuration parameters. All values are type-safe,
 /// and where applicable clamped to be within range.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 #[non_exhaustive]
 pub struct NetParameters {
     /// A weighting factor for bandwidth calculations
@@ -275,6 +338,38 @@ pub struct NetParameters {
     /// long, remove it from the consensus.
     pub guard_remove_unlisted_after: IntegerDays<BoundedInt32<1,3650>> = (20)
         from "guard-remove-unlisted-guards-after-days",
+    /// After a primary guard first fails, about how long should we wait
+    /// before we retry it?
+    pub guard_retry_primary_initial: IntegerSeconds<BoundedInt32<1, {i32::MAX}>> = (30)
+        from "guard-retry-primary-initial",
+    /// After a non-primary guard first fails, about how long should we wait
+    /// before we retry it?
+    pub guard_retry_nonprimary_initial: IntegerSeconds<BoundedInt32<1, {i32::MAX}>> = (150)
+        from "guard-retry-nonprimary-initial",
+    /// What is the longest that we should ever wait before we retry a failed
+    /// guard?
+    pub guard_retry_max: IntegerSeconds<BoundedInt32<1, {i32::MAX}>> = (3600)
+        from "guard-retry-max",
+    /// Each time a guard fails again, by what percentage should we multiply
+    /// the time that we wait before we retry it?
+    pub guard_retry_backoff: Percentage<BoundedInt32<100, 1000>> = (200)
+        from "guard-retry-backoff-percent",
+    /// What percentage of each delay before we retry a failed guard should
+    /// be random?
+    pub guard_retry_jitter: Percentage<BoundedInt32<0, 100>> = (50)
+        from "guard-retry-jitter-percent",
+    /// How many attempts to build a circuit may be in flight through one
+    /// primary guard before we prefer another primary guard instead?
+    ///
+    /// This is not a parameter that Tor defines: it is off (0) by default,
+    /// and we only spread our attempts when a consensus enables it.
+    pub guard_max_attempts_in_flight: BoundedInt32<0, {i32::MAX}> = (0)
+        from "guard-max-attempts-in-flight",
+    /// How strongly should clients prefer relays with an IPv6 ORPort, as a
+    /// percentage?  At 0, they have no preference; at 100, they only use
+    /// relays with IPv6.
+    pub ipv6_preference: Percentage<BoundedInt32<0, 100>> = (0)
+        from "client-ipv6-preference-percent",
 
 
     /// The minimum threshold for circuit patch construction
@@ -797,4 +892,22 @@ mod test {
         assert_eq!(p.guard_meaningful_restriction.as_percent().get(), 12);
         assert_eq!(p.guard_extreme_restriction.as_percent().get(), 3);
     }
+
+    #[test]
+    #[cfg(feature = "snapshot")]
+    fn to_values() {
+        let mut p = NetParameters::default();
+        let _ = p.saturating_update(
+            [("cbtquantile", 61), ("nf_ito_low", 1_000)]
+                .iter()
+                .map(|(a, b)| (a, b)),
+        );
+        let values = p.to_values();
+        assert!(values.contains(&("cbtquantile", 61)));
+
+        let mut p2 = NetParameters::default();
+        let ignored = p2.saturating_update(values.iter().map(|(a, b)| (a, b)));
+        assert!(ignored.is_empty());
+        assert_eq!(p, p2);
+    }
 }
@@ -0,0 +1,217 @@
+//! A standard set of rules for choosing the relays on a path.
+//!
+//! Every caller that builds a path through the network needs to apply the
+//! same constraints: no two relays in the same family or subnet, the right
+//! flags for each position, and perhaps some restrictions on countries.  A
+//! [`PathPolicy`] collects those constraints, and [`NetDir::pick_path`]
+//! applies them, so that callers don't have to assemble the checks
+//! themselves.
+
+use tor_netdoc::doc::netstatus::RelayFlags;
+
+#[cfg(feature = "geoip")]
+use tor_geoip::{CountryCode, HasCountryCode};
+
+use crate::{ExitPort, NetDir, PathExclusion, Relay, RelayRole, SubnetConfig, WeightRole};
+
+/// A set of constraints on the relays that make up a path.
+///
+/// Every path that [`NetDir::pick_path`] returns follows these rules:
+///  * The first hop is usable as a [guard](RelayRole::Guard), and every other
+///    hop but the last is usable as a [middle relay](RelayRole::Middle).
+///  * Unless the policy is [internal](PathPolicy::internal), the last hop is
+///    usable as an [exit](RelayRole::Exit) to every port in
+///    [`exit_ports`](PathPolicy::exit_ports).  Otherwise it is usable as a
+///    middle relay.
+///  * No two relays are in the same family, or in the same subnet according
+///    to the policy's [`SubnetConfig`].
+///  * Every relay has all of the [required flags](PathPolicy::require_flags).
+///  * If the `geoip` feature is enabled, no relay is in an
+///    [excluded country](PathPolicy::exclude_countries), and (if
+///    [requested](PathPolicy::distinct_countries)) no two relays are in the
+///    same country.
+#[derive(Clone, Debug)]
+pub struct PathPolicy {
+    /// The configuration to use when deciding whether two relays are in the
+    /// same subnet.
+    subnet_config: SubnetConfig,
+    /// Flags that every relay on the path must have.
+    required_flags: RelayFlags,
+    /// If present, the ports to which the last hop must allow exits.
+    ///
+    /// If absent, the path is internal, and the last hop is a middle relay.
+    exit_ports: Option<Vec<ExitPort>>,
+    /// Countries that no relay on the path may be in.
+    #[cfg(feature = "geoip")]
+    excluded_countries: Vec<CountryCode>,
+    /// If true, no two relays on the path may be in the same country.
+    #[cfg(feature = "geoip")]
+    distinct_countries: bool,
+}
+
+impl Default for PathPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PathPolicy {
+    /// Return a new policy for exit paths, using the default
+    /// [`SubnetConfig`], and with no further restrictions.
+    pub fn new() -> Self {
+        PathPolicy {
+            subnet_config: SubnetConfig::default(),
+            required_flags: RelayFlags::empty(),
+            exit_ports: Some(Vec::new()),
+            #[cfg(feature = "geoip")]
+            excluded_countries: Vec::new(),
+            #[cfg(feature = "geoip")]
+            distinct_countries: false,
+        }
+    }
+
+    /// Use `subnet_config` to decide which relays are in the same subnet.
+    pub fn subnet_config(mut self, subnet_config: SubnetConfig) -> Self {
+        self.subnet_config = subnet_config;
+        self
+    }
+
+    /// Require every relay on the path to have all of `flags`, in addition to
+    /// the flags needed for its position.
+    pub fn require_flags(mut self, flags: RelayFlags) -> Self {
+        self.required_flags |= flags;
+        self
+    }
+
+    /// Require the last hop to allow exits to every port in `ports`.
+    ///
+    /// If `ports` is empty, the last hop must allow exits to at least one
+    /// port.  This undoes any earlier call to
+    /// [`internal`](PathPolicy::internal).
+    pub fn exit_ports(mut self, ports: &[ExitPort]) -> Self {
+        self.exit_ports = Some(ports.to_vec());
+        self
+    }
+
+    /// Make this a policy for internal paths, whose last hop is a middle
+    /// relay rather than an exit.
+    pub fn internal(mut self) -> Self {
+        self.exit_ports = None;
+        self
+    }
+
+    /// Don't allow any relay on the path to be in any of the countries in
+    /// `countries`.
+    ///
+    /// Relays whose country we don't know are excluded too, as with
+    /// [`NetDir::pick_n_relays_excluding_countries`].
+    #[cfg(feature = "geoip")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "geoip")))]
+    pub fn exclude_countries(mut self, countries: &[CountryCode]) -> Self {
+        self.excluded_countries.extend_from_slice(countries);
+        self
+    }
+
+    /// Don't allow two relays on the path to be in the same country.
+    ///
+    /// Relays whose country we don't know are never excluded for this reason.
+    #[cfg(feature = "geoip")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "geoip")))]
+    pub fn distinct_countries(mut self) -> Self {
+        self.distinct_countries = true;
+        self
+    }
+
+    /// Return true if `relay` meets every requirement of this policy that
+    /// doesn't depend on its position or on the other relays in the path.
+    fn permits(&self, relay: &Relay<'_>) -> bool {
+        relay.rs.flags().contains(self.required_flags) && self.country_permitted(relay)
+    }
+
+    /// Return true if `relay` is outside our excluded countries.
+    #[cfg(feature = "geoip")]
+    fn country_permitted(&self, relay: &Relay<'_>) -> bool {
+        self.excluded_countries.is_empty()
+            || relay
+                .country_code()
+                .is_some_and(|cc| !self.excluded_countries.contains(&cc))
+    }
+
+    /// Return true if `relay` is outside our excluded countries.
+    ///
+    /// (Without the `geoip` feature, we never exclude any countries.)
+    #[cfg(not(feature = "geoip"))]
+    fn country_permitted(&self, _relay: &Relay<'_>) -> bool {
+        true
+    }
+
+    /// Return a new [`PathExclusion`] for the relays in `chosen`, according
+    /// to this policy.
+    fn exclusion<'a>(&self, chosen: &[Relay<'a>]) -> PathExclusion<'a> {
+        let exclusion = PathExclusion::new(chosen, &self.subnet_config);
+        #[cfg(feature = "geoip")]
+        let exclusion = if self.distinct_countries {
+            exclusion.exclude_same_country()
+        } else {
+            exclusion
+        };
+        exclusion
+    }
+}
+
+impl NetDir {
+    /// Choose a random path of `n_hops` relays that follows `policy`.
+    ///
+    /// The relays are returned in path order, starting with the guard.  Each
+    /// relay is chosen with probability proportional to its weight for its
+    /// position, as in [`NetDir::pick_relay`].  As in C Tor, we choose the
+    /// last hop first, then the first hop, then the middle hops.
+    ///
+    /// Returns `None` if `n_hops` is less than 2, or if we can't find a relay
+    /// for some position on the path.
+    ///
+    /// This function doesn't know about our guard sample: callers that use
+    /// persistent guards should use this for diagnostics and testing, or
+    /// check the first hop against their own guard selection.
+    pub fn pick_path<'a, R>(
+        &'a self,
+        rng: &mut R,
+        policy: &PathPolicy,
+        n_hops: usize,
+    ) -> Option<Vec<Relay<'a>>>
+    where
+        R: rand::Rng,
+    {
+        if n_hops < 2 {
+            return None;
+        }
+
+        let mut pick = |chosen: &[Relay<'a>], weight_role: WeightRole, role: RelayRole<'_>| {
+            let exclusion = policy.exclusion(chosen);
+            self.pick_relay(rng, weight_role, |r| {
+                r.usable_for(&role) && policy.permits(r) && exclusion.permits(r)
+            })
+        };
+
+        let last = match &policy.exit_ports {
+            Some(ports) => pick(&[], WeightRole::Exit, RelayRole::Exit(ports))?,
+            None => pick(&[], WeightRole::Middle, RelayRole::Middle)?,
+        };
+        let first = pick(
+            std::slice::from_ref(&last),
+            WeightRole::Guard,
+            RelayRole::Guard,
+        )?;
+
+        let mut chosen = vec![last, first];
+        for _ in 2..n_hops {
+            let middle = pick(&chosen, WeightRole::Middle, RelayRole::Middle)?;
+            chosen.push(middle);
+        }
+
+        // `chosen` is [last, first, middle...]: put it in path order.
+        let last = chosen.remove(0);
+        chosen.push(last);
+        Some(chosen)
+    }
+}
@@ -0,0 +1,321 @@
+//! Precomputed bitmaps of the ports that relays' exit policies allow.
+//!
+//! Checking a port against a [`PortPolicy`] requires a binary search over its
+//! ranges, and that shows up when we're choosing exits.  But relays share a
+//! fairly small number of distinct (interned) policies, so as microdescriptors
+//! arrive we compute a 65536-bit [`PortCoverage`] for each distinct policy, and
+//! give each relay a reference to the coverage for its policies.  Checking a
+//! port is then a single bit test, and coverages can be combined to answer
+//! questions about whole paths.
+//!
+//! We also keep an [`ExitIndex`] from each distinct policy to the relays that
+//! have it, so that finding every exit for a port only needs one bit test per
+//! distinct policy, rather than one per relay.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::Arc;
+
+use bitvec::prelude::*;
+use tor_netdoc::doc::microdesc::Microdesc;
+use tor_netdoc::types::policy::PortPolicy;
+
+use crate::RouterStatusIdx;
+
+/// The number of bits in a `PortCoverage`: one for every possible port.
+const N_PORTS: usize = 1 << 16;
+
+/// A bitmap of the ports that a policy allows.
+///
+/// Port 0 is never allowed.
+///
+/// Returned by
+/// [`RelayDetails::ipv4_port_coverage`](crate::details::RelayDetails::ipv4_port_coverage)
+/// and
+/// [`RelayDetails::ipv6_port_coverage`](crate::details::RelayDetails::ipv6_port_coverage).
+#[derive(Clone, Eq, PartialEq)]
+pub struct PortCoverage {
+    /// One bit per port, set if the port is allowed.
+    bits: BitVec,
+}
+
+impl PortCoverage {
+    /// Return a new `PortCoverage` that allows no ports.
+    pub fn new_reject_all() -> Self {
+        PortCoverage {
+            bits: bitvec![0; N_PORTS],
+        }
+    }
+
+    /// Return a new `PortCoverage` that allows every port (except 0).
+    pub fn new_accept_all() -> Self {
+        let mut bits = bitvec![1; N_PORTS];
+        bits.set(0, false);
+        PortCoverage { bits }
+    }
+
+    /// Return a new `PortCoverage` that allows exactly the ports that `policy`
+    /// allows.
+    pub fn from_policy(policy: &PortPolicy) -> Self {
+        let mut bits = bitvec![0; N_PORTS];
+        for range in policy.allowed_ranges() {
+            bits[usize::from(range.lo)..=usize::from(range.hi)].fill(true);
+        }
+        PortCoverage { bits }
+    }
+
+    /// Return true if this coverage allows `port`.
+    pub fn allows_port(&self, port: u16) -> bool {
+        self.bits[usize::from(port)]
+    }
+
+    /// Return true if this coverage allows any port at all.
+    pub fn allows_some_port(&self) -> bool {
+        self.bits.any()
+    }
+
+    /// Return the number of ports that this coverage allows.
+    pub fn n_allowed_ports(&self) -> usize {
+        self.bits.count_ones()
+    }
+
+    /// Restrict this coverage to the ports that are also allowed by `other`.
+    pub fn intersect_with(&mut self, other: &PortCoverage) {
+        self.bits &= other.bits.as_bitslice();
+    }
+
+    /// Extend this coverage to include the ports that are allowed by `other`.
+    pub fn union_with(&mut self, other: &PortCoverage) {
+        self.bits |= other.bits.as_bitslice();
+    }
+
+    /// Return a coverage of the ports allowed by every member of `coverages`.
+    ///
+    /// If `coverages` is empty, every port (except 0) is allowed.
+    pub fn intersection<'a, I>(coverages: I) -> Self
+    where
+        I: IntoIterator<Item = &'a PortCoverage>,
+    {
+        let mut result = PortCoverage::new_accept_all();
+        for c in coverages {
+            result.intersect_with(c);
+        }
+        result
+    }
+}
+
+impl fmt::Debug for PortCoverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PortCoverage")
+            .field("n_allowed_ports", &self.n_allowed_ports())
+            .finish_non_exhaustive()
+    }
+}
+
+/// The port coverage of a single relay's exit policies.
+#[derive(Clone, Debug)]
+pub(crate) struct ExitCoverage {
+    /// The ports to which this relay allows exiting on IPv4.
+    pub(crate) ipv4: Arc<PortCoverage>,
+    /// The ports to which this relay allows exiting on IPv6.
+    pub(crate) ipv6: Arc<PortCoverage>,
+}
+
+/// A cache of the `PortCoverage` for each distinct policy that we have seen.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CoverageCache {
+    /// Map from each policy to its coverage.
+    by_policy: HashMap<Arc<PortPolicy>, Arc<PortCoverage>>,
+}
+
+impl CoverageCache {
+    /// Return the coverage for `policy`, computing it if necessary.
+    fn coverage(&mut self, policy: &Arc<PortPolicy>) -> Arc<PortCoverage> {
+        Arc::clone(
+            self.by_policy
+                .entry(Arc::clone(policy))
+                .or_insert_with(|| Arc::new(PortCoverage::from_policy(policy))),
+        )
+    }
+
+    /// Return the coverage for a relay with the microdescriptor `md`.
+    ///
+    /// If `bad_exit` is true, the relay is flagged `BadExit`, and we treat its
+    /// policies as rejecting every port.
+    pub(crate) fn exit_coverage(&mut self, md: &Microdesc, bad_exit: bool) -> ExitCoverage {
+        if bad_exit {
+            let reject_all = self.coverage(&Arc::new(PortPolicy::new_reject_all()));
+            ExitCoverage {
+                ipv4: Arc::clone(&reject_all),
+                ipv6: reject_all,
+            }
+        } else {
+            ExitCoverage {
+                ipv4: self.coverage(md.ipv4_policy()),
+                ipv6: self.coverage(md.ipv6_policy()),
+            }
+        }
+    }
+}
+
+/// The relays that share a single exit policy.
+#[derive(Clone, Debug)]
+struct PolicyGroup {
+    /// The ports that the policy allows.
+    coverage: Arc<PortCoverage>,
+    /// The relays that have the policy.
+    members: BTreeSet<RouterStatusIdx>,
+}
+
+/// A map from each distinct exit policy to the relays that have it.
+type PolicyGroups = HashMap<Arc<PortPolicy>, PolicyGroup>;
+
+/// An index of the relays that allow exiting to any port, grouped by their
+/// exit policies.
+///
+/// Relays whose policies allow no ports (including every relay flagged
+/// `BadExit`) are not listed.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ExitIndex {
+    /// Groups of relays by their IPv4 exit policy.
+    ipv4: PolicyGroups,
+    /// Groups of relays by their IPv6 exit policy.
+    ipv6: PolicyGroups,
+}
+
+impl ExitIndex {
+    /// Record that the relay at `rsidx` has the microdescriptor `md`, and the
+    /// exit coverage `coverage`.
+    pub(crate) fn insert(
+        &mut self,
+        rsidx: RouterStatusIdx,
+        md: &Microdesc,
+        coverage: &ExitCoverage,
+    ) {
+        for (groups, policy, coverage) in [
+            (&mut self.ipv4, md.ipv4_policy(), &coverage.ipv4),
+            (&mut self.ipv6, md.ipv6_policy(), &coverage.ipv6),
+        ] {
+            if coverage.allows_some_port() {
+                groups
+                    .entry(Arc::clone(policy))
+                    .or_insert_with(|| PolicyGroup {
+                        coverage: Arc::clone(coverage),
+                        members: BTreeSet::new(),
+                    })
+                    .members
+                    .insert(rsidx);
+            }
+        }
+    }
+
+    /// Record that the relay at `rsidx`, which had the microdescriptor `md`,
+    /// is no longer in use.
+    pub(crate) fn remove(&mut self, rsidx: RouterStatusIdx, md: &Microdesc) {
+        for (groups, policy) in [
+            (&mut self.ipv4, md.ipv4_policy()),
+            (&mut self.ipv6, md.ipv6_policy()),
+        ] {
+            if let Some(group) = groups.get_mut(policy) {
+                group.members.remove(&rsidx);
+                if group.members.is_empty() {
+                    groups.remove(policy);
+                }
+            }
+        }
+    }
+
+    /// Return the relays that allow exiting to `port` on IPv4 (if `ipv4` is
+    /// true) or on IPv6 (if `ipv6` is true), in routerstatus order.
+    pub(crate) fn relays_allowing(
+        &self,
+        port: u16,
+        ipv4: bool,
+        ipv6: bool,
+    ) -> BTreeSet<RouterStatusIdx> {
+        let families = [(ipv4, &self.ipv4), (ipv6, &self.ipv6)];
+        families
+            .into_iter()
+            .filter(|(wanted, _)| *wanted)
+            .flat_map(|(_, groups)| groups.values())
+            .filter(|group| group.coverage.allows_port(port))
+            .flat_map(|group| group.members.iter().copied())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    fn cov(s: &str) -> PortCoverage {
+        PortCoverage::from_policy(&s.parse().unwrap())
+    }
+
+    #[test]
+    fn matches_policy() {
+        for s in [
+            "accept 80,443",
+            "accept 1-65535",
+            "reject 1-65535",
+            "reject 1-1023,6667",
+            "accept 22,80-90,65535",
+        ] {
+            let policy: PortPolicy = s.parse().unwrap();
+            let c = PortCoverage::from_policy(&policy);
+            for port in 0..=u16::MAX {
+                assert_eq!(c.allows_port(port), policy.allows_port(port), "{s} {port}");
+            }
+            assert_eq!(c.allows_some_port(), policy.allows_some_port());
+        }
+    }
+
+    #[test]
+    fn combining() {
+        let web = cov("accept 80,443");
+        let high = cov("reject 1-1023");
+        assert_eq!(web.n_allowed_ports(), 2);
+        assert_eq!(high.n_allowed_ports(), 65535 - 1023);
+
+        let both = PortCoverage::intersection([&web, &cov("accept 443-500")]);
+        assert_eq!(both.n_allowed_ports(), 1);
+        assert!(both.allows_port(443));
+
+        let mut either = web.clone();
+        either.union_with(&high);
+        assert_eq!(either.n_allowed_ports(), 65535 - 1023 + 2);
+
+        let mut none = web.clone();
+        none.intersect_with(&high);
+        assert!(!none.allows_some_port());
+        assert_eq!(none, PortCoverage::new_reject_all());
+
+        let all = PortCoverage::intersection([]);
+        assert_eq!(all, cov("accept 1-65535"));
+        assert!(!all.allows_port(0));
+    }
+
+    #[test]
+    fn cache_shares() {
+        let mut cache = CoverageCache::default();
+        let p1: Arc<PortPolicy> = "accept 80".parse::<PortPolicy>().unwrap().intern();
+        let p2: Arc<PortPolicy> = "accept 80".parse::<PortPolicy>().unwrap().intern();
+        let c1 = cache.coverage(&p1);
+        let c2 = cache.coverage(&p2);
+        assert!(Arc::ptr_eq(&c1, &c2));
+        assert_eq!(cache.by_policy.len(), 1);
+    }
+}
@@ -0,0 +1,192 @@
+//! Queries about which relays support which subprotocol versions.
+//!
+//! Before we start using a new protocol feature (such as a new circuit
+//! handshake), we often need to know how much of the network supports it.
+//! These helpers answer that from the `pr` lines in the consensus, so that
+//! callers don't need to look at each relay's protocol list themselves.
+
+use std::collections::HashMap;
+
+use tor_protover::{ProtoKind, Protocols};
+
+use crate::{NetDir, Relay};
+
+/// A summary of the subprotocol versions that the usable relays in a
+/// [`NetDir`] support.
+///
+/// Returned by [`NetDir::protocol_support_summary`].  This is a snapshot: it
+/// doesn't change when the directory does.
+#[derive(Clone, Debug, Default)]
+pub struct ProtocolSupportSummary {
+    /// The number of usable relays.
+    n_relays: usize,
+    /// The total bandwidth weight of the usable relays.
+    total_weight: u64,
+    /// For each distinct protocol list among the usable relays, the number
+    /// of relays that declare it, and their total bandwidth weight.
+    ///
+    /// (Relays running the same version of Tor have the same list, so there
+    /// are only a few dozen of these.)
+    by_protocols: Vec<(Protocols, usize, u64)>,
+}
+
+impl ProtocolSupportSummary {
+    /// Return the number of usable relays.
+    pub fn n_relays(&self) -> usize {
+        self.n_relays
+    }
+
+    /// Return the number of usable relays that support version `version` of
+    /// `protocol`.
+    pub fn n_supporting(&self, protocol: ProtoKind, version: u8) -> usize {
+        self.supporting(protocol, version).map(|(n, _)| n).sum()
+    }
+
+    /// Return the fraction of usable relays that support version `version`
+    /// of `protocol`.
+    ///
+    /// Returns 0.0 if there are no usable relays.
+    pub fn fraction_supporting(&self, protocol: ProtoKind, version: u8) -> f64 {
+        fraction(
+            self.n_supporting(protocol, version) as u64,
+            self.n_relays as u64,
+        )
+    }
+
+    /// Return the fraction of the usable relays' total bandwidth weight that
+    /// belongs to relays supporting version `version` of `protocol`.
+    ///
+    /// This is usually a better guide than
+    /// [`fraction_supporting`](ProtocolSupportSummary::fraction_supporting)
+    /// to how often we'd be able to use a feature, since we choose relays in
+    /// proportion to their weight.
+    ///
+    /// Returns 0.0 if the usable relays have no weight.
+    pub fn weight_fraction_supporting(&self, protocol: ProtoKind, version: u8) -> f64 {
+        let weight = self.supporting(protocol, version).map(|(_, w)| w).sum();
+        fraction(weight, self.total_weight)
+    }
+
+    /// Helper: return the relay count and weight for each protocol list that
+    /// includes version `version` of `protocol`.
+    fn supporting(
+        &self,
+        protocol: ProtoKind,
+        version: u8,
+    ) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.by_protocols
+            .iter()
+            .filter(move |(protos, _, _)| protos.supports_known_subver(protocol, version))
+            .map(|(_, n, w)| (*n, *w))
+    }
+}
+
+/// Return `part / whole`, or 0.0 if `whole` is zero.
+fn fraction(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 / whole as f64
+    }
+}
+
+impl NetDir {
+    /// Return an iterator over every [usable](NetDir#usable) relay that
+    /// supports version `version` of `protocol`.
+    pub fn relays_supporting<'a>(
+        &'a self,
+        protocol: ProtoKind,
+        version: u8,
+    ) -> impl Iterator<Item = Relay<'a>> + 'a {
+        self.relays()
+            .filter(move |relay| relay.supports(protocol, version))
+    }
+
+    /// Return a summary of the subprotocol versions that the
+    /// [usable](NetDir#usable) relays in this directory support.
+    ///
+    /// Use this to decide whether enough of the network supports a feature
+    /// for us to start using it.
+    pub fn protocol_support_summary(&self) -> ProtocolSupportSummary {
+        let mut by_protocols: HashMap<&Protocols, (usize, u64)> = HashMap::new();
+        let mut summary = ProtocolSupportSummary::default();
+        for relay in self.relays() {
+            let weight = u64::from(self.weights.bandwidth_of(relay.rs));
+            let entry = by_protocols.entry(relay.rs.protovers()).or_default();
+            entry.0 += 1;
+            entry.1 += weight;
+            summary.n_relays += 1;
+            summary.total_weight += weight;
+        }
+        summary.by_protocols = by_protocols
+            .into_iter()
+            .map(|(protos, (n, w))| (protos.clone(), n, w))
+            .collect();
+        summary
+    }
+}
+
+impl<'a> Relay<'a> {
+    /// Return true if this relay supports version `version` of `protocol`,
+    /// according to the consensus.
+    pub fn supports(&self, protocol: ProtoKind, version: u8) -> bool {
+        self.rs.protovers().supports_known_subver(protocol, version)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::testnet;
+    use float_eq::assert_float_eq;
+
+    #[test]
+    fn protocol_support() {
+        // Even-numbered relays support DirCache=2; the others support nothing.
+        let dir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+
+        let dircaches: Vec<_> = dir.relays_supporting(ProtoKind::DirCache, 2).collect();
+        assert_eq!(dircaches.len(), 20);
+        assert!(dircaches.iter().all(|r| r.rsa_id().as_bytes()[0] % 2 == 0));
+        assert!(dircaches[0].supports(ProtoKind::DirCache, 2));
+        assert!(!dircaches[0].supports(ProtoKind::DirCache, 1));
+        assert_eq!(dir.relays_supporting(ProtoKind::Relay, 2).count(), 0);
+
+        let summary = dir.protocol_support_summary();
+        assert_eq!(summary.n_relays(), 40);
+        assert_eq!(summary.n_supporting(ProtoKind::DirCache, 2), 20);
+        assert_eq!(summary.n_supporting(ProtoKind::Relay, 2), 0);
+        assert_float_eq!(
+            summary.fraction_supporting(ProtoKind::DirCache, 2),
+            0.5,
+            abs <= 1e-9
+        );
+        // In each group of ten relays, the even-numbered ones have weights
+        // 1, 3, 5, 7, and 9 (thousand), out of 1 through 10.
+        assert_float_eq!(
+            summary.weight_fraction_supporting(ProtoKind::DirCache, 2),
+            25.0 / 55.0,
+            abs <= 1e-9
+        );
+
+        let empty = ProtocolSupportSummary::default();
+        assert_eq!(empty.fraction_supporting(ProtoKind::DirCache, 2), 0.0);
+        assert_eq!(
+            empty.weight_fraction_supporting(ProtoKind::DirCache, 2),
+            0.0
+        );
+    }
+}
@@ -0,0 +1,127 @@
+//! Running statistics about the usable relays in a [`NetDir`](crate::NetDir).
+//!
+//! A relay becomes usable when we get its microdescriptor, so while we are
+//! bootstrapping, the set of usable relays grows with each batch of
+//! microdescriptors.  Rather than making callers who want to report on our
+//! progress scan the whole directory each time, we keep these statistics up
+//! to date as microdescriptors arrive (or are discarded).
+
+use std::collections::HashMap;
+
+#[cfg(feature = "geoip")]
+use tor_geoip::CountryCode;
+use tor_netdoc::doc::netstatus::RelayFlags;
+
+use crate::{RelayFlagQuery, UncheckedRelay};
+
+/// A summary of the usable relays in a [`NetDir`](crate::NetDir).
+///
+/// Returned by [`NetDir::usable_relay_stats`](crate::NetDir::usable_relay_stats)
+/// and [`PartialNetDir::usable_relay_stats`](crate::PartialNetDir::usable_relay_stats).
+/// Each of these is a snapshot: it doesn't change when the directory does.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct UsableRelayStats {
+    /// The number of usable relays.
+    n_usable: usize,
+    /// The number of usable relays with each distinct combination of flags,
+    /// indexed by the bits of that combination.
+    ///
+    /// (There are only a few dozen distinct combinations in practice, so this
+    /// is a lot smaller than a count per relay.)
+    by_flags: HashMap<u16, usize>,
+    /// The number of usable relays in each country, or with no known
+    /// country.
+    #[cfg(feature = "geoip")]
+    by_country: HashMap<Option<CountryCode>, usize>,
+}
+
+/// The properties of a single relay that we keep statistics on.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct StatsKey {
+    /// The relay's flags.
+    flags: RelayFlags,
+    /// The relay's country, if we know it.
+    #[cfg(feature = "geoip")]
+    cc: Option<CountryCode>,
+}
+
+impl StatsKey {
+    /// Return the key for `relay`.
+    pub(crate) fn for_relay(relay: &UncheckedRelay<'_>) -> Self {
+        StatsKey {
+            flags: *relay.rs.flags(),
+            #[cfg(feature = "geoip")]
+            cc: relay.cc,
+        }
+    }
+}
+
+impl UsableRelayStats {
+    /// Return the number of usable relays.
+    pub fn n_usable(&self) -> usize {
+        self.n_usable
+    }
+
+    /// Return the number of usable relays whose flags match `query`.
+    pub fn n_with_flags(&self, query: &RelayFlagQuery) -> usize {
+        self.by_flags
+            .iter()
+            .filter(|(bits, _)| query.matches(RelayFlags::from_bits_truncate(**bits)))
+            .map(|(_, n)| n)
+            .sum()
+    }
+
+    /// Return the number of usable relays in the country `cc`.
+    #[cfg(feature = "geoip")]
+    pub fn n_in_country(&self, cc: CountryCode) -> usize {
+        self.by_country.get(&Some(cc)).copied().unwrap_or(0)
+    }
+
+    /// Return the number of usable relays whose country we don't know.
+    ///
+    /// This is every usable relay if the directory was built without a GeoIP
+    /// database.
+    #[cfg(feature = "geoip")]
+    pub fn n_without_country(&self) -> usize {
+        self.by_country.get(&None).copied().unwrap_or(0)
+    }
+
+    /// Return an iterator over every country that has a usable relay, along
+    /// with the number of usable relays there.
+    ///
+    /// The countries are in no particular order.
+    #[cfg(feature = "geoip")]
+    pub fn countries(&self) -> impl Iterator<Item = (CountryCode, usize)> + '_ {
+        self.by_country
+            .iter()
+            .filter_map(|(cc, n)| Some(((*cc)?, *n)))
+    }
+
+    /// Record that a relay with `key` has become usable.
+    pub(crate) fn add(&mut self, key: StatsKey) {
+        self.n_usable += 1;
+        *self.by_flags.entry(key.flags.bits()).or_default() += 1;
+        #[cfg(feature = "geoip")]
+        {
+            *self.by_country.entry(key.cc).or_default() += 1;
+        }
+    }
+
+    /// Record that a relay with `key` is no longer usable.
+    pub(crate) fn remove(&mut self, key: StatsKey) {
+        /// Decrement the count for `k` in `map`, removing it if it reaches
+        /// zero.
+        fn decrement<K: std::hash::Hash + Eq>(map: &mut HashMap<K, usize>, k: &K) {
+            if let Some(n) = map.get_mut(k) {
+                *n = n.saturating_sub(1);
+                if *n == 0 {
+                    map.remove(k);
+                }
+            }
+        }
+        self.n_usable = self.n_usable.saturating_sub(1);
+        decrement(&mut self.by_flags, &key.flags.bits());
+        #[cfg(feature = "geoip")]
+        decrement(&mut self.by_country, &key.cc);
+    }
+}
@@ -0,0 +1,133 @@
+//! Definitions for the roles that a relay can play in the Tor network,
+//! and the requirements for each.
+//!
+//! Different crates need to decide whether a relay is suitable as a guard, an
+//! exit, an introduction point, and so on.  We keep those decisions here, in
+//! one place, so that they can't drift apart.
+
+use tor_netdoc::doc::netstatus;
+
+use crate::Relay;
+
+/// A role that a [`Relay`] might play on a circuit, or in the network.
+///
+/// Use [`Relay::usable_for`] to check whether a relay meets the requirements
+/// for a given role.
+///
+/// These requirements are the ones that follow from the relay's flags,
+/// protocol versions, and policies alone.  Higher-level code (like the
+/// `tor-relay-selection` crate) may impose further requirements depending on
+/// its configuration, such as requiring the `Stable` flag for long-lived
+/// ports.
+///
+/// We do not check for protocol versions that every relay is required to
+/// support according to the consensus.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum RelayRole<'a> {
+    /// A guard: the first hop of a multi-hop circuit.
+    ///
+    /// Guards need the `Guard`, `Fast`, and `Stable` flags,
+    /// and must be directory caches.
+    Guard,
+    /// A middle relay on a multi-hop circuit.
+    ///
+    /// Middle relays need the `Fast` flag.
+    Middle,
+    /// The last hop of an exit circuit, exiting to every port in the list.
+    ///
+    /// Exits need the `Fast` flag, must not be flagged as `BadExit`, and must
+    /// have a policy that allows every listed port. If the list is empty, the
+    /// policy must allow at least one port.
+    Exit(&'a [ExitPort]),
+    /// An onion service directory.
+    ///
+    /// Onion service directories need the `HSDir` flag.
+    HsDir,
+    /// An onion service introduction point.
+    ///
+    /// Introduction points need the `Fast` and `Stable` flags.
+    IntroPoint,
+    /// An onion service rendezvous point.
+    ///
+    /// Rendezvous points need the `Fast` and `Stable` flags.
+    RendPoint,
+    /// A directory cache, used for one-hop directory requests.
+    ///
+    /// Directory caches need the `V2Dir` flag, and must support the
+    /// `DirCache=2` protocol.
+    DirCache,
+}
+
+/// A port (and address family) to which an exit must allow connections.
+///
+/// Used with [`RelayRole::Exit`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ExitPort {
+    /// True if we're connecting to an IPv6 address.
+    ipv6: bool,
+    /// The port we're connecting to.
+    port: u16,
+}
+
+impl ExitPort {
+    /// Return an `ExitPort` for connections to `port` on an IPv4 address.
+    pub fn ipv4(port: u16) -> Self {
+        ExitPort { ipv6: false, port }
+    }
+    /// Return an `ExitPort` for connections to `port` on an IPv6 address.
+    pub fn ipv6(port: u16) -> Self {
+        ExitPort { ipv6: true, port }
+    }
+    /// Return the port number for this `ExitPort`.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+    /// Return true if this `ExitPort` is for an IPv6 address.
+    pub fn is_ipv6(&self) -> bool {
+        self.ipv6
+    }
+}
+
+impl<'a> Relay<'a> {
+    /// Return true if this relay meets the requirements to play `role`.
+    ///
+    /// See [`RelayRole`] for the requirements of each role.
+    pub fn usable_for(&self, role: &RelayRole<'_>) -> bool {
+        let rs = self.rs;
+        match role {
+            RelayRole::Guard => {
+                rs.is_flagged_guard()
+                    && rs.is_flagged_fast()
+                    && rs.is_flagged_stable()
+                    && rs_is_dir_cache(rs)
+            }
+            RelayRole::Middle => rs.is_flagged_fast(),
+            RelayRole::Exit(ports) => {
+                if !rs.is_flagged_fast() || rs.is_flagged_bad_exit() {
+                    return false;
+                }
+                let (v4, v6) = (&self.coverage.ipv4, &self.coverage.ipv6);
+                if ports.is_empty() {
+                    v4.allows_some_port() || v6.allows_some_port()
+                } else {
+                    ports.iter().all(|p| {
+                        let coverage = if p.ipv6 { v6 } else { v4 };
+                        coverage.allows_port(p.port)
+                    })
+                }
+            }
+            RelayRole::HsDir => rs.is_flagged_hsdir(),
+            RelayRole::IntroPoint | RelayRole::RendPoint => {
+                rs.is_flagged_fast() && rs.is_flagged_stable()
+            }
+            RelayRole::DirCache => rs_is_dir_cache(rs),
+        }
+    }
+}
+
+/// Return true if `rs` is usable as a directory cache.
+pub(crate) fn rs_is_dir_cache(rs: &netstatus::MdConsensusRouterStatus) -> bool {
+    use tor_protover::ProtoKind;
+    rs.is_flagged_v2dir() && rs.protovers().supports_known_subver(ProtoKind::DirCache, 2)
+}
@@ -0,0 +1,179 @@
+//! Looking up relays by the names that people use for them.
+//!
+//! Controllers and other user-facing tools let people name a relay by its
+//! nickname or by (part of) one of its identities, as C Tor's
+//! `GETINFO ns/name/...` does.  [`NetDir::search`] resolves such a name
+//! against every relay in the consensus.
+
+use tor_linkspec::HasRelayIds;
+
+use crate::{NetDir, UncheckedRelay};
+
+/// A parsed query for [`NetDir::search`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum SearchQuery<'q> {
+    /// A prefix of an RSA identity fingerprint, possibly with a nickname
+    /// that the relay must also have.
+    Fingerprint {
+        /// The prefix, as `$` and lowercase hex, to match the `Display`
+        /// format of `RsaIdentity`.
+        prefix: String,
+        /// The nickname, if one was given.
+        nickname: Option<&'q str>,
+    },
+    /// Either a nickname, or a prefix of a base64-encoded Ed25519 identity.
+    NameOrEd25519(&'q str),
+}
+
+impl<'q> SearchQuery<'q> {
+    /// Parse `query`, or return None if it can't match any relay.
+    fn parse(query: &'q str) -> Option<Self> {
+        let query = query.trim();
+        if let Some(fp) = query.strip_prefix('$') {
+            // As in C Tor, a fingerprint may be followed by `~nickname` or
+            // (for the long-obsolete Named flag) `=nickname`; we treat both
+            // the same way.
+            let (prefix, nickname) = match fp.split_once(['~', '=']) {
+                Some((prefix, nickname)) => (prefix, Some(nickname)),
+                None => (fp, None),
+            };
+            let valid = !prefix.is_empty()
+                && prefix.len() <= tor_llcrypto::pk::rsa::RSA_ID_LEN * 2
+                && prefix.chars().all(|c| c.is_ascii_hexdigit());
+            valid.then(|| SearchQuery::Fingerprint {
+                prefix: format!("${}", prefix.to_ascii_lowercase()),
+                nickname,
+            })
+        } else {
+            (!query.is_empty()).then_some(SearchQuery::NameOrEd25519(query))
+        }
+    }
+
+    /// Return true if `relay` matches this query.
+    fn matches(&self, relay: &UncheckedRelay<'_>) -> bool {
+        let nickname = relay.rs.nickname();
+        match self {
+            SearchQuery::Fingerprint {
+                prefix,
+                nickname: wanted,
+            } => {
+                relay
+                    .rsa_identity()
+                    .is_some_and(|id| id.to_string().starts_with(prefix.as_str()))
+                    && wanted.map_or(true, |w| w.eq_ignore_ascii_case(nickname))
+            }
+            SearchQuery::NameOrEd25519(q) => {
+                q.eq_ignore_ascii_case(nickname)
+                    || relay
+                        .ed_identity()
+                        .is_some_and(|ed| ed.to_string().starts_with(q))
+            }
+        }
+    }
+}
+
+impl NetDir {
+    /// Return every relay in the consensus that matches `query`, in consensus
+    /// order.
+    ///
+    /// `query` can be:
+    ///   * A nickname, which we compare case-insensitively.  (Nicknames are
+    ///     not unique, so this can return many relays.)
+    ///   * `$` followed by a prefix of a relay's RSA identity fingerprint in
+    ///     hex, optionally followed by `~` (or `=`) and a nickname that the
+    ///     relay must also have, as in `$0123ABCD~nickname`.
+    ///   * A prefix of a relay's Ed25519 identity, in unpadded base64.
+    ///
+    /// A query without a `$` is tried both as a nickname and as an Ed25519
+    /// prefix.  An empty or malformed query matches nothing.
+    ///
+    /// Unlike [`by_id`](NetDir::by_id), this includes relays that aren't
+    /// [usable](NetDir#usable); use [`UncheckedRelay::into_relay`] to find
+    /// out whether each one is.
+    pub fn search(&self, query: &str) -> Vec<UncheckedRelay<'_>> {
+        let Some(query) = SearchQuery::parse(query) else {
+            return Vec::new();
+        };
+        self.all_relays().filter(|r| query.matches(r)).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::testnet::construct_custom_netdir;
+    use tor_llcrypto::pk::ed25519::Ed25519Identity;
+    use tor_llcrypto::pk::rsa::RsaIdentity;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            SearchQuery::parse(" $ABcd~Foo "),
+            Some(SearchQuery::Fingerprint {
+                prefix: "$abcd".into(),
+                nickname: Some("Foo"),
+            })
+        );
+        assert_eq!(
+            SearchQuery::parse("moria1"),
+            Some(SearchQuery::NameOrEd25519("moria1"))
+        );
+        assert_eq!(SearchQuery::parse(""), None);
+        assert_eq!(SearchQuery::parse("$"), None);
+        assert_eq!(SearchQuery::parse("$xyz"), None);
+        assert_eq!(SearchQuery::parse(&format!("${}", "0".repeat(41))), None);
+    }
+
+    #[test]
+    fn search() {
+        // Even-numbered relays are all called "Even"; the others have unique
+        // names.
+        let netdir = construct_custom_netdir(|pos, nb, _| {
+            let name = if pos % 2 == 0 {
+                "Even".to_string()
+            } else {
+                format!("relay{}", pos)
+            };
+            nb.rs.nickname(name);
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+        let rsa_ids = |relays: Vec<UncheckedRelay<'_>>| -> Vec<RsaIdentity> {
+            relays.iter().map(|r| *r.rsa_identity().unwrap()).collect()
+        };
+
+        assert_eq!(netdir.search("even").len(), 20);
+        assert_eq!(rsa_ids(netdir.search("RELAY3")), vec![[3; 20].into()]);
+        assert!(netdir.search("nobody").is_empty());
+
+        // Relay identities are `[idx; 20]`, so `$1` matches 0x10 through
+        // 0x1f.
+        let ones = rsa_ids(netdir.search("$1"));
+        let expected: Vec<RsaIdentity> = (0x10..0x20_u8).map(|i| [i; 20].into()).collect();
+        assert_eq!(ones, expected);
+        assert_eq!(rsa_ids(netdir.search("$12")), vec![[0x12; 20].into()]);
+        assert_eq!(
+            rsa_ids(netdir.search(&format!("{}", RsaIdentity::from([0x21; 20])))),
+            vec![[0x21; 20].into()]
+        );
+        assert_eq!(rsa_ids(netdir.search("$12~even")), vec![[0x12; 20].into()]);
+        assert!(netdir.search("$13~even").is_empty());
+
+        let ed = Ed25519Identity::from([7; 32]).to_string();
+        assert_eq!(rsa_ids(netdir.search(&ed[..10])), vec![[7; 20].into()]);
+    }
+}
@@ -0,0 +1,267 @@
+//! Reproducible relay selection, for simulations.
+//!
+//! [`NetDir::pick_relay`] and [`NetDir::pick_n_relays`] take any RNG, but
+//! even with a seeded one, their results can change between releases: they
+//! rely on the sampling algorithms in the `rand` crate, and `pick_n_relays`
+//! shuffles its output.  Simulation frameworks need the same seed to give the
+//! same relays every time.  A [`SelectionSession`] makes weighted choices with
+//! its own, fixed algorithm, driven by a ChaCha20 stream from the caller's
+//! seed, so its results depend only on the seed, the directory, and the
+//! sequence of calls.
+
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+
+use crate::{NetDir, Relay, WeightRole};
+
+/// The weights with which a [`NetDir`] would choose among a set of relays
+/// for some role.
+///
+/// Returned by [`NetDir::weight_table`].  This is a snapshot: it doesn't
+/// change when the directory does.
+#[derive(Clone, Debug)]
+pub struct WeightTable {
+    /// The role that these weights are for.
+    role: WeightRole,
+    /// Each relay's identity and weight, in consensus order.
+    entries: Vec<(RsaIdentity, u64)>,
+    /// The sum of the weights in `entries`.
+    total: u64,
+}
+
+impl WeightTable {
+    /// Return the role that these weights are for.
+    pub fn role(&self) -> WeightRole {
+        self.role
+    }
+
+    /// Return the identity and weight of every relay in the table, in
+    /// consensus order.
+    ///
+    /// Relays with zero weight are included, though we never choose them.
+    pub fn entries(&self) -> &[(RsaIdentity, u64)] {
+        &self.entries[..]
+    }
+
+    /// Return the total weight of the relays in the table.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+/// A sequence of reproducible, weighted relay selections from a [`NetDir`].
+///
+/// Returned by [`NetDir::selection_session`].  Given the same seed and the
+/// same directory, the same sequence of calls always returns the same relays,
+/// with this version of this crate and (unless its semver notes say
+/// otherwise) with any later one.  Each choice uses exactly the weights that
+/// [`NetDir::weight_table`] reports for the same role and predicate.
+///
+/// This is meant for simulation and testing.  For choosing relays to use on
+/// the real network, use [`NetDir::pick_relay`] with a secure RNG.
+pub struct SelectionSession<'a> {
+    /// The directory that we're choosing relays from.
+    netdir: &'a NetDir,
+    /// The source of our randomness.
+    rng: ChaCha20Rng,
+}
+
+impl<'a> SelectionSession<'a> {
+    /// Choose a relay at random, as [`NetDir::pick_relay`] does.
+    ///
+    /// Returns None if (and only if) there are no relays with nonzero weight
+    /// for which `usable` returns true.
+    pub fn pick_relay<P>(&mut self, role: WeightRole, usable: P) -> Option<Relay<'a>>
+    where
+        P: FnMut(&Relay<'a>) -> bool,
+    {
+        let candidates = self.netdir.weighted_relays(role, usable);
+        let total = candidates.iter().map(|(_, w)| w).sum();
+        let idx = self.choose_index(&candidates, total)?;
+        Some(candidates[idx].0.clone())
+    }
+
+    /// Choose up to `n` distinct relays at random, as
+    /// [`NetDir::pick_n_relays`] does.
+    ///
+    /// The relays are returned in the order in which we chose them.  The
+    /// result is shorter than `n` if there are fewer than `n` relays with
+    /// nonzero weight for which `usable` returns true.
+    pub fn pick_n_relays<P>(&mut self, n: usize, role: WeightRole, usable: P) -> Vec<Relay<'a>>
+    where
+        P: FnMut(&Relay<'a>) -> bool,
+    {
+        let mut candidates = self.netdir.weighted_relays(role, usable);
+        let mut total: u64 = candidates.iter().map(|(_, w)| w).sum();
+        let mut chosen = Vec::with_capacity(n);
+        while chosen.len() < n {
+            let Some(idx) = self.choose_index(&candidates, total) else {
+                break;
+            };
+            // (We use `remove`, not `swap_remove`, so that the remaining
+            // candidates stay in consensus order.)
+            let (relay, weight) = candidates.remove(idx);
+            total -= weight;
+            chosen.push(relay);
+        }
+        chosen
+    }
+
+    /// Return the index of an entry in `candidates`, chosen with probability
+    /// proportional to its weight, or None if `total` is zero.
+    ///
+    /// `total` must be the sum of the weights in `candidates`.
+    fn choose_index(&mut self, candidates: &[(Relay<'a>, u64)], total: u64) -> Option<usize> {
+        if total == 0 {
+            return None;
+        }
+        let mut target = self.uniform_below(total);
+        candidates.iter().position(|(_, weight)| {
+            if target < *weight {
+                true
+            } else {
+                target -= weight;
+                false
+            }
+        })
+    }
+
+    /// Return a uniformly random integer in `0..bound`.
+    ///
+    /// We do this ourselves, rather than using `Rng::gen_range`, so that our
+    /// results can't change with the version of `rand`.
+    fn uniform_below(&mut self, bound: u64) -> u64 {
+        debug_assert!(bound > 0);
+        // Reject values from the incomplete final stretch of the u64 range,
+        // so that every result is equally likely.
+        let limit = u64::MAX - u64::MAX % bound;
+        loop {
+            let v = self.rng.next_u64();
+            if v < limit {
+                return v % bound;
+            }
+        }
+    }
+}
+
+impl NetDir {
+    /// Return a new [`SelectionSession`] that makes reproducible weighted
+    /// choices from this directory, using `seed`.
+    pub fn selection_session(&self, seed: [u8; 32]) -> SelectionSession<'_> {
+        SelectionSession {
+            netdir: self,
+            rng: ChaCha20Rng::from_seed(seed),
+        }
+    }
+
+    /// Return the weights with which we would choose among the usable relays
+    /// for which `usable` returns true, in the role `role`.
+    ///
+    /// These are exactly the weights that a [`SelectionSession`] uses with
+    /// the same arguments.
+    pub fn weight_table<'a, P>(&'a self, role: WeightRole, usable: P) -> WeightTable
+    where
+        P: FnMut(&Relay<'a>) -> bool,
+    {
+        let entries: Vec<_> = self
+            .weighted_relays(role, usable)
+            .into_iter()
+            .map(|(relay, weight)| (*relay.rsa_id(), weight))
+            .collect();
+        let total = entries.iter().map(|(_, w)| w).sum();
+        WeightTable {
+            role,
+            entries,
+            total,
+        }
+    }
+
+    /// Helper: return every usable relay for which `usable` returns true,
+    /// with its weight for `role`, in consensus order.
+    fn weighted_relays<'a, P>(&'a self, role: WeightRole, usable: P) -> Vec<(Relay<'a>, u64)>
+    where
+        P: FnMut(&Relay<'a>) -> bool,
+    {
+        self.relays()
+            .filter(usable)
+            .map(|relay| {
+                let weight = self.weights.weight_rs_for_role(relay.rs, role);
+                (relay, weight)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::testnet;
+    use std::collections::HashSet;
+
+    #[test]
+    fn reproducible() {
+        let dir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+        let ids = |relays: Vec<Relay<'_>>| -> Vec<RsaIdentity> {
+            relays.iter().map(|r| *r.rsa_id()).collect()
+        };
+        let run = |seed: [u8; 32]| {
+            let mut session = dir.selection_session(seed);
+            let one = session.pick_relay(WeightRole::Middle, |_| true).unwrap();
+            let many = session.pick_n_relays(10, WeightRole::Exit, |r| {
+                r.low_level_details().supports_exit_port_ipv4(80)
+            });
+            (*one.rsa_id(), ids(many))
+        };
+
+        let a = run([7; 32]);
+        assert_eq!(a, run([7; 32]));
+        assert_ne!(a, run([8; 32]));
+
+        // We never pick a relay twice, and we stop when we run out.
+        let (_, many) = a;
+        assert_eq!(many.iter().collect::<HashSet<_>>().len(), many.len());
+        let mut session = dir.selection_session([7; 32]);
+        let all = session.pick_n_relays(100, WeightRole::Middle, |_| true);
+        assert_eq!(all.len(), dir.relays().count());
+        assert!(session.pick_relay(WeightRole::Middle, |_| false).is_none());
+    }
+
+    #[test]
+    fn weights_respected() {
+        let dir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+        let table = dir.weight_table(WeightRole::Middle, |_| true);
+        assert_eq!(table.entries().len(), dir.relays().count());
+        assert_eq!(
+            table.total(),
+            table.entries().iter().map(|(_, w)| w).sum::<u64>()
+        );
+
+        // Count how often we choose each relay; heavier relays should come up
+        // more often.
+        let mut session = dir.selection_session([1; 32]);
+        let mut counts = std::collections::HashMap::new();
+        for _ in 0..10_000 {
+            let r = session.pick_relay(WeightRole::Middle, |_| true).unwrap();
+            *counts.entry(*r.rsa_id()).or_insert(0_u64) += 1;
+        }
+        for (id, weight) in table.entries() {
+            let expected = 10_000.0 * (*weight as f64) / (table.total() as f64);
+            let got = *counts.get(id).unwrap_or(&0) as f64;
+            assert!((got - expected).abs() < 5.0 * expected.sqrt() + 5.0);
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! Encode a [`NetDir`] in a compact binary format, to share it with another
+//! process.
+//!
+//! Building a `NetDir` means parsing a consensus and thousands of
+//! microdescriptors, and then computing weights and indices from them.  A
+//! helper process (a measurement worker, say) can skip that work by
+//! receiving a snapshot from a process that already has a `NetDir`.
+//!
+//! A snapshot holds the consensus, the network parameters, the relay weights,
+//! the GeoIP annotations (if the directory has any), the microdescriptors,
+//! and any measured bandwidths.  It doesn't hold overload reports.  We
+//! rebuild the lookup indices when we decode a snapshot, since they are
+//! cheap to compute.
+
+use crate::params::NetParameters;
+use crate::{weight, Error, NetDir, PartialNetDir, Result};
+
+use std::sync::Arc;
+
+use tor_bytes::{EncodeError, EncodeResult, Reader, Writer};
+use tor_netdoc::doc::microdesc::Microdesc;
+use tor_netdoc::doc::netstatus::MdConsensus;
+
+#[cfg(feature = "geoip")]
+use tor_geoip::{AsNumber, CountryCode};
+
+/// Bytes at the start of every snapshot, so that we can notice if we're given
+/// something else.
+///
+/// The last byte is a version number; we change it whenever we change the
+/// format.
+const SNAPSHOT_MAGIC: &[u8] = b"arti-netdir-snapshot\x02";
+
+/// Encode `n` as a 32-bit count.
+fn write_count<W: Writer + ?Sized>(w: &mut W, n: usize) -> EncodeResult<()> {
+    let n = u32::try_from(n).map_err(|_| EncodeError::BadLengthValue)?;
+    w.write_u32(n);
+    Ok(())
+}
+
+/// Shorthand for an error about the contents of a snapshot.
+fn bad(msg: &'static str) -> tor_bytes::Error {
+    tor_bytes::Error::InvalidMessage(msg.into())
+}
+
+impl NetDir {
+    /// Encode this directory in a compact binary format.
+    ///
+    /// Another process can use [`NetDir::from_snapshot`] to turn the result
+    /// into a ready-to-use copy of this directory, without having to parse
+    /// any documents or compute any weights.
+    ///
+    /// The snapshot includes this directory's consensus, network parameters,
+    /// weights, microdescriptors, measured bandwidths, and GeoIP annotations.
+    /// It does not include overload reports.
+    ///
+    /// The format can change in any release, so it is only good for passing a
+    /// directory between processes that are running the same build.
+    #[cfg_attr(docsrs, doc(cfg(feature = "snapshot")))]
+    pub fn serialize_snapshot(&self) -> std::result::Result<Vec<u8>, EncodeError> {
+        let mut w = Vec::new();
+        w.write_all(SNAPSHOT_MAGIC);
+
+        self.consensus.write_snapshot(&mut w)?;
+
+        let params = self.params.to_values();
+        write_count(&mut w, params.len())?;
+        for (name, value) in params {
+            let mut name_w = w.write_nested_u8len();
+            name_w.write_all(name.as_bytes());
+            name_w.finish()?;
+            w.write_all(&value.to_be_bytes());
+        }
+
+        self.weights.write_snapshot(&mut w);
+
+        #[cfg(feature = "geoip")]
+        if let (Some(ccs), Some(asns)) = (&self.country_codes, &self.asns) {
+            w.write_u8(1);
+            for (cc, asn) in ccs.iter().zip(asns) {
+                w.write_all(cc.as_ref().map_or(&b"\0\0"[..], |cc| cc.get().as_bytes()));
+                w.write_u32(asn.map_or(0, |asn| asn.get()));
+            }
+        } else {
+            w.write_u8(0);
+        }
+        #[cfg(not(feature = "geoip"))]
+        w.write_u8(0);
+
+        write_count(&mut w, self.mds.iter().flatten().count())?;
+        for md in self.mds.iter().flatten() {
+            md.write_snapshot(&mut w)?;
+        }
+
+        let mut measured: Vec<_> = self.measured_bandwidths.iter().collect();
+        measured.sort();
+        write_count(&mut w, measured.len())?;
+        for (rsidx, bw) in measured {
+            write_count(&mut w, rsidx.0)?;
+            w.write_u32(*bw);
+        }
+
+        Ok(w)
+    }
+
+    /// Decode a directory that was encoded with
+    /// [`serialize_snapshot`](NetDir::serialize_snapshot).
+    ///
+    /// The snapshot must come from a process running the same build as this
+    /// one.  We don't check any signatures or digests in the snapshot: the
+    /// process that made it is responsible for having done so.  Only use
+    /// this function on snapshots from a process that you trust.
+    #[cfg_attr(docsrs, doc(cfg(feature = "snapshot")))]
+    pub fn from_snapshot(bytes: &[u8]) -> Result<NetDir> {
+        let mut r = Reader::from_slice(bytes);
+        let netdir = Self::take_snapshot_from(&mut r).map_err(Error::InvalidSnapshot)?;
+        r.should_be_exhausted().map_err(Error::InvalidSnapshot)?;
+        Ok(netdir)
+    }
+
+    /// Implementation for `from_snapshot`.
+    fn take_snapshot_from(r: &mut Reader<'_>) -> tor_bytes::Result<NetDir> {
+        if r.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+            return Err(bad("not a NetDir snapshot from this version"));
+        }
+
+        let consensus = MdConsensus::read_snapshot(r)?;
+        let n_relays = consensus.relays().len();
+
+        let mut params = NetParameters::default();
+        let n_params = r.take_u32()?;
+        for _ in 0..n_params {
+            let name_len = r.take_u8()?;
+            let name = r.take(name_len.into())?;
+            let name = std::str::from_utf8(name).map_err(|_| bad("parameter name not UTF-8"))?;
+            let value = i32::from_be_bytes(r.extract()?);
+            if !params
+                .saturating_update(std::iter::once((name, &value)))
+                .is_empty()
+            {
+                return Err(bad("unrecognized network parameter"));
+            }
+        }
+
+        let weights = weight::WeightSet::read_snapshot(r, &consensus)?;
+
+        let has_geoip = match r.take_u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(bad("bad GeoIP flag")),
+        };
+        #[cfg(not(feature = "geoip"))]
+        if has_geoip {
+            return Err(bad("snapshot has GeoIP annotations, but GeoIP is disabled"));
+        }
+        #[cfg(feature = "geoip")]
+        let (country_codes, asns) = if has_geoip {
+            let mut ccs = Vec::with_capacity(n_relays);
+            let mut asns = Vec::with_capacity(n_relays);
+            for _ in 0..n_relays {
+                let cc: [u8; 2] = r.extract()?;
+                ccs.push(if cc == [0, 0] {
+                    None
+                } else {
+                    let cc: CountryCode = std::str::from_utf8(&cc)
+                        .ok()
+                        .and_then(|s| s.parse().ok())
+                        .ok_or_else(|| bad("bad country code"))?;
+                    Some(cc)
+                });
+                asns.push(AsNumber::new(r.take_u32()?));
+            }
+            (Some(ccs), Some(asns))
+        } else {
+            (None, None)
+        };
+
+        #[cfg(feature = "hs-common")]
+        let hsdir_params = crate::HsDirParams::compute(&consensus, &params)
+            .map_err(|_| bad("can't compute onion service directory parameters"))?;
+
+        let mut partial = PartialNetDir::from_parts(
+            consensus,
+            params,
+            weights,
+            #[cfg(feature = "hs-common")]
+            hsdir_params,
+            #[cfg(feature = "geoip")]
+            country_codes,
+            #[cfg(feature = "geoip")]
+            asns,
+        );
+
+        let n_mds = r.take_u32()?;
+        for _ in 0..n_mds {
+            let md = Microdesc::read_snapshot(r)?;
+            if !partial.netdir.add_arc_microdesc(Arc::new(md)) {
+                return Err(bad("microdescriptor not listed in consensus"));
+            }
+        }
+
+        let n_measured = r.take_u32()?;
+        for _ in 0..n_measured {
+            let rsidx = usize::try_from(r.take_u32()?).map_err(|_| bad("relay index too big"))?;
+            if rsidx >= n_relays {
+                return Err(bad("measured bandwidth for nonexistent relay"));
+            }
+            let bw = r.take_u32()?;
+            partial.netdir.measured_bandwidths.insert(rsidx.into(), bw);
+        }
+
+        #[cfg(feature = "hs-common")]
+        partial.compute_rings();
+        Ok(partial.netdir)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::testnet::{construct_custom_netdir_with_params, simple_net_func};
+    use crate::{Relay, WeightRole};
+    use tor_llcrypto::pk::rsa::RsaIdentity;
+    use tor_netdoc::doc::bwfile::BandwidthFile;
+
+    #[test]
+    fn round_trip() {
+        let mut dir = construct_custom_netdir_with_params(
+            simple_net_func,
+            [("circwindow", 500), ("bwweightscale", 7)],
+            None,
+        )
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+        let bwfile = BandwidthFile::parse(
+            "1523911758
+version=1.4.0
+=====
+bw=760 node_id=$0101010101010101010101010101010101010101
+bw=12 node_id=$0303030303030303030303030303030303030303
+",
+        )
+        .unwrap();
+        assert_eq!(dir.set_measured_bandwidths(&bwfile), 2);
+
+        let snapshot = dir.serialize_snapshot().unwrap();
+        let decoded = NetDir::from_snapshot(&snapshot).unwrap();
+
+        assert_eq!(decoded.params(), dir.params());
+        assert_eq!(decoded.params().circuit_window.get(), 500);
+        assert_eq!(
+            decoded.lifetime().valid_after(),
+            dir.lifetime().valid_after()
+        );
+        let ids = |d: &NetDir| {
+            d.relays()
+                .map(|r: Relay<'_>| *r.rsa_id())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&decoded), ids(&dir));
+        for id in ids(&dir) {
+            for role in [WeightRole::Guard, WeightRole::Middle, WeightRole::Exit] {
+                assert_eq!(
+                    decoded.weight_by_rsa_id(&id, role),
+                    dir.weight_by_rsa_id(&id, role)
+                );
+            }
+            let relay = decoded.by_id(&id).unwrap();
+            assert_eq!(
+                relay.measured_bandwidth(),
+                dir.by_id(&id).unwrap().measured_bandwidth()
+            );
+            assert_eq!(relay.id(), dir.by_id(&id).unwrap().id());
+        }
+        assert_eq!(
+            decoded
+                .by_id(&RsaIdentity::from([1; 20]))
+                .unwrap()
+                .measured_bandwidth(),
+            Some(760)
+        );
+
+        // Encoding is deterministic, so the decoded directory encodes the same.
+        assert_eq!(decoded.serialize_snapshot().unwrap(), snapshot);
+    }
+
+    #[test]
+    fn bad_snapshots() {
+        let dir = crate::testnet::construct_netdir()
+            .unwrap_if_sufficient()
+            .unwrap();
+        let snapshot = dir.serialize_snapshot().unwrap();
+
+        assert!(NetDir::from_snapshot(b"").is_err());
+        assert!(NetDir::from_snapshot(b"arti-netdir-snapshot\x00").is_err());
+        assert!(NetDir::from_snapshot(&snapshot[..snapshot.len() - 1]).is_err());
+        let mut old_version = snapshot.clone();
+        old_version[SNAPSHOT_MAGIC.len() - 1] = 1;
+        assert!(NetDir::from_snapshot(&old_version).is_err());
+        let mut extended = snapshot.clone();
+        extended.push(0);
+        assert!(NetDir::from_snapshot(&extended).is_err());
+    }
+
+    #[cfg(feature = "geoip")]
+    #[test]
+    fn round_trip_geoip() {
+        use tor_geoip::HasCountryCode;
+
+        let dir = crate::testnet::countries_builder().build().unwrap();
+        let decoded = NetDir::from_snapshot(&dir.serialize_snapshot().unwrap()).unwrap();
+        for idx in 0..6_u8 {
+            let id = RsaIdentity::from([idx; 20]);
+            assert_eq!(
+                decoded.by_id(&id).unwrap().country_code(),
+                dir.by_id(&id).unwrap().country_code()
+            );
+        }
+        assert_eq!(
+            decoded
+                .by_id(&RsaIdentity::from([0; 20]))
+                .unwrap()
+                .country_code(),
+            Some("DE".parse().unwrap())
+        );
+    }
+}
@@ -22,6 +22,9 @@ use tor_netdoc::doc::microdesc::{Microdesc, MicrodescBuilder};
 use tor_netdoc::doc::netstatus::{ConsensusBuilder, MdConsensus, MdConsensusRouterStatus};
 use tor_netdoc::doc::netstatus::{Lifetime, RelayFlags, RelayWeight, RouterStatusBuilder};
 
+mod builder;
+
+pub use builder::{NetDirBuilder, RelaySpec};
 pub use tor_netdoc::{BuildError, BuildResult};
 
 /// A set of builder objects for a single node.
@@ -287,6 +290,25 @@ where
     Ok((consensus, microdescs))
 }
 
+/// Return a [`NetDirBuilder`] for six guard-and-exit relays, the first two of
+/// which are in `DE`, each at an address of its own.
+#[cfg(all(test, feature = "geoip"))]
+pub(crate) fn countries_builder() -> NetDirBuilder {
+    let de = "DE".parse().unwrap();
+    let mut bld = NetDirBuilder::new();
+    for idx in 0..6_u8 {
+        let spec = bld
+            .relay([idx; 20].into(), [idx; 32].into())
+            .add_flags(RelayFlags::GUARD | RelayFlags::EXIT);
+        if idx < 2 {
+            spec.add_or_port(SocketAddr::from(([10, idx, 0, 1], 443)))
+                .add_or_port("[2001:db8::1]:443".parse().unwrap())
+                .country(de);
+        }
+    }
+    bld
+}
+
 #[cfg(test)]
 mod test {
     // @@ begin test lint list maintained by maint/add_warning @@
@@ -311,4 +333,99 @@ mod test {
         });
         assert_eq!(val, 40);
     }
+
+    #[test]
+    fn netdir_builder() {
+        use crate::{Relay, WeightRole};
+        use tor_linkspec::{HasAddrs, HasRelayIds};
+        use tor_llcrypto::pk::ed25519::Ed25519Identity;
+
+        // Too few relays to build paths.
+        let mut bld = NetDirBuilder::new();
+        bld.relay([1; 20].into(), [1; 32].into());
+        assert!(bld.build().is_err());
+        assert!(bld.build_partial().is_ok());
+
+        let mut bld = NetDirBuilder::new();
+        bld.param("circwindow", 100);
+        for idx in 0..6_u8 {
+            let spec = bld
+                .relay([idx; 20].into(), [idx; 32].into())
+                .add_or_port(SocketAddr::from(([10, idx, 0, 1], 443)))
+                .weight(RelayWeight::Measured(1000 * u32::from(idx + 1)));
+            if idx < 3 {
+                spec.add_flags(RelayFlags::GUARD);
+            } else {
+                spec.add_flags(RelayFlags::EXIT)
+                    .ipv4_policy("accept 443".parse().unwrap());
+            }
+        }
+        bld.relay([9; 20].into(), [9; 32].into()).omit_md(true);
+        let netdir = bld.build().unwrap();
+
+        assert_eq!(netdir.relays().count(), 6);
+        assert_eq!(netdir.all_relays().count(), 7);
+        assert_eq!(netdir.params().circuit_window.get(), 100);
+        assert!(netdir.by_id(&Ed25519Identity::from([9; 32])).is_none());
+
+        let r: Relay<'_> = netdir.by_id(&Ed25519Identity::from([4; 32])).unwrap();
+        assert_eq!(r.rsa_identity(), Some(&[4; 20].into()));
+        assert!(r.low_level_details().supports_exit_port_ipv4(443));
+        assert!(!r.low_level_details().supports_exit_port_ipv4(80));
+        assert_eq!(
+            netdir.relay_weight(&r, WeightRole::Exit),
+            crate::RelayWeight::from(5000)
+        );
+        let r = netdir.by_id(&Ed25519Identity::from([0; 32])).unwrap();
+        assert!(r.low_level_details().is_suitable_as_guard());
+        assert_eq!(r.addrs(), &[SocketAddr::from(([10, 0, 0, 1], 443))]);
+    }
+
+    #[test]
+    fn netdir_builder_families() {
+        use tor_llcrypto::pk::ed25519::Ed25519Identity;
+
+        let mut bld = NetDirBuilder::new();
+        for idx in 0..6_u8 {
+            bld.relay([idx; 20].into(), [idx; 32].into())
+                .add_flags(RelayFlags::GUARD | RelayFlags::EXIT);
+        }
+        bld.family([[0; 20].into(), [1; 20].into(), [2; 20].into()])
+            .family([[2; 20].into(), [3; 20].into()]);
+        let netdir = bld.build().unwrap();
+
+        let relay = |idx: u8| netdir.by_id(&Ed25519Identity::from([idx; 32])).unwrap();
+        let same_family = |a, b| relay(a).low_level_details().in_same_family(&relay(b));
+        assert!(same_family(0, 1));
+        assert!(same_family(1, 2));
+        assert!(same_family(2, 3));
+        assert!(!same_family(0, 3));
+        assert!(!same_family(4, 5));
+    }
+
+    #[cfg(feature = "geoip")]
+    #[test]
+    fn netdir_builder_countries() {
+        use tor_geoip::HasCountryCode;
+        use tor_llcrypto::pk::ed25519::Ed25519Identity;
+
+        let de = "DE".parse().unwrap();
+        let netdir = countries_builder().build().unwrap();
+        let country = |idx: u8| {
+            netdir
+                .by_id(&Ed25519Identity::from([idx; 32]))
+                .unwrap()
+                .country_code()
+        };
+        assert_eq!(country(0), Some(de));
+        assert_eq!(country(1), Some(de));
+        assert_eq!(country(2), None);
+
+        // Relays with the same address can't be in different countries.
+        let mut bld = NetDirBuilder::new();
+        bld.relay([0; 20].into(), [0; 32].into()).country(de);
+        bld.relay([1; 20].into(), [1; 32].into())
+            .country("US".parse().unwrap());
+        assert!(bld.build_partial().is_err());
+    }
 }
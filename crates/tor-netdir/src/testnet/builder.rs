@@ -0,0 +1,403 @@
+//! A builder for constructing a test network directory relay by relay.
+//!
+//! Where [`construct_custom_network`](super::construct_custom_network) starts
+//! from a fixed network of 40 relays and lets you adjust each one, a
+//! [`NetDirBuilder`] starts from nothing: you list exactly the relays you want,
+//! and it takes care of the consensus and microdescriptor boilerplate.
+
+#[cfg(feature = "geoip")]
+use std::collections::HashMap;
+#[cfg(feature = "geoip")]
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "geoip")]
+use tor_geoip::{CountryCode, GeoipDb};
+use tor_llcrypto::pk::{curve25519, ed25519::Ed25519Identity, rsa::RsaIdentity};
+use tor_netdoc::doc::microdesc::Microdesc;
+use tor_netdoc::doc::netstatus::{Lifetime, MdConsensus, RelayFlags, RelayWeight};
+use tor_netdoc::types::family::RelayFamily;
+use tor_netdoc::types::policy::PortPolicy;
+use tor_protover::Protocols;
+
+use super::{BuildError, BuildResult};
+use crate::{MdReceiver, NetDir, PartialNetDir};
+
+/// A description of a single relay, for use with a [`NetDirBuilder`].
+///
+/// Every field has a usable default, except for the identities.  By default, a
+/// relay:
+///  * is flagged `Running`, `Valid`, `Fast`, `Stable`, and `V2Dir`;
+///  * listens on `127.0.0.1:9001`;
+///  * has a measured weight of 1000;
+///  * supports no subprotocols;
+///  * rejects all exit traffic;
+///  * has no family;
+///  * has no country;
+///  * has a useless ntor onion key.
+#[derive(Clone, Debug)]
+pub struct RelaySpec {
+    /// The relay's RSA identity.
+    rsa_id: RsaIdentity,
+    /// The relay's Ed25519 identity.
+    ed_id: Ed25519Identity,
+    /// The relay's nickname, if we're setting one.
+    nickname: Option<String>,
+    /// The relay's ORPort addresses.  If empty, we use a default.
+    addrs: Vec<SocketAddr>,
+    /// The relay's flags in the consensus.
+    flags: RelayFlags,
+    /// The relay's weight in the consensus.
+    weight: RelayWeight,
+    /// The subprotocols that the relay supports.
+    protocols: Protocols,
+    /// The relay's exit policy for IPv4.
+    ipv4_policy: PortPolicy,
+    /// The relay's exit policy for IPv6.
+    ipv6_policy: PortPolicy,
+    /// The relays that this relay declares as its family.
+    family: RelayFamily,
+    /// The relay's ntor onion key.
+    ntor_key: curve25519::PublicKey,
+    /// The country that the relay's addresses are in, if we're setting one.
+    #[cfg(feature = "geoip")]
+    country: Option<CountryCode>,
+    /// If true, we leave this relay's microdescriptor out of the directory.
+    omit_md: bool,
+}
+
+impl RelaySpec {
+    /// Return a new RelaySpec for a relay with the given identities, and
+    /// every other property set to its default.
+    pub fn new(rsa_id: RsaIdentity, ed_id: Ed25519Identity) -> Self {
+        RelaySpec {
+            rsa_id,
+            ed_id,
+            nickname: None,
+            addrs: Vec::new(),
+            flags: RelayFlags::RUNNING
+                | RelayFlags::VALID
+                | RelayFlags::FAST
+                | RelayFlags::STABLE
+                | RelayFlags::V2DIR,
+            weight: RelayWeight::Measured(1000),
+            protocols: Protocols::new(),
+            ipv4_policy: PortPolicy::new_reject_all(),
+            ipv6_policy: PortPolicy::new_reject_all(),
+            family: RelayFamily::new(),
+            ntor_key: (*b"----nothing in dirmgr uses this-").into(),
+            #[cfg(feature = "geoip")]
+            country: None,
+            omit_md: false,
+        }
+    }
+
+    /// Set the relay's nickname.
+    pub fn nickname(&mut self, nickname: impl Into<String>) -> &mut Self {
+        self.nickname = Some(nickname.into());
+        self
+    }
+
+    /// Add an ORPort address for this relay.
+    ///
+    /// The first time this is called, it replaces the default address.
+    pub fn add_or_port(&mut self, addr: SocketAddr) -> &mut Self {
+        self.addrs.push(addr);
+        self
+    }
+
+    /// Replace the relay's flags with `flags`.
+    pub fn flags(&mut self, flags: RelayFlags) -> &mut Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Add `flags` to the relay's flags.
+    pub fn add_flags(&mut self, flags: RelayFlags) -> &mut Self {
+        self.flags |= flags;
+        self
+    }
+
+    /// Set the relay's weight in the consensus.
+    pub fn weight(&mut self, weight: RelayWeight) -> &mut Self {
+        self.weight = weight;
+        self
+    }
+
+    /// Set the subprotocols that the relay supports.
+    pub fn protocols(&mut self, protocols: Protocols) -> &mut Self {
+        self.protocols = protocols;
+        self
+    }
+
+    /// Set the relay's IPv4 exit policy.
+    pub fn ipv4_policy(&mut self, policy: PortPolicy) -> &mut Self {
+        self.ipv4_policy = policy;
+        self
+    }
+
+    /// Set the relay's IPv6 exit policy.
+    pub fn ipv6_policy(&mut self, policy: PortPolicy) -> &mut Self {
+        self.ipv6_policy = policy;
+        self
+    }
+
+    /// Set the relays that this relay declares as its family.
+    ///
+    /// Remember that two relays are only in the same family if each one lists
+    /// the other.
+    pub fn family(&mut self, family: RelayFamily) -> &mut Self {
+        self.family = family;
+        self
+    }
+
+    /// Set the relay's ntor onion key.
+    pub fn ntor_key(&mut self, key: curve25519::PublicKey) -> &mut Self {
+        self.ntor_key = key;
+        self
+    }
+
+    /// Put this relay's addresses in `country`.
+    ///
+    /// When any relay has a country, we build the directory with a GeoIP
+    /// database that maps each of those relays' addresses to its country.
+    /// Relays that share an address must be in the same country, so you will
+    /// usually want to give each such relay an address of its own with
+    /// [`add_or_port`](Self::add_or_port).
+    #[cfg(feature = "geoip")]
+    pub fn country(&mut self, country: CountryCode) -> &mut Self {
+        self.country = Some(country);
+        self
+    }
+
+    /// If `omit` is true, leave this relay's microdescriptor out of the
+    /// directory, so that the relay is listed but not usable.
+    pub fn omit_md(&mut self, omit: bool) -> &mut Self {
+        self.omit_md = omit;
+        self
+    }
+
+    /// Return the relay's ORPort addresses, including the default address if
+    /// none were set.
+    fn or_addrs(&self) -> Vec<SocketAddr> {
+        if self.addrs.is_empty() {
+            vec![SocketAddr::from(([127, 0, 0, 1], 9001))]
+        } else {
+            self.addrs.clone()
+        }
+    }
+}
+
+/// A builder for a [`NetDir`] made of explicitly specified relays.
+///
+/// Unlike the other functions in this module, this doesn't give you any relays
+/// unless you ask for them.
+///
+/// # Example
+///
+/// ```
+/// use tor_netdir::testnet::NetDirBuilder;
+/// use tor_netdoc::doc::netstatus::RelayFlags;
+///
+/// let mut bld = NetDirBuilder::new();
+/// for idx in 0..10_u8 {
+///     bld.relay([idx; 20].into(), [idx; 32].into())
+///         .add_flags(RelayFlags::GUARD | RelayFlags::EXIT)
+///         .ipv4_policy("accept 443".parse().unwrap());
+/// }
+/// let netdir = bld.build().unwrap();
+/// assert_eq!(netdir.relays().count(), 10);
+/// ```
+#[derive(Clone, Debug)]
+pub struct NetDirBuilder {
+    /// The lifetime of the consensus, if we're not using the default.
+    lifetime: Option<Lifetime>,
+    /// Network parameters to put in the consensus.
+    params: Vec<(String, i32)>,
+    /// The relays in the network, in order.
+    relays: Vec<RelaySpec>,
+}
+
+impl Default for NetDirBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NetDirBuilder {
+    /// Return a new NetDirBuilder with no relays.
+    ///
+    /// By default, the consensus is valid for one day (in realtime) after the
+    /// time at which we build it.
+    pub fn new() -> Self {
+        NetDirBuilder {
+            lifetime: None,
+            params: Vec::new(),
+            relays: Vec::new(),
+        }
+    }
+
+    /// Set the lifetime of the consensus.
+    pub fn lifetime(&mut self, lifetime: Lifetime) -> &mut Self {
+        self.lifetime = Some(lifetime);
+        self
+    }
+
+    /// Set the network parameter `name` to `value` in the consensus.
+    pub fn param(&mut self, name: impl Into<String>, value: i32) -> &mut Self {
+        self.params.push((name.into(), value));
+        self
+    }
+
+    /// Add a relay with the given identities and default properties, and
+    /// return a reference to it so that its properties can be adjusted.
+    pub fn relay(&mut self, rsa_id: RsaIdentity, ed_id: Ed25519Identity) -> &mut RelaySpec {
+        self.relays.push(RelaySpec::new(rsa_id, ed_id));
+        self.relays.last_mut().expect("just pushed a relay")
+    }
+
+    /// Add the relay described by `spec`.
+    pub fn add_relay(&mut self, spec: RelaySpec) -> &mut Self {
+        self.relays.push(spec);
+        self
+    }
+
+    /// Put the relays with the given RSA identities in a family together.
+    ///
+    /// Each relay that we have already added with one of these identities
+    /// lists all of the others in its family, in addition to any family
+    /// members that it already had.
+    pub fn family(&mut self, members: impl IntoIterator<Item = RsaIdentity>) -> &mut Self {
+        let members: Vec<RsaIdentity> = members.into_iter().collect();
+        for spec in &mut self.relays {
+            if !members.contains(&spec.rsa_id) {
+                continue;
+            }
+            for id in &members {
+                if *id != spec.rsa_id && !spec.family.contains(id) {
+                    spec.family.push(*id);
+                }
+            }
+        }
+        self
+    }
+
+    /// Construct the consensus and microdescriptors for this network.
+    pub fn build_documents(&self) -> BuildResult<(MdConsensus, Vec<Microdesc>)> {
+        let lifetime = match &self.lifetime {
+            Some(lifetime) => lifetime.clone(),
+            None => {
+                let now = SystemTime::now();
+                let one_day = Duration::new(86400, 0);
+                Lifetime::new(now, now + one_day / 2, now + one_day)?
+            }
+        };
+
+        let mut bld = MdConsensus::builder();
+        bld.consensus_method(34)
+            .lifetime(lifetime)
+            .param("bwweightscale", 1)
+            .weights("".parse()?);
+        for (name, value) in &self.params {
+            bld.param(name, *value);
+        }
+
+        let mut microdescs = Vec::new();
+        for spec in &self.relays {
+            let md = Microdesc::builder()
+                .ntor_key(spec.ntor_key)
+                .ed25519_id(spec.ed_id)
+                .family(spec.family.clone())
+                .ipv4_policy(spec.ipv4_policy.clone())
+                .ipv6_policy(spec.ipv6_policy.clone())
+                .testing_md()?;
+
+            let mut rs = bld.rs();
+            rs.identity(spec.rsa_id)
+                .doc_digest(*md.digest())
+                .protos(spec.protocols.clone())
+                .set_flags(spec.flags)
+                .weight(spec.weight);
+            if let Some(nickname) = &spec.nickname {
+                rs.nickname(nickname.clone());
+            }
+            for addr in spec.or_addrs() {
+                rs.add_or_port(addr);
+            }
+            rs.build_into(&mut bld)?;
+
+            if !spec.omit_md {
+                microdescs.push(md);
+            }
+        }
+
+        Ok((bld.testing_consensus()?, microdescs))
+    }
+
+    /// Construct a [`PartialNetDir`] for this network.
+    ///
+    /// Use this if the network might not have enough relays to build paths.
+    pub fn build_partial(&self) -> BuildResult<PartialNetDir> {
+        let (consensus, microdescs) = self.build_documents()?;
+        let params = self.params.iter().cloned().collect();
+        #[cfg(feature = "geoip")]
+        let mut dir = match self.geoip_db()? {
+            Some(db) => PartialNetDir::new_with_geoip(consensus, Some(&params), &db),
+            None => PartialNetDir::new(consensus, Some(&params)),
+        };
+        #[cfg(not(feature = "geoip"))]
+        let mut dir = PartialNetDir::new(consensus, Some(&params));
+        for md in microdescs {
+            dir.add_microdesc(md);
+        }
+        Ok(dir)
+    }
+
+    /// Construct a GeoIP database that puts every relay that has a country in
+    /// that country.
+    ///
+    /// Return `None` if no relay has a country.
+    #[cfg(feature = "geoip")]
+    fn geoip_db(&self) -> BuildResult<Option<GeoipDb>> {
+        let mut countries: HashMap<IpAddr, CountryCode> = HashMap::new();
+        let mut db_v4 = String::new();
+        let mut db_v6 = String::new();
+        for spec in &self.relays {
+            let Some(country) = spec.country else {
+                continue;
+            };
+            for addr in spec.or_addrs() {
+                match countries.insert(addr.ip(), country) {
+                    Some(other) if other != country => {
+                        return Err(BuildError::CannotBuild(
+                            "relays with the same address are in different countries",
+                        ))
+                    }
+                    Some(_) => continue,
+                    None => {}
+                }
+                match addr.ip() {
+                    IpAddr::V4(ip) => db_v4 += &format!("{0},{0},{1}\n", u32::from(ip), country),
+                    IpAddr::V6(ip) => db_v6 += &format!("{0},{0},{1}\n", ip, country),
+                }
+            }
+        }
+        if countries.is_empty() {
+            return Ok(None);
+        }
+        GeoipDb::new_from_legacy_format(&db_v4, &db_v6)
+            .map(Some)
+            .map_err(|_| BuildError::CannotBuild("unable to build a GeoIP database"))
+    }
+
+    /// Construct a [`NetDir`] for this network.
+    ///
+    /// Give an error if the network doesn't have enough usable relays to
+    /// build paths.
+    pub fn build(&self) -> BuildResult<NetDir> {
+        self.build_partial()?
+            .unwrap_if_sufficient()
+            .map_err(|_| BuildError::CannotBuild("not enough usable relays to build paths"))
+    }
+}
@@ -2,7 +2,7 @@
 
 use std::sync::{Arc, Mutex};
 
-use crate::{DirEvent, Error, NetDir, NetDirProvider, Result};
+use crate::{DetailedDirEvent, DirEvent, Error, NetDir, NetDirProvider, RelayListChange, Result};
 
 use postage::broadcast::{self, Receiver, Sender};
 use postage::sink::Sink as _;
@@ -14,6 +14,7 @@ use postage::sink::Sink as _;
 ///
 /// It notifies its owner of changes
 /// by firing a [`NewConsensus`](DirEvent::NewConsensus) event
+/// (and a corresponding [`DetailedDirEvent::NewConsensus`])
 /// each time [`TestNetDirProvider::set_netdir_and_notify`] is called.
 ///
 /// Calling [`TestNetDirProvider::set_netdir`] will **not** trigger a notification.
@@ -32,6 +33,10 @@ struct Inner {
     event_tx: Sender<DirEvent>,
     /// The event receiver.
     _event_rx: Receiver<DirEvent>,
+    /// The detailed event sender, which fires every time the netdir is updated.
+    detailed_tx: Sender<DetailedDirEvent>,
+    /// The detailed event receiver.
+    _detailed_rx: Receiver<DetailedDirEvent>,
 }
 
 #[allow(clippy::new_without_default)]
@@ -39,10 +44,13 @@ impl TestNetDirProvider {
     /// Create a new [`TestNetDirProvider`] with no netdir available.
     pub fn new() -> Self {
         let (event_tx, _event_rx) = broadcast::channel(128);
+        let (detailed_tx, _detailed_rx) = broadcast::channel(128);
         let inner = Inner {
             current: None,
             event_tx,
             _event_rx,
+            detailed_tx,
+            _detailed_rx,
         };
 
         Self {
@@ -59,15 +67,21 @@ impl TestNetDirProvider {
     /// Replace the `NetDir` in this [`TestNetDirProvider`],
     /// firing a [`NewConsensus`](DirEvent::NewConsensus) event.
     pub async fn set_netdir_and_notify(&self, dir: impl Into<Arc<NetDir>>) {
-        let mut event_tx = {
+        let (mut event_tx, mut detailed_tx, change) = {
             let mut inner = self.inner.lock().expect("lock poisoned");
-            inner.current = Some(dir.into());
-            inner.event_tx.clone()
+            let dir = dir.into();
+            let change = RelayListChange::between(inner.current.as_deref(), &dir);
+            inner.current = Some(dir);
+            (inner.event_tx.clone(), inner.detailed_tx.clone(), change)
         };
         event_tx
             .send(DirEvent::NewConsensus)
             .await
             .expect("receivers were dropped");
+        detailed_tx
+            .send(DetailedDirEvent::NewConsensus(Arc::new(change)))
+            .await
+            .expect("receivers were dropped");
     }
 }
 
@@ -93,6 +107,12 @@ impl NetDirProvider for TestNetDirProvider {
         Box::pin(events)
     }
 
+    fn detailed_events(&self) -> futures::stream::BoxStream<'static, DetailedDirEvent> {
+        let inner = self.inner.lock().expect("lock poisoned");
+        let events = inner.detailed_tx.subscribe();
+        Box::pin(events)
+    }
+
     fn params(&self) -> Arc<dyn AsRef<crate::params::NetParameters>> {
         if let Ok(nd) = self.netdir(crate::Timeliness::Unchecked) {
             nd
@@ -0,0 +1,323 @@
+//! Watching a single relay for changes across directory updates.
+//!
+//! An application that runs a relay (or depends on one) may want to know as
+//! soon as that relay loses a flag, changes its address, or drops out of the
+//! consensus.  [`NetDirProvider::watch_relay`] gives it a stream of
+//! [`RelayChange`]s for that relay, so that it doesn't need to compare
+//! directories itself.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+
+use futures::stream::{self, BoxStream, StreamExt as _};
+
+use tor_linkspec::{HasRelayIds, RelayIds};
+use tor_netdoc::doc::netstatus::RelayFlags;
+
+use crate::{ConsensusRelays as _, NetDir, NetDirProvider, Timeliness};
+
+/// What one consensus says about a watched relay.
+///
+/// Returned by [`RelayChange::before`] and [`RelayChange::after`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RelayListing {
+    /// The relay's flags.
+    flags: RelayFlags,
+    /// The relay's ORPort addresses.
+    addrs: Vec<SocketAddr>,
+}
+
+impl RelayListing {
+    /// Return the flags that the consensus gives this relay.
+    pub fn flags(&self) -> RelayFlags {
+        self.flags
+    }
+
+    /// Return the ORPort addresses that the consensus lists for this relay.
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs[..]
+    }
+}
+
+/// A change in what the directory says about a watched relay.
+///
+/// Returned by [`NetDirProvider::watch_relay`].
+#[derive(Clone, Debug)]
+pub struct RelayChange {
+    /// The identities of the relay that changed.
+    ids: RelayIds,
+    /// The relay's listing before the change, or `None` if it wasn't listed.
+    old: Option<RelayListing>,
+    /// The relay's listing after the change, or `None` if it isn't listed.
+    new: Option<RelayListing>,
+}
+
+impl RelayChange {
+    /// Return the identities of the relay, as passed to
+    /// [`watch_relay`](NetDirProvider::watch_relay).
+    pub fn ids(&self) -> &RelayIds {
+        &self.ids
+    }
+
+    /// Return the relay's listing before this change, or `None` if it was
+    /// not listed.
+    pub fn before(&self) -> Option<&RelayListing> {
+        self.old.as_ref()
+    }
+
+    /// Return the relay's listing after this change, or `None` if it is no
+    /// longer listed.
+    pub fn after(&self) -> Option<&RelayListing> {
+        self.new.as_ref()
+    }
+
+    /// Return true if the relay was unlisted before this change, and is
+    /// listed now.
+    pub fn became_listed(&self) -> bool {
+        self.old.is_none() && self.new.is_some()
+    }
+
+    /// Return true if the relay was listed before this change, and is not
+    /// listed now.
+    pub fn became_unlisted(&self) -> bool {
+        self.old.is_some() && self.new.is_none()
+    }
+
+    /// Return the flags that the relay had before this change, and doesn't
+    /// have now.
+    ///
+    /// A relay that is no longer listed has lost all of its flags.
+    pub fn flags_lost(&self) -> RelayFlags {
+        flags_of(&self.old) - flags_of(&self.new)
+    }
+
+    /// Return the flags that the relay has now, and didn't have before this
+    /// change.
+    pub fn flags_gained(&self) -> RelayFlags {
+        flags_of(&self.new) - flags_of(&self.old)
+    }
+
+    /// Return true if the relay is listed both before and after this change,
+    /// with different addresses.
+    pub fn addrs_changed(&self) -> bool {
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => old.addrs != new.addrs,
+            _ => false,
+        }
+    }
+}
+
+/// Return the flags in `listing`, or no flags if there is no listing.
+fn flags_of(listing: &Option<RelayListing>) -> RelayFlags {
+    listing
+        .as_ref()
+        .map(RelayListing::flags)
+        .unwrap_or_else(RelayFlags::empty)
+}
+
+/// Whether a directory lists a watched relay.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum ListingStatus {
+    /// The relay is listed.
+    Listed(RelayListing),
+    /// The relay is definitely not listed.
+    Unlisted,
+}
+
+impl ListingStatus {
+    /// Return the relay's listing, if it is listed.
+    fn into_listing(self) -> Option<RelayListing> {
+        match self {
+            ListingStatus::Listed(listing) => Some(listing),
+            ListingStatus::Unlisted => None,
+        }
+    }
+}
+
+impl NetDir {
+    /// Return what this directory says about the relay with `ids`.
+    ///
+    /// Returns `None` if we can't tell yet whether the relay is listed, as
+    /// with [`NetDir::ids_listed`].
+    fn relay_listing(&self, ids: &RelayIds) -> Option<ListingStatus> {
+        if !self.ids_listed(ids)? {
+            return Some(ListingStatus::Unlisted);
+        }
+        let idx = match (ids.rsa_identity(), ids.ed_identity()) {
+            (Some(rsa), _) => self.rsidx_by_rsa.get(rsa),
+            (None, Some(ed)) => self.rsidx_by_ed.get(ed).copied(),
+            (None, None) => None,
+        }?;
+        let rs = self.c_relays().get(idx)?;
+        Some(ListingStatus::Listed(RelayListing {
+            flags: *rs.flags(),
+            addrs: rs.addrs().to_vec(),
+        }))
+    }
+}
+
+/// The state of a stream returned by [`watch_relay`].
+struct RelayWatcher {
+    /// The provider whose directories we're watching.
+    ///
+    /// We hold this weakly, so that a forgotten stream doesn't keep the
+    /// provider alive.
+    provider: Weak<dyn NetDirProvider>,
+    /// The identities of the relay that we're watching.
+    ids: RelayIds,
+    /// The relay's status in the last directory that could tell us about
+    /// it, or `None` if we haven't seen such a directory yet.
+    last: Option<ListingStatus>,
+}
+
+impl RelayWatcher {
+    /// Look up the relay in the provider's latest directory, and return a
+    /// [`RelayChange`] if its listing has changed since we last looked.
+    fn observe(&mut self, provider: &dyn NetDirProvider) -> Option<RelayChange> {
+        let netdir = provider.netdir(Timeliness::Timely).ok()?;
+        let status = netdir.relay_listing(&self.ids)?;
+        match self.last.replace(status.clone()) {
+            Some(old) if old != status => Some(RelayChange {
+                ids: self.ids.clone(),
+                old: old.into_listing(),
+                new: status.into_listing(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Implementation for [`NetDirProvider::watch_relay`].
+pub(crate) fn watch_relay(
+    provider: Arc<dyn NetDirProvider>,
+    ids: RelayIds,
+) -> BoxStream<'static, RelayChange> {
+    let events = provider.events();
+    let mut watcher = RelayWatcher {
+        provider: Arc::downgrade(&provider),
+        ids,
+        last: None,
+    };
+    // Note the relay's current listing, so that we only report later
+    // changes.
+    let _: Option<RelayChange> = watcher.observe(provider.as_ref());
+    drop(provider);
+
+    stream::unfold((watcher, events), |(mut watcher, mut events)| async move {
+        while events.next().await.is_some() {
+            let provider = watcher.provider.upgrade()?;
+            if let Some(change) = watcher.observe(provider.as_ref()) {
+                return Some((change, (watcher, events)));
+            }
+        }
+        None
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+
+    use super::*;
+    use crate::testnet::construct_custom_netdir;
+
+    /// Return a test directory in which relay 5 has `extra_flags` added to its
+    /// usual flags, and is left out entirely if `listed` is false.
+    fn netdir(extra_flags: RelayFlags, listed: bool) -> NetDir {
+        construct_custom_netdir(|pos, nb, _| {
+            if pos == 5 {
+                nb.rs.add_flags(extra_flags);
+                nb.omit_rs = !listed;
+            }
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap()
+    }
+
+    #[test]
+    fn listing() {
+        let ids = RelayIds::builder()
+            .rsa_identity([5; 20].into())
+            .build()
+            .unwrap();
+        let nd = netdir(RelayFlags::BAD_EXIT, true);
+        let Some(ListingStatus::Listed(listing)) = nd.relay_listing(&ids) else {
+            panic!("relay not listed");
+        };
+        assert!(listing.flags().contains(RelayFlags::BAD_EXIT));
+        assert_eq!(listing.addrs().len(), 1);
+
+        let nd = netdir(RelayFlags::empty(), false);
+        assert_eq!(nd.relay_listing(&ids), Some(ListingStatus::Unlisted));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn watch() {
+        use crate::testprovider::TestNetDirProvider;
+        futures::executor::block_on(async {
+            let provider = Arc::new(TestNetDirProvider::new());
+            provider.set_netdir(netdir(RelayFlags::empty(), true));
+            let ids = RelayIds::builder()
+                .rsa_identity([5; 20].into())
+                .build()
+                .unwrap();
+            let mut changes = Arc::clone(&provider).watch_relay(ids.clone());
+            let mut unrelated = Arc::clone(&provider).watch_relay(
+                RelayIds::builder()
+                    .rsa_identity([9; 20].into())
+                    .build()
+                    .unwrap(),
+            );
+
+            // The relay gains a flag.
+            provider
+                .set_netdir_and_notify(netdir(RelayFlags::BAD_EXIT, true))
+                .await;
+            let c = changes.next().await.unwrap();
+            assert_eq!(c.ids(), &ids);
+            assert_eq!(c.flags_gained(), RelayFlags::BAD_EXIT);
+            assert!(c.flags_lost().is_empty());
+            assert!(!c.addrs_changed());
+            assert!(!c.became_listed() && !c.became_unlisted());
+
+            // A new consensus that changes nothing about the relay is not
+            // reported; dropping the relay is.
+            provider
+                .set_netdir_and_notify(netdir(RelayFlags::BAD_EXIT, true))
+                .await;
+            provider
+                .set_netdir_and_notify(netdir(RelayFlags::empty(), false))
+                .await;
+            let c = changes.next().await.unwrap();
+            assert!(c.became_unlisted());
+            assert!(c.flags_lost().contains(RelayFlags::BAD_EXIT));
+            assert!(c.after().is_none());
+
+            provider
+                .set_netdir_and_notify(netdir(RelayFlags::empty(), true))
+                .await;
+            let c = changes.next().await.unwrap();
+            assert!(c.became_listed());
+
+            // Once the provider is gone, the streams end.
+            drop(provider);
+            assert!(changes.next().await.is_none());
+            assert!(unrelated.next().await.is_none());
+        });
+    }
+}
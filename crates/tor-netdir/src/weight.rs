@@ -234,6 +234,12 @@ impl WeightSet {
         self.weight_bw_for_role(WeightKind::for_rs(rs), rs.weight(), role)
     }
 
+    /// Return the bandwidth that we use for `rs`, before applying any
+    /// role-specific weighting.
+    pub(crate) fn bandwidth_of(&self, rs: &MdConsensusRouterStatus) -> u32 {
+        self.bandwidth_fn.apply(rs.weight())
+    }
+
     /// Find the 64-bit weight to report for a relay of `kind` whose weight in
     /// the consensus is `relay_weight` when using it for `role`.
     fn weight_bw_for_role(
@@ -342,18 +348,91 @@ impl WeightSet {
     /// Assert that we have correctly computed our shift values so that
     /// our total weighted bws do not exceed u64::MAX.
     fn validate(self, consensus: &MdConsensus) -> Self {
+        assert!(
+            self.totals_fit(consensus),
+            "Incorrect relay weight calculation: total exceeded u64::MAX!"
+        );
+        self
+    }
+
+    /// Return true if, for every role, the total weighted bandwidth of the
+    /// relays in `consensus` fits in a u64.
+    fn totals_fit(&self, consensus: &MdConsensus) -> bool {
         use WeightRole::*;
-        for role in [Guard, Middle, Exit, BeginDir, Unweighted] {
-            let _: u64 = consensus
-                .c_relays()
-                .iter()
-                .map(|rs| self.weight_rs_for_role(rs, role))
-                .fold(0_u64, |a, b| {
-                    a.checked_add(b)
-                        .expect("Incorrect relay weight calculation: total exceeded u64::MAX!")
-                });
+        [Guard, Middle, Exit, BeginDir, Unweighted]
+            .into_iter()
+            .all(|role| {
+                consensus
+                    .c_relays()
+                    .iter()
+                    .map(|rs| self.weight_rs_for_role(rs, role))
+                    .try_fold(0_u64, u64::checked_add)
+                    .is_some()
+            })
+    }
+
+    /// Encode this WeightSet onto `w`, as part of a [`NetDir`](crate::NetDir)
+    /// snapshot.
+    #[cfg(feature = "snapshot")]
+    pub(crate) fn write_snapshot<W: tor_bytes::Writer + ?Sized>(&self, w: &mut W) {
+        w.write_u8(match self.bandwidth_fn {
+            BandwidthFn::Uniform => 0,
+            BandwidthFn::IncludeUnmeasured => 1,
+            BandwidthFn::MeasuredOnly => 2,
+        });
+        w.write_u8(self.shift);
+        for rw in &self.w {
+            w.write_u32(rw.as_guard);
+            w.write_u32(rw.as_middle);
+            w.write_u32(rw.as_exit);
+            w.write_u32(rw.as_dir);
         }
-        self
+    }
+
+    /// Decode a WeightSet for `consensus` that was encoded with
+    /// [`write_snapshot`](Self::write_snapshot).
+    #[cfg(feature = "snapshot")]
+    pub(crate) fn read_snapshot(
+        r: &mut tor_bytes::Reader<'_>,
+        consensus: &MdConsensus,
+    ) -> tor_bytes::Result<Self> {
+        let bandwidth_fn = match r.take_u8()? {
+            0 => BandwidthFn::Uniform,
+            1 => BandwidthFn::IncludeUnmeasured,
+            2 => BandwidthFn::MeasuredOnly,
+            _ => {
+                return Err(tor_bytes::Error::InvalidMessage(
+                    "bad bandwidth function".into(),
+                ))
+            }
+        };
+        let shift = r.take_u8()?;
+        let mut w = [RelayWeight {
+            as_guard: 0,
+            as_middle: 0,
+            as_exit: 0,
+            as_dir: 0,
+        }; 8];
+        for rw in &mut w {
+            rw.as_guard = r.take_u32()?;
+            rw.as_middle = r.take_u32()?;
+            rw.as_exit = r.take_u32()?;
+            rw.as_dir = r.take_u32()?;
+        }
+        let ws = WeightSet {
+            bandwidth_fn,
+            shift,
+            w,
+        };
+
+        // Unlike `validate`, we don't trust ourselves to have gotten this
+        // right: we got it from somebody else.
+        if shift >= 64 || !ws.totals_fit(consensus) {
+            return Err(tor_bytes::Error::InvalidMessage(
+                "relay weights would overflow".into(),
+            ));
+        }
+        Ok(ws)
     }
 }
 
@@ -32,6 +32,7 @@
 use crate::util::intern::InternCache;
 
 pub mod authcert;
+pub mod bwfile;
 #[cfg(feature = "hs-common")]
 pub mod hsdesc;
 pub mod microdesc;
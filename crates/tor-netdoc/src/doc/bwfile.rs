@@ -0,0 +1,320 @@
+//! Parsing implementation for bandwidth files.
+//!
+//! A bandwidth file is the output of a bandwidth scanner, such as `sbws`:
+//! it lists the bandwidth that the scanner measured for each relay.
+//! Directory authorities use these files to decide the weights that they vote
+//! for, but the files are also useful to relay operators and researchers who
+//! want the measured values themselves.
+//!
+//! Bandwidth files are not in Tor's usual meta-format: they are a timestamp,
+//! followed by a header of `key=value` lines, followed by one line of
+//! space-separated `key=value` pairs for each relay.  The format is described
+//! in [bandwidth-file-spec.txt](https://spec.torproject.org/bandwidth-file-spec).
+//!
+//! We support versions 1.0.0 through 1.x of the format.  We ignore header
+//! and relay keys that we don't recognize.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use tor_llcrypto::pk::ed25519::Ed25519Identity;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+
+use crate::types::misc::{Ed25519Public, LongIdent};
+use crate::{NetdocErrorKind as EK, Pos, Result};
+
+/// The value that a bandwidth file gives for `master_key_ed25519` when the
+/// scanner didn't know the relay's Ed25519 identity.
+const NO_ED25519_IDENTITY: &str = "noed25519identity";
+
+/// The latest timestamp that we accept in a bandwidth file: the last second of
+/// the year 9999.
+///
+/// Later times aren't plausible, and many of the libraries that we use to
+/// store and display times can't represent them.
+const MAX_TIMESTAMP: u64 = 253_402_300_799;
+
+/// A parsed bandwidth file.
+#[derive(Clone, Debug)]
+pub struct BandwidthFile {
+    /// The time at which the scanner produced this file.
+    timestamp: SystemTime,
+    /// The version of the format that this file uses.
+    version: String,
+    /// The header entries in this file, other than the version.
+    headers: HashMap<String, String>,
+    /// The measurements in this file, in the order that they appeared.
+    relays: Vec<BandwidthFileEntry>,
+}
+
+/// The measurement for a single relay in a [`BandwidthFile`].
+#[derive(Clone, Debug)]
+pub struct BandwidthFileEntry {
+    /// The relay's RSA identity.
+    rsa_identity: RsaIdentity,
+    /// The relay's Ed25519 identity, if the scanner knew it.
+    ed_identity: Option<Ed25519Identity>,
+    /// The relay's nickname, if the file gave one.
+    nickname: Option<String>,
+    /// The measured bandwidth, in kilobytes per second.
+    bandwidth: u32,
+    /// True if the scanner says it didn't actually measure this relay.
+    unmeasured: bool,
+}
+
+impl BandwidthFile {
+    /// Parse a bandwidth file from a string.
+    pub fn parse(s: &str) -> Result<BandwidthFile> {
+        Self::parse_inner(s).map_err(|e| e.within(s))
+    }
+
+    /// Implementation for `parse`: return errors with positions that
+    /// haven't yet been mapped to lines within `s`.
+    fn parse_inner(s: &str) -> Result<BandwidthFile> {
+        let mut lines = s.lines().peekable();
+
+        let first = lines
+            .next()
+            .ok_or_else(|| EK::MissingToken.with_msg("empty bandwidth file"))?;
+        let timestamp: u64 = first.trim().parse().map_err(|e| {
+            crate::Error::from(e)
+                .at_pos(Pos::at(first))
+                .with_msg("bad timestamp in bandwidth file")
+        })?;
+        let timestamp = Some(timestamp)
+            .filter(|secs| *secs <= MAX_TIMESTAMP)
+            .and_then(|secs| SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs)))
+            .ok_or_else(|| {
+                EK::BadArgument
+                    .at_pos(Pos::at(first))
+                    .with_msg("timestamp in bandwidth file is out of range")
+            })?;
+
+        // Version 1.0.0 files have no header after the timestamp.  Later
+        // versions must put "version" first in the header.
+        let mut version = "1.0.0".to_string();
+        let mut headers = HashMap::new();
+        if lines.peek().is_some_and(|l| l.starts_with("version=")) {
+            loop {
+                let line = lines.next().ok_or_else(|| {
+                    EK::MissingToken
+                        .at_pos(Pos::at_end_of(s))
+                        .with_msg("bandwidth file header has no terminator")
+                })?;
+                if line == "=====" || line == "====" {
+                    break;
+                }
+                let (key, value) = line.split_once('=').ok_or_else(|| {
+                    EK::BadArgument
+                        .at_pos(Pos::at(line))
+                        .with_msg("bandwidth file header line is not key=value")
+                })?;
+                if key == "version" {
+                    version = value.to_string();
+                } else {
+                    headers.insert(key.to_string(), value.to_string());
+                }
+            }
+        }
+
+        let relays = lines
+            .filter(|line| !line.trim().is_empty())
+            .map(BandwidthFileEntry::parse)
+            .collect::<Result<_>>()?;
+
+        Ok(BandwidthFile {
+            timestamp,
+            version,
+            headers,
+            relays,
+        })
+    }
+
+    /// Return the time at which the scanner produced this file.
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    /// Return the version of the bandwidth file format that this file uses.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Return the value of the header entry `key`, if there is one.
+    ///
+    /// (Use [`version`](Self::version) for the version.)
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.get(key).map(String::as_str)
+    }
+
+    /// Return the measurements in this file, in the order that they appeared.
+    pub fn relays(&self) -> &[BandwidthFileEntry] {
+        &self.relays
+    }
+}
+
+impl BandwidthFileEntry {
+    /// Parse a single relay line from a bandwidth file.
+    fn parse(line: &str) -> Result<BandwidthFileEntry> {
+        let mut rsa_identity = None;
+        let mut ed_identity = None;
+        let mut nickname = None;
+        let mut bandwidth = None;
+        let mut unmeasured = false;
+
+        for item in line.split_ascii_whitespace() {
+            let (key, value) = item.split_once('=').ok_or_else(|| {
+                EK::BadArgument
+                    .at_pos(Pos::at(item))
+                    .with_msg("bandwidth file entry is not key=value")
+            })?;
+            match key {
+                "node_id" => rsa_identity = Some(value.parse::<LongIdent>()?.into()),
+                "master_key_ed25519" if value != NO_ED25519_IDENTITY => {
+                    ed_identity = Some(value.parse::<Ed25519Public>()?.into());
+                }
+                "nick" => nickname = Some(value.to_string()),
+                "bw" => {
+                    bandwidth = Some(
+                        value
+                            .parse()
+                            .map_err(|e| crate::Error::from(e).at_pos(Pos::at(value)))?,
+                    );
+                }
+                "unmeasured" => unmeasured = value == "1",
+                _ => {}
+            }
+        }
+
+        let missing = |what: &'static str| EK::MissingToken.at_pos(Pos::at(line)).with_msg(what);
+        Ok(BandwidthFileEntry {
+            rsa_identity: rsa_identity
+                .ok_or_else(|| missing("bandwidth file entry has no node_id"))?,
+            ed_identity,
+            nickname,
+            bandwidth: bandwidth.ok_or_else(|| missing("bandwidth file entry has no bw"))?,
+            unmeasured,
+        })
+    }
+
+    /// Return the RSA identity of the relay.
+    pub fn rsa_identity(&self) -> &RsaIdentity {
+        &self.rsa_identity
+    }
+
+    /// Return the Ed25519 identity of the relay, if the scanner knew it.
+    pub fn ed_identity(&self) -> Option<&Ed25519Identity> {
+        self.ed_identity.as_ref()
+    }
+
+    /// Return the nickname of the relay, if the file gave one.
+    pub fn nickname(&self) -> Option<&str> {
+        self.nickname.as_deref()
+    }
+
+    /// Return the measured bandwidth of the relay, in kilobytes per second.
+    ///
+    /// This value is not scaled or otherwise adjusted: it is whatever the
+    /// scanner wrote.
+    pub fn bandwidth(&self) -> u32 {
+        self.bandwidth
+    }
+
+    /// Return true if the scanner says that it didn't actually measure this
+    /// relay.
+    ///
+    /// In that case, the [`bandwidth`](Self::bandwidth) is only an estimate.
+    pub fn is_unmeasured(&self) -> bool {
+        self.unmeasured
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    const V1_4: &str = "\
+1523911758
+version=1.4.0
+software=sbws
+software_version=1.0.2
+latest_bandwidth=2018-04-16T20:49:18
+=====
+bw=38000 bw_mean=1127824 bw_median=1180062 desc_bw_avg=1073741824 desc_bw_obs_last=17230879 desc_bw_obs_mean=14732306 error_circ=0 error_misc=0 error_stream=1 master_key_ed25519=ZGVmZ2hpamtsbW5vcHFyc3R1dnd4eXp7fH1+f4CBgoM nick=Test node_id=$68A483E05A2ABDCA6DA5A3EF8DB5177638A27F80 rtt=380 success=272 time=2018-05-08T16:13:26
+bw=1 node_id=$96C15995F30895689291F455587BD94CA427B6FC master_key_ed25519=noed25519identity unmeasured=1
+";
+
+    #[test]
+    fn parse_v1_4() {
+        let file = BandwidthFile::parse(V1_4).unwrap();
+        assert_eq!(
+            file.timestamp(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1523911758)
+        );
+        assert_eq!(file.version(), "1.4.0");
+        assert_eq!(file.header("software"), Some("sbws"));
+        assert_eq!(file.header("version"), None);
+        assert_eq!(file.relays().len(), 2);
+
+        let r = &file.relays()[0];
+        assert_eq!(
+            r.rsa_identity(),
+            &RsaIdentity::from_hex("68A483E05A2ABDCA6DA5A3EF8DB5177638A27F80").unwrap()
+        );
+        assert!(r.ed_identity().is_some());
+        assert_eq!(r.nickname(), Some("Test"));
+        assert_eq!(r.bandwidth(), 38000);
+        assert!(!r.is_unmeasured());
+
+        let r = &file.relays()[1];
+        assert!(r.ed_identity().is_none());
+        assert_eq!(r.nickname(), None);
+        assert_eq!(r.bandwidth(), 1);
+        assert!(r.is_unmeasured());
+    }
+
+    #[test]
+    fn parse_v1_0() {
+        let file = BandwidthFile::parse(
+            "1523911758\n\
+             node_id=$68A483E05A2ABDCA6DA5A3EF8DB5177638A27F80 bw=760 nick=Test\n",
+        )
+        .unwrap();
+        assert_eq!(file.version(), "1.0.0");
+        assert_eq!(file.relays().len(), 1);
+        assert_eq!(file.relays()[0].bandwidth(), 760);
+    }
+
+    #[test]
+    fn parse_bad() {
+        let kind = |s: &str| BandwidthFile::parse(s).unwrap_err().netdoc_error_kind();
+        assert_eq!(kind(""), EK::MissingToken);
+        assert_eq!(kind("yesterday\n"), EK::BadArgument);
+        assert_eq!(kind("253402300800\n"), EK::BadArgument);
+        assert_eq!(kind("18446744073709551615\n"), EK::BadArgument);
+        assert!(BandwidthFile::parse("253402300799\n").is_ok());
+        assert_eq!(kind("1523911758\nversion=1.4.0\n"), EK::MissingToken);
+        assert_eq!(
+            kind("1523911758\nnode_id=$68A483E05A2ABDCA6DA5A3EF8DB5177638A27F80\n"),
+            EK::MissingToken
+        );
+        assert_eq!(kind("1523911758\nbw=12 node_id=$1234\n"), EK::BadArgument);
+
+        let err = BandwidthFile::parse("1523911758\nversion=1.4.0\n=====\nbw=lots node_id=x\n")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "bad argument for entry on line 4, byte 4");
+    }
+}
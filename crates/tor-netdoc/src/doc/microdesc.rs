@@ -26,6 +26,10 @@ use tor_error::internal;
 use tor_llcrypto::d;
 use tor_llcrypto::pk::{curve25519, ed25519, rsa};
 
+#[cfg(any(feature = "routerdesc", feature = "ns_consensus"))]
+use crate::doc::routerdesc::RdDigest;
+#[cfg(feature = "routerdesc")]
+use crate::doc::routerdesc::RouterDesc;
 use digest::Digest;
 use once_cell::sync::Lazy;
 use std::sync::Arc;
@@ -34,6 +38,8 @@ use std::time;
 
 #[cfg(feature = "build_docs")]
 mod build;
+#[cfg(feature = "snapshot")]
+mod snapshot;
 
 #[cfg(feature = "build_docs")]
 pub use build::MicrodescBuilder;
@@ -51,6 +57,20 @@ pub struct MicrodescAnnotation {
 /// The digest of a microdescriptor as used in microdesc consensuses
 pub type MdDigest = [u8; 32];
 
+/// Return the digest that we give to a microdescriptor derived from the
+/// router descriptor whose digest is `rd_digest`.
+///
+/// Such a microdescriptor has no encoding of its own, so it can't have a real
+/// digest.  Instead we hash the router descriptor's digest, so that we can
+/// still tell which routerstatus the microdescriptor belongs to.
+#[cfg(any(feature = "routerdesc", feature = "ns_consensus"))]
+pub(crate) fn derived_md_digest(rd_digest: &RdDigest) -> MdDigest {
+    let mut d = d::Sha256::new();
+    d.update(b"microdesc derived from routerdesc");
+    d.update(rd_digest);
+    d.finalize().into()
+}
+
 /// A single microdescriptor.
 #[allow(dead_code)]
 #[cfg_attr(
@@ -105,6 +125,29 @@ impl Microdesc {
         MicrodescBuilder::new()
     }
 
+    /// Construct a microdescriptor holding the same information as the
+    /// router descriptor `rd`.
+    ///
+    /// The IPv4 exit policy is summarized with
+    /// [`AddrPolicy::ipv4_summary`](crate::types::policy::AddrPolicy::ipv4_summary).
+    ///
+    /// The result has no encoding, and its [`digest`](Microdesc::digest) is not
+    /// the digest of any real microdescriptor: instead, it matches the one
+    /// that [`NsConsensus::to_md_consensus`](crate::doc::netstatus::NsConsensus::to_md_consensus)
+    /// lists for `rd`.
+    #[cfg(feature = "routerdesc")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "routerdesc")))]
+    pub fn from_routerdesc(rd: &RouterDesc) -> Self {
+        Microdesc {
+            sha256: derived_md_digest(rd.digest()),
+            ntor_onion_key: *rd.ntor_onion_key(),
+            family: Arc::clone(rd.family()),
+            ipv4_policy: rd.ipv4_policy().ipv4_summary().intern(),
+            ipv6_policy: Arc::clone(rd.ipv6_policy()),
+            ed25519_id: *rd.ed_identity(),
+        }
+    }
+
     /// Return the sha256 digest of this microdesc.
     pub fn digest(&self) -> &MdDigest {
         &self.sha256
@@ -506,6 +549,24 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    #[cfg(feature = "routerdesc")]
+    fn from_routerdesc() -> Result<()> {
+        use tor_checkable::{SelfSigned, Timebound};
+        let rd = RouterDesc::parse(include_str!("../../testdata/routerdesc1.txt"))?
+            .check_signature()?
+            .dangerously_assume_timely();
+        let md = Microdesc::from_routerdesc(&rd);
+
+        assert_eq!(md.digest(), &derived_md_digest(rd.digest()));
+        assert_eq!(md.ntor_key(), rd.ntor_onion_key());
+        assert_eq!(md.ed25519_id(), rd.ed_identity());
+        assert_eq!(md.family(), rd.family().as_ref());
+        assert!(!md.ipv4_policy().allows_some_port());
+        assert_eq!(md.ipv6_policy(), rd.ipv6_policy());
+        Ok(())
+    }
+
     #[test]
     fn parse_multi() -> Result<()> {
         use humantime::parse_rfc3339;
@@ -0,0 +1,89 @@
+//! Snapshot encoding for microdescriptors.
+//!
+//! See [`crate::snapshot`] for what a snapshot is, and what it's for.
+
+use super::*;
+use crate::snapshot::Snapshot;
+use tor_bytes::{EncodeResult, Reader, Result as BytesResult, Writer};
+
+impl Snapshot for Microdesc {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        self.sha256.write_onto(w)?;
+        self.ntor_onion_key.write_onto(w)?;
+        self.family.write_onto(w)?;
+        self.ipv4_policy.write_onto(w)?;
+        self.ipv6_policy.write_onto(w)?;
+        self.ed25519_id.write_onto(w)
+    }
+    fn take_from(r: &mut Reader<'_>) -> BytesResult<Self> {
+        Ok(Microdesc {
+            sha256: Snapshot::take_from(r)?,
+            ntor_onion_key: Snapshot::take_from(r)?,
+            family: Snapshot::take_from(r)?,
+            ipv4_policy: Snapshot::take_from(r)?,
+            ipv6_policy: Snapshot::take_from(r)?,
+            ed25519_id: Snapshot::take_from(r)?,
+        })
+    }
+}
+
+impl Microdesc {
+    /// Encode this microdescriptor onto the end of `w`, in a compact binary
+    /// format.
+    ///
+    /// Use [`read_snapshot`](Self::read_snapshot) to decode it again.  The
+    /// format can change between any two releases of this crate, so it is
+    /// only good for passing a microdescriptor between processes running the
+    /// same build.
+    #[cfg_attr(docsrs, doc(cfg(feature = "snapshot")))]
+    pub fn write_snapshot<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        Snapshot::write_onto(self, w)
+    }
+
+    /// Decode a microdescriptor that was encoded with
+    /// [`write_snapshot`](Self::write_snapshot).
+    ///
+    /// We don't check the microdescriptor's digest, since we don't have its
+    /// text: only use this function on snapshots from a process that you
+    /// trust.
+    #[cfg_attr(docsrs, doc(cfg(feature = "snapshot")))]
+    pub fn read_snapshot(r: &mut Reader<'_>) -> BytesResult<Self> {
+        Snapshot::take_from(r)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let md = Microdesc::parse(include_str!("../../../testdata/microdesc1.txt")).unwrap();
+
+        let mut snapshot = Vec::new();
+        md.write_snapshot(&mut snapshot).unwrap();
+        let mut r = Reader::from_slice(&snapshot);
+        let decoded = Microdesc::read_snapshot(&mut r).unwrap();
+        r.should_be_exhausted().unwrap();
+
+        assert_eq!(decoded.digest(), md.digest());
+        assert_eq!(decoded.ntor_key(), md.ntor_key());
+        assert_eq!(decoded.family(), md.family());
+        assert_eq!(decoded.ipv4_policy(), md.ipv4_policy());
+        assert_eq!(decoded.ipv6_policy(), md.ipv6_policy());
+        assert_eq!(decoded.ed25519_id(), md.ed25519_id());
+    }
+}
@@ -33,7 +33,8 @@
 //! microdescriptors. We should probably decide whether we actually
 //! want to do this.
 //!
-//! TODO: This module doesn't implement vote parsing at all yet.
+//! Votes and detached signatures are only parsed if the `votes` feature
+//! is enabled.
 //!
 //! TODO: This module doesn't implement ns-flavored consensuses.
 //!
@@ -47,6 +48,10 @@ mod rs;
 
 #[cfg(feature = "build_docs")]
 mod build;
+#[cfg(feature = "snapshot")]
+mod snapshot;
+#[cfg(feature = "votes")]
+mod vote;
 
 use crate::doc::authcert::{AuthCert, AuthCertKeyIds};
 use crate::parse::keyword::Keyword;
@@ -79,6 +84,8 @@ pub use rs::MdConsensusRouterStatus;
 #[cfg(feature = "ns_consensus")]
 pub use rs::NsConsensusRouterStatus;
 use void::ResultVoidExt as _;
+#[cfg(feature = "votes")]
+pub use vote::{DetachedSignatures, UncheckedVote, Vote};
 
 /// The lifetime of a networkstatus document.
 ///
@@ -234,11 +241,12 @@ pub struct ProtoStatus {
 }
 
 /// A recognized 'flavor' of consensus document.
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash, Ord, PartialOrd)]
 #[non_exhaustive]
 pub enum ConsensusFlavor {
     /// A "microdesc"-flavored consensus.  This is the one that
     /// clients and relays use today.
+    #[default]
     Microdesc,
     /// A "networkstatus"-flavored consensus.  It's used for
     /// historical and network-health purposes.  Instead of listing
@@ -413,6 +421,9 @@ struct ConsensusHeader {
     /// upgrade the consensus algorithm.)
     #[cfg_attr(docsrs, doc(cfg(feature = "dangerous-expose-struct-fields")))]
     consensus_method: u32,
+    /// The flags that the authorities voted on when making this consensus.
+    #[cfg_attr(docsrs, doc(cfg(feature = "dangerous-expose-struct-fields")))]
+    known_flags: Vec<String>,
     /// Global shared-random value for the previous shared-random period.
     #[cfg_attr(docsrs, doc(cfg(feature = "dangerous-expose-struct-fields")))]
     shared_rand_prev: Option<SharedRandStatus>,
@@ -465,7 +476,7 @@ bitflags! {
     /// The bit values used to represent the flags have no meaning;
     /// they may change between releases of this crate.  Relying on their
     /// values may void your semver guarantees.
-    #[derive(Clone, Copy, Debug)]
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
     pub struct RelayFlags: u16 {
         /// Is this a directory authority?
         const AUTHORITY = (1<<0);
@@ -519,7 +530,7 @@ bitflags! {
 
 /// Recognized weight fields on a single relay in a consensus
 #[non_exhaustive]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum RelayWeight {
     /// An unmeasured weight for a relay.
     Unmeasured(u32),
@@ -636,6 +647,10 @@ pub struct Consensus<RS> {
     /// Footer for the consensus object.
     #[cfg_attr(docsrs, doc(cfg(feature = "dangerous-expose-struct-fields")))]
     footer: Footer,
+    /// The number of authority signatures that were listed on this
+    /// consensus when we parsed it.
+    #[cfg_attr(docsrs, doc(cfg(feature = "dangerous-expose-struct-fields")))]
+    n_signatures: usize,
 }
 
 /// A consensus document that lists relays along with their
@@ -699,6 +714,37 @@ impl<RS> Consensus<RS> {
         self.header.shared_rand_prev.as_ref()
     }
 
+    /// Return the consensus method that the authorities used to make this
+    /// consensus.
+    pub fn consensus_method(&self) -> u32 {
+        self.header.consensus_method
+    }
+
+    /// Return the flags that the authorities voted on when making this
+    /// consensus, as listed in its `known-flags` line.
+    ///
+    /// This is empty for a consensus made with a
+    /// [`ConsensusBuilder`](crate::doc::netstatus::ConsensusBuilder).
+    pub fn known_flags(&self) -> &[String] {
+        &self.header.known_flags[..]
+    }
+
+    /// Return the voting delays that this consensus declares, if any: the
+    /// number of seconds that the authorities wait for votes to propagate,
+    /// and then for signatures to propagate.
+    pub fn voting_delay(&self) -> Option<(u32, u32)> {
+        self.header.hdr.voting_delay
+    }
+
+    /// Return the number of authority signatures that were listed on this
+    /// consensus.
+    ///
+    /// This counts every `directory-signature` line, including any that we
+    /// couldn't check.  It is zero for a consensus that we built ourselves.
+    pub fn n_signatures(&self) -> usize {
+        self.n_signatures
+    }
+
     /// Return a [`ProtoStatus`] that lists the network's current requirements and
     /// recommendations for the list of protocols that every relay must implement.  
     pub fn relay_protocol_status(&self) -> &ProtoStatus {
@@ -712,6 +758,37 @@ impl<RS> Consensus<RS> {
     }
 }
 
+#[cfg(feature = "ns_consensus")]
+impl NsConsensus {
+    /// Return a microdesc-flavored copy of this consensus.
+    ///
+    /// The result lists the same relays, with the same flags and weights, and
+    /// has the same header and footer.  But instead of a router descriptor
+    /// digest, each routerstatus lists the digest of the microdescriptor that
+    /// `Microdesc::from_routerdesc` would build from that router descriptor.
+    ///
+    /// This lets code that only knows how to use a microdesc consensus work
+    /// with an ns consensus and its router descriptors.  The result has no
+    /// signatures of its own, and can't be encoded.
+    pub fn to_md_consensus(&self) -> MdConsensus {
+        let mut header = self.header.clone();
+        header.hdr.flavor = ConsensusFlavor::Microdesc;
+        Consensus {
+            header,
+            voters: self.voters.clone(),
+            relays: self
+                .relays
+                .iter()
+                .map(|rs| {
+                    rs.to_md_routerstatus(crate::doc::microdesc::derived_md_digest(rs.rd_digest()))
+                })
+                .collect(),
+            footer: self.footer.clone(),
+            n_signatures: self.n_signatures,
+        }
+    }
+}
+
 decl_keyword! {
     /// Keywords that can be used in votes and consensuses.
     // TODO: This is public because otherwise we can't use it in the
@@ -810,35 +887,6 @@ static NS_HEADER_RULES_CONSENSUS: Lazy<SectionRules<NetstatusKwd>> = Lazy::new(|
     rules.add(UNRECOGNIZED.rule().may_repeat().obj_optional());
     rules.build()
 });
-/*
-/// Rules for parsing the header of a vote.
-static NS_HEADER_RULES_VOTE: SectionRules<NetstatusKwd> = {
-    use NetstatusKwd::*;
-    let mut rules = NS_HEADER_RULES_COMMON_.clone();
-    rules.add(CONSENSUS_METHODS.rule().args(1..));
-    rules.add(FLAG_THRESHOLDS.rule());
-    rules.add(BANDWIDTH_FILE_HEADERS.rule());
-    rules.add(BANDWIDTH_FILE_DIGEST.rule().args(1..));
-    rules.add(UNRECOGNIZED.rule().may_repeat().obj_optional());
-    rules
-};
-/// Rules for parsing a single voter's information in a vote.
-static NS_VOTERINFO_RULES_VOTE: SectionRules<NetstatusKwd> = {
-    use NetstatusKwd::*;
-    let mut rules = SectionRules::new();
-    rules.add(DIR_SOURCE.rule().required().args(6..));
-    rules.add(CONTACT.rule().required());
-    rules.add(LEGACY_DIR_KEY.rule().args(1..));
-    rules.add(SHARED_RAND_PARTICIPATE.rule().no_args());
-    rules.add(SHARED_RAND_COMMIT.rule().may_repeat().args(4..));
-    rules.add(SHARED_RAND_PREVIOUS_VALUE.rule().args(2..));
-    rules.add(SHARED_RAND_CURRENT_VALUE.rule().args(2..));
-    // then comes an entire cert: When we implement vote parsing,
-    // we should use the authcert code for handling that.
-    rules.add(UNRECOGNIZED.rule().may_repeat().obj_optional());
-    rules
-};
- */
 /// Rules for parsing a single voter's information in a consensus
 static NS_VOTERINFO_RULES_CONSENSUS: Lazy<SectionRules<NetstatusKwd>> = Lazy::new(|| {
     use NetstatusKwd::*;
@@ -871,17 +919,6 @@ static NS_ROUTERSTATUS_RULES_NSCON: Lazy<SectionRules<NetstatusKwd>> = Lazy::new
     rules.build()
 });
 
-/*
-/// Rules for parsing a single routerstatus in a vote
-static NS_ROUTERSTATUS_RULES_VOTE: SectionRules<NetstatusKwd> = {
-    use NetstatusKwd::*;
-        let mut rules = NS_ROUTERSTATUS_RULES_COMMON_.clone();
-        rules.add(RS_R.rule().required().args(8..));
-        rules.add(RS_M.rule().may_repeat().args(2..));
-        rules.add(RS_ID.rule().may_repeat().args(2..)); // may-repeat?
-        rules
-    };
-*/
 /// Rules for parsing a single routerstatus in a microdesc consensus
 static NS_ROUTERSTATUS_RULES_MDCON: Lazy<SectionRules<NetstatusKwd>> = Lazy::new(|| {
     use NetstatusKwd::*;
@@ -1119,12 +1156,16 @@ impl ConsensusHeader {
             return Err(EK::BadDocumentType.err());
         }
 
-        // We're ignoring KNOWN_FLAGS in the consensus.
-
         let hdr = CommonHeader::from_section(sec)?;
 
         let consensus_method: u32 = sec.required(CONSENSUS_METHOD)?.parse_arg(0)?;
 
+        let known_flags = sec
+            .required(KNOWN_FLAGS)?
+            .args()
+            .map(str::to_string)
+            .collect();
+
         let shared_rand_prev = sec
             .get(SHARED_RAND_PREVIOUS_VALUE)
             .map(SharedRandStatus::from_item)
@@ -1138,6 +1179,7 @@ impl ConsensusHeader {
         Ok(ConsensusHeader {
             hdr,
             consensus_method,
+            known_flags,
             shared_rand_prev,
             shared_rand_cur,
         })
@@ -1304,7 +1346,11 @@ impl Footer {
 }
 
 /// Result of checking a single authority signature.
-enum SigCheckResult {
+///
+/// Returned by [`UnvalidatedConsensus::signature_statuses`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum SignatureStatus {
     /// The signature checks out.  Great!
     Valid,
     /// The signature is invalid; no additional information could make it
@@ -1313,6 +1359,9 @@ enum SigCheckResult {
     /// We can't check the signature because we don't have a
     /// certificate with the right signing key.
     MissingCert,
+    /// We can't check the signature because it uses a digest algorithm that
+    /// we don't support for this document.
+    UnsupportedDigest,
 }
 
 impl Signature {
@@ -1367,14 +1416,14 @@ impl Signature {
     /// Try to check whether this signature is a valid signature of a
     /// provided digest, given a slice of certificates that might contain
     /// its signing key.
-    fn check_signature(&self, signed_digest: &[u8], certs: &[AuthCert]) -> SigCheckResult {
+    fn check_signature(&self, signed_digest: &[u8], certs: &[AuthCert]) -> SignatureStatus {
         match self.find_cert(certs) {
-            None => SigCheckResult::MissingCert,
+            None => SignatureStatus::MissingCert,
             Some(cert) => {
                 let key = cert.signing_key();
                 match key.verify(signed_digest, &self.signature[..]) {
-                    Ok(()) => SigCheckResult::Valid,
-                    Err(_) => SigCheckResult::Invalid,
+                    Ok(()) => SignatureStatus::Valid,
+                    Err(_) => SignatureStatus::Invalid,
                 }
             }
         }
@@ -1526,11 +1575,12 @@ impl<RS: RouterStatus + ParseRouterStatus> Consensus<RS> {
 
         let footer = Self::take_footer(r)?;
 
-        let consensus = Consensus {
+        let mut consensus = Consensus {
             header,
             voters,
             relays,
             footer,
+            n_signatures: 0,
         };
 
         // Find the signatures.
@@ -1550,6 +1600,7 @@ impl<RS: RouterStatus + ParseRouterStatus> Consensus<RS> {
             }
             signatures.push(sig);
         }
+        consensus.n_signatures = signatures.len();
 
         let end_pos = match first_sig {
             None => return Err(EK::MissingToken.with_msg("directory-signature")),
@@ -1656,6 +1707,17 @@ impl<RS> UnvalidatedConsensus<RS> {
         self.siggroup.could_validate(authorities)
     }
 
+    /// Check every signature on this consensus with `certs`, and return the
+    /// keys that made each signature along with the outcome, in the order in
+    /// which the signatures appear.
+    ///
+    /// Unlike [`check_signature`](tor_checkable::ExternallySigned::check_signature),
+    /// this doesn't decide whether the consensus is well-signed: it's for
+    /// reporting on each authority's signature separately.
+    pub fn signature_statuses(&self, certs: &[AuthCert]) -> Vec<(AuthCertKeyIds, SignatureStatus)> {
+        self.siggroup.statuses(certs)
+    }
+
     /// Return the number of relays in this unvalidated consensus.
     ///
     /// This function is unstable. It is only enabled if the crate was
@@ -1757,6 +1819,30 @@ impl SignatureGroup {
         signed_by.len() > (authorities.len() / 2)
     }
 
+    /// Return the digest of the document that `sig` signs, if we have it.
+    fn digest_for(&self, sig: &Signature) -> Option<&[u8]> {
+        match sig.digestname.as_ref() {
+            "sha256" => self.sha256.as_ref().map(|a| &a[..]),
+            "sha1" => self.sha1.as_ref().map(|a| &a[..]),
+            _ => None, // We don't know how to find this digest.
+        }
+    }
+
+    /// Check each signature in this group with `certs`, and return its keys
+    /// and the outcome.
+    fn statuses(&self, certs: &[AuthCert]) -> Vec<(AuthCertKeyIds, SignatureStatus)> {
+        self.signatures
+            .iter()
+            .map(|sig| {
+                let status = match self.digest_for(sig) {
+                    Some(d) => sig.check_signature(d, certs),
+                    None => SignatureStatus::UnsupportedDigest,
+                };
+                (sig.key_ids, status)
+            })
+            .collect()
+    }
+
     /// Return true if the signature group defines a valid signature.
     ///
     /// A signature is valid if it signed by more than half of the
@@ -1777,21 +1863,14 @@ impl SignatureGroup {
                 continue;
             }
 
-            let d: Option<&[u8]> = match sig.digestname.as_ref() {
-                "sha256" => self.sha256.as_ref().map(|a| &a[..]),
-                "sha1" => self.sha1.as_ref().map(|a| &a[..]),
-                _ => None, // We don't know how to find this digest.
-            };
-            if d.is_none() {
+            let Some(d) = self.digest_for(sig) else {
                 // We don't support this kind of digest for this kind
                 // of document.
                 continue;
-            }
+            };
 
-            // Unwrap should be safe because of above `d.is_none()` check
-            #[allow(clippy::unwrap_used)]
-            match sig.check_signature(d.as_ref().unwrap(), certs) {
-                SigCheckResult::Valid => {
+            match sig.check_signature(d, certs) {
+                SignatureStatus::Valid => {
                     ok.insert(*id_fingerprint);
                 }
                 _ => continue,
@@ -1883,6 +1962,10 @@ mod test {
         let consensus = consensus.check_signature(&certs)?;
 
         assert_eq!(6, consensus.relays().len());
+        assert_eq!(consensus.consensus_method(), 28);
+        assert_eq!(consensus.known_flags().len(), 10);
+        assert_eq!(consensus.known_flags()[0], "Authority");
+        assert_eq!(consensus.n_signatures(), 3);
         let r0 = &consensus.relays()[0];
         assert_eq!(
             r0.md_digest(),
@@ -1931,6 +2014,36 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    #[cfg(feature = "ns_consensus")]
+    fn ns_to_md_consensus() -> Result<()> {
+        use crate::doc::microdesc::derived_md_digest;
+        use tor_checkable::Timebound;
+        let (_, _, consensus) = NsConsensus::parse(NS_CONSENSUS)?;
+        let consensus = consensus
+            .dangerously_assume_timely()
+            .dangerously_assume_wellsigned();
+        let md_consensus = consensus.to_md_consensus();
+
+        assert_eq!(md_consensus.header.hdr.flavor, ConsensusFlavor::Microdesc);
+        assert_eq!(
+            md_consensus.lifetime().valid_after(),
+            consensus.lifetime().valid_after()
+        );
+        assert_eq!(md_consensus.params(), consensus.params());
+        assert_eq!(md_consensus.relays().len(), consensus.relays().len());
+        for (md_rs, ns_rs) in md_consensus.relays().iter().zip(consensus.relays()) {
+            assert_eq!(md_rs.rsa_identity(), ns_rs.rsa_identity());
+            assert_eq!(md_rs.nickname(), ns_rs.nickname());
+            assert_eq!(md_rs.flags(), ns_rs.flags());
+            assert_eq!(md_rs.weight(), ns_rs.weight());
+            assert_eq!(md_rs.addrs(), ns_rs.addrs());
+            assert_eq!(md_rs.md_digest(), &derived_md_digest(ns_rs.rd_digest()));
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_bad() {
         use crate::Pos;
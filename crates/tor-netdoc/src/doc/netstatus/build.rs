@@ -243,6 +243,7 @@ impl<RS: RouterStatus + Clone> ConsensusBuilder<RS> {
         let header = ConsensusHeader {
             hdr,
             consensus_method,
+            known_flags: Vec::new(),
             shared_rand_prev: self.shared_rand_prev.clone(),
             shared_rand_cur: self.shared_rand_cur.clone(),
         };
@@ -260,6 +261,7 @@ impl<RS: RouterStatus + Clone> ConsensusBuilder<RS> {
             voters: self.voters.clone(),
             relays,
             footer,
+            n_signatures: 0,
         })
     }
 }
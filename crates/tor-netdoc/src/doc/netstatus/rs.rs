@@ -8,6 +8,8 @@ pub(crate) mod build;
 mod md;
 #[cfg(feature = "ns_consensus")]
 mod ns;
+#[cfg(feature = "snapshot")]
+mod snapshot;
 
 use super::{ConsensusFlavor, NetstatusKwd, RelayFlags, RelayWeight};
 use crate::doc;
@@ -175,6 +177,22 @@ macro_rules! implement_accessors {
             pub fn is_flagged_middle_only(&self) -> bool {
                 self.rs.flags.contains(RelayFlags::MIDDLE_ONLY)
             }
+            /// Remove `flags` from the relay flags of this routerstatus.
+            ///
+            /// This function is unstable. It is only enabled if the crate was
+            /// built with the `experimental-api` feature.
+            #[cfg(feature = "experimental-api")]
+            pub fn clear_flags(&mut self, flags: RelayFlags) {
+                self.rs.flags.remove(flags);
+            }
+            /// Replace the declared weight of this routerstatus.
+            ///
+            /// This function is unstable. It is only enabled if the crate was
+            /// built with the `experimental-api` feature.
+            #[cfg(feature = "experimental-api")]
+            pub fn set_weight(&mut self, weight: RelayWeight) {
+                self.rs.weight = weight;
+            }
         }
     };
 }
@@ -48,6 +48,18 @@ impl MdConsensusRouterStatus {
 
 impl Sealed for MdConsensusRouterStatus {}
 
+#[cfg(feature = "snapshot")]
+impl crate::snapshot::Snapshot for MdConsensusRouterStatus {
+    fn write_onto<W: tor_bytes::Writer + ?Sized>(&self, w: &mut W) -> tor_bytes::EncodeResult<()> {
+        self.rs.write_onto(w)
+    }
+    fn take_from(r: &mut tor_bytes::Reader<'_>) -> tor_bytes::Result<Self> {
+        Ok(MdConsensusRouterStatus {
+            rs: crate::snapshot::Snapshot::take_from(r)?,
+        })
+    }
+}
+
 impl RouterStatus for MdConsensusRouterStatus {
     type DocumentDigest = MdDigest;
 
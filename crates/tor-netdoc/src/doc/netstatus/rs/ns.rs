@@ -2,6 +2,8 @@
 //! old-style "ns" consensus documents.
 
 use super::{FromRsString, GenericRouterStatus};
+use crate::doc::microdesc::MdDigest;
+use crate::doc::netstatus::MdConsensusRouterStatus;
 use crate::doc::netstatus::{
     ConsensusFlavor, NetstatusKwd, ParseRouterStatus, RelayFlags, RelayWeight, RouterStatus,
 };
@@ -10,6 +12,7 @@ use crate::types::misc::*;
 use crate::{parse::parser::Section, util::private::Sealed};
 use crate::{Error, Result};
 use std::net;
+use std::sync::Arc;
 
 use tor_error::internal;
 use tor_llcrypto::pk::rsa::RsaIdentity;
@@ -47,6 +50,23 @@ impl NsConsensusRouterStatus {
     pub fn rd_digest(&self) -> &RdDigest {
         &self.rs.doc_digest
     }
+
+    /// Return a copy of this routerstatus as it would appear in a microdesc
+    /// consensus, listing the microdescriptor digest `md_digest`.
+    pub(crate) fn to_md_routerstatus(&self, md_digest: MdDigest) -> MdConsensusRouterStatus {
+        let rs = &self.rs;
+        GenericRouterStatus {
+            nickname: rs.nickname.clone(),
+            identity: rs.identity,
+            addrs: rs.addrs.clone(),
+            doc_digest: md_digest,
+            flags: rs.flags,
+            version: rs.version.clone(),
+            protos: Arc::clone(&rs.protos),
+            weight: rs.weight,
+        }
+        .into()
+    }
 }
 
 impl Sealed for NsConsensusRouterStatus {}
@@ -0,0 +1,58 @@
+//! Snapshot encoding for routerstatus entries.
+//!
+//! See [`crate::snapshot`] for what a snapshot is, and what it's for.
+
+use super::*;
+use crate::snapshot::{take_parsed, Snapshot};
+use tor_bytes::{EncodeResult, Error as BytesError, Reader, Result as BytesResult, Writer};
+
+impl Snapshot for Version {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        match self {
+            Version::Tor(v) => {
+                w.write_u8(0);
+                v.to_string().write_onto(w)
+            }
+            Version::Other(s) => {
+                w.write_u8(1);
+                s.to_string().write_onto(w)
+            }
+        }
+    }
+    fn take_from(r: &mut Reader<'_>) -> BytesResult<Self> {
+        match r.take_u8()? {
+            0 => Ok(Version::Tor(take_parsed(r, "Tor version")?)),
+            1 => Ok(Version::Other(
+                OTHER_VERSION_CACHE.intern_ref(&String::take_from(r)?),
+            )),
+            _ => Err(BytesError::InvalidMessage(
+                "unrecognized version type".into(),
+            )),
+        }
+    }
+}
+
+impl<D: Snapshot> Snapshot for GenericRouterStatus<D> {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        self.nickname.to_string().write_onto(w)?;
+        self.identity.write_onto(w)?;
+        self.addrs.write_onto(w)?;
+        self.doc_digest.write_onto(w)?;
+        self.flags.write_onto(w)?;
+        self.version.write_onto(w)?;
+        self.protos.as_ref().write_onto(w)?;
+        self.weight.write_onto(w)
+    }
+    fn take_from(r: &mut Reader<'_>) -> BytesResult<Self> {
+        Ok(GenericRouterStatus {
+            nickname: take_parsed(r, "nickname")?,
+            identity: Snapshot::take_from(r)?,
+            addrs: Snapshot::take_from(r)?,
+            doc_digest: Snapshot::take_from(r)?,
+            flags: Snapshot::take_from(r)?,
+            version: Snapshot::take_from(r)?,
+            protos: doc::PROTOVERS_CACHE.intern(Snapshot::take_from(r)?),
+            weight: Snapshot::take_from(r)?,
+        })
+    }
+}
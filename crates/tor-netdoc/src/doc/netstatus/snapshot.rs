@@ -0,0 +1,288 @@
+//! Snapshot encoding for consensus documents.
+//!
+//! See [`crate::snapshot`] for what a snapshot is, and what it's for.
+
+use super::*;
+use crate::snapshot::{take_len, write_len, Snapshot};
+use tor_bytes::{EncodeResult, Error as BytesError, Reader, Result as BytesResult, Writer};
+
+impl Snapshot for ConsensusFlavor {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        w.write_u8(match self {
+            ConsensusFlavor::Microdesc => 0,
+            ConsensusFlavor::Ns => 1,
+        });
+        Ok(())
+    }
+    fn take_from(r: &mut Reader<'_>) -> BytesResult<Self> {
+        match r.take_u8()? {
+            0 => Ok(ConsensusFlavor::Microdesc),
+            1 => Ok(ConsensusFlavor::Ns),
+            _ => Err(BytesError::InvalidMessage("unrecognized flavor".into())),
+        }
+    }
+}
+
+impl Snapshot for Lifetime {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        self.valid_after.write_onto(w)?;
+        self.fresh_until.write_onto(w)?;
+        self.valid_until.write_onto(w)
+    }
+    fn take_from(r: &mut Reader<'_>) -> BytesResult<Self> {
+        let valid_after = Snapshot::take_from(r)?;
+        let fresh_until = Snapshot::take_from(r)?;
+        let valid_until = Snapshot::take_from(r)?;
+        Lifetime::new(valid_after, fresh_until, valid_until)
+            .map_err(|_| BytesError::InvalidMessage("invalid lifetime".into()))
+    }
+}
+
+impl Snapshot for NetParams<i32> {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        // Sort the parameters, so that equal documents get equal snapshots.
+        let mut params: Vec<(String, i32)> =
+            self.params.iter().map(|(k, v)| (k.clone(), *v)).collect();
+        params.sort();
+        params.write_onto(w)
+    }
+    fn take_from(r: &mut Reader<'_>) -> BytesResult<Self> {
+        Ok(Vec::<(String, i32)>::take_from(r)?.into_iter().collect())
+    }
+}
+
+impl Snapshot for ProtoStatus {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        self.recommended.write_onto(w)?;
+        self.required.write_onto(w)
+    }
+    fn take_from(r: &mut Reader<'_>) -> BytesResult<Self> {
+        Ok(ProtoStatus {
+            recommended: Snapshot::take_from(r)?,
+            required: Snapshot::take_from(r)?,
+        })
+    }
+}
+
+impl Snapshot for SharedRandStatus {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        self.n_reveals.write_onto(w)?;
+        self.value.0.write_onto(w)?;
+        self.timestamp.write_onto(w)
+    }
+    fn take_from(r: &mut Reader<'_>) -> BytesResult<Self> {
+        Ok(SharedRandStatus {
+            n_reveals: Snapshot::take_from(r)?,
+            value: SharedRandVal(Snapshot::take_from(r)?),
+            timestamp: Snapshot::take_from(r)?,
+        })
+    }
+}
+
+impl Snapshot for CommonHeader {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        self.flavor.write_onto(w)?;
+        self.lifetime.write_onto(w)?;
+        self.client_versions.write_onto(w)?;
+        self.relay_versions.write_onto(w)?;
+        self.client_protos.write_onto(w)?;
+        self.relay_protos.write_onto(w)?;
+        self.params.write_onto(w)?;
+        self.voting_delay.write_onto(w)
+    }
+    fn take_from(r: &mut Reader<'_>) -> BytesResult<Self> {
+        Ok(CommonHeader {
+            flavor: Snapshot::take_from(r)?,
+            lifetime: Snapshot::take_from(r)?,
+            client_versions: Snapshot::take_from(r)?,
+            relay_versions: Snapshot::take_from(r)?,
+            client_protos: Snapshot::take_from(r)?,
+            relay_protos: Snapshot::take_from(r)?,
+            params: Snapshot::take_from(r)?,
+            voting_delay: Snapshot::take_from(r)?,
+        })
+    }
+}
+
+impl Snapshot for ConsensusHeader {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        self.hdr.write_onto(w)?;
+        self.consensus_method.write_onto(w)?;
+        self.known_flags.write_onto(w)?;
+        self.shared_rand_prev.write_onto(w)?;
+        self.shared_rand_cur.write_onto(w)
+    }
+    fn take_from(r: &mut Reader<'_>) -> BytesResult<Self> {
+        Ok(ConsensusHeader {
+            hdr: Snapshot::take_from(r)?,
+            consensus_method: Snapshot::take_from(r)?,
+            known_flags: Snapshot::take_from(r)?,
+            shared_rand_prev: Snapshot::take_from(r)?,
+            shared_rand_cur: Snapshot::take_from(r)?,
+        })
+    }
+}
+
+impl Snapshot for ConsensusVoterInfo {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        let ds = &self.dir_source;
+        ds.nickname.write_onto(w)?;
+        ds.identity.write_onto(w)?;
+        ds.ip.write_onto(w)?;
+        ds.dir_port.write_onto(w)?;
+        ds.or_port.write_onto(w)?;
+        self.contact.write_onto(w)?;
+        self.vote_digest.write_onto(w)
+    }
+    fn take_from(r: &mut Reader<'_>) -> BytesResult<Self> {
+        let dir_source = DirSource {
+            nickname: Snapshot::take_from(r)?,
+            identity: Snapshot::take_from(r)?,
+            ip: Snapshot::take_from(r)?,
+            dir_port: Snapshot::take_from(r)?,
+            or_port: Snapshot::take_from(r)?,
+        };
+        Ok(ConsensusVoterInfo {
+            dir_source,
+            contact: Snapshot::take_from(r)?,
+            vote_digest: Snapshot::take_from(r)?,
+        })
+    }
+}
+
+impl Snapshot for RelayFlags {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        self.bits().write_onto(w)
+    }
+    fn take_from(r: &mut Reader<'_>) -> BytesResult<Self> {
+        RelayFlags::from_bits(r.take_u16()?)
+            .ok_or_else(|| BytesError::InvalidMessage("unrecognized relay flags".into()))
+    }
+}
+
+impl Snapshot for RelayWeight {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        let (tag, bw) = match self {
+            RelayWeight::Unmeasured(bw) => (0_u8, bw),
+            RelayWeight::Measured(bw) => (1_u8, bw),
+        };
+        tag.write_onto(w)?;
+        bw.write_onto(w)
+    }
+    fn take_from(r: &mut Reader<'_>) -> BytesResult<Self> {
+        match r.take_u8()? {
+            0 => Ok(RelayWeight::Unmeasured(r.take_u32()?)),
+            1 => Ok(RelayWeight::Measured(r.take_u32()?)),
+            _ => Err(BytesError::InvalidMessage(
+                "unrecognized relay weight".into(),
+            )),
+        }
+    }
+}
+
+impl<RS: Snapshot> Snapshot for Consensus<RS> {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        self.header.write_onto(w)?;
+        self.voters.write_onto(w)?;
+        self.relays.write_onto(w)?;
+        self.footer.weights.write_onto(w)?;
+        write_len(w, self.n_signatures)
+    }
+    fn take_from(r: &mut Reader<'_>) -> BytesResult<Self> {
+        Ok(Consensus {
+            header: Snapshot::take_from(r)?,
+            voters: Snapshot::take_from(r)?,
+            relays: Snapshot::take_from(r)?,
+            footer: Footer {
+                weights: Snapshot::take_from(r)?,
+            },
+            n_signatures: take_len(r)?,
+        })
+    }
+}
+
+impl MdConsensus {
+    /// Encode this consensus onto the end of `w`, in a compact binary format.
+    ///
+    /// Use [`read_snapshot`](Self::read_snapshot) to decode it again.  The
+    /// format can change between any two releases of this crate, so it is
+    /// only good for passing a consensus between processes running the same
+    /// build.
+    #[cfg_attr(docsrs, doc(cfg(feature = "snapshot")))]
+    pub fn write_snapshot<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        Snapshot::write_onto(self, w)
+    }
+
+    /// Decode a consensus that was encoded with
+    /// [`write_snapshot`](Self::write_snapshot).
+    ///
+    /// This function doesn't check any signatures or lifetimes: the process
+    /// that made the snapshot is responsible for having done that.  Only use
+    /// it on snapshots from a process that you trust.
+    #[cfg_attr(docsrs, doc(cfg(feature = "snapshot")))]
+    pub fn read_snapshot(r: &mut Reader<'_>) -> BytesResult<Self> {
+        Snapshot::take_from(r)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use tor_checkable::Timebound;
+
+    const CONSENSUS: &str = include_str!("../../../testdata/mdconsensus1.txt");
+
+    #[test]
+    fn round_trip() {
+        let (_, _, consensus) = MdConsensus::parse(CONSENSUS).unwrap();
+        let consensus = consensus
+            .dangerously_assume_timely()
+            .dangerously_assume_wellsigned();
+
+        let mut snapshot = Vec::new();
+        consensus.write_snapshot(&mut snapshot).unwrap();
+        let mut r = Reader::from_slice(&snapshot);
+        let decoded = MdConsensus::read_snapshot(&mut r).unwrap();
+        r.should_be_exhausted().unwrap();
+
+        assert_eq!(
+            decoded.lifetime().valid_after(),
+            consensus.lifetime().valid_after()
+        );
+        assert_eq!(decoded.params(), consensus.params());
+        assert_eq!(decoded.bandwidth_weights(), consensus.bandwidth_weights());
+        assert_eq!(decoded.relays().len(), consensus.relays().len());
+        for (a, b) in decoded.relays().iter().zip(consensus.relays()) {
+            assert_eq!(a.rsa_identity(), b.rsa_identity());
+            assert_eq!(a.nickname(), b.nickname());
+            assert_eq!(a.addrs(), b.addrs());
+            assert_eq!(a.md_digest(), b.md_digest());
+            assert_eq!(a.flags(), b.flags());
+            assert_eq!(a.version(), b.version());
+            assert_eq!(a.protovers(), b.protovers());
+            assert_eq!(a.weight(), b.weight());
+        }
+
+        // Encoding is deterministic, so the decoded consensus encodes the same.
+        let mut snapshot2 = Vec::new();
+        decoded.write_snapshot(&mut snapshot2).unwrap();
+        assert_eq!(snapshot, snapshot2);
+
+        // A truncated snapshot is an error.
+        let mut r = Reader::from_slice(&snapshot[..snapshot.len() - 1]);
+        assert!(MdConsensus::read_snapshot(&mut r).is_err());
+    }
+}
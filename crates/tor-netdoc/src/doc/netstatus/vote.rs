@@ -0,0 +1,623 @@
+//! Parsing for authority votes and detached signatures.
+//!
+//! Clients never need these documents: they only use the consensus that the
+//! authorities compute from their votes.  But tools that watch the health of
+//! the voting process want to compare each authority's vote with the
+//! consensus, and to see which authorities have signed which flavors of the
+//! consensus.
+//!
+//! A vote uses the same format as an ns-flavored consensus, except that its
+//! header and voter sections have a few more fields, and its one voter
+//! section includes the authority's certificate.  We parse its relays as
+//! [`NsConsensusRouterStatus`] entries.
+//!
+//! Only available if `tor-netdoc` is built with the `votes` feature.
+
+use super::{
+    CommonHeader, ConsensusFlavor, DirSource, Lifetime, NetParams, NetstatusKwd,
+    NsConsensusRouterStatus, ParseRouterStatus as _, RouterStatus as _, Signature, SignatureStatus,
+    NS_FOOTER_RULES, NS_HEADER_RULES_COMMON_, NS_ROUTERSTATUS_RULES_COMMON_,
+};
+use crate::doc::authcert::{AuthCert, AuthCertKeyIds};
+use crate::parse::keyword::Keyword;
+use crate::parse::parser::SectionRules;
+use crate::parse::tokenize::{Item, ItemResult, NetDocReader};
+use crate::types::misc::*;
+use crate::util::PeekableIterator;
+use crate::{NetdocErrorKind as EK, Result};
+
+use std::time;
+
+use digest::Digest;
+use once_cell::sync::Lazy;
+use tor_checkable::{signed, timed, SelfSigned as _, Timebound as _};
+use tor_llcrypto as ll;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+
+/// Rules for parsing the header of a vote.
+static NS_HEADER_RULES_VOTE: Lazy<SectionRules<NetstatusKwd>> = Lazy::new(|| {
+    use NetstatusKwd::*;
+    let mut rules = NS_HEADER_RULES_COMMON_.clone();
+    rules.add(CONSENSUS_METHODS.rule().required().args(1..));
+    rules.add(PUBLISHED.rule().required());
+    rules.add(FLAG_THRESHOLDS.rule());
+    rules.add(BANDWIDTH_FILE_HEADERS.rule());
+    rules.add(BANDWIDTH_FILE_DIGEST.rule().args(1..));
+    rules.add(UNRECOGNIZED.rule().may_repeat().obj_optional());
+    rules.build()
+});
+/// Rules for parsing the voter's information in a vote.
+///
+/// The section ends with an entire authority certificate.  We only
+/// recognize the first keyword of the certificate here; we parse the rest of
+/// it with the authcert code.
+static NS_VOTERINFO_RULES_VOTE: Lazy<SectionRules<NetstatusKwd>> = Lazy::new(|| {
+    use NetstatusKwd::*;
+    let mut rules = SectionRules::builder();
+    rules.add(DIR_SOURCE.rule().required().args(6..));
+    rules.add(CONTACT.rule().required());
+    rules.add(LEGACY_DIR_KEY.rule().args(1..));
+    rules.add(SHARED_RAND_PARTICIPATE.rule().no_args());
+    rules.add(SHARED_RAND_COMMIT.rule().may_repeat().args(4..));
+    rules.add(SHARED_RAND_PREVIOUS_VALUE.rule().args(2..));
+    rules.add(SHARED_RAND_CURRENT_VALUE.rule().args(2..));
+    rules.add(DIR_KEY_CERTIFICATE_VERSION.rule().required().args(1..));
+    rules.add(UNRECOGNIZED.rule().may_repeat().obj_optional());
+    rules.build()
+});
+/// Rules for parsing a single routerstatus in a vote.
+static NS_ROUTERSTATUS_RULES_VOTE: Lazy<SectionRules<NetstatusKwd>> = Lazy::new(|| {
+    use NetstatusKwd::*;
+    let mut rules = NS_ROUTERSTATUS_RULES_COMMON_.clone();
+    rules.add(RS_R.rule().required().args(8..));
+    rules.add(RS_M.rule().may_repeat().args(2..));
+    rules.add(RS_ID.rule().may_repeat().args(2..));
+    rules.build()
+});
+
+/// A single authority's vote.
+///
+/// A vote describes one authority's view of the network, which it offers
+/// as input to the consensus.
+#[cfg_attr(docsrs, doc(cfg(feature = "votes")))]
+#[derive(Debug, Clone)]
+pub struct Vote {
+    /// Header fields common to votes and consensuses.
+    hdr: CommonHeader,
+    /// When the authority published this vote.
+    published: time::SystemTime,
+    /// The consensus methods that the authority supports.
+    consensus_methods: Vec<u32>,
+    /// The flags that the authority knows how to vote on.
+    known_flags: Vec<String>,
+    /// The identity and address of the authority that cast this vote.
+    voter: DirSource,
+    /// Human-readable contact information for the authority.
+    contact: String,
+    /// The authority certificate whose signing key signed this vote.
+    cert: AuthCert,
+    /// The relays that the authority voted on, ordered by RSA identity.
+    relays: Vec<NsConsensusRouterStatus>,
+}
+
+/// A vote whose signature and timeliness we haven't checked.
+///
+/// The authority certificate that comes with the vote has already been
+/// checked: the remaining signature is the one on the vote itself.
+#[cfg_attr(docsrs, doc(cfg(feature = "votes")))]
+pub type UncheckedVote = signed::SignatureGated<timed::TimerangeBound<Vote>>;
+
+impl Vote {
+    /// Try to parse a single vote from a string.
+    ///
+    /// The vote is valid for checking from the time it was published until
+    /// the end of the lifetime that it proposes.  Tools that look at old
+    /// votes will need to skip the timeliness check.
+    pub fn parse(s: &str) -> Result<UncheckedVote> {
+        let mut reader = NetDocReader::new(s);
+        Self::parse_from_reader(&mut reader).map_err(|e| e.within(s))
+    }
+
+    /// Extract an entire vote from a reader.
+    fn parse_from_reader(r: &mut NetDocReader<'_, NetstatusKwd>) -> Result<UncheckedVote> {
+        use NetstatusKwd::*;
+
+        let (hdr, published, consensus_methods, known_flags, start_pos) = {
+            let mut h = r.pause_at(|i| i.is_ok_with_kwd_in(&[DIR_SOURCE]));
+            let sec = NS_HEADER_RULES_VOTE.parse(&mut h)?;
+            // Unwrapping should be safe because above `.parse` would have
+            // returned an Error
+            #[allow(clippy::unwrap_used)]
+            let pos = sec.first_item().unwrap().offset_in(r.str()).unwrap();
+
+            let status: &str = sec.required(VOTE_STATUS)?.arg(0).unwrap_or("");
+            if status != "vote" {
+                return Err(EK::BadDocumentType.err());
+            }
+            let hdr = CommonHeader::from_section(&sec)?;
+            let published: time::SystemTime = sec
+                .required(PUBLISHED)?
+                .args_as_str()
+                .parse::<Iso8601TimeSp>()?
+                .into();
+            let consensus_methods = sec
+                .required(CONSENSUS_METHODS)?
+                .args()
+                .map(str::parse)
+                .collect::<std::result::Result<Vec<u32>, _>>()?;
+            let known_flags = sec
+                .required(KNOWN_FLAGS)?
+                .args()
+                .map(str::to_string)
+                .collect();
+            (hdr, published, consensus_methods, known_flags, pos)
+        };
+
+        let (voter, contact, cert) = {
+            let mut p = r.pause_at(|i| i.is_ok_with_kwd_in(&[RS_R, DIRECTORY_FOOTER]));
+            let sec = NS_VOTERINFO_RULES_VOTE.parse(&mut p)?;
+            // This unwrap is safe because the section has required items.
+            #[allow(clippy::unwrap_used)]
+            let first = sec.first_item().unwrap();
+            if first.kwd() != DIR_SOURCE {
+                return Err(EK::WrongStartingToken
+                    .with_msg(first.kwd_str().to_string())
+                    .at_pos(first.pos()));
+            }
+            let voter = DirSource::from_item(sec.required(DIR_SOURCE)?)?;
+            let contact = sec.required(CONTACT)?.args_as_str().to_string();
+
+            // The certificate runs from its first keyword to the end of the
+            // section.
+            let cert_item = sec.required(DIR_KEY_CERTIFICATE_VERSION)?;
+            #[allow(clippy::unwrap_used)]
+            let last = sec.last_item().unwrap();
+            let cert_text = match (cert_item.offset_in(r.str()), last.offset_after(r.str())) {
+                (Some(start), Some(end)) if start < end => &r.str()[start..end],
+                _ => {
+                    return Err(EK::MisplacedToken
+                        .with_msg(cert_item.kwd_str().to_string())
+                        .at_pos(cert_item.pos()))
+                }
+            };
+            // The certificate is self-signed, so we can check it right away.
+            // It only needs to have been valid when the vote was published.
+            let cert = AuthCert::parse(cert_text)?
+                .check_signature()?
+                .check_valid_at(&published)?;
+            if cert.id_fingerprint() != &voter.identity {
+                return Err(EK::BadArgument
+                    .at_pos(cert_item.pos())
+                    .with_msg("certificate does not match dir-source"));
+            }
+            (voter, contact, cert)
+        };
+
+        let mut relays: Vec<NsConsensusRouterStatus> = Vec::new();
+        while r
+            .peek()
+            .is_some_and(|i| i.is_ok_with_kwd_in(&[RS_R]) || i.is_err())
+        {
+            let pos = r.pos();
+            let mut first_r = true;
+            let mut p = r.pause_at(|i| match i {
+                Err(_) => false,
+                Ok(item) => {
+                    item.kwd() == DIRECTORY_FOOTER
+                        || if item.kwd() == RS_R {
+                            let was_first = first_r;
+                            first_r = false;
+                            !was_first
+                        } else {
+                            false
+                        }
+                }
+            });
+            let sec = NS_ROUTERSTATUS_RULES_VOTE.parse(&mut p)?;
+            let rs = NsConsensusRouterStatus::from_section(&sec)?;
+            if let Some(prev) = relays.last() {
+                if prev.rsa_identity() >= rs.rsa_identity() {
+                    return Err(EK::WrongSortOrder.at_pos(pos));
+                }
+            }
+            relays.push(rs);
+        }
+        relays.shrink_to_fit();
+
+        {
+            let mut p = r.pause_at(|i| i.is_ok_with_kwd_in(&[DIRECTORY_SIGNATURE]));
+            NS_FOOTER_RULES.parse(&mut p)?;
+        }
+
+        // Find the signature that the certificate's signing key made.
+        let mut first_sig: Option<Item<'_, NetstatusKwd>> = None;
+        let mut signature = None;
+        for item in &mut *r {
+            let item = item?;
+            if item.kwd() != DIRECTORY_SIGNATURE {
+                return Err(EK::UnexpectedToken
+                    .with_msg(item.kwd().to_str())
+                    .at_pos(item.pos()));
+            }
+            let sig = Signature::from_item(&item)?;
+            if sig.matches_cert(&cert) && sig.digestname == "sha1" {
+                signature = Some(sig);
+            }
+            if first_sig.is_none() {
+                first_sig = Some(item);
+            }
+        }
+
+        let end_pos = match first_sig {
+            None => return Err(EK::MissingToken.with_msg("directory-signature")),
+            // Unwrap should be safe because `first_sig` was parsed from `r`
+            #[allow(clippy::unwrap_used)]
+            Some(sig) => sig.offset_in(r.str()).unwrap() + "directory-signature ".len(),
+        };
+        let signature = signature
+            .ok_or_else(|| EK::BadSignature.with_msg("no signature from the vote's signing key"))?;
+
+        let sha1 = ll::d::Sha1::digest(&r.str().as_bytes()[start_pos..end_pos]);
+        let v_sig = ll::pk::rsa::ValidatableRsaSignature::new(
+            cert.signing_key(),
+            &signature.signature,
+            &sha1,
+        );
+
+        let valid_until = hdr.lifetime.valid_until();
+        let vote = Vote {
+            hdr,
+            published,
+            consensus_methods,
+            known_flags,
+            voter,
+            contact,
+            cert,
+            relays,
+        };
+        let timed = timed::TimerangeBound::new(vote, published..valid_until);
+        Ok(signed::SignatureGated::new(timed, vec![Box::new(v_sig)]))
+    }
+
+    /// Return the lifetime that this vote proposes for the consensus.
+    pub fn lifetime(&self) -> &Lifetime {
+        &self.hdr.lifetime
+    }
+
+    /// Return the time when the authority published this vote.
+    pub fn published(&self) -> time::SystemTime {
+        self.published
+    }
+
+    /// Return the consensus methods that the authority supports.
+    pub fn consensus_methods(&self) -> &[u32] {
+        &self.consensus_methods[..]
+    }
+
+    /// Return the names of the flags that the authority votes on.
+    ///
+    /// This includes flags that [`RelayFlags`](super::RelayFlags) doesn't
+    /// recognize.
+    pub fn known_flags(&self) -> &[String] {
+        &self.known_flags[..]
+    }
+
+    /// Return the network parameters that the authority voted for.
+    pub fn params(&self) -> &NetParams<i32> {
+        &self.hdr.params
+    }
+
+    /// Return the nickname of the authority that cast this vote.
+    pub fn authority_nickname(&self) -> &str {
+        &self.voter.nickname
+    }
+
+    /// Return the authority identity of the authority that cast this vote.
+    ///
+    /// This is the identity of the key that signs the authority's
+    /// certificates, as listed in the `v3ident` of its configuration.
+    pub fn authority_id(&self) -> &RsaIdentity {
+        &self.voter.identity
+    }
+
+    /// Return the contact information of the authority that cast this vote.
+    pub fn contact(&self) -> &str {
+        &self.contact
+    }
+
+    /// Return the certificate whose signing key signed this vote.
+    pub fn cert(&self) -> &AuthCert {
+        &self.cert
+    }
+
+    /// Return the relays that this vote lists.
+    pub fn relays(&self) -> &[NsConsensusRouterStatus] {
+        &self.relays[..]
+    }
+
+    /// Return the entry in this vote for the relay with RSA identity `id`,
+    /// if there is one.
+    pub fn relay_by_id(&self, id: &RsaIdentity) -> Option<&NsConsensusRouterStatus> {
+        self.relays
+            .binary_search_by(|rs| rs.rsa_identity().cmp(id))
+            .ok()
+            .map(|idx| &self.relays[idx])
+    }
+}
+
+decl_keyword! {
+    /// Keywords that can be used in a detached signature document.
+    DetachedSigKwd {
+        "consensus-digest" => CONSENSUS_DIGEST,
+        "valid-after" => VALID_AFTER,
+        "fresh-until" => FRESH_UNTIL,
+        "valid-until" => VALID_UNTIL,
+        "additional-digest" => ADDITIONAL_DIGEST,
+        "additional-signature" => ADDITIONAL_SIGNATURE,
+        "directory-signature" => DIRECTORY_SIGNATURE,
+    }
+}
+
+/// Rules for parsing a detached signature document.
+static DETACHED_SIG_RULES: Lazy<SectionRules<DetachedSigKwd>> = Lazy::new(|| {
+    use DetachedSigKwd::*;
+    let mut rules = SectionRules::builder();
+    rules.add(CONSENSUS_DIGEST.rule().required().args(1..));
+    rules.add(VALID_AFTER.rule().required());
+    rules.add(FRESH_UNTIL.rule().required());
+    rules.add(VALID_UNTIL.rule().required());
+    rules.add(ADDITIONAL_DIGEST.rule().may_repeat().args(3..));
+    rules.add(
+        ADDITIONAL_SIGNATURE
+            .rule()
+            .may_repeat()
+            .args(4..)
+            .obj_required(),
+    );
+    rules.add(
+        DIRECTORY_SIGNATURE
+            .rule()
+            .may_repeat()
+            .args(2..)
+            .obj_required(),
+    );
+    rules.add(UNRECOGNIZED.rule().may_repeat().obj_optional());
+    rules.build()
+});
+
+/// The signatures that the authorities have collected on the consensus
+/// for one voting period, detached from the consensus documents
+/// themselves.
+///
+/// Authorities exchange these documents to collect signatures on every
+/// flavor of the consensus.  We don't check the signatures while parsing,
+/// since we may not have the consensus or the certificates: use
+/// [`n_valid_signatures`](DetachedSignatures::n_valid_signatures) for that.
+#[cfg_attr(docsrs, doc(cfg(feature = "votes")))]
+#[derive(Debug, Clone)]
+pub struct DetachedSignatures {
+    /// The lifetime of the consensus documents that were signed.
+    lifetime: Lifetime,
+    /// The digest of each signed consensus flavor.
+    ///
+    /// This is a SHA1 digest for the ns flavor, and a SHA256 digest for the
+    /// others.
+    digests: Vec<(ConsensusFlavor, Vec<u8>)>,
+    /// The signatures on each flavor.
+    signatures: Vec<(ConsensusFlavor, Signature)>,
+}
+
+impl DetachedSignatures {
+    /// Try to parse a detached signature document from a string.
+    ///
+    /// Digests and signatures for flavors that we don't recognize are
+    /// ignored.
+    pub fn parse(s: &str) -> Result<DetachedSignatures> {
+        Self::parse_inner(s).map_err(|e| e.within(s))
+    }
+
+    /// Implementation for [`DetachedSignatures::parse`].
+    fn parse_inner(s: &str) -> Result<DetachedSignatures> {
+        use DetachedSigKwd::*;
+        let mut reader = NetDocReader::new(s);
+        let sec = DETACHED_SIG_RULES.parse(&mut reader)?;
+        reader.should_be_exhausted()?;
+
+        /// Parse a time from `item`.
+        fn time(item: &Item<'_, DetachedSigKwd>) -> Result<time::SystemTime> {
+            Ok(item.args_as_str().parse::<Iso8601TimeSp>()?.into())
+        }
+        let lifetime = Lifetime::new(
+            time(sec.required(VALID_AFTER)?)?,
+            time(sec.required(FRESH_UNTIL)?)?,
+            time(sec.required(VALID_UNTIL)?)?,
+        )?;
+
+        let mut digests = vec![(
+            ConsensusFlavor::Ns,
+            sec.required(CONSENSUS_DIGEST)?.parse_arg::<B16>(0)?.into(),
+        )];
+        for item in sec.slice(ADDITIONAL_DIGEST) {
+            if let Ok(flavor) = ConsensusFlavor::from_opt_name(item.arg(0)) {
+                digests.push((flavor, item.parse_arg::<B16>(2)?.into()));
+            }
+        }
+
+        let mut signatures = Vec::new();
+        for item in sec.slice(DIRECTORY_SIGNATURE) {
+            signatures.push((ConsensusFlavor::Ns, Self::signature(item, 0)?));
+        }
+        for item in sec.slice(ADDITIONAL_SIGNATURE) {
+            if let Ok(flavor) = ConsensusFlavor::from_opt_name(item.arg(0)) {
+                signatures.push((flavor, Self::signature(item, 1)?));
+            }
+        }
+
+        Ok(DetachedSignatures {
+            lifetime,
+            digests,
+            signatures,
+        })
+    }
+
+    /// Parse a signature from `item`, whose signature arguments start at
+    /// `skip`.
+    fn signature(item: &Item<'_, DetachedSigKwd>, skip: usize) -> Result<Signature> {
+        let args: Vec<&str> = item.args().skip(skip).collect();
+        let (alg, id_fp, sk_fp) = match args[..] {
+            [alg, id_fp, sk_fp, ..] => (alg, id_fp, sk_fp),
+            [id_fp, sk_fp] => ("sha1", id_fp, sk_fp),
+            _ => return Err(EK::TooFewArguments.at_pos(item.pos())),
+        };
+        Ok(Signature {
+            digestname: alg.to_string(),
+            key_ids: AuthCertKeyIds {
+                id_fingerprint: id_fp.parse::<Fingerprint>()?.into(),
+                sk_fingerprint: sk_fp.parse::<Fingerprint>()?.into(),
+            },
+            signature: item.obj("SIGNATURE")?,
+        })
+    }
+
+    /// Return the lifetime of the consensus documents that were signed.
+    pub fn lifetime(&self) -> &Lifetime {
+        &self.lifetime
+    }
+
+    /// Return the digest of the consensus with flavor `flavor`, if this
+    /// document lists one.
+    ///
+    /// This is a SHA1 digest for the ns flavor, and a SHA256 digest for the
+    /// microdesc flavor.
+    pub fn digest(&self, flavor: ConsensusFlavor) -> Option<&[u8]> {
+        self.digests
+            .iter()
+            .find(|(f, _)| *f == flavor)
+            .map(|(_, d)| &d[..])
+    }
+
+    /// Return the keys of the authorities that signed the consensus with
+    /// flavor `flavor`.
+    pub fn signers(&self, flavor: ConsensusFlavor) -> impl Iterator<Item = &AuthCertKeyIds> {
+        self.signatures
+            .iter()
+            .filter(move |(f, _)| *f == flavor)
+            .map(|(_, sig)| &sig.key_ids)
+    }
+
+    /// Discard every signature whose keys don't satisfy `keep`.
+    pub fn retain_signers<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&AuthCertKeyIds) -> bool,
+    {
+        self.signatures.retain(|(_, sig)| keep(&sig.key_ids));
+    }
+
+    /// Return the number of signatures on the consensus with flavor
+    /// `flavor` that are valid according to `certs`.
+    ///
+    /// Signatures for which `certs` has no certificate don't count.
+    pub fn n_valid_signatures(&self, flavor: ConsensusFlavor, certs: &[AuthCert]) -> usize {
+        let Some(digest) = self.digest(flavor) else {
+            return 0;
+        };
+        self.signatures
+            .iter()
+            .filter(|(f, _)| *f == flavor)
+            .filter(|(_, sig)| matches!(sig.check_signature(digest, certs), SignatureStatus::Valid))
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::doc::netstatus::RelayFlags;
+    use hex_literal::hex;
+
+    const VOTE: &str = include_str!("../../../testdata/vote1.txt");
+    const DETACHED_SIGS: &str = include_str!("../../../testdata/detached-sigs1.txt");
+
+    #[test]
+    fn parse_vote() {
+        let vote = Vote::parse(VOTE)
+            .unwrap()
+            .check_signature()
+            .unwrap()
+            .dangerously_assume_timely();
+
+        assert_eq!(vote.authority_nickname(), "test000a");
+        assert_eq!(
+            vote.authority_id(),
+            &hex!("BB2F6D0F01D1A4F937B1C73951594B831B1859F3").into()
+        );
+        assert_eq!(vote.contact(), "auth0@test.test");
+        assert_eq!(vote.cert().id_fingerprint(), vote.authority_id());
+        assert_eq!(vote.consensus_methods(), &[28, 29, 30, 31]);
+        assert_eq!(vote.known_flags().len(), 9);
+        assert_eq!(vote.params().get("circwindow"), Some(&80));
+        assert!(vote.published() < vote.lifetime().valid_after());
+
+        assert_eq!(vote.relays().len(), 3);
+        let rs = &vote.relays()[2];
+        assert_eq!(rs.nickname(), "test004r");
+        assert!(rs.flags().contains(RelayFlags::EXIT | RelayFlags::RUNNING));
+        assert!(rs.weight().is_measured());
+        assert!(vote.relay_by_id(rs.rsa_identity()).is_some());
+        assert!(vote.relay_by_id(&[0; 20].into()).is_none());
+    }
+
+    #[test]
+    fn parse_bad_vote() {
+        // Changing the signed part of the vote breaks the signature.
+        let altered = VOTE.replace("circwindow=80", "circwindow=81");
+        let vote = Vote::parse(&altered).unwrap();
+        assert!(vote.check_signature().is_err());
+
+        // A consensus is not a vote.
+        let altered = VOTE.replace("vote-status vote", "vote-status consensus");
+        let err = Vote::parse(&altered).err().unwrap();
+        assert_eq!(err.netdoc_error_kind(), EK::BadDocumentType);
+
+        // The certificate has to belong to the authority in the dir-source.
+        let altered = VOTE.replace("dir-source test000a BB2F", "dir-source test000a 0000");
+        assert!(Vote::parse(&altered).is_err());
+    }
+
+    #[test]
+    fn parse_detached_sigs() {
+        let sigs = DetachedSignatures::parse(DETACHED_SIGS).unwrap();
+        let vote = Vote::parse(VOTE)
+            .unwrap()
+            .check_signature()
+            .unwrap()
+            .dangerously_assume_timely();
+
+        assert_eq!(
+            sigs.digest(ConsensusFlavor::Ns).unwrap(),
+            &hex!("0196711D9BCE4E9961015F6DC7688E4879A21F53")[..]
+        );
+        assert_eq!(sigs.digest(ConsensusFlavor::Microdesc).unwrap().len(), 32);
+        assert_eq!(sigs.lifetime().valid_after(), vote.lifetime().valid_after());
+
+        for flavor in [ConsensusFlavor::Ns, ConsensusFlavor::Microdesc] {
+            let signers: Vec<_> = sigs.signers(flavor).collect();
+            assert_eq!(signers, vec![vote.cert().key_ids()]);
+            assert_eq!(sigs.n_valid_signatures(flavor, &[vote.cert().clone()]), 1);
+            assert_eq!(sigs.n_valid_signatures(flavor, &[]), 0);
+        }
+    }
+}
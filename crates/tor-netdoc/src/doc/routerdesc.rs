@@ -185,6 +185,14 @@ pub struct RouterDesc {
     /// on IPv6.
     #[cfg_attr(docsrs, doc(cfg(feature = "dangerous-expose-struct-fields")))]
     ipv6_policy: Arc<PortPolicy>,
+    /// The digest of this router descriptor, as it would be listed in an
+    /// "ns"-flavored consensus.
+    #[cfg_attr(docsrs, doc(cfg(feature = "dangerous-expose-struct-fields")))]
+    digest: RdDigest,
+    /// The time at which this relay says it was last overloaded, if it says
+    /// it has been overloaded recently.
+    #[cfg_attr(docsrs, doc(cfg(feature = "dangerous-expose-struct-fields")))]
+    overload_general: Option<time::SystemTime>,
 }
 
 /// Description of the software a relay is running.
@@ -237,6 +245,7 @@ decl_keyword! {
         "onion-key" => ONION_KEY,
         "onion-key-crosscert" => ONION_KEY_CROSSCERT,
         "or-address" => OR_ADDRESS,
+        "overload-general" => OVERLOAD_GENERAL,
         "platform" => PLATFORM,
         "proto" => PROTO,
         "published" => PUBLISHED,
@@ -307,6 +316,7 @@ static ROUTER_BODY_RULES: Lazy<SectionRules<RouterKwd>> = Lazy::new(|| {
     rules.add(OR_ADDRESS.rule().may_repeat().args(1..));
     rules.add(TUNNELLED_DIR_SERVER.rule());
     rules.add(PROTO.rule().required().args(1..));
+    rules.add(OVERLOAD_GENERAL.rule().args(3..));
     rules.add(UNRECOGNIZED.rule().may_repeat().obj_optional());
     // TODO: these aren't parsed yet.  Only authorities use them.
     {
@@ -409,6 +419,39 @@ impl RouterDesc {
             .chain(self.ipv6addr.map(net::SocketAddr::from))
     }
 
+    /// Return the family that this relay declares.
+    pub fn family(&self) -> &Arc<RelayFamily> {
+        &self.family
+    }
+
+    /// Return this relay's full IPv4 exit policy.
+    pub fn ipv4_policy(&self) -> &AddrPolicy {
+        &self.ipv4_policy
+    }
+
+    /// Return the time at which this relay says it was last overloaded.
+    ///
+    /// Relays only report this for 72 hours after they were last overloaded;
+    /// the time is rounded down to the hour.  Returns `None` if the relay
+    /// doesn't say that it has been overloaded, or if it reports overload
+    /// in a format we don't recognize.
+    pub fn overload_general(&self) -> Option<time::SystemTime> {
+        self.overload_general
+    }
+
+    /// Return a summary of this relay's IPv6 exit policy.
+    pub fn ipv6_policy(&self) -> &Arc<PortPolicy> {
+        &self.ipv6_policy
+    }
+
+    /// Return the digest of this router descriptor.
+    ///
+    /// This is the digest by which an "ns"-flavored consensus refers to the
+    /// descriptor.
+    pub fn digest(&self) -> &RdDigest {
+        &self.digest
+    }
+
     /// Helper: tokenize `s`, and divide it into three validated sections.
     fn parse_sections<'a>(
         reader: &mut NetDocReader<'a, RouterKwd>,
@@ -559,8 +602,9 @@ impl RouterDesc {
             ll::pk::ed25519::ValidatableEd25519Signature::new(ed25519_signing_key, sig, &d)
         };
 
-        // Extract legacy RSA signature.
-        let rsa_signature: ll::pk::rsa::ValidatableRsaSignature = {
+        // Extract legacy RSA signature.  The digest that it signs is also
+        // the digest that identifies this descriptor in a consensus.
+        let (rsa_signature, digest): (ll::pk::rsa::ValidatableRsaSignature, RdDigest) = {
             let mut d = ll::d::Sha1::new();
             let signed_end = rsa_sig_pos + b"router-signature\n".len();
             d.update(&s[start_offset..signed_end]);
@@ -568,7 +612,10 @@ impl RouterDesc {
             let sig = rsa_sig.obj("SIGNATURE")?;
             // TODO: we need to accept prefixes here. COMPAT BLOCKER.
 
-            ll::pk::rsa::ValidatableRsaSignature::new(&rsa_identity_key, &sig, &d)
+            (
+                ll::pk::rsa::ValidatableRsaSignature::new(&rsa_identity_key, &sig, &d),
+                d.into(),
+            )
         };
 
         // router nickname ipv4addr orport socksport dirport
@@ -593,6 +640,19 @@ impl RouterDesc {
             .parse::<Iso8601TimeSp>()?
             .into();
 
+        // overload-general
+        let overload_general = match body.get(OVERLOAD_GENERAL) {
+            // Version 1 is the only one we know.
+            Some(ov) if ov.arg(0) == Some("1") => Some(
+                ov.args_as_str()
+                    .split_once(' ')
+                    .map_or("", |(_, when)| when)
+                    .parse::<Iso8601TimeSp>()?
+                    .into(),
+            ),
+            _ => None,
+        };
+
         // ntor key
         let ntor_onion_key: Curve25519Public = body.required(NTOR_ONION_KEY)?.parse_arg(0)?;
         let ntor_onion_key: ll::pk::curve25519::PublicKey = ntor_onion_key.into();
@@ -797,6 +857,8 @@ impl RouterDesc {
             platform,
             ipv4_policy,
             ipv6_policy: ipv6_policy.intern(),
+            digest,
+            overload_general,
         };
 
         let time_gated = timed::TimerangeBound::new(desc, start_time..expiry);
@@ -988,6 +1050,50 @@ mod test {
             ]
         );
         assert!(rd.tap_onion_key.is_some());
+        assert_eq!(
+            hex::encode(rd.digest()),
+            "2516b9302d015686b1f272424d6cb4c3714856a7"
+        );
+        assert_eq!(
+            rd.ipv4_policy().ipv4_summary().to_string(),
+            "reject 1-65535"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_overload() -> Result<()> {
+        use tor_checkable::{SelfSigned, Timebound};
+        let parse = |extra: &str| {
+            let text = TESTDATA.replace(
+                "published 2022-11-14 19:58:52\n",
+                &format!("published 2022-11-14 19:58:52\n{extra}"),
+            );
+            // We changed the text, so the signatures won't be valid.
+            Ok::<_, Error>(
+                RouterDesc::parse(&text)?
+                    .dangerously_assume_wellsigned()
+                    .dangerously_assume_timely(),
+            )
+        };
+
+        assert!(parse("")?.overload_general().is_none());
+        let rd = parse("overload-general 1 2022-11-14 18:00:00\n")?;
+        assert_eq!(
+            rd.overload_general(),
+            Some(humantime::parse_rfc3339("2022-11-14T18:00:00Z").unwrap())
+        );
+        // We ignore versions we don't know.
+        let rd = parse("overload-general 2 2022-11-14 18:00:00 extra\n")?;
+        assert!(rd.overload_general().is_none());
+        // A bad time is an error, though.
+        assert!(parse("overload-general 1 yesterday\n").is_err());
+        // So is a repeated line.
+        assert!(parse(
+            "overload-general 1 2022-11-14 18:00:00\noverload-general 1 2022-11-14 18:00:00\n"
+        )
+        .is_err());
 
         Ok(())
     }
@@ -51,6 +51,8 @@ pub(crate) mod build;
 pub(crate) mod parse;
 pub mod doc;
 mod err;
+#[cfg(feature = "snapshot")]
+mod snapshot;
 pub mod types;
 mod util;
 
@@ -0,0 +1,326 @@
+//! A compact binary encoding for parsed documents.
+//!
+//! A "snapshot" of a document holds the values that we parsed from it, rather
+//! than its original text.  One process can use a snapshot to hand its parsed
+//! documents to another, so that the other doesn't have to parse them again.
+//!
+//! The snapshot format has no stability guarantees: it can change between
+//! any two releases of this crate.  Only use it to pass documents between
+//! processes that are running the same build.  Decoding a snapshot does
+//! not check any signatures, so only accept snapshots from processes that
+//! you trust as much as yourself.
+//!
+//! The functions that encode and decode snapshots are on the document types:
+//! see (for example) `MdConsensus::write_snapshot`.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tor_bytes::{EncodeError, EncodeResult, Error, Reader, Result, Writer};
+use tor_error::internal;
+use tor_llcrypto::pk::{curve25519, ed25519::Ed25519Identity, rsa::RsaIdentity};
+use tor_protover::Protocols;
+
+use crate::types::family::RelayFamily;
+use crate::types::policy::{PortPolicy, PortRange};
+
+/// An object that can be written to a snapshot, and read back.
+pub(crate) trait Snapshot: Sized {
+    /// Encode this object onto the end of `w`.
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()>;
+
+    /// Decode an object of this type from `r`.
+    fn take_from(r: &mut Reader<'_>) -> Result<Self>;
+}
+
+/// Implement [`Snapshot`] for types that [`tor_bytes`] can already encode.
+macro_rules! snapshot_via_tor_bytes {
+    { $( $t:ty ),* } => { $(
+        impl Snapshot for $t {
+            fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+                w.write(self)
+            }
+            fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+                r.extract()
+            }
+        }
+    )* }
+}
+
+snapshot_via_tor_bytes! {
+    u8, u16, u32, u64, Ipv4Addr, Ipv6Addr, RsaIdentity, Ed25519Identity, curve25519::PublicKey
+}
+
+impl<const N: usize> Snapshot for [u8; N] {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        w.write_all(&self[..]);
+        Ok(())
+    }
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        let mut out = [0; N];
+        r.take_into(&mut out[..])?;
+        Ok(out)
+    }
+}
+
+impl Snapshot for i32 {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        w.write_all(&self.to_be_bytes());
+        Ok(())
+    }
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        Ok(i32::from_be_bytes(r.extract()?))
+    }
+}
+
+impl Snapshot for bool {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        w.write_u8(u8::from(*self));
+        Ok(())
+    }
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        match r.take_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::InvalidMessage("bad boolean".into())),
+        }
+    }
+}
+
+/// Encode `n` as a 32-bit length.
+pub(crate) fn write_len<W: Writer + ?Sized>(w: &mut W, n: usize) -> EncodeResult<()> {
+    let n = u32::try_from(n).map_err(|_| EncodeError::BadLengthValue)?;
+    w.write_u32(n);
+    Ok(())
+}
+
+/// Decode a 32-bit length.
+pub(crate) fn take_len(r: &mut Reader<'_>) -> Result<usize> {
+    usize::try_from(r.take_u32()?).map_err(|_| Error::BadLengthValue)
+}
+
+impl Snapshot for String {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        write_len(w, self.len())?;
+        w.write_all(self.as_bytes());
+        Ok(())
+    }
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        let n = take_len(r)?;
+        let s = std::str::from_utf8(r.take(n)?)
+            .map_err(|_| Error::InvalidMessage("string was not UTF-8".into()))?;
+        Ok(s.to_string())
+    }
+}
+
+impl<T: Snapshot> Snapshot for Vec<T> {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        write_len(w, self.len())?;
+        for item in self {
+            item.write_onto(w)?;
+        }
+        Ok(())
+    }
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        let n = take_len(r)?;
+        // Don't trust `n` for the allocation: every item takes at least a byte.
+        let mut out = Vec::with_capacity(n.min(r.remaining()));
+        for _ in 0..n {
+            out.push(T::take_from(r)?);
+        }
+        Ok(out)
+    }
+}
+
+impl<T: Snapshot> Snapshot for Option<T> {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        self.is_some().write_onto(w)?;
+        if let Some(v) = self {
+            v.write_onto(w)?;
+        }
+        Ok(())
+    }
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        if bool::take_from(r)? {
+            Ok(Some(T::take_from(r)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<T: Snapshot, U: Snapshot> Snapshot for (T, U) {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        self.0.write_onto(w)?;
+        self.1.write_onto(w)
+    }
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        Ok((T::take_from(r)?, U::take_from(r)?))
+    }
+}
+
+impl Snapshot for SystemTime {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        let since_epoch = self
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|_| EncodeError::from(internal!("time before the epoch in a document")))?;
+        w.write_u64(since_epoch.as_secs());
+        w.write_u32(since_epoch.subsec_nanos());
+        Ok(())
+    }
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        let secs = r.take_u64()?;
+        let nanos = r.take_u32()?;
+        if nanos >= 1_000_000_000 {
+            return Err(Error::InvalidMessage("bad subsecond time".into()));
+        }
+        SystemTime::UNIX_EPOCH
+            .checked_add(Duration::new(secs, nanos))
+            .ok_or_else(|| Error::InvalidMessage("time out of range".into()))
+    }
+}
+
+impl Snapshot for IpAddr {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        match self {
+            IpAddr::V4(a) => {
+                w.write_u8(4);
+                a.write_onto(w)
+            }
+            IpAddr::V6(a) => {
+                w.write_u8(6);
+                a.write_onto(w)
+            }
+        }
+    }
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        match r.take_u8()? {
+            4 => Ok(IpAddr::V4(Ipv4Addr::take_from(r)?)),
+            6 => Ok(IpAddr::V6(Ipv6Addr::take_from(r)?)),
+            _ => Err(Error::InvalidMessage("bad address type".into())),
+        }
+    }
+}
+
+impl Snapshot for SocketAddr {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        self.ip().write_onto(w)?;
+        w.write_u16(self.port());
+        Ok(())
+    }
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        let ip = IpAddr::take_from(r)?;
+        Ok(SocketAddr::new(ip, r.take_u16()?))
+    }
+}
+
+/// Decode a string from `r`, and parse it as a `T`.
+///
+/// We use this for small values whose text form is already compact.
+pub(crate) fn take_parsed<T: FromStr>(r: &mut Reader<'_>, what: &'static str) -> Result<T> {
+    String::take_from(r)?
+        .parse()
+        .map_err(|_| Error::InvalidMessage(format!("unparseable {}", what).into()))
+}
+
+impl Snapshot for Protocols {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        self.to_string().write_onto(w)
+    }
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        take_parsed(r, "protocol list")
+    }
+}
+
+impl Snapshot for Arc<RelayFamily> {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        let members: Vec<RsaIdentity> = self.members().copied().collect();
+        members.write_onto(w)
+    }
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        let mut family = RelayFamily::new();
+        for id in Vec::<RsaIdentity>::take_from(r)? {
+            family.push(id);
+        }
+        Ok(family.intern())
+    }
+}
+
+impl Snapshot for Arc<PortPolicy> {
+    fn write_onto<W: Writer + ?Sized>(&self, w: &mut W) -> EncodeResult<()> {
+        let ranges: Vec<(u16, u16)> = self.allowed_ranges().map(|r| (r.lo, r.hi)).collect();
+        ranges.write_onto(w)
+    }
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        let ranges = Vec::<(u16, u16)>::take_from(r)?
+            .into_iter()
+            .map(|(lo, hi)| {
+                PortRange::new(lo, hi).ok_or_else(|| Error::InvalidMessage("bad port range".into()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if ranges.windows(2).any(|pair| pair[0].hi >= pair[1].lo) {
+            return Err(Error::InvalidMessage("port ranges out of order".into()));
+        }
+        Ok(PortPolicy::from_ranges(ranges).intern())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    /// Encode `v` and decode it again, checking that we used all the bytes.
+    fn round_trip<T: Snapshot>(v: &T) -> T {
+        let mut w = Vec::new();
+        v.write_onto(&mut w).unwrap();
+        let mut r = Reader::from_slice(&w);
+        let out = T::take_from(&mut r).unwrap();
+        r.should_be_exhausted().unwrap();
+        out
+    }
+
+    #[test]
+    fn simple_values() {
+        assert_eq!(round_trip(&-7_i32), -7);
+        assert_eq!(round_trip(&"hello".to_string()), "hello");
+        assert_eq!(round_trip(&vec![Some(1_u16), None]), vec![Some(1), None]);
+        let t = SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 5);
+        assert_eq!(round_trip(&t), t);
+        let a: SocketAddr = "[2001:db8::1]:9001".parse().unwrap();
+        assert_eq!(round_trip(&a), a);
+
+        let p: PortPolicy = "accept 80,443,8000-8999".parse().unwrap();
+        assert_eq!(round_trip(&Arc::new(p.clone())).as_ref(), &p);
+    }
+
+    #[test]
+    fn bad_values() {
+        let mut r = Reader::from_slice(&[2]);
+        assert!(bool::take_from(&mut r).is_err());
+        let mut r = Reader::from_slice(&[5, 0, 0, 0, 0]);
+        assert!(IpAddr::take_from(&mut r).is_err());
+        // A huge claimed length shouldn't make us allocate a huge vector.
+        let mut r = Reader::from_slice(&[0xff, 0xff, 0xff, 0xff, 1]);
+        assert!(Vec::<u8>::take_from(&mut r).is_err());
+        // Port ranges have to be in order.
+        let mut w = Vec::new();
+        vec![(80_u16, 80_u16), (20, 22)].write_onto(&mut w).unwrap();
+        let mut r = Reader::from_slice(&w);
+        assert!(Arc::<PortPolicy>::take_from(&mut r).is_err());
+    }
+}
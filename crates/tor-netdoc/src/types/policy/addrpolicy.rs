@@ -5,7 +5,7 @@ use std::fmt::Display;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
 
-use super::{PolicyError, PortRange};
+use super::{PolicyError, PortPolicy, PortRange};
 
 /// A sequence of rules that are applied to an address:port until one
 /// matches.
@@ -87,6 +87,59 @@ impl AddrPolicy {
     pub fn push(&mut self, kind: RuleKind, pattern: AddrPortPattern) {
         self.rules.push(AddrPolicyRule { kind, pattern });
     }
+
+    /// Return a [`PortPolicy`] summarizing which ports this policy allows
+    /// on IPv4.
+    ///
+    /// A port is allowed by the summary if the first rule that covers _every_
+    /// IPv4 address on that port is an "accept" rule.  Rules that cover only
+    /// some addresses are ignored: so `reject 10.0.0.0/8:*` does not keep a
+    /// port out of the summary, and `accept 192.0.2.0/24:80` does not put one
+    /// in.  Ports that no such rule covers are rejected.
+    ///
+    /// This is close to what the directory authorities do when they build the
+    /// policy summary in a microdescriptor, though they are a little cleverer
+    /// about large rejected address blocks.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use tor_netdoc::types::policy::{AddrPolicy, RuleKind};
+    ///
+    /// let mut policy = AddrPolicy::new();
+    /// policy.push(RuleKind::Reject, "127.0.0.0/8:*".parse().unwrap());
+    /// policy.push(RuleKind::Accept, "*:80-443".parse().unwrap());
+    /// policy.push(RuleKind::Reject, "*:*".parse().unwrap());
+    ///
+    /// assert_eq!(policy.ipv4_summary().to_string(), "accept 80-443");
+    /// ```
+    pub fn ipv4_summary(&self) -> PortPolicy {
+        // Every rule treats all of the ports between two consecutive
+        // boundaries alike, so we only need to look at one port from each
+        // span.
+        let mut starts = vec![1_u16];
+        for rule in &self.rules {
+            starts.push(rule.pattern.ports.lo);
+            if let Some(next) = rule.pattern.ports.hi.checked_add(1) {
+                starts.push(next);
+            }
+        }
+        starts.sort_unstable();
+        starts.dedup();
+
+        let allowed = starts.iter().enumerate().filter_map(|(i, &lo)| {
+            let hi = starts.get(i + 1).map_or(u16::MAX, |next| next - 1);
+            let verdict = self
+                .rules
+                .iter()
+                .find(|rule| {
+                    rule.pattern.pattern.covers_all_ipv4() && rule.pattern.ports.contains(lo)
+                })
+                .map(|rule| rule.kind);
+            (verdict == Some(RuleKind::Accept)).then(|| PortRange::new_unchecked(lo, hi))
+        });
+        PortPolicy::from_ranges(allowed)
+    }
 }
 
 /// A single rule in an address policy.
@@ -216,6 +269,10 @@ impl IpPattern {
             (_, _) => Err(PolicyError::InvalidMask),
         }
     }
+    /// Return true iff this pattern matches every IPv4 address.
+    fn covers_all_ipv4(&self) -> bool {
+        matches!(self, IpPattern::Star | IpPattern::V4Star)
+    }
     /// Return true iff `addr` is matched by this pattern.
     fn matches(&self, addr: &IpAddr) -> bool {
         match (self, addr) {
@@ -407,6 +464,42 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_ipv4_summary() {
+        fn summarize(rules: &[(RuleKind, &str)]) -> String {
+            let mut policy = AddrPolicy::new();
+            for (kind, pat) in rules {
+                policy.push(*kind, pat.parse().unwrap());
+            }
+            policy.ipv4_summary().to_string()
+        }
+        use RuleKind::{Accept, Reject};
+
+        assert_eq!(summarize(&[]), "reject 1-65535");
+        assert_eq!(summarize(&[(Reject, "*:*")]), "reject 1-65535");
+        assert_eq!(summarize(&[(Accept, "*:*")]), "accept 1-65535");
+        assert_eq!(
+            summarize(&[
+                (Reject, "*:25"),
+                (Reject, "10.0.0.0/8:*"),
+                (Accept, "192.0.2.0/24:8080"),
+                (Accept, "0.0.0.0/0:20-30"),
+                (Accept, "*:443"),
+                (Accept, "[::]/0:9000"),
+                (Reject, "*:*"),
+            ]),
+            "accept 20-24,26-30,443"
+        );
+        assert_eq!(
+            summarize(&[(Reject, "*:1-1023"), (Accept, "*:*")]),
+            "accept 1024-65535"
+        );
+        assert_eq!(
+            summarize(&[(Accept, "*:65535"), (Accept, "*:1")]),
+            "accept 1,65535"
+        );
+    }
+
     #[test]
     fn serde() {
         #[derive(Clone, Debug, serde::Serialize, serde::Deserialize, Eq, PartialEq)]
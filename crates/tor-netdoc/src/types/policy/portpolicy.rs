@@ -96,6 +96,17 @@ impl PortPolicy {
         out
     }
 
+    /// Helper: create a PortPolicy that allows the ports in `ranges`.
+    ///
+    /// The ranges must be sorted and must not overlap.
+    pub(crate) fn from_ranges(ranges: impl IntoIterator<Item = PortRange>) -> Self {
+        let mut out = PortPolicy::new_reject_all();
+        for range in ranges {
+            let _ = out.push_policy(range);
+        }
+        out
+    }
+
     /// Helper: replace this policy with its inverse.
     fn invert(&mut self) {
         let mut prev_hi = 0;
@@ -140,6 +151,12 @@ impl PortPolicy {
             .binary_search_by(|range| range.compare_to_port(port))
             .is_ok()
     }
+    /// Return an iterator over the ranges of ports that this policy allows.
+    ///
+    /// The ranges are sorted, and no two of them overlap or are adjacent.
+    pub fn allowed_ranges(&self) -> impl Iterator<Item = &PortRange> + '_ {
+        self.allowed.iter()
+    }
     /// Replace this PortPolicy with an interned copy, to save memory.
     pub fn intern(self) -> Arc<Self> {
         POLICY_CACHE.intern(self)
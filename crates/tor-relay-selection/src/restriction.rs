@@ -50,6 +50,12 @@ enum RestrictionInner<'a> {
     /// Require that the relay has a given country code.
     #[cfg(feature = "geoip")]
     RequireCountry(tor_geoip::CountryCode),
+    /// Require that the relay has one of the given country codes.
+    #[cfg(feature = "geoip")]
+    RequireCountryIn(Vec<tor_geoip::CountryCode>),
+    /// Require that the relay does not have any of the given country codes.
+    #[cfg(feature = "geoip")]
+    ExcludeCountries(Vec<tor_geoip::CountryCode>),
 }
 
 impl<'a> RelayRestriction<'a> {
@@ -79,6 +85,28 @@ impl<'a> RelayRestriction<'a> {
         }
     }
 
+    /// Require a relay that appears to be in one of the provided countries,
+    /// according to our geoip subsystem.
+    ///
+    /// Relays whose country we don't know are not permitted.
+    #[cfg(feature = "geoip")]
+    pub fn require_country_code_in(ccs: Vec<tor_geoip::CountryCode>) -> Self {
+        RelayRestriction {
+            inner: RestrictionInner::RequireCountryIn(ccs),
+        }
+    }
+
+    /// Require a relay that does not appear to be in any of the provided
+    /// countries, according to our geoip subsystem.
+    ///
+    /// Relays whose country we don't know are permitted.
+    #[cfg(feature = "geoip")]
+    pub fn exclude_country_codes(ccs: Vec<tor_geoip::CountryCode>) -> Self {
+        RelayRestriction {
+            inner: RestrictionInner::ExcludeCountries(ccs),
+        }
+    }
+
     /// Require that a relay has at least one address
     /// listed in `addr_patterns`.
     pub fn require_address(addr_patterns: Vec<AddrPortPattern>) -> Self {
@@ -123,6 +151,10 @@ impl<'a> RelayRestriction<'a> {
             HasAddrInSet(_) => Some("not reachable (according to address filter)"),
             #[cfg(feature = "geoip")]
             RequireCountry(_) => Some("not in correct country"),
+            #[cfg(feature = "geoip")]
+            RequireCountryIn(_) => Some("not in a permitted country"),
+            #[cfg(feature = "geoip")]
+            ExcludeCountries(_) => Some("in an excluded country"),
         }
     }
 }
@@ -137,6 +169,10 @@ impl<'a> LowLevelRelayPredicate for RelayRestriction<'a> {
             HasAddrInSet(patterns) => relay_has_addr_in_set(relay, patterns),
             #[cfg(feature = "geoip")]
             RequireCountry(cc) => relay.country_code() == Some(*cc),
+            #[cfg(feature = "geoip")]
+            RequireCountryIn(ccs) => relay.country_code().is_some_and(|cc| ccs.contains(&cc)),
+            #[cfg(feature = "geoip")]
+            ExcludeCountries(ccs) => !relay.country_code().is_some_and(|cc| ccs.contains(&cc)),
         }
     }
 }
@@ -359,7 +395,8 @@ fn relays_in_same_extended_family(
     r1: &Relay<'_>,
     r2: &Relay<'_>,
 ) -> bool {
-    r1.low_level_details().in_same_family(r2) || subnet_config.any_addrs_in_same_subnet(r1, r2)
+    r1.low_level_details()
+        .in_same_extended_family(r2, subnet_config)
 }
 
 #[cfg(test)]
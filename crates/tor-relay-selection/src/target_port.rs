@@ -41,6 +41,16 @@ impl TargetPort {
     }
 }
 
+impl From<TargetPort> for tor_netdir::ExitPort {
+    fn from(p: TargetPort) -> Self {
+        if p.ipv6 {
+            tor_netdir::ExitPort::ipv6(p.port)
+        } else {
+            tor_netdir::ExitPort::ipv4(p.port)
+        }
+    }
+}
+
 impl fmt::Display for TargetPort {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}{}", self.port, if self.ipv6 { "v6" } else { "v4" })
@@ -1,7 +1,7 @@
 //! Define a type describing how we're going to use a relay.
 
 use crate::{LowLevelRelayPredicate, RelaySelectionConfig, TargetPort};
-use tor_netdir::{Relay, WeightRole};
+use tor_netdir::{ExitPort, Relay, RelayRole, WeightRole};
 
 /// Description for how we plan to use a single relay.
 #[derive(Clone, Debug)]
@@ -22,17 +22,17 @@ pub struct RelayUsage {
 enum RelayUsageInner {
     /// Allow any relay that exits to any port.
     AnyExit,
-    /// Require that the relay can exit to every port in `TargetPort`.
-    ExitToAllPorts(Vec<TargetPort>),
+    /// Require that the relay can exit to every port in a given list.
+    ExitToAllPorts(Vec<ExitPort>),
     /// Require that the relay can exit to at least one port in a given set.
     ///
     /// (We split the ports into those that require Stability and those that do
     /// not, for efficiency.)
     ExitToAnyPort {
         /// The desired ports that require the Stable flag.
-        stable_ports: Vec<TargetPort>,
+        stable_ports: Vec<ExitPort>,
         /// The desired ports that do not require the Stable flag.
-        unstable_ports: Vec<TargetPort>,
+        unstable_ports: Vec<ExitPort>,
     },
     /// Allow any relay that's suitable as a middle-point.
     Middle,
@@ -75,7 +75,7 @@ impl RelayUsage {
     pub fn exit_to_all_ports(cfg: &RelaySelectionConfig, ports: Vec<TargetPort>) -> Self {
         let need_stable = ports.iter().any(|p| cfg.port_requires_stable_flag(p.port));
         RelayUsage {
-            inner: RelayUsageInner::ExitToAllPorts(ports),
+            inner: RelayUsageInner::ExitToAllPorts(ports.into_iter().map(ExitPort::from).collect()),
             need_stable,
         }
     }
@@ -84,7 +84,8 @@ impl RelayUsage {
     pub fn exit_to_any_port(cfg: &RelaySelectionConfig, ports: Vec<TargetPort>) -> Self {
         let (stable_ports, unstable_ports): (Vec<_>, Vec<_>) = ports
             .into_iter()
-            .partition(|p| cfg.port_requires_stable_flag(p.port));
+            .map(ExitPort::from)
+            .partition(|p| cfg.port_requires_stable_flag(p.port()));
         let need_stable = unstable_ports.is_empty() && !stable_ports.is_empty();
         RelayUsage {
             inner: RelayUsageInner::ExitToAnyPort {
@@ -220,32 +221,30 @@ impl LowLevelRelayPredicate for RelayUsage {
             return false;
         }
         match &self.inner {
-            AnyExit => relay.policies_allow_some_port(),
-            ExitToAllPorts(ports) => ports.iter().all(|p| p.is_supported_by(&relay)),
+            AnyExit => relay_in.usable_for(&RelayRole::Exit(&[])),
+            ExitToAllPorts(ports) => relay_in.usable_for(&RelayRole::Exit(ports)),
             ExitToAnyPort {
                 stable_ports,
                 unstable_ports,
             } => {
-                if relay.is_flagged_stable()
-                    && stable_ports.iter().any(|p| p.is_supported_by(&relay))
-                {
+                let exits_to =
+                    |p: &ExitPort| relay_in.usable_for(&RelayRole::Exit(std::slice::from_ref(p)));
+                if relay.is_flagged_stable() && stable_ports.iter().any(exits_to) {
                     return true;
                 }
-                unstable_ports.iter().any(|p| p.is_supported_by(&relay))
+                unstable_ports.iter().any(exits_to)
             }
-            Middle => true,
+            Middle => relay_in.usable_for(&RelayRole::Middle),
             // TODO: Is there a distinction we should implement?
-            // TODO: Move is_hs_intro_point logic here.
-            NewIntroPoint | ContinuingIntroPoint => relay.is_hs_intro_point(),
+            NewIntroPoint | ContinuingIntroPoint => relay_in.usable_for(&RelayRole::IntroPoint),
             // TODO: Is there a distinction we should implement?
-            // TODO: Move is_suitable_as_guard logic here.
-            NewGuard | ContinuingGuard => relay.is_suitable_as_guard() && relay.is_dir_cache(),
+            NewGuard | ContinuingGuard => relay_in.usable_for(&RelayRole::Guard),
             #[cfg(feature = "vanguards")]
             Vanguard => {
                 // TODO: we might want to impose additional restrictions here
                 true
             }
-            DirectoryCache => relay.is_dir_cache(),
+            DirectoryCache => relay_in.usable_for(&RelayRole::DirCache),
         }
     }
 }
@@ -325,8 +324,8 @@ mod test {
                 stable_ports,
                 unstable_ports,
             } => {
-                assert_eq!(&stable_ports[..], &[TargetPort::ipv4(22)]);
-                assert_eq!(&unstable_ports[..], &[TargetPort::ipv4(80)]);
+                assert_eq!(&stable_ports[..], &[ExitPort::ipv4(22)]);
+                assert_eq!(&unstable_ports[..], &[ExitPort::ipv4(80)]);
             }
             _ => {
                 panic!("Wrong kind of usage.");
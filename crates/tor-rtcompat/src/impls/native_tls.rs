@@ -72,6 +72,18 @@ where
             .map_err(|e| IoError::new(std::io::ErrorKind::Other, e))?;
         Ok(conn)
     }
+
+    async fn negotiate_validated(&self, stream: S, hostname: &str) -> IoResult<Self::Conn> {
+        // Unlike the connector we use for Tor relays, this one checks the
+        // certificate against the platform's trusted roots, and checks the
+        // hostname in it.
+        let connector: async_native_tls::TlsConnector = native_tls::TlsConnector::builder().into();
+        let conn = connector
+            .connect(hostname, stream)
+            .await
+            .map_err(|e| IoError::new(std::io::ErrorKind::Other, e))?;
+        Ok(conn)
+    }
 }
 
 impl<S> TlsProvider<S> for NativeTlsProvider
@@ -74,6 +74,14 @@ where
             .map_err(|e| IoError::new(io::ErrorKind::InvalidInput, e))?;
         self.connector.connect(name.to_owned(), stream).await
     }
+
+    async fn negotiate_validated(&self, _stream: S, _hostname: &str) -> IoResult<Self::Conn> {
+        // We don't ship a set of trusted roots for rustls to check against.
+        Err(IoError::new(
+            io::ErrorKind::Unsupported,
+            "rustls runtime cannot validate certificates in the web PKI",
+        ))
+    }
 }
 
 impl<S> TlsProvider<S> for RustlsProvider
@@ -329,6 +329,18 @@ pub trait TlsConnector<S> {
     /// [SNI](https://en.wikipedia.org/wiki/Server_Name_Indication) or one of
     /// the TLS 1.3 equivalents.
     async fn negotiate_unvalidated(&self, stream: S, sni_hostname: &str) -> IoResult<Self::Conn>;
+
+    /// Start a TLS session over the provided TCP stream `stream`, and check
+    /// that the peer's certificate is valid for `hostname` within the web PKI.
+    ///
+    /// Unlike [`negotiate_unvalidated`](TlsConnector::negotiate_unvalidated),
+    /// this is suitable for talking to an ordinary TLS server that isn't a Tor
+    /// relay.
+    ///
+    /// Not every connector knows which certificate authorities to trust.  One
+    /// that doesn't returns an error of kind
+    /// [`Unsupported`](std::io::ErrorKind::Unsupported).
+    async fn negotiate_validated(&self, stream: S, hostname: &str) -> IoResult<Self::Conn>;
 }
 
 /// Trait for a runtime that knows how to create TLS connections over
@@ -475,6 +475,9 @@ impl TlsProvider<LocalStream> for MockNetProvider {
 ///
 /// Note that no TLS is actually performed here: connections are simply
 /// told that they succeeded with a given certificate.
+///
+/// When asked to validate a connection, we treat a notional certificate as
+/// valid for a hostname if and only if it consists of exactly that hostname.
 #[derive(Clone)]
 #[non_exhaustive]
 pub struct MockTlsConnector;
@@ -514,6 +517,21 @@ impl TlsConnector<LocalStream> for MockTlsConnector {
 
         Ok(MockTlsStream { peer_cert, stream })
     }
+
+    async fn negotiate_validated(
+        &self,
+        stream: LocalStream,
+        hostname: &str,
+    ) -> IoResult<MockTlsStream> {
+        let conn = self.negotiate_unvalidated(stream, hostname).await?;
+        if conn.peer_cert.as_deref() != Some(hostname.as_bytes()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "certificate not valid for hostname",
+            ));
+        }
+        Ok(conn)
+    }
 }
 
 impl CertifiedConn for MockTlsStream {